@@ -0,0 +1,30 @@
+// Generates the gRPC service of src/grpc.rs with the grpc feature. It's described here rather than
+// compiled from proto/bast.proto (which it mirrors) so that building doesn't need protoc.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, input: &str, output: &str| Method::builder()
+        .name(name)
+        .route_name(route_name)
+        .input_type(format!("crate::grpc::{}", input))
+        .output_type(format!("crate::grpc::{}", output))
+        .codec_path("tonic::codec::ProstCodec");
+    let service = Service::builder()
+        .name("Store")
+        .package("bast")
+        .method(method("get", "Get", "GetRequest", "GetResponse").build())
+        .method(method("set", "Set", "SetRequest", "SetResponse").build())
+        .method(method("del", "Del", "DelRequest", "DelResponse").build())
+        .method(method("scan", "Scan", "ScanRequest", "ScanResponse").build())
+        .method(method("subscribe", "Subscribe", "SubscribeRequest", "Message").server_streaming().build())
+        .build();
+    Builder::new().build_client(false).build_transport(false).compile(&[service]);
+}
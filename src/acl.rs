@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use sha2::{Digest, Sha256};
+
+use crate::arg::Arg;
+use crate::glob::glob_match;
+use crate::{command_group, CommandSpec, RESPError, RESPValue};
+
+pub const DEFAULT_USER: &str = "default";
+
+const CATEGORIES: &[&str] = &[
+    "all", "read", "write", "fast", "slow", "admin", "dangerous", "keyspace", "string", "hash", "stream",
+    "hyperloglog", "bitmap", "geo", "pubsub", "transaction", "scripting", "connection",
+];
+
+fn in_category(spec: &CommandSpec, category: &str) -> bool {
+    let group = command_group(spec);
+    match category {
+        "all" => true,
+        "read" => spec.has_flag("readonly"),
+        "write" => spec.has_flag("write"),
+        "fast" => spec.has_flag("fast"),
+        "slow" => !spec.has_flag("fast"),
+        "admin" => spec.has_flag("admin"),
+        "dangerous" => spec.has_flag("admin") || matches!(spec.name, "FLUSHDB" | "FLUSHALL"),
+        "keyspace" => group == "generic" || matches!(spec.name, "DBSIZE" | "FLUSHDB" | "FLUSHALL"),
+        "pubsub" => spec.has_flag("pubsub"),
+        "transaction" => group == "transactions",
+        "string" | "hash" | "stream" | "hyperloglog" | "bitmap" | "geo" | "scripting" | "connection" => group == category,
+        _ => false
+    }
+}
+
+// The categories of a command, as shown by COMMAND INFO.
+pub fn categories(spec: &CommandSpec) -> Vec<&'static str> {
+    CATEGORIES.iter().copied().filter(|category| *category != "all" && in_category(spec, category)).collect()
+}
+
+fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Clone, Default)]
+pub struct User {
+    enabled: bool,
+    // Any password authenticates the user.
+    nopass: bool,
+    // SHA256 digests of the passwords, in hex.
+    passwords: BTreeSet<String>,
+    // Set by +@all, allowing commands that are only registered later on (by plugins) as well.
+    all_commands: bool,
+    commands: HashSet<&'static str>,
+    // The command rules applied since the last reset, which describe the allowed commands.
+    command_rules: Vec<String>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn can_run(&self, spec: &CommandSpec) -> bool {
+        self.all_commands || self.commands.contains(spec.name)
+    }
+
+    pub fn can_access(&self, key: &str) -> bool {
+        self.key_patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+    }
+
+    // Whether the user may access any key (~*), not only the ones matching some patterns.
+    pub fn can_access_all(&self) -> bool {
+        self.key_patterns.iter().any(|pattern| pattern == "*")
+    }
+
+    fn allow(&mut self, allowed: bool, specs: &[&'static CommandSpec], filter: impl Fn(&CommandSpec) -> bool) {
+        if !allowed && self.all_commands {
+            self.all_commands = false;
+            self.commands = specs.iter().map(|spec| spec.name).collect();
+        }
+        for spec in specs.iter().filter(|spec| filter(spec)) {
+            if allowed {
+                self.commands.insert(spec.name);
+            } else {
+                self.commands.remove(spec.name);
+            }
+        }
+    }
+
+    fn set_all_commands(&mut self, allowed: bool) {
+        self.all_commands = allowed;
+        self.commands.clear();
+        self.command_rules = if allowed { vec![String::from("+@all")] } else { vec![] };
+    }
+
+    fn apply_rule(&mut self, rule: &str, specs: &[&'static CommandSpec]) -> Result<(), RESPError> {
+        let error = |reason: &str| RESPError::InvalidAclRule(rule.to_owned(), reason.to_owned());
+
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            },
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            },
+            "allkeys" => self.key_patterns = vec![String::from("*")],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" => self.set_all_commands(true),
+            "nocommands" => self.set_all_commands(false),
+            "reset" => *self = User::default(),
+            _ => {
+                let (prefix, value) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+                match prefix {
+                    ">" => {
+                        self.nopass = false;
+                        self.passwords.insert(hash_password(value));
+                    },
+                    "<" => {
+                        if !self.passwords.remove(&hash_password(value)) {
+                            return Err(error("The password you are trying to remove from the user does not exist"));
+                        }
+                    },
+                    "#" | "!" => {
+                        if value.len() != 64 || !value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+                            return Err(error("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters"));
+                        }
+                        if prefix == "#" {
+                            self.nopass = false;
+                            self.passwords.insert(value.to_owned());
+                        } else if !self.passwords.remove(value) {
+                            return Err(error("The password you are trying to remove from the user does not exist"));
+                        }
+                    },
+                    "~" => {
+                        if !self.key_patterns.iter().any(|pattern| pattern == value) {
+                            self.key_patterns.push(value.to_owned());
+                        }
+                    },
+                    "+" | "-" => {
+                        let allowed = prefix == "+";
+                        let value = value.to_ascii_lowercase();
+                        match value.strip_prefix('@') {
+                            Some("all") => self.set_all_commands(allowed),
+                            Some(category) => {
+                                if !CATEGORIES.contains(&category) {
+                                    return Err(error("Unknown command category"));
+                                }
+                                self.allow(allowed, specs, |spec| in_category(spec, category));
+                                self.command_rules.push(format!("{}@{}", prefix, category));
+                            },
+                            None => {
+                                let name = value.to_ascii_uppercase();
+                                if !specs.iter().any(|spec| spec.name == name) {
+                                    return Err(error("Unknown command"));
+                                }
+                                self.allow(allowed, specs, |spec| spec.name == name);
+                                self.command_rules.push(format!("{}{}", prefix, value));
+                            }
+                        }
+                    },
+                    _ => return Err(error("Syntax error"))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    fn describe_commands(&self) -> String {
+        if self.command_rules.is_empty() {
+            String::from("-@all")
+        } else {
+            self.command_rules.join(" ")
+        }
+    }
+
+    fn describe_keys(&self) -> String {
+        self.key_patterns.iter().map(|pattern| format!("~{}", pattern)).collect::<Vec<_>>().join(" ")
+    }
+
+    // The user as the rules that would recreate it, as shown by ACL LIST.
+    fn describe(&self, name: &str) -> String {
+        let mut parts = vec![format!("user {}", name)];
+        parts.extend(self.flags().into_iter().map(String::from));
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if !self.key_patterns.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+}
+
+pub struct Acl {
+    users: BTreeMap<String, User>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        let default_user = User {
+            enabled: true,
+            nopass: true,
+            all_commands: true,
+            command_rules: vec![String::from("+@all")],
+            key_patterns: vec![String::from("*")],
+            ..User::default()
+        };
+        Self { users: BTreeMap::from([(DEFAULT_USER.to_owned(), default_user)]) }
+    }
+}
+
+impl Acl {
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    // Whether new connections have to authenticate before running commands.
+    pub fn auth_required(&self) -> bool {
+        self.users.get(DEFAULT_USER).is_none_or(|user| !user.nopass || !user.enabled)
+    }
+
+    pub fn default_user_has_password(&self) -> bool {
+        self.users.get(DEFAULT_USER).is_some_and(|user| !user.nopass)
+    }
+
+    // Set through the requirepass config, an empty password lets anyone in as the default user.
+    pub fn set_default_password(&mut self, password: &str) {
+        let user = self.users.entry(DEFAULT_USER.to_owned()).or_default();
+        user.passwords.clear();
+        user.nopass = password.is_empty();
+        if !password.is_empty() {
+            user.passwords.insert(hash_password(password));
+        }
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<(), RESPError> {
+        let user = self.users.get(username).filter(|user| user.enabled).ok_or(RESPError::WrongPass)?;
+        if !user.nopass && !user.passwords.contains(&hash_password(password)) {
+            return Err(RESPError::WrongPass);
+        }
+        Ok(())
+    }
+}
+
+fn user_reply(user: &User) -> RESPValue {
+    let blob = |s: &str| RESPValue::BlobString(s.to_owned().into());
+    RESPValue::Array(vec![
+        blob("flags"),
+        RESPValue::Array(user.flags().into_iter().map(blob).collect()),
+        blob("passwords"),
+        RESPValue::Array(user.passwords.iter().map(|hash| blob(hash)).collect()),
+        blob("commands"),
+        blob(&user.describe_commands()),
+        blob("keys"),
+        blob(&user.describe_keys()),
+    ])
+}
+
+// ACL SETUSER username [rule [rule ...]]
+// ACL GETUSER username
+// ACL LIST
+// ACL WHOAMI
+// ACL CAT [category]
+// ACL DELUSER username [username ...]
+pub fn acl(command: &[Arg], username: &str, acl: &mut Acl, specs: &[&'static CommandSpec]) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("ACL|{}", subcommand)))
+    };
+
+    match subcommand.as_str() {
+        "SETUSER" => {
+            check_arity(command.len() >= 3)?;
+
+            // Rules are applied to a copy, so a bad rule leaves the user untouched.
+            let mut user = acl.users.get(command[2].as_str()).cloned().unwrap_or_default();
+            for rule in &command[3..] {
+                user.apply_rule(rule, specs)?;
+            }
+            acl.users.insert(command[2].to_string(), user);
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "GETUSER" => {
+            check_arity(command.len() == 3)?;
+            Ok(acl.users.get(command[2].as_str()).map_or(RESPValue::Null, user_reply))
+        },
+        "LIST" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::Array(acl.users.iter()
+                .map(|(name, user)| RESPValue::BlobString(user.describe(name).into()))
+                .collect()))
+        },
+        "WHOAMI" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::BlobString(username.to_owned().into()))
+        },
+        "CAT" => {
+            check_arity(command.len() <= 3)?;
+            let names: Vec<String> = match command.get(2) {
+                Some(category) => {
+                    let category = category.to_ascii_lowercase();
+                    if !CATEGORIES.contains(&category.as_str()) {
+                        return Err(RESPError::UnknownAclCategory(category));
+                    }
+                    specs.iter()
+                        .filter(|spec| in_category(spec, &category))
+                        .map(|spec| spec.name.to_ascii_lowercase())
+                        .collect()
+                },
+                None => CATEGORIES.iter().map(|category| category.to_string()).collect()
+            };
+            Ok(RESPValue::Array(names.into_iter().map(|name| RESPValue::BlobString(name.into())).collect()))
+        },
+        "DELUSER" => {
+            check_arity(command.len() >= 3)?;
+            if command[2..].iter().any(|name| name == DEFAULT_USER) {
+                return Err(RESPError::DeleteDefaultUser);
+            }
+
+            let deleted = command[2..].iter().filter(|name| acl.users.remove(name.as_str()).is_some()).count();
+            Ok(RESPValue::Number(deleted as i64))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("ACL {}", command[1])))
+    }
+}
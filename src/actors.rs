@@ -0,0 +1,94 @@
+// Shards as actors, with `execution-model actors`. Every shard of the keyspace is served by a thread
+// of its own, running the commands on its keys one after the other in the order they were sent, so
+// commands on a shard never wait for the locks of the shard. Commands accessing several shards
+// (transactions and scripts included) go through the router, which parks the actors of those shards
+// in order and runs the command on a blocking thread once all of them are parked, in between the
+// commands of each of them. Commands accessing no shard run on the runtime, as they do with locking.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use tokio::sync::oneshot;
+
+pub const MODELS: &[&str] = &["locking", "actors"];
+
+type Job = Box<dyn FnOnce() + Send>;
+
+enum Message {
+    Run(Job),
+    // Acknowledged once the actor got to it, it then waits until the router releases it.
+    Park { parked: oneshot::Sender<()>, released: Receiver<()> },
+}
+
+pub struct Actors {
+    mailboxes: Vec<Sender<Message>>,
+}
+
+impl Actors {
+    // Starts an actor per shard, on the current runtime.
+    pub fn start(shards: usize) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Handle::current();
+        let mut mailboxes = Vec::with_capacity(shards);
+        for index in 0..shards {
+            let (sender, mailbox) = mpsc::channel();
+            let runtime = runtime.clone();
+            std::thread::Builder::new().name(format!("shard-{}", index)).spawn(move || {
+                // Commands may spawn tasks, like background saves do.
+                let _runtime = runtime.enter();
+                serve(mailbox);
+            })?;
+            mailboxes.push(sender);
+        }
+        Ok(Self { mailboxes })
+    }
+
+    // Runs the job on behalf of the shards (sorted by their index), returning what it returned.
+    pub async fn run<T: Send + 'static>(&self, shards: &[usize], job: impl FnOnce() -> T + Send + 'static) -> T {
+        let (sender, result) = oneshot::channel();
+        let job = move || {
+            let _ = sender.send(job());
+        };
+        match shards {
+            [] => job(),
+            [shard] => self.send(*shard, Message::Run(Box::new(job))),
+            shards => {
+                // Parked in order, so two routers never wait for each other.
+                let mut releases = Vec::with_capacity(shards.len());
+                for shard in shards {
+                    let (parked, acknowledged) = oneshot::channel();
+                    let (release, released) = mpsc::channel::<()>();
+                    self.send(*shard, Message::Park { parked, released });
+                    acknowledged.await.expect("Shard actor stopped");
+                    releases.push(release);
+                }
+                if let Err(e) = tokio::task::spawn_blocking(job).await {
+                    std::panic::resume_unwind(e.into_panic());
+                }
+            }
+        }
+        // Only dropped without a result when the job panicked, which fails the client like it
+        // would on the runtime.
+        result.await.expect("Command panicked on a shard actor")
+    }
+
+    fn send(&self, shard: usize, message: Message) {
+        self.mailboxes[shard].send(message).expect("Shard actor stopped");
+    }
+}
+
+fn serve(mailbox: Receiver<Message>) {
+    for message in mailbox {
+        match message {
+            Message::Run(job) => {
+                // The actor outlives the commands that panic.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+            },
+            Message::Park { parked, released } => {
+                // The router may have given up on parking it.
+                if parked.send(()).is_ok() {
+                    let _ = released.recv();
+                }
+            }
+        }
+    }
+}
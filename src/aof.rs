@@ -0,0 +1,642 @@
+// The append only file: every write is appended to `appendfilename` in `dir` as a command (in the
+// RESP protocol) redoing it, and the file is replayed on startup instead of loading the snapshot.
+//
+// Commands whose effect depends on when they run are journaled as their effect: relative TTLs as
+// absolute ones, XADD with the ID it picked, and claims without their idle time condition. Scripts
+// are journaled as the writes they made, and transactions and scripts are wrapped in MULTI / EXEC
+// so a file cut short halfway through one doesn't replay a part of it.
+//
+// BGREWRITEAOF (and the file growing by `auto-aof-rewrite-percentage`) rewrites the file as a
+// snapshot of the keyspace, in `snapshot-format`, followed by the writes made while the snapshot
+// was being written. Loading such a file loads its snapshot preamble, then replays the commands.
+//
+// The file may be encrypted (see encryption.rs), in which case every append is encrypted with the
+// key of the file, and the file is rewritten once it isn't the current key anymore.
+//
+// Writes are streamed to replicas journaled the same way (see replication.rs), whether the file is
+// on or not.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::arg::Arg;
+use crate::db::now_ms;
+use crate::encryption::{self, Key};
+use crate::snapshot::Purpose;
+use crate::{hash, logging, snapshot, stream, RESPError, RESPValue, SharedState};
+
+pub const FSYNC_POLICIES: &[&str] = &["always", "everysec", "no"];
+
+// When the file is fsynced (an index into FSYNC_POLICIES). Set through the config.
+pub static FSYNC: AtomicU8 = AtomicU8::new(1);
+
+// An `everysec` fsync is in progress.
+static FSYNCING: AtomicBool = AtomicBool::new(false);
+
+const FSYNC_ALWAYS: u8 = 0;
+const FSYNC_EVERYSEC: u8 = 1;
+
+// Seconds to wait before trying an automatic rewrite again after one failed.
+const REWRITE_RETRY_DELAY: u64 = 5;
+
+#[derive(Default)]
+struct State {
+    // Set on startup once the file was replayed, after which it can be turned on and off.
+    started: bool,
+    // None until the file is opened, after it was replayed, and while it's turned off.
+    file: Option<File>,
+    // The key the file is encrypted with, None when it isn't.
+    key: Option<Key>,
+    size: u64,
+    // The size of the file when it was opened or last rewritten, which automatic rewrites grow from.
+    base_size: u64,
+    // The writes made since the rewrite in progress started, appended to the rewritten file.
+    rewrite_buf: Option<Vec<u8>>,
+    // BGREWRITEAOF asked for a rewrite while a background save was in progress.
+    rewrite_scheduled: bool,
+    // Unix time in seconds of the last rewrite attempt.
+    last_rewrite_try: u64,
+    last_rewrite_ok: bool,
+    last_rewrite_seconds: i64,
+    // The nesting of transactions and scripts being run, whose writes go between MULTI and EXEC.
+    atomic_depth: usize,
+    multi_written: bool,
+    // Writes weren't fsynced yet.
+    unsynced: bool,
+    last_write_ok: bool,
+}
+
+#[derive(Default)]
+pub struct Aof {
+    state: Mutex<State>,
+}
+
+impl Aof {
+    // Called on startup once the file was replayed, with its path when it's on.
+    pub fn start(&self, path: Option<&str>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        state.last_write_ok = true;
+        state.last_rewrite_ok = true;
+        state.last_rewrite_seconds = -1;
+        if let Some(path) = path {
+            let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+            state.key = match file.metadata()?.len() {
+                0 => encryption::start_file(&mut file)?,
+                _ => encryption::file_key(&mut file)?
+            };
+            state.size = file.metadata()?.len();
+            state.base_size = state.size;
+            state.file = Some(file);
+            state.rewrite_scheduled = !encryption::is_current(state.key.as_ref());
+        }
+        Ok(())
+    }
+
+    // Whether writes are journaled, to the file or to the buffer of a rewrite.
+    pub fn journaling(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.file.is_some() || state.rewrite_buf.is_some()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn base_size(&self) -> u64 {
+        self.state.lock().unwrap().base_size
+    }
+
+    pub fn last_write_ok(&self) -> bool {
+        self.state.lock().unwrap().last_write_ok
+    }
+
+    pub fn rewriting(&self) -> bool {
+        self.state.lock().unwrap().rewrite_buf.is_some()
+    }
+
+    pub fn rewrite_scheduled(&self) -> bool {
+        self.state.lock().unwrap().rewrite_scheduled
+    }
+
+    pub fn last_rewrite_ok(&self) -> bool {
+        self.state.lock().unwrap().last_rewrite_ok
+    }
+
+    pub fn last_rewrite_seconds(&self) -> i64 {
+        self.state.lock().unwrap().last_rewrite_seconds
+    }
+
+    // Called when the keyspace was frozen for a rewrite, from when writes are buffered for it.
+    pub fn rewrite_started(&self) {
+        let mut state = self.state.lock().unwrap();
+        let mut buf = vec![];
+        // The writes of the transaction in progress made so far are in the snapshot, the rest of
+        // them still have to replay as a transaction.
+        if state.multi_written {
+            encode(&[Arg::from("MULTI")], &mut buf);
+        }
+        state.rewrite_buf = Some(buf);
+        state.rewrite_scheduled = false;
+        state.last_rewrite_try = now_ms() / 1000;
+    }
+
+    // Called once the snapshot of a rewrite was written to `path`. The writes made meanwhile are
+    // appended to it and it replaces the file, all while holding the state so that no write goes
+    // in between. Appending then continues to it, unless the file was turned off meanwhile.
+    pub fn rewrite_done(&self, result: io::Result<()>, path: &str, seconds: i64, shared: &SharedState) {
+        let mut state = self.state.lock().unwrap();
+        let buf = state.rewrite_buf.take().unwrap();
+        let result = result.and_then(|()| {
+            let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+            let key = encryption::file_key(&mut file)?;
+            match &key {
+                Some(key) => file.write_all(&encryption::seal(key, file.metadata()?.len(), &buf))?,
+                None => file.write_all(&buf)?
+            }
+            file.sync_data()?;
+            std::fs::rename(path, shared.config.get("appendfilename"))?;
+            Ok((file, key))
+        });
+        state.last_rewrite_ok = result.is_ok();
+        state.last_rewrite_seconds = seconds;
+        match result {
+            Ok((file, key)) => {
+                if shared.config.get_bool("appendonly") {
+                    state.size = file.metadata().map_or(0, |metadata| metadata.len());
+                    state.base_size = state.size;
+                    state.file = Some(file);
+                    state.key = key;
+                    state.unsynced = false;
+                    state.last_write_ok = true;
+                }
+                drop(state);
+                logging::log(shared, "notice", "Background AOF rewrite finished successfully");
+            },
+            Err(e) => {
+                drop(state);
+                let _ = std::fs::remove_file(path);
+                logging::log(shared, "warning", format!("Background AOF rewrite error: {}", e));
+            }
+        }
+    }
+
+    // Flushes the writes to the disk, when shutting down.
+    pub fn fsync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = &state.file {
+            file.sync_data()?;
+        }
+        state.unsynced = false;
+        Ok(())
+    }
+
+    // Called before running a transaction or a script, whose writes are journaled as one.
+    pub fn begin_atomic(&self) {
+        self.state.lock().unwrap().atomic_depth += 1;
+    }
+
+    pub fn end_atomic(&self, shared: &SharedState) {
+        let mut state = self.state.lock().unwrap();
+        state.atomic_depth -= 1;
+        if state.atomic_depth == 0 && std::mem::take(&mut state.multi_written) {
+            drop(state);
+            self.append(&[vec![Arg::from("EXEC")]], shared);
+        }
+    }
+
+    fn append(&self, commands: &[Vec<Arg>], shared: &SharedState) {
+        let mut state = self.state.lock().unwrap();
+        if (state.file.is_none() && state.rewrite_buf.is_none() && !shared.replication.streaming()) || commands.is_empty() {
+            return;
+        }
+        let mut buf = vec![];
+        if state.atomic_depth > 0 && !state.multi_written {
+            state.multi_written = true;
+            encode(&[Arg::from("MULTI")], &mut buf);
+        }
+        for command in commands {
+            encode(command, &mut buf);
+        }
+
+        if let Some(rewrite_buf) = &mut state.rewrite_buf {
+            rewrite_buf.extend_from_slice(&buf);
+        }
+        shared.replication.feed(&buf);
+
+        let size = state.size;
+        if let Some(key) = &state.key {
+            buf = encryption::seal(key, size, &buf);
+        }
+        let file = match state.file.as_mut() {
+            Some(file) => file,
+            None => return
+        };
+        let mut result = file.write_all(&buf);
+        if result.is_ok() && FSYNC.load(Ordering::Relaxed) == FSYNC_ALWAYS {
+            result = file.sync_data();
+        }
+        match result {
+            Ok(()) => {
+                state.size += buf.len() as u64;
+                state.unsynced = true;
+                state.last_write_ok = true;
+            },
+            Err(e) => {
+                // A command written in part would fail the replay, so it's cut off the file.
+                let _ = file.set_len(size);
+                state.last_write_ok = false;
+                drop(state);
+                logging::log(shared, "warning", format!("Error writing to the AOF file: {}", e));
+                if FSYNC.load(Ordering::Relaxed) == FSYNC_ALWAYS {
+                    logging::log(shared, "warning", "Can't recover from AOF write error when the AOF fsync policy is 'always'. Exiting...");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+// Appends the command in the RESP protocol, as clients send it.
+pub fn encode(command: &[Arg], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+    for arg in command {
+        buf.extend_from_slice(format!("${}\r\n", arg.raw().len()).as_bytes());
+        buf.extend_from_slice(arg.raw());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+fn reply_ids(reply: &RESPValue) -> Vec<String> {
+    let entries = match reply {
+        RESPValue::Array(entries) => entries,
+        _ => return vec![]
+    };
+    entries.iter().filter_map(|entry| match entry {
+        RESPValue::BlobString(id) => Some(id),
+        RESPValue::Array(entry) => match entry.first() {
+            Some(RESPValue::BlobString(id)) => Some(id),
+            _ => None
+        },
+        _ => None
+    }).map(|id| String::from_utf8_lossy(id).into_owned()).collect()
+}
+
+// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-milliseconds]
+//     [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]
+// Claims the entries it did claim regardless of their idle time, keeping their delivery time.
+fn claimed(command: &[Arg], reply: &RESPValue) -> Option<Vec<Arg>> {
+    let ids = reply_ids(reply);
+    if ids.is_empty() {
+        return None;
+    }
+    let mut claim = vec![Arg::from("XCLAIM")];
+    claim.extend_from_slice(&command[1..4]);
+    claim.push(Arg::from("0"));
+    claim.extend(ids.into_iter().map(Arg::from));
+
+    let mut time = now_ms();
+    let options = command.iter().skip(5).position(|arg| matches!(arg.to_ascii_uppercase().as_str(),
+        "IDLE" | "TIME" | "RETRYCOUNT" | "FORCE" | "JUSTID" | "LASTID"));
+    let mut args = command[options.map_or(command.len(), |i| i + 5)..].iter();
+    while let Some(arg) = args.next() {
+        match arg.to_ascii_uppercase().as_str() {
+            "IDLE" => time = time.saturating_sub(args.next()?.parse().ok()?),
+            "TIME" => time = args.next()?.parse().ok()?,
+            "FORCE" | "JUSTID" => claim.push(arg.to_owned()),
+            _ => claim.extend([arg.to_owned(), args.next()?.to_owned()])
+        }
+    }
+    claim.extend([Arg::from("TIME"), Arg::from(time.to_string())]);
+    Some(claim)
+}
+
+// The commands redoing what the command did, the command itself unless it depends on when it runs.
+fn effects(command: &[Arg], reply: &RESPValue, shared: &SharedState) -> Vec<Vec<Arg>> {
+    let key = command.get(1).map_or("", |key| key.as_str());
+    match command[0].as_str() {
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            if !matches!(reply, RESPValue::Number(1)) {
+                return vec![];
+            }
+            // A time in the past deleted the key.
+            match shared.db.lock().expire_time(key) {
+                Some(at) => vec![vec![Arg::from("PEXPIREAT"), Arg::from(key), Arg::from(at.to_string())]],
+                None => vec![vec![Arg::from("DEL"), Arg::from(key)]]
+            }
+        },
+        "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => hash::expire_effects(command, reply, shared),
+        // SETEX key seconds value
+        "SETEX" | "PSETEX" => match shared.db.lock().expire_time(key) {
+            Some(at) => vec![
+                vec![Arg::from("SET"), Arg::from(key), command[3].to_owned()],
+                vec![Arg::from("PEXPIREAT"), Arg::from(key), Arg::from(at.to_string())],
+            ],
+            None => vec![]
+        },
+        "SETNX" if matches!(reply, RESPValue::Number(0)) => vec![],
+        // RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+        "RESTORE" if command[2] != "0" && !command[4..].iter().any(|arg| arg.eq_ignore_ascii_case("ABSTTL")) => {
+            // The TTL as the absolute time it was restored with, or deleted when in the past.
+            match shared.db.lock().expire_time(key) {
+                Some(at) => {
+                    let mut restore = command.to_vec();
+                    restore[2] = Arg::from(at.to_string());
+                    restore.push(Arg::from("ABSTTL"));
+                    vec![restore]
+                },
+                None => vec![vec![Arg::from("DEL"), Arg::from(key)]]
+            }
+        },
+        "XADD" => match reply {
+            RESPValue::BlobString(id) => {
+                let mut add = command.to_vec();
+                add[stream::xadd_id_index(command)] = Arg::from_bytes(id.clone());
+                vec![add]
+            },
+            // NOMKSTREAM and the stream doesn't exist.
+            _ => vec![]
+        },
+        "XCLAIM" => claimed(command, reply).into_iter().collect(),
+        // XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+        "XAUTOCLAIM" => {
+            let (claimed_ids, deleted) = match reply {
+                RESPValue::Array(reply) if reply.len() == 3 => (&reply[1], reply_ids(&reply[2])),
+                _ => return vec![]
+            };
+            // Claimed like XCLAIM with the same options would.
+            let mut claim = command[..5].to_vec();
+            if command[6..].iter().any(|arg| arg.eq_ignore_ascii_case("JUSTID")) {
+                claim.push(Arg::from("JUSTID"));
+            }
+            let mut effects: Vec<Vec<Arg>> = claimed(&claim, claimed_ids).into_iter().collect();
+            // Entries deleted from the stream were dropped from the pending entries.
+            if !deleted.is_empty() {
+                let mut ack = vec![Arg::from("XACK"), Arg::from(key), command[2].to_owned()];
+                ack.extend(deleted.into_iter().map(Arg::from));
+                effects.push(ack);
+            }
+            effects
+        },
+        // The keys it deleted were journaled on their own.
+        "MIGRATE" => vec![],
+        // Only SORT with STORE writes.
+        "SORT" if !command.iter().any(|arg| arg.eq_ignore_ascii_case("STORE")) => vec![],
+        _ => vec![command.to_vec()]
+    }
+}
+
+// Whether the command changes the dataset, and so has to be journaled.
+pub fn is_write(command: &[Arg], write_flag: bool) -> bool {
+    write_flag || (command[0] == "FUNCTION"
+        && command.get(1).is_some_and(|sub| matches!(sub.to_ascii_uppercase().as_str(), "LOAD" | "DELETE" | "FLUSH" | "RESTORE")))
+}
+
+// Whether writes are journaled, to the file, to the buffer of a rewrite or to replicas.
+pub fn journaling(shared: &SharedState) -> bool {
+    shared.aof.journaling() || shared.replication.streaming()
+}
+
+// Journals a write command that succeeded, with the reply it got.
+pub fn feed(command: &[Arg], reply: &RESPValue, shared: &SharedState) {
+    if journaling(shared) {
+        shared.aof.append(&effects(command, reply, shared), shared);
+    }
+}
+
+// Journals the deletion of keys that expired or were evicted.
+pub fn deleted(keys: &[String], shared: &SharedState) {
+    if journaling(shared) {
+        let commands: Vec<Vec<Arg>> = keys.iter().map(|key| vec![Arg::from("DEL"), Arg::from(key)]).collect();
+        shared.aof.append(&commands, shared);
+    }
+}
+
+// Journals the TTLs the server gave keys on its own, see expire::limit_ttls.
+pub fn expires_set(expires: &[(String, u64)], shared: &SharedState) {
+    if journaling(shared) && !expires.is_empty() {
+        let commands: Vec<Vec<Arg>> = expires.iter()
+            .map(|(key, at)| vec![Arg::from("PEXPIREAT"), Arg::from(key), Arg::from(at.to_string())]).collect();
+        shared.aof.append(&commands, shared);
+    }
+}
+
+// Journals the deletion of expired fields of a hash.
+pub fn fields_deleted(key: &str, fields: &[String], shared: &SharedState) {
+    if journaling(shared) {
+        let command = [Arg::from("HDEL"), Arg::from(key)].into_iter().chain(fields.iter().map(Arg::from)).collect();
+        shared.aof.append(&[command], shared);
+    }
+}
+
+// The file a rewrite is written to, in `dir`, before it replaces the append only file.
+pub fn rewrite_path() -> String {
+    format!("temp-rewriteaof-bg-{}.aof", std::process::id())
+}
+
+// BGREWRITEAOF
+pub fn bgrewriteaof(shared: &SharedState) -> Result<RESPValue, RESPError> {
+    if shared.aof.rewriting() {
+        return Err(RESPError::AofRewriteInProgress);
+    }
+    if snapshot::start_background_save(shared, Purpose::AofRewrite) {
+        return Ok(RESPValue::SimpleString(String::from("Background append only file rewriting started")));
+    }
+    shared.aof.state.lock().unwrap().rewrite_scheduled = true;
+    Ok(RESPValue::SimpleString(String::from("Background append only file rewriting scheduled")))
+}
+
+// Turns the file on or off at runtime. Turning it on rewrites it, as it has to start with the
+// whole keyspace, and appending to it starts once the rewrite is done.
+pub fn set_enabled(shared: &SharedState, enabled: bool) -> Result<(), String> {
+    let mut state = shared.aof.state.lock().unwrap();
+    // On startup the file is opened once it was replayed.
+    if !state.started {
+        return Ok(());
+    }
+    if !enabled {
+        if let Some(file) = state.file.take() {
+            let _ = file.sync_data();
+        }
+        state.unsynced = false;
+        return Ok(());
+    }
+    if state.file.is_some() || state.rewrite_buf.is_some() {
+        return Ok(());
+    }
+    drop(state);
+    if !snapshot::start_background_save(shared, Purpose::AofRewrite) {
+        shared.aof.state.lock().unwrap().rewrite_scheduled = true;
+    }
+    Ok(())
+}
+
+// Schedules a rewrite when the file isn't encrypted with the current key anymore.
+pub fn keys_changed(shared: &SharedState) {
+    let mut state = shared.aof.state.lock().unwrap();
+    if state.file.is_some() && !encryption::is_current(state.key.as_ref()) {
+        state.rewrite_scheduled = true;
+    }
+}
+
+// Whether a rewrite was scheduled, the file grew by `auto-aof-rewrite-percentage` since it was last
+// rewritten, or it was turned on and still waits to be written.
+pub fn rewrite_due(shared: &SharedState) -> bool {
+    let mut state = shared.aof.state.lock().unwrap();
+    if std::mem::take(&mut state.rewrite_scheduled) {
+        return true;
+    }
+    if !state.last_rewrite_ok && now_ms() / 1000 < state.last_rewrite_try + REWRITE_RETRY_DELAY {
+        return false;
+    }
+    if state.file.is_none() {
+        return state.started && shared.config.get_bool("appendonly");
+    }
+    let percentage = shared.config.get_int("auto-aof-rewrite-percentage") as u64;
+    let growth = state.size.saturating_sub(state.base_size) * 100 / state.base_size.max(1);
+    percentage > 0 && state.size >= shared.config.get_int("auto-aof-rewrite-min-size") as u64 && growth >= percentage
+}
+
+// Fsyncs the file once a second with `appendfsync everysec`, run by the cron. The fsync is made on a
+// blocking thread so that a slow disk doesn't hold commands up, and is skipped while the last one is
+// still in progress.
+pub fn fsync(shared: &Arc<SharedState>) {
+    if FSYNC.load(Ordering::Relaxed) != FSYNC_EVERYSEC || FSYNCING.load(Ordering::Relaxed) {
+        return;
+    }
+    let file = {
+        let mut state = shared.aof.state.lock().unwrap();
+        if !std::mem::take(&mut state.unsynced) {
+            return;
+        }
+        match state.file.as_ref().map(File::try_clone) {
+            Some(Ok(file)) => file,
+            _ => return
+        }
+    };
+    FSYNCING.store(true, Ordering::Relaxed);
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tokio::task::spawn_blocking(move || file.sync_data()).await.unwrap() {
+            logging::log(&shared, "warning", format!("Error fsyncing the AOF file: {}", e));
+        }
+        FSYNCING.store(false, Ordering::Relaxed);
+    });
+}
+
+// Parses the command starting at `at`, None when the file ends before it does. Replicas parse the
+// writes their primary streams with it as well.
+pub fn parse_command(data: &[u8], at: &mut usize) -> io::Result<Option<Vec<Arg>>> {
+    fn line(data: &[u8], at: &mut usize, prefix: u8) -> io::Result<Option<usize>> {
+        let end = match data[*at..].windows(2).position(|window| window == b"\r\n") {
+            Some(end) => *at + end,
+            None => return Ok(None)
+        };
+        let number = (data[*at] == prefix).then(|| std::str::from_utf8(&data[*at + 1..end]).ok()?.parse().ok()).flatten();
+        *at = end + 2;
+        number.map(Some).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad file format reading the append only file"))
+    }
+
+    let argc = match line(data, at, b'*')? {
+        Some(argc) => argc,
+        None => return Ok(None)
+    };
+    let mut command = Vec::with_capacity(argc);
+    for _ in 0..argc {
+        let len = match line(data, at, b'$')? {
+            Some(len) => len,
+            None => return Ok(None)
+        };
+        if data.len() < *at + len + 2 {
+            return Ok(None);
+        }
+        command.push(Arg::from_bytes(data[*at..*at + len].to_vec()));
+        *at += len + 2;
+    }
+    if command.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty command in the append only file"));
+    }
+    command[0] = Arg::from(command[0].to_ascii_uppercase());
+    Ok(Some(command))
+}
+
+// Replays the file on startup through `run`, returning the amount of commands replayed or None when
+// there is no file. A rewritten file starts with a snapshot, which is loaded first. A file cut short
+// by a crash (in the middle of a command or of a transaction) is truncated to its last complete
+// command when `aof-load-truncated` is on, and refused otherwise.
+pub fn load(shared: &SharedState, mut run: impl FnMut(Vec<Arg>) -> Result<(), RESPError>) -> io::Result<Option<usize>> {
+    let path = shared.config.get("appendfilename");
+    let file = match std::fs::read(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let decrypted = if encryption::is_encrypted(&file) { Some(encryption::decrypt(&file)?) } else { None };
+    let data = decrypted.as_ref().map_or(&file, |decrypted| &decrypted.data);
+
+    let mut replay = |command: Vec<Arg>| match run(command) {
+        Err(RESPError::UnsupportedCommand(name)) => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("unknown command '{}' reading the append only file", name))),
+        // Commands failing is part of what they did.
+        _ => Ok(())
+    };
+
+    let mut at = 0;
+    if snapshot::is_snapshot(data) {
+        let (keys, size) = snapshot::load_from(data, shared)?;
+        logging::log(shared, "notice", format!("Loaded the snapshot preamble of the append only file: {} keys", keys));
+        at = size;
+    }
+    let mut replayed = 0;
+    // Where the transaction being read started, and its commands.
+    let mut multi: Option<(usize, Vec<Vec<Arg>>)> = None;
+    let truncated_at = loop {
+        if at == data.len() {
+            break multi.as_ref().map(|(start, _)| *start);
+        }
+        let command_start = at;
+        let command = match parse_command(data, &mut at)? {
+            Some(command) => command,
+            None => break Some(multi.as_ref().map_or(command_start, |(start, _)| *start))
+        };
+        match command[0].as_str() {
+            "MULTI" => multi = Some((command_start, vec![])),
+            "EXEC" => {
+                let (_, commands) = multi.take().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "EXEC without MULTI in the append only file"))?;
+                for command in commands {
+                    replay(command)?;
+                    replayed += 1;
+                }
+            },
+            _ => match &mut multi {
+                Some((_, commands)) => commands.push(command),
+                None => {
+                    replay(command)?;
+                    replayed += 1;
+                }
+            }
+        }
+    };
+
+    // An encrypted file may also be cut short in the middle of a record.
+    if truncated_at.is_some() || decrypted.as_ref().is_some_and(|decrypted| !decrypted.is_complete()) {
+        if !shared.config.get_bool("aof-load-truncated") {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "the append only file is truncated, set aof-load-truncated to yes to load it anyway"));
+        }
+        let truncated_at = truncated_at.unwrap_or(data.len());
+        let size = match &decrypted {
+            Some(decrypted) => decrypted.truncate(&path, truncated_at)?,
+            None => {
+                OpenOptions::new().write(true).open(&path)?.set_len(truncated_at as u64)?;
+                truncated_at as u64
+            }
+        };
+        logging::log(shared, "warning", format!(
+            "!!! Warning: short read while loading the AOF file {}!!! AOF loaded anyway because aof-load-truncated is enabled, truncating it from {} to {} bytes",
+            path, file.len(), size));
+    }
+    Ok(Some(replayed))
+}
@@ -0,0 +1,115 @@
+// The arguments of commands. Most arguments are text (keys, options, numbers), but values, like the
+// one SET sets or the payload RESTORE restores, may be any bytes. An argument that isn't valid UTF-8
+// keeps its bytes for the commands taking values, which read them with `raw`, while its text has
+// the invalid sequences replaced. Such arguments are refused anywhere but where a value is expected
+// (see binary_safe), so commands never see that text.
+
+use std::fmt;
+use std::ops::Deref;
+
+use bytes::Bytes;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Arg {
+    text: String,
+    // The bytes of an argument that isn't valid UTF-8.
+    binary: Option<Bytes>,
+}
+
+impl Arg {
+    pub fn from_bytes(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        match std::str::from_utf8(&bytes) {
+            Ok(text) => Self { text: text.to_owned(), binary: None },
+            Err(_) => Self { text: String::from_utf8_lossy(&bytes).into_owned(), binary: Some(bytes) }
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.binary.is_some()
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        self.binary.as_deref().unwrap_or(self.text.as_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        self.binary.clone().unwrap_or_else(|| Bytes::copy_from_slice(self.text.as_bytes()))
+    }
+}
+
+impl Deref for Arg {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.text
+    }
+}
+
+impl From<String> for Arg {
+    fn from(text: String) -> Self {
+        Self { text, binary: None }
+    }
+}
+
+impl From<&str> for Arg {
+    fn from(text: &str) -> Self {
+        Self::from(text.to_owned())
+    }
+}
+
+impl From<&String> for Arg {
+    fn from(text: &String) -> Self {
+        Self::from(text.to_owned())
+    }
+}
+
+impl PartialEq<str> for Arg {
+    fn eq(&self, other: &str) -> bool {
+        self.binary.is_none() && self.text == other
+    }
+}
+
+impl PartialEq<&str> for Arg {
+    fn eq(&self, other: &&str) -> bool {
+        self.binary.is_none() && self.text == *other
+    }
+}
+
+impl PartialEq<String> for Arg {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl PartialEq<Arg> for String {
+    fn eq(&self, other: &Arg) -> bool {
+        other == self.as_str()
+    }
+}
+
+impl From<Arg> for Bytes {
+    fn from(arg: Arg) -> Self {
+        arg.binary.unwrap_or_else(|| Bytes::from(arg.text))
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Whether the argument at `index` of the command may be any bytes, as it's a value stored or
+// compared as bytes rather than read as text.
+pub fn binary_safe(command: &str, index: usize) -> bool {
+    match command {
+        "ECHO" => index == 1,
+        "SET" | "SETNX" | "GETSET" | "PUBLISH" | "SPUBLISH" => index == 2,
+        "SETEX" | "PSETEX" | "HSETNX" | "RESTORE" | "RESTORE-ASKING" => index == 3,
+        "HSET" | "HMSET" => index >= 3 && index % 2 == 1,
+        "PFADD" | "BF.ADD" | "BF.MADD" | "BF.INSERT" | "BF.EXISTS" | "BF.MEXISTS" | "CF.ADD" | "CF.ADDNX" | "CF.INSERT" | "CF.INSERTNX"
+            | "CF.EXISTS" | "CF.MEXISTS" | "CF.COUNT" | "CF.DEL" => index >= 2,
+        _ => false
+    }
+}
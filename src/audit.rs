@@ -0,0 +1,131 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::arg::Arg;
+
+enum Sink {
+    Stdout,
+    File { path: String, file: File, size: u64 },
+}
+
+// Records write and admin commands, along with who ran them and when.
+pub struct AuditLog {
+    sink: Option<Sink>,
+    // The file is rotated once it grows beyond this many bytes, 0 never rotates.
+    pub max_size: u64,
+    // Rotated files kept around as `<path>.1` (the newest) up to `<path>.<max_files>`.
+    pub max_files: u64,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self { sink: None, max_size: 0, max_files: 5 }
+    }
+}
+
+impl AuditLog {
+    // An empty target disables the log, `stdout` writes to the standard output, and anything else
+    // is a path of a file to append to.
+    pub fn set_target(&mut self, target: &str) -> io::Result<()> {
+        self.sink = match target {
+            "" => None,
+            "stdout" => Some(Sink::Stdout),
+            path => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let size = file.metadata()?.len();
+                Some(Sink::File { path: path.to_owned(), file, size })
+            }
+        };
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub fn record(&mut self, addr: &str, user: &str, command: &[Arg]) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let line = format!("{}.{:06} [{}] {} {}\n", now.as_secs(), now.subsec_micros(), addr, user, quote_command(command));
+        self.write(line.as_bytes())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match &mut self.sink {
+            Some(Sink::Stdout) => io::stdout().write_all(line),
+            Some(Sink::File { file, size, .. }) => {
+                file.write_all(line)?;
+                *size += line.len() as u64;
+                if self.max_size > 0 && *size >= self.max_size {
+                    self.rotate()?;
+                }
+                Ok(())
+            },
+            None => Ok(())
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = match &self.sink {
+            Some(Sink::File { path, .. }) => path.clone(),
+            _ => return Ok(())
+        };
+
+        if self.max_files == 0 {
+            std::fs::remove_file(&path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = format!("{}.{}", path, i);
+                if std::fs::metadata(&from).is_ok() {
+                    std::fs::rename(&from, format!("{}.{}", path, i + 1))?;
+                }
+            }
+            std::fs::rename(&path, format!("{}.1", path))?;
+        }
+        self.set_target(&path)
+    }
+}
+
+// Passwords given to AUTH, CONFIG SET requirepass and ACL SETUSER are kept out of the log.
+fn is_secret(command: &[Arg], i: usize) -> bool {
+    let subcommand = command.get(1).map(|arg| arg.to_ascii_uppercase());
+    match (command[0].as_str(), subcommand.as_deref()) {
+        ("AUTH", _) => i > 0,
+        ("CONFIG", Some("SET")) => i == 3 && command[2].eq_ignore_ascii_case("requirepass"),
+        ("ACL", Some("SETUSER")) => i > 2 && (command[i].starts_with('>') || command[i].starts_with('<')),
+        _ => false
+    }
+}
+
+// The quoted arguments of a command, with passwords redacted, as logged and shown by MONITOR.
+pub fn quote_command(command: &[Arg]) -> String {
+    command.iter().enumerate()
+        .map(|(i, arg)| if is_secret(command, i) { String::from("\"(redacted)\"") } else { quote(arg.raw()) })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Quotes an argument the way MONITOR does, escaping anything that isn't printable (and the bytes
+// that aren't UTF-8).
+pub fn quote(arg: &[u8]) -> String {
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for chunk in arg.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+                c => quoted.push(c)
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
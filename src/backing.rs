@@ -0,0 +1,189 @@
+// A backing store, turning the server into a near cache in front of a primary store. With one,
+// commands that access string keys missing from the keyspace fetch them from the store first
+// (read-through), and once a write ran the keys it wrote are forwarded to the store, their string
+// value being set there and the ones that no longer exist deleted (write-through). Values of the
+// other types stay local, as do expirations, evictions and the commands run by transactions and
+// scripts. When the store fails, the command is replied to with the error, a write having already
+// been applied locally though.
+//
+// The store is either another RESP server, the `backing-upstream` address, or a Backing of the
+// application embedding the server (see Builder::backing).
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::protocol::{encode, parse, RESPValue};
+use crate::{command_keys, lookup_command, RESPError, SharedState};
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A primary store the server caches, set with [`Builder::backing`](crate::Builder::backing). Its
+/// methods are called on blocking threads, so they may block.
+pub trait Backing: Send + Sync {
+    /// The value of the key, None when the store doesn't have it.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Sets the value of the key.
+    fn set(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    /// Deletes the key, which may not exist.
+    fn del(&self, key: &str) -> io::Result<()>;
+}
+
+struct Connection {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl Connection {
+    fn request(&mut self, command: &[&[u8]]) -> io::Result<RESPValue> {
+        let mut request = BytesMut::new();
+        encode(RESPValue::Array(command.iter().map(|arg| RESPValue::BlobString(arg.to_vec().into())).collect()), &mut request);
+        self.stream.write_all(&request)?;
+        loop {
+            if let Some((reply, used)) = parse(&self.buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+                self.buf.advance(used);
+                return match reply {
+                    RESPValue::SimpleError(e) | RESPValue::BlobError(e) => Err(io::Error::other(String::from_utf8_lossy(&e).into_owned())),
+                    reply => Ok(reply)
+                };
+            }
+            let mut chunk = [0; 16 * 1024];
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the upstream closed the connection")),
+                read => self.buf.extend_from_slice(&chunk[..read])
+            }
+        }
+    }
+}
+
+// Another RESP server, the `backing-upstream` address, over a connection requests take turns on.
+struct Upstream {
+    address: String,
+    password: String,
+    // None until connected, and again once the connection broke.
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Upstream {
+    fn connect(&self) -> io::Result<Connection> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream, buf: BytesMut::new() };
+        if !self.password.is_empty() {
+            connection.request(&[b"AUTH", self.password.as_bytes()])?;
+        }
+        Ok(connection)
+    }
+
+    fn request(&self, command: &[&[u8]]) -> io::Result<RESPValue> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        let result = connection.as_mut().unwrap().request(command);
+        // The stream may be in the middle of a reply, so it can't be used anymore.
+        if result.as_ref().is_err_and(|e| e.kind() != io::ErrorKind::Other) {
+            *connection = None;
+        }
+        result
+    }
+}
+
+impl Backing for Upstream {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.request(&[b"GET", key.as_bytes()])? {
+            RESPValue::BlobString(value) => Ok(Some(value.to_vec())),
+            RESPValue::Null => Ok(None),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply to GET"))
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.request(&[b"SET", key.as_bytes(), value]).map(|_| ())
+    }
+
+    fn del(&self, key: &str) -> io::Result<()> {
+        self.request(&[b"DEL", key.as_bytes()]).map(|_| ())
+    }
+}
+
+// The backing store of the configuration, None without `backing-upstream`.
+pub fn configured(shared: &SharedState) -> Option<Arc<dyn Backing>> {
+    let address = shared.config.get("backing-upstream");
+    if address.is_empty() {
+        return None;
+    }
+    let password = shared.config.get("backing-upstream-password");
+    Some(Arc::new(Upstream { address, password, connection: Mutex::new(None) }))
+}
+
+fn keys(command: &[Arg], shared: &SharedState) -> Vec<String> {
+    lookup_command(&command[0], shared).map_or_else(Vec::new, |spec| command_keys(spec, command).into_iter().map(|key| key.to_string()).collect())
+}
+
+async fn blocking<T: Send + 'static>(backing: &Arc<dyn Backing>, f: impl FnOnce(&dyn Backing) -> io::Result<T> + Send + 'static) -> Result<T, RESPError> {
+    let backing = backing.clone();
+    tokio::task::spawn_blocking(move || f(&*backing)).await.unwrap().map_err(|e| RESPError::BackingStore(e.to_string()))
+}
+
+// Fetches the keys of the command that are missing from the keyspace from the backing store.
+pub async fn read_through(command: &[Arg], shared: &SharedState) -> Result<(), RESPError> {
+    let Some(backing) = &shared.backing else {
+        return Ok(());
+    };
+    let missing: Vec<String> = {
+        let db = shared.db.lock();
+        keys(command, shared).into_iter().filter(|key| !db.contains_key(key) || db.is_expired(key)).collect()
+    };
+    for key in missing {
+        let fetched = {
+            let key = key.clone();
+            blocking(backing, move |backing| backing.get(&key)).await?
+        };
+        let Some(value) = fetched else {
+            continue;
+        };
+        let mut db = shared.db.lock();
+        // Another client may have written it meanwhile.
+        if !db.contains_key(&key) || db.is_expired(&key) {
+            db.set(key, Value::string(value));
+        }
+    }
+    Ok(())
+}
+
+// Forwards the keys the write command wrote to the backing store.
+pub async fn write_through(command: &[Arg], shared: &SharedState) -> Result<(), RESPError> {
+    let Some(backing) = &shared.backing else {
+        return Ok(());
+    };
+    if !lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("write")) {
+        return Ok(());
+    }
+    for key in keys(command, shared) {
+        let value = {
+            let db = shared.db.lock();
+            match db.get(&key) {
+                Some(_) if db.is_expired(&key) => None,
+                Some(value @ (Value::String(_) | Value::CompressedString(_))) => Some(value.as_string().unwrap()),
+                // Only strings are kept in the store.
+                Some(_) => continue,
+                None => None
+            }
+        };
+        match value {
+            Some(value) => blocking(backing, move |backing| backing.set(&key, &value)).await?,
+            None => blocking(backing, move |backing| backing.del(&key)).await?
+        }
+    }
+    Ok(())
+}
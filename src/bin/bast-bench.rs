@@ -0,0 +1,276 @@
+// The benchmark: drives a mix of GET, SET and INCR from many connections at once, each pipelining
+// its requests, and reports the throughput and the latency percentiles, overall and per command. A
+// request's latency is from its pipeline being sent until its reply arrived. Error replies are
+// counted rather than failing the run.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bast::protocol::{RESPCodec, RESPValue};
+use bytes::Bytes;
+use clap::{ArgAction, Parser};
+use futures::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+#[derive(Parser)]
+#[command(version, about = "Benchmarks a bast server", disable_help_flag = true)]
+struct Args {
+    /// The host of the server
+    #[arg(short = 'h', long, default_value = "127.0.0.1")]
+    host: String,
+    /// The port of the server
+    #[arg(short, long, default_value_t = 6379)]
+    port: u16,
+    /// The user to authenticate as, with the password
+    #[arg(long)]
+    user: Option<String>,
+    /// The password to authenticate with
+    #[arg(short = 'a', long)]
+    pass: Option<String>,
+    /// The connections sending requests at once
+    #[arg(short, long, default_value_t = 50)]
+    clients: usize,
+    /// The requests to send in total
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    requests: usize,
+    /// The requests each connection sends before waiting for their replies
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+    /// The size of the values SET, in bytes
+    #[arg(short = 'd', long, default_value_t = 3)]
+    data_size: usize,
+    /// The keys used, picked at random, a single key when 0
+    #[arg(short = 'r', long, default_value_t = 0)]
+    keyspace: u64,
+    /// The commands sent (get, set and incr) with the weight of each, like get:80,set:20
+    #[arg(short = 't', long, default_value = "set,get")]
+    mix: String,
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Get,
+    Set,
+    Incr,
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Get => "GET",
+            Command::Set => "SET",
+            Command::Incr => "INCR",
+        }
+    }
+}
+
+// The commands along with the sum of the weights up to each, to pick them at random by weight.
+struct Mix(Vec<(Command, u32)>);
+
+impl Mix {
+    // Parses the --mix argument, like get:80,set:20 (a missing weight being 1).
+    fn parse(mix: &str) -> Result<Self, String> {
+        let mut commands = Vec::new();
+        let mut total = 0;
+        for part in mix.split(',') {
+            let (name, weight) = part.split_once(':').unwrap_or((part, "1"));
+            let command = match name.trim().to_ascii_lowercase().as_str() {
+                "get" => Command::Get,
+                "set" => Command::Set,
+                "incr" => Command::Incr,
+                _ => return Err(format!("Unsupported command '{}', the mix is of get, set and incr", name))
+            };
+            let weight: u32 = weight.trim().parse().map_err(|_| format!("Invalid weight '{}'", weight))?;
+            total += weight;
+            commands.push((command, total));
+        }
+        if total == 0 {
+            return Err(String::from("The mix has no weight"));
+        }
+        Ok(Self(commands))
+    }
+
+    fn commands(&self) -> impl Iterator<Item = Command> + '_ {
+        self.0.iter().map(|(command, _)| *command)
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> Command {
+        let total = self.0.last().unwrap().1;
+        let n = rng.gen_range(0..total);
+        self.0.iter().find(|(_, sum)| n < *sum).unwrap().0
+    }
+}
+
+struct Workload {
+    mix: Mix,
+    pipeline: usize,
+    keyspace: u64,
+    value: Bytes,
+    // The requests left to send, claimed a pipeline at a time by the connections.
+    remaining: AtomicUsize,
+}
+
+impl Workload {
+    fn claim(&self) -> usize {
+        let claimed = self.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| Some(remaining.saturating_sub(self.pipeline)));
+        claimed.unwrap().min(self.pipeline)
+    }
+
+    // Strings are SET and read on keys of their own, so INCR never finds a value that isn't a number.
+    fn request(&self, command: Command, rng: &mut impl Rng) -> RESPValue {
+        let prefix = match command {
+            Command::Incr => "counter",
+            _ => "key",
+        };
+        let key = match self.keyspace {
+            0 => String::from(prefix),
+            keyspace => format!("{}:{:012}", prefix, rng.gen_range(0..keyspace)),
+        };
+        let mut args = vec![RESPValue::BlobString(command.name().into()), RESPValue::BlobString(key.into())];
+        if command == Command::Set {
+            args.push(RESPValue::BlobString(self.value.clone()));
+        }
+        RESPValue::Array(args)
+    }
+}
+
+// The latencies of the requests of a command, in microseconds.
+#[derive(Default)]
+struct Latencies {
+    samples: Vec<u32>,
+    errors: usize,
+}
+
+impl Latencies {
+    fn merge(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.errors += other.errors;
+    }
+
+    // Sorted, to take percentiles.
+    fn report(&mut self, name: &str) {
+        self.samples.sort_unstable();
+        let Some(max) = self.samples.last() else {
+            return;
+        };
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * self.samples.len() as f64).ceil() as usize;
+            self.samples[rank.clamp(1, self.samples.len()) - 1] as f64 / 1000.0
+        };
+        let avg = self.samples.iter().map(|&sample| sample as f64).sum::<f64>() / self.samples.len() as f64 / 1000.0;
+        println!(
+            "{:<6} {:>10} {:>8} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3}",
+            name, self.samples.len(), self.errors, avg, self.samples[0] as f64 / 1000.0,
+            percentile(50.0), percentile(95.0), percentile(99.0), percentile(99.9), *max as f64 / 1000.0);
+    }
+}
+
+type Connection = Framed<TcpStream, RESPCodec>;
+
+async fn connect(args: &Args) -> Result<Connection, Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect((args.host.as_str(), args.port)).await?;
+    stream.set_nodelay(true)?;
+    let mut connection = Framed::new(stream, RESPCodec::default());
+
+    if let Some(pass) = &args.pass {
+        let mut auth = vec![RESPValue::BlobString("AUTH".into())];
+        auth.extend(args.user.clone().map(|user| RESPValue::BlobString(user.into())));
+        auth.push(RESPValue::BlobString(pass.clone().into()));
+        connection.send(RESPValue::Array(auth)).await?;
+        match connection.next().await.transpose()? {
+            Some(RESPValue::SimpleError(e)) => return Err(format!("AUTH failed: {}", String::from_utf8_lossy(&e)).into()),
+            Some(_) => {},
+            None => return Err("Server closed the connection".into())
+        }
+    }
+    Ok(connection)
+}
+
+// Sends pipelines until the requests run out, returning the latencies of each command of the mix.
+async fn drive(mut connection: Connection, workload: Arc<Workload>) -> Result<Vec<Latencies>, String> {
+    let commands: Vec<Command> = workload.mix.commands().collect();
+    let mut latencies: Vec<Latencies> = commands.iter().map(|_| Latencies::default()).collect();
+    let mut batch = Vec::with_capacity(workload.pipeline);
+    let mut rng = StdRng::from_entropy();
+    loop {
+        let claimed = workload.claim();
+        if claimed == 0 {
+            return Ok(latencies);
+        }
+
+        batch.clear();
+        for _ in 0..claimed {
+            let command = workload.mix.pick(&mut rng);
+            connection.feed(workload.request(command, &mut rng)).await.map_err(|e| e.to_string())?;
+            batch.push(command);
+        }
+        let sent = Instant::now();
+        connection.flush().await.map_err(|e| e.to_string())?;
+
+        for command in &batch {
+            let reply = connection.next().await.ok_or("Server closed the connection")?.map_err(|e| e.to_string())?;
+            let latency = &mut latencies[commands.iter().position(|c| c == command).unwrap()];
+            latency.samples.push(sent.elapsed().as_micros().min(u32::MAX as u128) as u32);
+            if reply.as_simple_error().is_some() {
+                latency.errors += 1;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    if args.clients == 0 || args.pipeline == 0 {
+        return Err("There must be at least one client and a pipeline of at least one request".into());
+    }
+    let workload = Arc::new(Workload {
+        mix: Mix::parse(&args.mix)?,
+        pipeline: args.pipeline,
+        keyspace: args.keyspace,
+        value: Bytes::from(vec![b'x'; args.data_size]),
+        remaining: AtomicUsize::new(args.requests),
+    });
+
+    // Connecting isn't part of the measure.
+    let mut connections = Vec::with_capacity(args.clients);
+    for _ in 0..args.clients {
+        connections.push(connect(&args).await.map_err(|e| format!("Could not connect to bast at {}:{}: {}", args.host, args.port, e))?);
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = connections.into_iter().map(|connection| tokio::spawn(drive(connection, workload.clone()))).collect();
+    let mut latencies: Vec<Latencies> = workload.mix.commands().map(|_| Latencies::default()).collect();
+    for task in tasks {
+        for (total, connection) in latencies.iter_mut().zip(task.await??) {
+            total.merge(connection);
+        }
+    }
+    let elapsed = start.elapsed().max(Duration::from_micros(1));
+
+    let sent: usize = latencies.iter().map(|latencies| latencies.samples.len()).sum();
+    println!(
+        "{} requests in {:.2} seconds: {} connections, a pipeline of {}, {} byte values, {} keys",
+        sent, elapsed.as_secs_f64(), args.clients, args.pipeline, args.data_size, args.keyspace.max(1));
+    println!("throughput: {:.2} requests per second", sent as f64 / elapsed.as_secs_f64());
+    println!();
+    println!(
+        "{:<6} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "", "requests", "errors", "avg ms", "min ms", "p50 ms", "p95 ms", "p99 ms", "p99.9 ms", "max ms");
+    let mut all = Latencies::default();
+    for (command, mut latencies) in workload.mix.commands().zip(latencies) {
+        latencies.report(command.name());
+        all.merge(latencies);
+    }
+    if workload.mix.commands().count() > 1 {
+        all.report("all");
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+// The command line client: runs a single command given as its arguments (`bast-cli SET k v`), or
+// reads commands in a prompt with line editing and history. Replies are printed the way the
+// protocol formats them, or raw (their contents alone, one per line) like redis-cli --raw, which is
+// the default when the output isn't a terminal.
+
+use std::io::{IsTerminal, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+
+use bast::protocol::{encode, parse, RESPValue};
+use bytes::{Buf, BytesMut};
+use clap::{ArgAction, Parser};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+// Commands after which the server keeps sending messages, printed until the connection closes.
+const STREAMING_COMMANDS: &[&str] = &["SUBSCRIBE", "PSUBSCRIBE", "SSUBSCRIBE", "MONITOR"];
+
+const HISTORY_FILE: &str = ".bastcli_history";
+
+#[derive(Parser)]
+#[command(version, about = "The command line client of bast", disable_help_flag = true)]
+struct Args {
+    /// The host of the server
+    #[arg(short = 'h', long, default_value = "127.0.0.1")]
+    host: String,
+    /// The port of the server
+    #[arg(short, long, default_value_t = 6379)]
+    port: u16,
+    /// A unix socket to connect to, rather than the host and port
+    #[arg(short, long)]
+    socket: Option<String>,
+    /// The user to authenticate as, with the password
+    #[arg(long)]
+    user: Option<String>,
+    /// The password to authenticate with
+    #[arg(short = 'a', long)]
+    pass: Option<String>,
+    /// Prints the contents of replies alone, the default when the output isn't a terminal
+    #[arg(long, conflicts_with = "no_raw")]
+    raw: bool,
+    /// Prints replies formatted, even when the output isn't a terminal
+    #[arg(long)]
+    no_raw: bool,
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+    /// The command to run, a prompt being opened when there's none
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+struct Connection {
+    args: Args,
+    // None until connected, and again once the connection broke, to reconnect on the next command.
+    stream: Option<Box<dyn Stream>>,
+    buf: BytesMut,
+}
+
+impl Connection {
+    fn new(args: Args) -> Self {
+        Self { args, stream: None, buf: BytesMut::new() }
+    }
+
+    fn address(&self) -> String {
+        match &self.args.socket {
+            Some(path) => path.clone(),
+            None => format!("{}:{}", self.args.host, self.args.port)
+        }
+    }
+
+    fn connect(&mut self) -> std::io::Result<()> {
+        let stream: Box<dyn Stream> = match &self.args.socket {
+            Some(path) => Box::new(UnixStream::connect(path)?),
+            None => Box::new(TcpStream::connect((self.args.host.as_str(), self.args.port))?)
+        };
+        self.stream = Some(stream);
+        self.buf.clear();
+
+        if let Some(pass) = self.args.pass.clone() {
+            let mut auth = vec![b"AUTH".to_vec()];
+            auth.extend(self.args.user.clone().map(String::into_bytes));
+            auth.push(pass.into_bytes());
+            if let RESPValue::SimpleError(e) = self.request(&auth)? {
+                eprintln!("AUTH failed: {}", String::from_utf8_lossy(&e));
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, command: &[Vec<u8>]) -> std::io::Result<RESPValue> {
+        if self.stream.is_none() {
+            self.connect()?;
+        }
+        let mut request = BytesMut::new();
+        encode(RESPValue::Array(command.iter().map(|arg| RESPValue::BlobString(arg.clone().into())).collect()), &mut request);
+        if let Err(e) = self.stream.as_mut().unwrap().write_all(&request) {
+            self.stream = None;
+            return Err(e);
+        }
+        self.reply()
+    }
+
+    fn reply(&mut self) -> std::io::Result<RESPValue> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        };
+        loop {
+            match parse(&self.buf) {
+                Ok(Some((value, used))) => {
+                    self.buf.advance(used);
+                    return Ok(value);
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    self.stream = None;
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+                }
+            }
+
+            let mut chunk = [0; 16 * 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.stream = None;
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Server closed the connection"));
+                },
+                Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+                Err(e) => {
+                    self.stream = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// The contents of the reply alone, like redis-cli --raw prints them.
+fn write_raw(value: &RESPValue, out: &mut Vec<u8>) {
+    match value {
+        RESPValue::BlobString(s) | RESPValue::BlobError(s) | RESPValue::SimpleError(s) => out.extend_from_slice(s),
+        RESPValue::SimpleString(s) => out.extend_from_slice(s.as_bytes()),
+        RESPValue::Number(n) => out.extend_from_slice(n.to_string().as_bytes()),
+        RESPValue::Double(d) => out.extend_from_slice(d.to_string().as_bytes()),
+        RESPValue::Boolean(b) => out.extend_from_slice(if *b { b"1" } else { b"0" }),
+        RESPValue::Null => {},
+        RESPValue::Array(values) | RESPValue::Set(values) | RESPValue::Push(values) => {
+            for v in values {
+                write_raw(v, out);
+            }
+            return;
+        },
+        RESPValue::Map(entries) => {
+            for (key, v) in entries {
+                out.extend_from_slice(key);
+                out.push(b'\n');
+                write_raw(v, out);
+            }
+            return;
+        }
+    }
+    out.push(b'\n');
+}
+
+fn print(value: &RESPValue, raw: bool) {
+    let mut out = Vec::new();
+    if raw {
+        write_raw(value, &mut out);
+    } else {
+        out.extend_from_slice(value.to_string().as_bytes());
+    }
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(&out).and_then(|_| stdout.flush());
+}
+
+// Splits a line of the prompt into arguments like redis-cli does: by spaces, unless quoted. Double
+// quotes support the escapes \n, \r, \t, \b, \a, \xHH and a backslash before any other character,
+// single quotes only \'. None when a quote isn't closed, or isn't followed by a space.
+fn split_args(line: &str) -> Option<Vec<Vec<u8>>> {
+    let line = line.as_bytes();
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                loop {
+                    match (line.get(i).copied()?, line.get(i + 1).copied()) {
+                        (c, _) if c == quote => break,
+                        (b'\\', Some(b'x')) if quote == b'"' => {
+                            let hex = line.get(i + 2..i + 4).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+                            match hex {
+                                Some(byte) => {
+                                    arg.push(byte);
+                                    i += 3;
+                                },
+                                None => arg.push(b'\\')
+                            }
+                        },
+                        (b'\\', Some(escaped)) if quote == b'"' => {
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                c => c
+                            });
+                            i += 1;
+                        },
+                        (b'\\', Some(b'\'')) => {
+                            arg.push(b'\'');
+                            i += 1;
+                        },
+                        (c, _) => arg.push(c)
+                    }
+                    i += 1;
+                }
+                i += 1;
+                if line.get(i).is_some_and(|c| !c.is_ascii_whitespace()) {
+                    return None;
+                }
+            },
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Runs the command, printing its reply (and the messages after it for the streaming commands).
+// Whether it didn't fail.
+fn run(connection: &mut Connection, command: &[Vec<u8>], raw: bool) -> bool {
+    let reply = match connection.request(command) {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("Could not connect to bast at {}: {}", connection.address(), e);
+            return false;
+        }
+    };
+    print(&reply, raw);
+    if reply.as_simple_error().is_some() {
+        return false;
+    }
+
+    let name = String::from_utf8_lossy(&command[0]).to_ascii_uppercase();
+    if STREAMING_COMMANDS.contains(&name.as_str()) {
+        if !raw {
+            eprintln!("Reading messages... (press Ctrl-C to quit)");
+        }
+        while let Ok(message) = connection.reply() {
+            print(&message, raw);
+        }
+    }
+    true
+}
+
+fn repl(connection: &mut Connection, raw: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut editor = DefaultEditor::new()?;
+    let history = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    // Connecting right away tells whether the server is there, the prompt reconnecting later.
+    if let Err(e) = connection.connect() {
+        eprintln!("Could not connect to bast at {}: {}", connection.address(), e);
+    }
+
+    loop {
+        let prompt = match connection.stream {
+            Some(_) => format!("{}> ", connection.address()),
+            None => String::from("not connected> ")
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into())
+        };
+        let Some(command) = split_args(&line) else {
+            eprintln!("Invalid argument(s)");
+            continue;
+        };
+        if command.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if command.len() == 1 && (command[0].eq_ignore_ascii_case(b"quit") || command[0].eq_ignore_ascii_case(b"exit")) {
+            break;
+        }
+        run(connection, &command, raw);
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let raw = args.raw || (!args.no_raw && !std::io::stdout().is_terminal());
+    let command: Vec<Vec<u8>> = args.command.iter().map(|arg| arg.clone().into_bytes()).collect();
+    let mut connection = Connection::new(args);
+
+    if command.is_empty() {
+        repl(&mut connection, raw)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    Ok(match run(&mut connection, &command, raw) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE
+    })
+}
@@ -0,0 +1,426 @@
+// Bit level operations on string values. Bits are numbered from the most significant bit of the
+// first byte, and strings are zero padded as needed when written past their end.
+
+use bytes::Bytes;
+
+use crate::arg::Arg;
+use crate::db::{Db, StringMut, Value};
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC, NOTIFY_STRING};
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+// Like Redis, strings are limited to 512MB.
+const MAX_BIT_OFFSET: i64 = (512 * 1024 * 1024 * 8) - 1;
+
+fn parse_bit_offset(arg: &str) -> Result<usize, RESPError> {
+    match arg.parse::<i64>() {
+        Ok(offset) if (0..=MAX_BIT_OFFSET).contains(&offset) => Ok(offset as usize),
+        _ => Err(RESPError::InvalidBitOffset)
+    }
+}
+
+fn parse_bit(arg: &str) -> Result<bool, RESPError> {
+    match arg {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(RESPError::InvalidBit)
+    }
+}
+
+fn get_bit(bytes: &[u8], offset: usize) -> bool {
+    bytes.get(offset / 8).is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+// Counts the set bits between the two offsets, inclusive.
+fn count_bits(bytes: &[u8], start: usize, end: usize) -> usize {
+    let (first, last) = (start / 8, end / 8);
+    if first == last {
+        return (start..=end).filter(|offset| get_bit(bytes, *offset)).count();
+    }
+
+    let head = (start..first * 8 + 8).filter(|offset| get_bit(bytes, *offset)).count();
+    let middle: usize = bytes[first + 1..last].iter().map(|byte| byte.count_ones() as usize).sum();
+    let tail = (last * 8..=end).filter(|offset| get_bit(bytes, *offset)).count();
+    head + middle + tail
+}
+
+// The string at `key` for modification, created empty if missing.
+fn string_mut<'a>(db: &'a mut Db, key: &str) -> Result<StringMut<'a>, RESPError> {
+    db.get(key).map(Value::as_string).transpose()?;
+    db.get_or_insert_with(key, || Value::string(Bytes::new())).as_string_mut()
+}
+
+// Resolves a range given by possibly negative indices into `len` units, None if it's empty.
+fn normalize_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+    (len > 0 && start <= end).then_some((start as usize, end as usize))
+}
+
+// Parses the optional `start end [BYTE|BIT]` arguments into a range of bits within `bytes`. The
+// outer None means no range was given.
+fn parse_bit_range(args: &[Arg], bytes: &[u8], end_optional: bool) -> Result<Option<Option<(usize, usize)>>, RESPError> {
+    let (start, end, unit) = match args {
+        [] => return Ok(None),
+        [start] if end_optional => (start, None, None),
+        [start, end] => (start, Some(end), None),
+        [start, end, unit] => (start, Some(end), Some(unit.to_ascii_uppercase())),
+        _ => return Err(RESPError::SyntaxError)
+    };
+
+    let start = parse_number(start)?;
+    let end = end.map(|end| parse_number(end)).transpose()?.unwrap_or(-1);
+    let range = match unit.as_deref() {
+        None | Some("BYTE") => normalize_range(start, end, bytes.len()).map(|(start, end)| (start * 8, end * 8 + 7)),
+        Some("BIT") => normalize_range(start, end, bytes.len() * 8),
+        _ => return Err(RESPError::SyntaxError)
+    };
+    Ok(Some(range))
+}
+
+// SETBIT key offset value
+pub fn setbit(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let offset = parse_bit_offset(&command[2])?;
+    let bit = parse_bit(&command[3])?;
+
+    let old = {
+        let mut db = shared.db.lock();
+        let mut bytes = string_mut(&mut db, key)?;
+        if bytes.len() <= offset / 8 {
+            bytes.resize(offset / 8 + 1, 0);
+        }
+
+        let mask = 0x80 >> (offset % 8);
+        let old = bytes[offset / 8] & mask != 0;
+        if bit {
+            bytes[offset / 8] |= mask;
+        } else {
+            bytes[offset / 8] &= !mask;
+        }
+        old
+    };
+
+    notify_keyspace_event(shared, NOTIFY_STRING, "setbit", key, 0);
+    Ok(RESPValue::Number(old as i64))
+}
+
+// GETBIT key offset
+pub fn getbit(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let offset = parse_bit_offset(&command[2])?;
+
+    let db = shared.db.lock();
+    let bit = match db.get(command[1].as_str()) {
+        Some(value) => get_bit(&value.as_string()?, offset),
+        None => false
+    };
+    Ok(RESPValue::Number(bit as i64))
+}
+
+// BITCOUNT key [start end [BYTE|BIT]]
+pub fn bitcount(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let bytes = match db.get(command[1].as_str()) {
+        Some(value) => value.as_string()?,
+        None => Bytes::new()
+    };
+
+    let count = match parse_bit_range(&command[2..], &bytes, false)? {
+        None => bytes.iter().map(|byte| byte.count_ones() as usize).sum(),
+        Some(None) => 0,
+        Some(Some((start, end))) => count_bits(&bytes, start, end)
+    };
+    Ok(RESPValue::Number(count as i64))
+}
+
+// BITPOS key bit [start [end [BYTE|BIT]]]
+pub fn bitpos(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let bit = parse_bit(&command[2])?;
+
+    let db = shared.db.lock();
+    let bytes = match db.get(command[1].as_str()) {
+        Some(value) => value.as_string()?,
+        None => return Ok(RESPValue::Number(if bit { -1 } else { 0 }))
+    };
+
+    let end_given = command.len() > 4;
+    let (start, end) = match parse_bit_range(&command[3..], &bytes, true)? {
+        None => match bytes.len() {
+            0 => return Ok(RESPValue::Number(-1)),
+            len => (0, len * 8 - 1)
+        },
+        Some(None) => return Ok(RESPValue::Number(-1)),
+        Some(Some(range)) => range
+    };
+
+    let mut offset = start;
+    while offset <= end {
+        // Skip whole bytes that can't contain the bit.
+        let byte = bytes[offset / 8];
+        if offset % 8 == 0 && offset + 7 <= end && byte == if bit { 0x00 } else { 0xff } {
+            offset += 8;
+            continue;
+        }
+        if get_bit(&bytes, offset) == bit {
+            return Ok(RESPValue::Number(offset as i64));
+        }
+        offset += 1;
+    }
+
+    // When looking for a clear bit without an explicit end, the string is considered padded with
+    // zeros to the right.
+    Ok(RESPValue::Number(if !bit && !end_given { end as i64 + 1 } else { -1 }))
+}
+
+// BITOP AND|OR|XOR|NOT destkey key [key ...]
+pub fn bitop(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let operation = command[1].to_ascii_uppercase();
+    let destination = command[2].as_str();
+    let keys = &command[3..];
+
+    let op: Option<fn(u8, u8) -> u8> = match operation.as_str() {
+        "AND" => Some(|a, b| a & b),
+        "OR" => Some(|a, b| a | b),
+        "XOR" => Some(|a, b| a ^ b),
+        "NOT" if keys.len() != 1 => return Err(RESPError::BitopNotSingleKey),
+        "NOT" => None,
+        _ => return Err(RESPError::SyntaxError)
+    };
+
+    let (len, deleted) = {
+        let mut db = shared.db.lock();
+        let sources = keys.iter()
+            .map(|key| db.get(key).map_or(Ok(Bytes::new()), Value::as_string))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Missing bytes of shorter strings count as zeros.
+        let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+        let result: Vec<u8> = (0..len).map(|i| {
+            let byte = |source: &Bytes| source.get(i).copied().unwrap_or(0);
+            match op {
+                Some(op) => sources[1..].iter().fold(byte(&sources[0]), |result, source| op(result, byte(source))),
+                None => !byte(&sources[0])
+            }
+        }).collect();
+
+        if result.is_empty() {
+            (len, db.remove(destination).is_some())
+        } else {
+            db.set(destination.to_owned(), Value::string(result));
+            (len, false)
+        }
+    };
+
+    if deleted {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", destination, 0);
+    } else if len > 0 {
+        notify_keyspace_event(shared, NOTIFY_STRING, "set", destination, 0);
+    }
+    Ok(RESPValue::Number(len as i64))
+}
+
+#[derive(Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Clone, Copy)]
+struct FieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl FieldType {
+    // Signed fields go up to 64 bits, unsigned ones up to 63 so they fit in a reply integer.
+    fn parse(arg: &str) -> Result<FieldType, RESPError> {
+        let signed = match arg.as_bytes().first() {
+            Some(b'i' | b'I') => true,
+            Some(b'u' | b'U') => false,
+            _ => return Err(RESPError::InvalidBitfieldType)
+        };
+        let bits = arg[1..].parse().map_err(|_| RESPError::InvalidBitfieldType)?;
+        let max_bits = if signed { 64 } else { 63 };
+        if bits < 1 || bits > max_bits {
+            return Err(RESPError::InvalidBitfieldType);
+        }
+        Ok(FieldType { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1 << (self.bits - 1)) - 1 } else { (1 << self.bits) - 1 }
+    }
+
+    // Applies the overflow behaviour to a value that may not fit in the field, None on failure.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+
+        match overflow {
+            Overflow::Wrap => {
+                // Keep the low bits, and sign extend them for signed fields.
+                let wrapped = value & ((1 << self.bits) - 1);
+                let wrapped = if self.signed && wrapped > self.max() { wrapped - (1 << self.bits) } else { wrapped };
+                Some(wrapped as i64)
+            },
+            Overflow::Sat => Some(if value > self.max() { self.max() } else { self.min() } as i64),
+            Overflow::Fail => None
+        }
+    }
+
+    fn get(&self, bytes: &[u8], offset: usize) -> i64 {
+        let mut value: u64 = 0;
+        for i in 0..self.bits as usize {
+            value = (value << 1) | get_bit(bytes, offset + i) as u64;
+        }
+
+        if self.signed && self.bits < 64 && value & (1 << (self.bits - 1)) != 0 {
+            value |= u64::MAX << self.bits;
+        }
+        value as i64
+    }
+
+    fn set(&self, bytes: &mut [u8], offset: usize, value: i64) {
+        let value = value as u64;
+        for i in 0..self.bits as usize {
+            let bit = value & (1 << (self.bits as usize - 1 - i)) != 0;
+            let (byte, mask) = ((offset + i) / 8, 0x80 >> ((offset + i) % 8));
+            if bit {
+                bytes[byte] |= mask;
+            } else {
+                bytes[byte] &= !mask;
+            }
+        }
+    }
+}
+
+enum FieldOperation {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+struct BitfieldOperation {
+    field: FieldType,
+    offset: usize,
+    operation: FieldOperation,
+    overflow: Overflow,
+}
+
+// An offset is either in bits, or multiplied by the field width when prefixed with `#`.
+fn parse_field_offset(arg: &str, field: &FieldType) -> Result<usize, RESPError> {
+    let offset = match arg.strip_prefix('#') {
+        Some(index) => index.parse::<i64>().ok().and_then(|index| index.checked_mul(field.bits as i64)),
+        None => arg.parse::<i64>().ok()
+    };
+    match offset {
+        Some(offset) if offset >= 0 && offset + field.bits as i64 - 1 <= MAX_BIT_OFFSET => Ok(offset as usize),
+        _ => Err(RESPError::InvalidBitOffset)
+    }
+}
+
+fn parse_bitfield(args: &[Arg]) -> Result<Vec<BitfieldOperation>, RESPError> {
+    let mut operations = vec![];
+    let mut overflow = Overflow::Wrap;
+
+    let mut i = 0;
+    while i < args.len() {
+        let subcommand = args[i].to_ascii_uppercase();
+        if subcommand == "OVERFLOW" {
+            overflow = match args.get(i + 1).map(|mode| mode.to_ascii_uppercase()).as_deref() {
+                Some("WRAP") => Overflow::Wrap,
+                Some("SAT") => Overflow::Sat,
+                Some("FAIL") => Overflow::Fail,
+                Some(_) => return Err(RESPError::InvalidOverflowType),
+                None => return Err(RESPError::SyntaxError)
+            };
+            i += 2;
+            continue;
+        }
+
+        let arity = match subcommand.as_str() {
+            "GET" => 3,
+            "SET" | "INCRBY" => 4,
+            _ => return Err(RESPError::SyntaxError)
+        };
+        if i + arity > args.len() {
+            return Err(RESPError::SyntaxError);
+        }
+
+        let field = FieldType::parse(&args[i + 1])?;
+        let offset = parse_field_offset(&args[i + 2], &field)?;
+        let operation = match subcommand.as_str() {
+            "GET" => FieldOperation::Get,
+            "SET" => FieldOperation::Set(parse_number(&args[i + 3])?),
+            _ => FieldOperation::IncrBy(parse_number(&args[i + 3])?)
+        };
+        operations.push(BitfieldOperation { field, offset, operation, overflow });
+        i += arity;
+    }
+    Ok(operations)
+}
+
+// BITFIELD key [GET type offset] [SET type offset value] [INCRBY type offset increment]
+//     [OVERFLOW WRAP|SAT|FAIL] ...
+pub fn bitfield(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let operations = parse_bitfield(&command[2..])?;
+
+    // The string only grows when there are writes, up to the last bit written.
+    let write_end = operations.iter()
+        .filter(|op| !matches!(op.operation, FieldOperation::Get))
+        .map(|op| op.offset + op.field.bits as usize)
+        .max();
+
+    let mut replies = Vec::with_capacity(operations.len());
+    let mut changed = false;
+    {
+        let mut db = shared.db.lock();
+        let mut string;
+        let bytes = match write_end {
+            Some(write_end) => {
+                string = string_mut(&mut db, key)?;
+                if string.len() < write_end.div_ceil(8) {
+                    string.resize(write_end.div_ceil(8), 0);
+                }
+                &mut *string
+            },
+            None => match db.get(key) {
+                Some(value) => &mut value.as_string()?.to_vec(),
+                None => &mut vec![]
+            }
+        };
+
+        for op in operations {
+            let old = op.field.get(bytes, op.offset);
+            let new = match op.operation {
+                FieldOperation::Get => {
+                    replies.push(RESPValue::Number(old));
+                    continue;
+                },
+                FieldOperation::Set(value) => op.field.fit(value as i128, op.overflow).map(|new| (new, old)),
+                FieldOperation::IncrBy(increment) => op.field.fit(old as i128 + increment as i128, op.overflow).map(|new| (new, new))
+            };
+
+            match new {
+                Some((new, reply)) => {
+                    op.field.set(bytes, op.offset, new);
+                    changed = true;
+                    replies.push(RESPValue::Number(reply));
+                },
+                None => replies.push(RESPValue::Null)
+            }
+        }
+    }
+
+    if changed {
+        notify_keyspace_event(shared, NOTIFY_STRING, "setbit", key, 0);
+    }
+    Ok(RESPValue::Array(replies))
+}
@@ -0,0 +1,401 @@
+// Scalable Bloom filters, with the BF commands of RedisBloom. A filter is a stack of layers, each a
+// classic Bloom filter sized for its capacity and error rate. Items are added to the last layer,
+// and once it's full a layer `expansion` times bigger is stacked on it, with half the error rate so
+// the error rate of the whole filter stays about the one it was reserved with. Non scaling filters
+// refuse items once their only layer is full instead.
+//
+// Filters are created by BF.RESERVE, or by adding to a key that doesn't exist with the options of
+// `bf-error-rate`, `bf-initial-size` and `bf-expansion-factor`. Items are hashed like RedisBloom
+// does (MurmurHash64A and double hashing), but filters aren't serialized the same way, so they can't
+// be moved between the two.
+
+use std::collections::HashMap;
+use std::f64::consts::LN_2;
+use std::io;
+
+use bytes::Bytes;
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::hyperloglog::murmurhash64a;
+use crate::notify::{notify_keyspace_event, NOTIFY_MODULE};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{rdb, RESPError, RESPValue, SharedState};
+
+pub const MODULE_ID: u64 = rdb::module_id(b"bastbloom", 0);
+
+const HASH_SEED: u64 = 0xc6a4a7935bd1e995;
+// Every layer has this much of the error rate of the layer below it.
+const ERROR_TIGHTENING: f64 = 0.5;
+// The largest capacity of a layer, and the most bits it may have (512 MiB worth).
+pub const MAX_CAPACITY: u64 = 1 << 30;
+const MAX_BITS: u64 = 1 << 32;
+pub const MAX_EXPANSION: u64 = 32768;
+
+// The two hashes of an item, which the bits it sets are derived from.
+fn hash(item: &[u8]) -> (u64, u64) {
+    let a = murmurhash64a(item, HASH_SEED);
+    (a, murmurhash64a(item, a))
+}
+
+#[derive(Clone)]
+struct Layer {
+    bits: Vec<u64>,
+    // The number of bits, which the hashes are taken modulo.
+    size: u64,
+    hashes: u32,
+    capacity: u64,
+    error: f64,
+    items: u64,
+}
+
+impl Layer {
+    fn new(capacity: u64, error: f64) -> Result<Self, RESPError> {
+        let bits_per_item = -error.ln() / (LN_2 * LN_2);
+        let size = (capacity as f64 * bits_per_item).ceil();
+        if size > MAX_BITS as f64 {
+            return Err(RESPError::FilterTooLarge);
+        }
+        let size = (size as u64).max(64);
+        let hashes = ((LN_2 * bits_per_item).ceil() as u32).max(1);
+        Ok(Self { bits: vec![0; size.div_ceil(64) as usize], size, hashes, capacity, error, items: 0 })
+    }
+
+    fn bit(&self, (a, b): (u64, u64), i: u32) -> u64 {
+        a.wrapping_add((i as u64).wrapping_mul(b)) % self.size
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        (0..self.hashes).map(|i| self.bit(hash, i)).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for i in 0..self.hashes {
+            let bit = self.bit(hash, i);
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    fn full(&self) -> bool {
+        self.items >= self.capacity
+    }
+
+    fn bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    fn validate(self) -> io::Result<Self> {
+        let valid = self.size >= 64 && self.size <= MAX_BITS && self.bits.len() as u64 == self.size.div_ceil(64)
+            && self.hashes > 0 && self.capacity > 0 && self.error > 0.0 && self.error < 1.0;
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bloom filter layer"));
+        }
+        Ok(self)
+    }
+}
+
+fn words(bytes: &[u8]) -> Vec<u64> {
+    bytes.chunks(8).map(|word| {
+        let mut padded = [0; 8];
+        padded[..word.len()].copy_from_slice(word);
+        u64::from_le_bytes(padded)
+    }).collect()
+}
+
+fn to_bytes(words: &[u64]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+#[derive(Clone)]
+pub struct Bloom {
+    layers: Vec<Layer>,
+    // How many times bigger every new layer is, 0 for a non scaling filter.
+    expansion: u64,
+}
+
+impl Bloom {
+    pub fn new(capacity: u64, error: f64, expansion: u64) -> Result<Self, RESPError> {
+        Ok(Self { layers: vec![Layer::new(capacity, error)?], expansion })
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        let hash = hash(item);
+        self.layers.iter().any(|layer| layer.contains(hash))
+    }
+
+    // Adds the item, false when it (or one colliding with it) was already there.
+    pub fn add(&mut self, item: &[u8]) -> Result<bool, RESPError> {
+        let hash = hash(item);
+        if self.layers.iter().any(|layer| layer.contains(hash)) {
+            return Ok(false);
+        }
+        let last = self.layers.last().unwrap();
+        if last.full() {
+            if self.expansion == 0 {
+                return Err(RESPError::NonScalingFilterFull);
+            }
+            let capacity = last.capacity.saturating_mul(self.expansion);
+            if capacity > MAX_CAPACITY {
+                return Err(RESPError::FilterFull);
+            }
+            self.layers.push(Layer::new(capacity, last.error * ERROR_TIGHTENING)?);
+        }
+        self.layers.last_mut().unwrap().insert(hash);
+        Ok(true)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.capacity).sum()
+    }
+
+    pub fn items(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.items).sum()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.layers.iter().map(|layer| std::mem::size_of::<Layer>() + layer.bytes()).sum::<usize>()
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.len(self.expansion as usize);
+        encoder.len(self.layers.len());
+        for layer in &self.layers {
+            encoder.len(layer.capacity as usize);
+            encoder.f64(layer.error);
+            encoder.len(layer.items as usize);
+            encoder.len(layer.size as usize);
+            encoder.len(layer.hashes as usize);
+            encoder.bytes(&to_bytes(&layer.bits));
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let expansion = decoder.len()? as u64;
+        let mut layers = vec![];
+        for _ in 0..decoder.len()? {
+            let (capacity, error, items) = (decoder.len()? as u64, decoder.f64()?, decoder.len()? as u64);
+            let (size, hashes) = (decoder.len()? as u64, decoder.len()? as u32);
+            let bits = words(decoder.bytes()?);
+            layers.push(Layer { bits, size, hashes, capacity, error, items }.validate()?);
+        }
+        if layers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty bloom filter"));
+        }
+        Ok(Self { layers, expansion })
+    }
+
+    // Written as the value of the bastbloom module type, see rdb.rs.
+    pub fn write_rdb(&self, buf: &mut Vec<u8>) {
+        rdb::write_module_uint(buf, self.expansion);
+        rdb::write_module_uint(buf, self.layers.len() as u64);
+        for layer in &self.layers {
+            rdb::write_module_uint(buf, layer.capacity);
+            rdb::write_module_double(buf, layer.error);
+            rdb::write_module_uint(buf, layer.items);
+            rdb::write_module_uint(buf, layer.size);
+            rdb::write_module_uint(buf, layer.hashes as u64);
+            rdb::write_module_string(buf, &to_bytes(&layer.bits));
+        }
+    }
+
+    pub fn read_rdb(reader: &mut rdb::Reader) -> io::Result<Self> {
+        let expansion = reader.module_uint()?;
+        let mut layers = vec![];
+        for _ in 0..reader.module_uint()? {
+            let (capacity, error, items) = (reader.module_uint()?, reader.module_double()?, reader.module_uint()?);
+            let (size, hashes) = (reader.module_uint()?, reader.module_uint()? as u32);
+            let bits = words(&reader.module_string()?);
+            layers.push(Layer { bits, size, hashes, capacity, error, items }.validate()?);
+        }
+        if layers.is_empty() {
+            return Err(rdb::corrupted("empty bloom filter"));
+        }
+        Ok(Self { layers, expansion })
+    }
+}
+
+// A `bf-error-rate`, between 0 and 1.
+pub fn parse_error_rate(value: &str) -> Option<String> {
+    value.parse::<f64>().ok().filter(|rate| *rate > 0.0 && *rate < 1.0).map(|rate| rate.to_string())
+}
+
+fn parse_error(arg: &str) -> Result<f64, RESPError> {
+    arg.parse::<f64>().ok().filter(|rate| *rate > 0.0 && *rate < 1.0).ok_or(RESPError::BadErrorRate)
+}
+
+pub fn parse_capacity(arg: &str) -> Result<u64, RESPError> {
+    arg.parse::<u64>().ok().filter(|capacity| (1..=MAX_CAPACITY).contains(capacity)).ok_or(RESPError::BadCapacity)
+}
+
+pub fn parse_expansion(arg: &str) -> Result<u64, RESPError> {
+    arg.parse::<u64>().ok().filter(|expansion| (1..=MAX_EXPANSION).contains(expansion)).ok_or(RESPError::BadExpansion)
+}
+
+// The options of a new filter, the ones of the config unless given.
+struct Options {
+    error: f64,
+    capacity: u64,
+    expansion: u64,
+}
+
+impl Options {
+    fn configured(shared: &SharedState) -> Self {
+        Self {
+            error: shared.config.get("bf-error-rate").parse().unwrap_or(0.01),
+            capacity: shared.config.get_int("bf-initial-size") as u64,
+            expansion: shared.config.get_int("bf-expansion-factor") as u64,
+        }
+    }
+
+    fn create(&self) -> Result<Bloom, RESPError> {
+        Bloom::new(self.capacity, self.error, self.expansion)
+    }
+}
+
+// Adds the items to the filter of the key, created with the options when it doesn't exist (refused
+// without them). Whether every item was added, or why it couldn't be.
+fn add(command: &[Arg], items: &[Arg], options: Option<Options>, shared: &SharedState) -> Result<Vec<Result<bool, RESPError>>, RESPError> {
+    let key = command[1].as_str();
+    let added = {
+        let mut db = shared.db.lock();
+        if db.get(key).is_none() {
+            let options = options.ok_or(RESPError::FilterNotFound)?;
+            db.set(key.to_owned(), Value::Bloom(options.create()?));
+        }
+        let bloom = db.get_mut(key).unwrap().as_bloom_mut()?;
+        items.iter().map(|item| bloom.add(item.raw())).collect::<Vec<_>>()
+    };
+    if added.iter().any(|added| matches!(added, Ok(true))) {
+        notify_keyspace_event(shared, NOTIFY_MODULE, &command[0].to_ascii_lowercase(), key, 0);
+    }
+    Ok(added)
+}
+
+fn added_reply(added: Vec<Result<bool, RESPError>>) -> RESPValue {
+    RESPValue::Array(added.into_iter().map(|added| match added {
+        Ok(added) => RESPValue::Number(added as i64),
+        Err(e) => e.into()
+    }).collect())
+}
+
+// BF.RESERVE key error_rate capacity [EXPANSION expansion] [NONSCALING]
+pub fn reserve(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let (error, capacity) = (parse_error(&command[2])?, parse_capacity(&command[3])?);
+    let (mut expansion, mut nonscaling) = (None, false);
+    let mut args = command[4..].iter();
+    while let Some(arg) = args.next() {
+        match arg.to_ascii_uppercase().as_str() {
+            "EXPANSION" => expansion = Some(parse_expansion(args.next().ok_or(RESPError::SyntaxError)?)?),
+            "NONSCALING" => nonscaling = true,
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+    if nonscaling && expansion.is_some() {
+        return Err(RESPError::NonScalingExpansion);
+    }
+    let expansion = if nonscaling { 0 } else { expansion.unwrap_or_else(|| Options::configured(shared).expansion) };
+    let bloom = Bloom::new(capacity, error, expansion)?;
+
+    {
+        let mut db = shared.db.lock();
+        if db.contains_key(key) {
+            return Err(RESPError::ItemExists);
+        }
+        db.set(key.to_owned(), Value::Bloom(bloom));
+    }
+    notify_keyspace_event(shared, NOTIFY_MODULE, "bf.reserve", key, 0);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// BF.ADD key item
+pub fn bf_add(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let added = add(command, &command[2..], Some(Options::configured(shared)), shared)?.remove(0)?;
+    Ok(RESPValue::Number(added as i64))
+}
+
+// BF.MADD key item [item ...]
+pub fn madd(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    Ok(added_reply(add(command, &command[2..], Some(Options::configured(shared)), shared)?))
+}
+
+// BF.INSERT key [CAPACITY capacity] [ERROR error] [EXPANSION expansion] [NOCREATE] [NONSCALING]
+//     ITEMS item [item ...]
+pub fn insert(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut options = Options::configured(shared);
+    let (mut create, mut nonscaling, mut expansion) = (true, false, false);
+    let mut i = 2;
+    loop {
+        let arg = command.get(i).ok_or(RESPError::SyntaxError)?.to_ascii_uppercase();
+        let value = command.get(i + 1).ok_or(RESPError::SyntaxError);
+        match arg.as_str() {
+            "CAPACITY" => options.capacity = parse_capacity(value?)?,
+            "ERROR" => options.error = parse_error(value?)?,
+            "EXPANSION" => {
+                options.expansion = parse_expansion(value?)?;
+                expansion = true;
+            },
+            "NOCREATE" => create = false,
+            "NONSCALING" => nonscaling = true,
+            "ITEMS" => break,
+            _ => return Err(RESPError::SyntaxError)
+        }
+        i += if matches!(arg.as_str(), "NOCREATE" | "NONSCALING") { 1 } else { 2 };
+    }
+    let items = &command[i + 1..];
+    if items.is_empty() {
+        return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+    }
+    if nonscaling && expansion {
+        return Err(RESPError::NonScalingExpansion);
+    }
+    if nonscaling {
+        options.expansion = 0;
+    }
+    Ok(added_reply(add(command, items, create.then_some(options), shared)?))
+}
+
+// BF.EXISTS key item
+// BF.MEXISTS key item [item ...]
+pub fn exists(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let bloom = db.get(command[1].as_str()).map(Value::as_bloom).transpose()?;
+    let mut found = command[2..].iter().map(|item| RESPValue::Number(bloom.is_some_and(|bloom| bloom.contains(item.raw())) as i64));
+    Ok(match command[0].as_str() {
+        "BF.EXISTS" => found.next().unwrap(),
+        _ => RESPValue::Array(found.collect())
+    })
+}
+
+// BF.CARD key
+pub fn card(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let bloom = db.get(command[1].as_str()).map(Value::as_bloom).transpose()?;
+    Ok(RESPValue::Number(bloom.map_or(0, Bloom::items) as i64))
+}
+
+// BF.INFO key [CAPACITY | SIZE | FILTERS | ITEMS | EXPANSION]
+pub fn info(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let bloom = db.get(command[1].as_str()).ok_or(RESPError::FilterNotFound)?.as_bloom()?;
+    let expansion = match bloom.expansion {
+        0 => RESPValue::Null,
+        expansion => RESPValue::Number(expansion as i64)
+    };
+    let fields = [
+        ("CAPACITY", "Capacity", RESPValue::Number(bloom.capacity() as i64)),
+        ("SIZE", "Size", RESPValue::Number(bloom.memory_usage() as i64)),
+        ("FILTERS", "Number of filters", RESPValue::Number(bloom.layers.len() as i64)),
+        ("ITEMS", "Number of items inserted", RESPValue::Number(bloom.items() as i64)),
+        ("EXPANSION", "Expansion rate", expansion),
+    ];
+    match command.get(2).map(|arg| arg.to_ascii_uppercase()) {
+        None => Ok(RESPValue::Map(fields.into_iter().map(|(_, name, value)| (Bytes::from(name), value)).collect::<HashMap<_, _>>())),
+        Some(_) if command.len() > 3 => Err(RESPError::SyntaxError),
+        Some(arg) => {
+            let (_, _, value) = fields.into_iter().find(|(field, _, _)| *field == arg).ok_or(RESPError::SyntaxError)?;
+            Ok(RESPValue::Array(vec![value]))
+        }
+    }
+}
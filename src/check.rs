@@ -0,0 +1,106 @@
+// Checking the persistence files, for `bast --check-dump` and `bast --check-aof`: whether a file
+// loads, and where it's corrupted when it doesn't. A broken append only file can be cut to its last
+// valid command, like aof-load-truncated does on startup for files a crash cut short, which loses
+// whatever follows the corruption but lets the server start again.
+
+use std::fs::OpenOptions;
+
+use crate::{aof, encryption, lookup_command, snapshot, SharedState};
+
+// The offsets reported are of the decrypted data for encrypted files.
+fn offsets_note(encrypted: bool) -> &'static str {
+    if encrypted { " (offsets are of the decrypted data)" } else { "" }
+}
+
+// Checks a snapshot file of either format by loading it into the keyspace.
+pub fn dump(path: &str, shared: &SharedState) -> Result<String, String> {
+    let file = std::fs::read(path).map_err(|e| format!("Failed reading {}: {}", path, e))?;
+    let encrypted = encryption::is_encrypted(&file);
+    let data = if encrypted {
+        let decrypted = encryption::decrypt(&file).map_err(|e| format!("{} is corrupted: {}", path, e))?;
+        if !decrypted.is_complete() {
+            return Err(format!("{} is corrupted: it's cut short after offset {}{}", path, decrypted.data.len(), offsets_note(true)));
+        }
+        decrypted.data
+    } else {
+        file
+    };
+    if !snapshot::is_snapshot(&data) {
+        return Err(format!("{} is not a snapshot file", path));
+    }
+
+    let (keys, size) = snapshot::load_from(&data, shared)
+        .map_err(|e| format!("{} is corrupted: {}{}", path, e, offsets_note(encrypted)))?;
+    if size != data.len() {
+        return Err(format!("{} is corrupted: {} bytes follow the end of the snapshot at offset {}{}",
+            path, data.len() - size, size, offsets_note(encrypted)));
+    }
+    Ok(format!("{} is valid: {} keys (not counting the expired ones), {} bytes", path, keys, data.len()))
+}
+
+// Checks an append only file: its snapshot preamble by loading it, and its commands by parsing them
+// (without running them). With `fix` a corrupted file is truncated to its last valid command, or to
+// the start of the transaction the corruption is in, as transactions are replayed whole or not at all.
+pub fn aof(path: &str, fix: bool, shared: &SharedState) -> Result<String, String> {
+    let file = std::fs::read(path).map_err(|e| format!("Failed reading {}: {}", path, e))?;
+    let encrypted = encryption::is_encrypted(&file);
+    let decrypted = if encrypted {
+        Some(encryption::decrypt(&file).map_err(|e| format!("{} is corrupted: {}", path, e))?)
+    } else {
+        None
+    };
+    let data = decrypted.as_ref().map_or(&file, |decrypted| &decrypted.data);
+
+    let mut at = 0;
+    if snapshot::is_snapshot(data) {
+        let (_, size) = snapshot::load_from(data, shared)
+            .map_err(|e| format!("The snapshot preamble of {} is corrupted, which can't be fixed: {}{}", path, e, offsets_note(encrypted)))?;
+        at = size;
+    }
+    let mut commands = 0;
+    // Where the transaction being read started, and its commands so far.
+    let mut multi: Option<(usize, usize)> = None;
+    // Where the file is corrupted, and how.
+    let problem = loop {
+        if at == data.len() {
+            break multi.map(|(start, _)| (start, String::from("the last transaction isn't closed by EXEC")));
+        }
+        let command_start = at;
+        let command = match aof::parse_command(data, &mut at) {
+            Ok(Some(command)) => command,
+            Ok(None) => break Some((command_start, String::from("the last command is cut short"))),
+            Err(e) => break Some((command_start, e.to_string()))
+        };
+        match command[0].as_str() {
+            "MULTI" => multi = Some((command_start, 0)),
+            "EXEC" => match multi.take() {
+                Some((_, transaction)) => commands += transaction,
+                None => break Some((command_start, String::from("EXEC without MULTI")))
+            },
+            name if lookup_command(name, shared).is_none() => break Some((command_start, format!("unknown command '{}'", name))),
+            _ => match &mut multi {
+                Some((_, transaction)) => *transaction += 1,
+                None => commands += 1
+            }
+        }
+    };
+    // An encrypted file may also be cut short in the middle of a record.
+    let problem = problem.or_else(|| decrypted.as_ref().filter(|decrypted| !decrypted.is_complete())
+        .map(|decrypted| (decrypted.data.len(), String::from("the last encrypted record is cut short"))));
+
+    let Some((offset, what)) = problem else {
+        return Ok(format!("{} is valid: {} commands, {} bytes", path, commands, file.len()));
+    };
+    let valid = multi.map_or(offset, |(start, _)| start.min(offset));
+    let report = format!("{} is corrupted at offset {}: {}. It's valid up to offset {} with {} commands, {} bytes follow{}",
+        path, offset, what, valid, commands, data.len() - valid, offsets_note(encrypted));
+    if !fix {
+        return Err(format!("{}. --fix truncates it to its valid part", report));
+    }
+
+    let size = match &decrypted {
+        Some(decrypted) => decrypted.truncate(path, valid),
+        None => OpenOptions::new().write(true).open(path).and_then(|file| file.set_len(valid as u64)).map(|_| valid as u64)
+    }.map_err(|e| format!("{}. Failed truncating it: {}", report, e))?;
+    Ok(format!("{}. Truncated it from {} to {} bytes", report, file.len(), size))
+}
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+
+use crate::arg::Arg;
+use crate::audit::quote_command;
+use crate::pubsub::ClientId;
+use crate::{RESPError, RESPValue, SharedState};
+
+// What a connection updates after every command, for other connections to see.
+pub struct ClientState {
+    pub name: String,
+    pub user: String,
+    // The lowercase name of the last command, empty before the first one.
+    pub last_command: String,
+    pub last_interaction: Instant,
+    pub channels: usize,
+    pub patterns: usize,
+    pub shard_channels: usize,
+    // The number of commands queued since MULTI, None when not in a transaction.
+    pub multi: Option<usize>,
+    // Set by READONLY.
+    pub readonly: bool,
+    // Waiting for a command that blocks, like a blocking read.
+    pub blocked: bool,
+    // The connection became the link of a replica.
+    pub replica: bool,
+}
+
+// A connection as seen by CLIENT LIST and CLIENT KILL.
+pub struct ClientInfo {
+    pub id: ClientId,
+    // The address of the peer, empty when unknown (like on unix sockets).
+    pub addr: String,
+    // The local address the client is connected to.
+    pub laddr: String,
+    created: Instant,
+    pub state: Mutex<ClientState>,
+    // Notified by CLIENT KILL, closing the connection.
+    pub killed: Notify,
+}
+
+impl ClientInfo {
+    pub fn new(id: ClientId, addr: String, laddr: String, user: &str) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            addr,
+            laddr,
+            created: now,
+            state: Mutex::new(ClientState {
+                name: String::new(),
+                user: user.to_owned(),
+                last_command: String::new(),
+                last_interaction: now,
+                channels: 0,
+                patterns: 0,
+                shard_channels: 0,
+                multi: None,
+                readonly: false,
+                blocked: false,
+                replica: false,
+            }),
+            killed: Notify::new(),
+        }
+    }
+
+    fn is_pubsub(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.channels + state.patterns + state.shard_channels > 0
+    }
+
+    // A line of CLIENT LIST.
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut flags = String::new();
+        if state.channels + state.patterns + state.shard_channels > 0 {
+            flags.push('P');
+        }
+        if state.multi.is_some() {
+            flags.push('x');
+        }
+        if state.readonly {
+            flags.push('r');
+        }
+        if state.blocked {
+            flags.push('b');
+        }
+        if state.replica {
+            flags.push('S');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 sub={} psub={} ssub={} multi={} user={} resp=2 cmd={}",
+            self.id, self.addr, self.laddr, state.name, self.created.elapsed().as_secs(),
+            state.last_interaction.elapsed().as_secs(), flags, state.channels, state.patterns, state.shard_channels,
+            state.multi.map_or(-1, |queued| queued as i64), state.user,
+            if state.last_command.is_empty() { "NULL" } else { &state.last_command }
+        )
+    }
+}
+
+// Set by CLIENT REPLY, whether replies are written back to the client. Pushed messages (like
+// published ones) are always written.
+#[derive(Default)]
+pub struct ReplyMode {
+    off: bool,
+    // The number of upcoming commands whose replies are dropped.
+    skip: usize,
+}
+
+impl ReplyMode {
+    // Called once for every command, returning whether its replies should be written.
+    pub fn next_reply(&mut self) -> bool {
+        let skipped = self.skip > 0;
+        self.skip = self.skip.saturating_sub(1);
+        !self.off && !skipped
+    }
+}
+
+// Set by CLIENT PAUSE: when the pause ends, and whether it holds back all commands or only writes.
+#[derive(Clone, Copy)]
+struct Pause {
+    until: Instant,
+    all: bool,
+}
+
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: Mutex<BTreeMap<ClientId, Arc<ClientInfo>>>,
+    pause: Mutex<Option<Pause>>,
+    unpaused: Notify,
+    // Clients that ran MONITOR, getting every executed command.
+    monitors: Mutex<HashMap<ClientId, UnboundedSender<RESPValue>>>,
+}
+
+impl ClientRegistry {
+    pub fn register(&self, info: Arc<ClientInfo>) {
+        self.clients.lock().unwrap().insert(info.id, info);
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    pub fn exists(&self, id: ClientId) -> bool {
+        self.clients.lock().unwrap().contains_key(&id)
+    }
+
+    fn all(&self) -> Vec<Arc<ClientInfo>> {
+        self.clients.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn monitor(&self, id: ClientId, sender: UnboundedSender<RESPValue>) {
+        self.monitors.lock().unwrap().insert(id, sender);
+    }
+
+    pub fn unmonitor(&self, id: ClientId) {
+        self.monitors.lock().unwrap().remove(&id);
+    }
+
+    // Sends a command to the monitoring clients, `source` being the address of the client that ran
+    // it (or lua for commands called by scripts).
+    pub fn feed_monitors(&self, source: &str, command: &[Arg]) {
+        let monitors = self.monitors.lock().unwrap();
+        if monitors.is_empty() {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let line = format!("{}.{:06} [0 {}] {}", now.as_secs(), now.subsec_micros(), source, quote_command(command));
+        for sender in monitors.values() {
+            let _ = sender.send(RESPValue::SimpleString(line.clone()));
+        }
+    }
+
+    // Pausing while already paused keeps the later end and the stricter mode of the two.
+    pub fn pause(&self, duration: Duration, all: bool) {
+        let mut pause = self.pause.lock().unwrap();
+        let until = Instant::now() + duration;
+        *pause = Some(match *pause {
+            Some(current) if current.until > Instant::now() => Pause { until: until.max(current.until), all: all || current.all },
+            _ => Pause { until, all }
+        });
+    }
+
+    pub fn unpause(&self) {
+        *self.pause.lock().unwrap() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    // Waits for a pause holding back the command to end, writes being held back by both modes.
+    pub async fn wait_unpaused(&self, write: bool) {
+        loop {
+            let notified = self.unpaused.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let until = match *self.pause.lock().unwrap() {
+                Some(pause) if (pause.all || write) && pause.until > Instant::now() => pause.until,
+                _ => return
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(until.into()) => {},
+                _ = notified => {}
+            }
+        }
+    }
+}
+
+// Closes the connections idle for `timeout` seconds, run by the cron every second. Subscribed clients
+// are expected to be idle, and neither they, blocked clients nor replicas time out.
+pub fn close_timed_out(shared: &Arc<SharedState>) {
+    let timeout = shared.config.get_int("timeout");
+    if timeout == 0 {
+        return;
+    }
+    for info in shared.clients.all() {
+        let state = info.state.lock().unwrap();
+        let subscribed = state.channels + state.patterns + state.shard_channels > 0;
+        if !subscribed && !state.blocked && !state.replica && state.last_interaction.elapsed() >= Duration::from_secs(timeout as u64) {
+            drop(state);
+            info.killed.notify_one();
+        }
+    }
+}
+
+fn parse_id(id: &str) -> Result<ClientId, RESPError> {
+    id.parse::<ClientId>().map_err(|_| RESPError::NotAnInteger)
+}
+
+fn filter_type(clients: Vec<Arc<ClientInfo>>, client_type: &str) -> Result<Vec<Arc<ClientInfo>>, RESPError> {
+    let pubsub = match client_type.to_ascii_lowercase().as_str() {
+        "normal" => false,
+        "pubsub" => true,
+        // There is no replication yet.
+        "master" | "replica" | "slave" => return Ok(vec![]),
+        _ => return Err(RESPError::UnknownClientType(client_type.to_owned()))
+    };
+    Ok(clients.into_iter().filter(|info| info.is_pubsub() == pubsub).collect())
+}
+
+// A client name is shown in CLIENT LIST lines, so it may not break them.
+fn valid_name(name: &str) -> bool {
+    name.bytes().all(|b| (b'!'..=b'~').contains(&b))
+}
+
+// CLIENT ID
+// CLIENT INFO
+// CLIENT LIST [TYPE normal|pubsub|master|replica] [ID client-id [client-id ...]]
+// CLIENT SETNAME name
+// CLIENT GETNAME
+// CLIENT KILL addr:port
+// CLIENT KILL [ID client-id] [ADDR addr:port] [LADDR addr:port] [USER username] [TYPE type] [SKIPME yes|no]
+// CLIENT PAUSE timeout [WRITE|ALL]
+// CLIENT UNPAUSE
+// CLIENT REPLY ON|OFF|SKIP
+pub fn client(command: &[Arg], me: &ClientInfo, reply_mode: &mut ReplyMode, registry: &ClientRegistry) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("CLIENT|{}", subcommand)))
+    };
+
+    match subcommand.as_str() {
+        "ID" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::Number(me.id as i64))
+        },
+        "INFO" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::BlobString(format!("{}\n", me.describe()).into()))
+        },
+        "LIST" => {
+            let mut clients = registry.all();
+            let mut args = command[2..].iter();
+            while let Some(arg) = args.next() {
+                match arg.to_ascii_uppercase().as_str() {
+                    "TYPE" => {
+                        let client_type = args.next().ok_or(RESPError::SyntaxError)?;
+                        clients = filter_type(clients, client_type)?;
+                    },
+                    "ID" => {
+                        let ids = args.by_ref().map(|id| parse_id(id)).collect::<Result<Vec<_>, _>>()?;
+                        if ids.is_empty() {
+                            return Err(RESPError::SyntaxError);
+                        }
+                        clients.retain(|info| ids.contains(&info.id));
+                    },
+                    _ => return Err(RESPError::SyntaxError)
+                }
+            }
+
+            let list: String = clients.iter().map(|info| format!("{}\n", info.describe())).collect();
+            Ok(RESPValue::BlobString(list.into()))
+        },
+        "SETNAME" => {
+            check_arity(command.len() == 3)?;
+            if !valid_name(&command[2]) {
+                return Err(RESPError::InvalidClientName);
+            }
+            me.state.lock().unwrap().name = command[2].to_string();
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "GETNAME" => {
+            check_arity(command.len() == 2)?;
+            let name = me.state.lock().unwrap().name.clone();
+            Ok(if name.is_empty() { RESPValue::Null } else { RESPValue::BlobString(name.into()) })
+        },
+        "KILL" => {
+            check_arity(command.len() >= 3)?;
+
+            // The old form, killing a single client by its address.
+            if command.len() == 3 {
+                let info = registry.all().into_iter().find(|info| info.addr == command[2]).ok_or(RESPError::NoSuchClient)?;
+                info.killed.notify_one();
+                return Ok(RESPValue::SimpleString(String::from("OK")));
+            }
+
+            if !command.len().is_multiple_of(2) {
+                return Err(RESPError::SyntaxError);
+            }
+            let mut clients = registry.all();
+            let mut skip_me = true;
+            for pair in command[2..].chunks(2) {
+                let value = &pair[1];
+                match pair[0].to_ascii_uppercase().as_str() {
+                    "ID" => {
+                        let id = parse_id(value)?;
+                        clients.retain(|info| info.id == id);
+                    },
+                    "ADDR" => clients.retain(|info| info.addr == *value),
+                    "LADDR" => clients.retain(|info| info.laddr == *value),
+                    "USER" => clients.retain(|info| info.state.lock().unwrap().user == *value),
+                    "TYPE" => clients = filter_type(clients, value)?,
+                    "SKIPME" => skip_me = match value.to_ascii_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(RESPError::SyntaxError)
+                    },
+                    _ => return Err(RESPError::SyntaxError)
+                }
+            }
+
+            let mut killed = 0;
+            for info in clients.iter().filter(|info| !skip_me || info.id != me.id) {
+                info.killed.notify_one();
+                killed += 1;
+            }
+            Ok(RESPValue::Number(killed))
+        },
+        "PAUSE" => {
+            check_arity(command.len() == 3 || command.len() == 4)?;
+            let timeout = command[2].parse::<i64>().map_err(|_| RESPError::NotAnInteger)?;
+            if timeout < 0 {
+                return Err(RESPError::NegativeTimeout);
+            }
+            let all = match command.get(3).map(|mode| mode.to_ascii_uppercase()).as_deref() {
+                None | Some("ALL") => true,
+                Some("WRITE") => false,
+                Some(_) => return Err(RESPError::SyntaxError)
+            };
+            registry.pause(Duration::from_millis(timeout as u64), all);
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "UNPAUSE" => {
+            check_arity(command.len() == 2)?;
+            registry.unpause();
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "REPLY" => {
+            check_arity(command.len() == 3)?;
+            match command[2].to_ascii_uppercase().as_str() {
+                "ON" => *reply_mode = ReplyMode::default(),
+                "OFF" => reply_mode.off = true,
+                // The reply to this command is skipped as well as the next one's.
+                "SKIP" => reply_mode.skip = 2,
+                _ => return Err(RESPError::SyntaxError)
+            }
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("CLIENT {}", command[1])))
+    }
+}
@@ -0,0 +1,1015 @@
+// Cluster mode (`cluster-enabled yes`): the keyspace is split into 16384 hash slots, each served by
+// one primary node of the cluster, and commands on keys of slots served elsewhere are answered
+// with -MOVED <slot> <host>:<port>, pointing the client at the node serving them. The slot of a key
+// is the CRC16 of its hash tag (the part between the first { and the next }, when not empty) or of
+// the whole key, so keys sharing a tag are always in the same slot. Commands on keys of different
+// slots are refused with -CROSSSLOT.
+//
+// Nodes talk over the cluster bus, on `cluster-port` (the port plus 10000 by default). Every second
+// each node pings the others with what it knows about itself (its ID, address, role, the slots it
+// serves and its config epoch) and gossip about the rest, which is how nodes met with CLUSTER MEET
+// learn about the whole cluster. A slot claimed by two primaries goes to the one with the higher
+// config epoch. A node not answering for `cluster-node-timeout` is suspected to be failing (PFAIL),
+// and fails (FAIL) once the majority of the primaries suspect it, which makes the cluster down while
+// it serves slots.
+//
+// Slots move between nodes while serving traffic: the target is set to import the slot and the
+// source to migrate it with CLUSTER SETSLOT, the keys are moved with MIGRATE, and the slot is
+// assigned to the target with SETSLOT NODE, which bumps its config epoch so its claim wins. Meanwhile
+// the source serves the keys it still has and answers with -ASK for the others, and the target
+// serves the keys of the slot to the clients that sent ASKING first.
+//
+// The configuration (the nodes, the slots they serve and the epochs) is saved to
+// `cluster-config-file` in the format of Redis, and loaded from it on startup.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use futures::future::join_all;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::arg::Arg;
+use crate::db::now_ms;
+use crate::{accept_tcp, aof, listen, logging, replication, routed_keys, validate_command, Client, RESPError, RESPValue, SharedState};
+
+pub const SLOTS: usize = 16384;
+// How often the other nodes are pinged.
+const PERIOD: Duration = Duration::from_secs(1);
+// Nodes forgotten with CLUSTER FORGET aren't added back by gossip for this long.
+const FORGET_TTL: Duration = Duration::from_secs(60);
+
+pub struct Cluster {
+    state: Mutex<State>,
+}
+
+struct State {
+    myself: String,
+    current_epoch: u64,
+    nodes: BTreeMap<String, Node>,
+    // The node serving every slot.
+    slots: Vec<Option<String>>,
+    // The slots being moved to other nodes, and from other nodes, along with those nodes.
+    migrating: BTreeMap<usize, String>,
+    importing: BTreeMap<usize, String>,
+    forgotten: HashMap<String, Instant>,
+    messages_sent: u64,
+    messages_received: u64,
+}
+
+struct Node {
+    ip: String,
+    port: u16,
+    cport: u16,
+    // The primary of a replica.
+    primary: Option<String>,
+    config_epoch: u64,
+    // Met with CLUSTER MEET and not answered yet, so its ID isn't known.
+    handshake: Option<Instant>,
+    // Unix times in milliseconds of the ping waiting for a pong, and of the last pong.
+    ping_sent: u64,
+    pong_received: u64,
+    connected: bool,
+    pfail: bool,
+    fail: bool,
+    // The primaries that reported the node as failing, and when.
+    fail_reports: HashMap<String, Instant>,
+}
+
+impl Node {
+    fn new(ip: String, port: u16, cport: u16) -> Self {
+        Self {
+            ip,
+            port,
+            cport,
+            primary: None,
+            config_epoch: 0,
+            handshake: None,
+            ping_sent: 0,
+            pong_received: 0,
+            connected: false,
+            pfail: false,
+            fail: false,
+            fail_reports: HashMap::new(),
+        }
+    }
+
+    fn is_primary(&self) -> bool {
+        self.primary.is_none()
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn key_slot(key: &str) -> usize {
+    let key = key.as_bytes();
+    let tag = key.iter().position(|&b| b == b'{')
+        .and_then(|start| key[start + 1..].iter().position(|&b| b == b'}').map(|len| &key[start + 1..start + 1 + len]))
+        .filter(|tag| !tag.is_empty());
+    crc16(tag.unwrap_or(key)) as usize % SLOTS
+}
+
+// Contiguous ranges of the slots.
+fn ranges(slots: impl Iterator<Item = usize>) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for slot in slots {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == slot => *end = slot,
+            _ => ranges.push((slot, slot))
+        }
+    }
+    ranges
+}
+
+fn format_ranges(ranges: &[(usize, usize)], separator: &str) -> String {
+    ranges.iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn parse_slot(slot: &str) -> Option<usize> {
+    slot.parse().ok().filter(|&slot| slot < SLOTS)
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    (start <= end && end < SLOTS).then_some((start, end))
+}
+
+impl State {
+    fn new(myself: String) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(myself.clone(), Node::new(String::new(), 0, 0));
+        Self {
+            myself,
+            current_epoch: 0,
+            nodes,
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            forgotten: HashMap::new(),
+            messages_sent: 0,
+            messages_received: 0,
+        }
+    }
+
+    fn me(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    fn me_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    fn slots_of(&self, id: &str) -> Vec<(usize, usize)> {
+        ranges((0..SLOTS).filter(|&slot| self.slots[slot].as_deref() == Some(id)))
+    }
+
+    // The primaries serving slots, whose majority decides a node failed.
+    fn size(&self) -> usize {
+        self.slots.iter().flatten().collect::<HashSet<_>>().len()
+    }
+
+    fn is_ok(&self) -> bool {
+        self.slots.iter().all(|owner| owner.as_ref().is_some_and(|owner| !self.nodes.get(owner).is_none_or(|node| node.fail)))
+    }
+
+    fn flags(&self, id: &str) -> String {
+        let node = &self.nodes[id];
+        let mut flags = vec![];
+        if id == self.myself {
+            flags.push("myself");
+        }
+        flags.push(if node.is_primary() { "master" } else { "slave" });
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+        if node.handshake.is_some() {
+            flags.push("handshake");
+        }
+        flags.join(",")
+    }
+
+    // A line of CLUSTER NODES, which is the format of the configuration file as well.
+    fn node_line(&self, id: &str) -> String {
+        let node = &self.nodes[id];
+        let connected = id == self.myself || node.connected;
+        let mut line = format!("{} {}:{}@{} {} {} {} {} {} {}",
+            id, node.ip, node.port, node.cport, self.flags(id), node.primary.as_deref().unwrap_or("-"),
+            node.ping_sent, node.pong_received, node.config_epoch, if connected { "connected" } else { "disconnected" });
+        let slots = format_ranges(&self.slots_of(id), " ");
+        if !slots.is_empty() {
+            write!(line, " {}", slots).unwrap();
+        }
+        if id == self.myself {
+            for (slot, target) in &self.migrating {
+                write!(line, " [{}->-{}]", slot, target).unwrap();
+            }
+            for (slot, source) in &self.importing {
+                write!(line, " [{}-<-{}]", slot, source).unwrap();
+            }
+        }
+        line
+    }
+
+    fn nodes_text(&self) -> String {
+        self.nodes.keys().map(|id| format!("{}\n", self.node_line(id))).collect()
+    }
+
+    // Makes sure this node's config epoch is unique among primaries, bumping it when another primary
+    // has the same one and a greater ID.
+    fn resolve_epoch_collision(&mut self, sender: &str) {
+        let (me, other) = (self.me(), &self.nodes[sender]);
+        if me.is_primary() && other.is_primary() && me.config_epoch == other.config_epoch && self.myself.as_str() < sender {
+            self.current_epoch += 1;
+            let epoch = self.current_epoch;
+            self.me_mut().config_epoch = epoch;
+        }
+    }
+
+    // Bumps the config epoch of this node, making its claims win over the ones of the others.
+    fn bump_epoch(&mut self) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        self.me_mut().config_epoch = epoch;
+    }
+}
+
+impl Cluster {
+    // Loads the configuration file, or starts a new cluster of this node alone when there's none.
+    pub fn open(shared: &SharedState) -> Result<Self, String> {
+        let path = shared.config.get("cluster-config-file");
+        shared.db.lock().index_slots();
+        let state = match std::fs::read_to_string(&path) {
+            Ok(text) => parse_config(&text).map_err(|e| format!("Failed loading {}: {}", path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::new(replication::random_id()),
+            Err(e) => return Err(format!("Failed reading {}: {}", path, e))
+        };
+        let cluster = Self { state: Mutex::new(state) };
+        {
+            let mut state = cluster.state.lock().unwrap();
+            let port = shared.config.get_int("port") as u16;
+            let cport = bus_port(shared);
+            let me = state.me_mut();
+            me.port = port;
+            me.cport = cport;
+            save(&state, shared).map_err(|e| format!("Failed saving {}: {}", path, e))?;
+            logging::log(shared, "notice", format!("Cluster node ID is {}", state.myself));
+        }
+        Ok(cluster)
+    }
+}
+
+fn bus_port(shared: &SharedState) -> u16 {
+    match shared.config.get_int("cluster-port") {
+        0 => (shared.config.get_int("port") + 10000) as u16,
+        port => port as u16
+    }
+}
+
+fn parse_config(text: &str) -> Result<State, String> {
+    let mut state = None;
+    let mut current_epoch = 0;
+    let mut nodes = vec![];
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields[0] == "vars" {
+            for pair in fields[1..].chunks(2) {
+                if let ["currentEpoch", epoch] = pair {
+                    current_epoch = epoch.parse().map_err(|_| format!("invalid current epoch '{}'", epoch))?;
+                }
+            }
+            continue;
+        }
+        let [id, addr, flags, primary, _, _, config_epoch, _, slots @ ..] = &fields[..] else {
+            return Err(format!("invalid line '{}'", line));
+        };
+        let invalid = || format!("invalid address '{}'", addr);
+        let (host, cport) = addr.split_once('@').ok_or_else(invalid)?;
+        let (ip, port) = host.rsplit_once(':').ok_or_else(invalid)?;
+        let mut node = Node::new(ip.to_owned(), port.parse().map_err(|_| invalid())?, cport.parse().map_err(|_| invalid())?);
+        node.primary = (*primary != "-").then(|| primary.to_string());
+        node.config_epoch = config_epoch.parse().map_err(|_| format!("invalid config epoch '{}'", config_epoch))?;
+        node.fail = flags.split(',').any(|flag| flag == "fail");
+        if flags.split(',').any(|flag| flag == "myself") {
+            state = Some(State::new(id.to_string()));
+        }
+        let mut owned = vec![];
+        for range in slots {
+            // Slots being migrated ([slot->-target]) or imported ([slot-<-source]), of this node.
+            if let Some(moving) = range.strip_prefix('[').and_then(|moving| moving.strip_suffix(']')) {
+                let state = state.as_mut().ok_or_else(|| format!("slots in migration of another node '{}'", range))?;
+                let invalid = || format!("invalid slot in migration '{}'", range);
+                match (moving.split_once("->-"), moving.split_once("-<-")) {
+                    (Some((slot, target)), _) => state.migrating.insert(parse_slot(slot).ok_or_else(invalid)?, target.to_owned()),
+                    (_, Some((slot, source))) => state.importing.insert(parse_slot(slot).ok_or_else(invalid)?, source.to_owned()),
+                    _ => return Err(invalid())
+                };
+                continue;
+            }
+            owned.push(parse_range(range).ok_or_else(|| format!("invalid slot range '{}'", range))?);
+        }
+        nodes.push((id.to_string(), node, owned));
+    }
+    let mut state = state.ok_or_else(|| String::from("the node itself is missing"))?;
+    state.current_epoch = current_epoch;
+    for (id, node, owned) in nodes {
+        for (start, end) in owned {
+            state.slots[start..=end].fill(Some(id.clone()));
+        }
+        state.nodes.insert(id, node);
+    }
+    Ok(state)
+}
+
+fn save(state: &State, shared: &SharedState) -> io::Result<()> {
+    let path = shared.config.get("cluster-config-file");
+    let text = format!("{}vars currentEpoch {} lastVoteEpoch 0\n", state.nodes_text(), state.current_epoch);
+    let temp = format!("{}.tmp", path);
+    std::fs::write(&temp, text)?;
+    std::fs::rename(&temp, &path)
+}
+
+fn save_or_log(state: &State, shared: &SharedState) {
+    if let Err(e) = save(state, shared) {
+        logging::log(shared, "warning", format!("Failed saving the cluster configuration: {}", e));
+    }
+}
+
+// Messages of the bus are RESP arrays: the type (MEET, PING or PONG), what the sender knows about
+// itself (ID, ports, primary, config and current epochs, slots), then five fields of gossip about
+// every other node it knows (ID, IP, ports and whether it's failing).
+fn message(kind: &str, state: &State) -> Vec<String> {
+    let me = state.me();
+    let mut message = vec![
+        kind.to_owned(),
+        state.myself.clone(),
+        me.port.to_string(),
+        me.cport.to_string(),
+        me.primary.clone().unwrap_or_else(|| String::from("-")),
+        me.config_epoch.to_string(),
+        state.current_epoch.to_string(),
+        format_ranges(&state.slots_of(&state.myself), ","),
+    ];
+    for (id, node) in state.nodes.iter().filter(|(id, node)| **id != state.myself && node.handshake.is_none()) {
+        let health = if node.fail { "fail" } else if node.pfail { "pfail" } else { "ok" };
+        message.extend([id.clone(), node.ip.clone(), node.port.to_string(), node.cport.to_string(), health.to_owned()]);
+    }
+    message
+}
+
+// Applies a message of another node, which was sent from `ip` to this node's `local_ip`. Returns
+// the ID of the sender.
+fn process(message: &[String], ip: &str, local_ip: &str, shared: &SharedState) -> Option<String> {
+    let cluster = shared.cluster.as_ref().unwrap();
+    let [kind, id, port, cport, primary, config_epoch, current_epoch, slots, gossip @ ..] = message else {
+        return None;
+    };
+    let (Ok(port), Ok(cport), Ok(config_epoch), Ok(current_epoch)) =
+        (port.parse::<u16>(), cport.parse::<u16>(), config_epoch.parse::<u64>(), current_epoch.parse::<u64>()) else {
+        return None;
+    };
+    let claimed: Vec<(usize, usize)> = match slots.as_str() {
+        "" => vec![],
+        slots => slots.split(',').map(parse_range).collect::<Option<_>>()?
+    };
+    let mut state = cluster.state.lock().unwrap();
+    state.messages_received += 1;
+    if *id == state.myself {
+        return None;
+    }
+    if state.me().ip.is_empty() {
+        state.me_mut().ip = local_ip.to_owned();
+    }
+    let timeout = shared.config.get_int("cluster-node-timeout") as u64;
+    if !state.nodes.contains_key(id) {
+        // Only met nodes are trusted, the others are learned through them.
+        if kind != "MEET" {
+            return Some(id.clone());
+        }
+        state.nodes.insert(id.clone(), Node::new(ip.to_owned(), port, cport));
+        logging::log(shared, "notice", format!("Node {} ({}:{}) met this node", id, ip, port));
+    }
+    state.current_epoch = state.current_epoch.max(current_epoch);
+    let mut changed = false;
+    {
+        let node = state.nodes.get_mut(id).unwrap();
+        let primary = (primary != "-").then(|| primary.clone());
+        changed |= node.ip != ip || node.port != port || node.cport != cport || node.primary != primary || node.config_epoch != config_epoch;
+        (node.ip, node.port, node.cport, node.primary, node.config_epoch) = (ip.to_owned(), port, cport, primary, config_epoch);
+        if kind == "PONG" {
+            node.ping_sent = 0;
+            node.pong_received = now_ms();
+        }
+        node.pfail = false;
+        node.fail_reports.clear();
+        if node.fail {
+            node.fail = false;
+            logging::log(shared, "notice", format!("Clear FAIL state for node {}: is reachable again.", id));
+        }
+    }
+    state.resolve_epoch_collision(id);
+
+    // Slots go to the claim of the highest config epoch, and the ones the sender stopped claiming
+    // are unassigned. The slots being imported are left alone, they're assigned with SETSLOT NODE.
+    if state.nodes[id].is_primary() {
+        let mut claims = vec![false; SLOTS];
+        for (start, end) in claimed {
+            claims[start..=end].fill(true);
+        }
+        for (slot, claimed) in claims.into_iter().enumerate() {
+            if state.importing.contains_key(&slot) {
+                continue;
+            }
+            let update = match &state.slots[slot] {
+                Some(owner) if owner == id => !claimed,
+                Some(owner) => claimed && state.nodes.get(owner).is_none_or(|owner| owner.config_epoch < config_epoch),
+                None => claimed
+            };
+            if update {
+                if state.slots[slot].as_ref() == Some(&state.myself) {
+                    state.migrating.remove(&slot);
+                }
+                state.slots[slot] = claimed.then(|| id.clone());
+                changed = true;
+            }
+        }
+    }
+
+    let reporter_is_primary = state.nodes[id].is_primary();
+    for entry in gossip.chunks(5) {
+        let [other, other_ip, other_port, other_cport, health] = entry else {
+            break;
+        };
+        if *other == state.myself || state.forgotten.contains_key(other) {
+            continue;
+        }
+        match state.nodes.get_mut(other) {
+            Some(node) => {
+                if reporter_is_primary && health != "ok" {
+                    node.fail_reports.insert(id.clone(), Instant::now());
+                } else {
+                    node.fail_reports.remove(id);
+                }
+                // Unless this node heard from it lately.
+                if health == "fail" && !node.fail && now_ms().saturating_sub(node.pong_received) > timeout {
+                    node.fail = true;
+                    changed = true;
+                    logging::log(shared, "notice", format!("Marking node {} as failing, as reported by {}.", other, id));
+                }
+            },
+            None => {
+                let (Ok(other_port), Ok(other_cport)) = (other_port.parse(), other_cport.parse()) else {
+                    continue;
+                };
+                // Replacing the handshake of the same address, when it was met with CLUSTER MEET too.
+                state.nodes.retain(|_, node| node.handshake.is_none() || node.ip != *other_ip || node.cport != other_cport);
+                state.nodes.insert(other.clone(), Node::new(other_ip.clone(), other_port, other_cport));
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        save_or_log(&state, shared);
+    }
+    Some(id.clone())
+}
+
+async fn read_message(socket: &mut TcpStream, buf: &mut BytesMut) -> io::Result<Vec<String>> {
+    loop {
+        let mut at = 0;
+        if let Some(message) = aof::parse_command(buf, &mut at)? {
+            let _ = buf.split_to(at);
+            return Ok(message.iter().map(|arg| arg.to_string()).collect());
+        }
+        if socket.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+async fn write_message(socket: &mut TcpStream, message: &[String]) -> io::Result<()> {
+    let mut buf = vec![];
+    aof::encode(&message.iter().map(Arg::from).collect::<Vec<_>>(), &mut buf);
+    socket.write_all(&buf).await
+}
+
+// Answers the pings of a node connected to the bus.
+async fn serve_node(mut socket: TcpStream, shared: Arc<SharedState>) -> io::Result<()> {
+    let cluster = shared.cluster.as_ref().unwrap();
+    let ip = socket.peer_addr()?.ip().to_string();
+    let local_ip = socket.local_addr()?.ip().to_string();
+    let mut buf = BytesMut::new();
+    loop {
+        let message = read_message(&mut socket, &mut buf).await?;
+        if !matches!(message[0].as_str(), "MEET" | "PING") {
+            continue;
+        }
+        process(&message, &ip, &local_ip, &shared);
+        let pong = {
+            let mut state = cluster.state.lock().unwrap();
+            state.messages_sent += 1;
+            self::message("PONG", &state)
+        };
+        write_message(&mut socket, &pong).await?;
+    }
+}
+
+// Pings the node (meets it when it may not know this node yet), returning its ID from the pong.
+async fn ping(id: &str, addr: (String, u16), shared: &SharedState) -> io::Result<String> {
+    let cluster = shared.cluster.as_ref().unwrap();
+    let timeout = Duration::from_millis(shared.config.get_int("cluster-node-timeout") as u64).min(PERIOD);
+    let ping = {
+        let mut state = cluster.state.lock().unwrap();
+        state.messages_sent += 1;
+        let Some(node) = state.nodes.get_mut(id) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if node.ping_sent == 0 {
+            node.ping_sent = now_ms();
+        }
+        let kind = if node.pong_received == 0 { "MEET" } else { "PING" };
+        message(kind, &state)
+    };
+    let mut socket = tokio::time::timeout(timeout, TcpStream::connect((addr.0.as_str(), addr.1))).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout connecting"))??;
+    let local_ip = socket.local_addr()?.ip().to_string();
+    write_message(&mut socket, &ping).await?;
+    let pong = tokio::time::timeout(timeout, read_message(&mut socket, &mut BytesMut::new())).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout waiting for the pong"))??;
+    if pong[0] != "PONG" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a pong"));
+    }
+    // A node met by its address is known by its ID from now on.
+    let real_id = pong.get(1).cloned().unwrap_or_default();
+    if real_id != id {
+        let mut state = cluster.state.lock().unwrap();
+        if let Some(mut node) = state.nodes.remove(id) {
+            if real_id != state.myself && !state.nodes.contains_key(&real_id) {
+                node.handshake = None;
+                node.pong_received = 0;
+                state.nodes.insert(real_id.clone(), node);
+            }
+        }
+    }
+    process(&pong, &addr.0, &local_ip, shared).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pong"))
+}
+
+// Runs the cluster bus, and pings the other nodes every period.
+pub async fn run(shared: Arc<SharedState>) {
+    let Some(cluster) = &shared.cluster else {
+        return;
+    };
+    let listeners = match listen(bus_port(&shared), &shared).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            logging::log(&shared, "warning", format!("Failed listening on the cluster bus: {}", e));
+            return;
+        }
+    };
+    let accept = {
+        let shared = shared.clone();
+        async move {
+            loop {
+                if let Ok((socket, _)) = accept_tcp(&listeners).await {
+                    tokio::spawn(serve_node(socket, shared.clone()));
+                }
+            }
+        }
+    };
+    tokio::spawn(accept);
+
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let nodes: Vec<(String, (String, u16))> = {
+            let state = cluster.state.lock().unwrap();
+            state.nodes.iter()
+                .filter(|(id, _)| **id != state.myself)
+                .map(|(id, node)| (id.clone(), (node.ip.clone(), node.cport)))
+                .collect()
+        };
+        let results = join_all(nodes.iter().map(|(id, addr)| ping(id, addr.clone(), &shared))).await;
+
+        let mut state = cluster.state.lock().unwrap();
+        let timeout = shared.config.get_int("cluster-node-timeout") as u64;
+        let now = now_ms();
+        for ((id, _), result) in nodes.iter().zip(results) {
+            let Some(node) = state.nodes.get_mut(id) else {
+                continue;
+            };
+            node.connected = result.is_ok();
+            if node.handshake.is_some_and(|since| since.elapsed() > Duration::from_millis(timeout).max(PERIOD * 2)) {
+                logging::log(&shared, "notice", format!("Handshake with {}:{} timed out, forgetting it", node.ip, node.port));
+                state.nodes.remove(id);
+                continue;
+            }
+            if node.handshake.is_none() && !node.pfail && node.ping_sent != 0 && now - node.ping_sent > timeout {
+                node.pfail = true;
+                logging::log(&shared, "notice", format!("Marking node {} as failing (possibly)", id));
+            }
+        }
+
+        // A node fails once the majority of the primaries (counting this one) suspect it.
+        let majority = state.size() / 2 + 1;
+        let me_primary = state.me().is_primary();
+        let mut failed = vec![];
+        for (id, node) in state.nodes.iter_mut() {
+            node.fail_reports.retain(|_, at| at.elapsed() < Duration::from_millis(timeout * 2));
+            if node.pfail && !node.fail && node.fail_reports.len() + me_primary as usize >= majority {
+                node.fail = true;
+                failed.push(id.clone());
+            }
+        }
+        for id in &failed {
+            logging::log(&shared, "notice", format!("Marking node {} as failing (quorum reached).", id));
+        }
+        state.forgotten.retain(|_, at| at.elapsed() < FORGET_TTL);
+
+        // Replicating the primary this node is a replica of, wherever it is now.
+        let primary = state.me().primary.as_ref().and_then(|primary| state.nodes.get(primary)).map(|node| (node.ip.clone(), node.port));
+        if !failed.is_empty() {
+            save_or_log(&state, &shared);
+        }
+        drop(state);
+        if let Some(primary) = primary.filter(|(ip, _)| !ip.is_empty()) {
+            shared.replication.set_primary(Some(primary), &shared);
+        }
+    }
+}
+
+// Checks the command is served by this node, answering with a redirection to the node serving its
+// keys when it isn't.
+pub fn check_redirect(command: &[Arg], client: &Client, shared: &SharedState) -> Result<(), RESPError> {
+    let Some(cluster) = &shared.cluster else {
+        return Ok(());
+    };
+    if client.from_primary {
+        return Ok(());
+    }
+    // Invalid commands are refused when they run.
+    let Ok(spec) = validate_command(command, shared) else {
+        return Ok(());
+    };
+    let keys = routed_keys(spec, command);
+    let Some(first) = keys.first() else {
+        return Ok(());
+    };
+    let slot = key_slot(first);
+    if keys[1..].iter().any(|key| key_slot(key) != slot) {
+        return Err(RESPError::CrossSlot);
+    }
+
+    let state = cluster.state.lock().unwrap();
+    let Some(owner) = &state.slots[slot] else {
+        return Err(RESPError::ClusterDown("Hash slot not served"));
+    };
+    if !state.is_ok() {
+        return Err(RESPError::ClusterDown("The cluster is down"));
+    }
+    if *owner == state.myself {
+        // The keys of a slot being migrated that aren't here anymore are asked from the target. Its
+        // channels are served here until the migration completes, like in Redis.
+        let Some(target) = state.migrating.get(&slot).filter(|_| !spec.has_flag("pubsub")) else {
+            return Ok(());
+        };
+        let missing = {
+            let db = shared.db.lock();
+            keys.iter().filter(|key| !db.contains_key(key)).count()
+        };
+        return match missing {
+            0 => Ok(()),
+            missing if missing == keys.len() => {
+                let node = &state.nodes[target];
+                Err(RESPError::Ask(slot, format!("{}:{}", node.ip, node.port)))
+            },
+            _ => Err(RESPError::TryAgain)
+        };
+    }
+    if state.importing.contains_key(&slot) && (client.asking || spec.has_flag("asking")) {
+        return Ok(());
+    }
+    // Replicas serve reads to the connections that asked for it with READONLY.
+    if state.me().primary.as_ref() == Some(owner) && client.readonly && !spec.has_flag("write") {
+        return Ok(());
+    }
+    let node = &state.nodes[owner];
+    Err(RESPError::Moved(slot, format!("{}:{}", node.ip, node.port)))
+}
+
+fn bulk(value: impl ToString) -> RESPValue {
+    RESPValue::BlobString(value.to_string().into())
+}
+
+fn node_entry(state: &State, id: &str) -> RESPValue {
+    let node = &state.nodes[id];
+    RESPValue::Array(vec![bulk(&node.ip), RESPValue::Number(node.port as i64), bulk(id)])
+}
+
+// The slots given as arguments, one by one or as ranges.
+fn parse_slots(args: &[Arg], as_ranges: bool) -> Result<Vec<usize>, RESPError> {
+    let slot = |arg: &Arg| arg.parse::<usize>().ok().filter(|&slot| slot < SLOTS)
+        .ok_or_else(|| RESPError::ClusterError(String::from("Invalid or out of range slot")));
+    if !as_ranges {
+        return args.iter().map(slot).collect();
+    }
+    if !args.len().is_multiple_of(2) {
+        return Err(RESPError::ClusterError(String::from("wrong number of arguments for 'cluster' command")));
+    }
+    let mut slots = vec![];
+    for pair in args.chunks(2) {
+        let (start, end) = (slot(&pair[0])?, slot(&pair[1])?);
+        if start > end {
+            return Err(RESPError::ClusterError(format!("start slot number {} is greater than end slot number {}", start, end)));
+        }
+        slots.extend(start..=end);
+    }
+    Ok(slots)
+}
+
+// CLUSTER subcommand [arg ...]
+pub fn cluster(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let Some(cluster) = &shared.cluster else {
+        return Err(RESPError::ClusterDisabled);
+    };
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("CLUSTER|{}", subcommand)))
+    };
+    let mut state = cluster.state.lock().unwrap();
+    let ok = || Ok(RESPValue::SimpleString(String::from("OK")));
+
+    match subcommand.as_str() {
+        "MYID" => {
+            check_arity(command.len() == 2)?;
+            Ok(bulk(&state.myself))
+        },
+        "INFO" => {
+            check_arity(command.len() == 2)?;
+            let assigned = state.slots.iter().flatten().count();
+            let (mut pfail, mut fail) = (0, 0);
+            for owner in state.slots.iter().flatten() {
+                match state.nodes.get(owner) {
+                    Some(node) if node.fail => fail += 1,
+                    Some(node) if node.pfail => pfail += 1,
+                    _ => {}
+                }
+            }
+            let fields = [
+                ("cluster_state", String::from(if state.is_ok() { "ok" } else { "fail" })),
+                ("cluster_slots_assigned", assigned.to_string()),
+                ("cluster_slots_ok", (assigned - pfail - fail).to_string()),
+                ("cluster_slots_pfail", pfail.to_string()),
+                ("cluster_slots_fail", fail.to_string()),
+                ("cluster_known_nodes", state.nodes.len().to_string()),
+                ("cluster_size", state.size().to_string()),
+                ("cluster_current_epoch", state.current_epoch.to_string()),
+                ("cluster_my_epoch", state.me().config_epoch.to_string()),
+                ("cluster_stats_messages_sent", state.messages_sent.to_string()),
+                ("cluster_stats_messages_received", state.messages_received.to_string()),
+            ];
+            Ok(bulk(fields.iter().map(|(field, value)| format!("{}:{}\r\n", field, value)).collect::<String>()))
+        },
+        "KEYSLOT" => {
+            check_arity(command.len() == 3)?;
+            Ok(RESPValue::Number(key_slot(&command[2]) as i64))
+        },
+        "COUNTKEYSINSLOT" => {
+            check_arity(command.len() == 3)?;
+            let slot = parse_slots(&command[2..3], false)?[0];
+            Ok(RESPValue::Number(shared.db.lock().slot_keys(slot).count() as i64))
+        },
+        "GETKEYSINSLOT" => {
+            check_arity(command.len() == 4)?;
+            let slot = parse_slots(&command[2..3], false)?[0];
+            let count = command[3].parse::<usize>().map_err(|_| RESPError::ClusterError(String::from("Invalid number of keys")))?;
+            let db = shared.db.lock();
+            Ok(RESPValue::Array(db.slot_keys(slot).take(count).map(bulk).collect()))
+        },
+        "NODES" => {
+            check_arity(command.len() == 2)?;
+            Ok(bulk(state.nodes_text()))
+        },
+        "SLOTS" => {
+            check_arity(command.len() == 2)?;
+            let mut owners: Vec<(usize, usize, &String)> = vec![];
+            for (slot, owner) in state.slots.iter().enumerate() {
+                let Some(owner) = owner else {
+                    continue;
+                };
+                match owners.last_mut() {
+                    Some((_, end, last)) if *last == owner && *end + 1 == slot => *end = slot,
+                    _ => owners.push((slot, slot, owner))
+                }
+            }
+            Ok(RESPValue::Array(owners.into_iter().map(|(start, end, owner)| {
+                let mut entry = vec![RESPValue::Number(start as i64), RESPValue::Number(end as i64), node_entry(&state, owner)];
+                let replicas = state.nodes.iter().filter(|(_, node)| node.primary.as_ref() == Some(owner) && !node.fail);
+                entry.extend(replicas.map(|(id, _)| node_entry(&state, id)));
+                RESPValue::Array(entry)
+            }).collect()))
+        },
+        "SHARDS" => {
+            check_arity(command.len() == 2)?;
+            let primaries = state.nodes.iter().filter(|(_, node)| node.is_primary() && node.handshake.is_none());
+            Ok(RESPValue::Array(primaries.map(|(primary, _)| {
+                let slots = state.slots_of(primary).into_iter()
+                    .flat_map(|(start, end)| [RESPValue::Number(start as i64), RESPValue::Number(end as i64)])
+                    .collect();
+                let members = std::iter::once(primary)
+                    .chain(state.nodes.iter().filter(|(_, node)| node.primary.as_ref() == Some(primary)).map(|(id, _)| id));
+                let nodes = members.map(|id| {
+                    let node = &state.nodes[id];
+                    RESPValue::Array(vec![
+                        bulk("id"), bulk(id),
+                        bulk("port"), RESPValue::Number(node.port as i64),
+                        bulk("ip"), bulk(&node.ip),
+                        bulk("endpoint"), bulk(&node.ip),
+                        bulk("role"), bulk(if node.is_primary() { "master" } else { "replica" }),
+                        bulk("health"), bulk(if node.fail { "fail" } else { "online" }),
+                    ])
+                }).collect();
+                RESPValue::Array(vec![bulk("slots"), RESPValue::Array(slots), bulk("nodes"), RESPValue::Array(nodes)])
+            }).collect()))
+        },
+        "MEET" => {
+            check_arity(command.len() == 4 || command.len() == 5)?;
+            let port = command[3].parse::<u16>().map_err(|_| RESPError::ClusterError(format!("Invalid base port specified: {}", command[3])))?;
+            let cport = match command.get(4) {
+                Some(cport) => cport.parse::<u16>().map_err(|_| RESPError::ClusterError(format!("Invalid bus port specified: {}", cport)))?,
+                None => port.wrapping_add(10000)
+            };
+            if command[2].parse::<std::net::IpAddr>().is_err() {
+                return Err(RESPError::ClusterError(format!("Invalid node address specified: {}:{}", command[2], port)));
+            }
+            let mut node = Node::new(command[2].to_string(), port, cport);
+            node.handshake = Some(Instant::now());
+            state.nodes.insert(replication::random_id(), node);
+            ok()
+        },
+        "FORGET" => {
+            check_arity(command.len() == 3)?;
+            let id = &*command[2];
+            if *id == state.myself {
+                return Err(RESPError::ClusterError(String::from("I tried hard but I can't forget myself...")));
+            }
+            if state.me().primary.as_ref() == Some(id) {
+                return Err(RESPError::ClusterError(String::from("Can't forget my master!")));
+            }
+            if state.nodes.remove(id).is_none() {
+                return Err(RESPError::ClusterError(format!("Unknown node {}", id)));
+            }
+            for owner in state.slots.iter_mut().filter(|owner| owner.as_ref() == Some(id)) {
+                *owner = None;
+            }
+            state.forgotten.insert(id.clone(), Instant::now());
+            save_or_log(&state, shared);
+            ok()
+        },
+        "ADDSLOTS" | "ADDSLOTSRANGE" | "DELSLOTS" | "DELSLOTSRANGE" => {
+            check_arity(command.len() >= 3)?;
+            let slots = parse_slots(&command[2..], subcommand.ends_with("RANGE"))?;
+            let adding = subcommand.starts_with("ADD");
+            if adding && !state.me().is_primary() {
+                return Err(RESPError::ClusterError(String::from("Only masters can be assigned slots")));
+            }
+            for &slot in &slots {
+                match (&state.slots[slot], adding) {
+                    (Some(_), true) => return Err(RESPError::ClusterError(format!("Slot {} is already busy", slot))),
+                    (None, false) => return Err(RESPError::ClusterError(format!("Slot {} is already unassigned", slot))),
+                    _ => {}
+                }
+            }
+            let myself = state.myself.clone();
+            for slot in slots {
+                state.slots[slot] = adding.then(|| myself.clone());
+            }
+            save_or_log(&state, shared);
+            ok()
+        },
+        "SETSLOT" => {
+            check_arity(command.len() == 4 || command.len() == 5)?;
+            if !state.me().is_primary() {
+                return Err(RESPError::ClusterError(String::from("Please use SETSLOT only with masters.")));
+            }
+            let slot = parse_slots(&command[2..3], false)?[0];
+            let action = command[3].to_ascii_uppercase();
+            let node = match (action.as_str(), command.get(4)) {
+                ("STABLE", None) => None,
+                ("MIGRATING" | "IMPORTING" | "NODE", Some(id)) => match state.nodes.get(id.as_str()) {
+                    Some(node) if node.is_primary() => Some(id.to_string()),
+                    Some(_) => return Err(RESPError::ClusterError(String::from("Target node is not a master"))),
+                    None => return Err(RESPError::ClusterError(format!("I don't know about node {}", id)))
+                },
+                _ => return Err(RESPError::SyntaxError)
+            };
+            let owned = state.slots[slot].as_ref() == Some(&state.myself);
+            match (action.as_str(), node) {
+                ("MIGRATING", Some(target)) => {
+                    if !owned {
+                        return Err(RESPError::ClusterError(format!("I'm not the owner of hash slot {}", slot)));
+                    }
+                    if target == state.myself {
+                        return Err(RESPError::ClusterError(String::from("Can't MIGRATE to myself")));
+                    }
+                    state.migrating.insert(slot, target);
+                },
+                ("IMPORTING", Some(source)) => {
+                    if owned {
+                        return Err(RESPError::ClusterError(format!("I'm already the owner of hash slot {}", slot)));
+                    }
+                    if source == state.myself {
+                        return Err(RESPError::ClusterError(String::from("Can't IMPORT from myself")));
+                    }
+                    state.importing.insert(slot, source);
+                },
+                ("NODE", Some(id)) => {
+                    if owned && id != state.myself && shared.db.lock().slot_keys(slot).next().is_some() {
+                        return Err(RESPError::ClusterError(format!(
+                            "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.", slot)));
+                    }
+                    if id != state.myself {
+                        state.migrating.remove(&slot);
+                    }
+                    // The claim of the node that imported the slot has to win over the one of its
+                    // former owner.
+                    if id == state.myself && state.importing.remove(&slot).is_some() {
+                        state.bump_epoch();
+                        logging::log(shared, "notice", format!("Slot {} imported, config epoch set to {}", slot, state.me().config_epoch));
+                    }
+                    state.slots[slot] = Some(id);
+                },
+                _ => {
+                    state.migrating.remove(&slot);
+                    state.importing.remove(&slot);
+                }
+            }
+            save_or_log(&state, shared);
+            ok()
+        },
+        "REPLICATE" => {
+            check_arity(command.len() == 3)?;
+            let id = &*command[2];
+            let Some(primary) = state.nodes.get(id) else {
+                return Err(RESPError::ClusterError(format!("Unknown node {}", id)));
+            };
+            if *id == state.myself {
+                return Err(RESPError::ClusterError(String::from("Can't replicate myself")));
+            }
+            if !primary.is_primary() {
+                return Err(RESPError::ClusterError(String::from("I can only replicate a master, not a replica.")));
+            }
+            if state.me().is_primary() && (!state.slots_of(&state.myself).is_empty() || shared.db.lock().len() != 0) {
+                return Err(RESPError::ClusterError(String::from("To set a master the node must be empty and without assigned slots.")));
+            }
+            let addr = (primary.ip.clone(), primary.port);
+            state.me_mut().primary = Some(id.clone());
+            state.me_mut().config_epoch = 0;
+            save_or_log(&state, shared);
+            drop(state);
+            shared.replication.set_primary(Some(addr), shared);
+            ok()
+        },
+        "REPLICAS" | "SLAVES" => {
+            check_arity(command.len() == 3)?;
+            if !state.nodes.contains_key(command[2].as_str()) {
+                return Err(RESPError::ClusterError(format!("Unknown node {}", command[2])));
+            }
+            Ok(RESPValue::Array(state.nodes.iter()
+                .filter(|(_, node)| node.primary.as_ref() == Some(&command[2]))
+                .map(|(id, _)| bulk(state.node_line(id)))
+                .collect()))
+        },
+        "BUMPEPOCH" => {
+            check_arity(command.len() == 2)?;
+            state.bump_epoch();
+            save_or_log(&state, shared);
+            Ok(RESPValue::SimpleString(format!("BUMPED {}", state.me().config_epoch)))
+        },
+        "SAVECONFIG" => {
+            check_arity(command.len() == 2)?;
+            save(&state, shared).map_err(|e| RESPError::ClusterError(format!("error saving the cluster node config: {}", e)))?;
+            ok()
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("CLUSTER {}", command[1])))
+    }
+}
@@ -0,0 +1,94 @@
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use bytes::Bytes;
+
+pub const ALGORITHMS: &[&str] = &["no", "lz4", "zstd"];
+
+// The algorithm new string values are compressed with (an index into ALGORITHMS), and the size
+// from which they are. Set through the config.
+pub static ALGORITHM: AtomicU8 = AtomicU8::new(0);
+pub static THRESHOLD: AtomicUsize = AtomicUsize::new(1024);
+
+const ZSTD_LEVEL: i32 = 3;
+
+// Totals of the compressed values that currently exist, for INFO memory.
+static VALUES: AtomicU64 = AtomicU64::new(0);
+static RAW_BYTES: AtomicU64 = AtomicU64::new(0);
+static COMPRESSED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Lz4,
+    Zstd,
+}
+
+// A string value stored compressed, decompressed whenever it's read.
+pub struct Compressed {
+    algorithm: Algorithm,
+    len: usize,
+    data: Box<[u8]>,
+}
+
+impl Compressed {
+    fn new(algorithm: Algorithm, len: usize, data: Box<[u8]>) -> Self {
+        VALUES.fetch_add(1, Ordering::Relaxed);
+        RAW_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+        COMPRESSED_BYTES.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Self { algorithm, len, data }
+    }
+
+    pub fn decompress(&self) -> Bytes {
+        match self.algorithm {
+            Algorithm::Lz4 => lz4_flex::block::decompress(&self.data, self.len).map_err(|e| e.to_string()),
+            Algorithm::Zstd => zstd::bulk::decompress(&self.data, self.len).map_err(|e| e.to_string()),
+        }.map(Bytes::from).unwrap_or_else(|e| panic!("Failed decompressing a value: {}", e))
+    }
+
+    // The name of the algorithm, as shown by OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        match self.algorithm {
+            Algorithm::Lz4 => "lz4",
+            Algorithm::Zstd => "zstd",
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.data.len()
+    }
+}
+
+impl Clone for Compressed {
+    fn clone(&self) -> Self {
+        Self::new(self.algorithm, self.len, self.data.clone())
+    }
+}
+
+impl Drop for Compressed {
+    fn drop(&mut self) {
+        VALUES.fetch_sub(1, Ordering::Relaxed);
+        RAW_BYTES.fetch_sub(self.len as u64, Ordering::Relaxed);
+        COMPRESSED_BYTES.fetch_sub(self.data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+// Compresses the string when compression is on, it's at least the threshold, and it shrinks.
+pub fn compress(bytes: &[u8]) -> Option<Compressed> {
+    if bytes.len() < THRESHOLD.load(Ordering::Relaxed) {
+        return None;
+    }
+    let (algorithm, data) = match ALGORITHM.load(Ordering::Relaxed) {
+        1 => (Algorithm::Lz4, lz4_flex::block::compress(bytes)),
+        2 => (Algorithm::Zstd, zstd::bulk::compress(bytes, ZSTD_LEVEL).ok()?),
+        _ => return None
+    };
+    if data.len() >= bytes.len() {
+        return None;
+    }
+    Some(Compressed::new(algorithm, bytes.len(), data.into_boxed_slice()))
+}
+
+// The amount of compressed values, and their size before and after compression.
+pub fn totals() -> (u64, u64, u64) {
+    (VALUES.load(Ordering::Relaxed), RAW_BYTES.load(Ordering::Relaxed), COMPRESSED_BYTES.load(Ordering::Relaxed))
+}
@@ -0,0 +1,1330 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+
+use crate::audit::quote;
+use crate::glob::glob_match;
+use crate::{actors, aof, bloom, cluster, compression, encryption, eviction, expire, hash, list, logging, metrics, notify, otel, replication, shutdown, snapshot, sorted_set, storage, uring, RESPError, SharedState};
+
+enum Kind {
+    Integer { min: i64, max: i64 },
+    // Bytes, which may be given with a unit (like 100mb or 1gb).
+    Memory,
+    Enum(&'static [&'static str]),
+    String,
+    // Parsed into its canonical form by the given function, None when invalid.
+    Custom(fn(&str) -> Option<String>),
+}
+
+pub struct Parameter {
+    pub name: &'static str,
+    alias: Option<&'static str>,
+    kind: Kind,
+    default: &'static str,
+    mutable: bool,
+    // Applies a new value to the state that depends on it, called after the value was validated.
+    apply: fn(&SharedState, &str) -> Result<(), String>,
+}
+
+fn no_apply(_: &SharedState, _: &str) -> Result<(), String> {
+    Ok(())
+}
+
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// A file in `dir`, so it can't be a path.
+fn parse_file_name(value: &str) -> Option<String> {
+    (!value.is_empty() && !value.contains('/')).then(|| value.to_owned())
+}
+
+// IP addresses separated by spaces, where * is every IPv4 address and ::* every IPv6 one. Ones
+// prefixed with - are skipped when they aren't available.
+fn parse_bind(value: &str) -> Option<String> {
+    let addresses: Vec<&str> = value.split_whitespace().collect();
+    let valid = addresses.iter()
+        .map(|address| address.strip_prefix('-').unwrap_or(address))
+        .all(|address| matches!(address, "*" | "::*") || address.parse::<std::net::IpAddr>().is_ok());
+    (valid && !addresses.is_empty()).then(|| addresses.join(" "))
+}
+
+// Pairs of `<seconds> <changes>`, or nothing to disable saving.
+fn parse_save_points(value: &str) -> Option<String> {
+    let numbers = value.split_whitespace().map(|n| n.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+    if numbers.len() % 2 != 0 || numbers.contains(&0) {
+        return None;
+    }
+    Some(numbers.iter().map(u64::to_string).collect::<Vec<_>>().join(" "))
+}
+
+const YES_NO: &[&str] = &["yes", "no"];
+
+const TLS_AUTH_CLIENTS: &[&str] = &["yes", "no", "optional"];
+
+const RATE_LIMIT_ACTIONS: &[&str] = &["delay", "reject"];
+
+const YES_NO_LOCAL: &[&str] = &["no", "yes", "local"];
+
+// Parameters that point the server at files, letting whoever sets them write (or read keys from)
+// anywhere, so CONFIG SET only changes them when enable-protected-configs allows it.
+const PROTECTED: &[&str] = &["dir", "dbfilename", "audit-log", "encryption-key-file"];
+
+pub const LOG_LEVELS: &[&str] = &["debug", "verbose", "notice", "warning", "nothing"];
+
+// Debug builds log every request by default.
+const DEFAULT_LOG_LEVEL: &str = if cfg!(debug_assertions) { "debug" } else { "notice" };
+
+pub const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "6379",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "bind",
+        alias: None,
+        kind: Kind::Custom(parse_bind),
+        default: "127.0.0.1",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tls-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tls-cert-file",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tls-key-file",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tls-ca-cert-file",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tls-auth-clients",
+        alias: None,
+        kind: Kind::Enum(TLS_AUTH_CLIENTS),
+        default: "yes",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tcp-keepalive",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "300",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "tcp-nodelay",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "yes",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "client-rate-limit-commands",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "client-rate-limit-bytes",
+        alias: None,
+        kind: Kind::Memory,
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "user-rate-limit-commands",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "user-rate-limit-bytes",
+        alias: None,
+        kind: Kind::Memory,
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "rate-limit-action",
+        alias: None,
+        kind: Kind::Enum(RATE_LIMIT_ACTIONS),
+        default: "delay",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "enable-debug-command",
+        alias: None,
+        kind: Kind::Enum(YES_NO_LOCAL),
+        default: "no",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "enable-protected-configs",
+        alias: None,
+        kind: Kind::Enum(YES_NO_LOCAL),
+        default: "no",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "protected-mode",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "yes",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "unixsocket",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "daemonize",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "pidfile",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "threads",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 1024 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "keyspace-shards",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: cluster::SLOTS as i64 },
+        default: "64",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "execution-model",
+        alias: None,
+        kind: Kind::Enum(actors::MODELS),
+        default: "locking",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "network-backend",
+        alias: None,
+        kind: Kind::Enum(uring::BACKENDS),
+        default: "epoll",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "proto-max-bulk-len",
+        alias: None,
+        kind: Kind::Memory,
+        default: "512mb",
+        mutable: true,
+        apply: |_, value| {
+            crate::MAX_BULK_LEN.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "proto-max-multibulk-len",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: i32::MAX as i64 },
+        default: "1048576",
+        mutable: true,
+        apply: |_, value| {
+            crate::MAX_MULTIBULK_LEN.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "proto-max-nesting",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 1024 },
+        default: "8",
+        mutable: true,
+        apply: |_, value| {
+            crate::MAX_NESTING.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxclients",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: i64::MAX },
+        default: "10000",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "logfile",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: |shared, value| shared.logger.open(value).map_err(|e| e.to_string()),
+    },
+    Parameter {
+        name: "dir",
+        alias: None,
+        kind: Kind::String,
+        default: ".",
+        mutable: true,
+        apply: |_, value| std::env::set_current_dir(value).map_err(|e| e.to_string()),
+    },
+    Parameter {
+        name: "dbfilename",
+        alias: None,
+        kind: Kind::Custom(parse_file_name),
+        default: "dump.bast",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "save",
+        alias: None,
+        kind: Kind::Custom(parse_save_points),
+        default: "3600 1 300 100 60 10000",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "shutdown-timeout",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "10",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "shutdown-on-sigterm",
+        alias: None,
+        kind: Kind::Custom(shutdown::parse_signal_flags),
+        default: "default",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "shutdown-on-sigint",
+        alias: None,
+        kind: Kind::Custom(shutdown::parse_signal_flags),
+        default: "default",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "snapshot-format",
+        alias: None,
+        kind: Kind::Enum(snapshot::FORMATS),
+        default: "bast",
+        mutable: true,
+        apply: no_apply,
+    },
+    // The commands are run by the shell, so they can only be set on startup.
+    Parameter {
+        name: "snapshot-storage",
+        alias: None,
+        kind: Kind::Enum(storage::BACKENDS),
+        default: "disk",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "snapshot-upload-command",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "snapshot-download-command",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    // Set again (or reloaded) to read the keys again after rotating them, see encryption.rs.
+    Parameter {
+        name: "encryption-key-file",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+        apply: |shared, value| {
+            encryption::set_keys(value, &shared.config.get("encryption-key-command"))?;
+            aof::keys_changed(shared);
+            Ok(())
+        },
+    },
+    // Run by the shell, so it can only be set on startup. The keys it gives are read again on SIGHUP.
+    Parameter {
+        name: "encryption-key-command",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: |shared, value| encryption::set_keys(&shared.config.get("encryption-key-file"), value),
+    },
+    Parameter {
+        name: "appendonly",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: |shared, value| aof::set_enabled(shared, value == "yes"),
+    },
+    Parameter {
+        name: "appendfilename",
+        alias: None,
+        kind: Kind::Custom(parse_file_name),
+        default: "appendonly.aof",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "appendfsync",
+        alias: None,
+        kind: Kind::Enum(aof::FSYNC_POLICIES),
+        default: "everysec",
+        mutable: true,
+        apply: |_, value| {
+            let policy = aof::FSYNC_POLICIES.iter().position(|policy| *policy == value).unwrap();
+            aof::FSYNC.store(policy as u8, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "aof-load-truncated",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "yes",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "auto-aof-rewrite-percentage",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "100",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "auto-aof-rewrite-min-size",
+        alias: None,
+        kind: Kind::Memory,
+        default: "67108864",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        alias: None,
+        kind: Kind::Custom(|value| notify::parse_flags(value).map(notify::flags_to_string)),
+        default: "",
+        mutable: true,
+        apply: |shared, value| {
+            shared.notify_keyspace_events.store(notify::parse_flags(value).unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "busy-reply-threshold",
+        alias: Some("lua-time-limit"),
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "5000",
+        mutable: true,
+        apply: |shared, value| {
+            shared.busy_reply_threshold.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "requirepass",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+        apply: |shared, value| {
+            shared.acl.lock().unwrap().set_default_password(value);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "audit-log",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+        apply: |shared, value| shared.audit_log.lock().unwrap().set_target(value).map_err(|e| e.to_string()),
+    },
+    Parameter {
+        name: "audit-log-max-size",
+        alias: None,
+        kind: Kind::Memory,
+        default: "0",
+        mutable: true,
+        apply: |shared, value| {
+            shared.audit_log.lock().unwrap().max_size = value.parse().unwrap();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "audit-log-max-files",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 1000 },
+        default: "5",
+        mutable: true,
+        apply: |shared, value| {
+            shared.audit_log.lock().unwrap().max_files = value.parse().unwrap();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory",
+        alias: None,
+        kind: Kind::Memory,
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        alias: None,
+        kind: Kind::Enum(eviction::POLICIES),
+        default: "noeviction",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "maxmemory-samples",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 64 },
+        default: "5",
+        mutable: true,
+        apply: no_apply,
+    },
+    // In seconds, see expire::limit_ttls.
+    Parameter {
+        name: "default-ttl",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "0",
+        mutable: true,
+        apply: |_, value| {
+            expire::DEFAULT_TTL.store(value.parse::<u64>().unwrap() * 1000, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "max-ttl",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "0",
+        mutable: true,
+        apply: |_, value| {
+            expire::MAX_TTL.store(value.parse::<u64>().unwrap() * 1000, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    // Opened on startup, see tiering.rs.
+    Parameter {
+        name: "tiered-storage-dir",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "backing-upstream",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "backing-upstream-password",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "lazyfree-lazy-eviction",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "lazyfree-lazy-expire",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "lazyfree-lazy-server-del",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "lazyfree-lazy-user-del",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "lazyfree-lazy-user-flush",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "hash-max-listpack-entries",
+        alias: Some("hash-max-ziplist-entries"),
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "128",
+        mutable: true,
+        apply: |_, value| {
+            hash::MAX_LISTPACK_ENTRIES.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "hash-max-listpack-value",
+        alias: Some("hash-max-ziplist-value"),
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "64",
+        mutable: true,
+        apply: |_, value| {
+            hash::MAX_LISTPACK_VALUE.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "list-max-listpack-size",
+        alias: Some("list-max-ziplist-size"),
+        kind: Kind::Integer { min: -5, max: i64::MAX },
+        default: "-2",
+        mutable: true,
+        apply: |_, value| {
+            list::MAX_LISTPACK_SIZE.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-entries",
+        alias: Some("zset-max-ziplist-entries"),
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "128",
+        mutable: true,
+        apply: |_, value| {
+            sorted_set::MAX_LISTPACK_ENTRIES.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-value",
+        alias: Some("zset-max-ziplist-value"),
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "64",
+        mutable: true,
+        apply: |_, value| {
+            sorted_set::MAX_LISTPACK_VALUE.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    // The options of filters created by adding to a key that doesn't exist, see bloom.rs and
+    // cuckoo.rs.
+    Parameter {
+        name: "bf-error-rate",
+        alias: None,
+        kind: Kind::Custom(bloom::parse_error_rate),
+        default: "0.01",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "bf-initial-size",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: bloom::MAX_CAPACITY as i64 },
+        default: "100",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "bf-expansion-factor",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: bloom::MAX_EXPANSION as i64 },
+        default: "2",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cf-initial-size",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 1 << 24 },
+        default: "1024",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cf-bucket-size",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 255 },
+        default: "2",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cf-max-iterations",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 65535 },
+        default: "20",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cf-expansion-factor",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: bloom::MAX_EXPANSION as i64 },
+        default: "1",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "value-compression",
+        alias: None,
+        kind: Kind::Enum(compression::ALGORITHMS),
+        default: "no",
+        mutable: true,
+        apply: |_, value| {
+            let algorithm = compression::ALGORITHMS.iter().position(|algorithm| *algorithm == value).unwrap();
+            compression::ALGORITHM.store(algorithm as u8, Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "value-compression-threshold",
+        alias: None,
+        kind: Kind::Memory,
+        default: "1kb",
+        mutable: true,
+        apply: |_, value| {
+            compression::THRESHOLD.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "hz",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 500 },
+        default: "10",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "active-expire-effort",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 10 },
+        default: "1",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "timeout",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "loglevel",
+        alias: None,
+        kind: Kind::Enum(LOG_LEVELS),
+        default: DEFAULT_LOG_LEVEL,
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "otel-endpoint",
+        alias: None,
+        kind: Kind::Custom(otel::parse_endpoint),
+        default: "",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "otel-sampling-percentage",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 100 },
+        default: "100",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "otel-service-name",
+        alias: None,
+        kind: Kind::String,
+        default: "bast",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "metrics-endpoint",
+        alias: None,
+        kind: Kind::Custom(metrics::parse_endpoint),
+        default: "",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "metrics-protocol",
+        alias: None,
+        kind: Kind::Enum(metrics::PROTOCOLS),
+        default: "statsd",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "metrics-transport",
+        alias: None,
+        kind: Kind::Enum(metrics::TRANSPORTS),
+        default: "udp",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "metrics-interval",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: 86400 },
+        default: "10",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "metrics-prefix",
+        alias: None,
+        kind: Kind::String,
+        default: "bast",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "health-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "rest-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "grpc-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "quic-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "health-replica-max-lag",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "1048576",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "log-format",
+        alias: None,
+        kind: Kind::Enum(logging::FORMATS),
+        default: "human",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "latency-monitor-threshold",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i64::MAX },
+        default: "0",
+        mutable: true,
+        apply: no_apply,
+    },
+    // Set at runtime by REPLICAOF instead.
+    Parameter {
+        name: "replicaof",
+        alias: Some("slaveof"),
+        kind: Kind::Custom(replication::parse_replicaof),
+        default: "",
+        mutable: false,
+        apply: replication::set_replicaof,
+    },
+    Parameter {
+        name: "masterauth",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "masteruser",
+        alias: None,
+        kind: Kind::String,
+        default: "",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "repl-ping-replica-period",
+        alias: Some("repl-ping-slave-period"),
+        kind: Kind::Integer { min: 1, max: i32::MAX as i64 },
+        default: "10",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "repl-timeout",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: i32::MAX as i64 },
+        default: "60",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "replica-read-only",
+        alias: Some("slave-read-only"),
+        kind: Kind::Enum(YES_NO),
+        default: "yes",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "repl-backlog-size",
+        alias: None,
+        kind: Kind::Memory,
+        default: "1048576",
+        mutable: true,
+        apply: |_, value| {
+            replication::BACKLOG_SIZE.store(value.parse().unwrap(), Ordering::Relaxed);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "repl-backlog-ttl",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: i32::MAX as i64 },
+        default: "3600",
+        mutable: true,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cluster-enabled",
+        alias: None,
+        kind: Kind::Enum(YES_NO),
+        default: "no",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cluster-config-file",
+        alias: None,
+        kind: Kind::Custom(parse_file_name),
+        default: "nodes.conf",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cluster-port",
+        alias: None,
+        kind: Kind::Integer { min: 0, max: 65535 },
+        default: "0",
+        mutable: false,
+        apply: no_apply,
+    },
+    Parameter {
+        name: "cluster-node-timeout",
+        alias: None,
+        kind: Kind::Integer { min: 1, max: i32::MAX as i64 },
+        default: "15000",
+        mutable: true,
+        apply: no_apply,
+    },
+];
+
+impl Parameter {
+    // Validates the value, returning it in its canonical form.
+    fn parse(&self, value: &str) -> Result<String, String> {
+        match &self.kind {
+            Kind::Integer { min, max } => match value.parse::<i64>() {
+                Ok(number) if (*min..=*max).contains(&number) => Ok(number.to_string()),
+                Ok(_) => Err(format!("argument must be between {} and {} inclusive", min, max)),
+                Err(_) => Err(String::from("argument couldn't be parsed into an integer"))
+            },
+            Kind::Memory => parse_memory(value).map(|bytes| bytes.to_string())
+                .ok_or_else(|| String::from("argument must be a memory value")),
+            Kind::Enum(values) => {
+                let value = value.to_ascii_lowercase();
+                if values.contains(&value.as_str()) {
+                    Ok(value)
+                } else {
+                    Err(format!("argument(s) must be one of the following: {}", values.join(", ")))
+                }
+            },
+            Kind::String => Ok(value.to_owned()),
+            Kind::Custom(parse) => parse(value).ok_or_else(|| String::from("Invalid argument"))
+        }
+    }
+}
+
+pub fn lookup(name: &str) -> Option<&'static Parameter> {
+    let name = name.to_ascii_lowercase();
+    PARAMETERS.iter().find(|parameter| parameter.name == name || parameter.alias == Some(name.as_str()))
+}
+
+// The current values of all parameters, kept in their canonical form.
+pub struct Config {
+    values: RwLock<HashMap<&'static str, String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let values = PARAMETERS.iter().map(|parameter| (parameter.name, parameter.default.to_owned())).collect();
+        Self { values: RwLock::new(values) }
+    }
+}
+
+impl Config {
+    pub fn get(&self, name: &str) -> String {
+        self.values.read().unwrap().get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn get_int(&self, name: &str) -> i64 {
+        self.get(name).parse().unwrap_or(0)
+    }
+
+    pub fn get_bool(&self, name: &str) -> bool {
+        self.get(name) == "yes"
+    }
+
+    // Records a value the server changed on its own (like REPLICAOF does), for CONFIG GET and
+    // CONFIG REWRITE to see.
+    pub fn store(&self, name: &'static str, value: String) {
+        self.values.write().unwrap().insert(name, value);
+    }
+
+    // Pairs of names and values of every parameter matching the glob pattern. Aliases are listed
+    // under their own names.
+    pub fn get_matching(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let values = self.values.read().unwrap();
+        let mut matching = vec![];
+        for parameter in PARAMETERS {
+            for name in std::iter::once(parameter.name).chain(parameter.alias) {
+                if glob_match(pattern.as_bytes(), name.as_bytes()) {
+                    matching.push((name, values[parameter.name].clone()));
+                }
+            }
+        }
+        matching
+    }
+}
+
+// Whether a client connected from `addr` (empty on unix sockets) may use what `option` enables, set
+// to yes or to local for the clients of the loopback interface and of the unix socket.
+pub fn enabled_for(option: &str, addr: &str, shared: &SharedState) -> bool {
+    match shared.config.get(option).as_str() {
+        "yes" => true,
+        "local" => addr.is_empty() || addr.parse::<std::net::SocketAddr>().is_ok_and(|addr| addr.ip().to_canonical().is_loopback()),
+        _ => false
+    }
+}
+
+pub fn set(shared: &SharedState, changes: &[(&str, &str)], addr: &str) -> Result<(), RESPError> {
+    let protected = changes.iter().map(|(name, _)| *name)
+        .find(|name| lookup(name).is_some_and(|parameter| PROTECTED.contains(&parameter.name)));
+    if let Some(name) = protected.filter(|_| !enabled_for("enable-protected-configs", addr, shared)) {
+        return Err(RESPError::InvalidConfigValue(name.to_string(), String::from("can't set protected config")));
+    }
+    apply_changes(shared, changes, false)
+}
+
+// Keeps only the last value of every parameter, as later directives (like command line arguments)
+// override earlier ones.
+fn last_values<'a>(changes: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+    let canonical = |name: &str| lookup(name).map(|parameter| parameter.name);
+    changes.iter().enumerate()
+        .filter(|(i, (name, _))| {
+            canonical(name).is_none() || !changes[i + 1..].iter().any(|(other, _)| canonical(other) == canonical(name))
+        })
+        .map(|(_, change)| *change)
+        .collect()
+}
+
+// Like CONFIG SET, but immutable parameters can be set as well, as nothing depends on them yet.
+pub fn set_at_startup(shared: &SharedState, changes: &[(&str, &str)]) -> Result<(), RESPError> {
+    apply_changes(shared, &last_values(changes), true)
+}
+
+// Applies the configuration again (on SIGHUP), skipping parameters that can't be changed at runtime.
+pub fn reload(shared: &SharedState, changes: &[(&str, &str)]) -> Result<(), RESPError> {
+    let changes: Vec<(&str, &str)> = last_values(changes).into_iter()
+        .filter(|(name, _)| lookup(name).is_none_or(|parameter| parameter.mutable))
+        .collect();
+    apply_changes(shared, &changes, false)
+}
+
+// Validates all the changes before applying any of them, so an invalid value leaves the
+// configuration untouched. Applying can still fail (like opening a file), reverting the parameters
+// that were already applied.
+fn apply_changes(shared: &SharedState, changes: &[(&str, &str)], at_startup: bool) -> Result<(), RESPError> {
+    let mut parsed = Vec::with_capacity(changes.len());
+    for (name, value) in changes {
+        let parameter = lookup(name).ok_or_else(|| RESPError::UnsupportedConfigParameter(name.to_string()))?;
+        if !parameter.mutable && !at_startup {
+            return Err(RESPError::InvalidConfigValue(name.to_string(), String::from("can't set immutable config")));
+        }
+        if parsed.iter().any(|(other, _): &(&Parameter, String)| other.name == parameter.name) {
+            return Err(RESPError::InvalidConfigValue(name.to_string(), String::from("duplicate parameter")));
+        }
+        let value = parameter.parse(value).map_err(|reason| RESPError::InvalidConfigValue(name.to_string(), reason))?;
+        parsed.push((parameter, value));
+    }
+
+    let mut applied: Vec<(&Parameter, String)> = vec![];
+    for (parameter, value) in parsed {
+        let old = shared.config.get(parameter.name);
+        if let Err(reason) = (parameter.apply)(shared, &value) {
+            for (parameter, old) in applied.into_iter().rev() {
+                let _ = (parameter.apply)(shared, &old);
+                shared.config.values.write().unwrap().insert(parameter.name, old);
+            }
+            return Err(RESPError::InvalidConfigValue(parameter.name.to_owned(), reason));
+        }
+        shared.config.values.write().unwrap().insert(parameter.name, value);
+        applied.push((parameter, old));
+    }
+    Ok(())
+}
+
+// Splits a line into its arguments, which may be quoted. Double quoted arguments support escapes
+// (like \n or \x00), single quoted ones only escaping the quote itself.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quote = match chars.peek() {
+            None => return Ok(args),
+            Some(c @ ('"' | '\'')) => {
+                let quote = *c;
+                chars.next();
+                Some(quote)
+            },
+            Some(_) => None
+        };
+
+        let mut arg = String::new();
+        loop {
+            match (quote, chars.next()) {
+                (Some(_), None) => return Err(String::from("Unbalanced quotes in configuration line")),
+                (None, None) => break,
+                (None, Some(c)) if c.is_whitespace() => break,
+                (Some(q), Some(c)) if c == q => {
+                    if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                        return Err(String::from("Closing quote must be followed by a space"));
+                    }
+                    break;
+                },
+                (Some('"'), Some('\\')) => match chars.next() {
+                    Some('n') => arg.push('\n'),
+                    Some('r') => arg.push('\r'),
+                    Some('t') => arg.push('\t'),
+                    Some('b') => arg.push('\u{8}'),
+                    Some('a') => arg.push('\u{7}'),
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape '\\x{}'", hex))?;
+                        arg.push(byte as char);
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err(String::from("Unbalanced quotes in configuration line"))
+                },
+                (Some('\''), Some('\\')) if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    arg.push('\'');
+                },
+                (_, Some(c)) => arg.push(c)
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Parses a redis.conf style file into its directives, a name followed by its arguments per line.
+// Empty lines and lines starting with `#` are skipped.
+pub fn parse_file(contents: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut directives = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut args = split_line(line).map_err(|reason| format!("line {}: '{}' - {}", i + 1, line, reason))?;
+        args[0].make_ascii_lowercase();
+        directives.push(args);
+    }
+    Ok(directives)
+}
+
+// Quotes an argument only when needed for it to be parsed back as is.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_graphic() && c != '"' && c != '\'' && c != '\\') {
+        arg.to_owned()
+    } else {
+        quote(arg.as_bytes())
+    }
+}
+
+const REWRITE_MARKER: &str = "# Generated by CONFIG REWRITE";
+
+// Writes the current configuration back to the file. Lines of parameters are updated in place
+// (dropping repeated ones), while comments and other directives are kept as they are. Parameters
+// missing from the file are appended when they aren't set to their defaults.
+pub fn rewrite(shared: &SharedState, path: &str) -> std::io::Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e)
+    };
+
+    let mut lines = vec![];
+    let mut written = HashSet::new();
+    for line in contents.lines() {
+        let parameter = split_line(line).ok()
+            .and_then(|args| args.first().and_then(|name| lookup(name)))
+            .filter(|_| !line.trim_start().starts_with('#'));
+        match parameter {
+            Some(parameter) => {
+                if written.insert(parameter.name) {
+                    lines.push(format!("{} {}", parameter.name, quote_arg(&shared.config.get(parameter.name))));
+                }
+            },
+            None => lines.push(line.to_owned())
+        }
+    }
+
+    for parameter in PARAMETERS {
+        let value = shared.config.get(parameter.name);
+        if written.contains(parameter.name) || value == parameter.default {
+            continue;
+        }
+        if !lines.iter().any(|line| line == REWRITE_MARKER) {
+            lines.push(REWRITE_MARKER.to_owned());
+        }
+        lines.push(format!("{} {}", parameter.name, quote_arg(&value)));
+    }
+
+    // Written to a temporary file first, so a failure never leaves a truncated config behind.
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, lines.join("\n") + "\n")?;
+    std::fs::rename(&temporary, path)
+}
@@ -0,0 +1,27 @@
+// CRC-64 with the Jones polynomial, the checksum Redis appends to RDB files and DUMP payloads.
+
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// Continues the checksum `crc` (0 to start one) over the bytes.
+pub fn update(mut crc: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        crc = TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
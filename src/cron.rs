@@ -0,0 +1,51 @@
+// The periodic background work, like serverCron of Redis. A single task ticks `hz` times a second and
+// runs the jobs that are due, every job having a period of its own (some of them configurable), which
+// is rounded to whole ticks and is at least one. Jobs run on the runtime one after the other, so a job
+// must be quick, handing anything slow (like disk I/O) over to a task or a thread of its own.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{aof, clients, expire, health, replication, snapshot, stats, SharedState};
+
+struct Job {
+    period: fn(&SharedState) -> Duration,
+    run: fn(&Arc<SharedState>),
+}
+
+const EVERY_TICK: fn(&SharedState) -> Duration = |_| Duration::ZERO;
+const EVERY_SECOND: fn(&SharedState) -> Duration = |_| Duration::from_secs(1);
+
+const JOBS: &[Job] = &[
+    Job { period: EVERY_TICK, run: expire::active_expire_cycle },
+    Job { period: |_| Duration::from_millis(100), run: stats::sample },
+    Job { period: EVERY_SECOND, run: clients::close_timed_out },
+    Job { period: EVERY_SECOND, run: replication::cron },
+    Job {
+        period: |shared| Duration::from_secs(shared.config.get_int("repl-ping-replica-period") as u64),
+        run: replication::ping_replicas,
+    },
+    Job { period: EVERY_SECOND, run: snapshot::cron },
+    Job { period: EVERY_SECOND, run: aof::fsync },
+    Job { period: |_| Duration::from_millis(500), run: health::refresh },
+];
+
+// The time between ticks.
+pub fn tick(shared: &SharedState) -> Duration {
+    Duration::from_millis(1000 / shared.config.get_int("hz") as u64)
+}
+
+pub async fn run(shared: Arc<SharedState>) {
+    let mut loops: u64 = 0;
+    loop {
+        let tick = tick(&shared);
+        tokio::time::sleep(tick).await;
+        for job in JOBS {
+            let ticks = ((job.period)(&shared).as_millis() / tick.as_millis()).max(1) as u64;
+            if loops.is_multiple_of(ticks) {
+                (job.run)(&shared);
+            }
+        }
+        loops += 1;
+    }
+}
@@ -0,0 +1,407 @@
+// Cuckoo filters, with the CF commands of RedisBloom. Unlike Bloom filters they can count and delete
+// items. A filter is a table of buckets of `bucket_size` 8 bit fingerprints, where an item can be in
+// either of two buckets: the one of its hash, or that one XORed with the hash of its fingerprint, so
+// either one is found from the other. Adding to two full buckets kicks a fingerprint out to its other
+// bucket, which may kick out another, up to `max_iterations` times. When that doesn't free a slot,
+// a filter `expansion` times bigger is stacked on the last one, up to 32 of them (non scaling
+// filters, with an expansion of 0, are full then).
+//
+// Filters are created by CF.RESERVE, or by adding to a key that doesn't exist with the options of
+// `cf-initial-size`, `cf-bucket-size`, `cf-max-iterations` and `cf-expansion-factor`. Which
+// fingerprints get kicked out is random, so the buckets of a replica may not be the ones of its
+// primary, although the items they have are.
+
+use std::collections::HashMap;
+use std::io;
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::arg::Arg;
+use crate::bloom::{parse_capacity, MAX_CAPACITY, MAX_EXPANSION};
+use crate::db::Value;
+use crate::hyperloglog::murmurhash64a;
+use crate::notify::{notify_keyspace_event, NOTIFY_MODULE};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{rdb, RESPError, RESPValue, SharedState};
+
+pub const MODULE_ID: u64 = rdb::module_id(b"bastcucko", 0);
+
+const MAX_LAYERS: usize = 32;
+const MAX_BUCKET_SIZE: u64 = 255;
+const MAX_ITERATIONS: u64 = 65535;
+// Multiplies fingerprints into the offset of the other bucket of an item.
+const ALT_HASH: u64 = 0x5bd1e995;
+
+fn fingerprint(hash: u64) -> u8 {
+    ((hash >> 32) % 255 + 1) as u8
+}
+
+// A table of buckets, a power of two of them, with 0 for an empty slot.
+#[derive(Clone)]
+struct Layer {
+    fingerprints: Vec<u8>,
+    buckets: u64,
+}
+
+impl Layer {
+    fn new(buckets: u64, bucket_size: u8) -> Self {
+        Self { fingerprints: vec![0; (buckets * bucket_size as u64) as usize], buckets }
+    }
+
+    fn bucket_size(&self) -> usize {
+        self.fingerprints.len() / self.buckets as usize
+    }
+
+    fn alt(&self, bucket: u64, fingerprint: u8) -> u64 {
+        (bucket ^ (fingerprint as u64).wrapping_mul(ALT_HASH)) & (self.buckets - 1)
+    }
+
+    // The two buckets of an item.
+    fn buckets_of(&self, hash: u64, fingerprint: u8) -> [u64; 2] {
+        let bucket = hash & (self.buckets - 1);
+        [bucket, self.alt(bucket, fingerprint)]
+    }
+
+    fn slots(&self, bucket: u64) -> &[u8] {
+        let size = self.bucket_size();
+        &self.fingerprints[bucket as usize * size..(bucket as usize + 1) * size]
+    }
+
+    fn slots_mut(&mut self, bucket: u64) -> &mut [u8] {
+        let size = self.bucket_size();
+        &mut self.fingerprints[bucket as usize * size..(bucket as usize + 1) * size]
+    }
+
+    fn count(&self, hash: u64, fingerprint: u8) -> usize {
+        let [first, second] = self.buckets_of(hash, fingerprint);
+        let in_bucket = |bucket| self.slots(bucket).iter().filter(|slot| **slot == fingerprint).count();
+        in_bucket(first) + if second != first { in_bucket(second) } else { 0 }
+    }
+
+    fn put(&mut self, bucket: u64, fingerprint: u8) -> bool {
+        match self.slots_mut(bucket).iter_mut().find(|slot| **slot == 0) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            },
+            None => false
+        }
+    }
+
+    fn remove(&mut self, hash: u64, fingerprint: u8) -> bool {
+        self.buckets_of(hash, fingerprint).into_iter().any(|bucket| {
+            match self.slots_mut(bucket).iter_mut().find(|slot| **slot == fingerprint) {
+                Some(slot) => {
+                    *slot = 0;
+                    true
+                },
+                None => false
+            }
+        })
+    }
+
+    // Makes room for the fingerprint by kicking others out to their other bucket, putting everything
+    // back when there's no room after `max_iterations` kicks.
+    fn kick(&mut self, hash: u64, mut fingerprint: u8, max_iterations: u16) -> bool {
+        let mut rng = rand::thread_rng();
+        let mut bucket = self.buckets_of(hash, fingerprint)[rng.gen_range(0..2)];
+        let mut kicked = vec![];
+        for _ in 0..max_iterations {
+            let slot = bucket as usize * self.bucket_size() + rng.gen_range(0..self.bucket_size());
+            std::mem::swap(&mut self.fingerprints[slot], &mut fingerprint);
+            kicked.push(slot);
+            bucket = self.alt(bucket, fingerprint);
+            if self.put(bucket, fingerprint) {
+                return true;
+            }
+        }
+        for slot in kicked.into_iter().rev() {
+            std::mem::swap(&mut self.fingerprints[slot], &mut fingerprint);
+        }
+        false
+    }
+
+    fn validate(self, bucket_size: u8) -> io::Result<Self> {
+        if bucket_size == 0 || !self.buckets.is_power_of_two() || self.fingerprints.len() as u64 != self.buckets * bucket_size as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid cuckoo filter layer"));
+        }
+        Ok(self)
+    }
+}
+
+fn hash(item: &[u8]) -> u64 {
+    murmurhash64a(item, 0)
+}
+
+#[derive(Clone)]
+pub struct Cuckoo {
+    layers: Vec<Layer>,
+    bucket_size: u8,
+    max_iterations: u16,
+    // How many times bigger every new layer is, 0 for a non scaling filter.
+    expansion: u16,
+    items: u64,
+    deletes: u64,
+}
+
+impl Cuckoo {
+    fn new(capacity: u64, bucket_size: u8, max_iterations: u16, expansion: u16) -> Self {
+        let buckets = capacity.div_ceil(bucket_size as u64).next_power_of_two();
+        Self { layers: vec![Layer::new(buckets, bucket_size)], bucket_size, max_iterations, expansion, items: 0, deletes: 0 }
+    }
+
+    pub fn count(&self, item: &[u8]) -> usize {
+        let hash = hash(item);
+        self.layers.iter().map(|layer| layer.count(hash, fingerprint(hash))).sum()
+    }
+
+    // Adds the item, even when it's there already.
+    pub fn add(&mut self, item: &[u8]) -> Result<(), RESPError> {
+        let hash = hash(item);
+        let fingerprint = fingerprint(hash);
+        let free = self.layers.iter_mut().rev()
+            .any(|layer| layer.buckets_of(hash, fingerprint).into_iter().any(|bucket| layer.put(bucket, fingerprint)));
+        if !free && !self.layers.last_mut().unwrap().kick(hash, fingerprint, self.max_iterations) {
+            let buckets = self.layers.last().unwrap().buckets.saturating_mul(self.expansion as u64).next_power_of_two();
+            if self.expansion == 0 || self.layers.len() >= MAX_LAYERS || buckets * self.bucket_size as u64 > MAX_CAPACITY {
+                return Err(RESPError::FilterFull);
+            }
+            let mut layer = Layer::new(buckets, self.bucket_size);
+            layer.put(layer.buckets_of(hash, fingerprint)[0], fingerprint);
+            self.layers.push(layer);
+        }
+        self.items += 1;
+        Ok(())
+    }
+
+    // Deletes an occurrence of the item, false when it isn't there.
+    pub fn delete(&mut self, item: &[u8]) -> bool {
+        let hash = hash(item);
+        let deleted = self.layers.iter_mut().rev().any(|layer| layer.remove(hash, fingerprint(hash)));
+        if deleted {
+            self.items = self.items.saturating_sub(1);
+            self.deletes += 1;
+        }
+        deleted
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.layers.iter().map(|layer| std::mem::size_of::<Layer>() + layer.fingerprints.len()).sum::<usize>()
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.len(self.bucket_size as usize);
+        encoder.len(self.max_iterations as usize);
+        encoder.len(self.expansion as usize);
+        encoder.len(self.items as usize);
+        encoder.len(self.deletes as usize);
+        encoder.len(self.layers.len());
+        for layer in &self.layers {
+            encoder.len(layer.buckets as usize);
+            encoder.bytes(&layer.fingerprints);
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let bucket_size = decoder.len()? as u8;
+        let (max_iterations, expansion) = (decoder.len()? as u16, decoder.len()? as u16);
+        let (items, deletes) = (decoder.len()? as u64, decoder.len()? as u64);
+        let mut layers = vec![];
+        for _ in 0..decoder.len()? {
+            let buckets = decoder.len()? as u64;
+            layers.push(Layer { fingerprints: decoder.bytes()?.to_vec(), buckets }.validate(bucket_size)?);
+        }
+        if layers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty cuckoo filter"));
+        }
+        Ok(Self { layers, bucket_size, max_iterations, expansion, items, deletes })
+    }
+
+    // Written as the value of the bastcucko module type, see rdb.rs.
+    pub fn write_rdb(&self, buf: &mut Vec<u8>) {
+        for n in [self.bucket_size as u64, self.max_iterations as u64, self.expansion as u64, self.items, self.deletes, self.layers.len() as u64] {
+            rdb::write_module_uint(buf, n);
+        }
+        for layer in &self.layers {
+            rdb::write_module_uint(buf, layer.buckets);
+            rdb::write_module_string(buf, &layer.fingerprints);
+        }
+    }
+
+    pub fn read_rdb(reader: &mut rdb::Reader) -> io::Result<Self> {
+        let bucket_size = reader.module_uint()? as u8;
+        let (max_iterations, expansion) = (reader.module_uint()? as u16, reader.module_uint()? as u16);
+        let (items, deletes) = (reader.module_uint()?, reader.module_uint()?);
+        let mut layers = vec![];
+        for _ in 0..reader.module_uint()? {
+            let buckets = reader.module_uint()?;
+            layers.push(Layer { fingerprints: reader.module_string()?, buckets }.validate(bucket_size)?);
+        }
+        if layers.is_empty() {
+            return Err(rdb::corrupted("empty cuckoo filter"));
+        }
+        Ok(Self { layers, bucket_size, max_iterations, expansion, items, deletes })
+    }
+}
+
+// The options of a new filter, the ones of the config unless given.
+struct Options {
+    capacity: u64,
+    bucket_size: u8,
+    max_iterations: u16,
+    expansion: u16,
+}
+
+impl Options {
+    fn configured(shared: &SharedState) -> Self {
+        Self {
+            capacity: shared.config.get_int("cf-initial-size") as u64,
+            bucket_size: shared.config.get_int("cf-bucket-size") as u8,
+            max_iterations: shared.config.get_int("cf-max-iterations") as u16,
+            expansion: shared.config.get_int("cf-expansion-factor") as u16,
+        }
+    }
+
+    fn create(&self) -> Cuckoo {
+        Cuckoo::new(self.capacity, self.bucket_size, self.max_iterations, self.expansion)
+    }
+}
+
+// Adds the items to the filter of the key, created with the options when it doesn't exist (refused
+// without them), only the ones it doesn't have already with `nx`. Whether every item was added, or
+// why it couldn't be.
+fn add(command: &[Arg], items: &[Arg], options: Option<Options>, nx: bool, shared: &SharedState) -> Result<Vec<Result<bool, RESPError>>, RESPError> {
+    let key = command[1].as_str();
+    let added = {
+        let mut db = shared.db.lock();
+        if db.get(key).is_none() {
+            let options = options.ok_or(RESPError::FilterNotFound)?;
+            db.set(key.to_owned(), Value::Cuckoo(options.create()));
+        }
+        let cuckoo = db.get_mut(key).unwrap().as_cuckoo_mut()?;
+        items.iter().map(|item| {
+            if nx && cuckoo.count(item.raw()) > 0 {
+                return Ok(false);
+            }
+            cuckoo.add(item.raw()).map(|_| true)
+        }).collect::<Vec<_>>()
+    };
+    if added.iter().any(|added| matches!(added, Ok(true))) {
+        notify_keyspace_event(shared, NOTIFY_MODULE, &command[0].to_ascii_lowercase(), key, 0);
+    }
+    Ok(added)
+}
+
+// CF.RESERVE key capacity [BUCKETSIZE bucketsize] [MAXITERATIONS maxiterations] [EXPANSION expansion]
+pub fn reserve(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let mut options = Options::configured(shared);
+    options.capacity = parse_capacity(&command[2])?;
+    let mut args = command[3..].iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(RESPError::SyntaxError)?;
+        match arg.to_ascii_uppercase().as_str() {
+            "BUCKETSIZE" => options.bucket_size = value.parse::<u64>().ok().filter(|size| (1..=MAX_BUCKET_SIZE).contains(size)).ok_or(RESPError::BadBucketSize)? as u8,
+            "MAXITERATIONS" => options.max_iterations = value.parse::<u64>().ok().filter(|max| (1..=MAX_ITERATIONS).contains(max)).ok_or(RESPError::BadMaxIterations)? as u16,
+            "EXPANSION" => options.expansion = value.parse::<u64>().ok().filter(|expansion| *expansion <= MAX_EXPANSION).ok_or(RESPError::BadExpansion)? as u16,
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+    if options.capacity.div_ceil(options.bucket_size as u64).next_power_of_two() * options.bucket_size as u64 > MAX_CAPACITY {
+        return Err(RESPError::BadCapacity);
+    }
+
+    {
+        let mut db = shared.db.lock();
+        if db.contains_key(key) {
+            return Err(RESPError::ItemExists);
+        }
+        db.set(key.to_owned(), Value::Cuckoo(options.create()));
+    }
+    notify_keyspace_event(shared, NOTIFY_MODULE, "cf.reserve", key, 0);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// CF.ADD key item
+// CF.ADDNX key item
+pub fn cf_add(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let nx = command[0] == "CF.ADDNX";
+    let added = add(command, &command[2..], Some(Options::configured(shared)), nx, shared)?.remove(0)?;
+    Ok(RESPValue::Number(added as i64))
+}
+
+// CF.INSERT key [CAPACITY capacity] [NOCREATE] ITEMS item [item ...]
+// CF.INSERTNX key [CAPACITY capacity] [NOCREATE] ITEMS item [item ...]
+pub fn insert(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut options = Options::configured(shared);
+    let mut create = true;
+    let mut i = 2;
+    loop {
+        match command.get(i).ok_or(RESPError::SyntaxError)?.to_ascii_uppercase().as_str() {
+            "CAPACITY" => {
+                options.capacity = parse_capacity(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?;
+                i += 2;
+            },
+            "NOCREATE" => {
+                create = false;
+                i += 1;
+            },
+            "ITEMS" => break,
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+    let items = &command[i + 1..];
+    if items.is_empty() {
+        return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+    }
+    let added = add(command, items, create.then_some(options), command[0] == "CF.INSERTNX", shared)?;
+    Ok(RESPValue::Array(added.into_iter().map(|added| match added {
+        Ok(added) => RESPValue::Number(added as i64),
+        Err(e) => e.into()
+    }).collect()))
+}
+
+// CF.EXISTS key item
+// CF.MEXISTS key item [item ...]
+// CF.COUNT key item
+pub fn exists(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let cuckoo = db.get(command[1].as_str()).map(Value::as_cuckoo).transpose()?;
+    let count = |item: &Arg| cuckoo.map_or(0, |cuckoo| cuckoo.count(item.raw()));
+    Ok(match command[0].as_str() {
+        "CF.EXISTS" => RESPValue::Number((count(&command[2]) > 0) as i64),
+        "CF.COUNT" => RESPValue::Number(count(&command[2]) as i64),
+        _ => RESPValue::Array(command[2..].iter().map(|item| RESPValue::Number((count(item) > 0) as i64)).collect())
+    })
+}
+
+// CF.DEL key item
+pub fn del(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let deleted = {
+        let mut db = shared.db.lock();
+        db.get_mut(key).ok_or(RESPError::FilterNotFound)?.as_cuckoo_mut()?.delete(command[2].raw())
+    };
+    if deleted {
+        notify_keyspace_event(shared, NOTIFY_MODULE, "cf.del", key, 0);
+    }
+    Ok(RESPValue::Number(deleted as i64))
+}
+
+// CF.INFO key
+pub fn info(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let cuckoo = db.get(command[1].as_str()).ok_or(RESPError::FilterNotFound)?.as_cuckoo()?;
+    let fields = [
+        ("Size", cuckoo.memory_usage() as i64),
+        ("Number of buckets", cuckoo.layers.iter().map(|layer| layer.buckets).sum::<u64>() as i64),
+        ("Number of filters", cuckoo.layers.len() as i64),
+        ("Number of items inserted", cuckoo.items as i64),
+        ("Number of items deleted", cuckoo.deletes as i64),
+        ("Bucket size", cuckoo.bucket_size as i64),
+        ("Expansion rate", cuckoo.expansion as i64),
+        ("Max iterations", cuckoo.max_iterations as i64),
+    ];
+    Ok(RESPValue::Map(fields.into_iter().map(|(name, value)| (Bytes::from(name), RESPValue::Number(value))).collect::<HashMap<_, _>>()))
+}
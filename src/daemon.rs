@@ -0,0 +1,54 @@
+// Running as a daemon. With `daemonize yes` the server forks on startup, before the runtime starts
+// any thread, and detaches from the terminal, its standard streams going to /dev/null (so logging
+// goes nowhere unless there's a `logfile`). The pid is written to `pidfile` (by default
+// /var/run/bast.pid when daemonized), which is removed on shutdown.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use crate::{logging, SharedState};
+
+const DEFAULT_PIDFILE: &str = "/var/run/bast.pid";
+
+pub fn daemonize() -> io::Result<()> {
+    // Safety: no other thread was started yet, so the child is left in a consistent state.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {},
+        _ => std::process::exit(0)
+    }
+    // Safety: plain system calls, on descriptors owned by this process.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn pidfile(shared: &SharedState) -> Option<String> {
+    match shared.config.get("pidfile") {
+        path if !path.is_empty() => Some(path),
+        _ if shared.config.get_bool("daemonize") => Some(String::from(DEFAULT_PIDFILE)),
+        _ => None
+    }
+}
+
+pub fn write_pidfile(shared: &SharedState) {
+    if let Some(path) = pidfile(shared) {
+        if let Err(e) = std::fs::write(&path, format!("{}\n", std::process::id())) {
+            logging::log(shared, "warning", format!("Failed to write PID file {}: {}", path, e));
+        }
+    }
+}
+
+pub fn remove_pidfile(shared: &SharedState) {
+    if let Some(path) = pidfile(shared) {
+        let _ = std::fs::remove_file(path);
+    }
+}
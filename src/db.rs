@@ -0,0 +1,1118 @@
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
+use std::collections::{hash_map, HashMap, HashSet};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use parking_lot::{ReentrantMutex, ReentrantMutexGuard};
+use rand::Rng;
+
+use crate::bloom::Bloom;
+use crate::cluster::{self, SLOTS};
+use crate::compression::{self, Compressed};
+use crate::cuckoo::Cuckoo;
+use crate::hash::Hash;
+use crate::list::List;
+use crate::memory::{key_usage, DEFAULT_SAMPLES};
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use crate::tiering::Tier;
+use crate::timer_wheel::TimerWheel;
+use crate::{rdb, RESPError};
+
+#[derive(Clone)]
+pub enum Value {
+    // Shared with the replies that read it, rather than copied into them.
+    String(Bytes),
+    // A big string, see compression::compress.
+    CompressedString(Compressed),
+    List(List),
+    Stream(Stream),
+    SortedSet(SortedSet),
+    Hash(Hash),
+    Bloom(Bloom),
+    Cuckoo(Cuckoo),
+    Json(serde_json::Value),
+}
+
+impl Value {
+    // A string value, compressed when it's big enough.
+    pub fn string(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        match compression::compress(&bytes) {
+            Some(compressed) => Value::CompressedString(compressed),
+            None => Value::String(bytes)
+        }
+    }
+
+    pub fn as_string(&self) -> Result<Bytes, RESPError> {
+        match self {
+            Value::String(s) => Ok(s.clone()),
+            Value::CompressedString(s) => Ok(s.decompress()),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<StringMut<'_>, RESPError> {
+        let bytes = match self {
+            Value::String(s) => Vec::from(std::mem::take(s)),
+            Value::CompressedString(s) => Vec::from(s.decompress()),
+            _ => return Err(RESPError::WrongType)
+        };
+        Ok(StringMut { slot: self, bytes })
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet, RESPError> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet, RESPError> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash, RESPError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut Hash, RESPError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, RESPError> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    // The type of the value, as Redis names it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) | Value::CompressedString(_) => "string",
+            Value::List(_) => "list",
+            Value::SortedSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Stream(_) => "stream",
+            // The names of the types of RedisBloom.
+            Value::Bloom(_) => "MBbloom--",
+            Value::Cuckoo(_) => "MBbloomCF",
+            // And the one of RedisJSON.
+            Value::Json(_) => "ReJSON-RL",
+        }
+    }
+
+    // The representation of the value, as shown by OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(_) => "raw",
+            Value::CompressedString(s) => s.encoding(),
+            Value::List(list) => list.encoding(),
+            Value::SortedSet(set) => set.encoding(),
+            Value::Hash(hash) => hash.encoding(),
+            Value::Stream(_) => "stream",
+            Value::Bloom(_) | Value::Cuckoo(_) | Value::Json(_) => "raw",
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream, RESPError> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_bloom(&self) -> Result<&Bloom, RESPError> {
+        match self {
+            Value::Bloom(bloom) => Ok(bloom),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_bloom_mut(&mut self) -> Result<&mut Bloom, RESPError> {
+        match self {
+            Value::Bloom(bloom) => Ok(bloom),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_cuckoo(&self) -> Result<&Cuckoo, RESPError> {
+        match self {
+            Value::Cuckoo(cuckoo) => Ok(cuckoo),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_cuckoo_mut(&mut self) -> Result<&mut Cuckoo, RESPError> {
+        match self {
+            Value::Cuckoo(cuckoo) => Ok(cuckoo),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_json(&self) -> Result<&serde_json::Value, RESPError> {
+        match self {
+            Value::Json(json) => Ok(json),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+
+    pub fn as_json_mut(&mut self) -> Result<&mut serde_json::Value, RESPError> {
+        match self {
+            Value::Json(json) => Ok(json),
+            _ => Err(RESPError::WrongType)
+        }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+// A string taken out of its value for modification, and put back (compressed again if it was)
+// once dropped. Taking an uncompressed string only copies the bytes when a reply still shares them.
+pub struct StringMut<'a> {
+    slot: &'a mut Value,
+    bytes: Vec<u8>,
+}
+
+impl Deref for StringMut<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+}
+
+impl DerefMut for StringMut<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.bytes
+    }
+}
+
+impl Drop for StringMut<'_> {
+    fn drop(&mut self) {
+        *self.slot = Value::string(std::mem::take(&mut self.bytes));
+    }
+}
+
+// The LFU counter of new keys, so they aren't the first to go before having a chance to be used.
+const LFU_INIT_VAL: u8 = 5;
+// How hard it gets to increment the LFU counter as it grows, and the minutes it takes to decay.
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_TIME: Duration = Duration::from_secs(60);
+
+struct Entry {
+    // Empty while the value is spilled to the disk tier.
+    value: OnceCell<Value>,
+    // Bumped on every write to the key, so WATCH can tell whether the key changed since.
+    version: u64,
+    // Updated on reads as well, through a shared reference.
+    last_access: Cell<Instant>,
+    // A logarithmic access frequency counter, decaying over time like in Redis.
+    lfu_counter: Cell<u8>,
+    // The bytes used by the key as last measured, counted in the used memory of the keyspace.
+    size: usize,
+}
+
+impl Entry {
+    fn new(key: &str, value: Value, version: u64) -> Self {
+        let size = key_usage(key, &value, DEFAULT_SAMPLES);
+        Self { value: OnceCell::from(value), version, last_access: Cell::new(Instant::now()), lfu_counter: Cell::new(LFU_INIT_VAL), size }
+    }
+
+    // The LFU counter after decaying for the time since the last access.
+    fn decayed_counter(&self) -> u8 {
+        let periods = self.last_access.get().elapsed().as_secs() / LFU_DECAY_TIME.as_secs();
+        self.lfu_counter.get().saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    fn touch(&self) {
+        let mut counter = self.decayed_counter();
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if rand::thread_rng().gen::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.lfu_counter.set(counter);
+        self.last_access.set(Instant::now());
+    }
+}
+
+// Access metadata of a key, as shown by the OBJECT command.
+pub struct AccessInfo {
+    pub idle: Duration,
+    pub frequency: u8,
+}
+
+// A key with its value and expire time, as saved in a snapshot.
+pub type SavedKey = (String, Value, Option<u64>);
+
+// The keyspace as it was when a background save started, for the keys the save didn't get to yet:
+// those are copied aside before their first write, so the save still sees their old value.
+#[derive(Default)]
+struct Frozen {
+    keys: Vec<String>,
+    pending: HashSet<String>,
+    copied: Vec<SavedKey>,
+}
+
+// The values spilled to the disk tier, see tiering.rs.
+#[derive(Default)]
+struct Spilled {
+    tier: Option<Arc<dyn Tier>>,
+    // The spilled keys and the sum of their sizes. Updated through a shared reference, as reading a
+    // spilled value brings it back into memory.
+    keys: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl Spilled {
+    // Reads a spilled value, leaving it in the disk tier. The keyspace can't go on without a value
+    // it has, so failing to read one is fatal.
+    fn read(&self, key: &str) -> Value {
+        let tier = self.tier.as_ref().expect("A value was spilled without a disk tier");
+        let value = tier.read(key)
+            .and_then(|payload| payload.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "missing from the disk tier")))
+            .and_then(|payload| rdb::undump(&payload));
+        value.unwrap_or_else(|e| panic!("Failed reading the spilled value of {}: {}", key, e))
+    }
+
+    fn bring_back(&self, key: &str, size: usize) -> Value {
+        let value = self.read(key);
+        // A copy left behind only wastes space until the key is spilled again, overwriting it.
+        let _ = self.tier.as_ref().unwrap().remove(key);
+        self.keys.set(self.keys.get() - 1);
+        self.bytes.set(self.bytes.get() - size);
+        value
+    }
+
+    fn resident<'a>(&self, key: &str, entry: &'a Entry) -> &'a Value {
+        entry.value.get_or_init(|| self.bring_back(key, entry.size))
+    }
+
+    fn resident_mut<'a>(&self, key: &str, entry: &'a mut Entry) -> &'a mut Value {
+        if entry.value.get().is_none() {
+            let _ = entry.value.set(self.bring_back(key, entry.size));
+        }
+        entry.value.get_mut().unwrap()
+    }
+
+    fn take(&self, key: &str, entry: Entry) -> Value {
+        let size = entry.size;
+        entry.value.into_inner().unwrap_or_else(|| self.bring_back(key, size))
+    }
+}
+
+// A part of the keyspace, see Keyspace.
+#[derive(Default)]
+pub struct Shard {
+    entries: HashMap<String, Entry>,
+    next_version: u64,
+    // The sum of the sizes of all entries, spilled ones included.
+    used_memory: usize,
+    // Keys modified in place since their size was last measured, measured again lazily.
+    resized: HashSet<String>,
+    // The unix time in milliseconds keys with a TTL expire at.
+    expires: TimerWheel<String>,
+    // The hashes with fields that have a TTL, at the time the first of those expires.
+    field_expires: TimerWheel<String>,
+    frozen: Option<Frozen>,
+    spilled: Spilled,
+    // The keys of every hash slot, kept in cluster mode only.
+    slots: Option<Vec<HashSet<String>>>,
+}
+
+impl Shard {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key).map(|entry| {
+            entry.touch();
+            self.spilled.resident(key, entry)
+        })
+    }
+
+    // Gets the value without counting it as an access, for introspection.
+    pub fn peek(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key).map(|entry| self.spilled.resident(key, entry))
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    // Counts as an access, without loading a spilled value. Returns whether the key exists.
+    pub fn touch(&self, key: &str) -> bool {
+        self.entries.get(key).inspect(|entry| entry.touch()).is_some()
+    }
+
+    pub fn access_info(&self, key: &str) -> Option<AccessInfo> {
+        self.entries.get(key).map(|entry| AccessInfo {
+            idle: entry.last_access.get().elapsed(),
+            frequency: entry.decayed_counter(),
+        })
+    }
+
+    // Sets the access metadata of a key, like RESTORE does with the one of the key it copies.
+    pub fn set_access_info(&self, key: &str, idle: Option<Duration>, frequency: Option<u8>) {
+        if let Some(entry) = self.entries.get(key) {
+            if let Some(last_access) = idle.and_then(|idle| Instant::now().checked_sub(idle)) {
+                entry.last_access.set(last_access);
+            }
+            if let Some(frequency) = frequency {
+                entry.lfu_counter.set(frequency);
+            }
+        }
+    }
+
+    // Gets the value for modification, which counts as a write to the key.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.preserve(key);
+        self.next_version += 1;
+        let version = self.next_version;
+        if self.entries.contains_key(key) && !self.resized.contains(key) {
+            self.resized.insert(key.to_owned());
+        }
+        let spilled = &self.spilled;
+        self.entries.get_mut(key).map(|entry| {
+            entry.version = version;
+            entry.touch();
+            spilled.resident_mut(key, entry)
+        })
+    }
+
+    pub fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> Value) -> &mut Value {
+        self.preserve(key);
+        self.next_version += 1;
+        let version = self.next_version;
+        if !self.resized.contains(key) {
+            self.resized.insert(key.to_owned());
+        }
+        let (used_memory, slots) = (&mut self.used_memory, &mut self.slots);
+        let entry = self.entries.entry(key.to_owned()).or_insert_with(|| {
+            let entry = Entry::new(key, f(), version);
+            *used_memory += entry.size;
+            if let Some(slots) = slots {
+                slots[cluster::key_slot(key)].insert(key.to_owned());
+            }
+            entry
+        });
+        entry.version = version;
+        entry.touch();
+        self.spilled.resident_mut(key, entry)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) -> Option<Value> {
+        self.preserve(&key);
+        self.next_version += 1;
+        let field_expiration = value.as_hash().ok().and_then(Hash::next_expiration);
+        let entry = Entry::new(&key, value, self.next_version);
+        self.used_memory += entry.size;
+        self.resized.remove(&key);
+        // Setting a key anew discards its TTL.
+        self.expires.remove(&key);
+        match field_expiration {
+            Some(at) => self.field_expires.insert(key.clone(), at),
+            None => {
+                self.field_expires.remove(&key);
+            }
+        }
+        match self.entries.entry(key) {
+            hash_map::Entry::Occupied(mut occupied) => {
+                let old = std::mem::replace(occupied.get_mut(), entry);
+                self.used_memory -= old.size;
+                Some(self.spilled.take(occupied.key(), old))
+            },
+            hash_map::Entry::Vacant(vacant) => {
+                if let Some(slots) = &mut self.slots {
+                    slots[cluster::key_slot(vacant.key())].insert(vacant.key().clone());
+                }
+                vacant.insert(entry);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.preserve(key);
+        self.resized.remove(key);
+        self.expires.remove(key);
+        self.field_expires.remove(key);
+        let entry = self.entries.remove(key)?;
+        self.used_memory -= entry.size;
+        if let Some(slots) = &mut self.slots {
+            slots[cluster::key_slot(key)].remove(key);
+        }
+        Some(self.spilled.take(key, entry))
+    }
+
+    // The bytes used by the keyspace in memory, measuring again the keys modified since last asked.
+    pub fn used_memory(&mut self) -> usize {
+        for key in std::mem::take(&mut self.resized) {
+            // Keys are spilled only after being measured, so their sizes stay the ones counted.
+            if let Some(entry) = self.entries.get_mut(&key).filter(|entry| entry.value.get().is_some()) {
+                let size = key_usage(&key, entry.value.get().unwrap(), DEFAULT_SAMPLES);
+                self.used_memory = self.used_memory - entry.size + size;
+                entry.size = size;
+            }
+        }
+        self.used_memory - self.spilled.bytes.get()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Spilled values are read without bringing them back into memory.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Cow<'_, Value>)> {
+        self.entries.iter().map(|(key, entry)| (key, match entry.value.get() {
+            Some(value) => Cow::Borrowed(value),
+            None => Cow::Owned(self.spilled.read(key))
+        }))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    // The keys whose values are in memory, which can be spilled.
+    pub fn resident_keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.iter().filter(|(_, entry)| entry.value.get().is_some()).map(|(key, _)| key)
+    }
+
+    pub fn set_tier(&mut self, tier: Arc<dyn Tier>) {
+        self.spilled.tier = Some(tier);
+    }
+
+    pub fn has_tier(&self) -> bool {
+        self.spilled.tier.is_some()
+    }
+
+    // Moves the value of the key to the disk tier, false when it isn't in memory.
+    pub fn spill(&mut self, key: &str) -> io::Result<bool> {
+        let (Some(tier), Some(entry)) = (&self.spilled.tier, self.entries.get_mut(key)) else {
+            return Ok(false);
+        };
+        let Some(value) = entry.value.get() else {
+            return Ok(false);
+        };
+        tier.write(key, &rdb::dump(value))?;
+        entry.value.take();
+        self.spilled.keys.set(self.spilled.keys.get() + 1);
+        self.spilled.bytes.set(self.spilled.bytes.get() + entry.size);
+        Ok(true)
+    }
+
+    // The number of spilled keys and the bytes they would use in memory.
+    pub fn spilled(&self) -> (usize, usize) {
+        (self.spilled.keys.get(), self.spilled.bytes.get())
+    }
+
+    // Removes every key, returning them so the caller decides when they're freed. Versions keep
+    // counting up, so a key recreated later can't be mistaken for the old one by WATCH.
+    pub fn flush(&mut self) -> Shard {
+        if let Some(frozen) = &self.frozen {
+            for key in frozen.pending.clone() {
+                self.preserve(&key);
+            }
+        }
+        if let Some(tier) = &self.spilled.tier {
+            // Copies left behind are overwritten if their keys are spilled again.
+            let _ = tier.clear();
+            self.spilled.keys.set(0);
+            self.spilled.bytes.set(0);
+        }
+        Shard {
+            entries: std::mem::take(&mut self.entries),
+            next_version: 0,
+            used_memory: std::mem::take(&mut self.used_memory),
+            resized: std::mem::take(&mut self.resized),
+            expires: std::mem::take(&mut self.expires),
+            field_expires: std::mem::take(&mut self.field_expires),
+            frozen: None,
+            spilled: Spilled::default(),
+            slots: self.slots.as_mut().map(|slots| std::mem::replace(slots, vec![HashSet::new(); SLOTS])),
+        }
+    }
+
+    // Starts keeping the keys of every hash slot, for cluster mode.
+    pub fn index_slots(&mut self) {
+        let mut slots = vec![HashSet::new(); SLOTS];
+        for key in self.entries.keys() {
+            slots[cluster::key_slot(key)].insert(key.clone());
+        }
+        self.slots = Some(slots);
+    }
+
+    // The keys of the hash slot, only known in cluster mode.
+    pub fn slot_keys(&self, slot: usize) -> impl Iterator<Item = &String> {
+        self.slots.iter().flat_map(move |slots| slots[slot].iter())
+    }
+
+    pub fn expire_time(&self, key: &str) -> Option<u64> {
+        self.expires.get(key)
+    }
+
+    pub fn is_expired(&self, key: &str) -> bool {
+        self.expires.get(key).is_some_and(|at| at <= now_ms())
+    }
+
+    // Sets the unix time in milliseconds the key expires at, false when there is no such key.
+    pub fn set_expire(&mut self, key: &str, at: u64) -> bool {
+        self.preserve(key);
+        if !self.bump_version(key) {
+            return false;
+        }
+        self.expires.insert(key.to_owned(), at);
+        true
+    }
+
+    // Removes the TTL of the key, false when it had none.
+    pub fn persist(&mut self, key: &str) -> bool {
+        self.preserve(key);
+        if self.expires.remove(key).is_none() {
+            return false;
+        }
+        self.bump_version(key);
+        true
+    }
+
+    pub fn expires_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    pub fn iter_expires(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.expires.iter()
+    }
+
+    // Deletes up to `limit` keys whose TTL passed, returning them along with their values.
+    pub fn remove_expired(&mut self, limit: usize) -> Vec<(String, Value)> {
+        let keys = self.expires.poll(now_ms(), limit);
+        keys.into_iter().filter_map(|key| {
+            let value = self.remove(&key)?;
+            Some((key, value))
+        }).collect()
+    }
+
+    // The keys whose TTL passed that weren't deleted yet, only counting the ones found by
+    // `remove_expired` so far.
+    pub fn stale_expires(&self) -> usize {
+        self.expires.due_len()
+    }
+
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.expires.next_deadline()
+    }
+
+    // The time the first field of the hash with a TTL expires at.
+    pub fn field_expiration(&self, key: &str) -> Option<u64> {
+        self.field_expires.get(key)
+    }
+
+    // Keeps track of when the first field of the hash with a TTL expires, once its fields changed.
+    // A spilled hash didn't change since it was spilled.
+    pub fn update_field_expiration(&mut self, key: &str) {
+        let at = match self.entries.get(key).map(|entry| entry.value.get()) {
+            Some(None) => return,
+            Some(Some(Value::Hash(hash))) => hash.next_expiration(),
+            _ => None
+        };
+        match at {
+            Some(at) => self.field_expires.insert(key.to_owned(), at),
+            None => {
+                self.field_expires.remove(key);
+            }
+        }
+    }
+
+    // Deletes the fields of the hash whose TTL passed, returning them along with the hash when they
+    // were its last fields (and so it was deleted as well).
+    pub fn remove_expired_fields(&mut self, key: &str) -> (Vec<String>, Option<Value>) {
+        let fields = match self.get_mut(key).map(Value::as_hash_mut) {
+            Some(Ok(hash)) => hash.remove_expired(now_ms()),
+            _ => vec![]
+        };
+        match self.peek(key) {
+            Some(Value::Hash(hash)) if hash.is_empty() => (fields, self.remove(key)),
+            _ => {
+                self.update_field_expiration(key);
+                (fields, None)
+            }
+        }
+    }
+
+    // Deletes the expired fields of up to `limit` hashes, see remove_expired_fields.
+    pub fn remove_due_fields(&mut self, limit: usize) -> Vec<(String, Vec<String>, Option<Value>)> {
+        let keys = self.field_expires.poll(now_ms(), limit);
+        keys.into_iter().map(|key| {
+            let (fields, value) = self.remove_expired_fields(&key);
+            (key, fields, value)
+        }).collect()
+    }
+
+    pub fn next_field_expiration(&self) -> Option<u64> {
+        self.field_expires.next_deadline()
+    }
+
+    // The hashes with fields that have a TTL.
+    pub fn field_expires_len(&self) -> usize {
+        self.field_expires.len()
+    }
+
+    // Counts a change of the key's metadata as a write for WATCH, false when there is no such key.
+    fn bump_version(&mut self, key: &str) -> bool {
+        self.next_version += 1;
+        let version = self.next_version;
+        self.entries.get_mut(key).map(|entry| entry.version = version).is_some()
+    }
+
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.entries.get(key).map(|entry| entry.version)
+    }
+
+    // Counts up on every write to the keyspace.
+    pub fn writes(&self) -> u64 {
+        self.next_version
+    }
+
+    fn saved_key(&self, key: &str) -> Option<SavedKey> {
+        let entry = self.entries.get(key)?;
+        let value = entry.value.get().cloned().unwrap_or_else(|| self.spilled.read(key));
+        Some((key.to_owned(), value, self.expires.get(key)))
+    }
+
+    // Starts a point-in-time view of the keyspace for a background save, until thawed.
+    pub fn freeze(&mut self) {
+        let keys: Vec<String> = self.entries.keys().cloned().collect();
+        let pending = keys.iter().cloned().collect();
+        self.frozen = Some(Frozen { keys, pending, copied: vec![] });
+    }
+
+    // Hands out up to `count` more keys as they were when the keyspace was frozen, none once all
+    // were handed out.
+    pub fn next_frozen(&mut self, count: usize) -> Vec<SavedKey> {
+        let Some(frozen) = &mut self.frozen else {
+            return vec![];
+        };
+        let mut keys = std::mem::take(&mut frozen.copied);
+        let mut taken = vec![];
+        while keys.len() + taken.len() < count {
+            match frozen.keys.pop() {
+                Some(key) if frozen.pending.remove(&key) => taken.push(key),
+                Some(_) => {},
+                None => break
+            }
+        }
+        keys.extend(taken.iter().filter_map(|key| self.saved_key(key)));
+        keys
+    }
+
+    // Ends the view of the keyspace, once the background save is done with it.
+    pub fn thaw(&mut self) {
+        self.frozen = None;
+    }
+
+    // Copies the key aside before its first write while the keyspace is frozen.
+    fn preserve(&mut self, key: &str) {
+        if !self.frozen.as_mut().is_some_and(|frozen| frozen.pending.remove(key)) {
+            return;
+        }
+        if let Some(saved) = self.saved_key(key) {
+            self.frozen.as_mut().unwrap().copied.push(saved);
+        }
+    }
+}
+
+// The keyspace, partitioned into shards by the hash slots of keys (so keys sharing a hash tag share
+// a shard), each locked on its own so commands on keys of different shards run in parallel.
+//
+// A command owns the shards of its keys while it runs (see `own_shards`), and `lock` gives it a view
+// of those shards only. Anything else, like commands without keys and background tasks, gets a view
+// of the whole keyspace, waiting for every shard. Transactions own the shards of all their commands
+// and scripts own every shard, so they stay atomic.
+pub struct Keyspace {
+    shards: Box<[ShardLock]>,
+}
+
+#[derive(Default)]
+struct ShardLock {
+    // Reentrant, as the commands of a transaction or a script own shards the transaction owns.
+    owner: ReentrantMutex<()>,
+    // Only ever locked by the thread owning the shard, so it never waits.
+    data: Mutex<Shard>,
+}
+
+// The shards a command owns, until dropped.
+pub struct Owned<'a> {
+    _guards: Vec<ReentrantMutexGuard<'a, ()>>,
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl Keyspace {
+    pub fn new(shards: usize) -> Self {
+        Self { shards: (0..shards).map(|_| ShardLock::default()).collect() }
+    }
+
+    pub fn own_all(&self) -> Owned<'_> {
+        self.own_shards(0..self.shards.len())
+    }
+
+    // Owns the shards by their sorted indexes, waiting for other threads to release them. They're
+    // taken in order, so two commands never wait for each other.
+    pub fn own_shards(&self, indexes: impl IntoIterator<Item = usize>) -> Owned<'_> {
+        Owned { _guards: indexes.into_iter().map(|index| self.shards[index].owner.lock()).collect() }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    // The sorted indexes of the shards of the keys.
+    pub fn shard_indexes<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+        let mut indexes: Vec<usize> = keys.into_iter().map(|key| shard_index(key, self.shards.len())).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes
+    }
+
+    // A view of the shards the current thread owns, or of the whole keyspace when it owns none.
+    pub fn lock(&self) -> Db<'_> {
+        let owning = self.shards.iter().any(|shard| shard.owner.is_owned_by_current_thread());
+        let shards = self.shards.iter().map(|shard| match shard.owner.is_owned_by_current_thread() {
+            true => Some(Locked { shard: shard.data.lock().unwrap(), _owned: None }),
+            false if owning => None,
+            false => {
+                let owned = shard.owner.lock();
+                Some(Locked { shard: shard.data.lock().unwrap(), _owned: Some(owned) })
+            }
+        }).collect();
+        Db { shards }
+    }
+}
+
+fn shard_index(key: &str, shards: usize) -> usize {
+    cluster::key_slot(key) % shards
+}
+
+struct Locked<'a> {
+    // Declared first, so it's unlocked before the shard is released.
+    shard: MutexGuard<'a, Shard>,
+    // Owned for the view, when the thread didn't own the shard already.
+    _owned: Option<ReentrantMutexGuard<'a, ()>>,
+}
+
+// The shards of the keyspace a thread has access to, see Keyspace::lock. Accessing a key of a
+// shard it doesn't have is a bug, as the command didn't declare the key.
+pub struct Db<'a> {
+    // By index, None for the ones other threads may own.
+    shards: Vec<Option<Locked<'a>>>,
+}
+
+impl<'a> Db<'a> {
+    fn shard(&self, key: &str) -> &Shard {
+        match &self.shards[shard_index(key, self.shards.len())] {
+            Some(locked) => &locked.shard,
+            None => panic!("Accessed the key {} without owning its shard", key)
+        }
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let index = shard_index(key, self.shards.len());
+        match &mut self.shards[index] {
+            Some(locked) => &mut locked.shard,
+            None => panic!("Accessed the key {} without owning its shard", key)
+        }
+    }
+
+    fn shards(&self) -> impl Iterator<Item = &Shard> {
+        self.shards.iter().flatten().map(|locked| &*locked.shard)
+    }
+
+    fn shards_mut(&mut self) -> impl Iterator<Item = &mut Shard> + use<'_, 'a> {
+        self.shards.iter_mut().flatten().map(|locked| &mut *locked.shard)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.shard(key).get(key)
+    }
+
+    pub fn peek(&self, key: &str) -> Option<&Value> {
+        self.shard(key).peek(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    pub fn touch(&self, key: &str) -> bool {
+        self.shard(key).touch(key)
+    }
+
+    pub fn access_info(&self, key: &str) -> Option<AccessInfo> {
+        self.shard(key).access_info(key)
+    }
+
+    pub fn set_access_info(&self, key: &str, idle: Option<Duration>, frequency: Option<u8>) {
+        self.shard(key).set_access_info(key, idle, frequency)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> Value) -> &mut Value {
+        self.shard_mut(key).get_or_insert_with(key, f)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) -> Option<Value> {
+        self.shard_mut(&key).set(key, value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.shard_mut(key).remove(key)
+    }
+
+    pub fn used_memory(&mut self) -> usize {
+        self.shards_mut().map(|shard| shard.used_memory()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards().map(|shard| shard.len()).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Cow<'_, Value>)> {
+        self.shards().flat_map(|shard| shard.iter())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.shards().flat_map(|shard| shard.keys())
+    }
+
+    pub fn resident_keys(&self) -> impl Iterator<Item = &String> {
+        self.shards().flat_map(|shard| shard.resident_keys())
+    }
+
+    pub fn set_tier(&mut self, tier: Arc<dyn Tier>) {
+        for shard in self.shards_mut() {
+            shard.set_tier(tier.clone());
+        }
+    }
+
+    pub fn has_tier(&self) -> bool {
+        self.shards().any(|shard| shard.has_tier())
+    }
+
+    pub fn spill(&mut self, key: &str) -> io::Result<bool> {
+        self.shard_mut(key).spill(key)
+    }
+
+    pub fn spilled(&self) -> (usize, usize) {
+        self.shards().map(|shard| shard.spilled()).fold((0, 0), |(keys, bytes), (more_keys, more_bytes)| (keys + more_keys, bytes + more_bytes))
+    }
+
+    pub fn random_key(&self) -> Option<&String> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut index = rand::thread_rng().gen_range(0..len);
+        for shard in self.shards() {
+            if index < shard.len() {
+                return shard.keys().nth(index);
+            }
+            index -= shard.len();
+        }
+        None
+    }
+
+    pub fn flush(&mut self) -> Vec<Shard> {
+        self.shards_mut().map(|shard| shard.flush()).collect()
+    }
+
+    pub fn index_slots(&mut self) {
+        for shard in self.shards_mut() {
+            shard.index_slots();
+        }
+    }
+
+    pub fn slot_keys(&self, slot: usize) -> impl Iterator<Item = &String> {
+        self.shards().flat_map(move |shard| shard.slot_keys(slot))
+    }
+
+    pub fn expire_time(&self, key: &str) -> Option<u64> {
+        self.shard(key).expire_time(key)
+    }
+
+    pub fn is_expired(&self, key: &str) -> bool {
+        self.shard(key).is_expired(key)
+    }
+
+    pub fn set_expire(&mut self, key: &str, at: u64) -> bool {
+        self.shard_mut(key).set_expire(key, at)
+    }
+
+    pub fn persist(&mut self, key: &str) -> bool {
+        self.shard_mut(key).persist(key)
+    }
+
+    pub fn expires_len(&self) -> usize {
+        self.shards().map(|shard| shard.expires_len()).sum()
+    }
+
+    pub fn iter_expires(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.shards().flat_map(|shard| shard.iter_expires())
+    }
+
+    // Starts from a random shard, so the ones coming first don't get all of the `limit`.
+    pub fn remove_expired(&mut self, limit: usize) -> Vec<(String, Value)> {
+        let count = self.shards.len();
+        let start = rand::thread_rng().gen_range(0..count);
+        let mut removed = vec![];
+        for i in 0..count {
+            if let Some(locked) = &mut self.shards[(start + i) % count] {
+                removed.extend(locked.shard.remove_expired(limit - removed.len()));
+            }
+            if removed.len() >= limit {
+                break;
+            }
+        }
+        removed
+    }
+
+    pub fn stale_expires(&self) -> usize {
+        self.shards().map(|shard| shard.stale_expires()).sum()
+    }
+
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.shards().filter_map(|shard| shard.next_expiration()).min()
+    }
+
+    pub fn field_expiration(&self, key: &str) -> Option<u64> {
+        self.shard(key).field_expiration(key)
+    }
+
+    pub fn update_field_expiration(&mut self, key: &str) {
+        self.shard_mut(key).update_field_expiration(key)
+    }
+
+    pub fn remove_expired_fields(&mut self, key: &str) -> (Vec<String>, Option<Value>) {
+        self.shard_mut(key).remove_expired_fields(key)
+    }
+
+    // Starts from a random shard, like remove_expired.
+    pub fn remove_due_fields(&mut self, limit: usize) -> Vec<(String, Vec<String>, Option<Value>)> {
+        let count = self.shards.len();
+        let start = rand::thread_rng().gen_range(0..count);
+        let mut removed = vec![];
+        for i in 0..count {
+            if let Some(locked) = &mut self.shards[(start + i) % count] {
+                removed.extend(locked.shard.remove_due_fields(limit - removed.len()));
+            }
+            if removed.len() >= limit {
+                break;
+            }
+        }
+        removed
+    }
+
+    pub fn next_field_expiration(&self) -> Option<u64> {
+        self.shards().filter_map(|shard| shard.next_field_expiration()).min()
+    }
+
+    pub fn field_expires_len(&self) -> usize {
+        self.shards().map(|shard| shard.field_expires_len()).sum()
+    }
+
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.shard(key).version(key)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.shards().map(|shard| shard.writes()).sum()
+    }
+
+    pub fn freeze(&mut self) {
+        for shard in self.shards_mut() {
+            shard.freeze();
+        }
+    }
+
+    pub fn next_frozen(&mut self, count: usize) -> Vec<SavedKey> {
+        let mut keys = vec![];
+        for shard in self.shards_mut() {
+            keys.extend(shard.next_frozen(count - keys.len()));
+            if keys.len() >= count {
+                break;
+            }
+        }
+        keys
+    }
+
+    pub fn thaw(&mut self) {
+        for shard in self.shards_mut() {
+            shard.thaw();
+        }
+    }
+
+    // Moves the shards out of the view, for something that can't borrow them.
+    #[cfg(feature = "wasm")]
+    pub fn detach(&mut self) -> Detached {
+        let shards = self.shards.iter_mut().map(|locked| locked.as_mut().map(|locked| std::mem::take(&mut *locked.shard))).collect();
+        Detached { shards }
+    }
+
+    #[cfg(feature = "wasm")]
+    pub fn attach(&mut self, detached: Detached) {
+        for (locked, shard) in self.shards.iter_mut().zip(detached.shards) {
+            if let (Some(locked), Some(shard)) = (locked, shard) {
+                *locked.shard = shard;
+            }
+        }
+    }
+}
+
+// The shards of a view, moved out of it until attached back (see Db::detach).
+#[cfg(feature = "wasm")]
+pub struct Detached {
+    shards: Vec<Option<Shard>>,
+}
+
+#[cfg(feature = "wasm")]
+impl Detached {
+    fn shard(&self, key: &str) -> &Shard {
+        let index = shard_index(key, self.shards.len());
+        self.shards[index].as_ref().unwrap_or_else(|| panic!("Accessed the key {} without owning its shard", key))
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let index = shard_index(key, self.shards.len());
+        self.shards[index].as_mut().unwrap_or_else(|| panic!("Accessed the key {} without owning its shard", key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.shard(key).get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) -> Option<Value> {
+        self.shard_mut(&key).set(key, value)
+    }
+}
@@ -0,0 +1,134 @@
+// DEBUG, the helpers of tests and of whoever looks into the internals of the server. As it can block
+// the server and reveal its internals, it's only allowed when enable-debug-command is set, either to
+// yes or to local for the clients of the loopback interface and of the unix socket.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::glob::glob_match;
+use crate::memory::{key_usage, DEFAULT_SAMPLES};
+use crate::{config, expire, list, rdb, RESPError, RESPValue, SharedState};
+
+// Whether a client connected from `addr` (empty on unix sockets) may run DEBUG.
+fn allowed(addr: &str, shared: &SharedState) -> bool {
+    config::enabled_for("enable-debug-command", addr, shared)
+}
+
+// The internals of a value, like Redis describes them.
+fn describe(value: &Value, idle: Duration) -> String {
+    let mut description = format!(
+        "Value at:{:p} refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+        value, value.encoding(), rdb::serialized_len(value), idle.as_secs());
+    // Every element of a quicklist is a node of its own.
+    if let Value::List(list::List::Quicklist(elements)) = value {
+        description.push_str(&format!(" ql_nodes:{} ql_listpack_max:{}", elements.len(), list::MAX_LISTPACK_SIZE.load(Ordering::Relaxed)));
+    }
+    description
+}
+
+// A histogram of the keyspace by type and encoding, biggest first, like `jmap -histo` of Java.
+fn jmap(shared: &SharedState) -> String {
+    let mut classes: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for (key, value) in shared.db.lock().iter() {
+        let class = classes.entry(format!("{} ({})", value.type_name(), value.encoding())).or_default();
+        class.0 += 1;
+        class.1 += key_usage(key, &value, DEFAULT_SAMPLES);
+    }
+    let mut classes: Vec<_> = classes.into_iter().collect();
+    classes.sort_by(|(_, (_, a)), (_, (_, b))| b.cmp(a));
+
+    let mut histogram = format!("{:>4}  {:>12}  {:>14}  class name\n", "num", "#instances", "#bytes");
+    histogram.push_str(&"-".repeat(50));
+    histogram.push('\n');
+    for (i, (class, (instances, bytes))) in classes.iter().enumerate() {
+        histogram.push_str(&format!("{:>4}: {:>12}  {:>14}  {}\n", i + 1, instances, bytes, class));
+    }
+    let (instances, bytes) = classes.iter().fold((0, 0), |(instances, bytes), (_, class)| (instances + class.0, bytes + class.1));
+    histogram.push_str(&format!("Total {:>12}  {:>14}\n", instances, bytes));
+    histogram
+}
+
+// Matches random patterns against random strings, which must neither hang nor crash.
+fn stringmatch_fuzz() {
+    const CHARSET: &[u8] = b"*?[]\\-^ab";
+    let mut rng = rand::thread_rng();
+    let mut random = |max_len: usize| -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| CHARSET[rng.gen_range(0..CHARSET.len())]).collect()
+    };
+    for _ in 0..100_000 {
+        let (pattern, string) = (random(16), random(16));
+        glob_match(&pattern, &string);
+    }
+}
+
+// DEBUG SLEEP seconds
+// DEBUG OBJECT key
+// DEBUG SET-ACTIVE-EXPIRE 0|1
+// DEBUG JMAP
+// DEBUG HTSTATS dbid
+// DEBUG STRINGMATCH-LEN
+pub fn debug(command: &[Arg], addr: &str, shared: &SharedState) -> Result<RESPValue, RESPError> {
+    if !allowed(addr, shared) {
+        return Err(RESPError::DebugNotAllowed);
+    }
+
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("DEBUG|{}", subcommand)))
+    };
+
+    match subcommand.as_str() {
+        "SLEEP" => {
+            check_arity(command.len() == 3)?;
+            let seconds = command[2].parse::<f64>().ok().filter(|seconds| seconds.is_finite() && *seconds >= 0.0).ok_or(RESPError::NotAFloat)?;
+            // Blocks the worker on purpose, like a slow command would.
+            std::thread::sleep(Duration::from_secs_f64(seconds));
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "OBJECT" => {
+            check_arity(command.len() == 3)?;
+            let db = shared.db.lock();
+            let (value, access) = db.peek(&command[2]).zip(db.access_info(&command[2])).ok_or(RESPError::NoSuchKey)?;
+            Ok(RESPValue::SimpleString(describe(value, access.idle)))
+        },
+        "SET-ACTIVE-EXPIRE" => {
+            check_arity(command.len() == 3)?;
+            let enabled = match command[2].as_str() {
+                "0" => false,
+                "1" => true,
+                _ => return Err(RESPError::SyntaxError)
+            };
+            expire::ACTIVE_EXPIRE.store(enabled, Ordering::Relaxed);
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "JMAP" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::BlobString(jmap(shared).into()))
+        },
+        "HTSTATS" => {
+            check_arity(command.len() == 3)?;
+            // There is a single database.
+            if command[2] != "0" {
+                return Err(RESPError::DatabaseOutOfRange);
+            }
+            let db = shared.db.lock();
+            Ok(RESPValue::BlobString(format!(
+                "[Dictionary HT]\nshards: {}\nnumber of elements: {}\n[Expires HT]\nnumber of elements: {}\n[Field expires]\nnumber of elements: {}\n",
+                shared.db.shards(), db.len(), db.expires_len(), db.field_expires_len()).into()))
+        },
+        "STRINGMATCH-LEN" => {
+            check_arity(command.len() == 2)?;
+            stringmatch_fuzz();
+            Ok(RESPValue::SimpleString(String::from("Apparently bast did not crash: test passed")))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("DEBUG {}", command[1])))
+    }
+}
@@ -0,0 +1,100 @@
+// DUMP and RESTORE, which move single keys between servers. The payload is the value serialized
+// like Redis does (see rdb::dump), so it's interchangeable with the one of Redis.
+
+use std::time::Duration;
+
+use crate::arg::Arg;
+use crate::db::{now_ms, Value};
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC};
+use crate::{parse_number, rdb, RESPError, RESPValue, SharedState};
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// The payload in hex, for the formats that only hold text (like exports).
+pub fn payload(value: &Value) -> String {
+    to_hex(&rdb::dump(value))
+}
+
+// DUMP key
+pub fn dump(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    Ok(match db.get(command[1].as_str()) {
+        Some(value) => RESPValue::BlobString(rdb::dump(value).into()),
+        None => RESPValue::Null
+    })
+}
+
+// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+pub fn restore(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let mut replace = false;
+    let mut absolute_ttl = false;
+    let mut idle = None;
+    let mut frequency = None;
+    let mut args = command[4..].iter();
+    while let Some(arg) = args.next() {
+        match arg.to_ascii_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute_ttl = true,
+            "IDLETIME" if frequency.is_none() => {
+                let seconds = parse_number(args.next().ok_or(RESPError::SyntaxError)?)?;
+                if seconds < 0 {
+                    return Err(RESPError::InvalidIdleTime);
+                }
+                idle = Some(Duration::from_secs(seconds as u64));
+            },
+            "FREQ" if idle.is_none() => {
+                let count = parse_number(args.next().ok_or(RESPError::SyntaxError)?)?;
+                frequency = Some(u8::try_from(count).map_err(|_| RESPError::InvalidFrequency)?);
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+    let ttl = parse_number(&command[2])?;
+    if ttl < 0 {
+        return Err(RESPError::InvalidTtl);
+    }
+    let payload = command[3].raw();
+    if !rdb::verify_dump(payload) {
+        return Err(RESPError::BadDumpPayload);
+    }
+    let value = rdb::undump(payload).map_err(|_| RESPError::BadDataFormat)?;
+
+    let mut db = shared.db.lock();
+    if !replace && db.contains_key(key) {
+        return Err(RESPError::BusyKey);
+    }
+    let at = match ttl {
+        0 => None,
+        ttl if absolute_ttl => Some(ttl as u64),
+        ttl => Some(now_ms() + ttl as u64)
+    };
+    // A key that would expire right away isn't restored, but still replaces the key it would have.
+    if at.is_some_and(|at| at <= now_ms()) {
+        let old = db.remove(key);
+        drop(db);
+        if lazyfree::free_replaced(old, shared) {
+            notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+        }
+        return Ok(RESPValue::SimpleString(String::from("OK")));
+    }
+    let old = db.set(key.to_owned(), value);
+    if let Some(at) = at {
+        db.set_expire(key, at);
+    }
+    db.set_access_info(key, idle, frequency);
+    drop(db);
+    lazyfree::free_replaced(old, shared);
+    notify_keyspace_event(shared, NOTIFY_GENERIC, "restore", key, 0);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
@@ -0,0 +1,244 @@
+// Encryption at rest of the snapshot and the append only file. With `encryption-key-file` set (or
+// `encryption-key-command`, to get the keys from a KMS), files are written encrypted with
+// AES-256-GCM, and both encrypted and plain files are loaded.
+//
+// The keys are 64 hex digits each, one per line, skipping blank lines and lines starting with #.
+// Files are encrypted with the first key and can be read with any of them, so rotating is putting a
+// new key first and reloading the keys (SIGHUP, or setting `encryption-key-file` again where
+// enable-protected-configs allows it): the append only file is then rewritten with the new key and
+// the snapshot is on its next save, after which the old key can be removed.
+//
+// An encrypted file is the magic and the ID of its key (the start of its SHA-256), followed by
+// records of a nonce, the length of the ciphertext and the ciphertext. The offset of every record
+// is authenticated along with it, so records can't be reordered or moved around.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::storage::Upload;
+use crate::{aof, dump, SharedState};
+
+const MAGIC: &[u8] = b"BASTENC1";
+const KEY_ID_LEN: usize = 8;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN;
+const NONCE_LEN: usize = 12;
+const RECORD_HEADER_LEN: usize = NONCE_LEN + 4;
+// Data is encrypted in records of up to this many bytes.
+const RECORD_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+pub struct Key {
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+// The first one encrypts, set through the config.
+static KEYS: RwLock<Vec<Key>> = RwLock::new(Vec::new());
+
+fn parse_keys(text: &str) -> Result<Vec<Key>, String> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| {
+        let bytes = dump::from_hex(line).filter(|bytes| bytes.len() == 32)
+            .ok_or_else(|| String::from("keys must be 64 hex digits"))?;
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(&bytes)[..KEY_ID_LEN]);
+        Ok(Key { id, cipher: Aes256Gcm::new_from_slice(&bytes).unwrap() })
+    }).collect()
+}
+
+// Reads the keys from the file or from the output of the command, encryption is off when neither
+// is set.
+pub fn set_keys(file: &str, command: &str) -> Result<(), String> {
+    let text = match (file, command) {
+        ("", "") => String::new(),
+        (file, "") => std::fs::read_to_string(file).map_err(|e| format!("can't read {}: {}", file, e))?,
+        ("", command) => {
+            let output = Command::new("sh").arg("-c").arg(command).stderr(Stdio::inherit()).output().map_err(|e| e.to_string())?;
+            if !output.status.success() {
+                return Err(format!("encryption-key-command failed ({})", output.status));
+            }
+            String::from_utf8(output.stdout).map_err(|_| String::from("encryption-key-command printed invalid UTF-8"))?
+        },
+        _ => return Err(String::from("only one of encryption-key-file and encryption-key-command can be set"))
+    };
+    let keys = parse_keys(&text)?;
+    if keys.is_empty() && !(file.is_empty() && command.is_empty()) {
+        return Err(String::from("no encryption keys were given"));
+    }
+    *KEYS.write().unwrap() = keys;
+    Ok(())
+}
+
+// Reads the keys again, on SIGHUP.
+pub fn reload_keys(shared: &SharedState) -> Result<(), String> {
+    set_keys(&shared.config.get("encryption-key-file"), &shared.config.get("encryption-key-command"))?;
+    aof::keys_changed(shared);
+    Ok(())
+}
+
+fn current() -> Option<Key> {
+    KEYS.read().unwrap().first().cloned()
+}
+
+// Whether a file encrypted with the key (None when it isn't encrypted) is encrypted as new files are.
+pub fn is_current(key: Option<&Key>) -> bool {
+    current().map(|current| current.id) == key.map(|key| key.id)
+}
+
+fn find(id: &[u8]) -> io::Result<Key> {
+    KEYS.read().unwrap().iter().find(|key| key.id == id).cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the file is encrypted with an unknown key"))
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Starts a new file, encrypted with the current key when there is one.
+pub fn start_file(file: &mut File) -> io::Result<Option<Key>> {
+    let key = current();
+    if let Some(key) = &key {
+        file.write_all(MAGIC)?;
+        file.write_all(&key.id)?;
+    }
+    Ok(key)
+}
+
+// The key an existing file is encrypted with, None when it isn't. The file needs to be readable.
+pub fn file_key(file: &mut File) -> io::Result<Option<Key>> {
+    let mut header = vec![];
+    (&*file).take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if !is_encrypted(&header) || header.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    find(&header[MAGIC.len()..]).map(Some)
+}
+
+// Encrypts the data to append at `offset` of a file encrypted with the key.
+pub fn seal(key: &Key, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut sealed = vec![];
+    for chunk in data.chunks(RECORD_SIZE) {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let aad = (offset + sealed.len() as u64).to_le_bytes();
+        let ciphertext = key.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad: &aad })
+            .expect("Records are much smaller than what AES-GCM can encrypt");
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        sealed.extend_from_slice(&ciphertext);
+    }
+    sealed
+}
+
+pub struct Decrypted {
+    pub data: Vec<u8>,
+    key: Key,
+    // The offsets in the file and in the data of every record.
+    records: Vec<(usize, usize)>,
+    // Where the last complete record ends, a crash may have cut the file short after it.
+    end: usize,
+    len: usize,
+}
+
+// Decrypts a file, up to its last complete record.
+pub fn decrypt(file: &[u8]) -> io::Result<Decrypted> {
+    if file.len() < HEADER_LEN {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the encrypted file is truncated"));
+    }
+    let key = find(&file[MAGIC.len()..HEADER_LEN])?;
+    let mut decrypted = Decrypted { data: vec![], key, records: vec![], end: HEADER_LEN, len: file.len() };
+    let mut at = HEADER_LEN;
+    while file.len() >= at + RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes(file[at + NONCE_LEN..at + RECORD_HEADER_LEN].try_into().unwrap()) as usize;
+        let end = at + RECORD_HEADER_LEN + len;
+        if end > file.len() {
+            break;
+        }
+        let aad = (at as u64).to_le_bytes();
+        let data = decrypted.key.cipher.decrypt(Nonce::from_slice(&file[at..at + NONCE_LEN]), Payload { msg: &file[at + RECORD_HEADER_LEN..end], aad: &aad })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("failed decrypting the record at offset {} of the encrypted file", at)))?;
+        decrypted.records.push((at, decrypted.data.len()));
+        decrypted.data.extend_from_slice(&data);
+        at = end;
+    }
+    decrypted.end = at;
+    Ok(decrypted)
+}
+
+impl Decrypted {
+    // Whether the file ends with its last complete record.
+    pub fn is_complete(&self) -> bool {
+        self.end == self.len
+    }
+
+    // Cuts the file at `path` to the first `len` bytes of the data, encrypting again the part kept of
+    // the record it cuts through. Returns the new size of the file.
+    pub fn truncate(&self, path: &str, len: usize) -> io::Result<u64> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if len == self.data.len() {
+            file.set_len(self.end as u64)?;
+            return Ok(self.end as u64);
+        }
+        let (at, start) = self.records.iter().rev().find(|(_, start)| *start <= len).copied().unwrap_or((HEADER_LEN, 0));
+        file.set_len(at as u64)?;
+        let kept = seal(&self.key, at as u64, &self.data[start..len]);
+        file.seek(SeekFrom::Start(at as u64))?;
+        file.write_all(&kept)?;
+        Ok((at + kept.len()) as u64)
+    }
+}
+
+// Encrypts what's written to the upload in records.
+struct EncryptedUpload {
+    upload: Box<dyn Upload>,
+    key: Key,
+    buf: Vec<u8>,
+    offset: u64,
+}
+
+impl EncryptedUpload {
+    fn write_records(&mut self, len: usize) -> io::Result<()> {
+        let sealed = seal(&self.key, self.offset, &self.buf[..len]);
+        self.upload.write_all(&sealed)?;
+        self.offset += sealed.len() as u64;
+        self.buf.drain(..len);
+        Ok(())
+    }
+}
+
+impl Write for EncryptedUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= RECORD_SIZE {
+            self.write_records(self.buf.len() - self.buf.len() % RECORD_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.upload.flush()
+    }
+}
+
+impl Upload for EncryptedUpload {
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        self.write_records(self.buf.len())?;
+        self.upload.commit()
+    }
+}
+
+// Encrypts the upload with the current key, leaving it as is when there is none.
+pub fn encrypting(mut upload: Box<dyn Upload>) -> io::Result<Box<dyn Upload>> {
+    let Some(key) = current() else {
+        return Ok(upload);
+    };
+    upload.write_all(MAGIC)?;
+    upload.write_all(&key.id)?;
+    Ok(Box::new(EncryptedUpload { upload, key, buf: vec![], offset: HEADER_LEN as u64 }))
+}
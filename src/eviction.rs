@@ -0,0 +1,77 @@
+use rand::seq::IteratorRandom;
+
+use crate::db::Db;
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_EVICTED};
+use crate::stats::Stats;
+use crate::{aof, logging, RESPError, SharedState};
+
+pub const POLICIES: &[&str] = &[
+    "noeviction", "allkeys-lru", "allkeys-lfu", "allkeys-random",
+    "volatile-lru", "volatile-lfu", "volatile-random", "volatile-ttl",
+];
+
+// Picks the key to evict out of a few sampled ones, an approximation of evicting the best key in
+// the whole keyspace, like in Redis.
+fn select_victim(db: &Db, policy: &str, samples: usize) -> Option<String> {
+    let (scope, algorithm) = policy.split_once('-')?;
+    let mut rng = rand::thread_rng();
+    let samples = samples.max(1);
+    // Volatile policies only evict keys with a TTL.
+    let sampled: Vec<&String> = if scope == "volatile" {
+        db.iter_expires().map(|(key, _)| key).choose_multiple(&mut rng, samples)
+    } else {
+        db.keys().choose_multiple(&mut rng, samples)
+    };
+
+    let victim = match algorithm {
+        "lru" => sampled.into_iter().max_by_key(|key| db.access_info(key).unwrap().idle),
+        "lfu" => sampled.into_iter().min_by_key(|key| db.access_info(key).unwrap().frequency),
+        "ttl" => sampled.into_iter().min_by_key(|key| db.expire_time(key).unwrap()),
+        _ => sampled.into_iter().next()
+    };
+    victim.cloned()
+}
+
+// Picks the value to spill to the disk tier out of a few sampled ones, the one not accessed for the
+// longest.
+fn select_spilled(db: &Db, samples: usize) -> Option<String> {
+    let sampled = db.resident_keys().choose_multiple(&mut rand::thread_rng(), samples.max(1));
+    sampled.into_iter().max_by_key(|key| db.access_info(key).unwrap().idle).cloned()
+}
+
+// Evicts keys as maxmemory-policy says until the keyspace fits in maxmemory again, failing when it
+// doesn't and nothing can be evicted. A maxmemory of 0 means no limit. With a disk tier, values are
+// spilled to it instead, and keys are only evicted when that fails or nothing is left to spill.
+pub fn free_memory_if_needed(shared: &SharedState) -> Result<(), RESPError> {
+    let maxmemory = shared.config.get_int("maxmemory") as usize;
+    if maxmemory == 0 {
+        return Ok(());
+    }
+    let policy = shared.config.get("maxmemory-policy");
+    let samples = shared.config.get_int("maxmemory-samples") as usize;
+
+    loop {
+        let key = {
+            let mut db = shared.db.lock();
+            if db.used_memory() <= maxmemory {
+                return Ok(());
+            }
+            if let Some(key) = db.has_tier().then(|| select_spilled(&db, samples)).flatten() {
+                match db.spill(&key) {
+                    Ok(_) => continue,
+                    Err(e) => logging::log(shared, "warning", format!("Failed spilling {} to the disk tier: {}", key, e))
+                }
+            }
+            let key = select_victim(&db, &policy, samples).ok_or(RESPError::OutOfMemory)?;
+            let value = db.remove(&key).unwrap();
+            lazyfree::free(value, shared.config.get_bool("lazyfree-lazy-eviction"), shared);
+            key
+        };
+
+        aof::deleted(std::slice::from_ref(&key), shared);
+        Stats::incr(&shared.stats.evicted_keys);
+        notify_keyspace_event(shared, NOTIFY_EVICTED, "evicted", &key, 0);
+        shared.tracking.lock().unwrap().invalidate(&[key.as_str()], None, &shared.pubsub.lock().unwrap());
+    }
+}
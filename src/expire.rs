@@ -0,0 +1,285 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::arg::Arg;
+use crate::cron;
+use crate::db::{now_ms, Value};
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_HASH};
+use crate::stats::Stats;
+use crate::{aof, parse_number, RESPError, RESPValue, SharedState};
+
+// Expired keys deleted by every round of the active expire cycle, at the lowest effort.
+const KEYS_PER_LOOP: usize = 20;
+// The percentage of the time between cycles a cycle may take, at the lowest effort.
+const CYCLE_TIME_PERCENT: u64 = 25;
+
+// Cleared by DEBUG SET-ACTIVE-EXPIRE 0, leaving the expired keys until they are accessed.
+pub static ACTIVE_EXPIRE: AtomicBool = AtomicBool::new(true);
+
+// The TTL in milliseconds of keys written without one, and the longest TTL they may have (none when
+// 0). Set through the config (`default-ttl` and `max-ttl`).
+pub static DEFAULT_TTL: AtomicU64 = AtomicU64::new(0);
+pub static MAX_TTL: AtomicU64 = AtomicU64::new(0);
+
+// Reports the deletion of expired keys.
+fn expired(keys: &[String], shared: &SharedState) {
+    aof::deleted(keys, shared);
+    for key in keys {
+        Stats::incr(&shared.stats.expired_keys);
+        notify_keyspace_event(shared, NOTIFY_EXPIRED, "expired", key, 0);
+    }
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    shared.tracking.lock().unwrap().invalidate(&keys, None, &shared.pubsub.lock().unwrap());
+}
+
+// Reports the deletion of expired fields of a hash, and of the hash itself when they were its last
+// (given back then, to be freed).
+fn fields_expired(key: &str, fields: &[String], value: Option<Value>, shared: &SharedState) {
+    if fields.is_empty() {
+        return;
+    }
+    aof::fields_deleted(key, fields, shared);
+    shared.stats.expired_subkeys.fetch_add(fields.len() as u64, Ordering::Relaxed);
+    notify_keyspace_event(shared, NOTIFY_HASH, "hexpired", key, 0);
+    if let Some(value) = value {
+        lazyfree::free(value, shared.config.get_bool("lazyfree-lazy-expire"), shared);
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+    }
+    shared.tracking.lock().unwrap().invalidate(&[key], None, &shared.pubsub.lock().unwrap());
+}
+
+// Deletes the key when its TTL has passed, or the fields of the hash whose TTLs have, before a
+// command gets to access it. Replicas leave it to their primary, which streams the deletion.
+pub fn expire_if_needed(key: &str, shared: &SharedState) {
+    if shared.replication.is_replica() {
+        return;
+    }
+    let mut db = shared.db.lock();
+    if db.is_expired(key) {
+        let value = db.remove(key);
+        drop(db);
+        if let Some(value) = value {
+            lazyfree::free(value, shared.config.get_bool("lazyfree-lazy-expire"), shared);
+        }
+        expired(&[key.to_owned()], shared);
+    } else if db.field_expiration(key).is_some_and(|at| at <= now_ms()) {
+        let (fields, value) = db.remove_expired_fields(key);
+        drop(db);
+        fields_expired(key, &fields, value, shared);
+    }
+}
+
+// Runs rounds of deletions until a round deletes less than `limit`, or the time is up. Returns
+// whether the time was up.
+fn in_rounds(limit: usize, start: Instant, time_limit: Duration, shared: &SharedState, mut round: impl FnMut() -> usize) -> bool {
+    loop {
+        if round() < limit {
+            return false;
+        }
+        if start.elapsed() > time_limit {
+            Stats::incr(&shared.stats.expired_time_cap_reached_count);
+            return true;
+        }
+    }
+}
+
+// Deletes expired keys nobody accesses anymore, on every tick of the cron. The expired keys are
+// found through the timer wheel of the keyspace, and are deleted in rounds until there are none left
+// or the cycle runs out of time, a higher active-expire-effort deleting more keys per round and giving
+// the cycle more time. The expired fields of hashes are deleted the same way with the time left.
+// Replicas don't, like with expire_if_needed.
+pub fn active_expire_cycle(shared: &Arc<SharedState>) {
+    let period = cron::tick(shared);
+    if shared.replication.is_replica() || !ACTIVE_EXPIRE.load(Ordering::Relaxed) {
+        return;
+    }
+    let (next_key, next_field) = {
+        let db = shared.db.lock();
+        (db.next_expiration(), db.next_field_expiration())
+    };
+    if next_key.into_iter().chain(next_field).min().is_none_or(|at| at > now_ms()) {
+        return;
+    }
+
+    let effort = shared.config.get_int("active-expire-effort") as usize - 1;
+    let keys_per_loop = KEYS_PER_LOOP + KEYS_PER_LOOP / 4 * effort;
+    let time_limit = period * (CYCLE_TIME_PERCENT + 2 * effort as u64) as u32 / 100;
+
+    let start = Instant::now();
+    let lazy = shared.config.get_bool("lazyfree-lazy-expire");
+    let out_of_time = in_rounds(keys_per_loop, start, time_limit, shared, || {
+        let removed = shared.db.lock().remove_expired(keys_per_loop);
+        let count = removed.len();
+        let keys: Vec<String> = removed.into_iter().map(|(key, value)| {
+            lazyfree::free(value, lazy, shared);
+            key
+        }).collect();
+        expired(&keys, shared);
+        count
+    });
+    if !out_of_time {
+        in_rounds(keys_per_loop, start, time_limit, shared, || {
+            let removed = shared.db.lock().remove_due_fields(keys_per_loop);
+            let count = removed.len();
+            for (key, fields, value) in removed {
+                fields_expired(&key, &fields, value, shared);
+            }
+            count
+        });
+    }
+
+    let stats = &shared.stats;
+    stats.expire_cycle_usec.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    let db = shared.db.lock();
+    // Keys left behind when the cycle ran out of time.
+    let stale = if db.expires_len() == 0 { 0.0 } else { db.stale_expires() as f64 * 100.0 / db.expires_len() as f64 };
+    stats.set_expired_stale_perc(stale);
+}
+
+// The NX, XX, GT and LT options of setting a TTL.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Condition {
+    Always,
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+impl Condition {
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.to_ascii_uppercase().as_str() {
+            "NX" => Some(Condition::Nx),
+            "XX" => Some(Condition::Xx),
+            "GT" => Some(Condition::Gt),
+            "LT" => Some(Condition::Lt),
+            _ => None
+        }
+    }
+
+    // Whether a TTL expiring at `at` may replace the current one, no TTL counting as an infinite one.
+    pub fn allows(self, current: Option<u64>, at: u64) -> bool {
+        match self {
+            Condition::Always => true,
+            Condition::Nx => current.is_none(),
+            Condition::Xx => current.is_some(),
+            Condition::Gt => current.is_some_and(|current| at > current),
+            Condition::Lt => current.is_none_or(|current| at < current),
+        }
+    }
+}
+
+// EXPIRE key seconds [NX | XX | GT | LT]
+// PEXPIRE key milliseconds [NX | XX | GT | LT]
+// EXPIREAT key unix-time-seconds [NX | XX | GT | LT]
+// PEXPIREAT key unix-time-milliseconds [NX | XX | GT | LT]
+// XX may go along with GT or LT, the TTL is set when all of them allow it.
+pub fn expire(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let time = parse_number(&command[2])?;
+    let conditions = command[3..].iter()
+        .map(|arg| Condition::parse(arg).ok_or_else(|| RESPError::UnsupportedOption(arg.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let has = |condition| conditions.contains(&condition);
+    if has(Condition::Nx) && (has(Condition::Xx) || has(Condition::Gt) || has(Condition::Lt)) {
+        return Err(RESPError::NxAndXxGtLt);
+    }
+    if has(Condition::Gt) && has(Condition::Lt) {
+        return Err(RESPError::GtAndLt);
+    }
+    let invalid = || RESPError::InvalidExpireTime(command[0].to_string());
+    let at = match command[0].as_str() {
+        "EXPIRE" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms() as i64)),
+        "PEXPIRE" => time.checked_add(now_ms() as i64),
+        "EXPIREAT" => time.checked_mul(1000),
+        _ => Some(time)
+    }.ok_or_else(invalid)?;
+
+    let mut db = shared.db.lock();
+    if !db.contains_key(key) {
+        return Ok(RESPValue::Number(0));
+    }
+    let current = db.expire_time(key);
+    if !conditions.iter().all(|condition| condition.allows(current, at.max(0) as u64)) {
+        return Ok(RESPValue::Number(0));
+    }
+    // A time in the past deletes the key right away.
+    if at <= now_ms() as i64 {
+        let value = db.remove(key).unwrap();
+        drop(db);
+        lazyfree::free(value, shared.config.get_bool("lazyfree-lazy-expire"), shared);
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+    } else {
+        db.set_expire(key, at as u64);
+        drop(db);
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "expire", key, 0);
+    }
+    Ok(RESPValue::Number(1))
+}
+
+// TTL key
+// PTTL key
+// EXPIRETIME key
+// PEXPIRETIME key
+pub fn ttl(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    if !db.contains_key(command[1].as_str()) {
+        return Ok(RESPValue::Number(-2));
+    }
+    let at = match db.expire_time(&command[1]) {
+        Some(at) => at,
+        None => return Ok(RESPValue::Number(-1))
+    };
+
+    let ttl = at.saturating_sub(now_ms());
+    Ok(RESPValue::Number(match command[0].as_str() {
+        "TTL" => (ttl + 500) / 1000,
+        "PTTL" => ttl,
+        "EXPIRETIME" => at / 1000,
+        _ => at
+    } as i64))
+}
+
+// Whether `default-ttl` or `max-ttl` is set, for limit_ttls to be called.
+pub fn limiting_ttls() -> bool {
+    DEFAULT_TTL.load(Ordering::Relaxed) != 0 || MAX_TTL.load(Ordering::Relaxed) != 0
+}
+
+// Gives the keys a write command accessed that have no TTL the one of `default-ttl`, and cuts the TTLs
+// longer than `max-ttl` (no TTL included) down to it, so nothing lives forever when serving as a
+// cache, PERSIST only restarting the TTL. Returns the keys whose TTL it set, with when they expire.
+pub fn limit_ttls(keys: &[String], shared: &SharedState) -> Vec<(String, u64)> {
+    let (default_ttl, max_ttl) = (DEFAULT_TTL.load(Ordering::Relaxed), MAX_TTL.load(Ordering::Relaxed));
+    let now = now_ms();
+    let mut limited = vec![];
+    let mut db = shared.db.lock();
+    for key in keys {
+        if !db.contains_key(key) {
+            continue;
+        }
+        let at = match db.expire_time(key) {
+            None if default_ttl != 0 => now + if max_ttl == 0 { default_ttl } else { default_ttl.min(max_ttl) },
+            None => now + max_ttl,
+            Some(at) if max_ttl != 0 && at > now + max_ttl => now + max_ttl,
+            Some(_) => continue
+        };
+        db.set_expire(key, at);
+        limited.push((key.to_owned(), at));
+    }
+    drop(db);
+    for (key, _) in &limited {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "expire", key, 0);
+    }
+    limited
+}
+
+// PERSIST key
+pub fn persist(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let persisted = shared.db.lock().persist(&command[1]);
+    if persisted {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "persist", &command[1], 0);
+    }
+    Ok(RESPValue::Number(persisted as i64))
+}
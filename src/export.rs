@@ -0,0 +1,302 @@
+// Exporting the keyspace to JSON or CSV, for `bast --export`, and importing it back with `bast
+// --import`, for analytics pipelines and migrations where the snapshot formats are overkill. Both
+// work on the dataset of the configuration (the snapshot, or the append only file with appendonly)
+// rather than serving anything.
+//
+// The JSON format has a line per key, like:
+//   {"key":"k","type":"string","expires_at":null,"value":"v"}
+//   {"key":"h","type":"hash","expires_at":1700000000000,"value":{"f":"v"},"field_expires_at":{"f":1700000000000}}
+// `expires_at` is the unix time in milliseconds the key expires at, null when it has no TTL, and
+// `field_expires_at` (only there when a field has a TTL) the ones of the fields of a hash. By type,
+// the value is:
+// - string: a string.
+// - list: an array of the elements.
+// - hash: an object of the fields to their values.
+// - zset: an object of the members to their scores (a string for infinities, like "inf").
+// - stream: an array of the entries, like {"id":"1-0","fields":["f","v"]} (without consumer groups).
+// - MBbloom-- and MBbloomCF (Bloom and cuckoo filters): the DUMP payload of the filter, in hex.
+// - ReJSON-RL (JSON documents): the document.
+// The CSV format has the columns key,type,expires_at,value, with the value of a string as is and the
+// JSON of the others, an empty expires_at meaning no TTL. It has no field TTLs.
+//
+// Values are exported as UTF-8, invalid sequences being replaced. Importing replaces the keys that
+// exist already, skips the ones that expired since, and saves the snapshot (so it's refused with
+// appendonly, as the append only file would be loaded instead).
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use serde_json::{json, Map, Number, Value as Json};
+
+use crate::db::{now_ms, Value};
+use crate::glob::glob_match;
+use crate::hash::Hash;
+use crate::list::List;
+use crate::sorted_set::SortedSet;
+use crate::stream::{Stream, StreamId};
+use crate::{dump, rdb, replay_append_only_file, snapshot, SharedState};
+
+const CSV_HEADER: &str = "key,type,expires_at,value";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unsupported format '{}', it's json or csv", format))
+        }
+    }
+}
+
+// Loads the dataset of the configuration, like on startup.
+fn load(shared: &SharedState) -> Result<(), String> {
+    if shared.config.get_bool("appendonly") {
+        replay_append_only_file(shared).map_err(|e| format!("Failed loading {}: {}", shared.config.get("appendfilename"), e))?;
+    } else {
+        snapshot::load(shared).map_err(|e| format!("Failed loading {}: {}", shared.config.get("dbfilename"), e))?;
+    }
+    Ok(())
+}
+
+fn text(bytes: &[u8]) -> Json {
+    Json::String(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// The type and JSON of a value, along with the expire times of the fields of a hash.
+fn to_json(value: &Value) -> (&'static str, Json, Option<Json>) {
+    match value {
+        Value::String(_) | Value::CompressedString(_) => ("string", text(&value.as_string().unwrap()), None),
+        Value::List(list) => ("list", Json::Array(list.iter().map(text).collect()), None),
+        Value::Hash(hash) => {
+            let fields = hash.iter().map(|(field, value, _)| (field.to_owned(), text(value))).collect::<Map<_, _>>();
+            let expires = hash.iter().filter_map(|(field, _, expire)| Some((field.to_owned(), Json::from(expire?)))).collect::<Map<_, _>>();
+            ("hash", Json::Object(fields), (!expires.is_empty()).then_some(Json::Object(expires)))
+        },
+        Value::SortedSet(set) => {
+            let scores = set.iter().map(|(member, score)| {
+                let score = Number::from_f64(score).map_or_else(|| Json::String(score.to_string()), Json::Number);
+                (member.to_owned(), score)
+            });
+            ("zset", Json::Object(scores.collect()), None)
+        },
+        Value::Stream(stream) => {
+            let entries = stream.range(StreamId { ms: 0, seq: 0 }, StreamId { ms: u64::MAX, seq: u64::MAX }, None, false).into_iter()
+                .map(|(id, fields)| json!({ "id": id.to_string(), "fields": fields.into_iter().flat_map(|(field, value)| [field, value]).collect::<Vec<_>>() }));
+            ("stream", Json::Array(entries.collect()), None)
+        },
+        Value::Bloom(_) | Value::Cuckoo(_) => (value.type_name(), Json::String(dump::payload(value)), None),
+        Value::Json(json) => (value.type_name(), json.clone(), None)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Writes the keys matching the pattern (all of them without one), returning how many were written.
+pub fn export(out: &mut dyn Write, pattern: Option<&str>, format: Format, shared: &SharedState) -> Result<usize, String> {
+    load(shared)?;
+    let write_error = |e: io::Error| format!("Failed writing the export: {}", e);
+    if format == Format::Csv {
+        writeln!(out, "{}", CSV_HEADER).map_err(write_error)?;
+    }
+
+    let db = shared.db.lock();
+    let mut entries: Vec<_> = db.iter()
+        .filter(|(key, _)| !db.is_expired(key) && pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), key.as_bytes())))
+        .collect();
+    // Sorted, so exports of the same dataset are the same.
+    entries.sort_unstable_by_key(|(key, _)| *key);
+    for (key, value) in &entries {
+        let expires_at = db.expire_time(key);
+        let (kind, value, field_expires) = to_json(value);
+        match format {
+            // Written field by field, for them to be in the documented order.
+            Format::Json => {
+                let field_expires = field_expires.map_or_else(String::new, |expires| format!(",\"field_expires_at\":{}", expires));
+                writeln!(out, "{{\"key\":{},\"type\":\"{}\",\"expires_at\":{},\"value\":{}{}}}",
+                    Json::from(key.as_str()), kind, Json::from(expires_at), value, field_expires)
+            },
+            Format::Csv => {
+                let value = match value {
+                    Json::String(value) => value,
+                    value => value.to_string()
+                };
+                let expires_at = expires_at.map_or_else(String::new, |at| at.to_string());
+                writeln!(out, "{},{},{},{}", csv_field(key), kind, expires_at, csv_field(&value))
+            }
+        }.map_err(write_error)?;
+    }
+    out.flush().map_err(write_error)?;
+    Ok(entries.len())
+}
+
+fn strings(value: &Json) -> Option<Vec<String>> {
+    value.as_array()?.iter().map(|element| element.as_str().map(str::to_owned)).collect()
+}
+
+// The value of a record of the type, and the expire times of its fields for hashes.
+fn from_json(kind: &str, value: Json, field_expires: Option<&Json>) -> Result<Value, String> {
+    let invalid = || format!("invalid {} value", kind);
+    match kind {
+        "string" => Ok(Value::string(value.as_str().ok_or_else(invalid)?.to_owned())),
+        "list" => Ok(Value::List(strings(&value).ok_or_else(invalid)?.into_iter().map(String::into_bytes).collect::<List>())),
+        "hash" => {
+            let mut hash = Hash::default();
+            for (field, value) in value.as_object().ok_or_else(invalid)? {
+                hash.insert(field.clone(), value.as_str().ok_or_else(invalid)?.to_owned().into());
+            }
+            for (field, at) in field_expires.map(|expires| expires.as_object().ok_or_else(invalid)).transpose()?.into_iter().flatten() {
+                hash.set_expire(field, at.as_u64().ok_or_else(invalid)?);
+            }
+            Ok(Value::Hash(hash))
+        },
+        "zset" => {
+            let mut set = SortedSet::default();
+            for (member, score) in value.as_object().ok_or_else(invalid)? {
+                let score = match score {
+                    Json::String(score) => score.parse().ok(),
+                    score => score.as_f64()
+                };
+                set.insert(member.clone(), score.filter(|score: &f64| !score.is_nan()).ok_or_else(invalid)?);
+            }
+            Ok(Value::SortedSet(set))
+        },
+        "stream" => {
+            let entries = value.as_array().ok_or_else(invalid)?.iter().map(|entry| {
+                let id = entry["id"].as_str().and_then(|id| StreamId::parse(id, 0).ok())?;
+                let fields = strings(&entry["fields"]).filter(|fields| fields.len() % 2 == 0)?;
+                let fields = fields.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+                Some((id, fields))
+            }).collect::<Option<Vec<_>>>().ok_or_else(invalid)?;
+            Ok(Value::Stream(Stream::from_entries(entries)))
+        },
+        "ReJSON-RL" => Ok(Value::Json(value.clone())),
+        "MBbloom--" | "MBbloomCF" => {
+            let payload = value.as_str().and_then(dump::from_hex).filter(|payload| rdb::verify_dump(payload)).ok_or_else(invalid)?;
+            rdb::undump(&payload).ok().filter(|value| value.type_name() == kind).ok_or_else(invalid)
+        },
+        kind => Err(format!("unsupported type '{}'", kind))
+    }
+}
+
+// Reads a record of CSV, None at the end of the input. Quoted fields may span lines.
+fn read_csv_record(input: &mut impl BufRead) -> io::Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().collect::<Vec<_>>().into_iter().peekable();
+    loop {
+        let Some(c) = chars.next() else {
+            if !quoted {
+                break;
+            }
+            // The quoted field goes on in the next line.
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unclosed quote"));
+            }
+            chars = line.chars().collect::<Vec<_>>().into_iter().peekable();
+            continue;
+        };
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            '\r' | '\n' if !quoted => {},
+            c => field.push(c)
+        }
+    }
+    Ok(Some(fields))
+}
+
+// Loads the records of the file ("-" being stdin) into the dataset and saves the snapshot, returning
+// how many keys were imported.
+pub fn import(path: &str, format: Format, shared: &SharedState) -> Result<usize, String> {
+    if shared.config.get_bool("appendonly") {
+        return Err(String::from("Importing saves the snapshot, which isn't loaded with appendonly yes"));
+    }
+    let file: Box<dyn Read> = match path {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(std::fs::File::open(path).map_err(|e| format!("Failed reading {}: {}", path, e))?)
+    };
+    let mut input = BufReader::new(file);
+    load(shared)?;
+
+    let now = now_ms();
+    let (mut record, mut imported) = (0, 0);
+    let mut db = shared.db.lock();
+    loop {
+        record += 1;
+        let read_error = |e: io::Error| format!("Failed reading {}: {}", path, e);
+        let invalid = |e: String| format!("Invalid record {} of {}: {}", record, path, e);
+        let (key, kind, expires_at, value, field_expires) = match format {
+            Format::Json => {
+                let mut line = String::new();
+                if input.read_line(&mut line).map_err(read_error)? == 0 {
+                    break;
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut parsed: Json = serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+                let key = parsed["key"].as_str().ok_or_else(|| invalid(String::from("no key")))?.to_owned();
+                let kind = parsed["type"].as_str().ok_or_else(|| invalid(String::from("no type")))?.to_owned();
+                let expires_at = match &parsed["expires_at"] {
+                    Json::Null => None,
+                    at => Some(at.as_u64().ok_or_else(|| invalid(String::from("invalid expires_at")))?)
+                };
+                let field_expires = parsed.get("field_expires_at").cloned();
+                (key, kind, expires_at, parsed["value"].take(), field_expires)
+            },
+            Format::Csv => {
+                let Some(fields) = read_csv_record(&mut input).map_err(read_error)? else {
+                    break;
+                };
+                if record == 1 && fields.join(",") == CSV_HEADER {
+                    continue;
+                }
+                let [key, kind, expires_at, value] = <[String; 4]>::try_from(fields)
+                    .map_err(|_| invalid(String::from("there must be 4 columns")))?;
+                let expires_at = match expires_at.as_str() {
+                    "" => None,
+                    at => Some(at.parse().map_err(|_| invalid(String::from("invalid expires_at")))?)
+                };
+                let value = match kind.as_str() {
+                    "string" => Json::String(value),
+                    _ => serde_json::from_str(&value).map_err(|e| invalid(e.to_string()))?
+                };
+                (key, kind, expires_at, value, None)
+            }
+        };
+        let value = from_json(&kind, value, field_expires.as_ref()).map_err(invalid)?;
+        if expires_at.is_some_and(|at| at <= now) {
+            continue;
+        }
+        db.set(key.clone(), value);
+        if let Some(at) = expires_at {
+            db.set_expire(&key, at);
+        }
+        imported += 1;
+    }
+    drop(db);
+
+    snapshot::save(shared).map_err(|e| format!("Failed saving {}: {}", shared.config.get("dbfilename"), e))?;
+    Ok(imported)
+}
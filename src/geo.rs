@@ -0,0 +1,445 @@
+// Geospatial indexes, stored as sorted sets scored by the 52 bit geohash of each member's position,
+// the same way Redis stores them.
+
+use std::cmp::Ordering;
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC, NOTIFY_ZSET};
+use crate::sorted_set::SortedSet;
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+// Geohashes can't represent the poles, so latitudes are limited like in EPSG:900913.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+// Bits per coordinate, interleaved into a 52 bit hash.
+const STEP: u32 = 26;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+
+fn encode(lon: f64, lat: f64) -> u64 {
+    let lat_offset = ((lat - LAT_MIN) / (LAT_MAX - LAT_MIN) * (1u64 << STEP) as f64) as u64;
+    let lon_offset = ((lon - LON_MIN) / (LON_MAX - LON_MIN) * (1u64 << STEP) as f64) as u64;
+
+    // Latitude bits go in the even positions and longitude bits in the odd ones.
+    let mut hash = 0;
+    for i in 0..STEP {
+        hash |= ((lat_offset >> i) & 1) << (2 * i);
+        hash |= ((lon_offset >> i) & 1) << (2 * i + 1);
+    }
+    hash
+}
+
+// The center of the area a hash represents.
+fn decode(hash: u64) -> (f64, f64) {
+    let (mut lat_offset, mut lon_offset) = (0u64, 0u64);
+    for i in 0..STEP {
+        lat_offset |= ((hash >> (2 * i)) & 1) << i;
+        lon_offset |= ((hash >> (2 * i + 1)) & 1) << i;
+    }
+
+    let cells = (1u64 << STEP) as f64;
+    let lat_min = LAT_MIN + (lat_offset as f64 / cells) * (LAT_MAX - LAT_MIN);
+    let lat_max = LAT_MIN + ((lat_offset + 1) as f64 / cells) * (LAT_MAX - LAT_MIN);
+    let lon_min = LON_MIN + (lon_offset as f64 / cells) * (LON_MAX - LON_MIN);
+    let lon_max = LON_MIN + ((lon_offset + 1) as f64 / cells) * (LON_MAX - LON_MIN);
+    (((lon_min + lon_max) / 2.0).clamp(LON_MIN, LON_MAX), ((lat_min + lat_max) / 2.0).clamp(LAT_MIN, LAT_MAX))
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+// The haversine distance in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2.to_radians() - lat1.to_radians()) / 2.0).sin();
+    let a = u * u + lat1.to_radians().cos() * lat2.to_radians().cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn parse_coordinate(arg: &str) -> Result<f64, RESPError> {
+    arg.parse::<f64>().ok().filter(|value| value.is_finite()).ok_or(RESPError::NotAFloat)
+}
+
+fn parse_position(lon: &str, lat: &str) -> Result<(f64, f64), RESPError> {
+    let (lon, lat) = (parse_coordinate(lon)?, parse_coordinate(lat)?);
+    if !(LON_MIN..=LON_MAX).contains(&lon) || !(LAT_MIN..=LAT_MAX).contains(&lat) {
+        return Err(RESPError::InvalidGeoPosition(lon, lat));
+    }
+    Ok((lon, lat))
+}
+
+// Meters per unit.
+fn parse_unit(arg: &str) -> Result<f64, RESPError> {
+    match arg.to_ascii_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(RESPError::UnsupportedGeoUnit)
+    }
+}
+
+fn position_of(set: &SortedSet, member: &str) -> Option<(f64, f64)> {
+    set.score(member).map(|score| decode(score as u64))
+}
+
+fn position_reply((lon, lat): (f64, f64)) -> RESPValue {
+    RESPValue::Array(vec![RESPValue::BlobString(lon.to_string().into()), RESPValue::BlobString(lat.to_string().into())])
+}
+
+// GEOADD key [NX|XX] [CH] longitude latitude member [longitude latitude member ...]
+pub fn geoadd(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut i = 2;
+    while let Some(option) = command.get(i).map(|arg| arg.to_ascii_uppercase()) {
+        match option.as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => ch = true,
+            _ => break
+        }
+        i += 1;
+    }
+    if nx && xx {
+        return Err(RESPError::NxAndXx);
+    }
+
+    let triplets = &command[i..];
+    if triplets.is_empty() || !triplets.len().is_multiple_of(3) {
+        return Err(RESPError::SyntaxError);
+    }
+    let members = triplets.chunks(3)
+        .map(|triplet| parse_position(&triplet[0], &triplet[1]).map(|(lon, lat)| (triplet[2].to_string(), encode(lon, lat))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (added, changed) = {
+        let mut db = shared.db.lock();
+        db.get(key).map(Value::as_sorted_set).transpose()?;
+        if xx && db.get(key).is_none() {
+            return Ok(RESPValue::Number(0));
+        }
+
+        let set = db.get_or_insert_with(key, || Value::SortedSet(SortedSet::default())).as_sorted_set_mut()?;
+        let (mut added, mut changed) = (0, 0);
+        for (member, hash) in members {
+            let old = set.score(&member);
+            if (nx && old.is_some()) || (xx && old.is_none()) {
+                continue;
+            }
+            match old {
+                None => added += 1,
+                Some(old) if old != hash as f64 => changed += 1,
+                Some(_) => continue
+            }
+            set.insert(member, hash as f64);
+        }
+
+        // The set may have been created only to stay empty.
+        if set.len() == 0 {
+            db.remove(key);
+        }
+        (added, changed)
+    };
+
+    if added + changed > 0 {
+        notify_keyspace_event(shared, NOTIFY_ZSET, "zadd", key, 0);
+    }
+    Ok(RESPValue::Number(if ch { added + changed } else { added }))
+}
+
+// GEOPOS key [member [member ...]]
+pub fn geopos(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let set = db.get(command[1].as_str()).map(Value::as_sorted_set).transpose()?;
+
+    let positions = command[2..].iter()
+        .map(|member| set.and_then(|set| position_of(set, member)).map_or(RESPValue::Null, position_reply))
+        .collect();
+    Ok(RESPValue::Array(positions))
+}
+
+// GEODIST key member1 member2 [M|KM|FT|MI]
+pub fn geodist(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let unit = match command.get(4) {
+        Some(unit) if command.len() == 5 => parse_unit(unit)?,
+        Some(_) => return Err(RESPError::SyntaxError),
+        None => 1.0
+    };
+
+    let db = shared.db.lock();
+    let set = match db.get(command[1].as_str()) {
+        Some(value) => value.as_sorted_set()?,
+        None => return Ok(RESPValue::Null)
+    };
+
+    match (position_of(set, &command[2]), position_of(set, &command[3])) {
+        (Some((lon1, lat1)), Some((lon2, lat2))) => {
+            let meters = distance(lon1, lat1, lon2, lat2);
+            Ok(RESPValue::BlobString(format!("{:.4}", meters / unit).into()))
+        },
+        _ => Ok(RESPValue::Null)
+    }
+}
+
+enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+struct GeoSearch {
+    from_member: Option<String>,
+    from_position: Option<(f64, f64)>,
+    shape: Option<Shape>,
+    // Meters per unit of the shape, which distances are replied in.
+    unit: f64,
+    order: Option<Ordering>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+struct GeoMatch {
+    member: String,
+    hash: u64,
+    distance: f64,
+    position: (f64, f64),
+}
+
+// Parses the search arguments of GEOSEARCH and GEOSEARCHSTORE, the latter doesn't support the WITH*
+// options but can store distances instead of hashes.
+fn parse_search(args: &[Arg], store: bool) -> Result<GeoSearch, RESPError> {
+    let mut search = GeoSearch {
+        from_member: None,
+        from_position: None,
+        shape: None,
+        unit: 1.0,
+        order: None,
+        count: None,
+        any: false,
+        with_coord: false,
+        with_dist: false,
+        with_hash: false,
+        store_dist: false,
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = |offset: usize| args.get(i + offset).ok_or(RESPError::SyntaxError);
+        let option = args[i].to_ascii_uppercase();
+        match option.as_str() {
+            "FROMMEMBER" => {
+                if search.from_member.is_some() || search.from_position.is_some() {
+                    return Err(RESPError::GeoSearchFrom);
+                }
+                search.from_member = Some(arg(1)?.to_string());
+                i += 2;
+            },
+            "FROMLONLAT" => {
+                if search.from_member.is_some() || search.from_position.is_some() {
+                    return Err(RESPError::GeoSearchFrom);
+                }
+                search.from_position = Some(parse_position(arg(1)?, arg(2)?)?);
+                i += 3;
+            },
+            "BYRADIUS" => {
+                if search.shape.is_some() {
+                    return Err(RESPError::GeoSearchBy);
+                }
+                let radius = parse_coordinate(arg(1)?)?;
+                if radius < 0.0 {
+                    return Err(RESPError::NegativeRadius);
+                }
+                search.unit = parse_unit(arg(2)?)?;
+                search.shape = Some(Shape::Radius(radius * search.unit));
+                i += 3;
+            },
+            "BYBOX" => {
+                if search.shape.is_some() {
+                    return Err(RESPError::GeoSearchBy);
+                }
+                let (width, height) = (parse_coordinate(arg(1)?)?, parse_coordinate(arg(2)?)?);
+                if width < 0.0 || height < 0.0 {
+                    return Err(RESPError::NegativeRadius);
+                }
+                search.unit = parse_unit(arg(3)?)?;
+                search.shape = Some(Shape::Box(width * search.unit, height * search.unit));
+                i += 4;
+            },
+            "ASC" => {
+                search.order = Some(Ordering::Less);
+                i += 1;
+            },
+            "DESC" => {
+                search.order = Some(Ordering::Greater);
+                i += 1;
+            },
+            "COUNT" => {
+                let count = parse_number(arg(1)?)?;
+                if count <= 0 {
+                    return Err(RESPError::NonPositiveCount);
+                }
+                search.count = Some(count as usize);
+                i += 2;
+                if args.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("ANY")) {
+                    search.any = true;
+                    i += 1;
+                }
+            },
+            "ANY" => return Err(RESPError::AnyWithoutCount),
+            "WITHCOORD" if !store => {
+                search.with_coord = true;
+                i += 1;
+            },
+            "WITHDIST" if !store => {
+                search.with_dist = true;
+                i += 1;
+            },
+            "WITHHASH" if !store => {
+                search.with_hash = true;
+                i += 1;
+            },
+            "STOREDIST" if store => {
+                search.store_dist = true;
+                i += 1;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+
+    if search.from_member.is_none() && search.from_position.is_none() {
+        return Err(RESPError::GeoSearchFrom);
+    }
+    if search.shape.is_none() {
+        return Err(RESPError::GeoSearchBy);
+    }
+    Ok(search)
+}
+
+// Scans the whole set for the members within the shape around the search center.
+fn search_members(set: &SortedSet, search: &GeoSearch) -> Result<Vec<GeoMatch>, RESPError> {
+    let (lon, lat) = match &search.from_member {
+        Some(member) => position_of(set, member).ok_or(RESPError::UndecodableMember)?,
+        None => search.from_position.unwrap()
+    };
+
+    let mut matches = vec![];
+    for (member, score) in set.iter() {
+        let hash = score as u64;
+        let (member_lon, member_lat) = decode(hash);
+
+        let within = match search.shape.as_ref().unwrap() {
+            Shape::Radius(radius) => {
+                let distance = distance(lon, lat, member_lon, member_lat);
+                (distance <= *radius).then_some(distance)
+            },
+            Shape::Box(width, height) => {
+                let inside = lat_distance(member_lat, lat) <= height / 2.0
+                    && distance(member_lon, member_lat, lon, member_lat) <= width / 2.0;
+                inside.then(|| distance(lon, lat, member_lon, member_lat))
+            }
+        };
+
+        if let Some(distance) = within {
+            matches.push(GeoMatch { member: member.to_owned(), hash, distance, position: (member_lon, member_lat) });
+            // With ANY the search stops as soon as enough matches were found.
+            if search.any && Some(matches.len()) == search.count {
+                break;
+            }
+        }
+    }
+
+    // Matches are sorted when a count is given without ANY, so the closest ones are returned.
+    let order = match search.order {
+        None if search.count.is_some() && !search.any => Some(Ordering::Less),
+        order => order
+    };
+    if let Some(order) = order {
+        matches.sort_by(|a, b| {
+            let ordering = a.distance.total_cmp(&b.distance);
+            if order == Ordering::Less { ordering } else { ordering.reverse() }
+        });
+    }
+    if let Some(count) = search.count {
+        matches.truncate(count);
+    }
+    Ok(matches)
+}
+
+// GEOSEARCH key FROMMEMBER member|FROMLONLAT longitude latitude BYRADIUS radius unit|BYBOX width
+//     height unit [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+pub fn geosearch(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let search = parse_search(&command[2..], false)?;
+
+    let db = shared.db.lock();
+    let set = match db.get(command[1].as_str()) {
+        Some(value) => value.as_sorted_set()?,
+        None => return Ok(RESPValue::Array(vec![]))
+    };
+
+    let matches = search_members(set, &search)?;
+    let replies = matches.into_iter().map(|found| {
+        if !search.with_dist && !search.with_hash && !search.with_coord {
+            return RESPValue::BlobString(found.member.into());
+        }
+
+        let mut reply = vec![RESPValue::BlobString(found.member.into())];
+        if search.with_dist {
+            reply.push(RESPValue::BlobString(format!("{:.4}", found.distance / search.unit).into()));
+        }
+        if search.with_hash {
+            reply.push(RESPValue::Number(found.hash as i64));
+        }
+        if search.with_coord {
+            reply.push(position_reply(found.position));
+        }
+        RESPValue::Array(reply)
+    }).collect();
+    Ok(RESPValue::Array(replies))
+}
+
+// GEOSEARCHSTORE destination source <GEOSEARCH options> [STOREDIST]
+pub fn geosearchstore(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let destination = command[1].as_str();
+    let search = parse_search(&command[3..], true)?;
+
+    let (stored, deleted) = {
+        let mut db = shared.db.lock();
+        let matches = match db.get(command[2].as_str()) {
+            Some(value) => search_members(value.as_sorted_set()?, &search)?,
+            None => vec![]
+        };
+
+        let mut set = SortedSet::default();
+        for found in matches {
+            let score = if search.store_dist { found.distance / search.unit } else { found.hash as f64 };
+            set.insert(found.member, score);
+        }
+
+        let stored = set.len();
+        if stored == 0 {
+            (stored, lazyfree::free_replaced(db.remove(destination), shared))
+        } else {
+            lazyfree::free_replaced(db.set(destination.to_owned(), Value::SortedSet(set)), shared);
+            (stored, false)
+        }
+    };
+
+    if stored > 0 {
+        notify_keyspace_event(shared, NOTIFY_ZSET, "geosearchstore", destination, 0);
+    } else if deleted {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", destination, 0);
+    }
+    Ok(RESPValue::Number(stored as i64))
+}
@@ -0,0 +1,76 @@
+// Redis style glob matching, supporting `*`, `?`, `[...]` character classes (with `^` negation and
+// `a-z` ranges) and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len()).any(|start| glob_match(&pattern[p + 1..], &string[start..]));
+            },
+            b'?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                s += 1;
+            },
+            b'[' => {
+                if s >= string.len() {
+                    return false;
+                }
+
+                p += 1;
+                let negate = p < pattern.len() && pattern[p] == b'^';
+                if negate {
+                    p += 1;
+                }
+
+                let mut matched = false;
+                while p < pattern.len() && pattern[p] != b']' {
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= pattern[p] == string[s];
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+                        let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        matched |= string[s] >= start && string[s] <= end;
+                        p += 2;
+                    } else {
+                        matched |= pattern[p] == string[s];
+                    }
+                    p += 1;
+                }
+
+                if matched == negate {
+                    return false;
+                }
+                s += 1;
+            },
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if s >= string.len() || pattern[p] != string[s] {
+                    return false;
+                }
+                s += 1;
+            },
+            c => {
+                if s >= string.len() || c != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+
+    s == string.len()
+}
@@ -0,0 +1,296 @@
+// The gRPC service, built with the grpc feature. With `grpc-port` set, every address of `bind` serves
+// the Store service of proto/bast.proto: Get, Set and Del run GET, SET (PSETEX with a TTL) and DEL,
+// Scan lists the keys a page at a time, and Subscribe streams the messages of SUBSCRIBE and
+// PSUBSCRIBE until it's cancelled. Every call runs as a client of its own, authenticated by the
+// "user" and "password" metadata when the server requires a password, and error replies become
+// statuses: UNAUTHENTICATED, PERMISSION_DENIED, INVALID_ARGUMENT for the ERR ones and
+// FAILED_PRECONDITION for the rest.
+
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use futures::Stream;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tonic::{Request, Response, Status};
+
+use crate::arg::Arg;
+use crate::glob::glob_match;
+use crate::{check_permissions, connect, denied_by_protected_mode, disconnect, execute_command, logging, Client, RESPError, RESPValue, SharedState};
+
+include!(concat!(env!("OUT_DIR"), "/bast.Store.rs"));
+
+use store_server::{Store, StoreServer};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetResponse {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DelRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub keys: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DelResponse {
+    #[prost(int64, tag = "1")]
+    pub deleted: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanRequest {
+    #[prost(string, tag = "1")]
+    pub cursor: String,
+    #[prost(string, tag = "2")]
+    pub r#match: String,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanResponse {
+    #[prost(string, tag = "1")]
+    pub cursor: String,
+    #[prost(string, repeated, tag = "2")]
+    pub keys: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub channels: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub patterns: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub channel: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+    #[prost(string, tag = "3")]
+    pub pattern: String,
+}
+
+fn to_status(e: &[u8]) -> Status {
+    let message = String::from_utf8_lossy(e).into_owned();
+    match message.split(' ').next().unwrap_or_default() {
+        "NOAUTH" | "WRONGPASS" => Status::unauthenticated(message),
+        "NOPERM" => Status::permission_denied(message),
+        "ERR" => Status::invalid_argument(message),
+        _ => Status::failed_precondition(message)
+    }
+}
+
+// The client a call runs as, disconnected once the call ends (for Subscribe, once the stream of
+// messages is dropped).
+struct Session {
+    client: Option<Client>,
+    shared: Arc<SharedState>,
+}
+
+impl Session {
+    async fn open<T>(request: &Request<T>, push_sender: UnboundedSender<RESPValue>, shared: &Arc<SharedState>) -> Result<Self, Status> {
+        let addr = request.remote_addr().map_or_else(String::new, |addr| addr.to_string());
+        if denied_by_protected_mode(Some(&addr), shared) {
+            logging::log(shared, "warning", format!("Denied the gRPC call from {} in protected mode", addr));
+            return Err(Status::permission_denied(RESPError::ProtectedMode.to_string()));
+        }
+        let laddr = request.local_addr().map_or_else(String::new, |laddr| laddr.to_string());
+        let id = shared.next_client_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut session = Self { client: Some(connect(id, addr, laddr, push_sender, shared)), shared: shared.clone() };
+
+        let metadata = |key| request.metadata().get(key).and_then(|value| value.to_str().ok()).map(str::to_owned);
+        if let Some(password) = metadata("password") {
+            let mut auth = vec![Arg::from("AUTH")];
+            auth.extend(metadata("user").map(Arg::from));
+            auth.push(Arg::from(password));
+            session.run(auth).await?;
+        }
+        Ok(session)
+    }
+
+    async fn run(&mut self, command: Vec<Arg>) -> Result<RESPValue, Status> {
+        logging::command(&self.shared, &command);
+        let client = self.client.take().unwrap();
+        client.info.state.lock().unwrap().last_command = command[0].to_ascii_lowercase();
+        let (client, replies) = execute_command(command, client, &self.shared).await;
+        client.sync_info();
+        self.client = Some(client);
+        let reply = replies.into_iter().next().unwrap_or(RESPValue::Null);
+        match reply.as_simple_error() {
+            Some(e) => Err(to_status(e)),
+            None => Ok(reply)
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            disconnect(&mut client, &self.shared);
+        }
+    }
+}
+
+// The message of a push of a subscription, None for the other pushes (like invalidations).
+fn to_message(push: RESPValue) -> Option<Message> {
+    let RESPValue::Push(values) = push else {
+        return None;
+    };
+    let mut values = values.into_iter().map(|value| match value {
+        RESPValue::BlobString(s) => Some(s.to_vec()),
+        _ => None
+    });
+    let kind = values.next()??;
+    let text = |value: Vec<u8>| String::from_utf8(value).ok();
+    match kind.as_slice() {
+        b"message" => Some(Message { channel: text(values.next()??)?, payload: values.next()??, pattern: String::new() }),
+        b"pmessage" => Some(Message { pattern: text(values.next()??)?, channel: text(values.next()??)?, payload: values.next()?? }),
+        _ => None
+    }
+}
+
+struct Service {
+    shared: Arc<SharedState>,
+}
+
+#[tonic::async_trait]
+impl Store for Service {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let mut session = Session::open(&request, mpsc::unbounded_channel().0, &self.shared).await?;
+        let value = match session.run(vec![Arg::from("GET"), Arg::from(request.into_inner().key)]).await? {
+            RESPValue::BlobString(value) => Some(value.to_vec()),
+            _ => None
+        };
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let mut session = Session::open(&request, mpsc::unbounded_channel().0, &self.shared).await?;
+        let SetRequest { key, value, ttl_ms } = request.into_inner();
+        let (key, value) = (Arg::from(key), Arg::from_bytes(value));
+        let command = match ttl_ms {
+            0 => vec![Arg::from("SET"), key, value],
+            ttl_ms => vec![Arg::from("PSETEX"), key, Arg::from(ttl_ms.to_string()), value]
+        };
+        session.run(command).await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn del(&self, request: Request<DelRequest>) -> Result<Response<DelResponse>, Status> {
+        let mut session = Session::open(&request, mpsc::unbounded_channel().0, &self.shared).await?;
+        let mut command = vec![Arg::from("DEL")];
+        command.extend(request.into_inner().keys.into_iter().map(Arg::from));
+        let deleted = match session.run(command).await? {
+            RESPValue::Number(deleted) => deleted,
+            _ => 0
+        };
+        Ok(Response::new(DelResponse { deleted }))
+    }
+
+    // There's no SCAN command, so the keys are read from the keyspace directly, by users allowed to
+    // run RANDOMKEY and only the keys they can access. Keys are listed in order, the cursor being the
+    // last key of the previous page, so keys added or removed meanwhile don't make others missed.
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanResponse>, Status> {
+        let session = Session::open(&request, mpsc::unbounded_channel().0, &self.shared).await?;
+        let client = session.client.as_ref().unwrap();
+        check_permissions(&[Arg::from("RANDOMKEY")], client, &self.shared)
+            .map_err(|e| to_status(e.to_string().as_bytes()))?;
+        let ScanRequest { cursor, r#match, count } = request.into_inner();
+        let count = match count {
+            0 => DEFAULT_SCAN_COUNT,
+            count => count as usize
+        };
+
+        // The first keys after the cursor, in a heap of the largest one.
+        let keys: Vec<String> = {
+            let acl = self.shared.acl.lock().unwrap();
+            let user = acl.user(&client.user).ok_or_else(|| Status::unauthenticated(RESPError::NoAuth.to_string()))?;
+            let db = self.shared.db.lock();
+            let mut page = BinaryHeap::with_capacity(count + 1);
+            let keys = db.keys().filter(|key| key.as_str() > cursor.as_str() && !db.is_expired(key))
+                .filter(|key| r#match.is_empty() || glob_match(r#match.as_bytes(), key.as_bytes()))
+                .filter(|key| user.can_access(key));
+            for key in keys {
+                page.push(key);
+                if page.len() > count {
+                    page.pop();
+                }
+            }
+            page.into_sorted_vec().into_iter().cloned().collect()
+        };
+        // A full page may be the last one, the next one being empty then.
+        let cursor = if keys.len() == count { keys.last().cloned().unwrap_or_default() } else { String::new() };
+        Ok(Response::new(ScanResponse { cursor, keys }))
+    }
+
+    type SubscribeStream = std::pin::Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let (push_sender, pushes) = mpsc::unbounded_channel();
+        let mut session = Session::open(&request, push_sender, &self.shared).await?;
+        let SubscribeRequest { channels, patterns } = request.into_inner();
+        if channels.is_empty() && patterns.is_empty() {
+            return Err(Status::invalid_argument("there are no channels or patterns to subscribe to"));
+        }
+        if !channels.is_empty() {
+            session.run([String::from("SUBSCRIBE")].into_iter().chain(channels).map(Arg::from).collect()).await?;
+        }
+        if !patterns.is_empty() {
+            session.run([String::from("PSUBSCRIBE")].into_iter().chain(patterns).map(Arg::from).collect()).await?;
+        }
+
+        // The session lives as long as the stream, unsubscribing once it's dropped.
+        let messages = futures::stream::unfold((pushes, session), |(mut pushes, session): (UnboundedReceiver<RESPValue>, Session)| async move {
+            loop {
+                if let Some(message) = to_message(pushes.recv().await?) {
+                    return Some((Ok(message), (pushes, session)));
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(messages)))
+    }
+}
+
+// Serves the gRPC service on the listeners of `grpc-port`.
+pub async fn serve(listeners: Vec<TcpListener>, shared: Arc<SharedState>) {
+    let connections = futures::stream::unfold((listeners, shared.clone()), |(listeners, shared)| async {
+        let connection = crate::accept_tcp(&listeners).await;
+        match &connection {
+            Ok((_, addr)) => logging::log(&shared, "verbose", format!("New gRPC connection from {}", addr)),
+            // Skipped by the server, which goes on accepting.
+            Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+        }
+        Some((connection.map(|(socket, _)| socket), (listeners, shared)))
+    });
+    let service = StoreServer::new(Service { shared: shared.clone() });
+    if let Err(e) = tonic::transport::Server::builder().add_service(service).serve_with_incoming(connections).await {
+        logging::log(&shared, "warning", format!("Failed serving gRPC: {}", e));
+    }
+}
@@ -0,0 +1,560 @@
+// Hashes, mapping fields to values, where every field may have a TTL of its own like in Redis 7.4.
+// Expired fields are deleted the way expired keys are, when the hash is accessed (see
+// expire::expire_if_needed) and by the active expire cycle, which finds the hashes with expiring
+// fields through a timer wheel of the keyspace. A hash is deleted along with its last field.
+//
+// Small hashes are packed in a listpack, converting to a hash table once they have more fields than
+// hash-max-listpack-entries or a field or value longer than hash-max-listpack-value, like in Redis.
+
+use std::collections::{hash_map, BTreeSet, HashMap};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+
+use crate::arg::Arg;
+use crate::db::{now_ms, Db, Value};
+use crate::expire::Condition;
+use crate::listpack::{self, Listpack};
+use crate::memory::{sampled_size, ENTRY_OVERHEAD};
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC, NOTIFY_HASH};
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+// The latest unix time in milliseconds a field may expire at, as in Redis.
+pub const MAX_EXPIRE_TIME: u64 = (1 << 48) - 1;
+
+// The limits of the listpack encoding, past which hashes convert to a hash table. Set through the
+// config.
+pub static MAX_LISTPACK_ENTRIES: AtomicUsize = AtomicUsize::new(128);
+pub static MAX_LISTPACK_VALUE: AtomicUsize = AtomicUsize::new(64);
+
+#[derive(Clone)]
+pub struct Field {
+    value: Bytes,
+    expire: Option<u64>,
+}
+
+#[derive(Clone)]
+enum Encoding {
+    // Every field followed by its value and the time it expires at (as little endian bytes, empty
+    // when it has no TTL), in the order they were added.
+    Listpack(Listpack),
+    Hashtable(HashMap<String, Field>),
+}
+
+fn decode_expire(bytes: &[u8]) -> Option<u64> {
+    (!bytes.is_empty()).then(|| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The fields of a listpack along with their values, expire times and offsets.
+fn listpack_fields(listpack: &Listpack) -> impl Iterator<Item = (usize, &str, &[u8], Option<u64>)> {
+    let mut entries = listpack.entries();
+    std::iter::from_fn(move || {
+        let (offset, field) = entries.next()?;
+        let (_, value) = entries.next()?;
+        let (_, expire) = entries.next()?;
+        Some((offset, std::str::from_utf8(field).unwrap(), value, decode_expire(expire)))
+    })
+}
+
+fn find_field<'a>(listpack: &'a Listpack, field: &str) -> Option<(usize, &'a [u8], Option<u64>)> {
+    listpack_fields(listpack).find(|(_, other, _, _)| *other == field).map(|(offset, _, value, expire)| (offset, value, expire))
+}
+
+fn remove_at(listpack: &mut Listpack, offset: usize) {
+    // The value and the expire time move up to the offset of the removed field.
+    for _ in 0..3 {
+        listpack.remove(offset);
+    }
+}
+
+fn insert_at(listpack: &mut Listpack, offset: usize, field: &str, value: &[u8], expire: Option<u64>) {
+    let expire = expire.map(u64::to_le_bytes);
+    listpack.insert(offset, expire.as_ref().map_or(&[][..], |bytes| &bytes[..]));
+    listpack.insert(offset, value);
+    listpack.insert(offset, field.as_bytes());
+}
+
+#[derive(Clone)]
+pub struct Hash {
+    encoding: Encoding,
+    // The fields with a TTL, ordered by the unix time in milliseconds they expire at.
+    expires: BTreeSet<(u64, String)>,
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Self { encoding: Encoding::Listpack(Listpack::default()), expires: BTreeSet::new() }
+    }
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => listpack.len() / 3,
+            Encoding::Hashtable(fields) => fields.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // A listpack with field TTLs is reported as listpackex, as in Redis.
+    pub fn encoding(&self) -> &'static str {
+        match &self.encoding {
+            Encoding::Listpack(_) if self.expires.is_empty() => "listpack",
+            Encoding::Listpack(_) => "listpackex",
+            Encoding::Hashtable(_) => "hashtable",
+        }
+    }
+
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        let expires = self.expires.len() * (size_of::<(u64, String)>() + ENTRY_OVERHEAD);
+        size_of::<Self>() + expires + match &self.encoding {
+            Encoding::Listpack(listpack) => listpack.memory_usage(),
+            Encoding::Hashtable(fields) => sampled_size(self.len(), samples, fields.iter()
+                .map(|(field, entry)| size_of::<String>() + field.capacity() + size_of::<Field>() + entry.value.len() + ENTRY_OVERHEAD)),
+        }
+    }
+
+    pub fn get(&self, field: &str) -> Option<&[u8]> {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => find_field(listpack, field).map(|(_, value, _)| value),
+            Encoding::Hashtable(fields) => fields.get(field).map(|entry| &entry.value[..]),
+        }
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    // Sets the value of the field, discarding the TTL it had. Returns whether the field is new.
+    pub fn insert(&mut self, field: String, value: Bytes) -> bool {
+        let long = field.len().max(value.len()) > MAX_LISTPACK_VALUE.load(Ordering::Relaxed);
+        let old = match &mut self.encoding {
+            Encoding::Listpack(listpack) => match find_field(listpack, &field) {
+                Some((offset, _, expire)) => {
+                    remove_at(listpack, offset);
+                    insert_at(listpack, offset, &field, &value, None);
+                    Some(expire)
+                },
+                None => {
+                    insert_at(listpack, listpack.end(), &field, &value, None);
+                    None
+                }
+            },
+            Encoding::Hashtable(fields) => fields.insert(field.clone(), Field { value, expire: None }).map(|old| old.expire),
+        };
+        if let Some(Some(at)) = old {
+            self.expires.remove(&(at, field));
+        }
+
+        if matches!(self.encoding, Encoding::Listpack(_)) && (long || self.len() > MAX_LISTPACK_ENTRIES.load(Ordering::Relaxed)) {
+            self.convert_to_hashtable();
+        }
+        old.is_none()
+    }
+
+    fn convert_to_hashtable(&mut self) {
+        let fields = self.iter()
+            .map(|(field, value, expire)| (field.to_owned(), Field { value: Bytes::copy_from_slice(value), expire }))
+            .collect();
+        self.encoding = Encoding::Hashtable(fields);
+    }
+
+    // Removes the field from the encoding, returning the time it expired at, if it existed.
+    fn take(&mut self, field: &str) -> Option<Option<u64>> {
+        match &mut self.encoding {
+            Encoding::Listpack(listpack) => {
+                let (offset, _, expire) = find_field(listpack, field)?;
+                remove_at(listpack, offset);
+                Some(expire)
+            },
+            Encoding::Hashtable(fields) => fields.remove(field).map(|old| old.expire),
+        }
+    }
+
+    pub fn remove(&mut self, field: &str) -> bool {
+        match self.take(field) {
+            Some(expire) => {
+                if let Some(at) = expire {
+                    self.expires.remove(&(at, field.to_owned()));
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    // The fields with their values and the times they expire at.
+    pub fn iter(&self) -> Iter<'_> {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => Iter::Listpack(listpack.iter()),
+            Encoding::Hashtable(fields) => Iter::Hashtable(fields.iter()),
+        }
+    }
+
+    pub fn expire_time(&self, field: &str) -> Option<u64> {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => find_field(listpack, field).and_then(|(_, _, expire)| expire),
+            Encoding::Hashtable(fields) => fields.get(field).and_then(|entry| entry.expire),
+        }
+    }
+
+    // Replaces the time the field expires at, returning the previous one, or None when there is no
+    // such field.
+    fn replace_expire(&mut self, field: &str, expire: Option<u64>) -> Option<Option<u64>> {
+        match &mut self.encoding {
+            Encoding::Listpack(listpack) => {
+                let (offset, value, old) = find_field(listpack, field)?;
+                let value = value.to_vec();
+                remove_at(listpack, offset);
+                insert_at(listpack, offset, field, &value, expire);
+                Some(old)
+            },
+            Encoding::Hashtable(fields) => fields.get_mut(field).map(|entry| std::mem::replace(&mut entry.expire, expire)),
+        }
+    }
+
+    // Sets the unix time in milliseconds the field expires at, false when there is no such field.
+    pub fn set_expire(&mut self, field: &str, at: u64) -> bool {
+        let Some(old) = self.replace_expire(field, Some(at)) else {
+            return false;
+        };
+        if let Some(old) = old {
+            self.expires.remove(&(old, field.to_owned()));
+        }
+        self.expires.insert((at, field.to_owned()));
+        true
+    }
+
+    // Removes the TTL of the field, false when it had none.
+    pub fn persist(&mut self, field: &str) -> bool {
+        match self.replace_expire(field, None).flatten() {
+            Some(at) => self.expires.remove(&(at, field.to_owned())),
+            None => false
+        }
+    }
+
+    // The time the first of the fields with a TTL expires at.
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.expires.first().map(|(at, _)| *at)
+    }
+
+    // Deletes the fields whose TTL passed by `now`, returning them.
+    pub fn remove_expired(&mut self, now: u64) -> Vec<String> {
+        let mut removed = vec![];
+        while let Some((at, _)) = self.expires.first() {
+            if *at > now {
+                break;
+            }
+            let (_, field) = self.expires.pop_first().unwrap();
+            self.take(&field);
+            removed.push(field);
+        }
+        removed
+    }
+}
+
+pub enum Iter<'a> {
+    Listpack(listpack::Iter<'a>),
+    Hashtable(hash_map::Iter<'a, String, Field>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a [u8], Option<u64>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Listpack(entries) => {
+                let field = entries.next()?;
+                let value = entries.next()?;
+                let expire = entries.next()?;
+                Some((std::str::from_utf8(field).unwrap(), value, decode_expire(expire)))
+            },
+            Iter::Hashtable(fields) => fields.next().map(|(field, entry)| (field.as_str(), &entry.value[..], entry.expire)),
+        }
+    }
+}
+
+fn get_hash<'a>(db: &'a Db, key: &str) -> Result<Option<&'a Hash>, RESPError> {
+    db.get(key).map(Value::as_hash).transpose()
+}
+
+// Gets the hash for modification, when it exists.
+fn get_hash_mut<'a>(db: &'a mut Db, key: &str) -> Result<Option<&'a mut Hash>, RESPError> {
+    if get_hash(db, key)?.is_none() {
+        return Ok(None);
+    }
+    db.get_mut(key).map(Value::as_hash_mut).transpose()
+}
+
+// Deletes the hash once its last field is gone, or else lets the keyspace know when its first field
+// expires now. Returns whether it was deleted.
+fn written(db: &mut Db, key: &str) -> bool {
+    if db.peek(key).is_some_and(|value| value.as_hash().is_ok_and(Hash::is_empty)) {
+        db.remove(key);
+        return true;
+    }
+    db.update_field_expiration(key);
+    false
+}
+
+// The fields of `FIELDS numfields field [field ...]`, starting at `i`.
+fn parse_fields(command: &[Arg], i: usize) -> Result<&[Arg], RESPError> {
+    if !command.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("FIELDS")) || i + 1 >= command.len() {
+        return Err(RESPError::MissingFields);
+    }
+    let count = parse_number(&command[i + 1])?;
+    if count <= 0 {
+        return Err(RESPError::InvalidNumFields);
+    }
+    let fields = &command[i + 2..];
+    if fields.len() as i64 != count {
+        return Err(RESPError::NumFieldsMismatch);
+    }
+    Ok(fields)
+}
+
+fn numbers(replies: impl IntoIterator<Item = i64>) -> RESPValue {
+    RESPValue::Array(replies.into_iter().map(RESPValue::Number).collect())
+}
+
+// HSET key field value [field value ...]
+// HMSET is the same, replying OK.
+pub fn hset(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    if !command.len().is_multiple_of(2) {
+        return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+    }
+
+    let added = {
+        let mut db = shared.db.lock();
+        let hash = db.get_or_insert_with(key, || Value::Hash(Hash::default())).as_hash_mut()?;
+        let added = command[2..].chunks(2).filter(|pair| hash.insert(pair[0].to_string(), Bytes::from(pair[1].clone()))).count();
+        written(&mut db, key);
+        added
+    };
+
+    notify_keyspace_event(shared, NOTIFY_HASH, "hset", key, 0);
+    Ok(match command[0].as_str() {
+        "HMSET" => RESPValue::SimpleString(String::from("OK")),
+        _ => RESPValue::Number(added as i64)
+    })
+}
+
+// HSETNX key field value
+pub fn hsetnx(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (key, field) = (command[1].as_str(), command[2].as_str());
+    {
+        let mut db = shared.db.lock();
+        if get_hash(&db, key)?.is_some_and(|hash| hash.contains(field)) {
+            return Ok(RESPValue::Number(0));
+        }
+        let hash = db.get_or_insert_with(key, || Value::Hash(Hash::default())).as_hash_mut()?;
+        hash.insert(field.to_owned(), Bytes::from(command[3].clone()));
+    }
+    notify_keyspace_event(shared, NOTIFY_HASH, "hset", key, 0);
+    Ok(RESPValue::Number(1))
+}
+
+// HGET key field
+pub fn hget(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    Ok(match get_hash(&db, &command[1])?.and_then(|hash| hash.get(command[2].as_str())) {
+        Some(value) => RESPValue::BlobString(Bytes::copy_from_slice(value)),
+        None => RESPValue::Null
+    })
+}
+
+// HMGET key field [field ...]
+pub fn hmget(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let hash = get_hash(&db, &command[1])?;
+    Ok(RESPValue::Array(command[2..].iter().map(|field| match hash.and_then(|hash| hash.get(field)) {
+        Some(value) => RESPValue::BlobString(Bytes::copy_from_slice(value)),
+        None => RESPValue::Null
+    }).collect()))
+}
+
+// HDEL key field [field ...]
+pub fn hdel(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let (removed, deleted) = {
+        let mut db = shared.db.lock();
+        let Some(hash) = get_hash_mut(&mut db, key)? else {
+            return Ok(RESPValue::Number(0));
+        };
+        let removed = command[2..].iter().filter(|field| hash.remove(field)).count();
+        (removed, written(&mut db, key))
+    };
+
+    if removed > 0 {
+        notify_keyspace_event(shared, NOTIFY_HASH, "hdel", key, 0);
+    }
+    if deleted {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+    }
+    Ok(RESPValue::Number(removed as i64))
+}
+
+// HLEN key
+pub fn hlen(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    Ok(RESPValue::Number(get_hash(&db, &command[1])?.map_or(0, Hash::len) as i64))
+}
+
+// HEXISTS key field
+pub fn hexists(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    Ok(RESPValue::Number(get_hash(&db, &command[1])?.is_some_and(|hash| hash.contains(&command[2])) as i64))
+}
+
+// HGETALL key
+// HKEYS key
+// HVALS key
+pub fn hgetall(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let Some(hash) = get_hash(&db, &command[1])? else {
+        return Ok(RESPValue::Array(vec![]));
+    };
+    let field = |field: &str| RESPValue::BlobString(Bytes::copy_from_slice(field.as_bytes()));
+    Ok(RESPValue::Array(match command[0].as_str() {
+        "HGETALL" => hash.iter().flat_map(|(name, value, _)| [field(name), RESPValue::BlobString(Bytes::copy_from_slice(value))]).collect(),
+        "HKEYS" => hash.iter().map(|(name, _, _)| field(name)).collect(),
+        _ => hash.iter().map(|(_, value, _)| RESPValue::BlobString(Bytes::copy_from_slice(value))).collect()
+    }))
+}
+
+// HEXPIRE key seconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+// HPEXPIRE key milliseconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+// HEXPIREAT key unix-time-seconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+// HPEXPIREAT key unix-time-milliseconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+pub fn hexpire(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let time = parse_number(&command[2])?;
+    let (condition, fields) = match Condition::parse(&command[3]) {
+        Some(condition) => (condition, parse_fields(command, 4)?),
+        None => (Condition::Always, parse_fields(command, 3)?)
+    };
+    let now = now_ms();
+    let at = match command[0].as_str() {
+        "HEXPIRE" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now as i64)),
+        "HPEXPIRE" => time.checked_add(now as i64),
+        "HEXPIREAT" => time.checked_mul(1000),
+        _ => Some(time)
+    };
+    let at = at.filter(|at| time >= 0 && *at <= MAX_EXPIRE_TIME as i64).ok_or(RESPError::InvalidFieldExpireTime)? as u64;
+
+    let (replies, deleted) = {
+        let mut db = shared.db.lock();
+        let Some(hash) = get_hash_mut(&mut db, key)? else {
+            return Ok(numbers(fields.iter().map(|_| -2)));
+        };
+        // A time in the past deletes the field right away.
+        let replies: Vec<i64> = fields.iter().map(|field| match hash.contains(field) {
+            false => -2,
+            true if !condition.allows(hash.expire_time(field), at) => 0,
+            true if at <= now => {
+                hash.remove(field);
+                2
+            },
+            true => {
+                hash.set_expire(field, at);
+                1
+            }
+        }).collect();
+        (replies, written(&mut db, key))
+    };
+
+    if replies.contains(&1) {
+        notify_keyspace_event(shared, NOTIFY_HASH, "hexpire", key, 0);
+    }
+    if replies.contains(&2) {
+        notify_keyspace_event(shared, NOTIFY_HASH, "hdel", key, 0);
+    }
+    if deleted {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+    }
+    Ok(numbers(replies))
+}
+
+// HEXPIRE and friends as journaled: the TTL of the fields it set as the absolute time with
+// HPEXPIREAT, and the fields a time in the past deleted with HDEL.
+pub fn expire_effects(command: &[Arg], reply: &RESPValue, shared: &SharedState) -> Vec<Vec<Arg>> {
+    let RESPValue::Array(replies) = reply else {
+        return vec![];
+    };
+    let key = command[1].as_str();
+    let fields = &command[command.len() - replies.len()..];
+    let with_reply = |n: i64| -> Vec<Arg> {
+        fields.iter().zip(replies).filter(|(_, reply)| matches!(reply, RESPValue::Number(reply) if *reply == n))
+            .map(|(field, _)| field.clone()).collect()
+    };
+    let (set, removed) = (with_reply(1), with_reply(2));
+
+    let mut effects = vec![];
+    // The fields were all set to expire at the same time.
+    let at = set.first().and_then(|field| shared.db.lock().peek(key)?.as_hash().ok()?.expire_time(field));
+    if let Some(at) = at {
+        let mut expire = vec![Arg::from("HPEXPIREAT"), Arg::from(key), Arg::from(at.to_string()), Arg::from("FIELDS"), Arg::from(set.len().to_string())];
+        expire.extend(set);
+        effects.push(expire);
+    }
+    if !removed.is_empty() {
+        effects.push([Arg::from("HDEL"), Arg::from(key)].into_iter().chain(removed).collect());
+    }
+    effects
+}
+
+// HTTL key FIELDS numfields field [field ...]
+// HPTTL key FIELDS numfields field [field ...]
+// HEXPIRETIME key FIELDS numfields field [field ...]
+// HPEXPIRETIME key FIELDS numfields field [field ...]
+pub fn httl(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let fields = parse_fields(command, 2)?;
+    let db = shared.db.lock();
+    let Some(hash) = get_hash(&db, &command[1])? else {
+        return Ok(numbers(fields.iter().map(|_| -2)));
+    };
+
+    let now = now_ms();
+    Ok(numbers(fields.iter().map(|field| match (hash.contains(field), hash.expire_time(field)) {
+        (false, _) => -2,
+        (true, None) => -1,
+        (true, Some(at)) => {
+            let ttl = at.saturating_sub(now);
+            (match command[0].as_str() {
+                "HTTL" => ttl.div_ceil(1000),
+                "HPTTL" => ttl,
+                "HEXPIRETIME" => at / 1000,
+                _ => at
+            }) as i64
+        }
+    })))
+}
+
+// HPERSIST key FIELDS numfields field [field ...]
+pub fn hpersist(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let fields = parse_fields(command, 2)?;
+    let replies: Vec<i64> = {
+        let mut db = shared.db.lock();
+        let Some(hash) = get_hash_mut(&mut db, key)? else {
+            return Ok(numbers(fields.iter().map(|_| -2)));
+        };
+        let replies = fields.iter().map(|field| match hash.contains(field) {
+            false => -2,
+            true if hash.persist(field) => 1,
+            true => -1
+        }).collect();
+        written(&mut db, key);
+        replies
+    };
+
+    if replies.contains(&1) {
+        notify_keyspace_event(shared, NOTIFY_HASH, "hpersist", key, 0);
+    }
+    Ok(numbers(replies))
+}
@@ -0,0 +1,138 @@
+// Health probes over HTTP, for orchestrators and load balancers that don't speak RESP. With
+// `health-port` set, every address of `bind` serves:
+//   GET /livez     200 as long as the process runs, loading the dataset included.
+//   GET /readyz    200 once the dataset was loaded and connections are accepted, 503 before that
+//                  and while shutting down.
+//   GET /replicaz  200 on a primary, and on a replica that's in sync with its primary and behind it
+//                  by at most `health-replica-max-lag` bytes (or ?max-lag=<bytes>), 503 otherwise.
+// The probes are served by threads of their own, so they're answered while the dataset is loading,
+// a thread per connection so a slow client doesn't hold back the others.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{bind_addresses, logging, SharedState};
+
+// The time a client has to send the whole request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+// Longer request lines are answered with 414.
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+const NOT_IN_SYNC: u64 = u64::MAX;
+
+#[derive(Default)]
+pub struct Health {
+    ready: AtomicBool,
+    replica: AtomicBool,
+    // NOT_IN_SYNC while the link with the primary is down or syncing.
+    replica_lag: AtomicU64,
+    max_lag: AtomicU64,
+}
+
+impl Health {
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    fn probe(&self, path: &str, query: &str) -> (u16, String) {
+        match path {
+            "/livez" => (200, String::from("alive")),
+            "/readyz" if self.ready.load(Ordering::Relaxed) => (200, String::from("ready")),
+            "/readyz" => (503, String::from("not ready")),
+            "/replicaz" if !self.replica.load(Ordering::Relaxed) => (200, String::from("primary")),
+            "/replicaz" => {
+                let max_lag = query.split('&').find_map(|param| param.strip_prefix("max-lag=")?.parse().ok())
+                    .unwrap_or_else(|| self.max_lag.load(Ordering::Relaxed));
+                match self.replica_lag.load(Ordering::Relaxed) {
+                    NOT_IN_SYNC => (503, String::from("not in sync")),
+                    lag if lag > max_lag => (503, format!("behind by {} bytes", lag)),
+                    lag => (200, format!("in sync, behind by {} bytes", lag))
+                }
+            },
+            _ => (404, String::from("not found"))
+        }
+    }
+}
+
+// Serves the probes on `health-port`, when set.
+pub fn serve(shared: &SharedState) -> Result<(), String> {
+    let port = shared.config.get_int("health-port") as u16;
+    if port == 0 {
+        return Ok(());
+    }
+    for (ip, optional) in bind_addresses(shared) {
+        let listener = match TcpListener::bind((ip.as_str(), port)) {
+            Ok(listener) => listener,
+            Err(e) if optional => {
+                logging::log(shared, "warning", format!("Skipping health probes on {}:{}: {}", ip, port, e));
+                continue;
+            },
+            Err(e) => return Err(format!("Failed listening for health probes on {}:{}: {}", ip, port, e))
+        };
+        let health = shared.health.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let health = health.clone();
+                std::thread::spawn(move || respond(stream, &health));
+            }
+        });
+    }
+    Ok(())
+}
+
+// Reads the request line, None when it's longer than MAX_REQUEST_LINE. The rest of the request is
+// ignored.
+fn read_request_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut line = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(end) = line.iter().position(|&byte| byte == b'\n') {
+            line.truncate(end);
+            return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+        }
+        if line.len() > MAX_REQUEST_LINE {
+            return Ok(None);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut chunk)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => line.extend_from_slice(&chunk[..read])
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, health: &Health) -> io::Result<()> {
+    let Some(request_line) = read_request_line(&mut stream)? else {
+        return write!(stream, "HTTP/1.1 414 URI Too Long\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    };
+    let (status, body) = match request_line.trim_end_matches('\r').split(' ').collect::<Vec<_>>()[..] {
+        ["GET" | "HEAD", target, _] => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            health.probe(path, query)
+        },
+        _ => (400, String::from("bad request"))
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Service Unavailable"
+    };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n", status, reason, body.len() + 1, body)
+}
+
+// Keeps the state of the replica the probes are answered with up to date, run by the cron.
+pub fn refresh(shared: &Arc<SharedState>) {
+    let health = &shared.health;
+    health.max_lag.store(shared.config.get_int("health-replica-max-lag") as u64, Ordering::Relaxed);
+    health.replica.store(shared.replication.is_replica(), Ordering::Relaxed);
+    let lag = shared.replication.lag(shared).unwrap_or(NOT_IN_SYNC);
+    health.replica_lag.store(lag, Ordering::Relaxed);
+}
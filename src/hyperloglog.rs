@@ -0,0 +1,388 @@
+// HyperLogLog cardinality estimation, using the same string representation as Redis so values can
+// be moved between the two.
+//
+// The value starts with a 16 bytes header: the "HYLL" magic, the encoding (dense or sparse), 3
+// unused bytes and the cached cardinality (little endian, its most significant bit set when stale).
+// The dense encoding packs the 16384 registers as 6 bit integers, while the sparse encoding is a
+// run length encoding of them, used while most of the registers are still zero.
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::notify::{notify_keyspace_event, NOTIFY_STRING};
+use crate::{RESPError, RESPValue, SharedState};
+
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+const HASH_SEED: u64 = 0xadc83b19;
+
+const MAGIC: &[u8] = b"HYLL";
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * REGISTER_BITS).div_ceil(8);
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+const CARDINALITY_OFFSET: usize = 8;
+const STALE_CARDINALITY_BIT: u8 = 1 << 7;
+
+// Sparse opcodes: ZERO (00xxxxxx) is a run of up to 64 zero registers, XZERO (01xxxxxx xxxxxxxx) a
+// run of up to 16384 zero registers and VAL (1vvvvvxx) a run of up to 4 registers set to 1-32.
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+// Sparse values growing past this size are converted to the dense encoding.
+const SPARSE_MAX_BYTES: usize = 3000;
+
+pub fn murmurhash64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// The register an element maps to, and the length of the run of zeros in its hash (plus one).
+fn register_for(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HASH_SEED);
+    let index = (hash as usize) & (REGISTERS - 1);
+    let run = ((hash >> P) | (1 << Q)).trailing_zeros() + 1;
+    (index, run as u8)
+}
+
+fn invalid_value() -> RESPError {
+    RESPError::InvalidHyperLogLog
+}
+
+// The registers of a HyperLogLog, decoded from either representation.
+struct Registers([u8; REGISTERS]);
+
+impl Registers {
+    fn new() -> Self {
+        Registers([0; REGISTERS])
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, RESPError> {
+        if value.len() < HEADER_SIZE || &value[..MAGIC.len()] != MAGIC {
+            return Err(invalid_value());
+        }
+
+        let mut registers = Registers::new();
+        let data = &value[HEADER_SIZE..];
+        match value[MAGIC.len()] {
+            ENCODING_DENSE => {
+                if value.len() != DENSE_SIZE {
+                    return Err(invalid_value());
+                }
+                for (i, register) in registers.0.iter_mut().enumerate() {
+                    *register = dense_get(data, i);
+                }
+            },
+            ENCODING_SPARSE => {
+                let mut index = 0;
+                let mut i = 0;
+                while i < data.len() {
+                    let opcode = data[i];
+                    let (len, value) = if opcode & 0x80 != 0 {
+                        i += 1;
+                        ((opcode & 0x3) as usize + 1, ((opcode >> 2) & 0x1f) + 1)
+                    } else if opcode & 0x40 != 0 {
+                        let next = *data.get(i + 1).ok_or_else(invalid_value)?;
+                        i += 2;
+                        ((((opcode & 0x3f) as usize) << 8 | next as usize) + 1, 0)
+                    } else {
+                        i += 1;
+                        ((opcode & 0x3f) as usize + 1, 0)
+                    };
+
+                    if index + len > REGISTERS {
+                        return Err(RESPError::CorruptedHyperLogLog);
+                    }
+                    registers.0[index..index + len].fill(value);
+                    index += len;
+                }
+                if index != REGISTERS {
+                    return Err(RESPError::CorruptedHyperLogLog);
+                }
+            },
+            _ => return Err(invalid_value())
+        }
+        Ok(registers)
+    }
+
+    // Encodes the registers sparsely when possible, densely otherwise.
+    fn encode(&self, cardinality: Option<u64>) -> Vec<u8> {
+        let mut value = match self.encode_sparse() {
+            Some(sparse) => sparse,
+            None => {
+                let mut value = header(ENCODING_DENSE);
+                value.resize(DENSE_SIZE, 0);
+                for (i, register) in self.0.iter().enumerate() {
+                    dense_set(&mut value[HEADER_SIZE..], i, *register);
+                }
+                value
+            }
+        };
+
+        let cached = match cardinality {
+            Some(cardinality) => cardinality.to_le_bytes(),
+            None => {
+                let mut stale = [0; 8];
+                stale[7] = STALE_CARDINALITY_BIT;
+                stale
+            }
+        };
+        value[CARDINALITY_OFFSET..HEADER_SIZE].copy_from_slice(&cached);
+        value
+    }
+
+    fn encode_sparse(&self) -> Option<Vec<u8>> {
+        let mut value = header(ENCODING_SPARSE);
+        let mut i = 0;
+        while i < REGISTERS {
+            let register = self.0[i];
+            let run = self.0[i..].iter().take_while(|r| **r == register).count();
+
+            if register == 0 {
+                let mut left = run;
+                while left > 0 {
+                    let len = left.min(SPARSE_XZERO_MAX_LEN);
+                    if len > SPARSE_ZERO_MAX_LEN {
+                        value.push(0x40 | ((len - 1) >> 8) as u8);
+                        value.push(((len - 1) & 0xff) as u8);
+                    } else {
+                        value.push((len - 1) as u8);
+                    }
+                    left -= len;
+                }
+            } else if register > SPARSE_VAL_MAX_VALUE {
+                return None;
+            } else {
+                let mut left = run;
+                while left > 0 {
+                    let len = left.min(SPARSE_VAL_MAX_LEN);
+                    value.push(0x80 | ((register - 1) << 2) | (len - 1) as u8);
+                    left -= len;
+                }
+            }
+
+            if value.len() > HEADER_SIZE + SPARSE_MAX_BYTES {
+                return None;
+            }
+            i += run;
+        }
+        Some(value)
+    }
+
+    // Returns whether the register changed.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let (index, run) = register_for(element);
+        if run > self.0[index] {
+            self.0[index] = run;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn merge(&mut self, other: &Registers) {
+        for (register, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    // The estimator from "New cardinality estimation algorithms for HyperLogLog sketches" by Otmar
+    // Ertl, which is also what Redis uses.
+    fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let mut histogram = [0u32; 64];
+        for register in self.0.iter() {
+            histogram[*register as usize] += 1;
+        }
+
+        let q = Q as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for j in (1..=q).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+
+        const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+        (ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn header(encoding: u8) -> Vec<u8> {
+    let mut header = Vec::with_capacity(DENSE_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.push(encoding);
+    header.resize(HEADER_SIZE, 0);
+    header
+}
+
+// Registers are packed least significant bits first, and may span two bytes.
+fn dense_get(data: &[u8], index: usize) -> u8 {
+    let bit = index * REGISTER_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let low = data[byte] as u16 >> shift;
+    let high = data.get(byte + 1).map_or(0, |b| (*b as u16) << (8 - shift));
+    ((low | high) as u8) & REGISTER_MAX
+}
+
+fn dense_set(data: &mut [u8], index: usize, value: u8) {
+    let bit = index * REGISTER_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    data[byte] &= !(REGISTER_MAX << shift);
+    data[byte] |= value << shift;
+    if shift + REGISTER_BITS > 8 {
+        let high_shift = 8 - shift;
+        data[byte + 1] &= !(REGISTER_MAX >> high_shift);
+        data[byte + 1] |= value >> high_shift;
+    }
+}
+
+// The cardinality cached in the header, if it's still valid.
+fn cached_count(value: &[u8]) -> Option<u64> {
+    let cached: [u8; 8] = value[CARDINALITY_OFFSET..HEADER_SIZE].try_into().unwrap();
+    (cached[7] & STALE_CARDINALITY_BIT == 0).then(|| u64::from_le_bytes(cached))
+}
+
+fn registers_of(value: &Value) -> Result<Registers, RESPError> {
+    Registers::decode(&value.as_string().map_err(|_| invalid_value())?)
+}
+
+// PFADD key [element [element ...]]
+pub fn pfadd(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+
+    let updated = {
+        let mut db = shared.db.lock();
+        let (mut registers, mut updated) = match db.get(key) {
+            Some(value) => (registers_of(value)?, false),
+            None => (Registers::new(), true)
+        };
+        for element in &command[2..] {
+            updated |= registers.add(element.raw());
+        }
+
+        if updated {
+            db.set(key.to_owned(), Value::string(registers.encode(None)));
+        }
+        updated
+    };
+
+    if updated {
+        notify_keyspace_event(shared, NOTIFY_STRING, "pfadd", key, 0);
+    }
+    Ok(RESPValue::Number(updated as i64))
+}
+
+// PFCOUNT key [key ...]
+pub fn pfcount(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut db = shared.db.lock();
+
+    if command.len() == 2 {
+        let key = command[1].as_str();
+        let value = match db.get(key) {
+            Some(value) => value.as_string().map_err(|_| invalid_value())?,
+            None => return Ok(RESPValue::Number(0))
+        };
+        let registers = Registers::decode(&value)?;
+        if let Some(count) = cached_count(&value) {
+            return Ok(RESPValue::Number(count as i64));
+        }
+
+        // Cache the cardinality for the next call, like Redis this counts as a write.
+        let count = registers.count();
+        if let Some(Ok(mut value)) = db.get_mut(key).map(Value::as_string_mut) {
+            value[CARDINALITY_OFFSET..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+        }
+        return Ok(RESPValue::Number(count as i64));
+    }
+
+    // The cardinality of the union of all the keys.
+    let mut union = Registers::new();
+    for key in &command[1..] {
+        if let Some(value) = db.get(key) {
+            union.merge(&registers_of(value)?);
+        }
+    }
+    Ok(RESPValue::Number(union.count() as i64))
+}
+
+// PFMERGE destkey [sourcekey [sourcekey ...]]
+pub fn pfmerge(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let destination = command[1].as_str();
+
+    {
+        let mut db = shared.db.lock();
+        // The destination takes part in the union too.
+        let mut union = Registers::new();
+        for key in &command[1..] {
+            if let Some(value) = db.get(key) {
+                union.merge(&registers_of(value)?);
+            }
+        }
+        db.set(destination.to_owned(), Value::string(union.encode(None)));
+    }
+
+    notify_keyspace_event(shared, NOTIFY_STRING, "pfadd", destination, 0);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
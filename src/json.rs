@@ -0,0 +1,804 @@
+// JSON documents, with the core commands of RedisJSON. A key holds a document that commands read and
+// change at paths, so updating a field of a document doesn't take rewriting all of it.
+//
+// Paths starting with `$` are JSONPath, of which a subset is supported: member names (`.name` or
+// `['name']`), array indexes counted from either end (`[0]`, `[-1]`), slices (`[1:3]`, `[::2]`),
+// wildcards (`.*`, `[*]`), unions (`[0,2]`, `['a','b']`), recursive descent (`..name`) and filters
+// comparing relative paths with literals (`[?(@.price < 10 && @.tag == 'a')]`). A JSONPath matches
+// any number of values, and commands reply with a result for every one of them. Other paths are the
+// legacy paths of RedisJSON 1 (like `.a.b[0]` or `a`), which commands reply to with the result of a
+// single match, or an error when there's none.
+
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+use serde_json::{Number, Value as Json};
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::memory::{sampled_size, ENTRY_OVERHEAD};
+use crate::notify::{notify_keyspace_event, NOTIFY_MODULE};
+use crate::{rdb, RESPError, RESPValue, SharedState};
+
+pub const MODULE_ID: u64 = rdb::module_id(b"ReJSON-RL", 3);
+
+// The deepest documents may be nested, well within the 128 levels serde_json parses so they can be
+// loaded back, even from within other JSON (like the lines of an export).
+const MAX_DEPTH: usize = 100;
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+enum Operand {
+    // A path relative to the value being filtered (`@`), standing for its first match.
+    Relative(Vec<Segment>),
+    Literal(Json),
+}
+
+enum Filter {
+    Or(Box<Filter>, Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Exists(Vec<Segment>),
+    Compare(Operand, Op, Operand),
+}
+
+enum Selector {
+    Name(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>, i64),
+    Wildcard,
+    Filter(Box<Filter>),
+}
+
+// Selects values among the children of the values matched so far, or among all their descendants.
+struct Segment {
+    descendant: bool,
+    selectors: Vec<Selector>,
+}
+
+// A step from a value to one of its children.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+// Where a value is in a document, the steps leading to it from the root.
+type Location = Vec<Step>;
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self) -> Result<T, RESPError> {
+        Err(RESPError::BadJsonPath(self.text.to_owned()))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        let eaten = self.text[self.pos..].starts_with(s);
+        if eaten {
+            self.pos += s.len();
+        }
+        eaten
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    // The segments up to the first character that can't continue them.
+    fn segments(&mut self) -> Result<Vec<Segment>, RESPError> {
+        let mut segments = vec![];
+        loop {
+            let descendant = self.eat("..");
+            let selectors = if self.eat("[") {
+                self.bracket()?
+            } else if descendant || self.eat(".") {
+                vec![if self.eat("*") { Selector::Wildcard } else { Selector::Name(self.name()?) }]
+            } else {
+                return Ok(segments);
+            };
+            segments.push(Segment { descendant, selectors });
+        }
+    }
+
+    fn name(&mut self) -> Result<String, RESPError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !b".[]()=!<>&|,'\" \t".contains(&c)) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.error();
+        }
+        Ok(self.text[start..self.pos].to_owned())
+    }
+
+    // A quoted string, where a backslash escapes the character after it.
+    fn string(&mut self, quote: u8) -> Result<String, RESPError> {
+        let mut s = String::new();
+        let mut chars = self.text[self.pos + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, c)) => s.push(c),
+                    None => break
+                },
+                c if c == quote as char => {
+                    self.pos += i + 2;
+                    return Ok(s);
+                },
+                c => s.push(c)
+            }
+        }
+        self.error()
+    }
+
+    fn integer(&mut self) -> Result<Option<i64>, RESPError> {
+        self.skip_spaces();
+        let start = self.pos;
+        self.eat("-");
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let integer = &self.text[start..self.pos];
+        self.skip_spaces();
+        match integer {
+            "" => Ok(None),
+            integer => integer.parse().map(Some).or_else(|_| self.error())
+        }
+    }
+
+    // The selectors between brackets, the opening one being eaten already.
+    fn bracket(&mut self) -> Result<Vec<Selector>, RESPError> {
+        self.skip_spaces();
+        if self.eat("?") {
+            let filter = self.or()?;
+            self.skip_spaces();
+            return if self.eat("]") { Ok(vec![Selector::Filter(Box::new(filter))]) } else { self.error() };
+        }
+        let mut selectors = vec![];
+        loop {
+            self.skip_spaces();
+            selectors.push(self.selector()?);
+            self.skip_spaces();
+            if self.eat("]") {
+                return Ok(selectors);
+            }
+            if !self.eat(",") {
+                return self.error();
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<Selector, RESPError> {
+        if self.eat("*") {
+            return Ok(Selector::Wildcard);
+        }
+        if let Some(quote @ (b'\'' | b'"')) = self.peek() {
+            return Ok(Selector::Name(self.string(quote)?));
+        }
+        let start = self.integer()?;
+        if !self.eat(":") {
+            return start.map_or_else(|| self.error(), |index| Ok(Selector::Index(index)));
+        }
+        let end = self.integer()?;
+        let step = if self.eat(":") { self.integer()? } else { None };
+        Ok(Selector::Slice(start, end, step.unwrap_or(1)))
+    }
+
+    fn or(&mut self) -> Result<Filter, RESPError> {
+        let mut filter = self.and()?;
+        loop {
+            self.skip_spaces();
+            if !self.eat("||") {
+                return Ok(filter);
+            }
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+    }
+
+    fn and(&mut self) -> Result<Filter, RESPError> {
+        let mut filter = self.unary()?;
+        loop {
+            self.skip_spaces();
+            if !self.eat("&&") {
+                return Ok(filter);
+            }
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Filter, RESPError> {
+        self.skip_spaces();
+        if self.eat("!") {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let filter = self.or()?;
+            self.skip_spaces();
+            return if self.eat(")") { Ok(filter) } else { self.error() };
+        }
+        let left = self.operand()?;
+        self.skip_spaces();
+        let ops = [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)];
+        match (left, ops.into_iter().find(|(s, _)| self.eat(s))) {
+            (left, Some((_, op))) => Ok(Filter::Compare(left, op, self.operand()?)),
+            (Operand::Relative(path), None) => Ok(Filter::Exists(path)),
+            _ => self.error()
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, RESPError> {
+        self.skip_spaces();
+        if self.eat("@") {
+            return Ok(Operand::Relative(self.segments()?));
+        }
+        if let Some(quote @ (b'\'' | b'"')) = self.peek() {
+            return Ok(Operand::Literal(Json::String(self.string(quote)?)));
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || b"+-.".contains(&c)) {
+            self.pos += 1;
+        }
+        match serde_json::from_str(&self.text[start..self.pos]) {
+            Ok(literal @ (Json::Number(_) | Json::Bool(_) | Json::Null)) => Ok(Operand::Literal(literal)),
+            _ => self.error()
+        }
+    }
+}
+
+fn children(value: &Json) -> Vec<(Step, &Json)> {
+    match value {
+        Json::Array(array) => array.iter().enumerate().map(|(i, child)| (Step::Index(i), child)).collect(),
+        Json::Object(map) => map.iter().map(|(key, child)| (Step::Key(key.to_owned()), child)).collect(),
+        _ => vec![]
+    }
+}
+
+fn index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+// The indexes of an array of `len` elements a slice selects, in the order it selects them.
+fn slice(start: Option<i64>, end: Option<i64>, step: i64, len: usize) -> Vec<usize> {
+    let len = len as i64;
+    let normalize = |i: i64| if i < 0 { i + len } else { i };
+    let mut indexes = vec![];
+    if step > 0 {
+        let mut i = start.map_or(0, normalize).clamp(0, len);
+        let end = end.map_or(len, normalize).clamp(0, len);
+        while i < end {
+            indexes.push(i as usize);
+            i += step;
+        }
+    } else if step < 0 {
+        let mut i = start.map_or(len - 1, normalize).clamp(-1, len - 1);
+        let end = end.map_or(-1, normalize).clamp(-1, len - 1);
+        while i > end {
+            indexes.push(i as usize);
+            i += step;
+        }
+    }
+    indexes
+}
+
+fn compare(a: Option<&Json>, op: Op, b: Option<&Json>) -> bool {
+    let ordering = match (a, b) {
+        (Some(Json::Number(a)), Some(Json::Number(b))) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Some(Json::String(a)), Some(Json::String(b))) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None
+    };
+    match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering != Some(Ordering::Equal),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+    }
+}
+
+impl Operand {
+    fn value<'a>(&'a self, node: &'a Json) -> Option<&'a Json> {
+        match self {
+            Operand::Relative(segments) => select(segments, node).into_iter().next().map(|(_, value)| value),
+            Operand::Literal(literal) => Some(literal)
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, node: &Json) -> bool {
+        match self {
+            Filter::Or(a, b) => a.matches(node) || b.matches(node),
+            Filter::And(a, b) => a.matches(node) && b.matches(node),
+            Filter::Not(filter) => !filter.matches(node),
+            Filter::Exists(segments) => !select(segments, node).is_empty(),
+            Filter::Compare(a, op, b) => compare(a.value(node), *op, b.value(node))
+        }
+    }
+}
+
+impl Segment {
+    fn apply<'a>(&self, location: &Location, node: &'a Json, matches: &mut Vec<(Location, &'a Json)>) {
+        let mut push = |step: Step, child: &'a Json| {
+            let mut location = location.clone();
+            location.push(step);
+            matches.push((location, child));
+        };
+        for selector in &self.selectors {
+            match (selector, node) {
+                (Selector::Name(name), Json::Object(map)) => {
+                    if let Some(child) = map.get(name) {
+                        push(Step::Key(name.to_owned()), child);
+                    }
+                },
+                (Selector::Index(i), Json::Array(array)) => {
+                    if let Some(i) = index(*i, array.len()) {
+                        push(Step::Index(i), &array[i]);
+                    }
+                },
+                (Selector::Slice(start, end, step), Json::Array(array)) => {
+                    for i in slice(*start, *end, *step, array.len()) {
+                        push(Step::Index(i), &array[i]);
+                    }
+                },
+                (Selector::Wildcard, _) => {
+                    for (step, child) in children(node) {
+                        push(step, child);
+                    }
+                },
+                (Selector::Filter(filter), _) => {
+                    for (step, child) in children(node) {
+                        if filter.matches(child) {
+                            push(step, child);
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn descend<'a>(&self, location: Location, node: &'a Json, matches: &mut Vec<(Location, &'a Json)>) {
+        self.apply(&location, node, matches);
+        for (step, child) in children(node) {
+            let mut location = location.clone();
+            location.push(step);
+            self.descend(location, child, matches);
+        }
+    }
+}
+
+// The values the segments match in the document, with their locations.
+fn select<'a>(segments: &[Segment], document: &'a Json) -> Vec<(Location, &'a Json)> {
+    let mut nodes = vec![(vec![], document)];
+    for segment in segments {
+        let mut matches = vec![];
+        for (location, node) in nodes {
+            if segment.descendant {
+                segment.descend(location, node, &mut matches);
+            } else {
+                segment.apply(&location, node, &mut matches);
+            }
+        }
+        nodes = matches;
+    }
+    nodes
+}
+
+fn resolve_mut<'a>(document: &'a mut Json, location: &[Step]) -> Option<&'a mut Json> {
+    location.iter().try_fold(document, |node, step| match (node, step) {
+        (Json::Object(map), Step::Key(key)) => map.get_mut(key),
+        (Json::Array(array), Step::Index(i)) => array.get_mut(*i),
+        _ => None
+    })
+}
+
+struct Path {
+    text: String,
+    legacy: bool,
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    fn parse(text: &str) -> Result<Self, RESPError> {
+        let mut parser = Parser { text, pos: 0 };
+        let legacy = !parser.eat("$");
+        let mut segments = vec![];
+        if legacy && text != "." {
+            // The leading dot of legacy paths is optional.
+            if !text.starts_with(['.', '[']) {
+                segments.push(Segment { descendant: false, selectors: vec![Selector::Name(parser.name()?)] });
+            }
+            segments.extend(parser.segments()?);
+        } else if !legacy {
+            segments = parser.segments()?;
+        }
+        if (legacy && text == ".") || parser.pos == text.len() {
+            Ok(Self { text: text.to_owned(), legacy, segments })
+        } else {
+            parser.error()
+        }
+    }
+
+    fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    fn select<'a>(&self, document: &'a Json) -> Vec<(Location, &'a Json)> {
+        select(&self.segments, document)
+    }
+
+    // The paths of the objects the member the path ends with is in, and its name, unless it ends
+    // with something else.
+    fn member(&self) -> Option<(&[Segment], &str)> {
+        let (last, parents) = self.segments.split_last()?;
+        match last.selectors.as_slice() {
+            [Selector::Name(name)] if !last.descendant => Some((parents, name)),
+            _ => None
+        }
+    }
+
+    fn not_found(&self) -> RESPError {
+        RESPError::JsonPathNotFound(self.text.to_owned())
+    }
+
+    // The first match of a legacy path, or all the matches of a JSONPath.
+    fn query(&self, document: &Json, legacy: bool) -> Result<Json, RESPError> {
+        let mut matches = self.select(document).into_iter().map(|(_, value)| value.clone());
+        if legacy {
+            matches.next().ok_or_else(|| self.not_found())
+        } else {
+            Ok(Json::Array(matches.collect()))
+        }
+    }
+}
+
+// The name of the type of a value, as JSON.TYPE of RedisJSON has it.
+fn type_name(value: &Json) -> &'static str {
+    match value {
+        Json::Null => "null",
+        Json::Bool(_) => "boolean",
+        Json::Number(n) if n.is_f64() => "number",
+        Json::Number(_) => "integer",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object"
+    }
+}
+
+// The nesting of a value, 0 for a scalar.
+fn depth(value: &Json) -> usize {
+    match value {
+        Json::Array(array) => 1 + array.iter().map(depth).max().unwrap_or(0),
+        Json::Object(map) => 1 + map.values().map(depth).max().unwrap_or(0),
+        _ => 0
+    }
+}
+
+fn parse_json(arg: &str) -> Result<Json, RESPError> {
+    serde_json::from_str(arg).map_err(|e| RESPError::InvalidJson(e.to_string()))
+}
+
+pub fn memory_usage(value: &Json, samples: usize) -> usize {
+    size_of::<Json>() + match value {
+        Json::String(s) => s.len(),
+        Json::Array(array) => sampled_size(array.len(), samples, array.iter().map(|element| memory_usage(element, samples))),
+        Json::Object(map) => sampled_size(map.len(), samples, map.iter().map(|(key, value)| {
+            size_of::<String>() + key.len() + ENTRY_OVERHEAD + memory_usage(value, samples)
+        })),
+        _ => 0
+    }
+}
+
+// The formatting of JSON.GET, every option being empty for compact JSON.
+#[derive(Default)]
+struct Format {
+    indent: String,
+    newline: String,
+    space: String,
+}
+
+impl Format {
+    fn write(&self, value: &Json, level: usize, out: &mut String) {
+        let (open, close, children) = match value {
+            Json::Array(array) => ('[', ']', array.iter().map(|child| (None, child)).collect::<Vec<_>>()),
+            Json::Object(map) => ('{', '}', map.iter().map(|(key, child)| (Some(key), child)).collect()),
+            scalar => return out.push_str(&scalar.to_string())
+        };
+        out.push(open);
+        for (i, (key, child)) in children.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&self.newline);
+            out.push_str(&self.indent.repeat(level + 1));
+            if let Some(key) = key {
+                out.push_str(&Json::from(key.as_str()).to_string());
+                out.push(':');
+                out.push_str(&self.space);
+            }
+            self.write(child, level + 1, out);
+        }
+        if !children.is_empty() {
+            out.push_str(&self.newline);
+            out.push_str(&self.indent.repeat(level));
+        }
+        out.push(close);
+    }
+
+    fn to_reply(&self, value: &Json) -> RESPValue {
+        let mut out = String::new();
+        self.write(value, 0, &mut out);
+        RESPValue::BlobString(out.into())
+    }
+}
+
+// JSON.SET key path value [NX | XX]
+pub fn set(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let path = Path::parse(&command[2])?;
+    let value = parse_json(&command[3])?;
+    let (nx, xx) = match command.get(4).map(|arg| arg.to_ascii_uppercase()).as_deref() {
+        None => (false, false),
+        Some("NX") => (true, false),
+        Some("XX") => (false, true),
+        Some(_) => return Err(RESPError::SyntaxError)
+    };
+    if command.len() > 5 {
+        return Err(RESPError::SyntaxError);
+    }
+    let depth = depth(&value);
+    if depth > MAX_DEPTH {
+        return Err(RESPError::JsonTooDeep);
+    }
+
+    {
+        let mut db = shared.db.lock();
+        let Some(document) = db.get(key) else {
+            if !path.is_root() {
+                return Err(RESPError::JsonNewAtRoot);
+            }
+            if xx {
+                return Ok(RESPValue::Null);
+            }
+            db.set(key.to_owned(), Value::Json(value));
+            drop(db);
+            notify_keyspace_event(shared, NOTIFY_MODULE, "json.set", key, 0);
+            return Ok(RESPValue::SimpleString(String::from("OK")));
+        };
+        let document = document.as_json()?;
+
+        // Either the values matched are replaced, or the member the path ends with is added to the
+        // objects it would be in.
+        let matched: Vec<Location> = path.select(document).into_iter().map(|(location, _)| location).collect();
+        let (locations, member) = if !matched.is_empty() {
+            if nx {
+                return Ok(RESPValue::Null);
+            }
+            (matched, None)
+        } else {
+            if xx {
+                return Ok(RESPValue::Null);
+            }
+            let Some((parents, name)) = path.member() else {
+                return Ok(RESPValue::Null);
+            };
+            let parents: Vec<Location> = select(parents, document).into_iter().filter(|(_, parent)| parent.is_object()).map(|(location, _)| location).collect();
+            if parents.is_empty() {
+                return if path.legacy { Err(path.not_found()) } else { Ok(RESPValue::Null) };
+            }
+            (parents, Some(name.to_owned()))
+        };
+        let nesting = depth + member.is_some() as usize;
+        if locations.iter().any(|location| location.len() + nesting > MAX_DEPTH) {
+            return Err(RESPError::JsonTooDeep);
+        }
+
+        let document = db.get_mut(key).unwrap().as_json_mut()?;
+        for location in &locations {
+            match (resolve_mut(document, location), &member) {
+                (Some(Json::Object(map)), Some(name)) => {
+                    map.insert(name.to_owned(), value.clone());
+                },
+                (Some(target), None) => *target = value.clone(),
+                _ => {}
+            }
+        }
+    }
+    notify_keyspace_event(shared, NOTIFY_MODULE, "json.set", key, 0);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// JSON.GET key [INDENT indent] [NEWLINE newline] [SPACE space] [path ...]
+pub fn get(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut format = Format::default();
+    let mut i = 2;
+    while let Some(arg) = command.get(i) {
+        let option = match arg.to_ascii_uppercase().as_str() {
+            "INDENT" => &mut format.indent,
+            "NEWLINE" => &mut format.newline,
+            "SPACE" => &mut format.space,
+            _ => break
+        };
+        *option = command.get(i + 1).ok_or(RESPError::SyntaxError)?.to_string();
+        i += 2;
+    }
+    let paths = command[i..].iter().map(|path| Path::parse(path)).collect::<Result<Vec<_>, _>>()?;
+
+    let db = shared.db.lock();
+    let Some(value) = db.get(command[1].as_str()) else {
+        return Ok(RESPValue::Null);
+    };
+    let document = value.as_json()?;
+    let reply = match paths.as_slice() {
+        [] => document.clone(),
+        [path] => path.query(document, path.legacy)?,
+        // An object of the results of the paths, all of them being JSONPath ones unless none is.
+        paths => {
+            let legacy = paths.iter().all(|path| path.legacy);
+            Json::Object(paths.iter().map(|path| Ok((path.text.to_owned(), path.query(document, legacy)?))).collect::<Result<_, RESPError>>()?)
+        }
+    };
+    Ok(format.to_reply(&reply))
+}
+
+// JSON.DEL key [path]
+pub fn del(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    if command.len() > 3 {
+        return Err(RESPError::SyntaxError);
+    }
+    let key = command[1].as_str();
+    let path = Path::parse(command.get(2).map_or("$", |path| path.as_str()))?;
+
+    let deleted = {
+        let mut db = shared.db.lock();
+        let Some(value) = db.get(key) else {
+            return Ok(RESPValue::Number(0));
+        };
+        let document = value.as_json()?;
+        if path.is_root() {
+            db.remove(key);
+            1
+        } else {
+            let mut matched: Vec<Location> = path.select(document).into_iter().map(|(location, _)| location).collect();
+            // Sorted, the values within another one follow it, and deleting it deletes them too.
+            matched.sort();
+            let mut locations: Vec<Location> = vec![];
+            for location in matched {
+                if !locations.last().is_some_and(|last| location.starts_with(last)) {
+                    locations.push(location);
+                }
+            }
+            if !locations.is_empty() {
+                let document = db.get_mut(key).unwrap().as_json_mut()?;
+                // Backwards, so the indexes of the elements left to delete don't shift.
+                for location in locations.iter().rev() {
+                    let (step, parent) = location.split_last().unwrap();
+                    match (resolve_mut(document, parent), step) {
+                        (Some(Json::Object(map)), Step::Key(key)) => {
+                            map.shift_remove(key);
+                        },
+                        (Some(Json::Array(array)), Step::Index(i)) => {
+                            array.remove(*i);
+                        },
+                        _ => {}
+                    }
+                }
+            }
+            locations.len()
+        }
+    };
+    if deleted > 0 {
+        notify_keyspace_event(shared, NOTIFY_MODULE, "json.del", key, 0);
+    }
+    Ok(RESPValue::Number(deleted as i64))
+}
+
+fn add(a: &Number, b: &Number) -> Result<Number, RESPError> {
+    if let Some(sum) = a.as_i64().zip(b.as_i64()).and_then(|(a, b)| a.checked_add(b)) {
+        return Ok(sum.into());
+    }
+    Number::from_f64(a.as_f64().unwrap_or(f64::NAN) + b.as_f64().unwrap_or(f64::NAN)).ok_or(RESPError::JsonNumberOverflow)
+}
+
+// JSON.NUMINCRBY key path value
+pub fn numincrby(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let path = Path::parse(&command[2])?;
+    let Ok(Json::Number(by)) = parse_json(&command[3]) else {
+        return Err(RESPError::NotAFloat);
+    };
+
+    let results = {
+        let mut db = shared.db.lock();
+        let document = db.get(key).ok_or(RESPError::NoSuchKey)?.as_json()?;
+        let matched = path.select(document);
+        if path.legacy {
+            match matched.iter().find(|(_, value)| !value.is_number()) {
+                _ if matched.is_empty() => return Err(path.not_found()),
+                Some((_, value)) => return Err(RESPError::JsonWrongType("number", type_name(value))),
+                None => {}
+            }
+        }
+        let results = matched.into_iter().map(|(location, value)| match value {
+            Json::Number(n) => Ok((location, Some(add(n, &by)?))),
+            _ => Ok((location, None))
+        }).collect::<Result<Vec<_>, RESPError>>()?;
+
+        if results.iter().any(|(_, result)| result.is_some()) {
+            let document = db.get_mut(key).unwrap().as_json_mut()?;
+            for (location, result) in &results {
+                if let (Some(target), Some(result)) = (resolve_mut(document, location), result) {
+                    *target = Json::Number(result.clone());
+                }
+            }
+        }
+        results.into_iter().map(|(_, result)| result.map_or(Json::Null, Json::Number)).collect::<Vec<_>>()
+    };
+    if results.iter().any(Json::is_number) {
+        notify_keyspace_event(shared, NOTIFY_MODULE, "json.numincrby", key, 0);
+    }
+    let reply = if path.legacy { results.last().unwrap().to_string() } else { Json::Array(results).to_string() };
+    Ok(RESPValue::BlobString(reply.into()))
+}
+
+// JSON.ARRAPPEND key path value [value ...]
+pub fn arrappend(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let path = Path::parse(&command[2])?;
+    let values = command[3..].iter().map(|value| parse_json(value)).collect::<Result<Vec<_>, _>>()?;
+    let depth = values.iter().map(depth).max().unwrap_or(0);
+
+    let lengths = {
+        let mut db = shared.db.lock();
+        let document = db.get(key).ok_or(RESPError::NoSuchKey)?.as_json()?;
+        let matched = path.select(document);
+        if path.legacy {
+            match matched.iter().find(|(_, value)| !value.is_array()) {
+                _ if matched.is_empty() => return Err(path.not_found()),
+                Some((_, value)) => return Err(RESPError::JsonWrongType("array", type_name(value))),
+                None => {}
+            }
+        }
+        let arrays: Vec<Option<Location>> = matched.into_iter().map(|(location, value)| value.is_array().then_some(location)).collect();
+        if arrays.iter().flatten().any(|location| location.len() + 1 + depth > MAX_DEPTH) {
+            return Err(RESPError::JsonTooDeep);
+        }
+
+        if arrays.iter().all(Option::is_none) {
+            vec![None; arrays.len()]
+        } else {
+            let document = db.get_mut(key).unwrap().as_json_mut()?;
+            arrays.into_iter().map(|location| {
+                let array = resolve_mut(document, &location?)?.as_array_mut()?;
+                array.extend(values.iter().cloned());
+                Some(array.len())
+            }).collect()
+        }
+    };
+    if lengths.iter().any(Option::is_some) {
+        notify_keyspace_event(shared, NOTIFY_MODULE, "json.arrappend", key, 0);
+    }
+    let length = |length: Option<usize>| length.map_or(RESPValue::Null, |length| RESPValue::Number(length as i64));
+    Ok(if path.legacy { length(*lengths.last().unwrap()) } else { RESPValue::Array(lengths.into_iter().map(length).collect()) })
+}
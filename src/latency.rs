@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::arg::Arg;
+use crate::stats::Stats;
+use crate::{RESPError, RESPValue, SharedState};
+
+// Only the latest samples of every event are kept, like in Redis.
+const HISTORY_LENGTH: usize = 160;
+
+struct Sample {
+    // Unix time in seconds.
+    time: u64,
+    // In milliseconds.
+    latency: u64,
+}
+
+#[derive(Default)]
+struct History {
+    samples: VecDeque<Sample>,
+    max: u64,
+}
+
+// Counts latencies in power of two buckets of microseconds, an HDR histogram of a single
+// significant bit.
+pub struct Histogram {
+    buckets: [u64; 64],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets: [0; 64] }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let usec = latency.as_micros().max(1) as u64;
+        self.buckets[(u64::BITS - 1 - usec.leading_zeros()) as usize] += 1;
+    }
+
+    // The cumulative counts of latencies below every power of two, from the lowest to the highest
+    // bucket recorded.
+    fn cumulative(&self) -> Vec<(u64, u64)> {
+        let first = self.buckets.iter().position(|count| *count > 0);
+        let last = self.buckets.iter().rposition(|count| *count > 0);
+        let (first, last) = match first.zip(last) {
+            Some(range) => range,
+            None => return vec![]
+        };
+
+        let mut total = 0;
+        (first..=last).map(|i| {
+            total += self.buckets[i];
+            (1 << (i + 1), total)
+        }).collect()
+    }
+}
+
+// Latency spikes of internal events (like slow commands), kept per event.
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: Mutex<BTreeMap<&'static str, History>>,
+}
+
+// Records the event when it took at least latency-monitor-threshold milliseconds, a threshold of 0
+// disabling the monitor.
+pub fn record(shared: &SharedState, event: &'static str, latency: Duration) {
+    let threshold = shared.config.get_int("latency-monitor-threshold") as u128;
+    let latency = latency.as_millis();
+    if threshold == 0 || latency < threshold {
+        return;
+    }
+
+    let latency = latency as u64;
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut events = shared.latency.events.lock().unwrap();
+    let history = events.entry(event).or_default();
+    history.max = history.max.max(latency);
+    // Spikes within the same second are a single sample of the highest latency.
+    match history.samples.back_mut() {
+        Some(last) if last.time == time => last.latency = last.latency.max(latency),
+        _ => {
+            if history.samples.len() == HISTORY_LENGTH {
+                history.samples.pop_front();
+            }
+            history.samples.push_back(Sample { time, latency });
+        }
+    }
+}
+
+// LATENCY LATEST
+// LATENCY HISTORY event
+// LATENCY RESET [event [event ...]]
+// LATENCY HISTOGRAM [command [command ...]]
+pub fn latency(command: &[Arg], monitor: &LatencyMonitor, stats: &Stats) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("LATENCY|{}", subcommand)))
+    };
+    let mut events = monitor.events.lock().unwrap();
+
+    match subcommand.as_str() {
+        "LATEST" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::Array(events.iter()
+                .filter_map(|(event, history)| {
+                    let last = history.samples.back()?;
+                    Some(RESPValue::Array(vec![
+                        RESPValue::BlobString(event.to_string().into()),
+                        RESPValue::Number(last.time as i64),
+                        RESPValue::Number(last.latency as i64),
+                        RESPValue::Number(history.max as i64),
+                    ]))
+                })
+                .collect()))
+        },
+        "HISTORY" => {
+            check_arity(command.len() == 3)?;
+            let samples = events.get(command[2].as_str()).map_or(vec![], |history| history.samples.iter()
+                .map(|sample| RESPValue::Array(vec![RESPValue::Number(sample.time as i64), RESPValue::Number(sample.latency as i64)]))
+                .collect());
+            Ok(RESPValue::Array(samples))
+        },
+        "RESET" => {
+            let reset = if command.len() == 2 {
+                std::mem::take(&mut *events).len()
+            } else {
+                command[2..].iter().filter(|event| events.remove(event.as_str()).is_some()).count()
+            };
+            Ok(RESPValue::Number(reset as i64))
+        },
+        "HISTOGRAM" => {
+            let requested: Vec<String> = command[2..].iter().map(|name| name.to_ascii_uppercase()).collect();
+            let histograms = stats.commands.lock().unwrap().iter()
+                .filter(|(name, _)| requested.is_empty() || requested.iter().any(|requested| requested == *name))
+                .flat_map(|(name, command_stats)| {
+                    let buckets = command_stats.histogram.cumulative().into_iter()
+                        .flat_map(|(bound, count)| [RESPValue::Number(bound as i64), RESPValue::Number(count as i64)])
+                        .collect();
+                    [
+                        RESPValue::BlobString(name.to_ascii_lowercase().into()),
+                        RESPValue::Array(vec![
+                            RESPValue::BlobString("calls".into()),
+                            RESPValue::Number(command_stats.calls as i64),
+                            RESPValue::BlobString("histogram_usec".into()),
+                            RESPValue::Array(buckets),
+                        ]),
+                    ]
+                })
+                .collect();
+            Ok(RESPValue::Array(histograms))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("LATENCY {}", command[1])))
+    }
+}
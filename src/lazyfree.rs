@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+
+use serde_json::Value as Json;
+
+use crate::db::{Shard, Value};
+use crate::SharedState;
+
+// Values with more elements than this are freed on the lazyfree thread when asked to, smaller ones
+// are faster to free right away than to hand over.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+#[derive(Default)]
+struct Counters {
+    pending: AtomicU64,
+    freed: AtomicU64,
+}
+
+// A thread freeing big values off the event loop, like the lazyfree background job of Redis.
+pub struct LazyFree {
+    sender: Sender<Box<dyn Send>>,
+    counters: Arc<Counters>,
+}
+
+impl Default for LazyFree {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel::<Box<dyn Send>>();
+        let counters = Arc::new(Counters::default());
+        let thread_counters = counters.clone();
+        std::thread::Builder::new().name(String::from("lazyfree")).spawn(move || {
+            for object in receiver {
+                drop(object);
+                thread_counters.pending.fetch_sub(1, Ordering::Relaxed);
+                thread_counters.freed.fetch_add(1, Ordering::Relaxed);
+            }
+        }).expect("Failed spawning the lazyfree thread");
+        Self { sender, counters }
+    }
+}
+
+impl LazyFree {
+    fn free_in_background(&self, object: Box<dyn Send>) {
+        self.counters.pending.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(object).unwrap();
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.counters.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn freed(&self) -> u64 {
+        self.counters.freed.load(Ordering::Relaxed)
+    }
+}
+
+// Roughly the allocations that freeing the value takes.
+fn free_effort(value: &Value) -> usize {
+    match value {
+        Value::String(_) | Value::CompressedString(_) | Value::Bloom(_) | Value::Cuckoo(_) => 1,
+        Value::List(list) => list.len(),
+        Value::SortedSet(set) => set.len(),
+        Value::Hash(hash) => hash.len(),
+        Value::Stream(stream) => stream.len(),
+        Value::Json(Json::Array(array)) => array.len(),
+        Value::Json(Json::Object(map)) => map.len(),
+        Value::Json(_) => 1,
+    }
+}
+
+// Frees the value, on the lazyfree thread when `lazy` and it's big enough to be worth it.
+pub fn free(value: Value, lazy: bool, shared: &SharedState) {
+    if lazy && free_effort(&value) > LAZYFREE_THRESHOLD {
+        shared.lazyfree.free_in_background(Box::new(value));
+    }
+}
+
+// Frees the keys of a flushed keyspace, on the lazyfree thread when `lazy`.
+pub fn free_db(shards: Vec<Shard>, lazy: bool, shared: &SharedState) {
+    if lazy && shards.iter().any(|shard| shard.len() > 0) {
+        shared.lazyfree.free_in_background(Box::new(shards));
+    }
+}
+
+// Frees a value the server deleted or overwrote on its own (like the destination of a STORE),
+// returning whether there was one.
+pub fn free_replaced(old: Option<Value>, shared: &SharedState) -> bool {
+    match old {
+        Some(old) => {
+            free(old, shared.config.get_bool("lazyfree-lazy-server-del"), shared);
+            true
+        },
+        None => false
+    }
+}
@@ -0,0 +1,3000 @@
+//! bast, a Redis compatible in-memory data store.
+//!
+//! - [`Server`]: runs the server, as its own process or embedded in an application.
+//! - [`LocalClient`]: runs commands on an embedded server in-process, without a connection.
+//! - [`protocol`]: the RESP codec the server reads requests and writes replies with, which is useful
+//!   to clients, proxies and test tools as well.
+
+mod acl;
+mod arg;
+mod actors;
+mod aof;
+mod audit;
+mod backing;
+mod bitmap;
+mod bloom;
+mod check;
+mod clients;
+mod cluster;
+mod compression;
+mod config;
+mod crc64;
+mod cron;
+mod cuckoo;
+mod daemon;
+mod db;
+mod debug;
+mod dump;
+mod encryption;
+mod eviction;
+mod export;
+mod expire;
+mod geo;
+mod health;
+mod glob;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hash;
+mod hyperloglog;
+mod json;
+mod latency;
+mod lazyfree;
+mod list;
+mod listpack;
+mod logging;
+mod memory;
+mod metrics;
+mod migrate;
+mod notify;
+mod otel;
+pub mod plugin;
+pub mod protocol;
+mod pubsub;
+#[cfg(feature = "quic")]
+mod quic;
+mod ratelimit;
+mod rdb;
+mod replication;
+mod replies;
+mod rest;
+mod scripting;
+mod sentinel;
+mod server;
+mod shutdown;
+mod snapshot;
+mod sort;
+mod sorted_set;
+mod stats;
+mod storage;
+mod stream;
+mod string;
+mod systemd;
+mod tiering;
+mod timer_wheel;
+mod tls;
+mod tracking;
+mod uring;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use backing::Backing;
+pub use server::{Builder, Handle, LocalClient, Server};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{watch, Notify};
+use tokio_util::codec::FramedRead;
+use futures::{StreamExt, SinkExt};
+use tracing::Instrument;
+
+use acl::Acl;
+use arg::Arg;
+use actors::Actors;
+use aof::Aof;
+use audit::AuditLog;
+use clients::{ClientInfo, ClientRegistry, ReplyMode};
+use cluster::Cluster;
+use latency::LatencyMonitor;
+use lazyfree::LazyFree;
+use otel::Otel;
+use health::Health;
+use ratelimit::{Buckets, RateLimits};
+use config::Config;
+use logging::Logger;
+use db::{Keyspace, Owned};
+use plugin::{CommandRegistry, Database};
+use protocol::{Limits, ProtocolError, RESPCodec, RESPValue};
+use replication::{ReplConf, ReplicaLink, Replication};
+use snapshot::Snapshots;
+use notify::{notify_keyspace_event, NOTIFY_GENERIC};
+use pubsub::{subscription_reply, ClientId, PubSub};
+use replies::ReplyWriter;
+use scripting::{Library, ScriptMonitor};
+use sentinel::Sentinel;
+use stats::Stats;
+use tracking::Tracking;
+
+#[derive(Debug)]
+pub enum RESPError {
+    Protocol(ProtocolError),
+    InvalidRequest(&'static str),
+    NonUtf8Argument(String),
+    WrongNumberOfArguments(String),
+    UnsupportedCommand(String),
+    UnsupportedConfigParameter(String),
+    InvalidConfigValue(String, String),
+    NoConfigFile,
+    NoSuchClient,
+    InvalidClientName,
+    UnknownClientType(String),
+    InvalidTrackingOptions(&'static str),
+    ConfigRewriteFailed(String),
+    NestedMulti,
+    ExecWithoutMulti,
+    DiscardWithoutMulti,
+    WatchInsideMulti,
+    ExecAbort,
+    NotAnInteger,
+    IncrementOverflow,
+    NegativeNumKeys,
+    TooManyNumKeys,
+    NoScript,
+    NotAllowedFromScript,
+    ScriptError(String),
+    MissingLibraryMetadata,
+    InvalidLibraryMetadata(String),
+    UnsupportedEngine(String),
+    NoFunctionsRegistered,
+    LibraryExists(String),
+    FunctionExists(String),
+    LibraryNotFound,
+    FunctionNotFound,
+    WriteFromReadOnlyScript,
+    WriteFlagInReadOnlyCall,
+    Busy,
+    NotBusy,
+    Unkillable,
+    SyntaxError,
+    CommandExists(String),
+    PluginLoadError(String),
+    WasmError(String),
+    WrongType,
+    InvalidStreamId,
+    StreamIdTooSmall,
+    StreamIdZero,
+    NegativeMaxLen,
+    NegativeTimeout,
+    UnbalancedStreams(String),
+    NoStreamForGroup,
+    GroupExists,
+    NoGroup(String, String),
+    NonPositiveCount,
+    InvalidHyperLogLog,
+    CorruptedHyperLogLog,
+    InvalidBitOffset,
+    InvalidBit,
+    BitopNotSingleKey,
+    InvalidBitfieldType,
+    InvalidOverflowType,
+    NotAFloat,
+    InvalidGeoPosition(f64, f64),
+    UnsupportedGeoUnit,
+    NxAndXx,
+    GeoSearchFrom,
+    GeoSearchBy,
+    NegativeRadius,
+    AnyWithoutCount,
+    UndecodableMember,
+    InvalidSortScore,
+    NoAuth,
+    AuthNotConfigured,
+    WrongPass,
+    NoPermission(String, String),
+    NoKeyPermission,
+    SortPatternsDenied,
+    InvalidAclRule(String, String),
+    UnknownAclCategory(String),
+    DeleteDefaultUser,
+    InvalidCommandSpecified,
+    InvalidCommandArguments,
+    NoKeyArguments,
+    OutOfMemory,
+    InvalidExpireTime(String),
+    UnsupportedOption(String),
+    NxAndXxGtLt,
+    GtAndLt,
+    InvalidFieldExpireTime,
+    MissingFields,
+    InvalidNumFields,
+    NumFieldsMismatch,
+    BackgroundSaveInProgress,
+    AofRewriteInProgress,
+    BusyKey,
+    InvalidTtl,
+    InvalidIdleTime,
+    InvalidFrequency,
+    BadDumpPayload,
+    BadDataFormat,
+    MigrateKeyWithKeys,
+    MigrateIOError(String),
+    TargetError(String),
+    InvalidMasterPort,
+    UnrecognizedReplConfOption(String),
+    NoPrimaryLink,
+    WaitOnReplica,
+    InvalidFailover(&'static str),
+    ReadOnlyReplica,
+    ReadOnlyConnection,
+    SubscribedContext(String),
+    NoSuchMaster,
+    FailoverInProgress,
+    NoGoodReplica,
+    NoQuorum(String),
+    Moved(usize, String),
+    Ask(usize, String),
+    TryAgain,
+    CrossSlot,
+    ClusterDown(&'static str),
+    ClusterDisabled,
+    ClusterError(String),
+    ReplicaOfInCluster,
+    ProtectedMode,
+    DebugNotAllowed,
+    NoSuchKey,
+    DatabaseOutOfRange,
+    MaxClients,
+    Throttled,
+    ShutdownFailed,
+    ServerStopped,
+    BackingStore(String),
+    ItemExists,
+    FilterNotFound,
+    BadErrorRate,
+    BadCapacity,
+    BadExpansion,
+    NonScalingExpansion,
+    BadBucketSize,
+    BadMaxIterations,
+    FilterTooLarge,
+    NonScalingFilterFull,
+    FilterFull,
+    BadJsonPath(String),
+    InvalidJson(String),
+    JsonPathNotFound(String),
+    JsonNewAtRoot,
+    JsonWrongType(&'static str, &'static str),
+    JsonTooDeep,
+    JsonNumberOverflow,
+    IOError(std::io::Error),
+}
+
+impl std::fmt::Display for RESPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RESPError::Protocol(e) => write!(f, "ERR {}", e),
+            RESPError::InvalidRequest(reason) => write!(f, "ERR Protocol error: {}", reason),
+            RESPError::NonUtf8Argument(command) => write!(f, "ERR invalid UTF-8 in the arguments of '{}' command", command.to_lowercase()),
+            RESPError::WrongNumberOfArguments(command) => write!(f, "ERR wrong number of arguments for '{}' command", command.to_lowercase()),
+            RESPError::UnsupportedCommand(command) => write!(f, "ERR unknown command '{}'", command),
+            RESPError::UnsupportedConfigParameter(name) => write!(f, "ERR Unknown option or number of arguments for CONFIG SET - '{}'", name),
+            RESPError::InvalidConfigValue(name, reason) => write!(f, "ERR CONFIG SET failed (possibly related to argument '{}') - {}", name, reason),
+            RESPError::NoConfigFile => write!(f, "ERR The server is running without a config file"),
+            RESPError::NoSuchClient => write!(f, "ERR No such client"),
+            RESPError::InvalidClientName => write!(f, "ERR Client names cannot contain spaces, newlines or special characters."),
+            RESPError::UnknownClientType(client_type) => write!(f, "ERR Unknown client type '{}'", client_type),
+            RESPError::InvalidTrackingOptions(reason) => write!(f, "ERR {}", reason),
+            RESPError::ConfigRewriteFailed(reason) => write!(f, "ERR Rewriting config file: {}", reason),
+            RESPError::NestedMulti => write!(f, "ERR MULTI calls can not be nested"),
+            RESPError::ExecWithoutMulti => write!(f, "ERR EXEC without MULTI"),
+            RESPError::DiscardWithoutMulti => write!(f, "ERR DISCARD without MULTI"),
+            RESPError::WatchInsideMulti => write!(f, "ERR WATCH inside MULTI is not allowed"),
+            RESPError::ExecAbort => write!(f, "EXECABORT Transaction discarded because of previous errors."),
+            RESPError::NotAnInteger => write!(f, "ERR value is not an integer or out of range"),
+            RESPError::IncrementOverflow => write!(f, "ERR increment or decrement would overflow"),
+            RESPError::NegativeNumKeys => write!(f, "ERR Number of keys can't be negative"),
+            RESPError::TooManyNumKeys => write!(f, "ERR Number of keys can't be greater than number of args"),
+            RESPError::NoScript => write!(f, "NOSCRIPT No matching script. Please use EVAL."),
+            RESPError::NotAllowedFromScript => write!(f, "ERR This Redis command is not allowed from script"),
+            RESPError::ScriptError(message) => {
+                // Errors raised by redis.call already carry an error code, keep them as is.
+                let code = message.split(' ').next().unwrap_or_default();
+                let has_code = !code.is_empty() && code.bytes().all(|c| c.is_ascii_uppercase());
+                if has_code {
+                    write!(f, "{}", message)
+                } else {
+                    write!(f, "ERR Error running script: {}", message)
+                }
+            },
+            RESPError::MissingLibraryMetadata => write!(f, "ERR Missing library metadata"),
+            RESPError::InvalidLibraryMetadata(part) => write!(f, "ERR Invalid metadata value given: {}", part),
+            RESPError::UnsupportedEngine(engine) => write!(f, "ERR Engine '{}' not found", engine),
+            RESPError::NoFunctionsRegistered => write!(f, "ERR No functions registered"),
+            RESPError::LibraryExists(name) => write!(f, "ERR Library '{}' already exists", name),
+            RESPError::FunctionExists(name) => write!(f, "ERR Function {} already exists", name),
+            RESPError::LibraryNotFound => write!(f, "ERR Library not found"),
+            RESPError::FunctionNotFound => write!(f, "ERR Function not found"),
+            RESPError::WriteFromReadOnlyScript => write!(f, "ERR Write commands are not allowed from read-only scripts."),
+            RESPError::WriteFlagInReadOnlyCall => write!(f, "ERR Can not execute a script with write flag using *_ro command."),
+            RESPError::Busy => write!(f, "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."),
+            RESPError::NotBusy => write!(f, "NOTBUSY No scripts in execution right now."),
+            RESPError::Unkillable => write!(f, "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command."),
+            RESPError::SyntaxError => write!(f, "ERR syntax error"),
+            RESPError::CommandExists(name) => write!(f, "ERR command '{}' already exists", name),
+            RESPError::PluginLoadError(e) => write!(f, "ERR Error loading the extension: {}", e),
+            RESPError::WasmError(e) => write!(f, "ERR Error running WebAssembly command: {}", e.lines().next().unwrap_or_default()),
+            RESPError::WrongType => write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value"),
+            RESPError::InvalidStreamId => write!(f, "ERR Invalid stream ID specified as stream command argument"),
+            RESPError::StreamIdTooSmall => write!(f, "ERR The ID specified in XADD is equal or smaller than the target stream top item"),
+            RESPError::StreamIdZero => write!(f, "ERR The ID specified in XADD must be greater than 0-0"),
+            RESPError::NegativeMaxLen => write!(f, "ERR The MAXLEN argument must be >= 0."),
+            RESPError::NegativeTimeout => write!(f, "ERR timeout is negative"),
+            RESPError::UnbalancedStreams(command) => write!(f, "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.", command.to_lowercase()),
+            RESPError::NoStreamForGroup => write!(f, "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."),
+            RESPError::GroupExists => write!(f, "BUSYGROUP Consumer Group name already exists"),
+            RESPError::NoGroup(key, group) => write!(f, "NOGROUP No such key '{}' or consumer group '{}'", key, group),
+            RESPError::NonPositiveCount => write!(f, "ERR COUNT must be > 0"),
+            RESPError::InvalidHyperLogLog => write!(f, "WRONGTYPE Key is not a valid HyperLogLog string value."),
+            RESPError::CorruptedHyperLogLog => write!(f, "INVALIDOBJ Corrupted HLL object detected"),
+            RESPError::InvalidBitOffset => write!(f, "ERR bit offset is not an integer or out of range"),
+            RESPError::InvalidBit => write!(f, "ERR bit is not an integer or out of range"),
+            RESPError::BitopNotSingleKey => write!(f, "ERR BITOP NOT must be called with a single source key."),
+            RESPError::InvalidBitfieldType => write!(f, "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is."),
+            RESPError::InvalidOverflowType => write!(f, "ERR Invalid OVERFLOW type specified"),
+            RESPError::NotAFloat => write!(f, "ERR value is not a valid float"),
+            RESPError::InvalidGeoPosition(lon, lat) => write!(f, "ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat),
+            RESPError::UnsupportedGeoUnit => write!(f, "ERR unsupported unit provided. please use M, KM, FT, MI"),
+            RESPError::NxAndXx => write!(f, "ERR XX and NX options at the same time are not compatible"),
+            RESPError::GeoSearchFrom => write!(f, "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"),
+            RESPError::GeoSearchBy => write!(f, "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH"),
+            RESPError::NegativeRadius => write!(f, "ERR radius cannot be negative"),
+            RESPError::AnyWithoutCount => write!(f, "ERR the ANY argument requires COUNT argument"),
+            RESPError::UndecodableMember => write!(f, "ERR could not decode requested zset member"),
+            RESPError::InvalidSortScore => write!(f, "ERR One or more scores can't be converted into double"),
+            RESPError::NoAuth => write!(f, "NOAUTH Authentication required."),
+            RESPError::AuthNotConfigured => write!(f, "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"),
+            RESPError::WrongPass => write!(f, "WRONGPASS invalid username-password pair or user is disabled."),
+            RESPError::NoPermission(user, command) => write!(f, "NOPERM User {} has no permissions to run the '{}' command", user, command.to_lowercase()),
+            RESPError::NoKeyPermission => write!(f, "NOPERM No permissions to access a key"),
+            RESPError::SortPatternsDenied => write!(f, "ERR BY/GET option of SORT denied due to insufficient ACL permissions"),
+            RESPError::InvalidAclRule(rule, reason) => write!(f, "ERR Error in ACL SETUSER modifier '{}': {}", rule, reason),
+            RESPError::UnknownAclCategory(category) => write!(f, "ERR Unknown category '{}'", category),
+            RESPError::DeleteDefaultUser => write!(f, "ERR The 'default' user cannot be removed"),
+            RESPError::InvalidCommandSpecified => write!(f, "ERR Invalid command specified"),
+            RESPError::InvalidCommandArguments => write!(f, "ERR Invalid number of arguments specified for command"),
+            RESPError::NoKeyArguments => write!(f, "ERR The command has no key arguments"),
+            RESPError::OutOfMemory => write!(f, "OOM command not allowed when used memory > 'maxmemory'."),
+            RESPError::InvalidExpireTime(command) => write!(f, "ERR invalid expire time in '{}' command", command.to_lowercase()),
+            RESPError::UnsupportedOption(option) => write!(f, "ERR Unsupported option {}", option),
+            RESPError::NxAndXxGtLt => write!(f, "ERR NX and XX, GT or LT options at the same time are not compatible"),
+            RESPError::GtAndLt => write!(f, "ERR GT and LT options at the same time are not compatible"),
+            RESPError::InvalidFieldExpireTime => write!(f, "ERR invalid expire time, must be >= 0 and <= {}", hash::MAX_EXPIRE_TIME),
+            RESPError::MissingFields => write!(f, "ERR Mandatory argument FIELDS is missing or not at the right position"),
+            RESPError::InvalidNumFields => write!(f, "ERR Parameter `numFields` should be greater than 0"),
+            RESPError::NumFieldsMismatch => write!(f, "ERR The `numfields` parameter must match the number of arguments"),
+            RESPError::BackgroundSaveInProgress => write!(f, "ERR Background save already in progress"),
+            RESPError::AofRewriteInProgress => write!(f, "ERR Background append only file rewriting already in progress"),
+            RESPError::BusyKey => write!(f, "BUSYKEY Target key name already exists."),
+            RESPError::InvalidTtl => write!(f, "ERR Invalid TTL value, must be >= 0"),
+            RESPError::InvalidIdleTime => write!(f, "ERR Invalid IDLETIME value, must be >= 0"),
+            RESPError::InvalidFrequency => write!(f, "ERR Invalid FREQ value, must be >= 0 and <= 255"),
+            RESPError::BadDumpPayload => write!(f, "ERR DUMP payload version or checksum are wrong"),
+            RESPError::BadDataFormat => write!(f, "ERR Bad data format"),
+            RESPError::MigrateKeyWithKeys => write!(f, "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"),
+            RESPError::MigrateIOError(step) => write!(f, "IOERR error or timeout {} target instance", step),
+            RESPError::TargetError(e) => write!(f, "ERR Target instance replied with error: {}", e),
+            RESPError::InvalidMasterPort => write!(f, "ERR Invalid master port"),
+            RESPError::UnrecognizedReplConfOption(option) => write!(f, "ERR Unrecognized REPLCONF option: {}", option),
+            RESPError::NoPrimaryLink => write!(f, "NOMASTERLINK Can't SYNC while not connected with my master"),
+            RESPError::WaitOnReplica => write!(f, "ERR WAIT cannot be used with replica instances."),
+            RESPError::InvalidFailover(reason) => write!(f, "ERR {}", reason),
+            RESPError::ReadOnlyReplica => write!(f, "READONLY You can't write against a read only replica."),
+            RESPError::ReadOnlyConnection => write!(f, "READONLY You can't write on a connection in READONLY mode."),
+            RESPError::SubscribedContext(command) => write!(f, "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", command),
+            RESPError::NoSuchMaster => write!(f, "ERR No such master with that name"),
+            RESPError::FailoverInProgress => write!(f, "INPROG Failover already in progress"),
+            RESPError::NoGoodReplica => write!(f, "NOGOODSLAVE No suitable replica to promote"),
+            RESPError::NoQuorum(reason) => write!(f, "NOQUORUM {}", reason),
+            RESPError::Moved(slot, addr) => write!(f, "MOVED {} {}", slot, addr),
+            RESPError::Ask(slot, addr) => write!(f, "ASK {} {}", slot, addr),
+            RESPError::TryAgain => write!(f, "TRYAGAIN Multiple keys request during rehashing of slot"),
+            RESPError::CrossSlot => write!(f, "CROSSSLOT Keys in request don't hash to the same slot"),
+            RESPError::ClusterDown(reason) => write!(f, "CLUSTERDOWN {}", reason),
+            RESPError::ClusterDisabled => write!(f, "ERR This instance has cluster support disabled"),
+            RESPError::ClusterError(reason) => write!(f, "ERR {}", reason),
+            RESPError::ReplicaOfInCluster => write!(f, "ERR REPLICAOF not allowed in cluster mode."),
+            RESPError::Throttled => write!(f, "THROTTLED Rate limit exceeded, slow down"),
+            RESPError::ShutdownFailed => write!(f, "ERR Errors trying to SHUTDOWN. Check logs."),
+            RESPError::ServerStopped => write!(f, "ERR The server stopped"),
+            RESPError::BackingStore(e) => write!(f, "ERR The backing store failed: {}", e),
+            RESPError::ItemExists => write!(f, "ERR item exists"),
+            RESPError::FilterNotFound => write!(f, "ERR not found"),
+            RESPError::BadErrorRate => write!(f, "ERR (0 < error rate range < 1)"),
+            RESPError::BadCapacity => write!(f, "ERR Bad capacity"),
+            RESPError::BadExpansion => write!(f, "ERR Bad expansion"),
+            RESPError::NonScalingExpansion => write!(f, "ERR Nonscaling filters cannot expand"),
+            RESPError::BadBucketSize => write!(f, "ERR Bad bucket size"),
+            RESPError::BadMaxIterations => write!(f, "ERR Bad max iterations"),
+            RESPError::FilterTooLarge => write!(f, "ERR filter too large for its capacity and error rate"),
+            RESPError::NonScalingFilterFull => write!(f, "ERR non scaling filter is full"),
+            RESPError::FilterFull => write!(f, "ERR Filter is full"),
+            RESPError::BadJsonPath(path) => write!(f, "ERR Invalid JSONPath '{}'", path),
+            RESPError::InvalidJson(e) => write!(f, "ERR Invalid JSON: {}", e),
+            RESPError::JsonPathNotFound(path) => write!(f, "ERR Path '{}' does not exist", path),
+            RESPError::JsonNewAtRoot => write!(f, "ERR new objects must be created at the root"),
+            RESPError::JsonWrongType(expected, found) => write!(f, "WRONGTYPE wrong type of path value - expected {} but found {}", expected, found),
+            RESPError::JsonTooDeep => write!(f, "ERR the document would be nested too deep"),
+            RESPError::JsonNumberOverflow => write!(f, "ERR result is not a finite number"),
+            RESPError::MaxClients => write!(f, "ERR max number of clients reached"),
+            RESPError::DebugNotAllowed => write!(f, "ERR DEBUG command not allowed. If the enable-debug-command option is set to \"local\", \
+                you can run it from a local connection, otherwise you need to set this option in the configuration file, and then \
+                restart the server."),
+            RESPError::NoSuchKey => write!(f, "ERR no such key"),
+            RESPError::DatabaseOutOfRange => write!(f, "ERR Out of range database"),
+            RESPError::ProtectedMode => write!(f, "DENIED bast is running in protected mode because protected mode is enabled and no \
+                password is set for the default user. In this mode connections are only accepted from the loopback interface. If you \
+                want to connect from external computers you may adopt one of the following solutions: 1) Just disable protected mode \
+                sending the command 'CONFIG SET protected-mode no' from the loopback interface, however MAKE SURE the server is not \
+                publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you \
+                can disable protected mode by setting protected-mode to no in the configuration file, and then restarting the server. \
+                3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up \
+                an authentication password for the default user. NOTE: You only need to do one of the above things in order for the \
+                server to start accepting connections from the outside."),
+            RESPError::IOError(e) => write!(f, "ERR {}", e),
+        }
+    }
+}
+
+impl From<RESPError> for RESPValue {
+    fn from(e: RESPError) -> RESPValue {
+        RESPValue::SimpleError(Bytes::from(e.to_string()))
+    }
+}
+
+impl From<std::io::Error> for RESPError {
+    fn from(e: std::io::Error) -> RESPError {
+        RESPError::IOError(e)
+    }
+}
+
+// What the decoder accepts, set by `proto-max-bulk-len`, `proto-max-multibulk-len` and
+// `proto-max-nesting`. A client going over them is replied with a protocol error and disconnected.
+static MAX_BULK_LEN: AtomicUsize = AtomicUsize::new(512 * 1024 * 1024);
+static MAX_MULTIBULK_LEN: AtomicUsize = AtomicUsize::new(1024 * 1024);
+static MAX_NESTING: AtomicUsize = AtomicUsize::new(8);
+
+fn protocol_limits() -> Limits {
+    Limits {
+        max_bulk_len: MAX_BULK_LEN.load(Ordering::Relaxed),
+        max_multibulk_len: MAX_MULTIBULK_LEN.load(Ordering::Relaxed),
+        max_nesting: MAX_NESTING.load(Ordering::Relaxed),
+    }
+}
+
+struct SharedState {
+    next_client_id: AtomicU64,
+    db: Keyspace,
+    // Set with `execution-model actors`, serving the shards of the keyspace.
+    actors: Option<Actors>,
+    pubsub: Mutex<PubSub>,
+    notify_keyspace_events: AtomicU32,
+    // Cached script bodies by their SHA1 digest.
+    scripts: Mutex<HashMap<String, String>>,
+    script_monitor: ScriptMonitor,
+    // Function libraries by their name.
+    libraries: Mutex<HashMap<String, Library>>,
+    // Commands registered by plugins.
+    commands: RwLock<CommandRegistry>,
+    // Milliseconds a script may run before other clients are replied with -BUSY.
+    busy_reply_threshold: AtomicU64,
+    // Notified whenever data is added to a key that blocked clients may be waiting on.
+    keys_ready: Notify,
+    acl: Mutex<Acl>,
+    audit_log: Mutex<AuditLog>,
+    // Commands renamed at startup by their original name, an empty new name disables the command.
+    renamed_commands: HashMap<String, String>,
+    stats: Stats,
+    config: Config,
+    logger: Logger,
+    clients: ClientRegistry,
+    tracking: Mutex<Tracking>,
+    latency: LatencyMonitor,
+    lazyfree: LazyFree,
+    rate_limits: RateLimits,
+    otel: Otel,
+    health: Arc<Health>,
+    snapshots: Snapshots,
+    aof: Aof,
+    replication: Replication,
+    // Set in sentinel mode, monitoring primaries instead of serving data.
+    sentinel: Option<Sentinel>,
+    // Set with `cluster-enabled`, serving its share of the hash slots of a cluster.
+    cluster: Option<Cluster>,
+    // Set with `backing-upstream` or by the application, the store the keyspace caches.
+    backing: Option<Arc<dyn Backing>>,
+    // The configuration file given at startup, rewritten by CONFIG REWRITE and reloaded on SIGHUP.
+    config_file: Option<String>,
+    // Directives from the command line, which override the ones in the configuration file.
+    config_overrides: Vec<Vec<String>>,
+    // Set for servers embedded in an application (see Server::start), notified once shut down rather
+    // than exiting the process.
+    stop: Option<Notify>,
+}
+
+impl SharedState {
+    fn new() -> Self {
+        Self {
+            next_client_id: AtomicU64::new(1),
+            db: Keyspace::default(),
+            actors: None,
+            pubsub: Mutex::new(PubSub::default()),
+            notify_keyspace_events: AtomicU32::new(0),
+            scripts: Mutex::new(HashMap::new()),
+            script_monitor: ScriptMonitor::default(),
+            libraries: Mutex::new(HashMap::new()),
+            commands: RwLock::new(CommandRegistry::default()),
+            busy_reply_threshold: AtomicU64::new(5000),
+            keys_ready: Notify::new(),
+            acl: Mutex::new(Acl::default()),
+            audit_log: Mutex::new(AuditLog::default()),
+            renamed_commands: HashMap::new(),
+            stats: Stats::default(),
+            config: Config::default(),
+            logger: Logger::default(),
+            clients: ClientRegistry::default(),
+            tracking: Mutex::new(Tracking::default()),
+            latency: LatencyMonitor::default(),
+            lazyfree: LazyFree::default(),
+            rate_limits: RateLimits::default(),
+            otel: Otel::default(),
+            health: Arc::default(),
+            snapshots: Snapshots::default(),
+            aof: Aof::default(),
+            replication: Replication::default(),
+            sentinel: None,
+            cluster: None,
+            backing: None,
+            config_file: None,
+            config_overrides: vec![],
+            stop: None,
+        }
+    }
+}
+
+struct Client {
+    id: ClientId,
+    // Shared with the client registry, for CLIENT LIST and CLIENT KILL.
+    info: Arc<ClientInfo>,
+    push_sender: UnboundedSender<RESPValue>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shard_channels: HashSet<String>,
+    // Commands queued since MULTI, None when not in a transaction.
+    multi: Option<Vec<Vec<Arg>>>,
+    // Set when a command failed to queue, which makes EXEC abort.
+    multi_failed: bool,
+    // Watched keys along with their version at the time of WATCH.
+    watched: Vec<(String, Option<u64>)>,
+    // Set by QUIT, the connection is closed once the pending replies are written.
+    close_after_reply: bool,
+    reply_mode: ReplyMode,
+    // Set by CLIENT CACHING, applying to the next command only.
+    caching: Option<bool>,
+    // Set while running commands called by a script.
+    in_script: bool,
+    // The ACL user the client is running commands as.
+    user: String,
+    authenticated: bool,
+    replconf: ReplConf,
+    // Set by PSYNC, turning the connection into the link to a replica.
+    replica_link: Option<ReplicaLink>,
+    // Set for the client applying the writes of the primary, which can't be refused.
+    from_primary: bool,
+    // Set by READONLY, refusing the writes of the connection.
+    readonly: bool,
+    // Set by ASKING, applying to the next command only.
+    asking: bool,
+    rate_limit: Buckets,
+    // Set for QUIC connections until their handshake completes, see quic.rs.
+    handshake: Option<watch::Receiver<bool>>,
+}
+
+impl Client {
+    fn new(info: Arc<ClientInfo>, push_sender: UnboundedSender<RESPValue>, authenticated: bool) -> Self {
+        Self {
+            id: info.id,
+            info,
+            push_sender,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            multi: None,
+            multi_failed: false,
+            watched: vec![],
+            close_after_reply: false,
+            reply_mode: ReplyMode::default(),
+            caching: None,
+            in_script: false,
+            user: acl::DEFAULT_USER.to_owned(),
+            authenticated,
+            replconf: ReplConf::default(),
+            replica_link: None,
+            from_primary: false,
+            readonly: false,
+            asking: false,
+            rate_limit: Buckets::default(),
+            handshake: None,
+        }
+    }
+
+    // In RESP2 subscribed clients can only receive pushes, see check_subscribed.
+    fn subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty() || !self.shard_channels.is_empty()
+    }
+
+    // Publishes the state that changes through commands to the client registry.
+    fn sync_info(&self) {
+        let mut state = self.info.state.lock().unwrap();
+        state.user = self.user.clone();
+        state.channels = self.channels.len();
+        state.patterns = self.patterns.len();
+        state.shard_channels = self.shard_channels.len();
+        state.multi = self.multi.as_ref().map(Vec::len);
+        state.readonly = self.readonly;
+    }
+}
+
+// Where the keys of a command are: the first and last key argument and the step between keys, a
+// negative last key counts from the end. Commands whose keys can't be described this way are
+// flagged with "movablekeys", and have their keys found by `command_keys`.
+#[derive(Clone, Copy)]
+struct KeySpec {
+    first: i64,
+    last: i64,
+    step: i64,
+}
+
+const NO_KEYS: KeySpec = KeySpec { first: 0, last: 0, step: 0 };
+const FIRST_KEY: KeySpec = KeySpec { first: 1, last: 1, step: 1 };
+const ALL_KEYS: KeySpec = KeySpec { first: 1, last: -1, step: 1 };
+
+struct CommandSpec {
+    name: &'static str,
+    // A negative arity means at least that many arguments (including the command name itself).
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: KeySpec,
+}
+
+impl CommandSpec {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "PING", arity: -1, flags: &["fast"], keys: NO_KEYS },
+    CommandSpec { name: "ECHO", arity: 2, flags: &["fast"], keys: NO_KEYS },
+    CommandSpec { name: "QUIT", arity: -1, flags: &["noscript", "fast", "no-auth"], keys: NO_KEYS },
+    CommandSpec { name: "RESET", arity: 1, flags: &["noscript", "fast", "no-auth"], keys: NO_KEYS },
+    CommandSpec { name: "AUTH", arity: -2, flags: &["noscript", "fast", "no-auth"], keys: NO_KEYS },
+    CommandSpec { name: "READONLY", arity: 1, flags: &["fast"], keys: NO_KEYS },
+    CommandSpec { name: "READWRITE", arity: 1, flags: &["fast"], keys: NO_KEYS },
+    CommandSpec { name: "GET", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "SET", arity: 3, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "SETNX", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "SETEX", arity: 4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "PSETEX", arity: 4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "GETSET", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "INCR", arity: 2, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "INCRBY", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "DECR", arity: 2, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "DECRBY", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XADD", arity: -5, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XLEN", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XRANGE", arity: -4, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "XREVRANGE", arity: -4, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "XREAD", arity: -4, flags: &["readonly", "movablekeys"], keys: NO_KEYS },
+    CommandSpec { name: "XDEL", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XTRIM", arity: -4, flags: &["write"], keys: FIRST_KEY },
+    CommandSpec { name: "XGROUP", arity: -4, flags: &["write", "denyoom"], keys: KeySpec { first: 2, last: 2, step: 1 } },
+    CommandSpec { name: "XREADGROUP", arity: -7, flags: &["write", "movablekeys"], keys: NO_KEYS },
+    CommandSpec { name: "XACK", arity: -4, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XPENDING", arity: -3, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "XCLAIM", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "XAUTOCLAIM", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PFADD", arity: -2, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PFCOUNT", arity: -2, flags: &["readonly", "may-replicate"], keys: ALL_KEYS },
+    CommandSpec { name: "PFMERGE", arity: -2, flags: &["write", "denyoom"], keys: ALL_KEYS },
+    CommandSpec { name: "BF.RESERVE", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.ADD", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.MADD", arity: -3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.INSERT", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.EXISTS", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.MEXISTS", arity: -3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.CARD", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BF.INFO", arity: -2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.RESERVE", arity: -3, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.ADD", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.ADDNX", arity: 3, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.INSERT", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.INSERTNX", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.EXISTS", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.MEXISTS", arity: -3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.COUNT", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.DEL", arity: 3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "CF.INFO", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.SET", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.GET", arity: -2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.DEL", arity: -2, flags: &["write"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.FORGET", arity: -2, flags: &["write"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.NUMINCRBY", arity: 4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "JSON.ARRAPPEND", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "SETBIT", arity: 4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "GETBIT", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "BITCOUNT", arity: -2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "BITPOS", arity: -3, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "BITOP", arity: -4, flags: &["write", "denyoom"], keys: KeySpec { first: 2, last: -1, step: 1 } },
+    CommandSpec { name: "BITFIELD", arity: -2, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "GEOADD", arity: -5, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "GEOPOS", arity: -2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "GEODIST", arity: -4, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "GEOSEARCH", arity: -7, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "GEOSEARCHSTORE", arity: -8, flags: &["write", "denyoom"], keys: KeySpec { first: 1, last: 2, step: 1 } },
+    CommandSpec { name: "HSET", arity: -4, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HSETNX", arity: 4, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HMSET", arity: -4, flags: &["write", "denyoom", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HGET", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HMGET", arity: -3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HDEL", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HLEN", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HEXISTS", arity: 3, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HGETALL", arity: 2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "HKEYS", arity: 2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "HVALS", arity: 2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "HEXPIRE", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HPEXPIRE", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HEXPIREAT", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HPEXPIREAT", arity: -6, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HTTL", arity: -5, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HPTTL", arity: -5, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HEXPIRETIME", arity: -5, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HPEXPIRETIME", arity: -5, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "HPERSIST", arity: -5, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "SORT", arity: -2, flags: &["write", "denyoom", "movablekeys"], keys: FIRST_KEY },
+    CommandSpec { name: "SORT_RO", arity: -2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "EXPIRE", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PEXPIRE", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "EXPIREAT", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PEXPIREAT", arity: -3, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "TTL", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PTTL", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "EXPIRETIME", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PEXPIRETIME", arity: 2, flags: &["readonly", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "PERSIST", arity: 2, flags: &["write", "fast"], keys: FIRST_KEY },
+    CommandSpec { name: "DEL", arity: -2, flags: &["write"], keys: ALL_KEYS },
+    CommandSpec { name: "UNLINK", arity: -2, flags: &["write", "fast"], keys: ALL_KEYS },
+    CommandSpec { name: "TOUCH", arity: -2, flags: &["readonly", "fast"], keys: ALL_KEYS },
+    CommandSpec { name: "RANDOMKEY", arity: 1, flags: &["readonly"], keys: NO_KEYS },
+    CommandSpec { name: "DUMP", arity: 2, flags: &["readonly"], keys: FIRST_KEY },
+    CommandSpec { name: "RESTORE", arity: -4, flags: &["write", "denyoom"], keys: FIRST_KEY },
+    CommandSpec { name: "RESTORE-ASKING", arity: -4, flags: &["write", "denyoom", "asking"], keys: FIRST_KEY },
+    CommandSpec { name: "MIGRATE", arity: -6, flags: &["write", "movablekeys"], keys: KeySpec { first: 3, last: 3, step: 1 } },
+    CommandSpec { name: "DBSIZE", arity: 1, flags: &["readonly", "fast"], keys: NO_KEYS },
+    CommandSpec { name: "FLUSHDB", arity: -1, flags: &["write"], keys: NO_KEYS },
+    CommandSpec { name: "FLUSHALL", arity: -1, flags: &["write"], keys: NO_KEYS },
+    CommandSpec { name: "SUBSCRIBE", arity: -2, flags: &["pubsub", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "PSUBSCRIBE", arity: -2, flags: &["pubsub", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SSUBSCRIBE", arity: -2, flags: &["pubsub", "noscript"], keys: ALL_KEYS },
+    CommandSpec { name: "UNSUBSCRIBE", arity: -1, flags: &["pubsub", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "PUNSUBSCRIBE", arity: -1, flags: &["pubsub", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SUNSUBSCRIBE", arity: -1, flags: &["pubsub", "noscript"], keys: ALL_KEYS },
+    CommandSpec { name: "PUBLISH", arity: 3, flags: &["pubsub", "fast", "may-replicate"], keys: NO_KEYS },
+    CommandSpec { name: "SPUBLISH", arity: 3, flags: &["pubsub", "fast", "may-replicate"], keys: FIRST_KEY },
+    CommandSpec { name: "CONFIG", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "CLIENT", arity: -2, flags: &["noscript"], keys: NO_KEYS },
+    CommandSpec { name: "MONITOR", arity: 1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "LATENCY", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "OBJECT", arity: -2, flags: &["readonly"], keys: KeySpec { first: 2, last: 2, step: 1 } },
+    CommandSpec { name: "MEMORY", arity: -2, flags: &["readonly"], keys: KeySpec { first: 2, last: 2, step: 1 } },
+    CommandSpec { name: "DEBUG", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "ACL", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "COMMAND", arity: -1, flags: &[], keys: NO_KEYS },
+    CommandSpec { name: "INFO", arity: -1, flags: &[], keys: NO_KEYS },
+    CommandSpec { name: "MULTI", arity: 1, flags: &["noscript", "fast"], keys: NO_KEYS },
+    CommandSpec { name: "EXEC", arity: 1, flags: &["noscript"], keys: NO_KEYS },
+    CommandSpec { name: "DISCARD", arity: 1, flags: &["noscript", "fast"], keys: NO_KEYS },
+    CommandSpec { name: "WATCH", arity: -2, flags: &["noscript", "fast"], keys: ALL_KEYS },
+    CommandSpec { name: "UNWATCH", arity: 1, flags: &["noscript", "fast"], keys: NO_KEYS },
+    CommandSpec { name: "EVAL", arity: -3, flags: &["noscript", "movablekeys", "may-replicate"], keys: NO_KEYS },
+    CommandSpec { name: "EVALSHA", arity: -3, flags: &["noscript", "movablekeys", "may-replicate"], keys: NO_KEYS },
+    CommandSpec { name: "SCRIPT", arity: -2, flags: &["noscript"], keys: NO_KEYS },
+    CommandSpec { name: "FUNCTION", arity: -2, flags: &["noscript"], keys: NO_KEYS },
+    CommandSpec { name: "FCALL", arity: -3, flags: &["noscript", "movablekeys", "may-replicate"], keys: NO_KEYS },
+    CommandSpec { name: "FCALL_RO", arity: -3, flags: &["noscript", "readonly", "movablekeys"], keys: NO_KEYS },
+    CommandSpec { name: "MODULE", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SHUTDOWN", arity: -1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SAVE", arity: 1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "BGSAVE", arity: -1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "LASTSAVE", arity: 1, flags: &["fast"], keys: NO_KEYS },
+    CommandSpec { name: "BGREWRITEAOF", arity: 1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "REPLICAOF", arity: 3, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SLAVEOF", arity: 3, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "REPLCONF", arity: -1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "PSYNC", arity: -3, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SYNC", arity: 1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "WAIT", arity: 3, flags: &["noscript"], keys: NO_KEYS },
+    CommandSpec { name: "FAILOVER", arity: -1, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "SENTINEL", arity: -2, flags: &["admin", "noscript"], keys: NO_KEYS },
+    CommandSpec { name: "CLUSTER", arity: -2, flags: &[], keys: NO_KEYS },
+    CommandSpec { name: "ASKING", arity: 1, flags: &["fast"], keys: NO_KEYS },
+];
+
+fn lookup_builtin_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+fn lookup_command(name: &str, shared: &SharedState) -> Option<&'static CommandSpec> {
+    lookup_builtin_command(name).or_else(|| shared.commands.read().unwrap().get(name).map(|(spec, _)| spec))
+}
+
+// The keys the command accesses, expects the arity to be valid.
+fn command_keys<'a>(spec: &CommandSpec, command: &'a [Arg]) -> Vec<&'a Arg> {
+    // The channels of the sharded pub/sub commands are covered by their key specs so they're routed
+    // like keys in a cluster, but they aren't keys.
+    if spec.has_flag("pubsub") {
+        return vec![];
+    }
+    routed_keys(spec, command)
+}
+
+// The arguments the key specs of the command cover: its keys, or the channels of the sharded pub/sub
+// commands. In a cluster, they must all be in the same hash slot.
+fn routed_keys<'a>(spec: &CommandSpec, command: &'a [Arg]) -> Vec<&'a Arg> {
+    if spec.has_flag("movablekeys") {
+        return match spec.name {
+            "SORT" => {
+                let store = command.iter().position(|arg| arg.eq_ignore_ascii_case("STORE"))
+                    .and_then(|i| command.get(i + 1));
+                std::iter::once(&command[1]).chain(store).collect()
+            },
+            "XREAD" | "XREADGROUP" => {
+                let streams = command.iter().position(|arg| arg.eq_ignore_ascii_case("STREAMS"))
+                    .map_or(&[][..], |i| &command[i + 1..]);
+                streams[..streams.len() / 2].iter().collect()
+            },
+            // The key, or the keys after KEYS (skipping over the arguments of AUTH and AUTH2).
+            "MIGRATE" => {
+                let mut i = 6;
+                while i < command.len() {
+                    match command[i].to_ascii_uppercase().as_str() {
+                        "AUTH" => i += 1,
+                        "AUTH2" => i += 2,
+                        "KEYS" => return command[i + 1..].iter().collect(),
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                vec![&command[3]]
+            },
+            "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO" => {
+                split_script_keys(&command[2..]).map_or(vec![], |(keys, _)| keys.iter().collect())
+            },
+            _ => vec![]
+        };
+    }
+
+    let KeySpec { first, last, step } = spec.keys;
+    if first == 0 {
+        return vec![];
+    }
+    let last = if last < 0 { command.len() as i64 + last } else { last.min(command.len() as i64 - 1) };
+    (first..=last).step_by(step as usize).map(|i| &command[i as usize]).collect()
+}
+
+// The group a command is documented under.
+fn command_group(spec: &CommandSpec) -> &'static str {
+    match spec.name {
+        "PING" | "ECHO" | "QUIT" | "RESET" | "AUTH" | "CLIENT" | "READONLY" | "READWRITE" => "connection",
+        "GET" | "SET" | "SETNX" | "SETEX" | "PSETEX" | "GETSET" | "INCR" | "INCRBY" | "DECR" | "DECRBY" => "string",
+        "PFADD" | "PFCOUNT" | "PFMERGE" => "hyperloglog",
+        "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" | "BITFIELD" => "bitmap",
+        "HSET" | "HSETNX" | "HMSET" | "HGET" | "HMGET" | "HDEL" | "HLEN" | "HEXISTS" | "HGETALL" | "HKEYS" | "HVALS" | "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT"
+        | "HPEXPIREAT" | "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" | "HPERSIST" => "hash",
+        "SORT" | "SORT_RO" | "RANDOMKEY" | "OBJECT" | "DEL" | "UNLINK" | "TOUCH" | "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" | "TTL" | "PTTL"
+        | "EXPIRETIME" | "PEXPIRETIME" | "PERSIST" | "DUMP" | "RESTORE" | "RESTORE-ASKING" | "MIGRATE" | "WAIT" => "generic",
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" | "PUBLISH" | "SPUBLISH" => "pubsub",
+        "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" => "transactions",
+        "EVAL" | "EVALSHA" | "SCRIPT" | "FUNCTION" | "FCALL" | "FCALL_RO" => "scripting",
+        "DBSIZE" | "FLUSHDB" | "FLUSHALL" | "CONFIG" | "ACL" | "COMMAND" | "INFO" | "MODULE" | "SHUTDOWN" | "SAVE" | "BGSAVE" | "LASTSAVE" | "BGREWRITEAOF" | "MONITOR" | "LATENCY" | "MEMORY" | "DEBUG"
+        | "REPLICAOF" | "SLAVEOF" | "REPLCONF" | "PSYNC" | "SYNC" | "FAILOVER" | "SENTINEL" => "server",
+        "CLUSTER" | "ASKING" => "cluster",
+        name if name.starts_with("BF.") => "bf",
+        name if name.starts_with("CF.") => "cf",
+        name if name.starts_with("JSON.") => "json",
+        name if name.starts_with('X') => "stream",
+        name if name.starts_with("GEO") => "geo",
+        _ => "module"
+    }
+}
+
+// The COMMAND INFO reply of a command. Key specs are only given for commands with a key range, the
+// rest are found through COMMAND GETKEYS.
+fn command_info(spec: &CommandSpec) -> RESPValue {
+    let simple = |s: &str| RESPValue::SimpleString(s.to_owned());
+    let blob = |s: &str| RESPValue::BlobString(s.to_owned().into());
+    let KeySpec { first, last, step } = spec.keys;
+
+    let key_specs = if first > 0 && !spec.has_flag("movablekeys") {
+        vec![RESPValue::Array(vec![
+            blob("flags"),
+            RESPValue::Array(vec![]),
+            blob("begin_search"),
+            RESPValue::Array(vec![blob("type"), blob("index"), blob("spec"), RESPValue::Array(vec![blob("index"), RESPValue::Number(first)])]),
+            blob("find_keys"),
+            RESPValue::Array(vec![blob("type"), blob("range"), blob("spec"), RESPValue::Array(vec![
+                blob("lastkey"),
+                RESPValue::Number(if last < 0 { last } else { last - first }),
+                blob("keystep"),
+                RESPValue::Number(step),
+                blob("limit"),
+                RESPValue::Number(0),
+            ])]),
+        ])]
+    } else {
+        vec![]
+    };
+
+    RESPValue::Array(vec![
+        blob(&spec.name.to_ascii_lowercase()),
+        RESPValue::Number(spec.arity),
+        RESPValue::Array(spec.flags.iter().map(|flag| simple(flag)).collect()),
+        RESPValue::Number(first),
+        RESPValue::Number(last),
+        RESPValue::Number(step),
+        RESPValue::Array(acl::categories(spec).into_iter().map(|category| simple(&format!("@{}", category))).collect()),
+        RESPValue::Array(vec![]),
+        RESPValue::Array(key_specs),
+        RESPValue::Array(vec![]),
+    ])
+}
+
+// COMMAND [COUNT | INFO [name ...] | DOCS [name ...] | GETKEYS command [arg ...]]
+fn command_introspection(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let specs = all_commands(shared);
+    let lookup = |name: &String| specs.iter().copied().find(|spec| spec.name == name.to_ascii_uppercase());
+
+    let subcommand = match command.get(1) {
+        Some(subcommand) => subcommand.to_ascii_uppercase(),
+        None => return Ok(RESPValue::Array(specs.into_iter().map(command_info).collect()))
+    };
+    match subcommand.as_str() {
+        "COUNT" => {
+            if command.len() != 2 {
+                return Err(RESPError::WrongNumberOfArguments(String::from("COMMAND|COUNT")));
+            }
+            Ok(RESPValue::Number(specs.len() as i64))
+        },
+        "INFO" => {
+            let infos = if command.len() == 2 {
+                specs.iter().map(|spec| command_info(spec)).collect()
+            } else {
+                command[2..].iter().map(|name| lookup(name).map_or(RESPValue::Null, command_info)).collect()
+            };
+            Ok(RESPValue::Array(infos))
+        },
+        "DOCS" => {
+            let documented: Vec<&CommandSpec> = if command.len() == 2 {
+                specs.clone()
+            } else {
+                command[2..].iter().filter_map(|name| lookup(name)).collect()
+            };
+
+            let mut docs = vec![];
+            for spec in documented {
+                docs.push(RESPValue::BlobString(spec.name.to_ascii_lowercase().into()));
+                docs.push(RESPValue::Array(vec![
+                    RESPValue::BlobString("group".into()),
+                    RESPValue::BlobString(command_group(spec).into()),
+                ]));
+            }
+            Ok(RESPValue::Array(docs))
+        },
+        "GETKEYS" => {
+            if command.len() < 3 {
+                return Err(RESPError::WrongNumberOfArguments(String::from("COMMAND|GETKEYS")));
+            }
+
+            let args: Vec<Arg> = std::iter::once(Arg::from(command[2].to_ascii_uppercase())).chain(command[3..].iter().cloned()).collect();
+            let spec = lookup(&args[0]).ok_or(RESPError::InvalidCommandSpecified)?;
+            let argc = args.len() as i64;
+            if (spec.arity >= 0 && argc != spec.arity) || argc < -spec.arity {
+                return Err(RESPError::InvalidCommandArguments);
+            }
+
+            let keys = routed_keys(spec, &args);
+            if keys.is_empty() {
+                return Err(RESPError::NoKeyArguments);
+            }
+            Ok(RESPValue::Array(keys.into_iter().map(|key| RESPValue::BlobString(key.to_owned().into())).collect()))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("COMMAND {}", command[1])))
+    }
+}
+
+// OBJECT ENCODING key
+// OBJECT IDLETIME key
+// OBJECT FREQ key
+// OBJECT REFCOUNT key
+fn object(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    if !matches!(subcommand.as_str(), "ENCODING" | "IDLETIME" | "FREQ" | "REFCOUNT") {
+        return Err(RESPError::UnsupportedCommand(format!("OBJECT {}", command[1])));
+    }
+    if command.len() != 3 {
+        return Err(RESPError::WrongNumberOfArguments(format!("OBJECT|{}", subcommand)));
+    }
+
+    let db = shared.db.lock();
+    let (value, access) = match db.peek(&command[2]).zip(db.access_info(&command[2])) {
+        Some(found) => found,
+        None => return Ok(RESPValue::Null)
+    };
+    match subcommand.as_str() {
+        "ENCODING" => Ok(RESPValue::BlobString(value.encoding().into())),
+        "IDLETIME" => Ok(RESPValue::Number(access.idle.as_secs() as i64)),
+        "FREQ" => Ok(RESPValue::Number(access.frequency as i64)),
+        // Values are never shared between keys.
+        "REFCOUNT" => Ok(RESPValue::Number(1)),
+        _ => unreachable!()
+    }
+}
+
+// The builtin commands along with the ones registered by plugins.
+fn all_commands(shared: &SharedState) -> Vec<&'static CommandSpec> {
+    COMMANDS.iter().chain(shared.commands.read().unwrap().specs()).collect()
+}
+
+// Checks the user of the client is allowed to run the command on its keys. Commands that don't
+// require authentication are always allowed, so users can switch to another user.
+fn check_permissions(command: &[Arg], client: &Client, shared: &SharedState) -> Result<(), RESPError> {
+    let spec = match validate_command(command, shared) {
+        Ok(spec) if !spec.has_flag("no-auth") => spec,
+        _ => return Ok(())
+    };
+
+    let acl = shared.acl.lock().unwrap();
+    // The user might have been deleted or disabled since the client authenticated.
+    let user = acl.user(&client.user).filter(|user| user.enabled()).ok_or(RESPError::NoAuth)?;
+    if !user.can_run(spec) {
+        return Err(RESPError::NoPermission(client.user.clone(), command[0].to_string()));
+    }
+    if !command_keys(spec, command).iter().all(|key| user.can_access(key)) {
+        return Err(RESPError::NoKeyPermission);
+    }
+    if matches!(spec.name, "SORT" | "SORT_RO") && !user.can_access_all() && sort::uses_patterns(command) {
+        return Err(RESPError::SortPatternsDenied);
+    }
+    Ok(())
+}
+
+// Refuses writes on a replica with `replica-read-only` set (other than the ones of its primary), and
+// on connections that called READONLY.
+fn check_read_only(command: &[Arg], client: &Client, shared: &SharedState) -> Result<(), RESPError> {
+    if client.from_primary || !lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("write")) {
+        return Ok(());
+    }
+    if shared.replication.is_replica() && shared.config.get_bool("replica-read-only") {
+        return Err(RESPError::ReadOnlyReplica);
+    }
+    if client.readonly {
+        return Err(RESPError::ReadOnlyConnection);
+    }
+    Ok(())
+}
+
+// Subscribed clients only get to manage their subscriptions, as anything else would reply in between
+// the messages.
+fn check_subscribed(command: &[Arg], client: &Client) -> Result<(), RESPError> {
+    let allowed = matches!(command[0].as_str(),
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" | "PING" | "QUIT" | "RESET");
+    if client.subscribed() && !allowed {
+        return Err(RESPError::SubscribedContext(command[0].to_ascii_lowercase()));
+    }
+    Ok(())
+}
+
+fn validate_command(command: &[Arg], shared: &SharedState) -> Result<&'static CommandSpec, RESPError> {
+    let spec = lookup_command(&command[0], shared).ok_or_else(|| RESPError::UnsupportedCommand(command[0].to_string()))?;
+
+    let argc = command.len() as i64;
+    if (spec.arity >= 0 && argc != spec.arity) || argc < -spec.arity {
+        return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+    }
+    Ok(spec)
+}
+
+// Subscribes to every name not yet in `subscribed`, replying with the total subscription count,
+// which includes `other_count` subscriptions sharing the same count (channels and patterns).
+fn subscribe_replies(kind: &str, names: &[Arg], subscribed: &mut HashSet<String>, other_count: usize, mut subscribe: impl FnMut(&str)) -> Vec<RESPValue> {
+    let mut replies = Vec::with_capacity(names.len());
+    for name in names {
+        if subscribed.insert(name.to_string()) {
+            subscribe(name);
+        }
+        replies.push(subscription_reply(kind, Some(name), subscribed.len() + other_count));
+    }
+    replies
+}
+
+fn unsubscribe_all(client: &mut Client, shared: &SharedState) {
+    let mut pubsub = shared.pubsub.lock().unwrap();
+    pubsub.unsubscribe_all(&client.channels, &client.patterns, client.id);
+    pubsub.sunsubscribe_all(&client.shard_channels, client.id);
+    client.channels.clear();
+    client.patterns.clear();
+    client.shard_channels.clear();
+}
+
+// Unsubscribes from the given names, or from everything in `subscribed` when no names are given.
+fn unsubscribe_replies(kind: &str, names: &[Arg], subscribed: &mut HashSet<String>, other_count: usize, mut unsubscribe: impl FnMut(&str)) -> Vec<RESPValue> {
+    let names: Vec<String> = if names.is_empty() {
+        subscribed.iter().cloned().collect()
+    } else {
+        names.iter().map(|name| name.to_string()).collect()
+    };
+
+    if names.is_empty() {
+        return vec![subscription_reply(kind, None, other_count)];
+    }
+
+    let mut replies = Vec::with_capacity(names.len());
+    for name in names {
+        if subscribed.remove(&name) {
+            unsubscribe(&name);
+        }
+        replies.push(subscription_reply(kind, Some(&name), subscribed.len() + other_count));
+    }
+    replies
+}
+
+// Queues the command while inside MULTI, otherwise executes it right away.
+fn process_command(command: Vec<Arg>, client: &mut Client, shared: &SharedState) -> Result<Vec<RESPValue>, RESPError> {
+    let in_multi = client.multi.is_some();
+    if in_multi && !matches!(command[0].as_str(), "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "QUIT" | "RESET") {
+        if let Err(e) = validate_command(&command, shared) {
+            client.multi_failed = true;
+            return Err(e);
+        }
+
+        client.multi.as_mut().unwrap().push(command);
+        return Ok(vec![RESPValue::SimpleString(String::from("QUEUED"))]);
+    }
+
+    handle_request(command, client, shared)
+}
+
+fn exec_transaction(client: &mut Client, shared: &SharedState) -> Result<Vec<RESPValue>, RESPError> {
+    let queued = client.multi.take().ok_or(RESPError::ExecWithoutMulti)?;
+    let failed = std::mem::take(&mut client.multi_failed);
+    let watched = std::mem::take(&mut client.watched);
+
+    if failed {
+        return Err(RESPError::ExecAbort);
+    }
+    // The writes were allowed when queued, but this may have become a replica since.
+    for command in &queued {
+        check_read_only(command, client, shared)?;
+    }
+
+    {
+        let db = shared.db.lock();
+        if watched.iter().any(|(key, version)| db.version(key) != *version) {
+            return Ok(vec![RESPValue::Null]);
+        }
+    }
+
+    // The transaction owns the shards of all its commands, so no other client can run in between them.
+    let mut replies = Vec::with_capacity(queued.len());
+    for command in queued {
+        match handle_request(command, client, shared) {
+            Ok(responses) => replies.extend(responses),
+            Err(e) => replies.push(e.into())
+        }
+    }
+    Ok(vec![RESPValue::Array(replies)])
+}
+
+fn parse_number(arg: &str) -> Result<i64, RESPError> {
+    arg.parse().map_err(|_| RESPError::NotAnInteger)
+}
+
+// Splits the `numkeys key [key ...] arg [arg ...]` arguments of EVAL and FCALL into the keys and
+// the rest of the arguments.
+fn split_script_keys(args: &[Arg]) -> Result<(&[Arg], &[Arg]), RESPError> {
+    let num_keys = parse_number(&args[0])?;
+    if num_keys < 0 {
+        return Err(RESPError::NegativeNumKeys);
+    }
+    let num_keys = num_keys as usize;
+    if num_keys > args.len() - 1 {
+        return Err(RESPError::TooManyNumKeys);
+    }
+    Ok(args[1..].split_at(num_keys))
+}
+
+// Command names are case-insensitive, so they're uppercased once, before anything looks them up.
+fn uppercase_name(command: &mut [Arg]) {
+    if command[0].bytes().any(|byte| byte.is_ascii_lowercase()) {
+        command[0] = Arg::from(command[0].to_ascii_uppercase());
+    }
+}
+
+// Maps the name a command was called by to the command itself. Renamed commands are only reachable
+// through their new name, and disabled commands not at all.
+fn resolve_renamed(mut command: Vec<Arg>, shared: &SharedState) -> Result<Vec<Arg>, RESPError> {
+    uppercase_name(&mut command);
+    let renamed = shared.renamed_commands.iter().find(|(_, new_name)| !new_name.is_empty() && new_name.eq_ignore_ascii_case(&command[0]));
+    if let Some((name, _)) = renamed {
+        command[0] = Arg::from(name);
+    } else if shared.renamed_commands.contains_key(command[0].as_str()) {
+        return Err(RESPError::UnsupportedCommand(command[0].to_string()));
+    }
+    Ok(command)
+}
+
+// Executes a command issued by a script through `redis.call` / `redis.pcall`.
+fn script_call(command: Vec<Arg>, client: &mut Client, shared: &SharedState, read_only: bool) -> Result<RESPValue, RESPError> {
+    let command = resolve_renamed(command, shared)?;
+    let spec = validate_command(&command, shared)?;
+    if spec.has_flag("noscript") {
+        return Err(RESPError::NotAllowedFromScript);
+    }
+    check_permissions(&command, client, shared)?;
+    check_read_only(&command, client, shared)?;
+    if spec.has_flag("write") {
+        if read_only {
+            return Err(RESPError::WriteFromReadOnlyScript);
+        }
+        shared.script_monitor.record_write();
+    }
+
+    client.in_script = true;
+    let result = handle_request(command, client, shared);
+    client.in_script = false;
+    let mut replies = result?;
+    Ok(if replies.len() == 1 { replies.pop().unwrap() } else { RESPValue::Array(replies) })
+}
+
+fn function_list(libraries: &HashMap<String, Library>, pattern: Option<&str>, with_code: bool) -> RESPValue {
+    let mut list = vec![];
+    for library in libraries.values() {
+        if pattern.is_some_and(|pattern| !glob::glob_match(pattern.as_bytes(), library.name.as_bytes())) {
+            continue;
+        }
+
+        let functions = library.functions.iter().map(|function| {
+            RESPValue::Array(vec![
+                RESPValue::BlobString("name".into()),
+                RESPValue::BlobString(function.name.to_owned().into()),
+                RESPValue::BlobString("description".into()),
+                function.description.as_ref().map_or(RESPValue::Null, |d| RESPValue::BlobString(d.to_owned().into())),
+                RESPValue::BlobString("flags".into()),
+                RESPValue::Array(function.flags.iter().map(|flag| RESPValue::BlobString(flag.to_owned().into())).collect()),
+            ])
+        }).collect();
+
+        let mut entry = vec![
+            RESPValue::BlobString("library_name".into()),
+            RESPValue::BlobString(library.name.to_owned().into()),
+            RESPValue::BlobString("engine".into()),
+            RESPValue::BlobString("LUA".into()),
+            RESPValue::BlobString("functions".into()),
+            RESPValue::Array(functions),
+        ];
+        if with_code {
+            entry.push(RESPValue::BlobString("library_code".into()));
+            entry.push(RESPValue::BlobString(library.code.to_owned().into()));
+        }
+        list.push(RESPValue::Array(entry));
+    }
+    RESPValue::Array(list)
+}
+
+fn function_load(code: &str, replace: bool, shared: &SharedState) -> Result<String, RESPError> {
+    let library = scripting::load_library(code)?;
+
+    let mut libraries = shared.libraries.lock().unwrap();
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(RESPError::LibraryExists(library.name));
+    }
+    for other in libraries.values().filter(|other| other.name != library.name) {
+        if let Some(function) = library.functions.iter().find(|function| other.function(&function.name).is_some()) {
+            return Err(RESPError::FunctionExists(function.name.to_owned()));
+        }
+    }
+
+    let name = library.name.clone();
+    libraries.insert(name.clone(), library);
+    Ok(name)
+}
+
+// Records write and admin commands in the audit log, when it is enabled.
+fn audit(command: &[Arg], client: &Client, shared: &SharedState) {
+    let mut audit_log = shared.audit_log.lock().unwrap();
+    if audit_log.enabled() && lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("write") || spec.has_flag("admin")) {
+        if let Err(e) = audit_log.record(&client.info.addr, &client.user, command) {
+            logging::log(shared, "warning", format!("Failed writing to the audit log: {}", e));
+        }
+    }
+}
+
+// Shows the command to clients running MONITOR, except for admin commands.
+fn monitor(command: &[Arg], client: &Client, shared: &SharedState) {
+    if lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("admin")) {
+        return;
+    }
+    let source = if client.in_script {
+        String::from("lua")
+    } else if client.info.addr.is_empty() {
+        format!("unix:{}", client.info.laddr)
+    } else {
+        client.info.addr.clone()
+    };
+    shared.clients.feed_monitors(&source, command);
+}
+
+// Deletes the keys of the command that expired, so it doesn't find them.
+fn expire_keys(command: &[Arg], shared: &SharedState) {
+    if let Ok(spec) = validate_command(command, shared) {
+        for key in command_keys(spec, command) {
+            expire::expire_if_needed(key, shared);
+        }
+    }
+}
+
+// Counts the command, and the keys read-only commands found or missed.
+fn track_command(command: &[Arg], shared: &SharedState) {
+    Stats::incr(&shared.stats.total_commands_processed);
+
+    let spec = match validate_command(command, shared) {
+        Ok(spec) if spec.has_flag("readonly") => spec,
+        _ => return
+    };
+    let db = shared.db.lock();
+    for key in command_keys(spec, command) {
+        Stats::incr(if db.contains_key(key) { &shared.stats.keyspace_hits } else { &shared.stats.keyspace_misses });
+    }
+}
+
+// Remembers the keys read by tracking clients, and invalidates the keys written by any client.
+fn track_keys(command: &[Arg], client: &mut Client, shared: &SharedState) {
+    // CLIENT CACHING applies to the command right after it.
+    if command[0] == "CLIENT" {
+        return;
+    }
+    let caching = client.caching.take();
+
+    let spec = match validate_command(command, shared) {
+        Ok(spec) => spec,
+        Err(_) => return
+    };
+    let keys: Vec<&str> = command_keys(spec, command).into_iter().map(|key| key.as_str()).collect();
+    let mut tracking = shared.tracking.lock().unwrap();
+    if matches!(spec.name, "FLUSHDB" | "FLUSHALL") {
+        tracking.invalidate_all(&shared.pubsub.lock().unwrap());
+    } else if spec.has_flag("write") {
+        tracking.invalidate(&keys, Some(client.id), &shared.pubsub.lock().unwrap());
+    } else if spec.has_flag("readonly") {
+        tracking.track_reads(client.id, &keys, caching);
+    }
+}
+
+// The shards of the keyspace the command accesses, sorted. Scripts, commands of plugins, SORT with BY
+// or GET patterns and writes without keys (like FLUSHALL) access all of them, as they access keys
+// they don't name. Transactions access the shards of their commands and of the keys they watch.
+fn command_shards(command: &[Arg], client: &Client, shared: &SharedState) -> Vec<usize> {
+    let Ok(spec) = validate_command(command, shared) else {
+        return vec![];
+    };
+    if spec.name == "EXEC" {
+        let Some(queued) = &client.multi else {
+            return vec![];
+        };
+        let mut shards = shared.db.shard_indexes(client.watched.iter().map(|(key, _)| key.as_str()));
+        shards.extend(queued.iter().flat_map(|command| command_shards(command, client, shared)));
+        shards.sort_unstable();
+        shards.dedup();
+        return shards;
+    }
+    let patterns = matches!(spec.name, "SORT" | "SORT_RO")
+        && command[2..].iter().any(|arg| arg.eq_ignore_ascii_case("BY") || arg.eq_ignore_ascii_case("GET"));
+    if patterns || matches!(spec.name, "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO") || lookup_builtin_command(spec.name).is_none() {
+        return (0..shared.db.shards()).collect();
+    }
+    let keys = command_keys(spec, command);
+    if keys.is_empty() && spec.has_flag("write") {
+        return (0..shared.db.shards()).collect();
+    }
+    shared.db.shard_indexes(keys.into_iter().map(|key| key.as_str()))
+}
+
+// Owns the shards of the keyspace the command accesses while it runs, see Keyspace.
+fn own_shards<'a>(command: &[Arg], client: &Client, shared: &'a SharedState) -> Owned<'a> {
+    shared.db.own_shards(command_shards(command, client, shared))
+}
+
+fn handle_request(command: Vec<Arg>, client: &mut Client, shared: &SharedState) -> Result<Vec<RESPValue>, RESPError> {
+    // Commands that may grow the keyspace make room first, and are refused when there is none.
+    if !client.from_primary && lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("denyoom")) {
+        eviction::free_memory_if_needed(shared)?;
+    }
+    // Owned until the command was journaled as well, so the writes to a key are journaled in the
+    // order they were made.
+    let _owned = own_shards(&command, client, shared);
+
+    expire_keys(&command, shared);
+    audit(&command, client, shared);
+    monitor(&command, client, shared);
+    track_command(&command, shared);
+    track_keys(&command, client, shared);
+
+    let spec = lookup_command(&command[0], shared);
+    // Writes are journaled once they succeeded, the ones of transactions and scripts as a whole.
+    let journaled = (aof::journaling(shared) && spec.is_some_and(|spec| aof::is_write(&command, spec.has_flag("write"))))
+        .then(|| command.clone());
+    // The keys of writes get their TTLs limited once they succeeded, replicas getting the TTLs their
+    // primary set.
+    let written = spec.filter(|spec| spec.has_flag("write") && !client.from_primary && expire::limiting_ttls())
+        .map(|spec| command_keys(spec, &command).into_iter().map(|key| key.to_string()).collect::<Vec<_>>());
+    let atomic = matches!(command[0].as_str(), "EXEC" | "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO");
+    if atomic {
+        shared.aof.begin_atomic();
+    }
+    let start = Instant::now();
+    let result = dispatch_command(command, client, shared);
+    if let Some(spec) = spec {
+        shared.stats.record_call(spec.name, start.elapsed(), result.is_err());
+    }
+    let limited = match (written, &result) {
+        (Some(keys), Ok(_)) => expire::limit_ttls(&keys, shared),
+        _ => vec![]
+    };
+    if let (Some(command), Ok([reply, ..])) = (journaled, result.as_deref()) {
+        aof::feed(&command, reply, shared);
+    }
+    aof::expires_set(&limited, shared);
+    if atomic {
+        shared.aof.end_atomic(shared);
+    }
+    result
+}
+
+fn dispatch_command(command: Vec<Arg>, client: &mut Client, shared: &SharedState) -> Result<Vec<RESPValue>, RESPError> {
+    let command_type = command[0].as_str();
+    if command.iter().enumerate().any(|(i, arg)| arg.is_binary() && !arg::binary_safe(command_type, i)) {
+        return Err(RESPError::NonUtf8Argument(command[0].to_string()));
+    }
+    match command_type {
+        "PING" => {
+            if command.len() > 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            // Subscribed RESP2 clients can only receive arrays, so they get a pong message instead.
+            let reply = match (client.subscribed(), command.get(1)) {
+                (true, message) => RESPValue::Array(vec![
+                    RESPValue::BlobString("pong".into()),
+                    RESPValue::BlobString(message.cloned().unwrap_or_default().into()),
+                ]),
+                (false, Some(message)) => RESPValue::BlobString(message.to_owned().into()),
+                (false, None) => RESPValue::SimpleString(String::from("PONG"))
+            };
+            Ok(vec![reply])
+        },
+        "ECHO" => {
+            if command.len() != 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+            Ok(vec![RESPValue::BlobString(command[1].to_bytes())])
+        },
+        "QUIT" => {
+            client.close_after_reply = true;
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "ASKING" => {
+            validate_command(&command, shared)?;
+            if shared.cluster.is_none() {
+                return Err(RESPError::ClusterDisabled);
+            }
+            client.asking = true;
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "READONLY" | "READWRITE" => {
+            validate_command(&command, shared)?;
+            client.readonly = command_type == "READONLY";
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "RESET" => {
+            if command.len() != 1 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            client.multi = None;
+            client.multi_failed = false;
+            client.watched.clear();
+            client.user = acl::DEFAULT_USER.to_owned();
+            client.authenticated = !shared.acl.lock().unwrap().auth_required();
+            client.reply_mode = ReplyMode::default();
+            client.caching = None;
+            client.readonly = false;
+            unsubscribe_all(client, shared);
+            shared.tracking.lock().unwrap().disable(client.id);
+            shared.clients.unmonitor(client.id);
+            Ok(vec![RESPValue::SimpleString(String::from("RESET"))])
+        },
+        "AUTH" => {
+            // AUTH <password> authenticates as the default user.
+            let (username, password) = match &command[1..] {
+                [password] => (None, password),
+                [username, password] => (Some(username.as_str()), password),
+                _ => return Err(RESPError::SyntaxError)
+            };
+
+            let acl = shared.acl.lock().unwrap();
+            if username.is_none() && !acl.default_user_has_password() {
+                return Err(RESPError::AuthNotConfigured);
+            }
+            let username = username.unwrap_or(acl::DEFAULT_USER);
+            acl.authenticate(username, password)?;
+
+            client.user = username.to_owned();
+            client.authenticated = true;
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "GET" => {
+            if command.len() != 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let db = shared.db.lock();
+            let value = match db.get(command[1].as_str()) {
+                Some(value) => RESPValue::BlobString(value.as_string()?),
+                None => RESPValue::Null
+            };
+            Ok(vec![value])
+        },
+        "SET" | "SETNX" | "SETEX" | "PSETEX" | "GETSET" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "SET" => string::set_command(&command, shared)?,
+                "SETNX" => string::setnx(&command, shared)?,
+                "GETSET" => string::getset(&command, shared)?,
+                _ => string::setex(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "INCR" | "INCRBY" | "DECR" | "DECRBY" => {
+            validate_command(&command, shared)?;
+            Ok(vec![string::incrby(&command, shared)?])
+        },
+        "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" | "BITFIELD" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "SETBIT" => bitmap::setbit(&command, shared)?,
+                "GETBIT" => bitmap::getbit(&command, shared)?,
+                "BITCOUNT" => bitmap::bitcount(&command, shared)?,
+                "BITPOS" => bitmap::bitpos(&command, shared)?,
+                "BITOP" => bitmap::bitop(&command, shared)?,
+                _ => bitmap::bitfield(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "GEOADD" | "GEOPOS" | "GEODIST" | "GEOSEARCH" | "GEOSEARCHSTORE" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "GEOADD" => geo::geoadd(&command, shared)?,
+                "GEOPOS" => geo::geopos(&command, shared)?,
+                "GEODIST" => geo::geodist(&command, shared)?,
+                "GEOSEARCH" => geo::geosearch(&command, shared)?,
+                _ => geo::geosearchstore(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "HSET" | "HSETNX" | "HMSET" | "HGET" | "HMGET" | "HDEL" | "HLEN" | "HEXISTS" | "HGETALL" | "HKEYS" | "HVALS" | "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT"
+        | "HPEXPIREAT" | "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" | "HPERSIST" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "HSET" | "HMSET" => hash::hset(&command, shared)?,
+                "HSETNX" => hash::hsetnx(&command, shared)?,
+                "HGET" => hash::hget(&command, shared)?,
+                "HMGET" => hash::hmget(&command, shared)?,
+                "HDEL" => hash::hdel(&command, shared)?,
+                "HLEN" => hash::hlen(&command, shared)?,
+                "HEXISTS" => hash::hexists(&command, shared)?,
+                "HGETALL" | "HKEYS" | "HVALS" => hash::hgetall(&command, shared)?,
+                "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => hash::hexpire(&command, shared)?,
+                "HPERSIST" => hash::hpersist(&command, shared)?,
+                _ => hash::httl(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            validate_command(&command, shared)?;
+            Ok(vec![expire::expire(&command, shared)?])
+        },
+        "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
+            validate_command(&command, shared)?;
+            Ok(vec![expire::ttl(&command, shared)?])
+        },
+        "PERSIST" => {
+            validate_command(&command, shared)?;
+            Ok(vec![expire::persist(&command, shared)?])
+        },
+        // UNLINK always frees big values in the background, DEL only when configured to.
+        "DEL" | "UNLINK" => {
+            validate_command(&command, shared)?;
+            let lazy = command_type == "UNLINK" || shared.config.get_bool("lazyfree-lazy-user-del");
+            let mut deleted = 0;
+            for key in &command[1..] {
+                let value = shared.db.lock().remove(key);
+                if let Some(value) = value {
+                    lazyfree::free(value, lazy, shared);
+                    notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+                    deleted += 1;
+                }
+            }
+            Ok(vec![RESPValue::Number(deleted)])
+        },
+        // Counts as an access of the keys, for eviction, without reading them.
+        "TOUCH" => {
+            validate_command(&command, shared)?;
+            let db = shared.db.lock();
+            let touched = command[1..].iter().filter(|key| db.touch(key)).count();
+            Ok(vec![RESPValue::Number(touched as i64)])
+        },
+        "DUMP" => {
+            validate_command(&command, shared)?;
+            Ok(vec![dump::dump(&command, shared)?])
+        },
+        "RESTORE" | "RESTORE-ASKING" => {
+            validate_command(&command, shared)?;
+            Ok(vec![dump::restore(&command, shared)?])
+        },
+        "MIGRATE" => {
+            validate_command(&command, shared)?;
+            Ok(vec![migrate::migrate(&command, shared)?])
+        },
+        "RANDOMKEY" => {
+            validate_command(&command, shared)?;
+            let db = shared.db.lock();
+            Ok(vec![db.random_key().map_or(RESPValue::Null, |key| RESPValue::BlobString(key.to_owned().into()))])
+        },
+        "DBSIZE" => {
+            validate_command(&command, shared)?;
+            Ok(vec![RESPValue::Number(shared.db.lock().len() as i64)])
+        },
+        // There's a single database, so both flush the same keys.
+        "FLUSHDB" | "FLUSHALL" => {
+            validate_command(&command, shared)?;
+            let asynchronous = match command.get(1).map(|mode| mode.to_ascii_uppercase()).as_deref() {
+                None => shared.config.get_bool("lazyfree-lazy-user-flush"),
+                Some("SYNC") if command.len() == 2 => false,
+                Some("ASYNC") if command.len() == 2 => true,
+                _ => return Err(RESPError::SyntaxError)
+            };
+
+            let flushed = shared.db.lock().flush();
+            lazyfree::free_db(flushed, asynchronous, shared);
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "SORT" | "SORT_RO" => {
+            validate_command(&command, shared)?;
+            Ok(vec![sort::sort(&command, shared)?])
+        },
+        "PFADD" | "PFCOUNT" | "PFMERGE" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "PFADD" => hyperloglog::pfadd(&command, shared)?,
+                "PFCOUNT" => hyperloglog::pfcount(&command, shared)?,
+                _ => hyperloglog::pfmerge(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "BF.RESERVE" | "BF.ADD" | "BF.MADD" | "BF.INSERT" | "BF.EXISTS" | "BF.MEXISTS" | "BF.CARD" | "BF.INFO" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "BF.RESERVE" => bloom::reserve(&command, shared)?,
+                "BF.ADD" => bloom::bf_add(&command, shared)?,
+                "BF.MADD" => bloom::madd(&command, shared)?,
+                "BF.INSERT" => bloom::insert(&command, shared)?,
+                "BF.EXISTS" | "BF.MEXISTS" => bloom::exists(&command, shared)?,
+                "BF.CARD" => bloom::card(&command, shared)?,
+                _ => bloom::info(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "CF.RESERVE" | "CF.ADD" | "CF.ADDNX" | "CF.INSERT" | "CF.INSERTNX" | "CF.EXISTS" | "CF.MEXISTS" | "CF.COUNT" | "CF.DEL" | "CF.INFO" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "CF.RESERVE" => cuckoo::reserve(&command, shared)?,
+                "CF.ADD" | "CF.ADDNX" => cuckoo::cf_add(&command, shared)?,
+                "CF.INSERT" | "CF.INSERTNX" => cuckoo::insert(&command, shared)?,
+                "CF.EXISTS" | "CF.MEXISTS" | "CF.COUNT" => cuckoo::exists(&command, shared)?,
+                "CF.DEL" => cuckoo::del(&command, shared)?,
+                _ => cuckoo::info(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "JSON.SET" | "JSON.GET" | "JSON.DEL" | "JSON.FORGET" | "JSON.NUMINCRBY" | "JSON.ARRAPPEND" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "JSON.SET" => json::set(&command, shared)?,
+                "JSON.GET" => json::get(&command, shared)?,
+                "JSON.DEL" | "JSON.FORGET" => json::del(&command, shared)?,
+                "JSON.NUMINCRBY" => json::numincrby(&command, shared)?,
+                _ => json::arrappend(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "XADD" | "XLEN" | "XRANGE" | "XREVRANGE" | "XREAD" | "XDEL" | "XTRIM" | "XGROUP" | "XREADGROUP" | "XACK"
+        | "XPENDING" | "XCLAIM" | "XAUTOCLAIM" => {
+            validate_command(&command, shared)?;
+
+            let reply = match command_type {
+                "XADD" => stream::xadd(&command, shared)?,
+                "XLEN" => stream::xlen(&command, shared)?,
+                "XRANGE" => stream::xrange(&command, shared, false)?,
+                "XREVRANGE" => stream::xrange(&command, shared, true)?,
+                "XREAD" | "XREADGROUP" => stream::xread(&command, shared)?,
+                "XDEL" => stream::xdel(&command, shared)?,
+                "XTRIM" => stream::xtrim(&command, shared)?,
+                "XGROUP" => stream::xgroup(&command, shared)?,
+                "XACK" => stream::xack(&command, shared)?,
+                "XPENDING" => stream::xpending(&command, shared)?,
+                "XCLAIM" => stream::xclaim(&command, shared)?,
+                _ => stream::xautoclaim(&command, shared)?
+            };
+            Ok(vec![reply])
+        },
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let mut pubsub = shared.pubsub.lock().unwrap();
+            let (id, sender) = (client.id, &client.push_sender);
+            Ok(match command_type {
+                "SUBSCRIBE" => subscribe_replies("subscribe", &command[1..], &mut client.channels, client.patterns.len(), |c| pubsub.subscribe(c, id, sender.clone())),
+                "PSUBSCRIBE" => subscribe_replies("psubscribe", &command[1..], &mut client.patterns, client.channels.len(), |p| pubsub.psubscribe(p, id, sender.clone())),
+                _ => subscribe_replies("ssubscribe", &command[1..], &mut client.shard_channels, 0, |c| pubsub.ssubscribe(c, id, sender.clone())),
+            })
+        },
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => {
+            let mut pubsub = shared.pubsub.lock().unwrap();
+            let id = client.id;
+            Ok(match command_type {
+                "UNSUBSCRIBE" => unsubscribe_replies("unsubscribe", &command[1..], &mut client.channels, client.patterns.len(), |c| pubsub.unsubscribe(c, id)),
+                "PUNSUBSCRIBE" => unsubscribe_replies("punsubscribe", &command[1..], &mut client.patterns, client.channels.len(), |p| pubsub.punsubscribe(p, id)),
+                _ => unsubscribe_replies("sunsubscribe", &command[1..], &mut client.shard_channels, 0, |c| pubsub.sunsubscribe(c, id)),
+            })
+        },
+        "PUBLISH" | "SPUBLISH" => {
+            if command.len() != 3 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let pubsub = shared.pubsub.lock().unwrap();
+            let receivers = if command_type == "PUBLISH" {
+                pubsub.publish(&command[1], command[2].raw())
+            } else {
+                pubsub.spublish(&command[1], command[2].raw())
+            };
+            Ok(vec![RESPValue::Number(receivers as i64)])
+        },
+        "CONFIG" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+            let subcommand = command[1].to_ascii_uppercase();
+            let arity_valid = match subcommand.as_str() {
+                "REWRITE" => command.len() == 2,
+                _ => command.len() >= 3
+            };
+            if !arity_valid {
+                return Err(RESPError::WrongNumberOfArguments(format!("CONFIG|{}", subcommand)));
+            }
+
+            match subcommand.as_str() {
+                "GET" => {
+                    // Every parameter is listed once, even when several patterns match it.
+                    let mut replies = vec![];
+                    let mut seen = HashSet::new();
+                    for pattern in &command[2..] {
+                        for (name, value) in shared.config.get_matching(pattern) {
+                            if seen.insert(name) {
+                                replies.push(RESPValue::BlobString(name.into()));
+                                replies.push(RESPValue::BlobString(value.into()));
+                            }
+                        }
+                    }
+                    Ok(vec![RESPValue::Array(replies)])
+                },
+                "SET" => {
+                    if !command.len().is_multiple_of(2) {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("CONFIG|SET")));
+                    }
+
+                    let changes: Vec<(&str, &str)> = command[2..].chunks(2).map(|pair| (pair[0].as_str(), pair[1].as_str())).collect();
+                    config::set(shared, &changes, &client.info.addr)?;
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                "REWRITE" => {
+                    let path = shared.config_file.as_ref().ok_or(RESPError::NoConfigFile)?;
+                    config::rewrite(shared, path).map_err(|e| RESPError::ConfigRewriteFailed(e.to_string()))?;
+                    logging::log(shared, "notice", "CONFIG REWRITE executed with success");
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                _ => Err(RESPError::UnsupportedCommand(format!("CONFIG {}", command[1])))
+            }
+        },
+        "MULTI" => {
+            if client.multi.is_some() {
+                return Err(RESPError::NestedMulti);
+            }
+
+            client.multi = Some(vec![]);
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "EXEC" => exec_transaction(client, shared),
+        "DISCARD" => {
+            if client.multi.take().is_none() {
+                return Err(RESPError::DiscardWithoutMulti);
+            }
+
+            client.multi_failed = false;
+            client.watched.clear();
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "WATCH" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+            if client.multi.is_some() {
+                return Err(RESPError::WatchInsideMulti);
+            }
+
+            let db = shared.db.lock();
+            for key in &command[1..] {
+                if !client.watched.iter().any(|(watched, _)| watched == key) {
+                    client.watched.push((key.to_string(), db.version(key)));
+                }
+            }
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "UNWATCH" => {
+            client.watched.clear();
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "EVAL" => {
+            if command.len() < 3 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let body = command[1].as_str();
+            shared.scripts.lock().unwrap().insert(scripting::sha1_hex(body), body.to_owned());
+            let (keys, argv) = split_script_keys(&command[2..])?;
+            let call = |command| script_call(command, client, shared, false);
+            Ok(vec![scripting::run_script(body, keys, argv, shared.script_monitor.kill_flag(), call)?])
+        },
+        "EVALSHA" => {
+            if command.len() < 3 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let sha = command[1].to_lowercase();
+            let body = shared.scripts.lock().unwrap().get(&sha).cloned().ok_or(RESPError::NoScript)?;
+            let (keys, argv) = split_script_keys(&command[2..])?;
+            let call = |command| script_call(command, client, shared, false);
+            Ok(vec![scripting::run_script(&body, keys, argv, shared.script_monitor.kill_flag(), call)?])
+        },
+        "SCRIPT" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            if command[1].eq_ignore_ascii_case("KILL") {
+                shared.script_monitor.kill()?;
+                return Ok(vec![RESPValue::SimpleString(String::from("OK"))]);
+            }
+
+            let mut scripts = shared.scripts.lock().unwrap();
+            match command[1].to_ascii_uppercase().as_str() {
+                "LOAD" => {
+                    if command.len() != 3 {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("SCRIPT|LOAD")));
+                    }
+
+                    let sha = scripting::sha1_hex(&command[2]);
+                    scripts.insert(sha.clone(), command[2].to_string());
+                    Ok(vec![RESPValue::BlobString(sha.into())])
+                },
+                "EXISTS" => {
+                    if command.len() < 3 {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("SCRIPT|EXISTS")));
+                    }
+
+                    let exists = command[2..].iter()
+                        .map(|sha| RESPValue::Number(scripts.contains_key(&sha.to_lowercase()) as i64))
+                        .collect();
+                    Ok(vec![RESPValue::Array(exists)])
+                },
+                "FLUSH" => {
+                    // ASYNC and SYNC behave the same, dropping the cache is cheap.
+                    if command.len() > 3 || (command.len() == 3 && !matches!(command[2].to_ascii_uppercase().as_str(), "ASYNC" | "SYNC")) {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("SCRIPT|FLUSH")));
+                    }
+
+                    scripts.clear();
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                _ => Err(RESPError::UnsupportedCommand(format!("SCRIPT {}", command[1])))
+            }
+        },
+        "FUNCTION" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            match command[1].to_ascii_uppercase().as_str() {
+                "LOAD" => {
+                    let replace = command.len() == 4 && command[2].eq_ignore_ascii_case("REPLACE");
+                    if command.len() != 3 && !replace {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("FUNCTION|LOAD")));
+                    }
+
+                    let name = function_load(command.last().unwrap(), replace, shared)?;
+                    Ok(vec![RESPValue::BlobString(name.into())])
+                },
+                "DELETE" => {
+                    if command.len() != 3 {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("FUNCTION|DELETE")));
+                    }
+
+                    shared.libraries.lock().unwrap().remove(command[2].as_str()).ok_or(RESPError::LibraryNotFound)?;
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                "LIST" => {
+                    let mut pattern = None;
+                    let mut with_code = false;
+                    let mut args = command[2..].iter();
+                    while let Some(arg) = args.next() {
+                        match arg.to_ascii_uppercase().as_str() {
+                            "WITHCODE" => with_code = true,
+                            "LIBRARYNAME" => pattern = Some(args.next().ok_or(RESPError::SyntaxError)?.as_str()),
+                            _ => return Err(RESPError::SyntaxError)
+                        }
+                    }
+
+                    let libraries = shared.libraries.lock().unwrap();
+                    Ok(vec![function_list(&libraries, pattern, with_code)])
+                },
+                "FLUSH" => {
+                    if command.len() > 3 || (command.len() == 3 && !matches!(command[2].to_ascii_uppercase().as_str(), "ASYNC" | "SYNC")) {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("FUNCTION|FLUSH")));
+                    }
+
+                    shared.libraries.lock().unwrap().clear();
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                _ => Err(RESPError::UnsupportedCommand(format!("FUNCTION {}", command[1])))
+            }
+        },
+        "FCALL" | "FCALL_RO" => {
+            if command.len() < 3 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            let name = command[1].as_str();
+            let library = shared.libraries.lock().unwrap().values()
+                .find(|library| library.function(name).is_some())
+                .cloned()
+                .ok_or(RESPError::FunctionNotFound)?;
+
+            let read_only = library.function(name).unwrap().flags.iter().any(|flag| flag == "no-writes");
+            if command_type == "FCALL_RO" && !read_only {
+                return Err(RESPError::WriteFlagInReadOnlyCall);
+            }
+
+            let (keys, argv) = split_script_keys(&command[2..])?;
+            let call = |command| script_call(command, client, shared, read_only);
+            Ok(vec![scripting::call_function(&library, name, keys, argv, shared.script_monitor.kill_flag(), call)?])
+        },
+        "MODULE" => {
+            if command.len() < 2 {
+                return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+            }
+
+            match command[1].to_ascii_uppercase().as_str() {
+                "LOAD" => {
+                    if command.len() != 3 {
+                        return Err(RESPError::WrongNumberOfArguments(String::from("MODULE|LOAD")));
+                    }
+
+                    shared.commands.write().unwrap().load(&command[2])?;
+                    Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+                },
+                "LIST" => {
+                    let commands = shared.commands.read().unwrap();
+                    let plugins = commands.plugins().iter().map(|(path, names)| {
+                        RESPValue::Array(vec![
+                            RESPValue::BlobString("name".into()),
+                            RESPValue::BlobString(path.to_owned().into()),
+                            RESPValue::BlobString("commands".into()),
+                            RESPValue::Array(names.iter().map(|name| RESPValue::BlobString(name.to_owned().into())).collect()),
+                        ])
+                    }).collect();
+                    Ok(vec![RESPValue::Array(plugins)])
+                },
+                _ => Err(RESPError::UnsupportedCommand(format!("MODULE {}", command[1])))
+            }
+        },
+        "COMMAND" => Ok(vec![command_introspection(&command, shared)?]),
+        "INFO" => Ok(vec![RESPValue::BlobString(stats::info(&command, shared).into())]),
+        "ACL" => {
+            validate_command(&command, shared)?;
+            let specs = all_commands(shared);
+            Ok(vec![acl::acl(&command, &client.user, &mut shared.acl.lock().unwrap(), &specs)?])
+        },
+        "OBJECT" => {
+            validate_command(&command, shared)?;
+            Ok(vec![object(&command, shared)?])
+        },
+        "MEMORY" => {
+            validate_command(&command, shared)?;
+            Ok(vec![memory::memory(&command, shared)?])
+        },
+        "DEBUG" => {
+            validate_command(&command, shared)?;
+            Ok(vec![debug::debug(&command, &client.info.addr, shared)?])
+        },
+        "LATENCY" => {
+            validate_command(&command, shared)?;
+            Ok(vec![latency::latency(&command, &shared.latency, &shared.stats)?])
+        },
+        "MONITOR" => {
+            validate_command(&command, shared)?;
+            shared.clients.monitor(client.id, client.push_sender.clone());
+            Ok(vec![RESPValue::SimpleString(String::from("OK"))])
+        },
+        "CLIENT" => {
+            validate_command(&command, shared)?;
+            match command[1].to_ascii_uppercase().as_str() {
+                "TRACKING" | "CACHING" | "GETREDIR" => Ok(vec![tracking::client_tracking(&command, client.id, &mut client.caching, shared)?]),
+                _ => Ok(vec![clients::client(&command, &client.info, &mut client.reply_mode, &shared.clients)?])
+            }
+        },
+        "SAVE" => {
+            validate_command(&command, shared)?;
+            Ok(vec![snapshot::save(shared)?])
+        },
+        "BGSAVE" => {
+            validate_command(&command, shared)?;
+            Ok(vec![snapshot::bgsave(&command, shared)?])
+        },
+        "LASTSAVE" => {
+            validate_command(&command, shared)?;
+            Ok(vec![snapshot::lastsave(shared)?])
+        },
+        "BGREWRITEAOF" => {
+            validate_command(&command, shared)?;
+            Ok(vec![aof::bgrewriteaof(shared)?])
+        },
+        "REPLICAOF" | "SLAVEOF" => {
+            validate_command(&command, shared)?;
+            // Replicas of a cluster are set with CLUSTER REPLICATE.
+            if shared.cluster.is_some() {
+                return Err(RESPError::ReplicaOfInCluster);
+            }
+            Ok(vec![replication::replicaof(&command, client, shared)?])
+        },
+        "REPLCONF" => {
+            validate_command(&command, shared)?;
+            Ok(vec![replication::replconf(&command, &mut client.replconf)?])
+        },
+        "FAILOVER" => {
+            validate_command(&command, shared)?;
+            Ok(vec![replication::failover(&command, shared)?])
+        },
+        "WAIT" => {
+            validate_command(&command, shared)?;
+            Ok(vec![replication::wait(&command, shared)?])
+        },
+        "SENTINEL" => {
+            validate_command(&command, shared)?;
+            Ok(vec![sentinel::sentinel(&command, shared)?])
+        },
+        "CLUSTER" => {
+            validate_command(&command, shared)?;
+            Ok(vec![cluster::cluster(&command, shared)?])
+        },
+        // Replied by the full sync that follows.
+        "PSYNC" | "SYNC" => {
+            validate_command(&command, shared)?;
+            replication::psync(&command, client, shared)?;
+            Ok(vec![])
+        },
+        // Run by transactions, otherwise by execute_command.
+        "SHUTDOWN" => {
+            validate_command(&command, shared)?;
+            shutdown::command_now(&command, shared)?;
+            client.close_after_reply = true;
+            Ok(vec![])
+        },
+        _ => {
+            let (spec, plugin) = shared.commands.read().unwrap().get(command_type)
+                .ok_or_else(|| RESPError::UnsupportedCommand(command[0].to_string()))?;
+            validate_command(&command, shared)?;
+
+            let args: Vec<String> = command[1..].iter().map(|arg| arg.to_string()).collect();
+            let reply = plugin.execute(&args, &mut Database::new(shared.db.lock()))?;
+            if spec.has_flag("write") {
+                shared.script_monitor.record_write();
+            }
+            Ok(vec![reply])
+        }
+    }
+}
+
+// Commands that are still served while a script is running for longer than the busy threshold.
+fn allowed_while_busy(command: &[Arg]) -> bool {
+    match command[0].as_str() {
+        "SCRIPT" => command.len() == 2 && command[1].eq_ignore_ascii_case("KILL"),
+        "SHUTDOWN" => command.len() == 2 && command[1].eq_ignore_ascii_case("NOSAVE"),
+        _ => false
+    }
+}
+
+// Whether CLIENT PAUSE WRITE holds the command back: commands that write or might propagate writes,
+// and EXEC of transactions with any of those.
+fn pausable_write(command: &[Arg], client: &Client, shared: &SharedState) -> bool {
+    let is_write = |command: &[Arg]| lookup_command(&command[0], shared)
+        .is_some_and(|spec| spec.has_flag("write") || spec.has_flag("may-replicate"));
+    match (command[0].as_str(), &client.multi) {
+        ("EXEC", Some(queued)) => queued.iter().any(|command| is_write(command)),
+        _ => is_write(command)
+    }
+}
+
+async fn execute_command(command: Vec<Arg>, mut client: Client, shared: &Arc<SharedState>) -> (Client, Vec<RESPValue>) {
+    let command = match resolve_renamed(command, shared) {
+        Ok(command) => command,
+        Err(e) => return (client, vec![e.into()])
+    };
+    // Sentinels only serve the commands about monitoring.
+    if shared.sentinel.is_some() && !sentinel::allowed(&command[0]) {
+        return (client, vec![RESPError::UnsupportedCommand(command[0].to_string()).into()]);
+    }
+    // Until the handshake of a QUIC connection completes, its commands may be 0-RTT data replayed by
+    // someone else, so only reads run and the rest wait for it.
+    if let Some(handshake) = &mut client.handshake {
+        let read_only = lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("readonly"));
+        if *handshake.borrow() || !read_only {
+            if handshake.wait_for(|done| *done).await.is_err() {
+                client.close_after_reply = true;
+                return (client, vec![]);
+            }
+            client.handshake = None;
+        }
+    }
+    if !client.authenticated && !lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("no-auth")) {
+        return (client, vec![RESPError::NoAuth.into()]);
+    }
+    let checked = check_permissions(&command, &client, shared)
+        .and_then(|_| cluster::check_redirect(&command, &client, shared))
+        .and_then(|_| check_read_only(&command, &client, shared))
+        .and_then(|_| check_subscribed(&command, &client));
+    client.asking = false;
+    if let Err(e) = checked {
+        if client.multi.is_some() {
+            client.multi_failed = true;
+        }
+        return (client, vec![e.into()]);
+    }
+
+    // Commands are only paused once they run rather than when queued, and CLIENT never is, so the
+    // pause can be lifted.
+    if command[0] != "CLIENT" && (client.multi.is_none() || command[0] == "EXEC") {
+        shared.clients.wait_unpaused(pausable_write(&command, &client, shared)).await;
+        // This may have become a replica meanwhile (see FAILOVER).
+        if client.multi.is_none() {
+            if let Err(e) = check_read_only(&command, &client, shared) {
+                return (client, vec![e.into()]);
+            }
+        }
+    }
+
+    let threshold = Duration::from_millis(shared.busy_reply_threshold.load(Ordering::Relaxed));
+    if shared.script_monitor.wait(threshold).await && !allowed_while_busy(&command) {
+        return (client, vec![RESPError::Busy.into()]);
+    }
+
+    // Scripts run on a blocking thread so the runtime can keep answering with -BUSY (and accept
+    // SCRIPT KILL) while they run, the monitor keeps everyone else from executing meanwhile. Owning
+    // the whole keyspace before starting, they run one at a time.
+    if client.multi.is_none() && matches!(command[0].as_str(), "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO") {
+        let script = {
+            let shared = shared.clone();
+            move || {
+                let _owned = shared.db.own_all();
+                shared.script_monitor.start();
+                let responses = timed_process_command(command, &mut client, &shared);
+                shared.script_monitor.finish();
+                (client, responses)
+            }
+        };
+        return match &shared.actors {
+            Some(actors) => actors.run(&(0..shared.db.shards()).collect::<Vec<_>>(), script).await,
+            None => tokio::task::spawn_blocking(script).await.unwrap()
+        };
+    }
+
+    if client.multi.is_none() && matches!(command[0].as_str(), "XREAD" | "XREADGROUP") {
+        expire_keys(&command, shared);
+        audit(&command, &client, shared);
+        monitor(&command, &client, shared);
+        track_command(&command, shared);
+        let journaled = (command[0] == "XREADGROUP" && aof::journaling(shared)).then(|| command.clone());
+        // A blocked client can still be killed by CLIENT KILL.
+        let info = client.info.clone();
+        return tokio::select! {
+            reply = blocking_xread(command, shared) => {
+                if let (Some(command), Ok(reply)) = (&journaled, &reply) {
+                    aof::feed(command, reply, shared);
+                }
+                (client, vec![reply.unwrap_or_else(|e| e.into())])
+            },
+            _ = info.killed.notified() => {
+                client.close_after_reply = true;
+                (client, vec![])
+            }
+        };
+    }
+
+    if client.multi.is_none() && command[0] == "WAIT" {
+        let _blocked = shared.stats.block();
+        let info = client.info.clone();
+        return tokio::select! {
+            reply = async {
+                validate_command(&command, shared)?;
+                replication::blocking_wait(&command, shared).await
+            } => (client, vec![reply.unwrap_or_else(|e| e.into())]),
+            _ = info.killed.notified() => {
+                client.close_after_reply = true;
+                (client, vec![])
+            }
+        };
+    }
+
+    // Waits for lagging replicas and scripts in progress, and only replies when failing.
+    if client.multi.is_none() && command[0] == "SHUTDOWN" {
+        let result = match validate_command(&command, shared) {
+            Ok(_) => shutdown::command(&command, shared).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = result {
+            return (client, vec![e.into()]);
+        }
+        client.close_after_reply = true;
+        return (client, vec![]);
+    }
+
+    // With a backing store, the keys of the command are read through it and its writes forwarded to
+    // it, see backing.rs. The commands of transactions are only queued until EXEC, which isn't.
+    let Some(backed) = (shared.backing.is_some() && client.multi.is_none()).then(|| command.clone()) else {
+        return run_command(command, client, shared).await;
+    };
+    if let Err(e) = backing::read_through(&backed, shared).await {
+        return (client, vec![e.into()]);
+    }
+    let (client, responses) = run_command(command, client, shared).await;
+    if responses.iter().any(|response| response.as_simple_error().is_some()) {
+        return (client, responses);
+    }
+    match backing::write_through(&backed, shared).await {
+        Ok(()) => (client, responses),
+        Err(e) => (client, vec![e.into()])
+    }
+}
+
+async fn run_command(command: Vec<Arg>, mut client: Client, shared: &Arc<SharedState>) -> (Client, Vec<RESPValue>) {
+    // With actors, the command runs on the actors of the shards it accesses, see actors.rs. The
+    // commands of transactions are only queued until EXEC.
+    if let Some(actors) = shared.actors.as_ref().filter(|_| client.multi.is_none() || command[0] == "EXEC") {
+        let shards = command_shards(&command, &client, shared);
+        if !shards.is_empty() {
+            let shared = shared.clone();
+            return actors.run(&shards, move || {
+                let responses = timed_process_command(command, &mut client, &shared);
+                (client, responses)
+            }).await;
+        }
+    }
+
+    let responses = timed_process_command(command, &mut client, shared);
+    (client, responses)
+}
+
+// Processes the command, reporting it to the latency monitor when it was slow.
+fn timed_process_command(command: Vec<Arg>, client: &mut Client, shared: &SharedState) -> Vec<RESPValue> {
+    let fast = lookup_command(&command[0], shared).is_some_and(|spec| spec.has_flag("fast"));
+    let start = Instant::now();
+    let responses = process_command(command, client, shared).unwrap_or_else(|e| vec![e.into()]);
+    latency::record(shared, if fast { "fast-command" } else { "command" }, start.elapsed());
+    responses
+}
+
+// XREAD and XREADGROUP with BLOCK wait for new entries by retrying whenever data is added to the keyspace, until
+// it gets a reply or times out. Inside transactions and scripts it never blocks.
+async fn blocking_xread(mut command: Vec<Arg>, shared: &SharedState) -> Result<RESPValue, RESPError> {
+    validate_command(&command, shared)?;
+    let block = match stream::parse_xread(&command)?.block {
+        Some(block) => block,
+        None => return stream::xread(&command, shared)
+    };
+    stream::resolve_last_ids(&mut command, shared)?;
+
+    let _blocked = shared.stats.block();
+    let deadline = (block > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(block));
+    loop {
+        let notified = shared.keys_ready.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let reply = stream::xread(&command, shared)?;
+        if !matches!(reply, RESPValue::Null) {
+            return Ok(reply);
+        }
+
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    return Ok(RESPValue::Null);
+                }
+            },
+            None => notified.await
+        }
+    }
+}
+
+// Replays the append only file as a client of its own. Keys aren't expired meanwhile, the file has
+// the deletions of the keys that expired when it was written.
+fn replay_append_only_file(shared: &SharedState) -> std::io::Result<Option<usize>> {
+    let info = Arc::new(ClientInfo::new(0, String::new(), String::new(), acl::DEFAULT_USER));
+    let mut client = Client::new(info, mpsc::unbounded_channel().0, true);
+    aof::load(shared, |mut command| {
+        uppercase_name(&mut command);
+        dispatch_command(command, &mut client, shared).map(|_| ())
+    })
+}
+
+// Serves a client connected over TCP, or over a unix socket in which case there is no address.
+// Protected mode only accepts connections from the loopback interface while the server listens on
+// other interfaces without requiring a password. Connections to the unix socket are always accepted.
+fn denied_by_protected_mode(maybe_addr: Option<&str>, shared: &SharedState) -> bool {
+    let Some(ip) = maybe_addr.and_then(|addr| addr.parse::<std::net::SocketAddr>().ok()).map(|addr| addr.ip().to_canonical()) else {
+        return false;
+    };
+    if !shared.config.get_bool("protected-mode") || ip.is_loopback() || shared.acl.lock().unwrap().auth_required() {
+        return false;
+    }
+    shared.config.get("bind").split(' ').map(|address| address.trim_start_matches('-')).any(|address| {
+        matches!(address, "*" | "::*") || address.parse::<std::net::IpAddr>().is_ok_and(|ip| !ip.is_loopback())
+    })
+}
+
+// Registers a client connected from `addr` (empty when unknown, like on unix sockets) to `laddr`.
+fn connect(id: ClientId, addr: String, laddr: String, push_sender: UnboundedSender<RESPValue>, shared: &SharedState) -> Client {
+    Stats::incr(&shared.stats.total_connections_received);
+    Stats::incr(&shared.stats.connected_clients);
+    let info = Arc::new(ClientInfo::new(id, addr, laddr, acl::DEFAULT_USER));
+    shared.clients.register(info.clone());
+    Client::new(info, push_sender, !shared.acl.lock().unwrap().auth_required())
+}
+
+// Forgets a client that disconnected.
+fn disconnect(client: &mut Client, shared: &SharedState) {
+    unsubscribe_all(client, shared);
+    shared.clients.unregister(client.id);
+    shared.tracking.lock().unwrap().disable(client.id);
+    shared.clients.unmonitor(client.id);
+    Stats::decr(&shared.stats.connected_clients);
+}
+
+async fn handle_connection(socket: impl AsyncRead + AsyncWrite + Unpin, maybe_addr: Option<String>, laddr: String, handshake: Option<watch::Receiver<bool>>, shared: Arc<SharedState>) {
+    let id = shared.next_client_id.fetch_add(1, Ordering::Relaxed);
+    // The messages about the client carry its id and address.
+    let span = tracing::info_span!("client", id, addr = maybe_addr.as_deref().unwrap_or(&laddr));
+    serve_connection(socket, id, maybe_addr, laddr, handshake, shared).instrument(span).await
+}
+
+async fn serve_connection(socket: impl AsyncRead + AsyncWrite + Unpin, id: ClientId, maybe_addr: Option<String>, laddr: String, handshake: Option<watch::Receiver<bool>>, shared: Arc<SharedState>) {
+    let (reader, writer) = tokio::io::split(socket);
+    let (mut reader, mut writer) = (FramedRead::new(reader, RESPCodec::new(protocol_limits())), ReplyWriter::new(writer));
+    if denied_by_protected_mode(maybe_addr.as_deref(), &shared) {
+        logging::log(&shared, "warning", format!("Denied the connection from {} in protected mode", maybe_addr.unwrap()));
+        let _ = writer.send(RESPError::ProtectedMode.into()).await;
+        return;
+    }
+    if shared.stats.connected_clients.load(Ordering::Relaxed) >= shared.config.get_int("maxclients") as u64 {
+        Stats::incr(&shared.stats.rejected_connections);
+        logging::log(&shared, "verbose", "Rejected a connection, the maximum number of clients was reached");
+        let _ = writer.send(RESPError::MaxClients.into()).await;
+        return;
+    }
+
+    let (push_sender, mut push_receiver) = mpsc::unbounded_channel();
+    let mut client = connect(id, maybe_addr.clone().unwrap_or_default(), laddr, push_sender, &shared);
+    client.handshake = handshake;
+    let info = client.info.clone();
+
+    // Replies are buffered while more requests are ready, and flushed once none is, so a pipeline is
+    // replied to with a write per read rather than a write per reply.
+    let mut unflushed = false;
+    loop {
+        // The proto-max-* limits may have changed since the last request.
+        reader.decoder_mut().set_limits(protocol_limits());
+        tokio::select! {
+            biased;
+            _ = info.killed.notified() => break,
+            maybe_result = reader.next() => {
+                let result = match maybe_result {
+                    Some(result) => result,
+                    // The client may only have closed its side (like QUIC clients finishing their
+                    // stream once the requests are sent), so the replies so far are still written.
+                    None => {
+                        if unflushed {
+                            let _ = writer.flush().await;
+                        }
+                        break;
+                    }
+                };
+                info.state.lock().unwrap().last_interaction = std::time::Instant::now();
+
+                match result {
+                    Ok(value) => {
+                        match value {
+                            RESPValue::Array(values) => {
+                                let invalid = if values.is_empty() {
+                                    Some("empty request")
+                                } else if !values.iter().all(|v| matches!(v, RESPValue::BlobString(_))) {
+                                    Some("expected an array of blob strings")
+                                } else {
+                                    None
+                                };
+                                if let Some(reason) = invalid {
+                                    logging::log(&shared, "warning", format!("Invalid request: {}", reason));
+                                    writer.feed(RESPError::InvalidRequest(reason).into()).await.unwrap();
+                                    unflushed = true;
+                                    continue;
+                                }
+
+                                let commands: Vec<Arg> = values.into_iter()
+                                    .map(|v| Arg::from_bytes(v.into_blob_string().unwrap()))
+                                    .collect();
+                                logging::command(&shared, &commands);
+                                info.state.lock().unwrap().last_command = commands[0].to_ascii_lowercase();
+                                if let Err(e) = ratelimit::throttle(&commands, &mut client, &shared).await {
+                                    writer.feed(e.into()).await.unwrap();
+                                    unflushed = true;
+                                    continue;
+                                }
+                                let span = otel::command_span(&commands, &client, &shared);
+                                let execution = execute_command(commands, client, &shared);
+                                tokio::pin!(execution);
+                                // The replies so far aren't held back by a command that waits, like
+                                // blocking reads.
+                                let (returned_client, responses) = match futures::poll!(&mut execution) {
+                                    std::task::Poll::Ready(executed) => executed,
+                                    std::task::Poll::Pending => {
+                                        if std::mem::take(&mut unflushed) {
+                                            let _ = writer.flush().await;
+                                        }
+                                        info.state.lock().unwrap().blocked = true;
+                                        let executed = execution.await;
+                                        let mut state = info.state.lock().unwrap();
+                                        state.blocked = false;
+                                        state.last_interaction = Instant::now();
+                                        executed
+                                    }
+                                };
+                                otel::end_command(span, &responses, &shared);
+                                client = returned_client;
+                                client.sync_info();
+                                if client.reply_mode.next_reply() {
+                                    for response in responses {
+                                        writer.feed(response).await.unwrap();
+                                        unflushed = true;
+                                    }
+                                }
+                                if client.close_after_reply || client.replica_link.is_some() {
+                                    let _ = writer.flush().await;
+                                    break;
+                                }
+                            },
+                            _ => {
+                                logging::log(&shared, "warning", "Invalid request: expected an array");
+                                writer.feed(RESPError::InvalidRequest("expected an array").into()).await.unwrap();
+                                unflushed = true;
+                            }
+                        }
+                    },
+                    Err(ProtocolError::IOError(e)) => {
+                        logging::log(&shared, "verbose", format!("Failed reading from the client: {}", e));
+                        break;
+                    },
+                    // The rest of the stream can't be made sense of, so like Redis the client is
+                    // replied with the error and disconnected.
+                    Err(e) => {
+                        logging::log(&shared, "verbose", format!("Closing the connection: {}", e));
+                        let _ = writer.send(RESPError::Protocol(e).into()).await;
+                        break;
+                    }
+                }
+            },
+            Some(push) = push_receiver.recv() => {
+                writer.feed(push).await.unwrap();
+                unflushed = true;
+            },
+            result = writer.flush(), if unflushed => {
+                unflushed = false;
+                if result.is_err() {
+                    break;
+                }
+            },
+        }
+    }
+
+    if let Some(link) = client.replica_link.take() {
+        info.state.lock().unwrap().replica = true;
+        let socket = reader.into_inner().unsplit(writer.into_inner());
+        replication::serve_replica(socket, link, &info, &shared).await;
+    }
+
+    disconnect(&mut client, &shared);
+
+    match maybe_addr {
+        Some(addr) => logging::log(&shared, "verbose", format!("Closing connection from {}", addr)),
+        None => logging::log(&shared, "verbose", "Closing connection")
+    }
+}
+
+// The directives of the configuration file, followed by the command line arguments overriding them.
+fn read_directives(shared: &SharedState) -> Result<Vec<Vec<String>>, String> {
+    let mut directives = match &shared.config_file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed reading {}: {}", path, e))?;
+            config::parse_file(&contents)?
+        },
+        None => vec![]
+    };
+    directives.extend(shared.config_overrides.iter().cloned());
+    Ok(directives)
+}
+
+// The directives setting config parameters, ones taking several values (like bind) getting them
+// space separated.
+fn parameter_changes(directives: &[Vec<String>]) -> Result<Vec<(&str, String)>, String> {
+    directives.iter()
+        .filter(|directive| !matches!(directive[0].as_str(), "rename-command" | "loadmodule" | "sentinel"))
+        .map(|directive| match &directive[..] {
+            [name] => Err(format!("Missing value for '{}'", name)),
+            [name, values @ ..] => Ok((name.as_str(), values.join(" "))),
+            [] => unreachable!()
+        })
+        .collect()
+}
+
+// Applies the directives of the configuration file, and then the ones overriding them (like the
+// command line arguments).
+fn load_config(config_file: Option<String>, overrides: Vec<Vec<String>>, sentinel: bool, shared: &mut SharedState) -> Result<(), String> {
+    shared.config_file = config_file;
+    shared.config_overrides = overrides;
+    if sentinel {
+        shared.sentinel = Some(Sentinel::default());
+    }
+
+    let directives = read_directives(shared)?;
+    for directive in &directives {
+        match (directive[0].as_str(), &directive[1..]) {
+            ("rename-command", [name, new_name]) => {
+                let name = name.to_ascii_uppercase();
+                if lookup_builtin_command(&name).is_none() {
+                    return Err(format!("No such command '{}' to rename", name));
+                }
+                shared.renamed_commands.insert(name, new_name.to_owned());
+            },
+            ("loadmodule", [path]) => {
+                shared.commands.write().unwrap().load(path).map_err(|e| format!("Failed loading module {}: {}", path, e))?;
+            },
+            ("sentinel", args) => match &mut shared.sentinel {
+                Some(sentinel) => sentinel.configure(args).map_err(|e| format!("Invalid sentinel directive: {}", e))?,
+                None => return Err(String::from("sentinel directives are only valid in sentinel mode (--sentinel)"))
+            },
+            ("rename-command" | "loadmodule", _) => return Err(format!("Wrong number of arguments for '{}'", directive[0])),
+            _ => {}
+        }
+    }
+
+    let changes = parameter_changes(&directives)?;
+    let changes: Vec<(&str, &str)> = changes.iter().map(|(name, value)| (*name, value.as_str())).collect();
+    config::set_at_startup(shared, &changes).map_err(|e| format!("Invalid configuration: {}", e))
+}
+
+// Re-reads the configuration file on SIGHUP. Renamed commands and modules are only loaded at startup.
+fn reload_config(shared: &SharedState) -> Result<(), String> {
+    let directives = read_directives(shared)?;
+    let changes = parameter_changes(&directives)?;
+    let changes: Vec<(&str, &str)> = changes.iter().map(|(name, value)| (*name, value.as_str())).collect();
+    config::reload(shared, &changes).map_err(|e| format!("Invalid configuration: {}", e))?;
+    // The keys of a KMS may have been rotated.
+    encryption::reload_keys(shared)
+}
+
+// The addresses of `bind`, each with whether failing to listen on it is fine (marked by a `-`).
+fn bind_addresses(shared: &SharedState) -> Vec<(String, bool)> {
+    shared.config.get("bind").split(' ').map(|address| {
+        let (address, optional) = match address.strip_prefix('-') {
+            Some(address) => (address, true),
+            None => (address, false)
+        };
+        let ip = match address {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            ip => ip
+        };
+        (ip.to_owned(), optional)
+    }).collect()
+}
+
+// Listens on the port of every address of `bind`.
+async fn listen(port: u16, shared: &SharedState) -> Result<Vec<TcpListener>, String> {
+    let mut listeners = vec![];
+    for (ip, optional) in bind_addresses(shared) {
+        match TcpListener::bind((ip.as_str(), port)).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if optional => logging::log(shared, "warning", format!("Skipping listening on {}:{}: {}", ip, port, e)),
+            Err(e) => return Err(format!("Failed listening on {}:{}: {}", ip, port, e))
+        }
+    }
+    if listeners.is_empty() {
+        return Err(format!("Failed listening on port {} of any address", port));
+    }
+    Ok(listeners)
+}
+
+// Applies `tcp-keepalive` and `tcp-nodelay` to an accepted connection, so connections to dead peers
+// are eventually closed and small replies aren't delayed.
+fn set_socket_options(socket: socket2::SockRef<'_>, shared: &SharedState) {
+    let keepalive = shared.config.get_int("tcp-keepalive") as u64;
+    let result = socket.set_tcp_nodelay(shared.config.get_bool("tcp-nodelay")).and_then(|_| match keepalive {
+        0 => Ok(()),
+        seconds => {
+            // Like in Redis, the peer is probed every third of the time after the first probe.
+            let keepalive = socket2::TcpKeepalive::new()
+                .with_time(Duration::from_secs(seconds))
+                .with_interval(Duration::from_secs((seconds / 3).max(1)));
+            socket.set_tcp_keepalive(&keepalive)
+        }
+    });
+    if let Err(e) = result {
+        logging::log(shared, "warning", format!("Failed setting the socket options of a connection: {}", e));
+    }
+}
+
+async fn accept_tcp(listeners: &[TcpListener]) -> std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)> {
+    if listeners.is_empty() {
+        return std::future::pending().await;
+    }
+    futures::future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await.0
+}
+
+async fn accept_unix(listeners: &[UnixListener]) -> std::io::Result<tokio::net::UnixStream> {
+    if listeners.is_empty() {
+        return std::future::pending().await;
+    }
+    futures::future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await.0.map(|(socket, _)| socket)
+}
+
+// Receives the signal, never when it isn't handled.
+async fn received(signal: &mut Option<Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        },
+        None => std::future::pending().await
+    }
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(listeners: Vec<TcpListener>, shared: &Arc<SharedState>) -> Result<(), String> {
+    tokio::spawn(grpc::serve(listeners, shared.clone()));
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_listeners: Vec<TcpListener>, _shared: &Arc<SharedState>) -> Result<(), String> {
+    Err(String::from("gRPC is not supported by this build"))
+}
+
+#[cfg(feature = "quic")]
+fn serve_quic(port: u16, shared: &Arc<SharedState>) -> Result<(), String> {
+    quic::serve(port, shared)
+}
+
+#[cfg(not(feature = "quic"))]
+fn serve_quic(_port: u16, _shared: &Arc<SharedState>) -> Result<(), String> {
+    Err(String::from("QUIC is not supported by this build"))
+}
+
+// What an embedded server hands over once serving: its state, and the runtime it runs on.
+type Ready = (Arc<SharedState>, tokio::runtime::Handle);
+
+// Loads the dataset and serves clients until shut down, on the given listeners rather than the
+// configured ones when there are any. A server running as its own process handles signals and exits
+// when shut down, while an embedded one (see Server::start) leaves the signals to the application,
+// hands `ready` its state once serving, and returns when shut down.
+async fn serve(mut shared: SharedState, listeners: Vec<std::net::TcpListener>, ready: Option<std::sync::mpsc::Sender<Ready>>) -> Result<(), Box<dyn std::error::Error>> {
+    let process = ready.is_none();
+    let handled = |kind: SignalKind| process.then(|| signal(kind)).transpose();
+    // Their default is to terminate, which loading the dataset shouldn't be prone to.
+    let mut hangup = handled(SignalKind::hangup())?;
+    let mut user1 = handled(SignalKind::user_defined1())?;
+    // Probes are answered while the dataset loads, as not ready yet.
+    health::serve(&shared)?;
+    if shared.config.get("execution-model") == "actors" {
+        shared.actors = Some(Actors::start(shared.db.shards()).map_err(|e| format!("Failed starting the shard actors: {}", e))?);
+    }
+    let tiered_storage_dir = shared.config.get("tiered-storage-dir");
+    if !tiered_storage_dir.is_empty() {
+        let tier = tiering::open(&tiered_storage_dir).map_err(|e| format!("Failed opening {}: {}", tiered_storage_dir, e))?;
+        shared.db.lock().set_tier(tier);
+    }
+    let start = Instant::now();
+    if shared.sentinel.is_some() {
+        // Sentinels have no keyspace to load.
+        shared.aof.start(None)?;
+    } else if shared.config.get_bool("appendonly") {
+        let path = shared.config.get("appendfilename");
+        match replay_append_only_file(&shared) {
+            Ok(Some(commands)) => logging::log(&shared, "notice", format!("DB loaded from append only file: {} commands in {:.3} seconds", commands, start.elapsed().as_secs_f64())),
+            Ok(None) => {},
+            Err(e) => return Err(format!("Failed loading {}: {}", path, e).into())
+        }
+        shared.aof.start(Some(&path)).map_err(|e| format!("Failed opening {}: {}", path, e))?;
+    } else {
+        shared.aof.start(None)?;
+        match snapshot::load(&shared) {
+            Ok(Some(keys)) => logging::log(&shared, "notice", format!("DB loaded from disk: {} keys in {:.3} seconds", keys, start.elapsed().as_secs_f64())),
+            Ok(None) => {},
+            Err(e) => return Err(format!("Failed loading {}: {}", shared.config.get("dbfilename"), e).into())
+        }
+    }
+    shared.snapshots.loaded(&shared);
+    if shared.config.get_bool("cluster-enabled") {
+        shared.cluster = Some(Cluster::open(&shared)?);
+    }
+    // The loaded keyspace may not fit in memory, it does once enough of it was spilled.
+    if shared.db.lock().has_tier() {
+        let _ = eviction::free_memory_if_needed(&shared);
+    }
+    let shared = Arc::new(shared);
+
+    let (inherited, inherited_unix) = if !listeners.is_empty() {
+        let listeners: std::io::Result<Vec<TcpListener>> = listeners.into_iter()
+            .map(|listener| listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)))
+            .collect();
+        (listeners?, vec![])
+    } else if process {
+        systemd::listeners().map_err(|e| format!("Failed inheriting the sockets of systemd: {}", e))?
+    } else {
+        (vec![], vec![])
+    };
+    // Sockets passed by systemd (or bound by the application) are served instead of the configured ones.
+    let activated = !inherited.is_empty() || !inherited_unix.is_empty();
+    let port = shared.config.get_int("port") as u16;
+    // Port 0 leaves only the TLS port or the unix socket.
+    let listeners = match port {
+        _ if activated => inherited,
+        0 => vec![],
+        // Served by threads of io_uring instead.
+        port if shared.config.get("network-backend") == "io_uring" => {
+            uring::serve(port, &shared)?;
+            vec![]
+        },
+        port => listen(port, &shared).await?
+    };
+    let tls_port = shared.config.get_int("tls-port") as u16;
+    let tls_acceptor = tls::acceptor(&shared).map_err(|e| format!("Failed setting up TLS: {}", e))?.map(Arc::new);
+    let tls_listeners = match tls_acceptor {
+        Some(_) => listen(tls_port, &shared).await?,
+        None => vec![]
+    };
+    let rest_port = shared.config.get_int("rest-port") as u16;
+    let rest_listeners = match rest_port {
+        0 => vec![],
+        port => listen(port, &shared).await?
+    };
+    let grpc_port = shared.config.get_int("grpc-port") as u16;
+    if grpc_port != 0 {
+        serve_grpc(listen(grpc_port, &shared).await?, &shared)?;
+    }
+    let quic_port = shared.config.get_int("quic-port") as u16;
+    if quic_port != 0 {
+        serve_quic(quic_port, &shared).map_err(|e| format!("Failed setting up QUIC: {}", e))?;
+    }
+
+    let unixsocket = shared.config.get("unixsocket");
+    let unix_listeners = if activated || unixsocket.is_empty() {
+        inherited_unix
+    } else {
+        // A socket file left behind by a previous run would fail the bind.
+        let _ = std::fs::remove_file(&unixsocket);
+        vec![UnixListener::bind(&unixsocket)?]
+    };
+
+    let mut terminate = handled(SignalKind::terminate())?;
+    let mut interrupt = handled(SignalKind::interrupt())?;
+    tokio::spawn(cron::run(shared.clone()));
+    tokio::spawn(snapshot::saver(shared.clone()));
+    tokio::spawn(replication::replicate(shared.clone()));
+    tokio::spawn(replication::coordinate_failover(shared.clone()));
+    tokio::spawn(sentinel::run(shared.clone()));
+    tokio::spawn(cluster::run(shared.clone()));
+    tokio::spawn(otel::exporter(shared.clone()));
+    tokio::spawn(metrics::pusher(shared.clone()));
+
+    if activated && !process {
+        logging::log(&shared, "notice", "Ready to accept connections on the sockets of the application");
+    } else if activated {
+        logging::log(&shared, "notice", "Ready to accept connections on the sockets of systemd");
+    } else if port != 0 {
+        logging::log(&shared, "notice", format!("Ready to accept connections on port {}", port));
+    }
+    if !tls_listeners.is_empty() {
+        logging::log(&shared, "notice", format!("Ready to accept TLS connections on port {}", tls_port));
+    }
+    if !rest_listeners.is_empty() {
+        logging::log(&shared, "notice", format!("Ready to accept HTTP requests on port {}", rest_port));
+    }
+    if grpc_port != 0 {
+        logging::log(&shared, "notice", format!("Ready to accept gRPC calls on port {}", grpc_port));
+    }
+    if quic_port != 0 {
+        logging::log(&shared, "notice", format!("Ready to accept QUIC connections on port {}", quic_port));
+    }
+    // The dataset was loaded by now, so the server is ready for traffic.
+    shared.health.set_ready(true);
+    match ready {
+        Some(ready) => {
+            let _ = ready.send((shared.clone(), tokio::runtime::Handle::current()));
+        },
+        None => if let Err(e) = systemd::notify("READY=1\nSTATUS=Ready to accept connections") {
+            logging::log(&shared, "warning", format!("Failed notifying systemd: {}", e));
+        }
+    }
+    loop {
+        tokio::select! {
+            result = accept_tcp(&listeners) => match result {
+                Ok((socket, addr)) => {
+                    logging::log(&shared, "verbose", format!("New connection from {}", addr));
+                    set_socket_options(socket2::SockRef::from(&socket), &shared);
+                    let laddr = socket.local_addr().map_or_else(|_| String::new(), |laddr| laddr.to_string());
+                    tokio::spawn(handle_connection(socket, Some(addr.to_string()), laddr, None, shared.clone()));
+                },
+                Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+            },
+            result = accept_tcp(&tls_listeners) => match result {
+                Ok((socket, addr)) => {
+                    logging::log(&shared, "verbose", format!("New TLS connection from {}", addr));
+                    set_socket_options(socket2::SockRef::from(&socket), &shared);
+                    let laddr = socket.local_addr().map_or_else(|_| String::new(), |laddr| laddr.to_string());
+                    let acceptor = tls_acceptor.clone().unwrap();
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        match tls::accept(&acceptor, socket).await {
+                            Ok(socket) => handle_connection(socket, Some(addr.to_string()), laddr, None, shared).await,
+                            Err(e) => logging::log(&shared, "verbose", format!("Failed TLS handshake with {}: {}", addr, e))
+                        }
+                    });
+                },
+                Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+            },
+            result = accept_tcp(&rest_listeners) => match result {
+                Ok((socket, addr)) => {
+                    logging::log(&shared, "verbose", format!("New HTTP connection from {}", addr));
+                    tokio::spawn(rest::handle(socket, addr, shared.clone()));
+                },
+                Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+            },
+            result = accept_unix(&unix_listeners) => match result {
+                Ok(socket) => {
+                    let path = socket.local_addr().ok().and_then(|laddr| laddr.as_pathname().map(|path| path.display().to_string()));
+                    let path = path.unwrap_or_else(|| unixsocket.clone());
+                    logging::log(&shared, "verbose", format!("New connection on {}", path));
+                    tokio::spawn(handle_connection(socket, None, path, None, shared.clone()));
+                },
+                Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+            },
+            _ = received(&mut hangup) => match reload_config(&shared) {
+                Ok(()) => logging::log(&shared, "notice", "Received SIGHUP, configuration reloaded"),
+                Err(e) => logging::log(&shared, "warning", format!("Received SIGHUP, failed reloading the configuration: {}", e))
+            },
+            // Lets the log file be rotated.
+            _ = received(&mut user1) => match shared.logger.open(&shared.config.get("logfile")) {
+                Ok(()) => logging::log(&shared, "notice", "Received SIGUSR1, log file reopened"),
+                Err(e) => logging::log(&shared, "warning", format!("Received SIGUSR1, failed reopening the log file: {}", e))
+            },
+            // No connections are accepted while shutting down.
+            _ = received(&mut terminate) => shutdown::on_signal(&shared, "SIGTERM").await,
+            _ = received(&mut interrupt) => shutdown::on_signal(&shared, "SIGINT").await,
+            // Embedded servers stop serving once shut down.
+            _ = async { shared.stop.as_ref().unwrap().notified().await }, if !process => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The names COMMAND and COMMAND DOCS report, lowercase like in Redis.
+    fn reported_names(shared: &SharedState) -> Vec<String> {
+        let name = |value: &RESPValue| match value {
+            RESPValue::BlobString(name) => String::from_utf8(name.to_vec()).unwrap(),
+            other => panic!("expected a command name, got {:?}", other)
+        };
+        let mut names = vec![];
+        let Ok(RESPValue::Array(infos)) = command_introspection(&[Arg::from("COMMAND")], shared) else {
+            panic!("COMMAND didn't reply with an array");
+        };
+        for info in &infos {
+            let RESPValue::Array(fields) = info else {
+                panic!("expected the info of a command, got {:?}", info);
+            };
+            names.push(name(&fields[0]));
+        }
+        let Ok(RESPValue::Array(docs)) = command_introspection(&[Arg::from("COMMAND"), Arg::from("DOCS")], shared) else {
+            panic!("COMMAND DOCS didn't reply with an array");
+        };
+        names.extend(docs.iter().step_by(2).map(name));
+        names
+    }
+
+    #[test]
+    fn accepts_every_reported_command() {
+        let shared = SharedState::new();
+        let names = reported_names(&shared);
+        assert!(!names.is_empty());
+        for name in names {
+            let command = resolve_renamed(vec![Arg::from(name.as_str())], &shared).unwrap();
+            assert!(!matches!(validate_command(&command, &shared), Err(RESPError::UnsupportedCommand(_))), "'{}' is reported but unknown", name);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use crate::listpack::{self, Listpack};
+use crate::memory::sampled_size;
+
+// The limit of the listpack encoding, past which lists convert to a quicklist: a positive number
+// of elements, or a negative number for 4kb, 8kb, 16kb, 32kb or 64kb. Set through the config.
+pub static MAX_LISTPACK_SIZE: AtomicI64 = AtomicI64::new(-2);
+
+fn fits_listpack(len: usize, bytes: usize) -> bool {
+    match MAX_LISTPACK_SIZE.load(Ordering::Relaxed) {
+        size if size >= 0 => len <= size as usize,
+        size => bytes <= 4096 << (-size - 1).min(4),
+    }
+}
+
+// Elements in insertion order. Small lists are packed in a listpack.
+#[derive(Clone)]
+pub enum List {
+    Listpack(Listpack),
+    Quicklist(VecDeque<Vec<u8>>),
+}
+
+impl FromIterator<Vec<u8>> for List {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(elements: I) -> Self {
+        let elements: Vec<Vec<u8>> = elements.into_iter().collect();
+        let bytes: usize = elements.iter().map(Vec::len).sum();
+        if fits_listpack(elements.len(), bytes) {
+            let mut listpack = Listpack::default();
+            for element in &elements {
+                listpack.push(element);
+            }
+            List::Listpack(listpack)
+        } else {
+            List::Quicklist(elements.into())
+        }
+    }
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        match self {
+            List::Listpack(listpack) => listpack.len(),
+            List::Quicklist(elements) => elements.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            List::Listpack(_) => "listpack",
+            List::Quicklist(_) => "quicklist",
+        }
+    }
+
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            List::Listpack(listpack) => listpack.memory_usage(),
+            List::Quicklist(elements) => {
+                size_of::<Self>() + elements.capacity() * size_of::<Vec<u8>>()
+                    + sampled_size(elements.len(), samples, elements.iter().map(Vec::capacity))
+            },
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            List::Listpack(listpack) => Iter::Listpack(listpack.iter()),
+            List::Quicklist(elements) => Iter::Quicklist(elements.iter()),
+        }
+    }
+}
+
+pub enum Iter<'a> {
+    Listpack(listpack::Iter<'a>),
+    Quicklist(std::collections::vec_deque::Iter<'a, Vec<u8>>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Listpack(entries) => entries.next(),
+            Iter::Quicklist(elements) => elements.next().map(Vec::as_slice),
+        }
+    }
+}
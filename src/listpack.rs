@@ -0,0 +1,144 @@
+use std::mem::size_of;
+
+// Appends the LEB128 varint of the length, reversed when it's for reading backwards.
+fn encode_len(buf: &mut Vec<u8>, mut len: usize, reversed: bool) {
+    let start = buf.len();
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    if reversed {
+        buf[start..].reverse();
+    }
+}
+
+fn len_size(len: usize) -> usize {
+    (usize::BITS - len.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
+// Reads the varint starting at `offset`, returning it along with its size.
+fn decode_len(buf: &[u8], offset: usize) -> (usize, usize) {
+    let mut len = 0;
+    let mut i = 0;
+    loop {
+        let byte = buf[offset + i];
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        i += 1;
+        if byte & 0x80 == 0 {
+            return (len, i);
+        }
+    }
+}
+
+// Reads the reversed varint ending right before `end`, returning it along with its size.
+fn decode_len_backwards(buf: &[u8], end: usize) -> (usize, usize) {
+    let mut len = 0;
+    let mut i = 0;
+    loop {
+        let byte = buf[end - 1 - i];
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        i += 1;
+        if byte & 0x80 == 0 {
+            return (len, i);
+        }
+    }
+}
+
+// Byte strings packed one after the other in a single allocation, like the listpack of Redis. Each
+// entry is its length, its bytes and its length again (reversed), so it can be walked both ways.
+#[derive(Clone, Default)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.buf.capacity()
+    }
+
+    // Inserts the value as the entry starting at `offset`, a byte offset given by `entries`.
+    pub fn insert(&mut self, offset: usize, value: &[u8]) {
+        let mut entry = Vec::with_capacity(2 * len_size(value.len()) + value.len());
+        encode_len(&mut entry, value.len(), false);
+        entry.extend_from_slice(value);
+        encode_len(&mut entry, value.len(), true);
+        self.buf.splice(offset..offset, entry);
+        self.len += 1;
+    }
+
+    // The offset right after the last entry.
+    pub fn end(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn push(&mut self, value: &[u8]) {
+        self.insert(self.end(), value);
+    }
+
+    // Removes the entry starting at `offset`.
+    pub fn remove(&mut self, offset: usize) {
+        let (len, size) = decode_len(&self.buf, offset);
+        self.buf.drain(offset..offset + 2 * size + len);
+        self.len -= 1;
+    }
+
+    // The entries along with their byte offsets.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if offset == self.buf.len() {
+                return None;
+            }
+            let (len, size) = decode_len(&self.buf, offset);
+            let entry = (offset, &self.buf[offset + size..offset + size + len]);
+            offset += 2 * size + len;
+            Some(entry)
+        })
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { buf: &self.buf, front: 0, back: self.buf.len() }
+    }
+}
+
+pub struct Iter<'a> {
+    buf: &'a [u8],
+    front: usize,
+    back: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let (len, size) = decode_len(self.buf, self.front);
+        let value = &self.buf[self.front + size..self.front + size + len];
+        self.front += 2 * size + len;
+        Some(value)
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let (len, size) = decode_len_backwards(self.buf, self.back);
+        let value = &self.buf[self.back - size - len..self.back - size];
+        self.back -= 2 * size + len;
+        Some(value)
+    }
+}
@@ -0,0 +1,136 @@
+// Logging, with the tracing crate. Messages are logged under the configured loglevel, to the
+// configured logfile or to the standard output, either as Redis does (`log-format human`) or as JSON
+// objects (`log-format json`). Each client connection is a span, so the messages about a client
+// carry its id and address, and every command it sends is an event at the debug level.
+
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::arg::Arg;
+use crate::audit::quote_command;
+use crate::config::LOG_LEVELS;
+use crate::SharedState;
+
+pub const FORMATS: &[&str] = &["human", "json"];
+
+// Writes log lines to the configured logfile, or to the standard output when there is none.
+#[derive(Clone, Default)]
+pub struct Logger {
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl Logger {
+    pub fn open(&self, path: &str) -> io::Result<()> {
+        let file = if path.is_empty() {
+            None
+        } else {
+            Some(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+// The subscriber formats a line at a time and writes it at once.
+impl Write for Logger {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.file.lock().unwrap() {
+            Some(file) => file.write(buf),
+            None => io::stdout().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.file.lock().unwrap() {
+            Some(file) => file.flush(),
+            None => io::stdout().flush()
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Logger {
+    type Writer = Logger;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+// Like Redis: the pid, the time, a single character marking the level (. debug, - verbose,
+// * notice, # warning) and the message, preceded by the spans it's in.
+struct Human;
+
+impl<S, N> FormatEvent<S, N> for Human
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mark = match *event.metadata().level() {
+            Level::TRACE => '.',
+            Level::DEBUG => '-',
+            Level::INFO => '*',
+            _ => '#'
+        };
+        write!(writer, "{} {}.{:03} {} ", std::process::id(), now.as_secs(), now.subsec_millis(), mark)?;
+        for span in ctx.event_scope().into_iter().flat_map(|scope| scope.from_root()) {
+            match span.extensions().get::<FormattedFields<N>>() {
+                Some(fields) if !fields.is_empty() => write!(writer, "{}{{{}}} ", span.name(), fields)?,
+                _ => write!(writer, "{} ", span.name())?
+            }
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+// Installs the subscriber writing the messages, once the configuration was loaded. Embedded servers
+// leave the subscriber of the application (or of the first server of the process) in place.
+pub fn init(shared: &SharedState) {
+    let layer = tracing_subscriber::fmt::layer().with_writer(shared.logger.clone()).with_ansi(false);
+    let layer = match shared.config.get("log-format").as_str() {
+        "json" => layer.json().with_current_span(false).with_span_list(true).boxed(),
+        _ => layer.event_format(Human).boxed()
+    };
+    // Only the messages of this crate, which were already filtered by the loglevel.
+    let filter = tracing_subscriber::filter::filter_fn(|metadata| metadata.target().starts_with("bast"));
+    let subscriber = tracing_subscriber::registry().with(layer.with_filter(filter));
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+// Whether messages of the given level are logged under the configured loglevel.
+pub fn enabled(shared: &SharedState, level: &str) -> bool {
+    let configured = shared.config.get("loglevel");
+    let index = |level: &str| LOG_LEVELS.iter().position(|other| *other == level);
+    index(level) >= index(&configured)
+}
+
+pub fn log(shared: &SharedState, level: &str, message: impl Display) {
+    if !enabled(shared, level) {
+        return;
+    }
+    match level {
+        "debug" => tracing::trace!("{}", message),
+        "verbose" => tracing::debug!("{}", message),
+        "notice" => tracing::info!("{}", message),
+        _ => tracing::warn!("{}", message)
+    }
+}
+
+// A command a client sent, logged at the debug level.
+pub fn command(shared: &SharedState, command: &[Arg]) {
+    if enabled(shared, "debug") {
+        tracing::trace!(command = command[0].to_ascii_lowercase(), arguments = command.len() - 1, "{}", quote_command(command));
+    }
+}
@@ -0,0 +1,160 @@
+use std::mem::size_of;
+
+use bytes::Bytes;
+
+use crate::arg::Arg;
+use crate::db::Value;
+use crate::json;
+use crate::stats::{human_bytes, rss_bytes};
+use crate::{RESPError, RESPValue, SharedState};
+
+// A rough estimate of the bookkeeping of a hash table or tree node per element (pointers, hashes
+// and allocator headers).
+pub const ENTRY_OVERHEAD: usize = 16;
+
+// Elements sampled for the size of aggregate values, unless asked otherwise.
+pub const DEFAULT_SAMPLES: usize = 5;
+
+// The size of `len` elements given the sizes of the first ones, only the first `samples` of them
+// being measured (all of them when 0) and averaged for the rest.
+pub fn sampled_size(len: usize, samples: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    if samples == 0 || samples >= len {
+        return sizes.sum();
+    }
+    let sampled: usize = sizes.take(samples).sum();
+    sampled * len / samples
+}
+
+fn value_usage(value: &Value, samples: usize) -> usize {
+    match value {
+        Value::String(s) => size_of::<Bytes>() + s.len(),
+        Value::CompressedString(s) => s.memory_usage(),
+        Value::List(list) => list.memory_usage(samples),
+        Value::SortedSet(set) => set.memory_usage(samples),
+        Value::Hash(hash) => hash.memory_usage(samples),
+        Value::Stream(stream) => stream.memory_usage(samples),
+        Value::Bloom(bloom) => bloom.memory_usage(),
+        Value::Cuckoo(cuckoo) => cuckoo.memory_usage(),
+        Value::Json(json) => json::memory_usage(json, samples),
+    }
+}
+
+// The bytes used by a key and its value, including the overhead of keeping it in the keyspace.
+pub fn key_usage(key: &str, value: &Value, samples: usize) -> usize {
+    size_of::<String>() + key.len() + size_of::<Value>() + size_of::<u64>() + ENTRY_OVERHEAD + value_usage(value, samples)
+}
+
+struct Dataset {
+    keys: usize,
+    bytes: usize,
+    // The biggest key along with its size.
+    biggest: Option<(String, usize)>,
+}
+
+fn dataset(shared: &SharedState) -> Dataset {
+    let db = shared.db.lock();
+    let mut dataset = Dataset { keys: 0, bytes: 0, biggest: None };
+    for (key, value) in db.iter() {
+        let usage = key_usage(key, &value, DEFAULT_SAMPLES);
+        dataset.keys += 1;
+        dataset.bytes += usage;
+        if dataset.biggest.as_ref().is_none_or(|(_, biggest)| usage > *biggest) {
+            dataset.biggest = Some((key.to_owned(), usage));
+        }
+    }
+    dataset
+}
+
+fn doctor(shared: &SharedState) -> String {
+    let dataset = dataset(shared);
+    let rss = rss_bytes() as usize;
+    let maxmemory = shared.config.get_int("maxmemory") as usize;
+
+    if dataset.bytes < 1024 * 1024 {
+        return String::from("This instance is empty or is using very little memory, there is nothing to diagnose yet.\n");
+    }
+
+    let mut issues = vec![];
+    if maxmemory > 0 && dataset.bytes * 10 >= maxmemory * 9 {
+        issues.push(format!(
+            "The dataset ({}) is close to maxmemory ({}), consider raising it or setting an eviction policy.",
+            human_bytes(dataset.bytes as u64), human_bytes(maxmemory as u64)));
+    }
+    if let Some((key, size)) = &dataset.biggest {
+        if dataset.keys > 1 && size * 2 > dataset.bytes {
+            issues.push(format!(
+                "The key '{}' alone uses {} out of the {} of the dataset, big keys are slow to delete and to move around.",
+                key, human_bytes(*size as u64), human_bytes(dataset.bytes as u64)));
+        }
+    }
+    // The baseline of the process dwarfs small datasets, so only big ones are checked.
+    if dataset.bytes >= 64 * 1024 * 1024 && rss > dataset.bytes * 2 {
+        issues.push(format!(
+            "The process uses {} while the dataset only takes {}, memory might be fragmented after many deletions.",
+            human_bytes(rss as u64), human_bytes(dataset.bytes as u64)));
+    }
+
+    if issues.is_empty() {
+        String::from("No memory issues were detected in this instance.\n")
+    } else {
+        issues.iter().map(|issue| format!(" * {}\n", issue)).collect()
+    }
+}
+
+// MEMORY USAGE key [SAMPLES count]
+// MEMORY STATS
+// MEMORY DOCTOR
+pub fn memory(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("MEMORY|{}", subcommand)))
+    };
+
+    match subcommand.as_str() {
+        "USAGE" => {
+            check_arity(command.len() == 3 || command.len() == 5)?;
+            let samples = match &command[3..] {
+                [] => DEFAULT_SAMPLES,
+                [option, samples] if option.eq_ignore_ascii_case("SAMPLES") => {
+                    samples.parse::<usize>().map_err(|_| RESPError::NotAnInteger)?
+                },
+                _ => return Err(RESPError::SyntaxError)
+            };
+
+            let db = shared.db.lock();
+            Ok(db.peek(&command[2]).map_or(RESPValue::Null, |value| {
+                RESPValue::Number(key_usage(&command[2], value, samples) as i64)
+            }))
+        },
+        "STATS" => {
+            check_arity(command.len() == 2)?;
+            let dataset = dataset(shared);
+            let rss = rss_bytes() as usize;
+            let scripts: usize = shared.scripts.lock().unwrap().iter().map(|(sha, script)| sha.len() + script.len()).sum();
+            let functions: usize = shared.libraries.lock().unwrap().values().map(|library| library.code.len()).sum();
+            let percentage = |part: usize, total: usize| if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 };
+
+            let stats = [
+                ("total.allocated", RESPValue::Number(rss as i64)),
+                ("lua.caches", RESPValue::Number(scripts as i64)),
+                ("functions.caches", RESPValue::Number(functions as i64)),
+                ("overhead.hashtable.main", RESPValue::Number((dataset.keys * ENTRY_OVERHEAD) as i64)),
+                ("keys.count", RESPValue::Number(dataset.keys as i64)),
+                ("keys.bytes-per-key", RESPValue::Number(dataset.bytes.checked_div(dataset.keys).unwrap_or(0) as i64)),
+                ("dataset.bytes", RESPValue::Number(dataset.bytes as i64)),
+                // Doubles are bulk strings in RESP2.
+                ("dataset.percentage", RESPValue::BlobString(format!("{:.2}", percentage(dataset.bytes, rss)).into())),
+            ];
+            Ok(RESPValue::Array(stats.into_iter()
+                .flat_map(|(name, value)| [RESPValue::BlobString(name.into()), value])
+                .collect()))
+        },
+        "DOCTOR" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::BlobString(doctor(shared).into()))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("MEMORY {}", command[1])))
+    }
+}
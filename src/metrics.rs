@@ -0,0 +1,154 @@
+// Pushing metrics to StatsD or Graphite, for setups without Prometheus. With `metrics-endpoint` set
+// the numeric fields of INFO everything are sent every `metrics-interval` seconds, named
+// `<metrics-prefix>.<section>.<field>` (fields like db0's keys=..,expires=.. are split into
+// `keyspace.db0.keys` and so on). StatsD gets them as gauges, Graphite in its plaintext protocol,
+// over UDP or over a TCP connection kept open between pushes, as `metrics-transport` says.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::arg::Arg;
+use crate::{logging, stats, SharedState};
+
+pub const PROTOCOLS: &[&str] = &["statsd", "graphite"];
+pub const TRANSPORTS: &[&str] = &["udp", "tcp"];
+
+// Keeps datagrams under the usual MTU.
+const MAX_DATAGRAM: usize = 1400;
+
+// `host:port`, or nothing to push nothing.
+pub fn parse_endpoint(value: &str) -> Option<String> {
+    match value.rsplit_once(':') {
+        _ if value.is_empty() => Some(String::new()),
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Some(value.to_owned()),
+        _ => None
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+// The numeric fields of INFO everything, by their metric name.
+fn collect(shared: &SharedState) -> Vec<(String, String)> {
+    let prefix = shared.config.get("metrics-prefix");
+    let mut metrics = vec![];
+    let mut section = String::new();
+    for line in stats::info(&[Arg::from("INFO"), Arg::from("everything")], shared).lines() {
+        if let Some(title) = line.strip_prefix("# ") {
+            section = sanitize(&title.to_ascii_lowercase());
+            continue;
+        }
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = format!("{}.{}.{}", prefix, section, sanitize(field));
+        if value.parse::<f64>().is_ok() {
+            metrics.push((name, value.to_owned()));
+            continue;
+        }
+        for (subfield, value) in value.split(',').filter_map(|pair| pair.split_once('=')) {
+            if value.parse::<f64>().is_ok() {
+                metrics.push((format!("{}.{}", name, sanitize(subfield)), value.to_owned()));
+            }
+        }
+    }
+    metrics
+}
+
+fn format_lines(metrics: &[(String, String)], protocol: &str) -> Vec<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    metrics.iter().map(|(name, value)| match protocol {
+        "graphite" => format!("{} {} {}\n", name, value, now),
+        _ => format!("{}:{}|g\n", name, value)
+    }).collect()
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    async fn open(endpoint: &str, transport: &str) -> std::io::Result<Self> {
+        Ok(match transport {
+            "tcp" => Connection::Tcp(TcpStream::connect(endpoint).await?),
+            _ => {
+                let target = tokio::net::lookup_host(endpoint).await?.next()
+                    .ok_or_else(|| std::io::Error::other(format!("no address for {}", endpoint)))?;
+                let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+                socket.connect(target).await?;
+                Connection::Udp(socket)
+            }
+        })
+    }
+
+    async fn send(&mut self, lines: &[String]) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(socket) => socket.write_all(lines.concat().as_bytes()).await,
+            Connection::Udp(socket) => {
+                let mut datagram = String::new();
+                for line in lines {
+                    if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+                        socket.send(datagram.as_bytes()).await?;
+                        datagram.clear();
+                    }
+                    datagram.push_str(line);
+                }
+                if !datagram.is_empty() {
+                    socket.send(datagram.as_bytes()).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Pushes the metrics every `metrics-interval` seconds, connecting again after failures.
+pub async fn pusher(shared: Arc<SharedState>) {
+    let mut connection: Option<(String, Connection)> = None;
+    let mut failing = false;
+    // Checked every second, so changing the interval applies right away.
+    let mut cron = tokio::time::interval(Duration::from_secs(1));
+    let mut pushed = Instant::now();
+    loop {
+        cron.tick().await;
+        let endpoint = shared.config.get("metrics-endpoint");
+        if endpoint.is_empty() {
+            connection = None;
+            continue;
+        }
+        if pushed.elapsed() < Duration::from_secs(shared.config.get_int("metrics-interval") as u64) {
+            continue;
+        }
+        pushed = Instant::now();
+        let transport = shared.config.get("metrics-transport");
+        let target = format!("{}/{}", transport, endpoint);
+        let lines = format_lines(&collect(&shared), &shared.config.get("metrics-protocol"));
+
+        let result = async {
+            // The endpoint or the transport may have changed since connecting.
+            if connection.as_ref().is_none_or(|(connected, _)| *connected != target) {
+                connection = Some((target.clone(), Connection::open(&endpoint, &transport).await?));
+            }
+            connection.as_mut().unwrap().1.send(&lines).await
+        }.await;
+        match result {
+            Ok(()) => {
+                if std::mem::take(&mut failing) {
+                    logging::log(&shared, "notice", format!("Pushing metrics to {} again", endpoint));
+                }
+            },
+            Err(e) => {
+                connection = None;
+                // Only the first of consecutive failures is logged.
+                if !std::mem::replace(&mut failing, true) {
+                    logging::log(&shared, "warning", format!("Failed pushing metrics to {}: {}", endpoint, e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// MIGRATE, which moves keys to another server by restoring them there (see dump.rs) and deleting
+// them here once they were. Like in Redis the server is blocked while talking to the target, up to
+// the given timeout for every step.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::arg::Arg;
+use crate::db::now_ms;
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC};
+use crate::{aof, parse_number, rdb, RESPError, RESPValue, SharedState};
+
+// The timeout used when the given one isn't positive.
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let addr = (host, port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the host"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+// Reads a reply of the target, which is a status or an error, the error as Err.
+fn read_reply(reader: &mut impl BufRead) -> Result<Result<(), String>, RESPError> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(_) if line.ends_with("\r\n") => {},
+        _ => return Err(RESPError::MigrateIOError(String::from("reading from")))
+    }
+    Ok(match line.strip_prefix('-') {
+        Some(error) => Err(error.trim_end().to_owned()),
+        None => Ok(())
+    })
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password]
+//     [AUTH2 username password] [KEYS key [key ...]]
+pub fn migrate(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth = None;
+    let mut keys = &command[3..4];
+    let mut i = 6;
+    while i < command.len() {
+        match command[i].to_ascii_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" => {
+                let password = command.get(i + 1).ok_or(RESPError::SyntaxError)?;
+                auth = Some(vec![Arg::from("AUTH"), password.clone()]);
+                i += 1;
+            },
+            "AUTH2" => {
+                let credentials = command.get(i + 1..i + 3).ok_or(RESPError::SyntaxError)?;
+                auth = Some([&[Arg::from("AUTH")], credentials].concat());
+                i += 2;
+            },
+            "KEYS" => {
+                if !command[3].is_empty() {
+                    return Err(RESPError::MigrateKeyWithKeys);
+                }
+                keys = &command[i + 1..];
+                break;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+        i += 1;
+    }
+    let port = parse_number(&command[2])?;
+    let db_index = parse_number(&command[4])?;
+    let timeout = match parse_number(&command[5])? {
+        timeout if timeout > 0 => Duration::from_millis(timeout as u64),
+        _ => Duration::from_millis(DEFAULT_TIMEOUT_MS)
+    };
+
+    let mut requests = vec![];
+    if let Some(auth) = &auth {
+        aof::encode(auth, &mut requests);
+    }
+    // Servers start out with database 0 selected, which is the only one bast has.
+    if db_index != 0 {
+        aof::encode(&[Arg::from("SELECT"), Arg::from(db_index.to_string())], &mut requests);
+    }
+    // The keys that exist, restored with the time they have left to live.
+    let mut migrated = vec![];
+    {
+        let db = shared.db.lock();
+        let now = now_ms();
+        for key in keys {
+            let value = match db.get(key) {
+                Some(value) => value,
+                None => continue
+            };
+            // A key that expired but wasn't deleted yet lives for the shortest TTL there is.
+            let ttl = db.expire_time(key).map_or(0, |at| at.saturating_sub(now).max(1));
+            // Targets importing the slot of the key only accept it with RESTORE-ASKING.
+            let restore_command = if shared.cluster.is_some() { "RESTORE-ASKING" } else { "RESTORE" };
+            let mut restore = vec![Arg::from(restore_command), key.clone(), Arg::from(ttl.to_string()), Arg::from_bytes(rdb::dump(value))];
+            if replace {
+                restore.push(Arg::from("REPLACE"));
+            }
+            aof::encode(&restore, &mut requests);
+            migrated.push(key);
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(RESPValue::SimpleString(String::from("NOKEY")));
+    }
+
+    let port = u16::try_from(port).map_err(|_| RESPError::MigrateIOError(String::from("connecting to")))?;
+    let mut stream = connect(&command[1], port, timeout).map_err(|_| RESPError::MigrateIOError(String::from("connecting to")))?;
+    stream.write_all(&requests).map_err(|_| RESPError::MigrateIOError(String::from("writing to")))?;
+    let mut reader = BufReader::new(stream);
+    let handshake = auth.is_some() as usize + (db_index != 0) as usize;
+    for _ in 0..handshake {
+        read_reply(&mut reader)?.map_err(RESPError::TargetError)?;
+    }
+
+    // Keys the target failed to restore are kept, and the last error it replied with is returned.
+    let mut error = None;
+    let mut restored = vec![];
+    for key in migrated {
+        match read_reply(&mut reader)? {
+            Ok(()) => restored.push(key.to_string()),
+            Err(e) => error = Some(e)
+        }
+    }
+    if !copy && !restored.is_empty() {
+        aof::deleted(&restored, shared);
+        for key in &restored {
+            let old = shared.db.lock().remove(key);
+            lazyfree::free_replaced(old, shared);
+            notify_keyspace_event(shared, NOTIFY_GENERIC, "del", key, 0);
+        }
+    }
+    match error {
+        Some(e) => Err(RESPError::TargetError(e)),
+        None => Ok(RESPValue::SimpleString(String::from("OK")))
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::Ordering;
+
+use crate::SharedState;
+
+// Event classes, matching the characters of the `notify-keyspace-events` flag set.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0;
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1;
+pub const NOTIFY_GENERIC: u32 = 1 << 2;
+pub const NOTIFY_STRING: u32 = 1 << 3;
+pub const NOTIFY_LIST: u32 = 1 << 4;
+pub const NOTIFY_SET: u32 = 1 << 5;
+pub const NOTIFY_HASH: u32 = 1 << 6;
+pub const NOTIFY_ZSET: u32 = 1 << 7;
+pub const NOTIFY_EXPIRED: u32 = 1 << 8;
+pub const NOTIFY_EVICTED: u32 = 1 << 9;
+pub const NOTIFY_STREAM: u32 = 1 << 10;
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11;
+pub const NOTIFY_MODULE: u32 = 1 << 12;
+pub const NOTIFY_NEW: u32 = 1 << 13;
+// `A` doesn't include key misses and new keys, those have to be asked for explicitly.
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC | NOTIFY_STRING | NOTIFY_LIST | NOTIFY_SET | NOTIFY_HASH
+    | NOTIFY_ZSET | NOTIFY_EXPIRED | NOTIFY_EVICTED | NOTIFY_STREAM | NOTIFY_MODULE;
+
+const FLAG_CHARS: [(char, u32); 14] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('d', NOTIFY_MODULE),
+    ('m', NOTIFY_KEY_MISS),
+    ('n', NOTIFY_NEW),
+    ('K', NOTIFY_KEYSPACE),
+    ('E', NOTIFY_KEYEVENT),
+];
+
+pub fn parse_flags(flags: &str) -> Option<u32> {
+    let mut parsed = 0;
+    for c in flags.chars() {
+        parsed |= match c {
+            'A' => NOTIFY_ALL,
+            c => FLAG_CHARS.iter().find(|(flag, _)| *flag == c)?.1
+        };
+    }
+    Some(parsed)
+}
+
+pub fn flags_to_string(flags: u32) -> String {
+    let mut s = String::new();
+    let mut remaining = flags;
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        s.push('A');
+        remaining &= !NOTIFY_ALL;
+    }
+    for (c, flag) in FLAG_CHARS {
+        if remaining & flag != 0 {
+            s.push(c);
+        }
+    }
+    s
+}
+
+// The single hook every mutating command goes through to report what it did to a key.
+pub fn notify_keyspace_event(shared: &SharedState, class: u32, event: &str, key: &str, db: usize) {
+    let flags = shared.notify_keyspace_events.load(Ordering::Relaxed);
+    if flags & class == 0 {
+        return;
+    }
+
+    let pubsub = shared.pubsub.lock().unwrap();
+    if flags & NOTIFY_KEYSPACE != 0 {
+        pubsub.publish(&format!("__keyspace@{}__:{}", db, key), event.as_bytes());
+    }
+    if flags & NOTIFY_KEYEVENT != 0 {
+        pubsub.publish(&format!("__keyevent@{}__:{}", db, event), key.as_bytes());
+    }
+}
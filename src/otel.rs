@@ -0,0 +1,204 @@
+// OpenTelemetry tracing. With `otel-endpoint` set (an OTLP/HTTP collector, like
+// http://127.0.0.1:4318) every command is a span, with the client's id, address and user, the
+// amount of keys and whether it failed, and so are background saves and the syncs of replicas.
+// `otel-sampling-percentage` of them are kept, buffered and exported once a second as OTLP JSON.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::arg::Arg;
+use crate::{command_keys, logging, lookup_command, Client, RESPValue, SharedState};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+// Spans beyond these are dropped while the collector can't keep up.
+const MAX_BUFFERED: usize = 8192;
+const DEFAULT_PATH: &str = "/v1/traces";
+
+pub struct Span {
+    name: String,
+    start: u64,
+    started: Instant,
+    end: u64,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Otel {
+    spans: Mutex<Vec<Span>>,
+    dropped: AtomicU64,
+}
+
+// `http://host:port[/path]`, the path being /v1/traces by default, or nothing to export nothing.
+pub fn parse_endpoint(value: &str) -> Option<String> {
+    if value.is_empty() {
+        return Some(String::new());
+    }
+    let (authority, _) = split_endpoint(value)?;
+    let (host, port) = authority.rsplit_once(':')?;
+    (!host.is_empty() && port.parse::<u16>().is_ok()).then(|| value.to_owned())
+}
+
+fn split_endpoint(endpoint: &str) -> Option<(&str, &str)> {
+    let rest = endpoint.strip_prefix("http://")?;
+    Some(match rest.find('/') {
+        Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+        Some(i) => (&rest[..i], DEFAULT_PATH),
+        None => (rest, DEFAULT_PATH)
+    })
+}
+
+// Starts a span, None when not exporting or when it isn't sampled.
+pub fn start(shared: &SharedState, name: &str) -> Option<Span> {
+    if shared.config.get("otel-endpoint").is_empty() {
+        return None;
+    }
+    let percentage = shared.config.get_int("otel-sampling-percentage") as f64;
+    if rand::thread_rng().gen::<f64>() * 100.0 >= percentage {
+        return None;
+    }
+    let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    Some(Span { name: name.to_owned(), start, started: Instant::now(), end: 0, attributes: vec![], error: None })
+}
+
+pub fn set(span: &mut Option<Span>, key: &'static str, value: impl Into<Value>) {
+    if let Some(span) = span {
+        span.attributes.push((key, value.into()));
+    }
+}
+
+pub fn end(span: Option<Span>, error: Option<impl Display>, shared: &SharedState) {
+    let Some(mut span) = span else {
+        return;
+    };
+    span.end = span.start + span.started.elapsed().as_nanos() as u64;
+    span.error = error.map(|e| e.to_string());
+    let mut spans = shared.otel.spans.lock().unwrap();
+    if spans.len() < MAX_BUFFERED {
+        spans.push(span);
+    } else {
+        shared.otel.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The span of a command a client sent.
+pub fn command_span(command: &[Arg], client: &Client, shared: &SharedState) -> Option<Span> {
+    let mut span = start(shared, &command[0]);
+    if span.is_some() {
+        let keys = lookup_command(&command[0], shared).map_or(0, |spec| command_keys(spec, command).len());
+        set(&mut span, "db.system.name", "redis");
+        set(&mut span, "db.operation.name", command[0].as_str());
+        set(&mut span, "bast.command.keys", keys);
+        set(&mut span, "client.address", client.info.addr.as_str());
+        set(&mut span, "bast.client.id", client.id);
+        set(&mut span, "bast.client.user", client.user.as_str());
+    }
+    span
+}
+
+// Ends the span of a command, failed when it replied with an error.
+pub fn end_command(span: Option<Span>, replies: &[RESPValue], shared: &SharedState) {
+    let error = replies.iter().find_map(|reply| match reply {
+        RESPValue::SimpleError(e) => Some(String::from_utf8_lossy(e).into_owned()),
+        _ => None
+    });
+    end(span, error, shared);
+}
+
+fn encode(spans: &[Span], service_name: &str) -> Vec<u8> {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let attribute = |key: &str, value: &Value| {
+        let value = match value {
+            Value::Number(n) => json!({"intValue": n.to_string()}),
+            Value::Bool(b) => json!({"boolValue": b}),
+            Value::String(s) => json!({"stringValue": s}),
+            value => json!({"stringValue": value.to_string()})
+        };
+        json!({"key": key, "value": value})
+    };
+    let mut rng = rand::thread_rng();
+    let spans: Vec<Value> = spans.iter().map(|span| {
+        // Clients don't propagate a trace context over RESP, so every span starts a trace.
+        let (trace_id, span_id) = (rng.gen::<[u8; 16]>(), rng.gen::<[u8; 8]>());
+        let status = match &span.error {
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({"code": 1})
+        };
+        json!({
+            "traceId": hex(&trace_id),
+            "spanId": hex(&span_id),
+            "name": span.name,
+            // A server span.
+            "kind": 2,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.to_string(),
+            "attributes": span.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+            "status": status,
+        })
+    }).collect();
+    let request = json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute("service.name", &Value::from(service_name))]},
+            "scopeSpans": [{"scope": {"name": "bast", "version": env!("CARGO_PKG_VERSION")}, "spans": spans}],
+        }]
+    });
+    serde_json::to_vec(&request).unwrap()
+}
+
+async fn post(endpoint: &str, body: &[u8]) -> std::io::Result<()> {
+    let (authority, path) = split_endpoint(endpoint).unwrap();
+    let mut socket = TcpStream::connect(authority).await?;
+    let head = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", path, authority, body.len());
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    let mut response = vec![];
+    socket.read_to_end(&mut response).await?;
+    let status_line = response.split(|b| *b == b'\r').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::other(format!("the collector replied {}", status_line)))
+    }
+}
+
+// Exports the buffered spans once a second.
+pub async fn exporter(shared: Arc<SharedState>) {
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let spans = std::mem::take(&mut *shared.otel.spans.lock().unwrap());
+        let endpoint = shared.config.get("otel-endpoint");
+        if spans.is_empty() || endpoint.is_empty() {
+            continue;
+        }
+        let dropped = shared.otel.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            logging::log(&shared, "warning", format!("Dropped {} OpenTelemetry spans, the collector can't keep up", dropped));
+        }
+
+        let body = encode(&spans, &shared.config.get("otel-service-name"));
+        match tokio::time::timeout(EXPORT_TIMEOUT, post(&endpoint, &body)).await {
+            Ok(Ok(())) => {
+                if std::mem::take(&mut failing) {
+                    logging::log(&shared, "notice", format!("Exporting OpenTelemetry spans to {} again", endpoint));
+                }
+            },
+            result => {
+                // Only the first of consecutive failures is logged.
+                if !std::mem::replace(&mut failing, true) {
+                    let e = result.map_or_else(|_| String::from("timed out"), |result| result.unwrap_err().to_string());
+                    logging::log(&shared, "warning", format!("Failed exporting OpenTelemetry spans to {}: {}", endpoint, e));
+                }
+            }
+        }
+    }
+}
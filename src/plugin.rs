@@ -0,0 +1,166 @@
+// Commands added on top of the builtin ones, by the application embedding the server (see
+// Builder::command), by dynamic plugin libraries or by WebAssembly modules (see wasm.rs), the last
+// two loaded with `loadmodule` or MODULE LOAD.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::db::{Db, Value};
+use crate::{CommandSpec, RESPError, RESPValue, FIRST_KEY, NO_KEYS};
+
+/// A command added on top of the builtin ones, executed while holding the keyspace.
+pub trait Command: Send + Sync {
+    /// The name the command is called by, in any case.
+    fn name(&self) -> &str;
+
+    /// Like the one of the builtin commands, including the name itself, a negative arity meaning at
+    /// least that many arguments.
+    fn arity(&self) -> i64;
+
+    /// Flags like the ones COMMAND INFO lists. Commands flagged `readonly` or `write` take a single
+    /// key as their first argument.
+    fn flags(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Runs the command with its arguments, the name excluded.
+    fn execute(&self, args: &[String], db: &mut Database<'_>) -> Result<RESPValue, RESPError>;
+}
+
+/// The keyspace as seen by a [`Command`], which holds it for as long as it runs. Only string values
+/// can be read and written, keys of other types reply with WRONGTYPE.
+pub struct Database<'a> {
+    db: Db<'a>,
+}
+
+impl<'a> Database<'a> {
+    pub(crate) fn new(db: Db<'a>) -> Self {
+        Self { db }
+    }
+
+    // WebAssembly modules access the keyspace directly.
+    #[cfg(feature = "wasm")]
+    pub(crate) fn db(&mut self) -> &mut Db<'a> {
+        &mut self.db
+    }
+
+    /// The string value of the key, None when it doesn't exist.
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>, RESPError> {
+        self.db.get(key).map(Value::as_string).transpose()
+    }
+
+    /// Sets the key to the string value, discarding its previous value and TTL.
+    pub fn set(&mut self, key: &str, value: impl Into<Bytes>) {
+        self.db.set(key.to_owned(), Value::string(value));
+    }
+
+    /// Deletes the key, returning whether it existed.
+    pub fn del(&mut self, key: &str) -> bool {
+        self.db.remove(key).is_some()
+    }
+
+    /// Whether the key exists, whatever its type.
+    pub fn exists(&self, key: &str) -> bool {
+        self.db.contains_key(key)
+    }
+}
+
+// The symbol a dynamic plugin library exports to register its commands.
+#[cfg(feature = "dynamic-plugins")]
+const REGISTER_SYMBOL: &[u8] = b"bast_register_plugin";
+
+#[cfg(feature = "dynamic-plugins")]
+type RegisterFn = fn(&mut CommandRegistry) -> Result<(), RESPError>;
+
+/// The commands added on top of the builtin ones. Dynamic plugin libraries register their commands
+/// in it from the `bast_register_plugin(&mut CommandRegistry) -> Result<(), RESPError>` function
+/// they export.
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: HashMap<String, (&'static CommandSpec, Arc<dyn Command>)>,
+    // Loaded plugin libraries with the commands each of them registered.
+    plugins: Vec<(String, Vec<String>)>,
+    #[cfg(feature = "dynamic-plugins")]
+    libraries: Vec<libloading::Library>,
+}
+
+impl CommandRegistry {
+    /// Adds the command, failing when there already is one by that name.
+    pub fn register(&mut self, command: impl Command + 'static) -> Result<(), RESPError> {
+        self.add(Arc::new(command))
+    }
+
+    pub(crate) fn add(&mut self, command: Arc<dyn Command>) -> Result<(), RESPError> {
+        let name = command.name().to_ascii_uppercase();
+        if crate::lookup_builtin_command(&name).is_some() || self.commands.contains_key(&name) {
+            return Err(RESPError::CommandExists(name));
+        }
+
+        // Plugins live for as long as the server does, so their specs can be leaked.
+        let spec: &'static CommandSpec = Box::leak(Box::new(CommandSpec {
+            name: Box::leak(name.clone().into_boxed_str()),
+            arity: command.arity(),
+            flags: Box::leak(command.flags().to_vec().into_boxed_slice()),
+            // Plugins that access keys take a single key as their first argument.
+            keys: if command.flags().iter().any(|flag| matches!(*flag, "readonly" | "write")) { FIRST_KEY } else { NO_KEYS },
+        }));
+        self.commands.insert(name, (spec, command));
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<(&'static CommandSpec, Arc<dyn Command>)> {
+        self.commands.get(name).map(|(spec, command)| (*spec, command.clone()))
+    }
+
+    pub(crate) fn specs(&self) -> impl Iterator<Item = &'static CommandSpec> + '_ {
+        self.commands.values().map(|(spec, _)| *spec)
+    }
+
+    pub(crate) fn plugins(&self) -> &[(String, Vec<String>)] {
+        &self.plugins
+    }
+
+    // Loads a plugin, either a WebAssembly module or a dynamic library.
+    pub(crate) fn load(&mut self, path: &str) -> Result<(), RESPError> {
+        let names = if path.ends_with(".wasm") {
+            self.load_wasm_module(path)?
+        } else {
+            self.load_library(path)?
+        };
+        self.plugins.push((path.to_owned(), names));
+        Ok(())
+    }
+
+    #[cfg(feature = "dynamic-plugins")]
+    fn load_library(&mut self, path: &str) -> Result<Vec<String>, RESPError> {
+        let before: Vec<String> = self.commands.keys().cloned().collect();
+
+        // Safety: plugins are trusted code built against this exact version of the server.
+        let library = unsafe { libloading::Library::new(path) }.map_err(|e| RESPError::PluginLoadError(e.to_string()))?;
+        let register = *unsafe { library.get::<RegisterFn>(REGISTER_SYMBOL) }.map_err(|e| RESPError::PluginLoadError(e.to_string()))?;
+
+        // The registered commands point into the library's code, so it must never be unloaded,
+        // even when it fails half way through registering.
+        self.libraries.push(library);
+        register(self)?;
+
+        Ok(self.commands.keys().filter(|name| !before.contains(name)).cloned().collect())
+    }
+
+    #[cfg(not(feature = "dynamic-plugins"))]
+    fn load_library(&mut self, _path: &str) -> Result<Vec<String>, RESPError> {
+        Err(RESPError::PluginLoadError(String::from("dynamic plugins are not supported by this build")))
+    }
+
+    #[cfg(feature = "wasm")]
+    fn load_wasm_module(&mut self, path: &str) -> Result<Vec<String>, RESPError> {
+        crate::wasm::load_module(self, path)
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn load_wasm_module(&mut self, _path: &str) -> Result<Vec<String>, RESPError> {
+        Err(RESPError::PluginLoadError(String::from("WebAssembly modules are not supported by this build")))
+    }
+}
@@ -0,0 +1,512 @@
+//! The Redis serialization protocol (RESP), as bast speaks it on the wire.
+//!
+//! [`RESPCodec`] decodes values as their bytes arrive and encodes them back, and works with
+//! `tokio_util::codec::Framed` and friends. [`parse`] and [`encode`] do the same over plain buffers.
+//!
+//! Values are written in RESP2, the types RESP2 doesn't have being written the way Redis writes them
+//! to RESP2 clients (a map as a flat array of its keys and values, a double as a blob string and so
+//! on). Reading supports every RESP2 type: simple strings, errors, integers, blob strings, arrays and
+//! their null forms.
+//!
+//! ```
+//! use bast::protocol::{encode, parse, RESPValue};
+//! use bytes::BytesMut;
+//!
+//! let mut buf = BytesMut::new();
+//! encode(RESPValue::Array(vec![RESPValue::BlobString("PING".into())]), &mut buf);
+//! assert_eq!(&buf[..], b"*1\r\n$4\r\nPING\r\n");
+//!
+//! let (value, used) = parse(&buf).unwrap().unwrap();
+//! assert_eq!(value, RESPValue::Array(vec![RESPValue::BlobString("PING".into())]));
+//! assert_eq!(used, buf.len());
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use bytes::{Buf, Bytes, BytesMut};
+use enum_as_inner::EnumAsInner;
+use memchr::memchr;
+use tokio_util::codec::{Decoder, Encoder};
+
+const WORD_BREAK: &str = "\r\n";
+const BREAK_FIRST_CHAR: u8 = b'\r';
+const NEW_LINE: u8 = b'\n';
+// The longest line, the headers of blob strings and arrays included, as in Redis.
+const MAX_LINE: usize = 64 * 1024;
+
+// RESP3 protocol
+// TODO: Add all missing types
+// https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md
+/// A value of the protocol, a request or a reply.
+#[derive(Debug, EnumAsInner, Clone, PartialEq)]
+pub enum RESPValue {
+    BlobString(Bytes),
+    SimpleString(String),
+    BlobError(Bytes),
+    SimpleError(Bytes),
+    Number(i64),
+    Double(f64),
+    Boolean(bool),
+    Null,
+    Array(Vec<RESPValue>),
+    Map(HashMap<Bytes, RESPValue>), // TODO: Add integers + booleans? as valid keys (separate types?)
+    Set(Vec<RESPValue>),
+    Push(Vec<RESPValue>),
+}
+
+impl RESPValue {
+    fn write_format_tabbed(&self, f: &mut std::fmt::Formatter, num_of_tabs: usize) -> std::fmt::Result {
+        let t = "  ".repeat(num_of_tabs);
+        match self {
+            RESPValue::BlobString(text) => writeln!(f, "{}blob string: {}", t, String::from_utf8_lossy(text)),
+            RESPValue::SimpleString(text) => writeln!(f, "{}simple string: {}", t, text),
+            RESPValue::BlobError(text) | RESPValue::SimpleError(text) => writeln!(f, "{}error: {}", t, String::from_utf8_lossy(text)),
+            RESPValue::Number(n) => writeln!(f, "{}number: {}", t, n),
+            RESPValue::Double(d) => writeln!(f, "{}double: {}", t, d),
+            RESPValue::Boolean(b) => writeln!(f, "{}boolean: {}", t, b),
+            RESPValue::Array(arr) => {
+                writeln!(f, "{}array({}) [", t, arr.len())?;
+                for v in arr {
+                    v.write_format_tabbed(f, num_of_tabs + 1)?;
+                }
+                writeln!(f, "{}]", t)
+            },
+            RESPValue::Push(arr) => {
+                writeln!(f, "{}push({}) [", t, arr.len())?;
+                for v in arr {
+                    v.write_format_tabbed(f, num_of_tabs + 1)?;
+                }
+                writeln!(f, "{}]", t)
+            },
+            RESPValue::Set(arr) => {
+                writeln!(f, "{}set({}) [", t, arr.len())?;
+                for v in arr {
+                    v.write_format_tabbed(f, num_of_tabs + 1)?;
+                }
+                writeln!(f, "{}]", t)
+            },
+            RESPValue::Map(entries) => {
+                writeln!(f, "{}map({}) [", t, entries.len())?;
+                for (key, v) in entries {
+                    writeln!(f, "{}  {}:", t, String::from_utf8_lossy(key))?;
+                    v.write_format_tabbed(f, num_of_tabs + 2)?;
+                }
+                writeln!(f, "{}]", t)
+            },
+            RESPValue::Null => writeln!(f, "{}null", t),
+        }
+    }
+}
+
+impl std::fmt::Display for RESPValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_format_tabbed(f, 0)
+    }
+}
+
+/// Why a stream of bytes isn't valid RESP. Past such an error the rest of the stream can't be made
+/// sense of, and the decoder starts over.
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnsupportedValue(char),
+    WordNotEndingWithNewLine,
+    NewLineInSimpleString,
+    StringParseEncodingError,
+    InvalidInteger,
+    /// A blob string is longer than [`Limits::max_bulk_len`], or its length isn't a number.
+    InvalidBulkLength,
+    /// An array has more elements than [`Limits::max_multibulk_len`], or its length isn't a number.
+    InvalidMultibulkLength,
+    /// Arrays are nested deeper than [`Limits::max_nesting`].
+    TooDeeplyNested,
+    /// A line, like the header of a blob string or a simple string, is longer than 64KB.
+    LineTooLong,
+    /// Reading from the underlying stream failed.
+    IOError(std::io::Error),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnsupportedValue(c) => write!(f, "Protocol error: unexpected '{}'", c.escape_default()),
+            ProtocolError::WordNotEndingWithNewLine => write!(f, "Protocol error: expected CRLF"),
+            ProtocolError::NewLineInSimpleString => write!(f, "Protocol error: newline in simple string"),
+            ProtocolError::StringParseEncodingError => write!(f, "Protocol error: invalid UTF-8 in simple string"),
+            ProtocolError::InvalidInteger => write!(f, "Protocol error: invalid integer"),
+            ProtocolError::InvalidBulkLength => write!(f, "Protocol error: invalid bulk length"),
+            ProtocolError::InvalidMultibulkLength => write!(f, "Protocol error: invalid multibulk length"),
+            ProtocolError::TooDeeplyNested => write!(f, "Protocol error: too deeply nested"),
+            ProtocolError::LineTooLong => write!(f, "Protocol error: too big line"),
+            ProtocolError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> ProtocolError {
+        ProtocolError::IOError(e)
+    }
+}
+
+/// What the decoder accepts. Going over them is a [`ProtocolError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The longest blob string, in bytes.
+    pub max_bulk_len: usize,
+    /// The most elements of an array.
+    pub max_multibulk_len: usize,
+    /// How deep arrays may be nested in each other.
+    pub max_nesting: usize,
+}
+
+impl Default for Limits {
+    /// The defaults of Redis: 512mb blob strings, arrays of a million elements, nested 8 deep.
+    fn default() -> Self {
+        Self { max_bulk_len: 512 * 1024 * 1024, max_multibulk_len: 1024 * 1024, max_nesting: 8 }
+    }
+}
+
+fn parse_integer(slice: &[u8]) -> Option<i64> {
+    std::str::from_utf8(slice).ok()?.parse().ok()
+}
+
+/// Appends the RESP2 encoding of the value to the buffer.
+pub fn encode(value: RESPValue, buf: &mut BytesMut) {
+    // Writing to a BytesMut only fails when it can't grow, like any allocation.
+    write_value(value, buf).unwrap()
+}
+
+fn write_value(value: RESPValue, buf: &mut BytesMut) -> std::fmt::Result {
+    match value {
+        RESPValue::BlobString(s) => {
+            write!(buf, "${}\r\n", s.len())?;
+            buf.extend_from_slice(&s);
+            buf.extend_from_slice(WORD_BREAK.as_bytes());
+        },
+        RESPValue::SimpleString(s) => {
+            write!(buf, "+{}\r\n", s)?;
+        },
+        RESPValue::SimpleError(e) | RESPValue::BlobError(e) => {
+            buf.extend_from_slice(b"-");
+            buf.extend_from_slice(&e);
+            buf.extend_from_slice(WORD_BREAK.as_bytes());
+        },
+        RESPValue::Number(n) => {
+            write!(buf, ":{}\r\n", n)?;
+        },
+        RESPValue::Double(d) => {
+            write_value(RESPValue::BlobString(d.to_string().into()), buf)?;
+        },
+        RESPValue::Boolean(b) => {
+            write!(buf, ":{}\r\n", b as i64)?;
+        },
+        RESPValue::Null => {
+            write!(buf, "$-1\r\n")?;
+        },
+        // Pushes are sent as plain arrays, as connections always speak RESP2 on the wire.
+        RESPValue::Array(values) | RESPValue::Set(values) | RESPValue::Push(values) => {
+            write!(buf, "*{}\r\n", values.len())?;
+            for v in values {
+                write_value(v, buf)?;
+            }
+        },
+        RESPValue::Map(entries) => {
+            write!(buf, "*{}\r\n", entries.len() * 2)?;
+            for (key, value) in entries {
+                write_value(RESPValue::BlobString(key), buf)?;
+                write_value(value, buf)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Decodes the first value of the buffer, returning it along with the number of bytes it took, or
+/// None when it didn't fully arrive yet.
+pub fn parse(buf: &[u8]) -> Result<Option<(RESPValue, usize)>, ProtocolError> {
+    let mut buf = BytesMut::from(buf);
+    let len = buf.len();
+    Ok(RESPCodec::default().decode(&mut buf)?.map(|value| (value, len - buf.len())))
+}
+
+/// Decodes values as their bytes arrive, resuming where the last call stopped rather than parsing
+/// the value from its start again, and encodes values (see [`encode`]).
+// Every element is split off the buffer once decoded, so the buffer starts at the element being
+// decoded.
+#[derive(Default)]
+pub struct RESPCodec {
+    limits: Limits,
+    // The arrays being decoded, the innermost last.
+    arrays: Vec<PartialArray>,
+    // The size of the blob string whose body is awaited, after its header was decoded.
+    blob_size: Option<usize>,
+    // How far the end of the line at the start of the buffer was looked for.
+    scanned: usize,
+}
+
+struct PartialArray {
+    values: Vec<RESPValue>,
+    remaining: usize,
+}
+
+// Arrays claiming more elements than that only get room for them as they arrive.
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+
+impl RESPCodec {
+    /// A codec decoding up to the given limits, rather than the defaults.
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, ..Self::default() }
+    }
+
+    /// Changes the limits, which apply to what is decoded from now on.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // The end of the line at the start of the buffer, once all of it arrived.
+    fn line_end(&mut self, buf: &BytesMut) -> Result<Option<usize>, ProtocolError> {
+        let Some(end) = memchr(BREAK_FIRST_CHAR, &buf[self.scanned..]).map(|end| self.scanned + end) else {
+            if buf.len() > MAX_LINE {
+                return Err(ProtocolError::LineTooLong);
+            }
+            self.scanned = buf.len();
+            return Ok(None);
+        };
+        if end > MAX_LINE {
+            return Err(ProtocolError::LineTooLong);
+        }
+        self.scanned = end;
+        match buf.get(end + 1) {
+            None => Ok(None),
+            Some(&NEW_LINE) => Ok(Some(end)),
+            Some(_) => Err(ProtocolError::WordNotEndingWithNewLine)
+        }
+    }
+
+    // The next element, unless it didn't fully arrive yet. Arrays are decoded as their elements.
+    fn next_element(&mut self, buf: &mut BytesMut) -> Result<Option<RESPValue>, ProtocolError> {
+        loop {
+            if let Some(size) = self.blob_size {
+                if buf.len() < size + WORD_BREAK.len() {
+                    return Ok(None);
+                }
+                if &buf[size..size + WORD_BREAK.len()] != WORD_BREAK.as_bytes() {
+                    return Err(ProtocolError::WordNotEndingWithNewLine);
+                }
+                self.blob_size = None;
+                let blob = buf.split_to(size).freeze();
+                buf.advance(WORD_BREAK.len());
+                return Ok(Some(RESPValue::BlobString(blob)));
+            }
+
+            let Some(end) = self.line_end(buf)? else {
+                return Ok(None);
+            };
+            self.scanned = 0;
+            let line = buf.split_to(end + WORD_BREAK.len());
+            let Some((&kind, content)) = line[..end].split_first() else {
+                return Err(ProtocolError::UnsupportedValue('\r'));
+            };
+            match kind {
+                b'$' => match parse_integer(content).ok_or(ProtocolError::InvalidBulkLength)? {
+                    size if size < 0 => return Ok(Some(RESPValue::Null)),
+                    size if size as u64 > self.limits.max_bulk_len as u64 => return Err(ProtocolError::InvalidBulkLength),
+                    size => self.blob_size = Some(size as usize)
+                },
+                b'+' | b'-' => {
+                    if memchr(NEW_LINE, content).is_some() {
+                        return Err(ProtocolError::NewLineInSimpleString);
+                    }
+                    if kind == b'-' {
+                        return Ok(Some(RESPValue::SimpleError(Bytes::copy_from_slice(content))));
+                    }
+                    let s = String::from_utf8(content.to_vec()).map_err(|_| ProtocolError::StringParseEncodingError)?;
+                    return Ok(Some(RESPValue::SimpleString(s)));
+                },
+                b':' => return Ok(Some(RESPValue::Number(parse_integer(content).ok_or(ProtocolError::InvalidInteger)?))),
+                b'*' => match parse_integer(content).ok_or(ProtocolError::InvalidMultibulkLength)? {
+                    size if size < 0 => return Ok(Some(RESPValue::Null)),
+                    0 => return Ok(Some(RESPValue::Array(vec![]))),
+                    size if size as u64 > self.limits.max_multibulk_len as u64 => return Err(ProtocolError::InvalidMultibulkLength),
+                    _ if self.arrays.len() >= self.limits.max_nesting => return Err(ProtocolError::TooDeeplyNested),
+                    size => {
+                        let size = size as usize;
+                        let values = Vec::with_capacity(size.min(MAX_PREALLOCATED_ELEMENTS));
+                        self.arrays.push(PartialArray { values, remaining: size });
+                    }
+                },
+                kind => return Err(ProtocolError::UnsupportedValue(kind as char))
+            }
+        }
+    }
+
+    fn resume(&mut self, buf: &mut BytesMut) -> Result<Option<RESPValue>, ProtocolError> {
+        loop {
+            let Some(mut value) = self.next_element(buf)? else {
+                return Ok(None);
+            };
+            // Completes the arrays the value was the last element of.
+            loop {
+                let Some(array) = self.arrays.last_mut() else {
+                    return Ok(Some(value));
+                };
+                array.values.push(value);
+                array.remaining -= 1;
+                if array.remaining > 0 {
+                    break;
+                }
+                value = RESPValue::Array(self.arrays.pop().unwrap().values);
+            }
+        }
+    }
+}
+
+impl Decoder for RESPCodec {
+    type Item = RESPValue;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.resume(buf);
+        if result.is_err() {
+            *self = Self::new(self.limits);
+        }
+        result
+    }
+
+    // A peer disconnecting in the middle of a value ends the stream, rather than failing it.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(buf)
+    }
+}
+
+impl Encoder<RESPValue> for RESPCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, value: RESPValue, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        encode(value, buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(s: &str) -> RESPValue {
+        RESPValue::BlobString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    fn encoded(value: RESPValue) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        encode(value, &mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn parses_every_resp2_type() {
+        let cases: &[(&[u8], RESPValue)] = &[
+            (b"+OK\r\n", RESPValue::SimpleString(String::from("OK"))),
+            (b"-ERR wrong\r\n", RESPValue::SimpleError(Bytes::from_static(b"ERR wrong"))),
+            (b":-42\r\n", RESPValue::Number(-42)),
+            (b"$5\r\nhe\r\no\r\n", blob("he\r\no")),
+            (b"$0\r\n\r\n", blob("")),
+            (b"$-1\r\n", RESPValue::Null),
+            (b"*-1\r\n", RESPValue::Null),
+            (b"*0\r\n", RESPValue::Array(vec![])),
+            (b"*2\r\n*1\r\n:1\r\n$1\r\na\r\n", RESPValue::Array(vec![RESPValue::Array(vec![RESPValue::Number(1)]), blob("a")])),
+        ];
+        for (bytes, value) in cases {
+            assert_eq!(parse(bytes).unwrap(), Some((value.clone(), bytes.len())));
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = RESPValue::Array(vec![
+            blob("SET"),
+            RESPValue::SimpleString(String::from("simple")),
+            RESPValue::SimpleError(Bytes::from_static(b"ERR oops")),
+            RESPValue::Number(i64::MIN),
+            RESPValue::Null,
+            RESPValue::Array(vec![blob(""), RESPValue::Array(vec![])]),
+        ]);
+        let bytes = encoded(value.clone());
+        assert_eq!(parse(&bytes).unwrap(), Some((value, bytes.len())));
+    }
+
+    #[test]
+    fn encodes_resp3_types_as_resp2() {
+        assert_eq!(encoded(RESPValue::Double(1.5)), b"$3\r\n1.5\r\n");
+        assert_eq!(encoded(RESPValue::Boolean(true)), b":1\r\n");
+        assert_eq!(encoded(RESPValue::BlobError(Bytes::from_static(b"ERR x"))), b"-ERR x\r\n");
+        assert_eq!(encoded(RESPValue::Push(vec![RESPValue::Number(1)])), b"*1\r\n:1\r\n");
+        assert_eq!(encoded(RESPValue::Map(HashMap::from([(Bytes::from_static(b"k"), RESPValue::Number(1))]))), b"*2\r\n$1\r\nk\r\n:1\r\n");
+    }
+
+    #[test]
+    fn resumes_partial_values() {
+        let bytes = encoded(RESPValue::Array(vec![blob("GET"), blob("key")]));
+        let mut codec = RESPCodec::default();
+        let mut buf = BytesMut::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            buf.extend_from_slice(&[*byte]);
+            let decoded = codec.decode(&mut buf).unwrap();
+            assert_eq!(decoded.is_some(), i == bytes.len() - 1);
+        }
+        assert!(buf.is_empty());
+        assert_eq!(parse(&bytes[..bytes.len() - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn decodes_pipelined_values() {
+        let mut buf = BytesMut::from(&b":1\r\n:2\r\n:3"[..]);
+        let mut codec = RESPCodec::default();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(RESPValue::Number(1)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(RESPValue::Number(2)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(RESPValue::Number(3)));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(parse(b"?\r\n"), Err(ProtocolError::UnsupportedValue('?'))));
+        assert!(matches!(parse(b"+OK\rX"), Err(ProtocolError::WordNotEndingWithNewLine)));
+        assert!(matches!(parse(b"$3\r\nabcd\r\n"), Err(ProtocolError::WordNotEndingWithNewLine)));
+        assert!(matches!(parse(b"+a\nb\r\n"), Err(ProtocolError::NewLineInSimpleString)));
+        assert!(matches!(parse(b":x\r\n"), Err(ProtocolError::InvalidInteger)));
+        assert!(matches!(parse(b"$x\r\n"), Err(ProtocolError::InvalidBulkLength)));
+        assert!(matches!(parse(b"*x\r\n"), Err(ProtocolError::InvalidMultibulkLength)));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits { max_bulk_len: 3, max_multibulk_len: 2, max_nesting: 1 };
+        let decode = |bytes: &[u8]| RESPCodec::new(limits).decode(&mut BytesMut::from(bytes));
+        assert!(decode(b"$3\r\nabc\r\n").is_ok());
+        assert!(matches!(decode(b"$4\r\n"), Err(ProtocolError::InvalidBulkLength)));
+        assert!(matches!(decode(b"*3\r\n"), Err(ProtocolError::InvalidMultibulkLength)));
+        assert!(matches!(decode(b"*1\r\n*1\r\n"), Err(ProtocolError::TooDeeplyNested)));
+    }
+
+    #[test]
+    fn limits_the_length_of_lines() {
+        let mut codec = RESPCodec::default();
+        let mut buf = BytesMut::from(&b"*"[..]);
+        buf.extend_from_slice(&[b'1'; MAX_LINE / 2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[b'1'; MAX_LINE]);
+        assert!(matches!(codec.decode(&mut buf), Err(ProtocolError::LineTooLong)));
+        assert!(matches!(parse(&[&b"+"[..], &[b'a'; MAX_LINE + 1], b"\r\n"].concat()), Err(ProtocolError::LineTooLong)));
+    }
+
+    #[test]
+    fn starts_over_after_an_error() {
+        let mut codec = RESPCodec::default();
+        assert!(codec.decode(&mut BytesMut::from(&b"*2\r\n$1\r\na\r\n?\r\n"[..])).is_err());
+        assert_eq!(codec.decode(&mut BytesMut::from(&b":7\r\n"[..])).unwrap(), Some(RESPValue::Number(7)));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::glob::glob_match;
+use crate::RESPValue;
+
+pub type ClientId = u64;
+
+// A subscription namespace, every message published on one of its channels is pushed to the
+// subscribers as a `[kind, channel, message]` array.
+struct Channels {
+    message_kind: &'static str,
+    subscribers: HashMap<String, HashMap<ClientId, UnboundedSender<RESPValue>>>,
+}
+
+impl Channels {
+    fn new(message_kind: &'static str) -> Self {
+        Self { message_kind, subscribers: HashMap::new() }
+    }
+
+    fn subscribe(&mut self, channel: &str, id: ClientId, sender: UnboundedSender<RESPValue>) {
+        self.subscribers.entry(channel.to_owned()).or_default().insert(id, sender);
+    }
+
+    fn unsubscribe(&mut self, channel: &str, id: ClientId) {
+        if let Some(clients) = self.subscribers.get_mut(channel) {
+            clients.remove(&id);
+            if clients.is_empty() {
+                self.subscribers.remove(channel);
+            }
+        }
+    }
+
+    fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let clients = match self.subscribers.get(channel) {
+            Some(clients) => clients,
+            None => return 0
+        };
+
+        let push = RESPValue::Push(vec![
+            RESPValue::BlobString(self.message_kind.to_owned().into()),
+            RESPValue::BlobString(channel.to_owned().into()),
+            RESPValue::BlobString(Bytes::copy_from_slice(message)),
+        ]);
+
+        // A closed receiver means the client is disconnecting and will unsubscribe on its own.
+        clients.values().filter(|sender| sender.send(push.clone()).is_ok()).count()
+    }
+}
+
+pub struct PubSub {
+    channels: Channels,
+    patterns: HashMap<String, HashMap<ClientId, UnboundedSender<RESPValue>>>,
+    shard_channels: Channels,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self {
+            channels: Channels::new("message"),
+            patterns: HashMap::new(),
+            shard_channels: Channels::new("smessage"),
+        }
+    }
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, channel: &str, id: ClientId, sender: UnboundedSender<RESPValue>) {
+        self.channels.subscribe(channel, id, sender);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str, id: ClientId) {
+        self.channels.unsubscribe(channel, id);
+    }
+
+    pub fn psubscribe(&mut self, pattern: &str, id: ClientId, sender: UnboundedSender<RESPValue>) {
+        self.patterns.entry(pattern.to_owned()).or_default().insert(id, sender);
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str, id: ClientId) {
+        if let Some(clients) = self.patterns.get_mut(pattern) {
+            clients.remove(&id);
+            if clients.is_empty() {
+                self.patterns.remove(pattern);
+            }
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.subscribers.len()
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn unsubscribe_all(&mut self, channels: &HashSet<String>, patterns: &HashSet<String>, id: ClientId) {
+        for channel in channels {
+            self.channels.unsubscribe(channel, id);
+        }
+        for pattern in patterns {
+            self.punsubscribe(pattern, id);
+        }
+    }
+
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut receivers = self.channels.publish(channel, message);
+
+        for (pattern, clients) in &self.patterns {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+
+            let push = RESPValue::Push(vec![
+                RESPValue::BlobString("pmessage".into()),
+                RESPValue::BlobString(pattern.to_owned().into()),
+                RESPValue::BlobString(channel.to_owned().into()),
+                RESPValue::BlobString(Bytes::copy_from_slice(message)),
+            ]);
+            receivers += clients.values().filter(|sender| sender.send(push.clone()).is_ok()).count();
+        }
+
+        receivers
+    }
+
+    // Sends a message to a single subscriber of the channel, returning whether it is subscribed.
+    // Unlike publish, the message can be any value (like the array of keys of an invalidation).
+    pub fn send_to(&self, channel: &str, id: ClientId, message: RESPValue) -> bool {
+        let sender = match self.channels.subscribers.get(channel).and_then(|clients| clients.get(&id)) {
+            Some(sender) => sender,
+            None => return false
+        };
+        let push = RESPValue::Push(vec![
+            RESPValue::BlobString("message".into()),
+            RESPValue::BlobString(channel.to_owned().into()),
+            message,
+        ]);
+        sender.send(push).is_ok()
+    }
+
+    pub fn ssubscribe(&mut self, channel: &str, id: ClientId, sender: UnboundedSender<RESPValue>) {
+        self.shard_channels.subscribe(channel, id, sender);
+    }
+
+    pub fn sunsubscribe(&mut self, channel: &str, id: ClientId) {
+        self.shard_channels.unsubscribe(channel, id);
+    }
+
+    pub fn sunsubscribe_all(&mut self, channels: &HashSet<String>, id: ClientId) {
+        for channel in channels {
+            self.shard_channels.unsubscribe(channel, id);
+        }
+    }
+
+    pub fn spublish(&self, channel: &str, message: &[u8]) -> usize {
+        self.shard_channels.publish(channel, message)
+    }
+}
+
+pub fn subscription_reply(kind: &str, channel: Option<&str>, count: usize) -> RESPValue {
+    RESPValue::Push(vec![
+        RESPValue::BlobString(kind.to_owned().into()),
+        channel.map_or(RESPValue::Null, |c| RESPValue::BlobString(c.to_owned().into())),
+        RESPValue::Number(count as i64),
+    ])
+}
@@ -0,0 +1,90 @@
+// RESP over QUIC, built with the quic feature (experimental). With `quic-port` set, every address of
+// `bind` accepts QUIC connections on that UDP port, secured by the certificate and key of TLS (see
+// tls.rs) and negotiating the ALPN protocol "resp". Every bidirectional stream a client opens is
+// served as a connection of its own, like a TCP connection would be.
+//
+// Clients resuming a session can send their first commands as 0-RTT data, which saves a round trip
+// on high latency links. Early data can be replayed by someone on the path though, so only read-only
+// commands run before the handshake completes, the others (AUTH included) waiting for it, which a
+// replayed connection never completes.
+
+use std::sync::Arc;
+
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, EndpointConfig, Incoming, ServerConfig, TokioRuntime};
+use tokio::sync::watch;
+
+use crate::{bind_addresses, handle_connection, logging, tls, SharedState};
+
+const ALPN: &[u8] = b"resp";
+
+async fn serve_connection(incoming: Incoming, laddr: String, shared: Arc<SharedState>) {
+    let addr = incoming.remote_address();
+    logging::log(&shared, "verbose", format!("New QUIC connection from {}", addr));
+    let connecting = match incoming.accept() {
+        Ok(connecting) => connecting,
+        Err(e) => return logging::log(&shared, "verbose", format!("Failed accepting the QUIC connection from {}: {}", addr, e))
+    };
+    // Streams are served as soon as they arrive, 0-RTT ones included, rather than once the
+    // handshake completes, which is told to their connections.
+    let (connection, handshake) = match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            let (done, handshake) = watch::channel(false);
+            let connected = connection.clone();
+            tokio::spawn(async move {
+                // Resolves once the handshake completed or failed, the connection being closed then.
+                accepted.await;
+                let _ = done.send(connected.close_reason().is_none());
+            });
+            (connection, Some(handshake))
+        },
+        Err(connecting) => match connecting.await {
+            Ok(connection) => (connection, None),
+            Err(e) => return logging::log(&shared, "verbose", format!("Failed QUIC handshake with {}: {}", addr, e))
+        }
+    };
+    loop {
+        match connection.accept_bi().await {
+            Ok((send, recv)) => {
+                let stream = tokio::io::join(recv, send);
+                tokio::spawn(handle_connection(stream, Some(addr.to_string()), laddr.clone(), handshake.clone(), shared.clone()));
+            },
+            Err(e) => return logging::log(&shared, "verbose", format!("QUIC connection from {} closed: {}", addr, e))
+        }
+    }
+}
+
+async fn accept(endpoint: Endpoint, shared: Arc<SharedState>) {
+    let laddr = endpoint.local_addr().map_or_else(|_| String::new(), |laddr| laddr.to_string());
+    while let Some(incoming) = endpoint.accept().await {
+        tokio::spawn(serve_connection(incoming, laddr.clone(), shared.clone()));
+    }
+}
+
+// Serves QUIC connections on the port of every address of `bind`.
+pub fn serve(port: u16, shared: &Arc<SharedState>) -> Result<(), String> {
+    let mut crypto = tls::server_config(shared)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    // QUIC takes early data either not at all or unbounded.
+    crypto.max_early_data_size = u32::MAX;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(|e| e.to_string())?;
+    let config = ServerConfig::with_crypto(Arc::new(crypto));
+
+    let mut endpoints = vec![];
+    for (ip, optional) in bind_addresses(shared) {
+        let endpoint = std::net::UdpSocket::bind((ip.as_str(), port))
+            .and_then(|socket| Endpoint::new(EndpointConfig::default(), Some(config.clone()), socket, Arc::new(TokioRuntime)));
+        match endpoint {
+            Ok(endpoint) => endpoints.push(endpoint),
+            Err(e) if optional => logging::log(shared, "warning", format!("Skipping listening on {}:{} over QUIC: {}", ip, port, e)),
+            Err(e) => return Err(format!("Failed listening on {}:{} over QUIC: {}", ip, port, e))
+        }
+    }
+    if endpoints.is_empty() {
+        return Err(format!("Failed listening on port {} of any address over QUIC", port));
+    }
+    for endpoint in endpoints {
+        tokio::spawn(accept(endpoint, shared.clone()));
+    }
+    Ok(())
+}
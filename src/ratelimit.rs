@@ -0,0 +1,120 @@
+// Rate limiting of the commands clients send, so a noisy client can't starve the others of a
+// shared server. Every connection gets `client-rate-limit-commands` commands and
+// `client-rate-limit-bytes` bytes of requests per second, and every ACL user gets
+// `user-rate-limit-commands` and `user-rate-limit-bytes` across all of its connections (0 being
+// unlimited). Limits are token buckets holding up to a second worth of tokens, so short bursts pass.
+//
+// A client over its limits is delayed until it's back under them, which stops reading its requests
+// meanwhile, or with `rate-limit-action reject` gets -THROTTLED for its commands instead.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::arg::Arg;
+use crate::stats::Stats;
+use crate::{Client, RESPError, SharedState};
+
+struct Bucket {
+    // Negative when more was taken than there was, which has to be paid back before taking more.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Self { tokens: rate, updated: Instant::now() }
+    }
+
+    // How long until the bucket isn't in debt anymore.
+    fn wait(&mut self, rate: f64) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(rate);
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+// The buckets of commands and of bytes of a connection or a user, created once limited.
+#[derive(Default)]
+pub struct Buckets {
+    commands: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn wait(&mut self, commands_rate: i64, bytes_rate: i64) -> Duration {
+        let wait = |bucket: &mut Option<Bucket>, rate: i64| match rate {
+            0 => {
+                *bucket = None;
+                Duration::ZERO
+            },
+            rate => bucket.get_or_insert_with(|| Bucket::new(rate as f64)).wait(rate as f64)
+        };
+        wait(&mut self.commands, commands_rate).max(wait(&mut self.bytes, bytes_rate))
+    }
+
+    fn take(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.commands {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+}
+
+// The buckets of the ACL users.
+#[derive(Default)]
+pub struct RateLimits {
+    users: Mutex<HashMap<String, Buckets>>,
+}
+
+// The size of the command as sent by the client.
+fn request_bytes(command: &[Arg]) -> usize {
+    let header = |len: usize| 3 + len.to_string().len();
+    header(command.len()) + command.iter().map(|arg| header(arg.len()) + arg.len() + 2).sum::<usize>()
+}
+
+// Waits until the client and its user are under their limits before running the command, or refuses
+// it with `rate-limit-action reject`.
+pub async fn throttle(command: &[Arg], client: &mut Client, shared: &SharedState) -> Result<(), RESPError> {
+    let config = &shared.config;
+    let (client_commands, client_bytes) = (config.get_int("client-rate-limit-commands"), config.get_int("client-rate-limit-bytes"));
+    let (user_commands, user_bytes) = (config.get_int("user-rate-limit-commands"), config.get_int("user-rate-limit-bytes"));
+    if client_commands == 0 && client_bytes == 0 && user_commands == 0 && user_bytes == 0 {
+        return Ok(());
+    }
+    let user_wait = |shared: &SharedState, user: &str| {
+        let mut users = shared.rate_limits.users.lock().unwrap();
+        // Users no longer limited don't need buckets anymore.
+        if user_commands == 0 && user_bytes == 0 {
+            users.clear();
+            return Duration::ZERO;
+        }
+        users.entry(user.to_owned()).or_default().wait(user_commands, user_bytes)
+    };
+
+    let mut wait = client.rate_limit.wait(client_commands, client_bytes).max(user_wait(shared, &client.user));
+    if !wait.is_zero() {
+        Stats::incr(&shared.stats.rate_limited_commands);
+        if config.get("rate-limit-action") == "reject" {
+            return Err(RESPError::Throttled);
+        }
+        // Other connections of the user may take its tokens meanwhile, so it's checked again.
+        while !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+            wait = user_wait(shared, &client.user);
+        }
+    }
+    let bytes = request_bytes(command);
+    client.rate_limit.take(bytes);
+    if let Some(buckets) = shared.rate_limits.users.lock().unwrap().get_mut(&client.user) {
+        buckets.take(bytes);
+    }
+    Ok(())
+}
@@ -0,0 +1,777 @@
+// Reading and writing Redis RDB files, so datasets can move between Redis and bast. Strings, lists,
+// hashes, sorted sets, streams and functions are read in every encoding of RDB versions 1 to 12
+// (but the zipmap of the oldest ones), and files with other types (like sets, which bast doesn't
+// have) are refused. Files are written as version 11 (Redis 7.2) with the plain encodings every
+// later version of Redis loads as well, except for hashes with field TTLs which only Redis 7.4 and
+// later have. Bloom and cuckoo filters are written as values of bast's own module types, which only
+// bast loads, and JSON documents as values of the module type of RedisJSON holding their JSON text.
+// Single values are serialized the same way for DUMP and RESTORE.
+
+use std::cell::Cell;
+use std::io;
+
+use crate::bloom::{self, Bloom};
+use crate::cuckoo::{self, Cuckoo};
+use crate::db::{now_ms, Db, Value};
+use crate::hash::Hash;
+use crate::json;
+use crate::list::List;
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use crate::{crc64, logging, SharedState};
+
+pub const MAGIC: &[u8] = b"REDIS";
+const VERSION: u32 = 11;
+const MAX_VERSION: u32 = 12;
+
+const OP_SLOT_INFO: u8 = 0xf4;
+const OP_FUNCTION2: u8 = 0xf5;
+const OP_FUNCTION_PRE_GA: u8 = 0xf6;
+const OP_MODULE_AUX: u8 = 0xf7;
+const OP_IDLE: u8 = 0xf8;
+const OP_FREQ: u8 = 0xf9;
+const OP_AUX: u8 = 0xfa;
+const OP_RESIZEDB: u8 = 0xfb;
+const OP_EXPIRETIME_MS: u8 = 0xfc;
+const OP_EXPIRETIME: u8 = 0xfd;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// The opcodes of the values of module types, which are made of numbers and strings.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+const MODULE_NAME_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// The containers of quicklist nodes.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+pub fn corrupted(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+pub fn write_len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+pub fn write_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_len(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// The ID of a module type: its 9 character name in 6 bits a character, followed by the 10 bit
+// version of its encoding.
+pub const fn module_id(name: &[u8; 9], encoding_version: u64) -> u64 {
+    let mut id = 0;
+    let mut i = 0;
+    while i < name.len() {
+        let mut position = 0;
+        while MODULE_NAME_CHARSET[position] != name[i] {
+            position += 1;
+        }
+        id = (id << 6) | position as u64;
+        i += 1;
+    }
+    (id << 10) | encoding_version
+}
+
+pub fn write_module_uint(buf: &mut Vec<u8>, n: u64) {
+    write_len(buf, MODULE_OPCODE_UINT);
+    write_len(buf, n);
+}
+
+pub fn write_module_double(buf: &mut Vec<u8>, n: f64) {
+    write_len(buf, MODULE_OPCODE_DOUBLE);
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn write_module_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_len(buf, MODULE_OPCODE_STRING);
+    write_string(buf, bytes);
+}
+
+fn write_aux(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.push(OP_AUX);
+    write_string(buf, name.as_bytes());
+    write_string(buf, value.as_bytes());
+}
+
+pub fn write_header(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(format!("{:04}", VERSION).as_bytes());
+    write_aux(buf, "redis-ver", "7.2.0");
+    write_aux(buf, "redis-bits", &usize::BITS.to_string());
+    write_aux(buf, "ctime", &(now_ms() / 1000).to_string());
+    write_aux(buf, "aof-base", "0");
+    buf.push(OP_SELECTDB);
+    write_len(buf, 0);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) | Value::CompressedString(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+        Value::Hash(hash) if hash.next_expiration().is_some() => TYPE_HASH_METADATA,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS,
+        Value::Bloom(_) | Value::Cuckoo(_) | Value::Json(_) => TYPE_MODULE_2,
+    }
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(_) | Value::CompressedString(_) => write_string(buf, &value.as_string().unwrap()),
+        Value::List(list) => {
+            write_len(buf, list.len() as u64);
+            for element in list.iter() {
+                write_string(buf, element);
+            }
+        },
+        Value::SortedSet(set) => {
+            write_len(buf, set.len() as u64);
+            for (member, score) in set.iter() {
+                write_string(buf, member.as_bytes());
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        },
+        // With field TTLs, the time the first field expires at comes first, and every field has its
+        // TTL as the time it expires at relative to that one, plus one (0 when it has none).
+        Value::Hash(hash) => {
+            let min = hash.next_expiration();
+            if let Some(min) = min {
+                buf.extend_from_slice(&min.to_le_bytes());
+            }
+            write_len(buf, hash.len() as u64);
+            for (field, value, expire) in hash.iter() {
+                if let Some(min) = min {
+                    write_len(buf, expire.map_or(0, |at| at - min + 1));
+                }
+                write_string(buf, field.as_bytes());
+                write_string(buf, value);
+            }
+        },
+        Value::Stream(stream) => stream.write_rdb(buf),
+        Value::Bloom(bloom) => {
+            write_len(buf, bloom::MODULE_ID);
+            bloom.write_rdb(buf);
+            write_len(buf, MODULE_OPCODE_EOF);
+        },
+        Value::Cuckoo(cuckoo) => {
+            write_len(buf, cuckoo::MODULE_ID);
+            cuckoo.write_rdb(buf);
+            write_len(buf, MODULE_OPCODE_EOF);
+        },
+        Value::Json(json) => {
+            write_len(buf, json::MODULE_ID);
+            write_module_string(buf, json.to_string().as_bytes());
+            write_len(buf, MODULE_OPCODE_EOF);
+        },
+    }
+}
+
+pub fn write_key(buf: &mut Vec<u8>, key: &str, value: &Value, expire: Option<u64>) {
+    if let Some(at) = expire {
+        buf.push(OP_EXPIRETIME_MS);
+        buf.extend_from_slice(&at.to_le_bytes());
+    }
+    buf.push(value_type(value));
+    write_string(buf, key.as_bytes());
+    write_value(buf, value);
+}
+
+pub fn write_function(buf: &mut Vec<u8>, code: &str) {
+    buf.push(OP_FUNCTION2);
+    write_string(buf, code.as_bytes());
+}
+
+pub fn write_eof(buf: &mut Vec<u8>) {
+    buf.push(OP_EOF);
+}
+
+// An element of a ziplist or a listpack, which keep numbers as integers.
+pub enum Element<'a> {
+    Bytes(&'a [u8]),
+    Int(i64),
+}
+
+impl Element<'_> {
+    pub fn to_vec(&self) -> Vec<u8> {
+        match self {
+            Element::Bytes(bytes) => bytes.to_vec(),
+            Element::Int(n) => n.to_string().into_bytes(),
+        }
+    }
+
+    pub fn as_int(&self) -> io::Result<i64> {
+        match self {
+            Element::Bytes(bytes) => std::str::from_utf8(bytes).ok().and_then(|s| s.parse().ok())
+                .ok_or_else(|| corrupted("invalid integer")),
+            Element::Int(n) => Ok(*n),
+        }
+    }
+
+    fn as_score(&self) -> io::Result<f64> {
+        match self {
+            Element::Bytes(bytes) => parse_score(bytes),
+            Element::Int(n) => Ok(*n as f64),
+        }
+    }
+}
+
+fn parse_score(bytes: &[u8]) -> io::Result<f64> {
+    std::str::from_utf8(bytes).ok().and_then(|s| s.parse().ok()).ok_or_else(|| corrupted("invalid score"))
+}
+
+// The size of the length of a listpack entry stored after it, for walking the listpack backwards.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5
+    }
+}
+
+pub fn encode_listpack(elements: &[Element]) -> Vec<u8> {
+    let mut buf = vec![0; 6];
+    for element in elements {
+        let start = buf.len();
+        match element {
+            Element::Int(n @ 0..=127) => buf.push(*n as u8),
+            Element::Int(n @ -4096..=4095) => buf.extend_from_slice(&[0xc0 | ((*n >> 8) as u8 & 0x1f), *n as u8]),
+            Element::Int(n) if i16::try_from(*n).is_ok() => {
+                buf.push(0xf1);
+                buf.extend_from_slice(&(*n as i16).to_le_bytes());
+            },
+            Element::Int(n @ -8388608..=8388607) => {
+                buf.push(0xf2);
+                buf.extend_from_slice(&n.to_le_bytes()[..3]);
+            },
+            Element::Int(n) if i32::try_from(*n).is_ok() => {
+                buf.push(0xf3);
+                buf.extend_from_slice(&(*n as i32).to_le_bytes());
+            },
+            Element::Int(n) => {
+                buf.push(0xf4);
+                buf.extend_from_slice(&n.to_le_bytes());
+            },
+            Element::Bytes(bytes) => {
+                match bytes.len() {
+                    len @ 0..=63 => buf.push(0x80 | len as u8),
+                    len @ 64..=4095 => buf.extend_from_slice(&[0xe0 | (len >> 8) as u8, len as u8]),
+                    len => {
+                        buf.push(0xf0);
+                        buf.extend_from_slice(&(len as u32).to_le_bytes());
+                    }
+                }
+                buf.extend_from_slice(bytes);
+            }
+        }
+        // The length again, in 7 bit groups from the most significant one, with the high bit set
+        // on all groups but the first.
+        let len = buf.len() - start;
+        let size = backlen_size(len);
+        for i in (0..size).rev() {
+            let group = ((len >> (7 * i)) & 0x7f) as u8;
+            buf.push(if i == size - 1 { group } else { group | 0x80 });
+        }
+    }
+    buf.push(0xff);
+    let total = buf.len() as u32;
+    buf[..4].copy_from_slice(&total.to_le_bytes());
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    buf[4..6].copy_from_slice(&count.to_le_bytes());
+    buf
+}
+
+fn int_at(data: &[u8], at: usize, size: usize) -> io::Result<i64> {
+    let bytes = data.get(at..at + size).ok_or_else(|| corrupted("truncated integer"))?;
+    let mut le = [0; 8];
+    le[..size].copy_from_slice(bytes);
+    // Sign extends from the size of the integer.
+    let shift = 64 - 8 * size as u32;
+    Ok((i64::from_le_bytes(le) << shift) >> shift)
+}
+
+fn bytes_at(data: &[u8], at: usize, len: usize) -> io::Result<&[u8]> {
+    data.get(at..at + len).ok_or_else(|| corrupted("truncated string"))
+}
+
+pub fn decode_listpack(data: &[u8]) -> io::Result<Vec<Element<'_>>> {
+    let mut elements = vec![];
+    let mut at = 6;
+    loop {
+        let byte = *data.get(at).ok_or_else(|| corrupted("truncated listpack"))?;
+        let (element, len) = match byte {
+            0xff => break,
+            0x00..=0x7f => (Element::Int(byte as i64), 1),
+            0x80..=0xbf => {
+                let len = (byte & 0x3f) as usize;
+                (Element::Bytes(bytes_at(data, at + 1, len)?), 1 + len)
+            },
+            0xc0..=0xdf => {
+                let n = ((byte as i64 & 0x1f) << 8) | *data.get(at + 1).ok_or_else(|| corrupted("truncated listpack"))? as i64;
+                (Element::Int(if n >= 1 << 12 { n - (1 << 13) } else { n }), 2)
+            },
+            0xe0..=0xef => {
+                let len = ((byte as usize & 0x0f) << 8) | *data.get(at + 1).ok_or_else(|| corrupted("truncated listpack"))? as usize;
+                (Element::Bytes(bytes_at(data, at + 2, len)?), 2 + len)
+            },
+            0xf0 => {
+                let len = int_at(data, at + 1, 4)? as u32 as usize;
+                (Element::Bytes(bytes_at(data, at + 5, len)?), 5 + len)
+            },
+            0xf1 => (Element::Int(int_at(data, at + 1, 2)?), 3),
+            0xf2 => (Element::Int(int_at(data, at + 1, 3)?), 4),
+            0xf3 => (Element::Int(int_at(data, at + 1, 4)?), 5),
+            0xf4 => (Element::Int(int_at(data, at + 1, 8)?), 9),
+            _ => return Err(corrupted("invalid listpack encoding"))
+        };
+        elements.push(element);
+        at += len + backlen_size(len);
+    }
+    Ok(elements)
+}
+
+fn decode_ziplist(data: &[u8]) -> io::Result<Vec<Element<'_>>> {
+    let mut elements = vec![];
+    let mut at = 10;
+    loop {
+        let prevlen = *data.get(at).ok_or_else(|| corrupted("truncated ziplist"))?;
+        if prevlen == 0xff {
+            break;
+        }
+        at += if prevlen < 0xfe { 1 } else { 5 };
+        let byte = *data.get(at).ok_or_else(|| corrupted("truncated ziplist"))?;
+        let (element, len) = match byte {
+            0x00..=0x3f => {
+                let len = byte as usize;
+                (Element::Bytes(bytes_at(data, at + 1, len)?), 1 + len)
+            },
+            0x40..=0x7f => {
+                let len = ((byte as usize & 0x3f) << 8) | *data.get(at + 1).ok_or_else(|| corrupted("truncated ziplist"))? as usize;
+                (Element::Bytes(bytes_at(data, at + 2, len)?), 2 + len)
+            },
+            0x80 => {
+                let len = u32::from_be_bytes(bytes_at(data, at + 1, 4)?.try_into().unwrap()) as usize;
+                (Element::Bytes(bytes_at(data, at + 5, len)?), 5 + len)
+            },
+            0xc0 => (Element::Int(int_at(data, at + 1, 2)?), 3),
+            0xd0 => (Element::Int(int_at(data, at + 1, 4)?), 5),
+            0xe0 => (Element::Int(int_at(data, at + 1, 8)?), 9),
+            0xf0 => (Element::Int(int_at(data, at + 1, 3)?), 4),
+            0xfe => (Element::Int(int_at(data, at + 1, 1)?), 2),
+            0xf1..=0xfd => (Element::Int((byte & 0x0f) as i64 - 1), 1),
+            _ => return Err(corrupted("invalid ziplist encoding"))
+        };
+        elements.push(element);
+        at += len;
+    }
+    Ok(elements)
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut at = 0;
+    while at < input.len() {
+        let ctrl = input[at] as usize;
+        at += 1;
+        if ctrl < 1 << 5 {
+            // A literal run of ctrl + 1 bytes.
+            output.extend_from_slice(bytes_at(input, at, ctrl + 1)?);
+            at += ctrl + 1;
+        } else {
+            // A back reference.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(at).ok_or_else(|| corrupted("truncated compressed string"))? as usize;
+                at += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(at).ok_or_else(|| corrupted("truncated compressed string"))? as usize + 1;
+            at += 1;
+            let start = output.len().checked_sub(offset).ok_or_else(|| corrupted("invalid compressed string"))?;
+            for i in 0..run + 2 {
+                output.push(output[start + i]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(corrupted("invalid compressed string"));
+    }
+    Ok(output)
+}
+
+enum Length {
+    Len(u64),
+    // A special encoding of a string.
+    Encoded(u8),
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    // Whether strings that had to be UTF-8 weren't, since the last take_non_utf8.
+    non_utf8: Cell<bool>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(corrupted("unexpected end of file"));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u64_le(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn length(&mut self) -> io::Result<Length> {
+        let byte = self.u8()?;
+        Ok(match byte >> 6 {
+            0 => Length::Len((byte & 0x3f) as u64),
+            1 => Length::Len((((byte & 0x3f) as u64) << 8) | self.u8()? as u64),
+            2 if byte == 0x80 => Length::Len(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64),
+            2 if byte == 0x81 => Length::Len(u64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+            2 => return Err(corrupted("invalid length")),
+            _ => Length::Encoded(byte & 0x3f)
+        })
+    }
+
+    pub fn len(&mut self) -> io::Result<u64> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(corrupted("invalid length"))
+        }
+    }
+
+    pub fn string(&mut self) -> io::Result<Vec<u8>> {
+        Ok(match self.length()? {
+            Length::Len(len) => self.take(len as usize)?.to_vec(),
+            Length::Encoded(0) => (self.u8()? as i8).to_string().into_bytes(),
+            Length::Encoded(1) => i16::from_le_bytes(self.take(2)?.try_into().unwrap()).to_string().into_bytes(),
+            Length::Encoded(2) => i32::from_le_bytes(self.take(4)?.try_into().unwrap()).to_string().into_bytes(),
+            Length::Encoded(3) => {
+                let compressed_len = self.len()? as usize;
+                let len = self.len()? as usize;
+                lzf_decompress(self.take(compressed_len)?, len)?
+            },
+            Length::Encoded(_) => return Err(corrupted("invalid string encoding"))
+        })
+    }
+
+    pub fn utf8(&mut self) -> io::Result<String> {
+        String::from_utf8(self.string()?).map_err(|_| corrupted("invalid string"))
+    }
+
+    // Reads a string kept as text by bast (like a key or a hash field), which Redis doesn't require
+    // to be UTF-8. One that isn't gets its invalid sequences replaced and is noted, for the key it's
+    // part of to be skipped rather than the whole file failing to load.
+    pub fn text(&mut self) -> io::Result<String> {
+        let bytes = self.string()?;
+        Ok(self.to_text(&bytes))
+    }
+
+    // The text of an element of a listpack or ziplist, like `text`.
+    pub fn to_text(&self, bytes: &[u8]) -> String {
+        if std::str::from_utf8(bytes).is_err() {
+            self.non_utf8.set(true);
+        }
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+
+    pub fn take_non_utf8(&self) -> bool {
+        self.non_utf8.replace(false)
+    }
+
+    fn module_opcode(&mut self, expected: u64) -> io::Result<()> {
+        if self.len()? != expected {
+            return Err(corrupted("unexpected module value opcode"));
+        }
+        Ok(())
+    }
+
+    pub fn module_uint(&mut self) -> io::Result<u64> {
+        self.module_opcode(MODULE_OPCODE_UINT)?;
+        self.len()
+    }
+
+    pub fn module_double(&mut self) -> io::Result<f64> {
+        self.module_opcode(MODULE_OPCODE_DOUBLE)?;
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn module_string(&mut self) -> io::Result<Vec<u8>> {
+        self.module_opcode(MODULE_OPCODE_STRING)?;
+        self.string()
+    }
+}
+
+fn zset_from_pairs(elements: &[Element], reader: &Reader) -> io::Result<SortedSet> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(corrupted("invalid sorted set"));
+    }
+    let mut set = SortedSet::default();
+    for pair in elements.chunks(2) {
+        set.insert(reader.to_text(&pair[0].to_vec()), pair[1].as_score()?);
+    }
+    Ok(set)
+}
+
+// Fields and values, followed by the times the fields expire at (0 when they have none) with TTLs.
+fn hash_from_elements(elements: &[Element], ttls: bool, reader: &Reader) -> io::Result<Hash> {
+    let width = if ttls { 3 } else { 2 };
+    if !elements.len().is_multiple_of(width) {
+        return Err(corrupted("invalid hash"));
+    }
+    let mut hash = Hash::default();
+    for entry in elements.chunks(width) {
+        let field = reader.to_text(&entry[0].to_vec());
+        hash.insert(field.clone(), entry[1].to_vec().into());
+        if ttls && entry[2].as_int()? > 0 {
+            hash.set_expire(&field, entry[2].as_int()? as u64);
+        }
+    }
+    Ok(hash)
+}
+
+fn read_value(value_type: u8, reader: &mut Reader) -> io::Result<Value> {
+    Ok(match value_type {
+        TYPE_STRING => Value::string(reader.string()?),
+        TYPE_LIST => {
+            let len = reader.len()?;
+            Value::List((0..len).map(|_| reader.string()).collect::<io::Result<List>>()?)
+        },
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut set = SortedSet::default();
+            for _ in 0..reader.len()? {
+                let member = reader.text()?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(reader.take(8)?.try_into().unwrap())
+                } else {
+                    match reader.u8()? {
+                        253 => f64::NAN,
+                        254 => f64::INFINITY,
+                        255 => f64::NEG_INFINITY,
+                        len => parse_score(reader.take(len as usize)?)?
+                    }
+                };
+                set.insert(member, score);
+            }
+            Value::SortedSet(set)
+        },
+        TYPE_HASH | TYPE_HASH_METADATA => {
+            let min = if value_type == TYPE_HASH_METADATA { Some(reader.u64_le()?) } else { None };
+            let mut hash = Hash::default();
+            for _ in 0..reader.len()? {
+                let ttl = if min.is_some() { reader.len()? } else { 0 };
+                let field = reader.text()?;
+                hash.insert(field.clone(), reader.string()?.into());
+                if let (Some(min), 1..) = (min, ttl) {
+                    hash.set_expire(&field, min + ttl - 1);
+                }
+            }
+            Value::Hash(hash)
+        },
+        TYPE_HASH_ZIPLIST => Value::Hash(hash_from_elements(&decode_ziplist(&reader.string()?)?, false, reader)?),
+        TYPE_HASH_LISTPACK => Value::Hash(hash_from_elements(&decode_listpack(&reader.string()?)?, false, reader)?),
+        TYPE_HASH_LISTPACK_EX => {
+            reader.u64_le()?;
+            Value::Hash(hash_from_elements(&decode_listpack(&reader.string()?)?, true, reader)?)
+        },
+        TYPE_LIST_ZIPLIST => Value::List(decode_ziplist(&reader.string()?)?.iter().map(Element::to_vec).collect()),
+        TYPE_ZSET_ZIPLIST => Value::SortedSet(zset_from_pairs(&decode_ziplist(&reader.string()?)?, reader)?),
+        TYPE_ZSET_LISTPACK => Value::SortedSet(zset_from_pairs(&decode_listpack(&reader.string()?)?, reader)?),
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let mut elements = vec![];
+            for _ in 0..reader.len()? {
+                let container = if value_type == TYPE_LIST_QUICKLIST_2 { reader.len()? } else { QUICKLIST_NODE_PACKED };
+                let node = reader.string()?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => elements.push(node),
+                    QUICKLIST_NODE_PACKED if value_type == TYPE_LIST_QUICKLIST => elements.extend(decode_ziplist(&node)?.iter().map(Element::to_vec)),
+                    QUICKLIST_NODE_PACKED => elements.extend(decode_listpack(&node)?.iter().map(Element::to_vec)),
+                    _ => return Err(corrupted("invalid quicklist node"))
+                }
+            }
+            Value::List(elements.into_iter().collect())
+        },
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => Value::Stream(Stream::read_rdb(reader, value_type)?),
+        TYPE_MODULE_2 => {
+            let value = match reader.len()? {
+                bloom::MODULE_ID => Value::Bloom(Bloom::read_rdb(reader)?),
+                cuckoo::MODULE_ID => Value::Cuckoo(Cuckoo::read_rdb(reader)?),
+                json::MODULE_ID => Value::Json(serde_json::from_slice(&reader.module_string()?).map_err(|_| corrupted("invalid JSON"))?),
+                _ => return Err(corrupted("unsupported module type"))
+            };
+            reader.module_opcode(MODULE_OPCODE_EOF)?;
+            value
+        },
+        _ => return Err(corrupted(&format!("unsupported value type {}", value_type)))
+    })
+}
+
+// A single value as DUMP serializes it, like Redis does: its type and RDB encoding, followed by the
+// RDB version and the CRC-64 of everything before it.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut payload = vec![value_type(value)];
+    write_value(&mut payload, value);
+    payload.extend_from_slice(&(VERSION as u16).to_le_bytes());
+    let crc = crc64::update(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+// The length of the RDB encoding of a value, without its type.
+pub fn serialized_len(value: &Value) -> usize {
+    let mut buf = vec![];
+    write_value(&mut buf, value);
+    buf.len()
+}
+
+// Whether the payload has a footer with a version that can be read and a matching checksum.
+pub fn verify_dump(payload: &[u8]) -> bool {
+    if payload.len() < 10 {
+        return false;
+    }
+    let (body, checksum) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes(body[body.len() - 2..].try_into().unwrap()) as u32;
+    version <= MAX_VERSION && crc64::update(0, body) == u64::from_le_bytes(checksum.try_into().unwrap())
+}
+
+// Reads the value of a verified DUMP payload.
+pub fn undump(payload: &[u8]) -> io::Result<Value> {
+    let mut reader = Reader { buf: &payload[..payload.len() - 10], non_utf8: Cell::new(false) };
+    let value = read_value(reader.u8()?, &mut reader)?;
+    if !reader.buf.is_empty() {
+        return Err(corrupted("data after the end of the value"));
+    }
+    if reader.take_non_utf8() {
+        return Err(corrupted("strings that aren't UTF-8"));
+    }
+    Ok(value)
+}
+
+// Loads an RDB file into the keyspace, returning the amount of keys loaded and the size of the RDB
+// data, which may be followed by more. Keys that expired since are skipped, as are the access times
+// and frequencies of keys.
+pub fn load(data: &[u8], db: &mut Db, shared: &SharedState) -> io::Result<(usize, usize)> {
+    let version: u32 = data.get(MAGIC.len()..MAGIC.len() + 4).and_then(|version| std::str::from_utf8(version).ok())
+        .and_then(|version| version.parse().ok()).ok_or_else(|| corrupted("invalid RDB version"))?;
+    if !(1..=MAX_VERSION).contains(&version) {
+        return Err(corrupted(&format!("unsupported RDB version {}", version)));
+    }
+    let mut reader = Reader { buf: &data[MAGIC.len() + 4..], non_utf8: Cell::new(false) };
+    let loaded = load_records(&mut reader, db, shared)
+        .map_err(|e| corrupted(&format!("{} at offset {}", e, data.len() - reader.buf.len())))?;
+    let end = data.len() - reader.buf.len();
+    if version >= 5 {
+        let checksum = reader.u64_le()?;
+        // A zero checksum means the server that wrote the file had checksums disabled.
+        if checksum != 0 && crc64::update(0, &data[..end]) != checksum {
+            return Err(corrupted("wrong checksum"));
+        }
+        return Ok((loaded, end + 8));
+    }
+    Ok((loaded, end))
+}
+
+// Loads the records of an RDB file up to its EOF opcode, returning the amount of keys loaded.
+fn load_records(reader: &mut Reader, db: &mut Db, shared: &SharedState) -> io::Result<usize> {
+    let now = now_ms();
+    let mut loaded = 0;
+    let mut expire = None;
+    loop {
+        match reader.u8()? {
+            OP_AUX => {
+                reader.string()?;
+                reader.string()?;
+            },
+            OP_RESIZEDB => {
+                reader.len()?;
+                reader.len()?;
+            },
+            OP_SLOT_INFO => {
+                reader.len()?;
+                reader.len()?;
+                reader.len()?;
+            },
+            OP_SELECTDB => {
+                let index = reader.len()?;
+                if index != 0 {
+                    return Err(corrupted(&format!("keys of database {} can't be loaded, there is only database 0", index)));
+                }
+            },
+            OP_EXPIRETIME => expire = Some(u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as u64 * 1000),
+            OP_EXPIRETIME_MS => expire = Some(reader.u64_le()?),
+            OP_IDLE => {
+                reader.len()?;
+            },
+            OP_FREQ => {
+                reader.u8()?;
+            },
+            OP_FUNCTION2 => {
+                let code = reader.utf8()?;
+                crate::function_load(&code, true, shared).map_err(|e| corrupted(&e.to_string()))?;
+            },
+            OP_FUNCTION_PRE_GA | OP_MODULE_AUX => return Err(corrupted("modules and pre-release functions are unsupported")),
+            OP_EOF => break,
+            value_type => {
+                let key = reader.text()?;
+                let value = read_value(value_type, reader).map_err(|e| corrupted(&format!("key '{}': {}", key, e)))?;
+                let at = expire.take();
+                if reader.take_non_utf8() {
+                    logging::log(shared, "warning", format!("Skipping the key '{}' of the RDB file, it has strings that aren't UTF-8", key.escape_debug()));
+                    continue;
+                }
+                if at.is_some_and(|at| at <= now) {
+                    continue;
+                }
+                db.set(key.clone(), value);
+                if let Some(at) = at {
+                    db.set_expire(&key, at);
+                }
+                loaded += 1;
+            }
+        }
+    }
+    Ok(loaded)
+}
@@ -0,0 +1,1242 @@
+// Replication: a replica (set with REPLICAOF or `replicaof`) connects to its primary, gets the whole
+// keyspace with a full sync and then applies the writes the primary streams to it.
+//
+// On the primary, PSYNC (or SYNC) freezes the keyspace like BGSAVE does, and the snapshot (in
+// `snapshot-format`, never encrypted) is streamed right off the writer to the replica as an
+// `$EOF:<mark>` bulk, as it isn't stored anywhere. The writes made from the moment the keyspace was
+// frozen are journaled like for the append only file (see aof.rs) and streamed to the replica after
+// the snapshot, so every write is either in the snapshot or after it.
+//
+// On the replica, a task connects to the primary and handshakes (PING, AUTH with `masterauth`,
+// REPLCONF and PSYNC), loads the snapshot into an emptied keyspace, then runs the streamed writes as
+// a client of its own. Keys don't expire on their own in a replica, the primary streams their
+// deletion instead.
+//
+// The primary may be a genuine Redis server as well, to migrate from Redis without downtime: its
+// snapshot is an RDB file, and the writes it streams are rewritten into ones bast runs (see
+// Translation).
+//
+// The primary keeps the latest writes it streamed in a backlog of `repl-backlog-size` bytes, so a
+// replica whose link broke continues from its offset when it connects again (PSYNC <replid> <offset>
+// answered with +CONTINUE) rather than starting over with a full sync, as long as the writes it
+// missed are still in the backlog. Replicas keep a backlog of what they applied as well, and a
+// replica turned into a primary keeps its former replication ID as a second one, so other replicas
+// of the same primary can continue from it.
+//
+// A replica serves replicas of its own, with full syncs of its keyspace and the writes of its
+// primary streamed on exactly as they were received, so they all share the history (and offsets)
+// of the primary. Whenever the history of a replica changes (a full sync, or its primary continued
+// it under a new ID) its replicas are disconnected, and then start over or continue from where they
+// were.
+//
+// Replicas acknowledge the offset they applied up to with REPLCONF ACK every second, and right away
+// when the stream asks them to with REPLCONF GETACK. WAIT blocks until enough replicas acknowledged
+// the offset of the writes made before it.
+//
+// FAILOVER hands the primary role over to a replica without losing writes: the primary pauses
+// writes, waits for the replica to acknowledge everything it was sent, and then becomes a replica
+// of it, asking for the switch with PSYNC <replid> <offset> FAILOVER. The replica turns into a
+// primary when the replication ID is its own, and continues the history of the former primary.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+use crate::arg::Arg;
+use crate::clients::ClientInfo;
+use crate::db::now_ms;
+use crate::pubsub::ClientId;
+use crate::snapshot::{self, Purpose};
+use crate::storage::Upload;
+use crate::{acl, aof, lazyfree, logging, otel, process_command, uppercase_name, Client, RESPError, RESPValue, SharedState};
+
+// The length of replication IDs and of the marks ending the snapshot of a full sync.
+const ID_LEN: usize = 40;
+
+// The bytes of writes kept in the backlog. Set through the config.
+pub static BACKLOG_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+
+pub fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ID_LEN).map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap()).collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReplicaState {
+    // Waiting for the keyspace to be frozen for its full sync.
+    WaitBgsave,
+    SendBulk,
+    Online,
+}
+
+struct Replica {
+    id: ClientId,
+    ip: String,
+    listening_port: u16,
+    state: ReplicaState,
+    // The replication offset it acknowledged, or the one its sync started from before it did.
+    offset: u64,
+    last_ack: Instant,
+    // Set by PSYNC rather than SYNC, which gets the replication ID and offset first.
+    psync: bool,
+    // Understands a snapshot ending with a mark, rather than one sent with its length.
+    eof: bool,
+    // Where its full sync is sent, until the keyspace is frozen for it.
+    sync: Option<UnboundedSender<Option<Bytes>>>,
+    stream: UnboundedSender<Bytes>,
+}
+
+// What a replica told with REPLCONF before PSYNC, kept on its client.
+#[derive(Default)]
+pub struct ReplConf {
+    listening_port: u16,
+    ip_address: Option<String>,
+    eof: bool,
+}
+
+// Handed from PSYNC to the connection, which turns into the link to the replica.
+pub struct ReplicaLink {
+    id: ClientId,
+    // The full sync, then None once it was all sent.
+    sync: UnboundedReceiver<Option<Bytes>>,
+    stream: UnboundedReceiver<Bytes>,
+}
+
+// Where the link of a replica to its primary stands.
+#[derive(Clone, Copy, PartialEq)]
+enum LinkState {
+    Connecting,
+    Sync,
+    Connected,
+}
+
+struct State {
+    replid: String,
+    // The ID of the history the current one continues, and the offset until which they're the same.
+    replid2: Option<(String, u64)>,
+    // The amount of bytes of writes streamed to replicas, or applied from the primary.
+    offset: u64,
+    // The latest writes, ending at the offset. Created along with the first replica.
+    backlog: Option<VecDeque<u8>>,
+    replicas: Vec<Replica>,
+    // When the last replica went away, the backlog is dropped `repl-backlog-ttl` seconds later.
+    no_replicas_since: Instant,
+    // The snapshot of the full sync in progress, until the writer takes it.
+    sync_upload: Option<SyncUpload>,
+    // Set by REPLICAOF, None while this is a primary.
+    primary: Option<(String, u16)>,
+    link: LinkState,
+    last_io: Instant,
+    // The bytes a replica received from its primary (or were waiting in the socket) but hasn't
+    // applied yet, as of the last read.
+    lag: u64,
+    failover: Option<Failover>,
+}
+
+// A failover started by FAILOVER.
+struct Failover {
+    // The replica to fail over to, None for the first one that catches up.
+    target: Option<(String, u16)>,
+    // Fail over to the target once the deadline passed, even if it didn't catch up.
+    force: bool,
+    deadline: Option<Instant>,
+    // Set once this became a replica of the target, until it accepts the PSYNC.
+    in_progress: bool,
+}
+
+// Writes stay paused until the failover ends, which lifts the pause.
+const FAILOVER_PAUSE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+impl State {
+    fn append(&mut self, buf: &[u8]) {
+        self.offset += buf.len() as u64;
+        if let Some(backlog) = &mut self.backlog {
+            backlog.extend(buf);
+            let excess = backlog.len().saturating_sub(BACKLOG_SIZE.load(Ordering::Relaxed));
+            backlog.drain(..excess);
+        }
+    }
+
+    fn stream(&mut self, buf: &[u8]) {
+        self.append(buf);
+        let buf = Bytes::copy_from_slice(buf);
+        for replica in self.replicas.iter().filter(|replica| replica.state != ReplicaState::WaitBgsave) {
+            // A replica that's gone is removed by its connection.
+            let _ = replica.stream.send(buf.clone());
+        }
+    }
+
+    // Disconnects the replicas, when they have to learn about a history that changed.
+    fn drop_replicas(&mut self) {
+        self.replicas.clear();
+        self.sync_upload = None;
+        self.no_replicas_since = Instant::now();
+    }
+
+    // The writes a replica that has the history of `replid` up to `offset` is missing, None when
+    // they aren't in the backlog anymore (or never were).
+    fn missing(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let known = replid == self.replid
+            || self.replid2.as_ref().is_some_and(|(replid2, until)| replid == replid2 && offset <= *until);
+        let backlog = self.backlog.as_ref().filter(|_| known)?;
+        let start = self.offset - backlog.len() as u64;
+        if offset < start || offset > self.offset {
+            return None;
+        }
+        Some(backlog.range((offset - start) as usize..).copied().collect())
+    }
+}
+
+pub struct Replication {
+    state: Mutex<State>,
+    // Whether writes are streamed to replicas, checked on every write.
+    streaming: AtomicBool,
+    // Notified when REPLICAOF changes the primary.
+    primary_changed: Notify,
+    // Notified when a replica acknowledged an offset.
+    acked: Notify,
+    // Notified when a failover starts or is aborted.
+    failover_changed: Notify,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State {
+                replid: random_id(),
+                replid2: None,
+                offset: 0,
+                backlog: None,
+                replicas: vec![],
+                no_replicas_since: Instant::now(),
+                sync_upload: None,
+                primary: None,
+                link: LinkState::Connecting,
+                last_io: Instant::now(),
+                lag: 0,
+                failover: None,
+            }),
+            streaming: AtomicBool::new(false),
+            primary_changed: Notify::new(),
+            acked: Notify::new(),
+            failover_changed: Notify::new(),
+        }
+    }
+}
+
+impl Replication {
+    pub fn streaming(&self) -> bool {
+        self.streaming.load(Ordering::Relaxed)
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().primary.is_some()
+    }
+
+    fn primary(&self) -> Option<(String, u16)> {
+        self.state.lock().unwrap().primary.clone()
+    }
+
+    // Streams writes (in the RESP protocol) to the backlog and the replicas whose full sync started.
+    pub fn feed(&self, buf: &[u8]) {
+        if !self.streaming() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        // The writes of a replica are streamed on as its primary sent them, see `applied`.
+        if state.primary.is_some() {
+            return;
+        }
+        state.stream(buf);
+    }
+
+    // Called when the keyspace was frozen for a full sync, along with which the replicas waiting
+    // for one start getting the writes.
+    pub fn sync_started(&self) {
+        let mut state = self.state.lock().unwrap();
+        let State { replid, offset, replicas, .. } = &mut *state;
+        let mut upload = SyncUpload { mark: random_id(), senders: vec![], whole: vec![], buf: vec![] };
+        for replica in replicas.iter_mut().filter(|replica| replica.state == ReplicaState::WaitBgsave) {
+            replica.state = ReplicaState::SendBulk;
+            replica.offset = *offset;
+            let sender = replica.sync.take().unwrap();
+            if replica.psync {
+                let _ = sender.send(Some(Bytes::from(format!("+FULLRESYNC {} {}\r\n", replid, offset))));
+            }
+            if replica.eof {
+                let _ = sender.send(Some(Bytes::from(format!("$EOF:{}\r\n", upload.mark))));
+                upload.senders.push(sender);
+            } else {
+                upload.whole.push(sender);
+            }
+        }
+        state.sync_upload = Some(upload);
+        self.start_backlog(&mut state);
+    }
+
+    fn start_backlog(&self, state: &mut State) {
+        if state.backlog.is_none() {
+            state.backlog = Some(VecDeque::new());
+            self.streaming.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Where the snapshot of the full sync that started is written to.
+    pub fn sync_upload(&self) -> Box<dyn Upload> {
+        Box::new(self.state.lock().unwrap().sync_upload.take().unwrap())
+    }
+
+    fn replica_online(&self, id: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.state = ReplicaState::Online;
+        }
+    }
+
+    fn replica_acked(&self, id: ClientId, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.offset = offset;
+            replica.last_ack = Instant::now();
+        }
+        self.acked.notify_waiters();
+    }
+
+    // The amount of replicas that acknowledged the offset.
+    fn acknowledged(&self, offset: u64) -> usize {
+        self.state.lock().unwrap().replicas.iter().filter(|replica| replica.offset >= offset).count()
+    }
+
+    fn replica_gone(&self, id: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.retain(|replica| replica.id != id);
+        if state.replicas.is_empty() {
+            state.no_replicas_since = Instant::now();
+        }
+    }
+
+    // Whether this became a replica of the target of a failover, which it asks to take over.
+    fn failing_over(&self) -> bool {
+        self.state.lock().unwrap().failover.as_ref().is_some_and(|failover| failover.in_progress)
+    }
+
+    // Ends the failover, turning this back into a primary if it already became a replica.
+    fn abort_failover(&self, reason: &str, shared: &SharedState) {
+        let Some(failover) = self.state.lock().unwrap().failover.take() else {
+            return;
+        };
+        if failover.in_progress {
+            self.set_primary(None, shared);
+        }
+        shared.clients.unpause();
+        self.failover_changed.notify_one();
+        logging::log(shared, "warning", format!("FAILOVER aborted: {}", reason));
+    }
+
+    // Called once the target of the failover accepted to take over.
+    fn failover_done(&self, shared: &SharedState) {
+        if self.state.lock().unwrap().failover.take().is_some() {
+            shared.clients.unpause();
+            logging::log(shared, "notice", "Failover target accepted the PSYNC FAILOVER, this is a replica of it now");
+        }
+    }
+
+    // Sets the primary to replicate, None turning this into a primary. The replicas of this server
+    // are disconnected, as it can't serve them anymore. False when nothing changed.
+    pub fn set_primary(&self, primary: Option<(String, u16)>, shared: &SharedState) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.primary == primary {
+            return false;
+        }
+        if primary.is_none() {
+            // The history of the dataset continues on its own from here.
+            let replid = std::mem::replace(&mut state.replid, random_id());
+            state.replid2 = Some((replid, state.offset));
+        }
+        state.primary = primary.clone();
+        state.link = LinkState::Connecting;
+        state.drop_replicas();
+        drop(state);
+        shared.config.store("replicaof", primary.map_or_else(String::new, |(host, port)| format!("{} {}", host, port)));
+        self.primary_changed.notify_one();
+        true
+    }
+
+    fn set_link(&self, link: LinkState) {
+        let mut state = self.state.lock().unwrap();
+        state.link = link;
+        state.last_io = Instant::now();
+    }
+
+    // Called when data arrived from the primary.
+    fn primary_io(&self) {
+        self.state.lock().unwrap().last_io = Instant::now();
+    }
+
+    // How far behind its primary this replica is in bytes, None when it isn't in sync with one:
+    // while connecting or syncing, or when the primary was silent for `repl-timeout`.
+    pub fn lag(&self, shared: &SharedState) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        let timeout = Duration::from_secs(shared.config.get_int("repl-timeout") as u64);
+        let in_sync = state.primary.is_some() && state.link == LinkState::Connected && state.last_io.elapsed() < timeout;
+        in_sync.then_some(state.lag)
+    }
+
+    fn set_lag(&self, lag: u64) {
+        self.state.lock().unwrap().lag = lag;
+    }
+
+    // Called when writes from the primary were applied, with the bytes they were sent as, which are
+    // streamed on to the replicas of this one.
+    fn applied(&self, buf: &[u8]) {
+        self.state.lock().unwrap().stream(buf);
+    }
+
+    // The history of the dataset, which PSYNC asks the primary to continue.
+    fn history(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    }
+
+    // Called once the snapshot of a full sync was loaded, from which the history of the primary is
+    // followed.
+    fn synced(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = None;
+        state.offset = offset;
+        state.backlog = None;
+        state.drop_replicas();
+        self.start_backlog(&mut state);
+    }
+
+    // Called when the primary continued the history from where it was, which it may have under a
+    // new ID (when it was a replica turned into a primary).
+    fn continued(&self, replid: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replid) = replid.filter(|replid| *replid != state.replid) {
+            let previous = std::mem::replace(&mut state.replid, replid.to_owned());
+            state.replid2 = Some((previous, state.offset));
+            state.drop_replicas();
+        }
+        self.start_backlog(&mut state);
+    }
+}
+
+// Streams the snapshot of a full sync to the replicas as it's written. Replicas that don't understand
+// a snapshot ending with a mark get it as a whole once it's complete.
+struct SyncUpload {
+    mark: String,
+    senders: Vec<UnboundedSender<Option<Bytes>>>,
+    whole: Vec<UnboundedSender<Option<Bytes>>>,
+    buf: Vec<u8>,
+}
+
+impl Write for SyncUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = Bytes::copy_from_slice(buf);
+        for sender in &self.senders {
+            let _ = sender.send(Some(chunk.clone()));
+        }
+        if !self.whole.is_empty() {
+            self.buf.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Upload for SyncUpload {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let mark = Bytes::from(self.mark);
+        for sender in &self.senders {
+            let _ = sender.send(Some(mark.clone()));
+            let _ = sender.send(None);
+        }
+        let whole = Bytes::from([format!("${}\r\n", self.buf.len()).into_bytes(), self.buf].concat());
+        for sender in &self.whole {
+            let _ = sender.send(Some(whole.clone()));
+            let _ = sender.send(None);
+        }
+        Ok(())
+    }
+}
+
+// REPLCONF <option> <value> [<option> <value> ...]
+pub fn replconf(command: &[Arg], replconf: &mut ReplConf) -> Result<RESPValue, RESPError> {
+    if command.len().is_multiple_of(2) {
+        return Err(RESPError::SyntaxError);
+    }
+    for pair in command[1..].chunks(2) {
+        match pair[0].to_ascii_lowercase().as_str() {
+            "listening-port" => replconf.listening_port = pair[1].parse().map_err(|_| RESPError::NotAnInteger)?,
+            "ip-address" => replconf.ip_address = Some(pair[1].to_string()),
+            "capa" => replconf.eof |= pair[1].eq_ignore_ascii_case("eof"),
+            // Only meaningful on the link of a replica, see `serve_replica`.
+            "ack" => {},
+            _ => return Err(RESPError::UnrecognizedReplConfOption(pair[0].to_string()))
+        }
+    }
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// PSYNC replicationid offset / SYNC
+// Continues the replica from its offset when the backlog has the writes it missed, and starts a
+// full sync of it otherwise. The connection then only streams to the replica.
+pub fn psync(command: &[Arg], client: &mut Client, shared: &SharedState) -> Result<(), RESPError> {
+    // The former primary asks to take over its history, see `coordinate_failover`.
+    if command.get(3).is_some_and(|arg| arg.eq_ignore_ascii_case("FAILOVER")) {
+        if !shared.replication.is_replica() || shared.replication.history().0 != command[1] {
+            return Err(RESPError::InvalidFailover("PSYNC FAILOVER replid must match my replid."));
+        }
+        logging::log(shared, "notice", format!("Failover request received for replid {}.", command[1]));
+        shared.replication.set_primary(None, shared);
+    }
+    // A replica can only serve the history of its primary once it has it.
+    if shared.replication.is_replica() && shared.replication.state.lock().unwrap().link != LinkState::Connected {
+        return Err(RESPError::NoPrimaryLink);
+    }
+    let ip = client.replconf.ip_address.clone()
+        .unwrap_or_else(|| client.info.addr.rsplit_once(':').map_or_else(String::new, |(ip, _)| ip.to_owned()));
+    let name = format!("{}:{}", ip, client.replconf.listening_port);
+    logging::log(shared, "notice", format!("Replica {} asks for synchronization", name));
+
+    let (sync_sender, sync) = mpsc::unbounded_channel();
+    let (stream_sender, stream) = mpsc::unbounded_channel();
+    let mut replica = Replica {
+        id: client.id,
+        ip,
+        listening_port: client.replconf.listening_port,
+        state: ReplicaState::WaitBgsave,
+        offset: 0,
+        last_ack: Instant::now(),
+        psync: command[0] == "PSYNC",
+        eof: client.replconf.eof,
+        sync: Some(sync_sender),
+        stream: stream_sender,
+    };
+    client.replica_link = Some(ReplicaLink { id: client.id, sync, stream });
+
+    let mut state = shared.replication.state.lock().unwrap();
+    // The offset asked for is of the next byte the replica wants.
+    let missing = match &command[1..] {
+        [replid, offset, ..] => offset.parse::<u64>().ok().and_then(|offset| state.missing(replid, offset.checked_sub(1)?)),
+        _ => None
+    };
+    if let Some(missing) = missing {
+        replica.state = ReplicaState::SendBulk;
+        replica.offset = state.offset - missing.len() as u64;
+        let sync = replica.sync.take().unwrap();
+        let _ = sync.send(Some(Bytes::from(format!("+CONTINUE {}\r\n", state.replid))));
+        logging::log(shared, "notice", format!("Partial resynchronization request from {} accepted. Sending {} bytes of backlog starting from offset {}.",
+            name, missing.len(), replica.offset));
+        let _ = sync.send(Some(Bytes::from(missing)));
+        let _ = sync.send(None);
+        state.replicas.push(replica);
+        return Ok(());
+    }
+    if replica.psync && command[1] != "?" {
+        logging::log(shared, "notice", format!("Partial resynchronization not accepted from {}, the backlog doesn't have the writes it missed", name));
+    }
+    state.replicas.push(replica);
+    drop(state);
+
+    // Otherwise the saver starts it once the background save in progress is done.
+    if snapshot::start_background_save(shared, Purpose::FullSync) {
+        logging::log(shared, "notice", "Starting BGSAVE for SYNC with target: replicas sockets");
+    }
+    Ok(())
+}
+
+// Whether replicas wait for a full sync to start.
+pub fn sync_due(shared: &SharedState) -> bool {
+    shared.replication.state.lock().unwrap().replicas.iter().any(|replica| replica.state == ReplicaState::WaitBgsave)
+}
+
+// Handles what a replica sent, which are only acknowledgements.
+fn read_acks(read: usize, input: &mut BytesMut, id: ClientId, shared: &SharedState) -> io::Result<()> {
+    if read == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut at = 0;
+    while let Some(command) = aof::parse_command(input, &mut at)? {
+        if let [replconf, ack, offset, ..] = &command[..] {
+            if replconf.eq_ignore_ascii_case("REPLCONF") && ack.eq_ignore_ascii_case("ACK") {
+                if let Ok(offset) = offset.parse() {
+                    shared.replication.replica_acked(id, offset);
+                }
+            }
+        }
+        input.advance(at);
+        at = 0;
+    }
+    Ok(())
+}
+
+// Sends the full sync and then the writes to a replica, over the connection that asked for them.
+pub async fn serve_replica(mut socket: impl AsyncRead + AsyncWrite + Unpin, mut link: ReplicaLink, info: &ClientInfo, shared: &SharedState) {
+    let result: io::Result<()> = async {
+        let mut input = BytesMut::new();
+        loop {
+            tokio::select! {
+                chunk = link.sync.recv() => match chunk {
+                    Some(Some(chunk)) => socket.write_all(&chunk).await?,
+                    Some(None) => break,
+                    None => return Err(io::Error::other("the full sync failed"))
+                },
+                read = socket.read_buf(&mut input) => read_acks(read?, &mut input, link.id, shared)?,
+                _ = info.killed.notified() => return Ok(()),
+            }
+        }
+        shared.replication.replica_online(link.id);
+        logging::log(shared, "notice", format!("Synchronization with replica {} succeeded", info.addr));
+        loop {
+            tokio::select! {
+                chunk = link.stream.recv() => match chunk {
+                    Some(chunk) => socket.write_all(&chunk).await?,
+                    // Dropped by REPLICAOF.
+                    None => return Ok(())
+                },
+                read = socket.read_buf(&mut input) => read_acks(read?, &mut input, link.id, shared)?,
+                _ = info.killed.notified() => return Ok(()),
+            }
+        }
+    }.await;
+    shared.replication.replica_gone(link.id);
+    match result {
+        Ok(()) => logging::log(shared, "notice", format!("Connection with replica {} closed", info.addr)),
+        Err(e) => logging::log(shared, "warning", format!("Connection with replica {} lost: {}", info.addr, e))
+    }
+}
+
+// Keeps the replicas waiting for a full sync from timing out with newlines, and drops the backlog of
+// a primary left without replicas for `repl-backlog-ttl` seconds. Run by the cron every second.
+pub fn cron(shared: &Arc<SharedState>) {
+    let mut state = shared.replication.state.lock().unwrap();
+    for replica in state.replicas.iter() {
+        if let Some(sync) = &replica.sync {
+            let _ = sync.send(Some(Bytes::from_static(b"\n")));
+        }
+    }
+    let ttl = shared.config.get_int("repl-backlog-ttl") as u64;
+    if state.primary.is_none() && state.replicas.is_empty() && ttl > 0 && state.no_replicas_since.elapsed() >= Duration::from_secs(ttl) {
+        state.backlog = None;
+        shared.replication.streaming.store(false, Ordering::Relaxed);
+    }
+}
+
+// Pings the replicas getting writes, run by the cron every `repl-ping-replica-period` seconds.
+pub fn ping_replicas(shared: &Arc<SharedState>) {
+    // A failover waits for the replicas to reach an offset that pings would move.
+    let failover = shared.replication.state.lock().unwrap().failover.is_some();
+    if shared.replication.streaming() && !failover {
+        let mut ping = vec![];
+        aof::encode(&[Arg::from("PING")], &mut ping);
+        shared.replication.feed(&ping);
+    }
+}
+
+// REPLICAOF host port / REPLICAOF NO ONE
+pub fn replicaof(command: &[Arg], client: &Client, shared: &SharedState) -> Result<RESPValue, RESPError> {
+    if shared.replication.state.lock().unwrap().failover.is_some() {
+        return Err(RESPError::InvalidFailover("REPLICAOF not allowed while failing over."));
+    }
+    let request = format!("user request from 'id={} addr={}'", client.id, client.info.addr);
+    if command[1].eq_ignore_ascii_case("NO") && command[2].eq_ignore_ascii_case("ONE") {
+        if shared.replication.set_primary(None, shared) {
+            logging::log(shared, "notice", format!("MASTER MODE enabled ({})", request));
+        }
+        return Ok(RESPValue::SimpleString(String::from("OK")));
+    }
+    let port = command[2].parse::<u16>().map_err(|_| RESPError::InvalidMasterPort)?;
+    if !shared.replication.set_primary(Some((command[1].to_string(), port)), shared) {
+        return Ok(RESPValue::SimpleString(String::from("OK Already connected to specified master")));
+    }
+    logging::log(shared, "notice", format!("REPLICAOF {}:{} enabled ({})", command[1], port, request));
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// FAILOVER [TO host port [FORCE]] [ABORT] [TIMEOUT milliseconds]
+// Starts a failover to the given replica (or the first one to catch up), carried out by
+// `coordinate_failover`.
+pub fn failover(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let mut target = None;
+    let mut force = false;
+    let mut abort = false;
+    let mut timeout = None;
+    let mut i = 1;
+    while i < command.len() {
+        match command[i].to_ascii_uppercase().as_str() {
+            "TO" if target.is_none() && i + 2 < command.len() => {
+                let port = command[i + 2].parse::<u16>().map_err(|_| RESPError::InvalidMasterPort)?;
+                target = Some((command[i + 1].to_string(), port));
+                i += 2;
+            },
+            "FORCE" if !force => force = true,
+            "ABORT" if !abort => abort = true,
+            "TIMEOUT" if timeout.is_none() && i + 1 < command.len() => {
+                let milliseconds = command[i + 1].parse::<i64>().map_err(|_| RESPError::NotAnInteger)?;
+                if milliseconds <= 0 {
+                    return Err(RESPError::InvalidFailover("FAILOVER timeout must be greater than 0"));
+                }
+                timeout = Some(Duration::from_millis(milliseconds as u64));
+                i += 1;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+        i += 1;
+    }
+
+    if abort {
+        if target.is_some() || force || timeout.is_some() {
+            return Err(RESPError::SyntaxError);
+        }
+        if shared.replication.state.lock().unwrap().failover.is_none() {
+            return Err(RESPError::InvalidFailover("No failover in progress."));
+        }
+        shared.replication.abort_failover("Failover manually aborted", shared);
+        return Ok(RESPValue::SimpleString(String::from("OK")));
+    }
+    if force && (target.is_none() || timeout.is_none()) {
+        return Err(RESPError::InvalidFailover("FAILOVER with force option requires both a timeout and target HOST and IP."));
+    }
+
+    let mut state = shared.replication.state.lock().unwrap();
+    if state.primary.is_some() {
+        return Err(RESPError::InvalidFailover("FAILOVER is not valid when server is a replica."));
+    }
+    if state.replicas.is_empty() {
+        return Err(RESPError::InvalidFailover("FAILOVER requires connected replicas."));
+    }
+    if state.failover.is_some() {
+        return Err(RESPError::InvalidFailover("FAILOVER already in progress."));
+    }
+    if let Some((host, port)) = &target {
+        let replica = state.replicas.iter().find(|replica| replica.ip == *host && replica.listening_port == *port)
+            .ok_or(RESPError::InvalidFailover("FAILOVER target HOST and PORT is not a replica."))?;
+        if replica.state != ReplicaState::Online {
+            return Err(RESPError::InvalidFailover("FAILOVER target replica is not online."));
+        }
+    }
+    state.failover = Some(Failover { target, force, deadline: timeout.map(|timeout| Instant::now() + timeout), in_progress: false });
+    drop(state);
+
+    logging::log(shared, "notice", "FAILOVER requested, pausing writes and waiting for a replica to catch up");
+    shared.clients.pause(FAILOVER_PAUSE, false);
+    // Asking for acknowledgements right away rather than waiting for the next ones.
+    let mut getack = vec![];
+    aof::encode(&[Arg::from("REPLCONF"), Arg::from("GETACK"), Arg::from("*")], &mut getack);
+    shared.replication.feed(&getack);
+    shared.replication.failover_changed.notify_one();
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// Waits for the replica of a failover to catch up, then makes this a replica of it. The failover
+// is aborted when its deadline passed first, unless it's forced to the target.
+pub async fn coordinate_failover(shared: Arc<SharedState>) {
+    loop {
+        let acked = shared.replication.acked.notified();
+        let changed = shared.replication.failover_changed.notified();
+        tokio::pin!(acked, changed);
+        acked.as_mut().enable();
+        changed.as_mut().enable();
+
+        // The target to fail over to now, and whether the deadline passed.
+        let step = {
+            let mut state = shared.replication.state.lock().unwrap();
+            let offset = state.offset;
+            let State { failover, replicas, .. } = &mut *state;
+            failover.as_mut().filter(|failover| !failover.in_progress).map(|failover| {
+                let caught_up = replicas.iter()
+                    .filter(|replica| replica.state == ReplicaState::Online && replica.offset >= offset)
+                    .map(|replica| (replica.ip.clone(), replica.listening_port))
+                    .find(|replica| failover.target.as_ref().is_none_or(|target| target == replica));
+                let expired = failover.deadline.is_some_and(|deadline| deadline <= Instant::now());
+                let target = match caught_up {
+                    Some(target) => Some(target),
+                    None if expired && failover.force => failover.target.clone(),
+                    None => None
+                };
+                failover.in_progress = target.is_some();
+                (target, expired)
+            })
+        };
+        match step {
+            None => {
+                changed.await;
+                continue;
+            },
+            Some((Some((host, port)), _)) => {
+                logging::log(&shared, "notice", format!("Failover target {}:{} is synced, failing over to it", host, port));
+                shared.replication.set_primary(Some((host, port)), &shared);
+                continue;
+            },
+            Some((None, true)) => {
+                shared.replication.abort_failover("Replica never caught up before timeout", &shared);
+                continue;
+            },
+            Some((None, false)) => {}
+        }
+        tokio::select! {
+            _ = acked => {},
+            _ = changed => {},
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {},
+        }
+    }
+}
+
+// Parses the `replicaof` parameter, `<host> <port>` or nothing.
+pub fn parse_replicaof(value: &str) -> Option<String> {
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Some(String::new()),
+        [host, port] => port.parse::<u16>().ok().map(|port| format!("{} {}", host, port)),
+        _ => None
+    }
+}
+
+// Applies the `replicaof` parameter on startup.
+pub fn set_replicaof(shared: &SharedState, value: &str) -> Result<(), String> {
+    let primary = value.split_once(' ').map(|(host, port)| (host.to_owned(), port.parse().unwrap()));
+    shared.replication.set_primary(primary, shared);
+    Ok(())
+}
+
+// The connection of a replica to its primary.
+struct PrimaryLink {
+    socket: TcpStream,
+    buf: BytesMut,
+    timeout: Duration,
+    last_read: tokio::time::Instant,
+}
+
+impl PrimaryLink {
+    // Reads more from the primary, failing when it was silent for `repl-timeout`.
+    async fn fill(&mut self, shared: &SharedState) -> io::Result<()> {
+        // Timed from the last read rather than from the call, as acknowledging cancels the read.
+        let read = tokio::time::timeout_at(self.last_read + self.timeout, self.socket.read_buf(&mut self.buf)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout on the link with the MASTER"))??;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the MASTER closed the connection"));
+        }
+        self.last_read = tokio::time::Instant::now();
+        shared.replication.primary_io();
+        Ok(())
+    }
+
+    // The bytes waiting in the socket, yet to be read.
+    fn unread(&self) -> usize {
+        let mut unread: libc::c_int = 0;
+        // Safety: FIONREAD only writes the amount to the given int.
+        match unsafe { libc::ioctl(self.socket.as_raw_fd(), libc::FIONREAD, &mut unread) } {
+            -1 => 0,
+            _ => unread as usize
+        }
+    }
+
+    // Reads a reply line, skipping the newlines the primary keeps the link alive with.
+    async fn read_line(&mut self, shared: &SharedState) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line = self.buf.split_to(end + 1);
+                let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+                if !line.is_empty() {
+                    return Ok(line);
+                }
+                continue;
+            }
+            self.fill(shared).await?;
+        }
+    }
+
+    async fn command(&mut self, command: &[&str], shared: &SharedState) -> io::Result<String> {
+        let command: Vec<Arg> = command.iter().copied().map(Arg::from).collect();
+        let mut buf = vec![];
+        aof::encode(&command, &mut buf);
+        self.socket.write_all(&buf).await?;
+        self.read_line(shared).await
+    }
+
+    // Acknowledges the offset applied up to.
+    async fn ack(&mut self, shared: &SharedState) -> io::Result<()> {
+        let offset = shared.replication.history().1;
+        let mut buf = vec![];
+        aof::encode(&[Arg::from("REPLCONF"), Arg::from("ACK"), Arg::from(offset.to_string())], &mut buf);
+        self.socket.write_all(&buf).await
+    }
+
+    // Reads the snapshot of the full sync, sent either with its length or ending with a mark.
+    async fn read_snapshot(&mut self, shared: &SharedState) -> io::Result<Vec<u8>> {
+        let header = self.read_line(shared).await?;
+        if let Some(mark) = header.strip_prefix("$EOF:") {
+            let mark = mark.as_bytes();
+            let mut searched = 0;
+            loop {
+                if let Some(at) = self.buf[searched..].windows(mark.len()).position(|window| window == mark) {
+                    let snapshot = self.buf.split_to(searched + at).to_vec();
+                    self.buf.advance(mark.len());
+                    return Ok(snapshot);
+                }
+                searched = self.buf.len().saturating_sub(mark.len());
+                self.fill(shared).await?;
+            }
+        }
+        let len = header.strip_prefix('$').and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| io::Error::other(format!("bad protocol from MASTER, the first byte is not '$' but: {}", header)))?;
+        while self.buf.len() < len {
+            self.fill(shared).await?;
+        }
+        Ok(self.buf.split_to(len).to_vec())
+    }
+}
+
+// Rewrites the writes a genuine Redis primary streams into ones bast runs: Redis streams SELECT
+// (bast only has database 0, so the writes to other databases are skipped), SET with expiry options
+// (SET <key> <value> PXAT <ms> since Redis 7) and the SETEX of older versions, and commands bast
+// doesn't have are dropped rather than failing the transactions they're in.
+#[derive(Default)]
+struct Translation {
+    db: u64,
+    // Commands dropped so far, warned about once.
+    dropped: HashSet<String>,
+}
+
+impl Translation {
+    fn translate(&mut self, mut command: Vec<Arg>, shared: &SharedState) -> Vec<Vec<Arg>> {
+        uppercase_name(&mut command);
+        if command[0] == "SELECT" {
+            let db = command.get(1).and_then(|db| db.parse().ok()).unwrap_or(0);
+            if db != 0 && db != self.db {
+                logging::log(shared, "warning", format!("Skipping the writes of database {} the MASTER streams, only database 0 is replicated", db));
+            }
+            self.db = db;
+            return vec![];
+        }
+        if self.db != 0 {
+            return vec![];
+        }
+
+        let now = now_ms();
+        let expire_at = match (command[0].as_str(), command.len()) {
+            ("SETEX" | "PSETEX", 4) => {
+                let Ok(ttl) = command[2].parse::<u64>() else {
+                    return vec![command];
+                };
+                let ms = if command[0] == "SETEX" { ttl * 1000 } else { ttl };
+                command = vec![Arg::from("SET"), command[1].clone(), command[3].clone()];
+                Some(now + ms)
+            },
+            ("SET", 4..) => {
+                let mut expire_at = None;
+                let mut options = command.split_off(3).into_iter();
+                while let Some(option) = options.next() {
+                    let option = option.to_ascii_uppercase();
+                    let mut value = || options.next().and_then(|value| value.parse::<u64>().ok());
+                    expire_at = match option.as_str() {
+                        "EX" => value().map(|seconds| now + seconds * 1000),
+                        "PX" => value().map(|ms| now + ms),
+                        "EXAT" => value().map(|seconds| seconds * 1000),
+                        "PXAT" => value(),
+                        "KEEPTTL" => shared.db.lock().expire_time(&command[1]),
+                        // NX, XX and GET were already checked by the primary.
+                        _ => expire_at
+                    };
+                }
+                expire_at
+            },
+            _ => None
+        };
+        if crate::lookup_command(&command[0], shared).is_none() {
+            if self.dropped.insert(command[0].to_string()) {
+                logging::log(shared, "warning", format!("Dropping the {} writes the MASTER streams, the command is unsupported", command[0]));
+            }
+            return vec![];
+        }
+        match expire_at {
+            Some(at) => {
+                let key = command[1].clone();
+                vec![command, vec![Arg::from("PEXPIREAT"), key, Arg::from(at.to_string())]]
+            },
+            None => vec![command]
+        }
+    }
+}
+
+fn unexpected_reply(to: &str, reply: &str) -> io::Error {
+    io::Error::other(format!("Error reply to {} from master: '{}'", to, reply))
+}
+
+// Syncs with the primary and applies the writes it streams, until the link breaks.
+async fn sync_with_primary(host: &str, port: u16, shared: &SharedState) -> io::Result<()> {
+    shared.replication.set_link(LinkState::Connecting);
+    logging::log(shared, "notice", format!("Connecting to MASTER {}:{}", host, port));
+    let timeout = Duration::from_secs(shared.config.get_int("repl-timeout") as u64);
+    let socket = tokio::time::timeout(timeout, TcpStream::connect((host, port))).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout connecting to the MASTER"))??;
+    let mut link = PrimaryLink { socket, buf: BytesMut::new(), timeout, last_read: tokio::time::Instant::now() };
+    logging::log(shared, "notice", "MASTER <-> REPLICA sync started");
+
+    // Not authenticated yet is fine, AUTH is next.
+    let reply = link.command(&["PING"], shared).await?;
+    if reply.starts_with('-') && !reply.starts_with("-NOAUTH") && !reply.starts_with("-NOPERM") {
+        return Err(unexpected_reply("PING", &reply));
+    }
+    let password = shared.config.get("masterauth");
+    if !password.is_empty() {
+        let user = shared.config.get("masteruser");
+        let reply = match user.as_str() {
+            "" => link.command(&["AUTH", &password], shared).await?,
+            user => link.command(&["AUTH", user, &password], shared).await?
+        };
+        if reply.starts_with('-') {
+            return Err(io::Error::other(format!("Unable to AUTH to MASTER: {}", reply)));
+        }
+    }
+    // Older primaries may not know these, and sync regardless.
+    let listening_port = shared.config.get("port");
+    link.command(&["REPLCONF", "listening-port", &listening_port], shared).await?;
+    link.command(&["REPLCONF", "capa", "eof", "capa", "psync2"], shared).await?;
+
+    // Asking to continue from the history of the dataset, in case this was a replica of it before.
+    let (replid, offset) = shared.replication.history();
+    let offset = (offset + 1).to_string();
+    let reply = match shared.replication.failing_over() {
+        true => link.command(&["PSYNC", &replid, &offset, "FAILOVER"], shared).await?,
+        false => link.command(&["PSYNC", &replid, &offset], shared).await?
+    };
+    match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset.parse::<u64>().map_err(|_| unexpected_reply("PSYNC", &reply))?;
+            logging::log(shared, "notice", format!("Full resync from master: {}:{}", replid, offset));
+            let mut span = otel::start(shared, "REPLICA SYNC");
+            otel::set(&mut span, "server.address", host);
+            otel::set(&mut span, "server.port", port);
+            let result = full_sync(&mut link, shared).await;
+            otel::end(span, result.as_ref().err(), shared);
+            result?;
+            shared.replication.synced(replid.to_owned(), offset);
+            logging::log(shared, "notice", "MASTER <-> REPLICA sync: Finished with success");
+        },
+        ["+CONTINUE", ref replid @ ..] if replid.len() <= 1 => {
+            shared.replication.continued(replid.first().copied());
+            logging::log(shared, "notice", "MASTER <-> REPLICA sync: Master accepted a Partial Resynchronization.");
+        },
+        _ => return Err(unexpected_reply("PSYNC", &reply))
+    }
+    shared.replication.set_link(LinkState::Connected);
+    shared.replication.failover_done(shared);
+
+    let info = Arc::new(ClientInfo::new(0, format!("{}:{}", host, port), String::new(), acl::DEFAULT_USER));
+    let mut client = Client::new(info, mpsc::unbounded_channel().0, true);
+    client.from_primary = true;
+    // The offset only counts whole transactions, so a replica never continues from the middle of one.
+    let mut parsed = 0;
+    let mut translation = Translation::default();
+    let mut acks = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let mut getack = false;
+        loop {
+            // A command cut short is parsed again once the rest of it arrived.
+            let mut end = parsed;
+            let Some(command) = aof::parse_command(&link.buf, &mut end)? else {
+                break;
+            };
+            parsed = end;
+            if command.len() == 3 && command[0].eq_ignore_ascii_case("REPLCONF") && command[1].eq_ignore_ascii_case("GETACK") {
+                getack = true;
+            } else {
+                for command in translation.translate(command, shared) {
+                    // Failing is part of what the command did on the primary as well.
+                    let _ = process_command(command, &mut client, shared);
+                }
+            }
+            if client.multi.is_none() {
+                shared.replication.applied(&link.buf.split_to(parsed));
+                parsed = 0;
+            }
+        }
+        if getack {
+            link.ack(shared).await?;
+        }
+        shared.replication.set_lag((link.buf.len() + link.unread()) as u64);
+        tokio::select! {
+            result = link.fill(shared) => result?,
+            _ = acks.tick() => link.ack(shared).await?,
+        }
+    }
+}
+
+// Replaces the keyspace with the snapshot the primary sends for a full sync.
+async fn full_sync(link: &mut PrimaryLink, shared: &SharedState) -> io::Result<()> {
+    shared.replication.set_link(LinkState::Sync);
+    let data = link.read_snapshot(shared).await?;
+    logging::log(shared, "notice", format!("MASTER <-> REPLICA sync: received {} bytes from master", data.len()));
+
+    logging::log(shared, "notice", "MASTER <-> REPLICA sync: Flushing old data");
+    let flushed = shared.db.lock().flush();
+    lazyfree::free_db(flushed, true, shared);
+    shared.libraries.lock().unwrap().clear();
+    logging::log(shared, "notice", "MASTER <-> REPLICA sync: Loading DB in memory");
+    if !snapshot::is_snapshot(&data) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the MASTER sent an unknown snapshot format"));
+    }
+    snapshot::load_from(&data, shared)?;
+    shared.snapshots.loaded(shared);
+    // The append only file has to start over from the loaded keyspace.
+    if shared.config.get_bool("appendonly") && !shared.aof.rewriting() {
+        let _ = aof::bgrewriteaof(shared);
+    }
+    Ok(())
+}
+
+// Replicates the primary set by REPLICAOF, connecting to it again whenever the link breaks.
+pub async fn replicate(shared: Arc<SharedState>) {
+    loop {
+        let changed = shared.replication.primary_changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        let Some((host, port)) = shared.replication.primary() else {
+            changed.await;
+            continue;
+        };
+        let result = tokio::select! {
+            result = sync_with_primary(&host, port, &shared) => result,
+            _ = &mut changed => continue,
+        };
+        shared.replication.set_link(LinkState::Connecting);
+        if let Err(e) = result {
+            logging::log(&shared, "warning", format!("Replication with MASTER {}:{} failed: {}", host, port, e));
+            if shared.replication.failing_over() {
+                shared.replication.abort_failover(&format!("the failover target failed taking over: {}", e), &shared);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+            _ = changed => {},
+        }
+    }
+}
+
+fn parse_wait(command: &[Arg], shared: &SharedState) -> Result<(usize, u64), RESPError> {
+    if shared.replication.is_replica() {
+        return Err(RESPError::WaitOnReplica);
+    }
+    let wanted = command[1].parse::<i64>().map_err(|_| RESPError::NotAnInteger)?;
+    let timeout = command[2].parse::<i64>().map_err(|_| RESPError::NotAnInteger)?;
+    if timeout < 0 {
+        return Err(RESPError::NegativeTimeout);
+    }
+    Ok((wanted.max(0) as usize, timeout as u64))
+}
+
+// WAIT numreplicas timeout
+// Inside transactions, replies with the amount of replicas that acknowledged the writes made so far
+// without blocking, see `blocking_wait`.
+pub fn wait(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    parse_wait(command, shared)?;
+    let offset = shared.replication.history().1;
+    Ok(RESPValue::Number(shared.replication.acknowledged(offset) as i64))
+}
+
+// Blocks until `numreplicas` replicas acknowledged the writes made so far or the timeout (in
+// milliseconds, 0 for none) passed, replying with the amount that did.
+pub async fn blocking_wait(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (wanted, timeout) = parse_wait(command, shared)?;
+    let offset = shared.replication.history().1;
+    if shared.replication.acknowledged(offset) >= wanted {
+        return wait(command, shared);
+    }
+    let deadline = (timeout > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(timeout));
+    let acknowledged = wait_acknowledged(offset, |acknowledged| acknowledged >= wanted, deadline, shared).await;
+    Ok(RESPValue::Number(acknowledged as i64))
+}
+
+// Waits until every replica acknowledged the writes streamed so far, or until the deadline, returning
+// whether they all did. The shutdown waits for lagging replicas with it.
+pub async fn wait_replicas(shared: &SharedState, deadline: tokio::time::Instant) -> bool {
+    let offset = shared.replication.history().1;
+    let replicas = || shared.replication.state.lock().unwrap().replicas.len();
+    if shared.replication.acknowledged(offset) >= replicas() {
+        return true;
+    }
+    wait_acknowledged(offset, |acknowledged| acknowledged >= replicas(), Some(deadline), shared).await >= replicas()
+}
+
+// Asks the replicas to acknowledge the writes streamed to them and waits until enough acknowledged
+// `offset` or until the deadline, returning how many did.
+async fn wait_acknowledged(offset: u64, enough: impl Fn(usize) -> bool, deadline: Option<tokio::time::Instant>, shared: &SharedState) -> usize {
+    let mut getack = vec![];
+    aof::encode(&[Arg::from("REPLCONF"), Arg::from("GETACK"), Arg::from("*")], &mut getack);
+    shared.replication.feed(&getack);
+    loop {
+        let acked = shared.replication.acked.notified();
+        tokio::pin!(acked);
+        acked.as_mut().enable();
+        let acknowledged = shared.replication.acknowledged(offset);
+        if enough(acknowledged) {
+            return acknowledged;
+        }
+        match deadline {
+            Some(deadline) => if tokio::time::timeout_at(deadline, acked).await.is_err() {
+                return shared.replication.acknowledged(offset);
+            },
+            None => acked.await
+        }
+    }
+}
+
+// The fields of INFO replication.
+pub fn info(shared: &SharedState) -> Vec<(String, String)> {
+    let state = shared.replication.state.lock().unwrap();
+    let mut fields = vec![];
+    let mut field = |name: &str, value: String| fields.push((name.to_owned(), value));
+    match &state.primary {
+        Some((host, port)) => {
+            field("role", String::from("slave"));
+            field("master_host", host.to_owned());
+            field("master_port", port.to_string());
+            field("master_link_status", String::from(if state.link == LinkState::Connected { "up" } else { "down" }));
+            field("master_last_io_seconds_ago", state.last_io.elapsed().as_secs().to_string());
+            field("master_sync_in_progress", ((state.link == LinkState::Sync) as u8).to_string());
+            field("slave_repl_offset", state.offset.to_string());
+        },
+        None => field("role", String::from("master"))
+    }
+    field("connected_slaves", state.replicas.len().to_string());
+    for (i, replica) in state.replicas.iter().enumerate() {
+        let replica_state = match replica.state {
+            ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online"
+        };
+        field(&format!("slave{}", i), format!("ip={},port={},state={},offset={},lag={}",
+            replica.ip, replica.listening_port, replica_state, replica.offset, replica.last_ack.elapsed().as_secs()));
+    }
+    field("master_failover_state", String::from(match &state.failover {
+        None => "no-failover",
+        Some(failover) if failover.in_progress => "failover-in-progress",
+        Some(_) => "waiting-for-sync"
+    }));
+    field("master_replid", state.replid.clone());
+    field("master_replid2", state.replid2.as_ref().map_or_else(|| "0".repeat(ID_LEN), |(replid2, _)| replid2.clone()));
+    field("master_repl_offset", state.offset.to_string());
+    field("second_repl_offset", state.replid2.as_ref().map_or_else(|| String::from("-1"), |(_, until)| (until + 1).to_string()));
+    let histlen = state.backlog.as_ref().map_or(0, VecDeque::len) as u64;
+    field("repl_backlog_active", (state.backlog.is_some() as u8).to_string());
+    field("repl_backlog_size", BACKLOG_SIZE.load(Ordering::Relaxed).to_string());
+    field("repl_backlog_first_byte_offset", (state.offset - histlen + 1).to_string());
+    field("repl_backlog_histlen", histlen.to_string());
+    fields
+}
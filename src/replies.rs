@@ -0,0 +1,120 @@
+// Writing replies to a client. Replies are encoded into a buffer, except for large blob strings
+// which are kept as the buffers they already are (like the value of a key) and written along with
+// the rest in a single vectored write, so a multi-megabyte GET isn't copied on its way out.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::protocol::{encode, RESPValue};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::Sink;
+use tokio::io::AsyncWrite;
+
+// Blob strings at least as large are written from their own buffer rather than copied.
+const MIN_ZERO_COPY_SIZE: usize = 16 * 1024;
+// Replies are flushed before more are buffered once that many bytes wait.
+const BACKPRESSURE_BOUNDARY: usize = 64 * 1024;
+// The buffers written by a single vectored write, at most.
+const MAX_WRITE_BUFFERS: usize = 64;
+
+pub struct ReplyWriter<W> {
+    inner: W,
+    // Encoded replies waiting to be written, in order.
+    chunks: VecDeque<Bytes>,
+    // Where replies are encoded, until a large blob string or a flush moves it to the chunks.
+    encoded: BytesMut,
+    buffered: usize,
+}
+
+impl<W: AsyncWrite + Unpin> ReplyWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, chunks: VecDeque::new(), encoded: BytesMut::new(), buffered: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn encode(&mut self, value: RESPValue) {
+        let start = self.encoded.len();
+        match value {
+            RESPValue::BlobString(blob) if blob.len() >= MIN_ZERO_COPY_SIZE => {
+                write!(self.encoded, "${}\r\n", blob.len()).unwrap();
+                self.buffered += self.encoded.len() - start + blob.len() + 2;
+                self.end_chunk();
+                self.chunks.push_back(blob);
+                self.encoded.extend_from_slice(b"\r\n");
+            },
+            // Encoded element by element, as they may hold large blob strings.
+            RESPValue::Array(values) | RESPValue::Push(values) => {
+                write!(self.encoded, "*{}\r\n", values.len()).unwrap();
+                self.buffered += self.encoded.len() - start;
+                for value in values {
+                    self.encode(value);
+                }
+            },
+            value => {
+                encode(value, &mut self.encoded);
+                self.buffered += self.encoded.len() - start;
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        if !self.encoded.is_empty() {
+            self.chunks.push_back(self.encoded.split().freeze());
+        }
+    }
+
+    // Drops the bytes that were written.
+    fn advance(&mut self, mut written: usize) {
+        self.buffered -= written;
+        while written > 0 {
+            let chunk = self.chunks.front_mut().unwrap();
+            if chunk.len() > written {
+                chunk.advance(written);
+                return;
+            }
+            written -= chunk.len();
+            self.chunks.pop_front();
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<RESPValue> for ReplyWriter<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.buffered >= BACKPRESSURE_BOUNDARY {
+            ready!(self.poll_flush(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, value: RESPValue) -> io::Result<()> {
+        self.get_mut().encode(value);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.end_chunk();
+        while !this.chunks.is_empty() {
+            let buffers: Vec<IoSlice> = this.chunks.iter().take(MAX_WRITE_BUFFERS).map(|chunk| IoSlice::new(chunk)).collect();
+            let written = ready!(Pin::new(&mut this.inner).poll_write_vectored(cx, &buffers))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.advance(written);
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,274 @@
+// A REST gateway over HTTP, for scripts and webhooks that don't speak RESP. With `rest-port` set,
+// every address of `bind` serves:
+//   GET    /keys/{key}          GET key
+//   PUT    /keys/{key}?ex=N     SET key (or SETEX key N) with the request body as the value
+//   DELETE /keys/{key}          DEL key
+//   POST   /command             any command, the body being its JSON array like ["HSET","h","f","v"]
+// Replies are JSON: strings, numbers, arrays and objects for maps, null for nil and {"error": ...}
+// for errors, along with a 400 status (401 for authentication errors). A missing key is a 404.
+// Every request runs as a client of its own, authenticated by basic authentication when the server
+// requires a password, and the connection is closed once it's replied to.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Map, Number, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::arg::Arg;
+use crate::{connect, denied_by_protected_mode, disconnect, execute_command, logging, protocol_limits, RESPError, RESPValue, SharedState};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADERS_SIZE: usize = 64 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    // The user and password of basic authentication.
+    credentials: Option<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn new(status: u16, body: Value) -> Self {
+        Self { status, body }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::new(status, json!({ "error": message.into() }))
+    }
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut len) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None
+        };
+        bits = (bits << 6 | value as u32) & 0xffffff;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Reads a line of the request head, reading no more than the `room` left for it, so an endless line
+// is refused as soon as it doesn't fit rather than once it ends.
+async fn read_head_line(stream: &mut BufReader<TcpStream>, room: usize) -> std::io::Result<Result<String, Response>> {
+    let mut line = String::new();
+    let read = (&mut *stream).take(room as u64).read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Ok(Err(match read {
+            read if read == room => Response::error(431, "the request head is too large"),
+            _ => Response::error(400, "bad request")
+        }));
+    }
+    Ok(Ok(line))
+}
+
+// Reads a request, or the response refusing it when it isn't valid HTTP or is too large.
+async fn read_request(stream: &mut BufReader<TcpStream>, max_body: usize) -> std::io::Result<Result<Request, Response>> {
+    let request_line = match read_head_line(stream, MAX_HEADERS_SIZE).await? {
+        Ok(line) => line,
+        Err(response) => return Ok(Err(response))
+    };
+    let Some((method, target)) = request_line.split_once(' ').and_then(|(method, rest)| Some((method, rest.split_once(' ')?.0))) else {
+        return Ok(Err(Response::error(400, "bad request")));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (mut content_length, mut credentials, mut headers_size) = (0, None, request_line.len());
+    loop {
+        let line = match read_head_line(stream, MAX_HEADERS_SIZE - headers_size).await? {
+            Ok(line) => line,
+            Err(response) => return Ok(Err(response))
+        };
+        headers_size += line.len();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(Err(Response::error(400, "bad request")));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            match value.parse() {
+                Ok(len) => content_length = len,
+                Err(_) => return Ok(Err(Response::error(400, "bad request")))
+            }
+        } else if name.eq_ignore_ascii_case("authorization") {
+            credentials = value.strip_prefix("Basic ").and_then(base64_decode).and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| decoded.split_once(':').map(|(user, password)| (user.to_owned(), password.to_owned())));
+        }
+    }
+    if content_length > max_body {
+        return Ok(Err(Response::error(413, "the body is too large")));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Ok(Request { method: method.to_owned(), path: path.to_owned(), query: query.to_owned(), credentials, body }))
+}
+
+// The command a request is for.
+fn route(request: &Request) -> Result<Vec<Arg>, Response> {
+    if let Some(key) = request.path.strip_prefix("/keys/") {
+        let key = Arg::from(percent_decode(key).ok_or_else(|| Response::error(400, "invalid key"))?);
+        return match request.method.as_str() {
+            "GET" => Ok(vec![Arg::from("GET"), key]),
+            "DELETE" => Ok(vec![Arg::from("DEL"), key]),
+            "PUT" => {
+                let value = Arg::from_bytes(request.body.clone());
+                match request.query.split('&').find_map(|param| param.strip_prefix("ex=")) {
+                    Some(seconds) => Ok(vec![Arg::from("SETEX"), key, Arg::from(seconds), value]),
+                    None => Ok(vec![Arg::from("SET"), key, value])
+                }
+            },
+            _ => Err(Response::error(405, "method not allowed"))
+        };
+    }
+    if request.path == "/command" {
+        if request.method != "POST" {
+            return Err(Response::error(405, "method not allowed"));
+        }
+        let invalid = || Response::error(400, "the body must be a JSON array of strings and numbers");
+        let Ok(Value::Array(args)) = serde_json::from_slice(&request.body) else {
+            return Err(invalid());
+        };
+        let mut command = args.into_iter().map(|arg| match arg {
+            Value::String(arg) => Ok(Arg::from(arg)),
+            Value::Number(arg) => Ok(Arg::from(arg.to_string())),
+            _ => Err(invalid())
+        }).collect::<Result<Vec<_>, _>>()?;
+        if command.is_empty() {
+            return Err(invalid());
+        }
+        // Names are matched in upper case, like RESP clients send them.
+        command[0] = Arg::from(command[0].to_ascii_uppercase());
+        return Ok(command);
+    }
+    Err(Response::error(404, "not found"))
+}
+
+fn to_json(reply: RESPValue) -> Value {
+    match reply {
+        RESPValue::BlobString(s) => Value::String(String::from_utf8_lossy(&s).into_owned()),
+        RESPValue::SimpleString(s) => Value::String(s),
+        RESPValue::BlobError(e) | RESPValue::SimpleError(e) => json!({ "error": String::from_utf8_lossy(&e) }),
+        RESPValue::Number(n) => Value::from(n),
+        // JSON has no infinities.
+        RESPValue::Double(d) => Number::from_f64(d).map_or_else(|| Value::String(d.to_string()), Value::Number),
+        RESPValue::Boolean(b) => Value::Bool(b),
+        RESPValue::Null => Value::Null,
+        RESPValue::Array(values) | RESPValue::Set(values) | RESPValue::Push(values) => Value::Array(values.into_iter().map(to_json).collect()),
+        RESPValue::Map(entries) => Value::Object(entries.into_iter()
+            .map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), to_json(value))).collect::<Map<_, _>>())
+    }
+}
+
+// Runs the command as a new client, authenticating it first when there are credentials.
+async fn run(command: Vec<Arg>, credentials: Option<(String, String)>, addr: String, laddr: String, shared: &Arc<SharedState>) -> RESPValue {
+    let id = shared.next_client_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let mut client = connect(id, addr, laddr, mpsc::unbounded_channel().0, shared);
+    let auth = credentials.map(|(user, password)| vec![Arg::from("AUTH"), Arg::from(user), Arg::from(password)]);
+    let mut reply = RESPValue::Null;
+    for command in auth.into_iter().chain([command]) {
+        logging::command(shared, &command);
+        client.info.state.lock().unwrap().last_command = command[0].to_ascii_lowercase();
+        let (executed, replies) = execute_command(command, client, shared).await;
+        client = executed;
+        client.sync_info();
+        reply = replies.into_iter().next().unwrap_or(RESPValue::Null);
+        if reply.as_simple_error().is_some() {
+            break;
+        }
+    }
+    disconnect(&mut client, shared);
+    reply
+}
+
+async fn respond(stream: TcpStream, addr: String, shared: Arc<SharedState>) -> std::io::Result<()> {
+    let laddr = stream.local_addr().map_or_else(|_| String::new(), |laddr| laddr.to_string());
+    let mut stream = BufReader::new(stream);
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream, protocol_limits().max_bulk_len)).await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let response = match request.and_then(|request| Ok((route(&request)?, request))) {
+        Err(response) => response,
+        Ok(_) if denied_by_protected_mode(Some(&addr), &shared) => {
+            logging::log(&shared, "warning", format!("Denied the HTTP request from {} in protected mode", addr));
+            Response::error(403, RESPError::ProtectedMode.to_string())
+        },
+        Ok((command, request)) => {
+            let missing = command[0] == "GET" && request.path.starts_with("/keys/");
+            match run(command, request.credentials, addr, laddr, &shared).await {
+                RESPValue::SimpleError(e) if e.starts_with(b"NOAUTH") || e.starts_with(b"WRONGPASS") =>
+                    Response::error(401, String::from_utf8_lossy(&e)),
+                RESPValue::SimpleError(e) => Response::error(400, String::from_utf8_lossy(&e)),
+                RESPValue::Null if missing => Response::new(404, Value::Null),
+                RESPValue::Number(0) if request.method == "DELETE" => Response::new(404, Value::from(0)),
+                reply => Response::new(200, to_json(reply))
+            }
+        }
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Payload Too Large"
+    };
+    let body = response.body.to_string();
+    let authenticate = if response.status == 401 { "WWW-Authenticate: Basic realm=\"bast\"\r\n" } else { "" };
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status, reason, body.len(), authenticate);
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Serves a connection to `rest-port`.
+pub async fn handle(stream: TcpStream, addr: std::net::SocketAddr, shared: Arc<SharedState>) {
+    if let Err(e) = respond(stream, addr.to_string(), shared.clone()).await {
+        logging::log(&shared, "verbose", format!("Failed serving the HTTP request from {}: {}", addr, e));
+    }
+}
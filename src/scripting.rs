@@ -0,0 +1,355 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, Value, Variadic};
+use tokio::sync::Notify;
+
+use crate::arg::Arg;
+use crate::{RESPError, RESPValue};
+
+// How many Lua instructions run between checks for SCRIPT KILL.
+const KILL_CHECK_INSTRUCTIONS: u32 = 100_000;
+
+// Tracks the script currently running (scripts run one at a time, off the runtime), so other
+// clients can wait for it, or be told the server is busy once it runs for too long.
+#[derive(Default)]
+pub struct ScriptMonitor {
+    started: Mutex<Option<Instant>>,
+    wrote: AtomicBool,
+    kill: Arc<AtomicBool>,
+    finished: Notify,
+}
+
+impl ScriptMonitor {
+    pub fn start(&self) {
+        self.wrote.store(false, Ordering::Relaxed);
+        self.kill.store(false, Ordering::Relaxed);
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn finish(&self) {
+        *self.started.lock().unwrap() = None;
+        self.finished.notify_waiters();
+    }
+
+    pub fn running_for(&self) -> Option<Duration> {
+        self.started.lock().unwrap().map(|started| started.elapsed())
+    }
+
+    pub fn record_write(&self) {
+        self.wrote.store(true, Ordering::Relaxed);
+    }
+
+    pub fn kill_flag(&self) -> Arc<AtomicBool> {
+        self.kill.clone()
+    }
+
+    pub fn kill(&self) -> Result<(), RESPError> {
+        if self.running_for().is_none() {
+            return Err(RESPError::NotBusy);
+        }
+        // Killing a script mid way through its writes would break its atomicity.
+        if self.wrote.load(Ordering::Relaxed) {
+            return Err(RESPError::Unkillable);
+        }
+        self.kill.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Waits until the running script finishes or until it has been running for `threshold`,
+    // returning whether it's still running.
+    pub async fn wait(&self, threshold: Duration) -> bool {
+        loop {
+            let finished = self.finished.notified();
+            let elapsed = match self.running_for() {
+                Some(elapsed) => elapsed,
+                None => return false
+            };
+            if elapsed >= threshold {
+                return true;
+            }
+            let _ = tokio::time::timeout(threshold - elapsed, finished).await;
+        }
+    }
+}
+
+pub fn sha1_hex(body: &str) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+fn resp_to_lua<'lua>(lua: &'lua Lua, value: RESPValue) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        RESPValue::BlobString(s) => Value::String(lua.create_string(s)?),
+        RESPValue::SimpleString(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s)?;
+            Value::Table(table)
+        },
+        RESPValue::SimpleError(e) | RESPValue::BlobError(e) => {
+            let table = lua.create_table()?;
+            table.set("err", lua.create_string(&e)?)?;
+            Value::Table(table)
+        },
+        RESPValue::Number(n) => Value::Integer(n as mlua::Integer),
+        RESPValue::Double(d) => Value::Number(d),
+        RESPValue::Boolean(b) => Value::Boolean(b),
+        RESPValue::Array(values) | RESPValue::Push(values) => {
+            let table = lua.create_table()?;
+            for (i, v) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, resp_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        },
+        RESPValue::Null | RESPValue::Map(_) | RESPValue::Set(_) => Value::Boolean(false),
+    })
+}
+
+fn lua_to_resp(value: Value) -> mlua::Result<RESPValue> {
+    Ok(match value {
+        Value::Boolean(true) => RESPValue::Number(1),
+        Value::Integer(n) => RESPValue::Number(n),
+        Value::Number(n) => RESPValue::Number(n as i64),
+        Value::String(s) => RESPValue::BlobString(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(table) => {
+            if let Value::String(e) = table.raw_get::<_, Value>("err")? {
+                return Ok(RESPValue::SimpleError(Bytes::copy_from_slice(e.as_bytes())));
+            }
+            if let Value::String(s) = table.raw_get::<_, Value>("ok")? {
+                return Ok(RESPValue::SimpleString(s.to_str()?.to_owned()));
+            }
+
+            // Like Redis, the array ends at the first nil.
+            let mut values = vec![];
+            for v in table.sequence_values::<Value>() {
+                values.push(lua_to_resp(v?)?);
+            }
+            RESPValue::Array(values)
+        },
+        _ => RESPValue::Null
+    })
+}
+
+fn lua_args_to_command(lua: &Lua, args: Variadic<Value>) -> mlua::Result<Vec<Arg>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(String::from("Please specify at least one argument for this redis lib call")));
+    }
+
+    args.into_iter().map(|arg| {
+        match lua.coerce_string(arg)? {
+            Some(s) => Ok(Arg::from_bytes(s.as_bytes().to_vec())),
+            None => Err(mlua::Error::RuntimeError(String::from("Lua redis lib command arguments must be strings or integers")))
+        }
+    }).collect()
+}
+
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        // Drop the stack traceback, error replies must fit in a single line.
+        mlua::Error::RuntimeError(message) => message.lines().next().unwrap_or_default().to_owned(),
+        mlua::Error::SyntaxError { message, .. } => message.to_owned(),
+        e => e.to_string()
+    }
+}
+
+// Runs `f` on a fresh Lua state with the `redis` library set up, `call` executes the commands
+// issued through `redis.call` and `redis.pcall`.
+fn with_redis_api<R>(kill: Arc<AtomicBool>, call: impl FnMut(Vec<Arg>) -> Result<RESPValue, RESPError>, f: impl FnOnce(&Lua) -> mlua::Result<R>) -> Result<R, RESPError> {
+    let lua = Lua::new();
+    let call = RefCell::new(call);
+
+    lua.set_hook(HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS), move |_, _| {
+        if kill.load(Ordering::Relaxed) {
+            return Err(mlua::Error::RuntimeError(String::from("Script killed by user with SCRIPT KILL...")));
+        }
+        Ok(())
+    });
+
+    let result = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+
+        redis.set("call", scope.create_function(|lua, args: Variadic<Value>| {
+            let command = lua_args_to_command(lua, args)?;
+            let reply = (call.borrow_mut())(command).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+            resp_to_lua(lua, reply)
+        })?)?;
+
+        redis.set("pcall", scope.create_function(|lua, args: Variadic<Value>| {
+            let command = lua_args_to_command(lua, args)?;
+            let reply = (call.borrow_mut())(command).unwrap_or_else(|e| e.into());
+            resp_to_lua(lua, reply)
+        })?)?;
+
+        redis.set("error_reply", lua.create_function(|lua, message: mlua::String| {
+            let table = lua.create_table()?;
+            table.set("err", message)?;
+            Ok(table)
+        })?)?;
+
+        redis.set("status_reply", lua.create_function(|lua, message: mlua::String| {
+            let table = lua.create_table()?;
+            table.set("ok", message)?;
+            Ok(table)
+        })?)?;
+
+        redis.set("sha1hex", lua.create_function(|_, s: mlua::String| Ok(sha1_hex(s.to_str()?)))?)?;
+
+        lua.globals().set("redis", redis)?;
+        f(&lua)
+    });
+
+    result.map_err(|e| RESPError::ScriptError(error_message(&e)))
+}
+
+fn texts(args: &[Arg]) -> Vec<&str> {
+    args.iter().map(|arg| arg.as_str()).collect()
+}
+
+// Runs a script with the KEYS and ARGV globals set.
+pub fn run_script(body: &str, keys: &[Arg], args: &[Arg], kill: Arc<AtomicBool>, call: impl FnMut(Vec<Arg>) -> Result<RESPValue, RESPError>) -> Result<RESPValue, RESPError> {
+    with_redis_api(kill, call, |lua| {
+        let globals = lua.globals();
+        globals.set("KEYS", texts(keys))?;
+        globals.set("ARGV", texts(args))?;
+
+        let script = lua.load(body).set_name(format!("@user_script:{}", sha1_hex(body))).into_function()?;
+        let value = script.call::<_, Value>(())?;
+        lua_to_resp(value)
+    })
+}
+
+const FUNCTION_FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+// Where `redis.register_function` keeps the registered callbacks while the library code runs.
+const REGISTERED_FUNCTIONS: &str = "__registered_functions";
+
+#[derive(Clone)]
+pub struct FunctionSpec {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: Vec<FunctionSpec>,
+}
+
+impl Library {
+    pub fn function(&self, name: &str) -> Option<&FunctionSpec> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+}
+
+fn register_function<'lua>(lua: &'lua Lua, args: Variadic<Value<'lua>>) -> mlua::Result<()> {
+    let registered: mlua::Table = lua.named_registry_value(REGISTERED_FUNCTIONS)?;
+
+    let entry = lua.create_table()?;
+    let name = match (args.first(), args.get(1)) {
+        (Some(Value::String(name)), Some(Value::Function(callback))) if args.len() == 2 => {
+            entry.set("callback", callback.clone())?;
+            name.to_str()?.to_owned()
+        },
+        (Some(Value::Table(spec)), None) => {
+            let name: String = spec.get("function_name")
+                .map_err(|_| mlua::Error::RuntimeError(String::from("function_name argument given to redis.register_function must be a string")))?;
+            let callback: mlua::Function = spec.get("callback")
+                .map_err(|_| mlua::Error::RuntimeError(String::from("callback argument given to redis.register_function must be a function")))?;
+            entry.set("callback", callback)?;
+            entry.set("description", spec.get::<_, Option<String>>("description")?)?;
+            if let Some(flags) = spec.get::<_, Option<Vec<String>>>("flags")? {
+                if let Some(flag) = flags.iter().find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str())) {
+                    return Err(mlua::Error::RuntimeError(format!("unknown flag given: {}", flag)));
+                }
+                entry.set("flags", flags)?;
+            }
+            name
+        },
+        _ => return Err(mlua::Error::RuntimeError(String::from("wrong number of arguments to redis.register_function")))
+    };
+
+    if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+        return Err(mlua::Error::RuntimeError(String::from("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long")));
+    }
+    if registered.contains_key(name.as_str())? {
+        return Err(mlua::Error::RuntimeError(format!("Function {} already exists", name)));
+    }
+    registered.set(name, entry)
+}
+
+// Runs the library code (minus its shebang line), returning the table of registered functions.
+fn load_library_code<'lua>(lua: &'lua Lua, library: &str, code: &str) -> mlua::Result<mlua::Table<'lua>> {
+    lua.set_named_registry_value(REGISTERED_FUNCTIONS, lua.create_table()?)?;
+    let redis: mlua::Table = lua.globals().get("redis")?;
+    redis.set("register_function", lua.create_function(register_function)?)?;
+
+    // Keep the shebang line empty so error line numbers still match the code the user sent.
+    let body = code.find('\n').map_or("", |newline| &code[newline..]);
+    lua.load(body).set_name(format!("@user_function:{}", library)).exec()?;
+
+    // Registering is only allowed while the library loads.
+    redis.set("register_function", Value::Nil)?;
+    lua.named_registry_value(REGISTERED_FUNCTIONS)
+}
+
+// Parses a `#!lua name=<library>` shebang line.
+fn parse_shebang(code: &str) -> Result<String, RESPError> {
+    let shebang = code.lines().next().filter(|line| line.starts_with("#!")).ok_or(RESPError::MissingLibraryMetadata)?;
+    let mut parts = shebang[2..].split(' ').filter(|part| !part.is_empty());
+
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(RESPError::UnsupportedEngine(engine.to_owned()));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_owned()),
+            None => return Err(RESPError::InvalidLibraryMetadata(part.to_owned()))
+        }
+    }
+    name.ok_or(RESPError::MissingLibraryMetadata)
+}
+
+pub fn load_library(code: &str) -> Result<Library, RESPError> {
+    let name = parse_shebang(code)?;
+
+    let call = |_: Vec<Arg>| Err(RESPError::NotAllowedFromScript);
+    let functions = with_redis_api(Arc::new(AtomicBool::new(false)), call, |lua| {
+        let registered = load_library_code(lua, &name, code)?;
+
+        let mut functions = vec![];
+        for pair in registered.pairs::<String, mlua::Table>() {
+            let (function_name, entry) = pair?;
+            functions.push(FunctionSpec {
+                name: function_name,
+                description: entry.get("description")?,
+                flags: entry.get::<_, Option<Vec<String>>>("flags")?.unwrap_or_default(),
+            });
+        }
+        Ok(functions)
+    })?;
+
+    if functions.is_empty() {
+        return Err(RESPError::NoFunctionsRegistered);
+    }
+
+    Ok(Library { name, code: code.to_owned(), functions })
+}
+
+// Calls a function of a loaded library with the keys and args tables as its arguments.
+pub fn call_function(library: &Library, function: &str, keys: &[Arg], args: &[Arg], kill: Arc<AtomicBool>, call: impl FnMut(Vec<Arg>) -> Result<RESPValue, RESPError>) -> Result<RESPValue, RESPError> {
+    with_redis_api(kill, call, |lua| {
+        let registered = load_library_code(lua, &library.name, &library.code)?;
+        let entry: mlua::Table = registered.get(function)?;
+        let callback: mlua::Function = entry.get("callback")?;
+        let value = callback.call::<_, Value>((texts(keys), texts(args)))?;
+        lua_to_resp(value)
+    })
+}
@@ -0,0 +1,865 @@
+// Sentinel mode (--sentinel): instead of serving data, the server monitors groups of a primary and
+// its replicas set with `sentinel monitor <name> <host> <port> <quorum>`, and fails over to a
+// replica when the primary is down, the way Redis Sentinel does.
+//
+// Every second each instance is pinged, asked for INFO replication (which is how the replicas of a
+// primary are found) and sent a hello on the `__sentinel__:hello` channel. The sentinels
+// monitoring the same primary find each other through those hellos, which they're subscribed to on
+// every instance.
+//
+// A primary not replying for `down-after-milliseconds` is subjectively down, and objectively down
+// once `quorum` sentinels (counting this one) think so, which is asked with SENTINEL
+// IS-MASTER-DOWN-BY-ADDR. A sentinel seeing it objectively down starts a new epoch and asks the
+// others to vote for it as the leader of the failover, every sentinel voting once per epoch. The
+// leader, once it has both `quorum` votes and a majority, promotes the replica that got the most
+// writes with REPLICAOF NO ONE and points the others at it. The new configuration is announced
+// through the hellos along with its epoch, a higher epoch winning, and replicas found pointing
+// elsewhere (like the former primary when it's back) are pointed at the primary.
+//
+// Events (like +sdown, +odown and +switch-master) are logged and published on channels named after
+// them, and clients find the primary with SENTINEL GET-MASTER-ADDR-BY-NAME. What the sentinels
+// discover isn't written to the configuration file, they discover it again after a restart.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::join_all;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::arg::Arg;
+use crate::{aof, logging, replication, RESPError, RESPValue, SharedState};
+
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+// How often instances are checked.
+const PERIOD: Duration = Duration::from_secs(1);
+// The timeout of connecting to an instance and of every command sent to it.
+const TIMEOUT: Duration = Duration::from_millis(500);
+// Replicas not replying for this long aren't promoted.
+const REPLICA_STALE: Duration = Duration::from_secs(5);
+// How long replicas are found pointing elsewhere before they're pointed at the primary, since a
+// failover this sentinel didn't hear about yet may have pointed them there.
+const FIX_GRACE: Duration = Duration::from_secs(4);
+
+// Commands served in sentinel mode.
+const COMMANDS: &[&str] = &[
+    "PING", "ECHO", "QUIT", "RESET", "AUTH", "CLIENT", "INFO", "COMMAND", "ACL", "CONFIG", "SENTINEL", "SHUTDOWN",
+    "SUBSCRIBE", "PSUBSCRIBE", "UNSUBSCRIBE", "PUNSUBSCRIBE", "PUBLISH",
+];
+
+type Addr = (String, u16);
+
+pub struct Sentinel {
+    run_id: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+    // Instances a task is subscribed to the hellos of.
+    subscribed: HashSet<Addr>,
+}
+
+struct Master {
+    addr: Addr,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    auth_pass: String,
+    // The epoch of the failover that made this the primary.
+    config_epoch: u64,
+    last_reply: Instant,
+    s_down: bool,
+    o_down: bool,
+    replicas: BTreeMap<Addr, Replica>,
+    // The other sentinels monitoring the primary, by their ID.
+    sentinels: BTreeMap<String, Peer>,
+    // The sentinel this one voted for as the leader of a failover, and in which epoch.
+    vote: Option<(String, u64)>,
+    failover: Option<Failover>,
+    // When this sentinel last tried to fail over, or voted for another one to.
+    last_failover: Option<Instant>,
+}
+
+struct Replica {
+    last_reply: Instant,
+    // The role and primary it reported, and whether its link to that primary is up.
+    is_master: bool,
+    primary: Option<Addr>,
+    link_up: bool,
+    offset: u64,
+    // Since when it's been found pointing elsewhere than the primary.
+    misconfigured_since: Option<Instant>,
+}
+
+impl Replica {
+    fn new() -> Self {
+        Self { last_reply: Instant::now(), is_master: false, primary: None, link_up: false, offset: 0, misconfigured_since: None }
+    }
+}
+
+struct Peer {
+    addr: Addr,
+    last_hello: Instant,
+    // What it replied to the last SENTINEL IS-MASTER-DOWN-BY-ADDR.
+    master_down: bool,
+    leader: Option<(String, u64)>,
+}
+
+struct Failover {
+    epoch: u64,
+    started: Instant,
+    // Set by SENTINEL FAILOVER, failing over without asking the other sentinels.
+    forced: bool,
+}
+
+impl Master {
+    fn new(addr: Addr, quorum: usize) -> Self {
+        Self {
+            addr,
+            quorum,
+            down_after: Duration::from_secs(30),
+            failover_timeout: Duration::from_secs(180),
+            auth_pass: String::new(),
+            config_epoch: 0,
+            last_reply: Instant::now(),
+            s_down: false,
+            o_down: false,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            vote: None,
+            failover: None,
+            last_failover: None,
+        }
+    }
+
+    // The votes a leader needs: the quorum, and a majority of the sentinels.
+    fn needed_votes(&self) -> usize {
+        let total = self.sentinels.len() + 1;
+        self.quorum.max(total / 2 + 1)
+    }
+
+    // Makes the replica the primary, the former primary becoming one of its replicas.
+    fn switch_to(&mut self, addr: Addr, config_epoch: u64) {
+        self.replicas.remove(&addr);
+        let former = std::mem::replace(&mut self.addr, addr);
+        self.replicas.insert(former, Replica::new());
+        self.config_epoch = config_epoch;
+        self.last_reply = Instant::now();
+        self.s_down = false;
+        self.o_down = false;
+        self.failover = None;
+        for peer in self.sentinels.values_mut() {
+            peer.master_down = false;
+        }
+    }
+}
+
+impl Default for Sentinel {
+    fn default() -> Self {
+        Self { run_id: replication::random_id(), state: Mutex::new(State::default()) }
+    }
+}
+
+impl Sentinel {
+    // Applies a `sentinel` directive of the configuration file.
+    pub fn configure(&mut self, args: &[String]) -> Result<(), String> {
+        let state = self.state.get_mut().unwrap();
+        let number = |value: &str| value.parse::<u64>().map_err(|_| format!("invalid number '{}'", value));
+        match &args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            [option, name, host, port, quorum] if option.eq_ignore_ascii_case("monitor") => {
+                let port = port.parse().map_err(|_| format!("invalid port '{}'", port))?;
+                let quorum = number(quorum)?.max(1) as usize;
+                state.masters.insert(name.to_string(), Master::new((host.to_string(), port), quorum));
+            },
+            [option, name, value] => {
+                let master = state.masters.get_mut(*name).ok_or_else(|| format!("no such master '{}', monitor it first", name))?;
+                match option.to_ascii_lowercase().as_str() {
+                    "down-after-milliseconds" => master.down_after = Duration::from_millis(number(value)?),
+                    "failover-timeout" => master.failover_timeout = Duration::from_millis(number(value)?),
+                    "auth-pass" => master.auth_pass = value.to_string(),
+                    _ => return Err(format!("unknown sentinel option '{}'", option))
+                }
+            },
+            _ => return Err(String::from("wrong number of arguments for 'sentinel'"))
+        }
+        Ok(())
+    }
+}
+
+// Whether the command is served in sentinel mode.
+pub fn allowed(name: &str) -> bool {
+    COMMANDS.contains(&name)
+}
+
+// Logs an event and publishes it on the channel named after it.
+fn event(shared: &SharedState, kind: &str, message: &str) {
+    logging::log(shared, "warning", format!("{} {}", kind, message));
+    shared.pubsub.lock().unwrap().publish(kind, message.as_bytes());
+}
+
+// How events name an instance, like `master mymaster 127.0.0.1 6379`.
+fn instance(role: &str, name: &str, addr: &Addr) -> String {
+    format!("{} {} {} {}", role, name, addr.0, addr.1)
+}
+
+// Parses the reply starting at `at`, None when the data ends before it does.
+fn parse_reply(data: &[u8], at: &mut usize) -> io::Result<Option<RESPValue>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid reply from an instance");
+    let Some(end) = data[*at..].windows(2).position(|window| window == b"\r\n").map(|end| *at + end) else {
+        return Ok(None);
+    };
+    let kind = data[*at];
+    let line = std::str::from_utf8(&data[*at + 1..end]).map_err(|_| invalid())?;
+    let number = || line.parse::<i64>().map_err(|_| invalid());
+    *at = end + 2;
+    Ok(Some(match kind {
+        b'+' => RESPValue::SimpleString(line.to_owned()),
+        b'-' => RESPValue::SimpleError(Bytes::copy_from_slice(line.as_bytes())),
+        b':' => RESPValue::Number(number()?),
+        b'$' | b'*' if number()? < 0 => RESPValue::Null,
+        b'$' => {
+            let len = number()? as usize;
+            if data.len() < *at + len + 2 {
+                return Ok(None);
+            }
+            let value = Bytes::copy_from_slice(&data[*at..*at + len]);
+            *at += len + 2;
+            RESPValue::BlobString(value)
+        },
+        b'*' => {
+            let mut items = vec![];
+            for _ in 0..number()? {
+                match parse_reply(data, at)? {
+                    Some(item) => items.push(item),
+                    None => return Ok(None)
+                }
+            }
+            RESPValue::Array(items)
+        },
+        _ => return Err(invalid())
+    }))
+}
+
+fn reply_text(reply: &RESPValue) -> String {
+    match reply {
+        RESPValue::SimpleString(text) => text.clone(),
+        RESPValue::BlobString(text) | RESPValue::SimpleError(text) => String::from_utf8_lossy(text).into_owned(),
+        RESPValue::Number(number) => number.to_string(),
+        _ => String::new()
+    }
+}
+
+// A connection to an instance or to another sentinel.
+struct Link {
+    socket: TcpStream,
+    buf: BytesMut,
+}
+
+impl Link {
+    async fn connect(addr: &Addr, auth_pass: &str) -> io::Result<Self> {
+        let socket = tokio::time::timeout(TIMEOUT, TcpStream::connect((addr.0.as_str(), addr.1))).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout connecting"))??;
+        let mut link = Self { socket, buf: BytesMut::new() };
+        if !auth_pass.is_empty() {
+            if let RESPValue::SimpleError(error) = link.command(&["AUTH", auth_pass]).await? {
+                return Err(io::Error::other(String::from_utf8_lossy(&error).into_owned()));
+            }
+        }
+        Ok(link)
+    }
+
+    async fn send(&mut self, command: &[&str]) -> io::Result<()> {
+        let command: Vec<Arg> = command.iter().copied().map(Arg::from).collect();
+        let mut buf = vec![];
+        aof::encode(&command, &mut buf);
+        self.socket.write_all(&buf).await
+    }
+
+    // Reads the next reply, waiting up to the timeout for it when there is one.
+    async fn reply(&mut self, timeout: Option<Duration>) -> io::Result<RESPValue> {
+        loop {
+            let mut at = 0;
+            if let Some(reply) = parse_reply(&self.buf, &mut at)? {
+                let _ = self.buf.split_to(at);
+                return Ok(reply);
+            }
+            let read = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.socket.read_buf(&mut self.buf)).await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timeout reading a reply"))??,
+                None => self.socket.read_buf(&mut self.buf).await?
+            };
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &[&str]) -> io::Result<RESPValue> {
+        self.send(command).await?;
+        self.reply(Some(TIMEOUT)).await
+    }
+}
+
+// What an instance replied to the checks of a period.
+struct Check {
+    pong: bool,
+    is_master: bool,
+    // The replicas of a primary.
+    replicas: Vec<Addr>,
+    // The primary of a replica, with whether it's linked to it and its offset.
+    primary: Option<Addr>,
+    link_up: bool,
+    offset: u64,
+}
+
+// The hello of this sentinel about a primary, without the address it's announced on.
+struct Hello {
+    port: u16,
+    run_id: String,
+    current_epoch: u64,
+    name: String,
+    master: Addr,
+    config_epoch: u64,
+}
+
+// Pings the instance, asks it for INFO replication and publishes the hello on it.
+async fn check(addr: &Addr, auth_pass: &str, hello: &Hello) -> io::Result<Check> {
+    let mut link = Link::connect(addr, auth_pass).await?;
+    // Busy loading or without a link to its primary is still alive.
+    let pong = match link.command(&["PING"]).await? {
+        RESPValue::SimpleString(_) => true,
+        reply => ["LOADING", "MASTERDOWN"].iter().any(|prefix| reply_text(&reply).starts_with(prefix))
+    };
+    let info = reply_text(&link.command(&["INFO", "replication"]).await?);
+    let fields: BTreeMap<&str, &str> = info.lines().filter_map(|line| line.trim_end().split_once(':')).collect();
+    let mut check = Check {
+        pong,
+        is_master: fields.get("role") == Some(&"master"),
+        replicas: vec![],
+        primary: None,
+        link_up: fields.get("master_link_status") == Some(&"up"),
+        offset: fields.get("slave_repl_offset").and_then(|offset| offset.parse().ok()).unwrap_or(0),
+    };
+    for (field, value) in &fields {
+        if !field.starts_with("slave") || !field[5..].chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let values: BTreeMap<&str, &str> = value.split(',').filter_map(|pair| pair.split_once('=')).collect();
+        if let (Some(ip), Some(Ok(port))) = (values.get("ip"), values.get("port").map(|port| port.parse())) {
+            check.replicas.push((ip.to_string(), port));
+        }
+    }
+    if let (Some(host), Some(Ok(port))) = (fields.get("master_host"), fields.get("master_port").map(|port| port.parse())) {
+        check.primary = Some((host.to_string(), port));
+    }
+
+    let ip = link.socket.local_addr()?.ip().to_string();
+    let message = format!("{},{},{},{},{},{},{},{}",
+        ip, hello.port, hello.run_id, hello.current_epoch, hello.name, hello.master.0, hello.master.1, hello.config_epoch);
+    link.command(&["PUBLISH", HELLO_CHANNEL, &message]).await?;
+    Ok(check)
+}
+
+// Applies a hello of another sentinel, learning about it and about newer configurations.
+fn process_hello(message: &str, shared: &SharedState) {
+    let sentinel = shared.sentinel.as_ref().unwrap();
+    let fields: Vec<&str> = message.split(',').collect();
+    let [ip, port, run_id, current_epoch, name, master_ip, master_port, config_epoch] = fields[..] else {
+        return;
+    };
+    let (Ok(port), Ok(current_epoch), Ok(master_port), Ok(config_epoch)) =
+        (port.parse::<u16>(), current_epoch.parse::<u64>(), master_port.parse::<u16>(), config_epoch.parse::<u64>()) else {
+        return;
+    };
+    if run_id == sentinel.run_id {
+        return;
+    }
+    let mut state = sentinel.state.lock().unwrap();
+    state.current_epoch = state.current_epoch.max(current_epoch);
+    let Some(master) = state.masters.get_mut(name) else {
+        return;
+    };
+    let addr = (ip.to_owned(), port);
+    if !master.sentinels.contains_key(run_id) {
+        // A sentinel that restarted comes back with a new ID.
+        master.sentinels.retain(|_, peer| peer.addr != addr);
+        event(shared, "+sentinel", &format!("{} @ {}", instance("sentinel", name, &addr), run_id));
+    }
+    let peer = master.sentinels.entry(run_id.to_owned())
+        .or_insert(Peer { addr: addr.clone(), last_hello: Instant::now(), master_down: false, leader: None });
+    peer.addr = addr;
+    peer.last_hello = Instant::now();
+
+    let announced = (master_ip.to_owned(), master_port);
+    if config_epoch > master.config_epoch {
+        if announced != master.addr {
+            let former = master.addr.clone();
+            master.switch_to(announced.clone(), config_epoch);
+            event(shared, "+config-update-from", &instance("sentinel", name, &peer_addr(master, run_id)));
+            event(shared, "+switch-master", &format!("{} {} {} {} {}", name, former.0, former.1, announced.0, announced.1));
+        }
+        master.config_epoch = config_epoch;
+    }
+}
+
+fn peer_addr(master: &Master, run_id: &str) -> Addr {
+    master.sentinels.get(run_id).map_or_else(|| (String::new(), 0), |peer| peer.addr.clone())
+}
+
+// Subscribes to the hellos published on the instance, for as long as it's monitored.
+async fn subscribe(addr: Addr, shared: Arc<SharedState>) {
+    let sentinel = shared.sentinel.as_ref().unwrap();
+    let monitored = || sentinel.state.lock().unwrap().masters.values()
+        .any(|master| master.addr == addr || master.replicas.contains_key(&addr));
+    let auth_pass = || sentinel.state.lock().unwrap().masters.values()
+        .find(|master| master.addr == addr || master.replicas.contains_key(&addr))
+        .map_or_else(String::new, |master| master.auth_pass.clone());
+    while monitored() {
+        let result: io::Result<()> = async {
+            let mut link = Link::connect(&addr, &auth_pass()).await?;
+            link.send(&["SUBSCRIBE", HELLO_CHANNEL]).await?;
+            while monitored() {
+                let reply = match link.reply(Some(PERIOD)).await {
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    reply => reply?
+                };
+                if let RESPValue::Array(items) = reply {
+                    if let [kind, _, message] = &items[..] {
+                        if reply_text(kind) == "message" {
+                            process_hello(&reply_text(message), &shared);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }.await;
+        if result.is_err() {
+            tokio::time::sleep(PERIOD).await;
+        }
+    }
+    sentinel.state.lock().unwrap().subscribed.remove(&addr);
+}
+
+// Monitors the primaries, every period.
+pub async fn run(shared: Arc<SharedState>) {
+    let Some(sentinel) = &shared.sentinel else {
+        return;
+    };
+    {
+        let state = sentinel.state.lock().unwrap();
+        logging::log(&shared, "notice", format!("Sentinel ID is {}", sentinel.run_id));
+        for (name, master) in &state.masters {
+            event(&shared, "+monitor", &format!("{} quorum {}", instance("master", name, &master.addr), master.quorum));
+        }
+    }
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let names: Vec<String> = sentinel.state.lock().unwrap().masters.keys().cloned().collect();
+        join_all(names.iter().map(|name| monitor(name, &shared))).await;
+
+        let mut state = sentinel.state.lock().unwrap();
+        let instances: Vec<Addr> = state.masters.values()
+            .flat_map(|master| std::iter::once(master.addr.clone()).chain(master.replicas.keys().cloned()))
+            .collect();
+        for addr in instances {
+            if state.subscribed.insert(addr.clone()) {
+                tokio::spawn(subscribe(addr, shared.clone()));
+            }
+        }
+    }
+}
+
+// Checks the instances of a primary, and fails over when it's down.
+async fn monitor(name: &str, shared: &SharedState) {
+    let sentinel = shared.sentinel.as_ref().unwrap();
+    let (addr, auth_pass, replicas, hello) = {
+        let state = sentinel.state.lock().unwrap();
+        let master = &state.masters[name];
+        let hello = Hello {
+            port: shared.config.get_int("port") as u16,
+            run_id: sentinel.run_id.clone(),
+            current_epoch: state.current_epoch,
+            name: name.to_owned(),
+            master: master.addr.clone(),
+            config_epoch: master.config_epoch,
+        };
+        (master.addr.clone(), master.auth_pass.clone(), master.replicas.keys().cloned().collect::<Vec<_>>(), hello)
+    };
+    let (master_check, replica_checks) = tokio::join!(
+        check(&addr, &auth_pass, &hello),
+        join_all(replicas.iter().map(|replica| check(replica, &auth_pass, &hello)))
+    );
+
+    // Replicas found pointing elsewhere, which are pointed at the primary.
+    let mut misconfigured = vec![];
+    let (s_down, peers) = {
+        let mut state = sentinel.state.lock().unwrap();
+        let master = state.masters.get_mut(name).unwrap();
+        if master.addr != addr {
+            // Switched by a hello meanwhile.
+            return;
+        }
+        if let Ok(check) = &master_check {
+            if check.pong {
+                master.last_reply = Instant::now();
+            }
+            for replica in &check.replicas {
+                if !master.replicas.contains_key(replica) && *replica != master.addr {
+                    master.replicas.insert(replica.clone(), Replica::new());
+                    event(shared, "+slave", &instance("slave", name, replica));
+                }
+            }
+        }
+        for (replica_addr, check) in replicas.iter().zip(&replica_checks) {
+            let (Some(replica), Ok(check)) = (master.replicas.get_mut(replica_addr), check) else {
+                continue;
+            };
+            if check.pong {
+                replica.last_reply = Instant::now();
+            }
+            replica.is_master = check.is_master;
+            replica.primary = check.primary.clone();
+            replica.link_up = check.link_up;
+            replica.offset = check.offset;
+            if !replica.is_master && replica.primary.as_ref() == Some(&addr) {
+                replica.misconfigured_since = None;
+            } else if replica.misconfigured_since.get_or_insert_with(Instant::now).elapsed() > FIX_GRACE
+                && !master.s_down && master.failover.is_none() {
+                misconfigured.push(replica_addr.clone());
+            }
+        }
+
+        let s_down = master.last_reply.elapsed() > master.down_after;
+        if s_down != master.s_down {
+            master.s_down = s_down;
+            event(shared, if s_down { "+sdown" } else { "-sdown" }, &instance("master", name, &addr));
+            if !s_down && master.o_down {
+                master.o_down = false;
+                event(shared, "-odown", &instance("master", name, &addr));
+            }
+        }
+        let peers: Vec<(String, Addr)> = master.sentinels.iter().map(|(run_id, peer)| (run_id.clone(), peer.addr.clone())).collect();
+        (s_down, peers)
+    };
+
+    for replica in misconfigured {
+        if replicate_from(&replica, &addr, &auth_pass).await {
+            event(shared, "+fix-slave-config", &instance("slave", name, &replica));
+        }
+    }
+
+    // Trying after a random delay, for the sentinels finding the primary down at once not to all ask
+    // for votes at once and split them.
+    let can_try = |master: &Master| master.o_down && master.failover.is_none()
+        && master.last_failover.is_none_or(|last| last.elapsed() > master.failover_timeout * 2);
+    let trying = can_try(&sentinel.state.lock().unwrap().masters[name]);
+    if trying {
+        let delay = rand::thread_rng().gen_range(Duration::ZERO..PERIOD);
+        tokio::time::sleep(delay).await;
+        let mut state = sentinel.state.lock().unwrap();
+        let State { current_epoch, masters, .. } = &mut *state;
+        let master = masters.get_mut(name).unwrap();
+        // Unless another one asked for the vote of this one meanwhile.
+        if master.addr == addr && can_try(master) {
+            *current_epoch += 1;
+            master.vote = Some((sentinel.run_id.clone(), *current_epoch));
+            master.failover = Some(Failover { epoch: *current_epoch, started: Instant::now(), forced: false });
+            master.last_failover = Some(Instant::now());
+            event(shared, "+new-epoch", &current_epoch.to_string());
+            event(shared, "+try-failover", &instance("master", name, &addr));
+        }
+    }
+
+    if s_down {
+        // Asking the others whether they think it's down too, and for their vote once electing.
+        let (current_epoch, electing) = {
+            let state = sentinel.state.lock().unwrap();
+            let failover = state.masters[name].failover.as_ref();
+            (state.current_epoch.to_string(), failover.is_some_and(|failover| !failover.forced))
+        };
+        let candidate = if electing { sentinel.run_id.as_str() } else { "*" };
+        let replies = join_all(peers.iter().map(|(_, peer)| async {
+            let mut link = Link::connect(peer, "").await?;
+            link.command(&["SENTINEL", "IS-MASTER-DOWN-BY-ADDR", &addr.0, &addr.1.to_string(), &current_epoch, candidate]).await
+        })).await;
+        let mut state = sentinel.state.lock().unwrap();
+        let master = state.masters.get_mut(name).unwrap();
+        for ((run_id, _), reply) in peers.iter().zip(replies) {
+            let (Some(peer), Ok(RESPValue::Array(items))) = (master.sentinels.get_mut(run_id), reply) else {
+                continue;
+            };
+            if let [down, leader, epoch] = &items[..] {
+                peer.master_down = reply_text(down) == "1";
+                let leader = reply_text(leader);
+                peer.leader = (leader != "*").then(|| (leader, reply_text(epoch).parse().unwrap_or(0)));
+            }
+        }
+    }
+
+    let elected = {
+        let mut state = sentinel.state.lock().unwrap();
+        let master = state.masters.get_mut(name).unwrap();
+        if master.addr != addr {
+            return;
+        }
+        let o_down = master.s_down && 1 + master.sentinels.values().filter(|peer| peer.master_down).count() >= master.quorum;
+        if o_down != master.o_down {
+            master.o_down = o_down;
+            event(shared, if o_down { "+odown" } else { "-odown" }, &format!("{} #quorum {}", instance("master", name, &addr), master.quorum));
+        }
+        match &master.failover {
+            Some(failover) if failover.forced => Some(failover.epoch),
+            Some(failover) => {
+                let me = Some((sentinel.run_id.clone(), failover.epoch));
+                let votes = (master.vote == me) as usize + master.sentinels.values().filter(|peer| peer.leader == me).count();
+                if votes >= master.needed_votes() {
+                    event(shared, "+elected-leader", &instance("master", name, &addr));
+                    Some(failover.epoch)
+                } else if !master.o_down || failover.started.elapsed() > master.failover_timeout {
+                    master.failover = None;
+                    event(shared, "-failover-abort-not-elected", &instance("master", name, &addr));
+                    None
+                } else {
+                    None
+                }
+            },
+            None => None
+        }
+    };
+    if let Some(epoch) = elected {
+        fail_over(name, epoch, shared).await;
+    }
+}
+
+// Promotes the best replica of the primary and points the others at it.
+async fn fail_over(name: &str, epoch: u64, shared: &SharedState) {
+    let sentinel = shared.sentinel.as_ref().unwrap();
+    let (former, auth_pass, candidates) = {
+        let state = sentinel.state.lock().unwrap();
+        let master = &state.masters[name];
+        let mut candidates: Vec<(&Addr, &Replica)> = master.replicas.iter()
+            .filter(|(_, replica)| replica.last_reply.elapsed() < REPLICA_STALE && !replica.is_master)
+            .collect();
+        // The one that got the most writes.
+        candidates.sort_by(|(a, a_replica), (b, b_replica)| b_replica.offset.cmp(&a_replica.offset).then(a.cmp(b)));
+        (master.addr.clone(), master.auth_pass.clone(), candidates.into_iter().map(|(addr, _)| addr.clone()).collect::<Vec<_>>())
+    };
+
+    let mut promoted = None;
+    for candidate in candidates {
+        event(shared, "+selected-slave", &instance("slave", name, &candidate));
+        let Ok(mut link) = Link::connect(&candidate, &auth_pass).await else {
+            continue;
+        };
+        if matches!(link.command(&["REPLICAOF", "NO", "ONE"]).await, Ok(RESPValue::SimpleString(_))) {
+            promoted = Some(candidate);
+            break;
+        }
+    }
+    let (promoted, replicas) = {
+        let mut state = sentinel.state.lock().unwrap();
+        let master = state.masters.get_mut(name).unwrap();
+        let Some(promoted) = promoted else {
+            master.failover = None;
+            event(shared, "-failover-abort-no-good-slave", &instance("master", name, &former));
+            return;
+        };
+        event(shared, "+promoted-slave", &instance("slave", name, &promoted));
+        master.switch_to(promoted.clone(), epoch);
+        event(shared, "+switch-master", &format!("{} {} {} {} {}", name, former.0, former.1, promoted.0, promoted.1));
+        (promoted, master.replicas.keys().cloned().collect::<Vec<_>>())
+    };
+
+    // A former primary that's down is pointed at it once it's back, when it's checked.
+    for replica in replicas {
+        if replicate_from(&replica, &promoted, &auth_pass).await {
+            event(shared, "+slave-reconf-sent", &instance("slave", name, &replica));
+        }
+    }
+}
+
+// Points the instance at the primary, returns whether it accepted.
+async fn replicate_from(instance: &Addr, primary: &Addr, auth_pass: &str) -> bool {
+    let Ok(mut link) = Link::connect(instance, auth_pass).await else {
+        return false;
+    };
+    matches!(link.command(&["REPLICAOF", &primary.0, &primary.1.to_string()]).await, Ok(RESPValue::SimpleString(_)))
+}
+
+fn flat(fields: Vec<(&str, String)>) -> RESPValue {
+    RESPValue::Array(fields.into_iter()
+        .flat_map(|(field, value)| [RESPValue::BlobString(field.to_owned().into()), RESPValue::BlobString(value.into())])
+        .collect())
+}
+
+fn master_fields(name: &str, master: &Master) -> RESPValue {
+    let mut flags = vec!["master"];
+    if master.s_down {
+        flags.push("s_down");
+    }
+    if master.o_down {
+        flags.push("o_down");
+    }
+    if master.failover.is_some() {
+        flags.push("failover_in_progress");
+    }
+    flat(vec![
+        ("name", name.to_owned()),
+        ("ip", master.addr.0.clone()),
+        ("port", master.addr.1.to_string()),
+        ("flags", flags.join(",")),
+        ("last-ok-ping-reply", master.last_reply.elapsed().as_millis().to_string()),
+        ("num-slaves", master.replicas.len().to_string()),
+        ("num-other-sentinels", master.sentinels.len().to_string()),
+        ("quorum", master.quorum.to_string()),
+        ("down-after-milliseconds", master.down_after.as_millis().to_string()),
+        ("failover-timeout", master.failover_timeout.as_millis().to_string()),
+        ("config-epoch", master.config_epoch.to_string()),
+    ])
+}
+
+// SENTINEL subcommand [arg ...]
+pub fn sentinel(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let Some(sentinel) = &shared.sentinel else {
+        return Err(RESPError::UnsupportedCommand(command[0].to_string()));
+    };
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("SENTINEL|{}", subcommand)))
+    };
+    let mut state = sentinel.state.lock().unwrap();
+    let State { current_epoch, masters, .. } = &mut *state;
+
+    match subcommand.as_str() {
+        "MYID" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::BlobString(sentinel.run_id.clone().into()))
+        },
+        "MASTERS" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::Array(masters.iter().map(|(name, master)| master_fields(name, master)).collect()))
+        },
+        "MASTER" => {
+            check_arity(command.len() == 3)?;
+            let master = masters.get(command[2].as_str()).ok_or(RESPError::NoSuchMaster)?;
+            Ok(master_fields(&command[2], master))
+        },
+        "GET-MASTER-ADDR-BY-NAME" => {
+            check_arity(command.len() == 3)?;
+            Ok(masters.get(command[2].as_str()).map_or(RESPValue::Null, |master| RESPValue::Array(vec![
+                RESPValue::BlobString(master.addr.0.clone().into()),
+                RESPValue::BlobString(master.addr.1.to_string().into()),
+            ])))
+        },
+        "REPLICAS" | "SLAVES" => {
+            check_arity(command.len() == 3)?;
+            let master = masters.get(command[2].as_str()).ok_or(RESPError::NoSuchMaster)?;
+            Ok(RESPValue::Array(master.replicas.iter().map(|((ip, port), replica)| {
+                let down = replica.last_reply.elapsed() > master.down_after;
+                flat(vec![
+                    ("name", format!("{}:{}", ip, port)),
+                    ("ip", ip.clone()),
+                    ("port", port.to_string()),
+                    ("flags", String::from(if down { "slave,s_down" } else { "slave" })),
+                    ("last-ok-ping-reply", replica.last_reply.elapsed().as_millis().to_string()),
+                    ("master-link-status", String::from(if replica.link_up { "ok" } else { "err" })),
+                    ("master-host", replica.primary.as_ref().map_or_else(String::new, |primary| primary.0.clone())),
+                    ("master-port", replica.primary.as_ref().map_or_else(String::new, |primary| primary.1.to_string())),
+                    ("slave-repl-offset", replica.offset.to_string()),
+                ])
+            }).collect()))
+        },
+        "SENTINELS" => {
+            check_arity(command.len() == 3)?;
+            let master = masters.get(command[2].as_str()).ok_or(RESPError::NoSuchMaster)?;
+            Ok(RESPValue::Array(master.sentinels.iter().map(|(run_id, peer)| flat(vec![
+                ("name", run_id.clone()),
+                ("ip", peer.addr.0.clone()),
+                ("port", peer.addr.1.to_string()),
+                ("runid", run_id.clone()),
+                ("flags", String::from("sentinel")),
+                ("last-hello-message", peer.last_hello.elapsed().as_millis().to_string()),
+                ("voted-leader", peer.leader.as_ref().map_or_else(|| String::from("?"), |(leader, _)| leader.clone())),
+                ("voted-leader-epoch", peer.leader.as_ref().map_or(0, |(_, epoch)| *epoch).to_string()),
+            ])).collect()))
+        },
+        // Asked by the other sentinels, with the ID of the one asking for a vote (or * when only
+        // asking whether the primary is down).
+        "IS-MASTER-DOWN-BY-ADDR" => {
+            check_arity(command.len() == 6)?;
+            let port = command[3].parse::<u16>().map_err(|_| RESPError::NotAnInteger)?;
+            let epoch = command[4].parse::<u64>().map_err(|_| RESPError::NotAnInteger)?;
+            let candidate = &*command[5];
+            let Some(master) = masters.values_mut().find(|master| master.addr.0 == command[2] && master.addr.1 == port) else {
+                return Ok(RESPValue::Array(vec![RESPValue::Number(0), RESPValue::BlobString("*".into()), RESPValue::Number(0)]));
+            };
+            if candidate != "*" && master.vote.as_ref().is_none_or(|(_, voted)| *voted < epoch) {
+                *current_epoch = (*current_epoch).max(epoch);
+                master.vote = Some((candidate.clone(), epoch));
+                if *candidate != sentinel.run_id {
+                    // Leaving the failover to the one voted for.
+                    master.last_failover = Some(Instant::now());
+                }
+            }
+            let (leader, leader_epoch) = master.vote.clone().unwrap_or_else(|| (String::from("*"), 0));
+            Ok(RESPValue::Array(vec![
+                RESPValue::Number(master.s_down as i64),
+                RESPValue::BlobString(leader.into()),
+                RESPValue::Number(leader_epoch as i64),
+            ]))
+        },
+        "FAILOVER" => {
+            check_arity(command.len() == 3)?;
+            let master = masters.get_mut(command[2].as_str()).ok_or(RESPError::NoSuchMaster)?;
+            if master.failover.is_some() {
+                return Err(RESPError::FailoverInProgress);
+            }
+            if !master.replicas.values().any(|replica| replica.last_reply.elapsed() < REPLICA_STALE && !replica.is_master) {
+                return Err(RESPError::NoGoodReplica);
+            }
+            *current_epoch += 1;
+            master.vote = Some((sentinel.run_id.clone(), *current_epoch));
+            master.failover = Some(Failover { epoch: *current_epoch, started: Instant::now(), forced: true });
+            master.last_failover = Some(Instant::now());
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "CKQUORUM" => {
+            check_arity(command.len() == 3)?;
+            let master = masters.get(command[2].as_str()).ok_or(RESPError::NoSuchMaster)?;
+            let usable = 1 + master.sentinels.values().filter(|peer| peer.last_hello.elapsed() < REPLICA_STALE).count();
+            if usable < master.quorum {
+                return Err(RESPError::NoQuorum(format!("{} usable Sentinels. Not enough available Sentinels to reach the specified quorum for this master", usable)));
+            }
+            if usable < master.needed_votes() {
+                return Err(RESPError::NoQuorum(format!("{} usable Sentinels. Not enough available Sentinels to reach the majority and authorize a failover", usable)));
+            }
+            Ok(RESPValue::SimpleString(format!("OK {} usable Sentinels. Quorum and failover authorization can be reached", usable)))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("SENTINEL {}", command[1])))
+    }
+}
+
+// The fields of INFO sentinel.
+pub fn info(shared: &SharedState) -> Vec<(String, String)> {
+    let Some(sentinel) = &shared.sentinel else {
+        return vec![];
+    };
+    let state = sentinel.state.lock().unwrap();
+    let mut fields = vec![(String::from("sentinel_masters"), state.masters.len().to_string())];
+    for (i, (name, master)) in state.masters.iter().enumerate() {
+        let status = if master.o_down { "odown" } else if master.s_down { "sdown" } else { "ok" };
+        fields.push((format!("master{}", i), format!("name={},status={},address={}:{},slaves={},sentinels={}",
+            name, status, master.addr.0, master.addr.1, master.replicas.len(), master.sentinels.len() + 1)));
+    }
+    fields
+}
@@ -0,0 +1,369 @@
+// The server as a library. It's configured like the binary is (a configuration file overridden by
+// directives), and then either runs as the process, like the binary does, or is embedded in an
+// application, serving on a thread and a runtime of its own until shut down. The application can
+// connect to an embedded server, or run commands on it in-process with a LocalClient.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+use tokio::sync::{mpsc::UnboundedReceiver, Notify};
+
+use crate::arg::Arg;
+use crate::backing::Backing;
+use crate::db::Keyspace;
+use crate::plugin::Command;
+use crate::protocol::RESPValue;
+use crate::shutdown::{self, Flags};
+use crate::{backing, check, connect, daemon, disconnect, execute_command, export, load_config, logging, serve, Client, RESPError, SharedState};
+
+/// Configures a [`Server`], see [`Server::builder`].
+#[derive(Default)]
+pub struct Builder {
+    config_file: Option<String>,
+    directives: Vec<Vec<String>>,
+    sentinel: bool,
+    addresses: Vec<String>,
+    backing: Option<Arc<dyn Backing>>,
+    commands: Vec<Arc<dyn Command>>,
+}
+
+impl Builder {
+    /// Reads a redis.conf style configuration file, which the rest of the builder overrides.
+    pub fn config_file(mut self, path: impl Into<String>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Sets a configuration parameter, like a line of the configuration file would.
+    pub fn config(self, name: &str, value: &str) -> Self {
+        self.directive([name, value])
+    }
+
+    /// Adds a line of the configuration file split into its words, like
+    /// `["rename-command", "FLUSHALL", ""]`.
+    pub fn directive<S: Into<String>>(mut self, words: impl IntoIterator<Item = S>) -> Self {
+        self.directives.push(words.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Monitors the primaries of the `sentinel monitor` directives, instead of serving data.
+    pub fn sentinel(mut self, sentinel: bool) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
+
+    /// Listens on the address (like `127.0.0.1:6379`) rather than on the configured `bind` and
+    /// `port`. Port 0 picks a free port, see [`Server::local_addr`]. Can be given several times.
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.addresses.push(address.into());
+        self
+    }
+
+    /// Caches the store, rather than the `backing-upstream` server: keys missing from the keyspace
+    /// are read from it, and writes are forwarded to it.
+    pub fn backing(mut self, backing: impl Backing + 'static) -> Self {
+        self.backing = Some(Arc::new(backing));
+        self
+    }
+
+    /// Adds a command on top of the builtin ones, like the plugins loaded with `loadmodule` do.
+    /// Building fails when there already is a command by that name.
+    ///
+    /// ```
+    /// use bast::plugin::{Command, Database};
+    /// use bast::protocol::RESPValue;
+    ///
+    /// struct Strlen;
+    ///
+    /// impl Command for Strlen {
+    ///     fn name(&self) -> &str {
+    ///         "MYSTRLEN"
+    ///     }
+    ///
+    ///     fn arity(&self) -> i64 {
+    ///         2
+    ///     }
+    ///
+    ///     fn flags(&self) -> &[&'static str] {
+    ///         &["readonly"]
+    ///     }
+    ///
+    ///     fn execute(&self, args: &[String], db: &mut Database<'_>) -> Result<RESPValue, bast::RESPError> {
+    ///         Ok(RESPValue::Number(db.get(&args[0])?.map_or(0, |value| value.len() as i64)))
+    ///     }
+    /// }
+    ///
+    /// let server = bast::Server::builder().config("save", "").command(Strlen).build()?;
+    /// let handle = server.start()?;
+    /// let mut client = handle.client();
+    /// client.command(&["SET", "k", "value"]);
+    /// assert_eq!(client.command(&["mystrlen", "k"]), RESPValue::Number(5));
+    /// handle.shutdown()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn command(mut self, command: impl Command + 'static) -> Self {
+        self.commands.push(Arc::new(command));
+        self
+    }
+
+    /// Loads the configuration and binds the addresses, without serving anything yet.
+    pub fn build(self) -> Result<Server, Box<dyn Error>> {
+        let listeners = self.addresses.iter()
+            .map(|address| TcpListener::bind(address.as_str()).map_err(|e| format!("Failed listening on {}: {}", address, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut directives = self.directives;
+        // The configuration tells the addresses bound, like to INFO and to the protected mode.
+        if !listeners.is_empty() {
+            let addrs = listeners.iter().map(TcpListener::local_addr).collect::<std::io::Result<Vec<_>>>()?;
+            let ips: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+            directives.push(vec![String::from("bind"), ips.join(" ")]);
+            directives.push(vec![String::from("port"), addrs[0].port().to_string()]);
+        }
+
+        let mut shared = SharedState::new();
+        load_config(self.config_file, directives, self.sentinel, &mut shared)?;
+        for command in self.commands {
+            shared.commands.get_mut().unwrap().add(command).map_err(|e| e.to_string())?;
+        }
+        logging::init(&shared);
+        shared.db = Keyspace::new(shared.config.get_int("keyspace-shards") as usize);
+        shared.backing = self.backing.or_else(|| backing::configured(&shared));
+        Ok(Server { shared, listeners })
+    }
+}
+
+/// A bast server.
+///
+/// An application (or an integration test) can run one in-process:
+///
+/// ```
+/// use std::io::{Read, Write};
+///
+/// let server = bast::Server::builder().bind("127.0.0.1:0").config("save", "").build()?;
+/// let handle = server.start()?;
+///
+/// let mut client = std::net::TcpStream::connect(handle.local_addr().unwrap())?;
+/// client.write_all(b"*1\r\n$4\r\nPING\r\n")?;
+/// let mut reply = [0; 7];
+/// client.read_exact(&mut reply)?;
+/// assert_eq!(&reply, b"+PONG\r\n");
+///
+/// handle.shutdown()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// The servers of a process share the limits of encodings and of the protocol, and the messages
+/// are logged by the `tracing` subscriber of the application when it has one, otherwise by the one
+/// of the first server.
+pub struct Server {
+    shared: SharedState,
+    listeners: Vec<TcpListener>,
+}
+
+// The runtime serving clients, with `threads` workers (one per core when 0).
+fn runtime(shared: &SharedState) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    let threads = shared.config.get_int("threads") as usize;
+    if threads > 0 {
+        runtime.worker_threads(threads);
+    }
+    runtime.enable_all().build()
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// The address of the first listener of [`Builder::bind`], with the port that was picked.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listeners.first().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Runs the server as the process, like the `bast` binary: daemonizing when configured, handling
+    /// signals, and exiting the process once shut down.
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        // Forking before the runtime starts any thread.
+        if self.shared.config.get_bool("daemonize") {
+            daemon::daemonize().map_err(|e| format!("Failed daemonizing: {}", e))?;
+        }
+        daemon::write_pidfile(&self.shared);
+        runtime(&self.shared)?.block_on(serve(self.shared, self.listeners, None))
+    }
+
+    /// Checks a snapshot file of either format (like `bast --check-dump`) by loading it, rather than
+    /// serving anything. Returns what's in it, or where it's corrupted.
+    pub fn check_dump(&self, path: &str) -> Result<String, Box<dyn Error>> {
+        check::dump(path, &self.shared).map_err(Into::into)
+    }
+
+    /// Checks an append only file (like `bast --check-aof`) rather than serving anything. Returns how
+    /// many commands it has, or where it's corrupted. With `fix`, a corrupted file is truncated to its
+    /// last valid command, and what was cut is returned.
+    pub fn check_aof(&self, path: &str, fix: bool) -> Result<String, Box<dyn Error>> {
+        check::aof(path, fix, &self.shared).map_err(Into::into)
+    }
+
+    /// Writes the keys of the dataset (like `bast --export`) matching the glob-style pattern, all of
+    /// them without one, in the `json` or `csv` format, rather than serving anything. Returns how
+    /// many keys were written.
+    pub fn export(&self, out: &mut dyn Write, pattern: Option<&str>, format: &str) -> Result<usize, Box<dyn Error>> {
+        export::export(out, pattern, export::Format::parse(format)?, &self.shared).map_err(Into::into)
+    }
+
+    /// Imports the keys of a file of the `json` or `csv` format of [`Server::export`] ("-" being
+    /// stdin) into the dataset (like `bast --import`) and saves the snapshot. Returns how many keys
+    /// were imported.
+    pub fn import(&self, path: &str, format: &str) -> Result<usize, Box<dyn Error>> {
+        export::import(path, export::Format::parse(format)?, &self.shared).map_err(Into::into)
+    }
+
+    /// Serves on a thread and a runtime of its own, once the dataset was loaded. The signals are
+    /// left to the application, and the server runs until [`Handle::shutdown`] or `SHUTDOWN`.
+    pub fn start(mut self) -> Result<Handle, Box<dyn Error>> {
+        self.shared.stop = Some(Notify::new());
+        let local_addr = self.local_addr();
+        let runtime = runtime(&self.shared)?;
+        let (sender, ready) = mpsc::channel();
+        let thread = std::thread::Builder::new().name(String::from("bast")).spawn(move || {
+            let result = runtime.block_on(serve(self.shared, self.listeners, Some(sender))).map_err(|e| e.to_string());
+            // The connections left are dropped along with the runtime.
+            runtime.shutdown_background();
+            result
+        })?;
+
+        match ready.recv() {
+            Ok((shared, runtime)) => Ok(Handle { shared, runtime, thread, local_addr }),
+            // It failed before serving, like loading the dataset.
+            Err(_) => Err(match thread.join() {
+                Ok(Err(e)) => e.into(),
+                _ => "The server stopped before serving".into()
+            })
+        }
+    }
+}
+
+/// A server started by [`Server::start`]. Dropping it leaves the server running.
+pub struct Handle {
+    shared: Arc<SharedState>,
+    runtime: tokio::runtime::Handle,
+    thread: JoinHandle<Result<(), String>>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl Handle {
+    /// The address of the first listener of [`Builder::bind`].
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// A new client running commands on the server in-process.
+    pub fn client(&self) -> LocalClient {
+        let (push_sender, pushes) = tokio::sync::mpsc::unbounded_channel();
+        let id = self.shared.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let client = connect(id, String::new(), String::new(), push_sender, &self.shared);
+        LocalClient { client: Some(client), shared: self.shared.clone(), runtime: self.runtime.clone(), pushes, pending: VecDeque::new() }
+    }
+
+    /// Shuts the server down like `SHUTDOWN` does (waiting for lagging replicas, fsyncing the AOF
+    /// and saving a final snapshot when there are save points) and waits for it to stop. When that
+    /// fails the server is stopped anyway, and the error is returned.
+    pub fn shutdown(self) -> Result<(), Box<dyn Error>> {
+        let shared = self.shared.clone();
+        // A client may have shut it down already.
+        let result = match self.thread.is_finished() {
+            true => Ok(()),
+            false => futures::executor::block_on(self.runtime.spawn(async move {
+                shutdown::shutdown(&shared, Flags::default()).await.map_err(|e| e.to_string())
+            })).unwrap_or_else(|e| match e.is_cancelled() {
+                // It was stopping already.
+                true => Ok(()),
+                false => Err(e.to_string())
+            })
+        };
+        if result.is_err() {
+            let _ = shutdown::exit(&self.shared, Flags { save: Some(false), now: true, force: true });
+        }
+
+        let stopped = self.thread.join().map_err(|_| String::from("The server panicked"))?;
+        result.and(stopped).map_err(Into::into)
+    }
+}
+
+/// A client of a server started by [`Server::start`], running commands right on the server rather
+/// than through a connection, so there's no socket and no encoding of requests and replies. Other
+/// than that it's like a connection: it has its own state (like its transaction, its subscriptions
+/// and its user), shows in `CLIENT LIST`, and is local like the clients of the unix socket.
+///
+/// ```
+/// use bast::protocol::RESPValue;
+///
+/// let handle = bast::Server::builder().config("port", "0").config("save", "").build()?.start()?;
+/// let mut client = handle.client();
+/// assert_eq!(client.command(&["SET", "key", "value"]), RESPValue::SimpleString(String::from("OK")));
+/// assert_eq!(client.command(&["GET", "key"]), RESPValue::BlobString("value".into()));
+/// drop(client);
+/// handle.shutdown()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct LocalClient {
+    // Taken while a command runs on the server.
+    client: Option<Client>,
+    shared: Arc<SharedState>,
+    runtime: tokio::runtime::Handle,
+    pushes: UnboundedReceiver<RESPValue>,
+    // The replies after the first of commands replying more than once, like SUBSCRIBE.
+    pending: VecDeque<RESPValue>,
+}
+
+impl LocalClient {
+    /// Runs the command, like `["SET", "key", "value"]`, and returns its reply (errors being
+    /// [`RESPValue::SimpleError`]). Blocks the thread until the command is done, including while a
+    /// blocking command waits. The replies after the first of commands replying more than once
+    /// (like SUBSCRIBE) are returned by [`LocalClient::try_next_push`].
+    pub fn command<S: AsRef<[u8]>>(&mut self, command: &[S]) -> RESPValue {
+        let command: Vec<Arg> = command.iter().map(|arg| Arg::from_bytes(arg.as_ref().to_vec())).collect();
+        if command.is_empty() {
+            return RESPError::UnsupportedCommand(String::new()).into();
+        }
+        // Lost when the server stopped in the middle of a command.
+        let Some(client) = self.client.take() else {
+            return RESPError::ServerStopped.into();
+        };
+
+        logging::command(&self.shared, &command);
+        client.info.state.lock().unwrap().last_command = command[0].to_ascii_lowercase();
+        let shared = self.shared.clone();
+        let executed = futures::executor::block_on(self.runtime.spawn(async move { execute_command(command, client, &shared).await }));
+        let Ok((mut client, replies)) = executed else {
+            return RESPError::ServerStopped.into();
+        };
+        client.sync_info();
+        let replied = client.reply_mode.next_reply();
+        self.client = Some(client);
+
+        let mut replies = replies.into_iter().filter(|_| replied);
+        let reply = replies.next().unwrap_or(RESPValue::Null);
+        self.pending.extend(replies);
+        reply
+    }
+
+    /// The next message pushed to the client (like a message of a channel it subscribed to), if
+    /// there is one already.
+    pub fn try_next_push(&mut self) -> Option<RESPValue> {
+        self.pending.pop_front().or_else(|| self.pushes.try_recv().ok())
+    }
+}
+
+impl Drop for LocalClient {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            disconnect(&mut client, &self.shared);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// Shutting down gracefully, on SIGTERM and SIGINT or with SHUTDOWN. Unless shutting down now, writes are paused and
+// the server waits up to `shutdown-timeout` seconds for the script in progress to finish and for the
+// replicas to acknowledge every write. Then the AOF is fsynced and a final snapshot is saved (when
+// there are save points, or when asked to), and the process exits (or an embedded server stops serving).
+// When any of that fails the server carries on serving instead, unless forced to exit anyway.
+
+use std::time::Duration;
+
+use crate::arg::Arg;
+use crate::{daemon, logging, replication, snapshot, systemd, RESPError, SharedState};
+
+#[derive(Clone, Copy, Default)]
+pub struct Flags {
+    // Whether to save a snapshot, by default when there are save points.
+    pub save: Option<bool>,
+    // Exits without waiting for scripts and replicas.
+    pub now: bool,
+    // Exits even when the final save or fsync fails.
+    pub force: bool,
+}
+
+// A combination of save or nosave, now and force.
+pub fn parse_flags(words: &[&str]) -> Option<Flags> {
+    let mut flags = Flags::default();
+    for word in words {
+        match word.to_ascii_lowercase().as_str() {
+            "save" if flags.save.is_none() => flags.save = Some(true),
+            "nosave" if flags.save.is_none() => flags.save = Some(false),
+            "now" => flags.now = true,
+            "force" => flags.force = true,
+            _ => return None
+        }
+    }
+    Some(flags)
+}
+
+// `shutdown-on-sigterm` and `shutdown-on-sigint`: default, or flags to shut down with.
+pub fn parse_signal_flags(value: &str) -> Option<String> {
+    let words: Vec<String> = value.split_whitespace().map(str::to_ascii_lowercase).collect();
+    match &words[..] {
+        [] => None,
+        [word] if word == "default" => Some(word.to_owned()),
+        _ => parse_flags(&words.iter().map(String::as_str).collect::<Vec<_>>()).map(|_| words.join(" "))
+    }
+}
+
+// Exits the process, or returns the error that kept it from doing so. Embedded servers return once
+// they stop serving instead.
+pub async fn shutdown(shared: &SharedState, flags: Flags) -> Result<(), RESPError> {
+    // Load balancers stop sending traffic while the replicas catch up.
+    shared.health.set_ready(false);
+    if !flags.now {
+        let timeout = Duration::from_secs(shared.config.get_int("shutdown-timeout") as u64);
+        let deadline = tokio::time::Instant::now() + timeout;
+        shared.clients.pause(timeout, false);
+        let threshold = shared.script_monitor.running_for().unwrap_or_default() + timeout;
+        if shared.script_monitor.wait(threshold).await {
+            logging::log(shared, "warning", "Shutting down while a script is still running");
+        }
+        if !replication::wait_replicas(shared, deadline).await {
+            logging::log(shared, "warning", "Lagging replicas didn't catch up before shutting down");
+        }
+    }
+
+    exit(shared, flags)
+}
+
+// Exits right away, or returns the error that kept it from doing so.
+pub fn exit(shared: &SharedState, flags: Flags) -> Result<(), RESPError> {
+    if let Err(e) = finish(shared, flags) {
+        shared.clients.unpause();
+        shared.health.set_ready(true);
+        return Err(e);
+    }
+    if let Some(stop) = &shared.stop {
+        logging::log(shared, "warning", "Bast is now ready to stop, bye bye...");
+        stop.notify_one();
+        return Ok(());
+    }
+    let _ = systemd::notify("STOPPING=1");
+    daemon::remove_pidfile(shared);
+    logging::log(shared, "warning", "Bast is now ready to exit, bye bye...");
+    std::process::exit(0);
+}
+
+fn finish(shared: &SharedState, flags: Flags) -> Result<(), RESPError> {
+    if let Err(e) = shared.aof.fsync() {
+        logging::log(shared, "warning", format!("Error fsyncing the AOF file on shutdown: {}", e));
+        if !flags.force {
+            return Err(RESPError::ShutdownFailed);
+        }
+    }
+    let save = flags.save.unwrap_or_else(|| !shared.config.get("save").is_empty());
+    if save && shared.sentinel.is_none() {
+        logging::log(shared, "notice", "Saving the final snapshot before exiting.");
+        if let Err(e) = snapshot::save(shared) {
+            logging::log(shared, "warning", format!("Error trying to save the DB, can't exit: {}", e));
+            if !flags.force {
+                return Err(RESPError::ShutdownFailed);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Shuts down with the flags of `shutdown-on-sigterm` or `shutdown-on-sigint`.
+pub async fn on_signal(shared: &SharedState, signal: &str) {
+    logging::log(shared, "warning", format!("Received {} scheduling shutdown...", signal));
+    let value = shared.config.get(&format!("shutdown-on-{}", signal.to_ascii_lowercase()));
+    let words: Vec<&str> = value.split(' ').filter(|word| *word != "default").collect();
+    if shutdown(shared, parse_flags(&words).unwrap()).await.is_ok() {
+        return;
+    }
+    logging::log(shared, "warning", format!("{} received but errors trying to shut down the server, check the logs for more information", signal));
+}
+
+fn command_flags(command: &[Arg]) -> Result<Flags, RESPError> {
+    parse_flags(&command[1..].iter().map(|arg| arg.as_str()).collect::<Vec<_>>()).ok_or(RESPError::SyntaxError)
+}
+
+// SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE], which doesn't reply when shutting down.
+pub async fn command(command: &[Arg], shared: &SharedState) -> Result<(), RESPError> {
+    let flags = command_flags(command)?;
+    logging::log(shared, "warning", "User requested shutdown...");
+    shutdown(shared, flags).await
+}
+
+// SHUTDOWN run by a transaction, which can't wait for anything.
+pub fn command_now(command: &[Arg], shared: &SharedState) -> Result<(), RESPError> {
+    let flags = command_flags(command)?;
+    logging::log(shared, "warning", "User requested shutdown...");
+    exit(shared, flags)
+}
@@ -0,0 +1,679 @@
+// Point-in-time snapshots of the keyspace and the function libraries, saved to `dbfilename` in
+// `dir` by SAVE, BGSAVE and the save points, and loaded back on startup. A rewrite of the append
+// only file starts it with a snapshot as well.
+//
+// The file is the magic and format version, followed by opcodes: the database index, keys (with
+// their expire time when they have one) and function libraries, then an end opcode and the CRC-64
+// of everything before it. Lengths are LEB128 varints and numbers are little endian.
+//
+// With `snapshot-format rdb` snapshots are written as Redis RDB files instead, and either kind of
+// file is loaded on startup. Where they are kept is up to `snapshot-storage` (see storage.rs), and
+// they may be encrypted (see encryption.rs). The full sync of a replica is a snapshot as well, which
+// is streamed to it rather than kept (see replication.rs).
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, Notify};
+
+use crate::arg::Arg;
+use crate::bloom::Bloom;
+use crate::cuckoo::Cuckoo;
+use crate::db::{now_ms, SavedKey, Value};
+use crate::hash::Hash;
+use crate::list::List;
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use crate::storage::{self, Disk, Storage, Upload};
+use crate::{aof, crc64, encryption, logging, otel, rdb, replication, RESPError, RESPValue, SharedState};
+
+pub const FORMATS: &[&str] = &["bast", "rdb"];
+
+const MAGIC: &[u8] = b"BAST";
+const VERSION: u8 = 1;
+
+const OP_FUNCTION: u8 = 0xf5;
+const OP_EXPIRETIME_MS: u8 = 0xfc;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_ZSET: u8 = 2;
+const TYPE_STREAM: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_BLOOM: u8 = 5;
+const TYPE_CUCKOO: u8 = 6;
+const TYPE_JSON: u8 = 7;
+
+// Keys handed from the keyspace to the writer of a background save at a time.
+const SAVE_CHUNK: usize = 128;
+// Seconds to wait before trying a save point again after a background save failed.
+const SAVE_RETRY_DELAY: u64 = 5;
+
+fn corrupted(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, n: f64) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn len(&mut self, mut len: usize) {
+        loop {
+            let byte = (len & 0x7f) as u8;
+            len >>= 7;
+            if len == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(corrupted("unexpected end of file"));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn len(&mut self) -> io::Result<usize> {
+        let mut len = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.u8()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(len);
+            }
+        }
+        Err(corrupted("invalid length"))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| corrupted("invalid string"))
+    }
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) | Value::CompressedString(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::SortedSet(_) => TYPE_ZSET,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) => TYPE_STREAM,
+        Value::Bloom(_) => TYPE_BLOOM,
+        Value::Cuckoo(_) => TYPE_CUCKOO,
+        Value::Json(_) => TYPE_JSON,
+    }
+}
+
+fn encode_value(value: &Value, encoder: &mut Encoder) {
+    match value {
+        Value::String(_) | Value::CompressedString(_) => encoder.bytes(&value.as_string().unwrap()),
+        Value::List(list) => {
+            encoder.len(list.len());
+            for element in list.iter() {
+                encoder.bytes(element);
+            }
+        },
+        Value::SortedSet(set) => {
+            encoder.len(set.len());
+            for (member, score) in set.iter() {
+                encoder.str(member);
+                encoder.f64(score);
+            }
+        },
+        // Every field with its value and expire time, 0 when it has none.
+        Value::Hash(hash) => {
+            encoder.len(hash.len());
+            for (field, value, expire) in hash.iter() {
+                encoder.str(field);
+                encoder.bytes(value);
+                encoder.u64(expire.unwrap_or(0));
+            }
+        },
+        Value::Stream(stream) => stream.encode(encoder),
+        Value::Bloom(bloom) => bloom.encode(encoder),
+        Value::Cuckoo(cuckoo) => cuckoo.encode(encoder),
+        // As JSON text.
+        Value::Json(json) => encoder.str(&json.to_string()),
+    }
+}
+
+fn decode_value(value_type: u8, decoder: &mut Decoder) -> io::Result<Value> {
+    Ok(match value_type {
+        TYPE_STRING => Value::string(decoder.bytes()?.to_vec()),
+        TYPE_LIST => {
+            let len = decoder.len()?;
+            Value::List((0..len).map(|_| decoder.bytes().map(<[u8]>::to_vec)).collect::<io::Result<List>>()?)
+        },
+        TYPE_ZSET => {
+            let mut set = SortedSet::default();
+            for _ in 0..decoder.len()? {
+                let member = decoder.string()?;
+                set.insert(member, decoder.f64()?);
+            }
+            Value::SortedSet(set)
+        },
+        TYPE_HASH => {
+            let mut hash = Hash::default();
+            for _ in 0..decoder.len()? {
+                let field = decoder.string()?;
+                hash.insert(field.clone(), Bytes::copy_from_slice(decoder.bytes()?));
+                match decoder.u64()? {
+                    0 => {},
+                    at => {
+                        hash.set_expire(&field, at);
+                    }
+                }
+            }
+            Value::Hash(hash)
+        },
+        TYPE_STREAM => Value::Stream(Stream::decode(decoder)?),
+        TYPE_BLOOM => Value::Bloom(Bloom::decode(decoder)?),
+        TYPE_CUCKOO => Value::Cuckoo(Cuckoo::decode(decoder)?),
+        TYPE_JSON => Value::Json(serde_json::from_slice(decoder.bytes()?).map_err(|_| corrupted("invalid JSON"))?),
+        _ => return Err(corrupted("unknown value type"))
+    })
+}
+
+// Writes a snapshot to the storage, where it replaces the stored one only once it's complete.
+struct SnapshotWriter {
+    upload: Box<dyn Upload>,
+    rdb: bool,
+    crc: u64,
+    encoder: Encoder,
+}
+
+impl SnapshotWriter {
+    fn create(storage: &dyn Storage, name: &str, format: &str) -> io::Result<Self> {
+        Self::new(encryption::encrypting(storage.upload(name)?)?, format)
+    }
+
+    fn new(upload: Box<dyn Upload>, format: &str) -> io::Result<Self> {
+        let rdb = format == "rdb";
+        let mut writer = Self { upload, rdb, crc: 0, encoder: Encoder::default() };
+        if rdb {
+            rdb::write_header(&mut writer.encoder.buf);
+        } else {
+            writer.encoder.buf.extend_from_slice(MAGIC);
+            writer.encoder.u8(VERSION);
+            writer.encoder.u8(OP_SELECTDB);
+            writer.encoder.len(0);
+        }
+        writer.flush_encoder()?;
+        Ok(writer)
+    }
+
+    fn flush_encoder(&mut self) -> io::Result<()> {
+        self.crc = crc64::update(self.crc, &self.encoder.buf);
+        self.upload.write_all(&self.encoder.buf)?;
+        self.encoder.buf.clear();
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str, value: &Value, expire: Option<u64>) -> io::Result<()> {
+        if self.rdb {
+            rdb::write_key(&mut self.encoder.buf, key, value, expire);
+            return self.flush_encoder();
+        }
+        if let Some(at) = expire {
+            self.encoder.u8(OP_EXPIRETIME_MS);
+            self.encoder.u64(at);
+        }
+        self.encoder.u8(value_type(value));
+        self.encoder.str(key);
+        encode_value(value, &mut self.encoder);
+        self.flush_encoder()
+    }
+
+    fn finish(mut self, libraries: &[String]) -> io::Result<()> {
+        for code in libraries {
+            if self.rdb {
+                rdb::write_function(&mut self.encoder.buf, code);
+            } else {
+                self.encoder.u8(OP_FUNCTION);
+                self.encoder.str(code);
+            }
+        }
+        if self.rdb {
+            rdb::write_eof(&mut self.encoder.buf);
+        } else {
+            self.encoder.u8(OP_EOF);
+        }
+        self.flush_encoder()?;
+        self.upload.write_all(&self.crc.to_le_bytes())?;
+        self.upload.commit()
+    }
+}
+
+// What a background save is written for.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Purpose {
+    Snapshot,
+    AofRewrite,
+    // Streamed to replicas, see replication.rs.
+    FullSync,
+}
+
+// The state of the saves, as reported by LASTSAVE and INFO persistence.
+pub struct Snapshots {
+    // Notified when a background save was started, for the saver task to write it.
+    requested: Notify,
+    in_progress: AtomicBool,
+    purpose: Mutex<Purpose>,
+    // BGSAVE SCHEDULE asked for a save while one was in progress.
+    scheduled: AtomicBool,
+    // The function libraries when the background save in progress started.
+    libraries: Mutex<Vec<String>>,
+    started: Mutex<Option<Instant>>,
+    // The writes count of the keyspace when the background save in progress started.
+    started_writes: AtomicU64,
+    // The writes count of the keyspace as of the last successful save.
+    saved_writes: AtomicU64,
+    // Unix time in seconds of the last successful save, and of the last background save attempt.
+    last_save: AtomicU64,
+    last_bgsave_try: AtomicU64,
+    last_bgsave_ok: AtomicBool,
+    last_bgsave_seconds: AtomicI64,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self {
+            requested: Notify::new(),
+            in_progress: AtomicBool::new(false),
+            purpose: Mutex::new(Purpose::Snapshot),
+            scheduled: AtomicBool::new(false),
+            libraries: Mutex::new(vec![]),
+            started: Mutex::new(None),
+            started_writes: AtomicU64::new(0),
+            saved_writes: AtomicU64::new(0),
+            last_save: AtomicU64::new(now_ms() / 1000),
+            last_bgsave_try: AtomicU64::new(0),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_seconds: AtomicI64::new(-1),
+        }
+    }
+}
+
+impl Snapshots {
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+
+    // Whether a background save of the snapshot file is in progress.
+    pub fn saving(&self) -> bool {
+        self.in_progress() && *self.purpose.lock().unwrap() == Purpose::Snapshot
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_seconds(&self) -> i64 {
+        self.last_bgsave_seconds.load(Ordering::Relaxed)
+    }
+
+    // The writes to the keyspace since the last successful save.
+    pub fn changes_since_save(&self, shared: &SharedState) -> u64 {
+        shared.db.lock().writes() - self.saved_writes.load(Ordering::Relaxed)
+    }
+
+    // Counts the writes of loading the snapshot as saved.
+    pub fn loaded(&self, shared: &SharedState) {
+        self.saved_writes.store(shared.db.lock().writes(), Ordering::Relaxed);
+    }
+
+    fn saved(&self, writes: u64) {
+        self.saved_writes.store(writes, Ordering::Relaxed);
+        self.last_save.store(now_ms() / 1000, Ordering::Relaxed);
+    }
+}
+
+fn library_codes(shared: &SharedState) -> Vec<String> {
+    shared.libraries.lock().unwrap().values().map(|library| library.code.clone()).collect()
+}
+
+// SAVE
+pub fn save(shared: &SharedState) -> Result<RESPValue, RESPError> {
+    if shared.snapshots.in_progress() {
+        return Err(RESPError::BackgroundSaveInProgress);
+    }
+    let path = shared.config.get("dbfilename");
+    let format = shared.config.get("snapshot-format");
+    let libraries = library_codes(shared);
+
+    let db = shared.db.lock();
+    let mut writer = SnapshotWriter::create(&*storage::configured(shared), &path, &format)?;
+    for (key, value) in db.iter() {
+        writer.key(key, &value, db.expire_time(key))?;
+    }
+    writer.finish(&libraries)?;
+    shared.snapshots.saved(db.writes());
+    drop(db);
+
+    logging::log(shared, "notice", "DB saved on disk");
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// BGSAVE [SCHEDULE]
+pub fn bgsave(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let schedule = match command.get(1) {
+        Some(arg) if arg.eq_ignore_ascii_case("SCHEDULE") => true,
+        Some(_) => return Err(RESPError::SyntaxError),
+        None => false
+    };
+    if start_background_save(shared, Purpose::Snapshot) {
+        return Ok(RESPValue::SimpleString(String::from("Background saving started")));
+    }
+    if !schedule {
+        return Err(RESPError::BackgroundSaveInProgress);
+    }
+    shared.snapshots.scheduled.store(true, Ordering::Relaxed);
+    Ok(RESPValue::SimpleString(String::from("Background saving scheduled")))
+}
+
+// LASTSAVE
+pub fn lastsave(shared: &SharedState) -> Result<RESPValue, RESPError> {
+    Ok(RESPValue::Number(shared.snapshots.last_save() as i64))
+}
+
+// Freezes the keyspace as it is right now for the saver task to write, to the snapshot file, to
+// a rewrite of the append only file or to replicas. False when a background save is already in
+// progress.
+pub fn start_background_save(shared: &SharedState, purpose: Purpose) -> bool {
+    let snapshots = &shared.snapshots;
+    if snapshots.in_progress() {
+        return false;
+    }
+    let mut db = shared.db.lock();
+    db.freeze();
+    snapshots.in_progress.store(true, Ordering::Relaxed);
+    *snapshots.purpose.lock().unwrap() = purpose;
+    snapshots.started_writes.store(db.writes(), Ordering::Relaxed);
+    // Along with freezing, so that every write is either in the snapshot or after it.
+    match purpose {
+        Purpose::AofRewrite => shared.aof.rewrite_started(),
+        Purpose::FullSync => shared.replication.sync_started(),
+        Purpose::Snapshot => {}
+    }
+    drop(db);
+
+    *snapshots.libraries.lock().unwrap() = library_codes(shared);
+    *snapshots.started.lock().unwrap() = Some(Instant::now());
+    match purpose {
+        Purpose::AofRewrite => logging::log(shared, "notice", "Background append only file rewriting started"),
+        Purpose::FullSync => {},
+        Purpose::Snapshot => {
+            snapshots.last_bgsave_try.store(now_ms() / 1000, Ordering::Relaxed);
+            logging::log(shared, "notice", "Background saving started");
+        }
+    }
+    snapshots.requested.notify_one();
+    true
+}
+
+// Whether a save point (`save <seconds> <changes>` pairs) was reached, or a save was scheduled.
+fn save_due(shared: &SharedState) -> bool {
+    let snapshots = &shared.snapshots;
+    if snapshots.scheduled.swap(false, Ordering::Relaxed) {
+        return true;
+    }
+    let now = now_ms() / 1000;
+    if !snapshots.last_bgsave_ok() && now < snapshots.last_bgsave_try.load(Ordering::Relaxed) + SAVE_RETRY_DELAY {
+        return false;
+    }
+    let changes = snapshots.changes_since_save(shared);
+    let elapsed = now.saturating_sub(snapshots.last_save());
+    let points: Vec<u64> = shared.config.get("save").split_whitespace().map(|n| n.parse().unwrap()).collect();
+    points.chunks(2).any(|point| elapsed >= point[0] && changes >= point[1])
+}
+
+// Writes the background saves, handing the frozen keys over to a writer thread a chunk at a time
+// so commands keep being served meanwhile.
+pub async fn saver(shared: Arc<SharedState>) {
+    loop {
+        shared.snapshots.requested.notified().await;
+        background_save(&shared).await;
+    }
+}
+
+// Starts the full sync of replicas waiting for one, a save every time a save point is reached, and a
+// rewrite of the append only file when it's due. Run by the cron every second.
+pub fn cron(shared: &Arc<SharedState>) {
+    if shared.snapshots.in_progress() {
+        return;
+    }
+    if replication::sync_due(shared) {
+        start_background_save(shared, Purpose::FullSync);
+    } else if aof::rewrite_due(shared) {
+        start_background_save(shared, Purpose::AofRewrite);
+    } else if save_due(shared) {
+        start_background_save(shared, Purpose::Snapshot);
+    }
+}
+
+async fn background_save(shared: &SharedState) {
+    let snapshots = &shared.snapshots;
+    let purpose = *snapshots.purpose.lock().unwrap();
+    // Rewrites of the append only file are kept in `dir` along with it, and full syncs aren't kept.
+    let (upload, path) = match purpose {
+        Purpose::Snapshot => {
+            let path = shared.config.get("dbfilename");
+            (storage::configured(shared).upload(&path).and_then(encryption::encrypting), path)
+        },
+        Purpose::AofRewrite => {
+            let path = aof::rewrite_path();
+            (Disk.upload(&path).and_then(encryption::encrypting), path)
+        },
+        Purpose::FullSync => (Ok(shared.replication.sync_upload()), String::new())
+    };
+    let mut span = otel::start(shared, match purpose {
+        Purpose::Snapshot => "BGSAVE",
+        Purpose::AofRewrite => "BGREWRITEAOF",
+        Purpose::FullSync => "FULL SYNC"
+    });
+    let format = shared.config.get("snapshot-format");
+    let libraries = std::mem::take(&mut *snapshots.libraries.lock().unwrap());
+    let (sender, mut receiver) = mpsc::channel::<Vec<SavedKey>>(16);
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut writer = SnapshotWriter::new(upload?, &format)?;
+        while let Some(chunk) = receiver.blocking_recv() {
+            for (key, value, expire) in chunk {
+                writer.key(&key, &value, expire)?;
+            }
+        }
+        writer.finish(&libraries)
+    });
+
+    let mut keys = 0;
+    loop {
+        let chunk = shared.db.lock().next_frozen(SAVE_CHUNK);
+        keys += chunk.len();
+        if chunk.is_empty() || sender.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+    let result = writer.await.unwrap();
+    shared.db.lock().thaw();
+    otel::set(&mut span, "bast.keys", keys);
+    otel::end(span, result.as_ref().err(), shared);
+
+    let started = snapshots.started.lock().unwrap().take().unwrap();
+    match purpose {
+        Purpose::AofRewrite => {
+            shared.aof.rewrite_done(result, &path, started.elapsed().as_secs() as i64, shared);
+            snapshots.in_progress.store(false, Ordering::Relaxed);
+            return;
+        },
+        Purpose::FullSync => {
+            match result {
+                Ok(()) => logging::log(shared, "notice", "Background RDB transfer terminated with success"),
+                Err(e) => logging::log(shared, "warning", format!("Background transfer error: {}", e))
+            }
+            snapshots.in_progress.store(false, Ordering::Relaxed);
+            return;
+        },
+        Purpose::Snapshot => {}
+    }
+    snapshots.last_bgsave_seconds.store(started.elapsed().as_secs() as i64, Ordering::Relaxed);
+    snapshots.last_bgsave_ok.store(result.is_ok(), Ordering::Relaxed);
+    match result {
+        Ok(()) => {
+            snapshots.saved(snapshots.started_writes.load(Ordering::Relaxed));
+            logging::log(shared, "notice", "Background saving terminated with success");
+        },
+        Err(e) => logging::log(shared, "warning", format!("Background saving error: {}", e))
+    }
+    snapshots.in_progress.store(false, Ordering::Relaxed);
+}
+
+// Loads the snapshot file (or an RDB file) into the (empty) keyspace on startup, returning the
+// amount of keys loaded or None when there is no snapshot file. Keys that expired since are skipped.
+pub fn load(shared: &SharedState) -> io::Result<Option<usize>> {
+    let data = match storage::configured(shared).download(&shared.config.get("dbfilename"))? {
+        Some(data) => data,
+        None => return Ok(None)
+    };
+    let data = if encryption::is_encrypted(&data) {
+        let decrypted = encryption::decrypt(&data)?;
+        if !decrypted.is_complete() {
+            return Err(corrupted("unexpected end of file"));
+        }
+        decrypted.data
+    } else {
+        data
+    };
+    if !is_snapshot(&data) {
+        return Err(corrupted("not a snapshot file"));
+    }
+    let (loaded, size) = load_from(&data, shared)?;
+    if size != data.len() {
+        return Err(corrupted("data after the end of the snapshot"));
+    }
+    Ok(Some(loaded))
+}
+
+pub fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(rdb::MAGIC)
+}
+
+// Loads the snapshot (of either format) the data starts with, returning the amount of keys loaded
+// and the size of the snapshot, as more may follow it (like in the append only file).
+pub fn load_from(data: &[u8], shared: &SharedState) -> io::Result<(usize, usize)> {
+    if data.starts_with(rdb::MAGIC) {
+        return rdb::load(data, &mut shared.db.lock(), shared);
+    }
+    if data.len() < MAGIC.len() + 1 {
+        return Err(corrupted("unexpected end of file"));
+    }
+    if data[MAGIC.len()] != VERSION {
+        return Err(corrupted("unsupported snapshot version"));
+    }
+
+    let mut decoder = Decoder { buf: &data[MAGIC.len() + 1..] };
+    let loaded = load_records(&mut decoder, shared)
+        .map_err(|e| corrupted(&format!("{} at offset {}", e, data.len() - decoder.buf.len())))?;
+    let end = data.len() - decoder.buf.len();
+    if crc64::update(0, &data[..end]) != decoder.u64()? {
+        return Err(corrupted("wrong checksum"));
+    }
+    Ok((loaded, end + 8))
+}
+
+// Loads the records of a snapshot up to its EOF opcode, returning the amount of keys loaded.
+fn load_records(decoder: &mut Decoder, shared: &SharedState) -> io::Result<usize> {
+    let mut db = shared.db.lock();
+    let now = now_ms();
+    let mut loaded = 0;
+    let mut expire = None;
+    loop {
+        match decoder.u8()? {
+            OP_SELECTDB => {
+                if decoder.len()? != 0 {
+                    return Err(corrupted("unsupported database index"));
+                }
+            },
+            OP_EXPIRETIME_MS => expire = Some(decoder.u64()?),
+            OP_FUNCTION => {
+                let code = decoder.string()?;
+                crate::function_load(&code, true, shared).map_err(|e| corrupted(&e.to_string()))?;
+            },
+            OP_EOF => break,
+            // Not an opcode, so the type of a key's value.
+            value_type => {
+                let key = decoder.string()?;
+                let value = decode_value(value_type, decoder)?;
+                match expire.take() {
+                    Some(at) if at <= now => {},
+                    at => {
+                        db.set(key.clone(), value);
+                        if let Some(at) = at {
+                            db.set_expire(&key, at);
+                        }
+                        loaded += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(loaded)
+}
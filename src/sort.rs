@@ -0,0 +1,185 @@
+use std::cmp::Ordering;
+
+use crate::arg::Arg;
+use crate::db::{Db, Value};
+use crate::lazyfree;
+use crate::list::List;
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC, NOTIFY_LIST};
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+struct SortOptions {
+    by: Option<String>,
+    limit: Option<(i64, i64)>,
+    get: Vec<String>,
+    descending: bool,
+    alpha: bool,
+    store: Option<String>,
+}
+
+fn parse_sort(command: &[Arg], read_only: bool) -> Result<SortOptions, RESPError> {
+    let mut options = SortOptions { by: None, limit: None, get: vec![], descending: false, alpha: false, store: None };
+
+    let mut i = 2;
+    while i < command.len() {
+        let arg = |offset: usize| command.get(i + offset).ok_or(RESPError::SyntaxError);
+        match command[i].to_ascii_uppercase().as_str() {
+            "ASC" => options.descending = false,
+            "DESC" => options.descending = true,
+            "ALPHA" => options.alpha = true,
+            "BY" => {
+                options.by = Some(arg(1)?.to_string());
+                i += 1;
+            },
+            "LIMIT" => {
+                options.limit = Some((parse_number(arg(1)?)?, parse_number(arg(2)?)?));
+                i += 2;
+            },
+            "GET" => {
+                options.get.push(arg(1)?.to_string());
+                i += 1;
+            },
+            "STORE" if !read_only => {
+                options.store = Some(arg(1)?.to_string());
+                i += 1;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+        i += 1;
+    }
+    Ok(options)
+}
+
+// Whether the command reads other keys through BY or GET patterns, which can't be told in advance.
+pub fn uses_patterns(command: &[Arg]) -> bool {
+    parse_sort(command, command[0] == "SORT_RO").is_ok_and(|options| options.by.is_some() || !options.get.is_empty())
+}
+
+// Looks up the string a pattern points to for an element, by substituting the element for the
+// first `*` of the pattern. `#` stands for the element itself, and a pattern ending with `->field`
+// (after the `*`) points to a field of a hash.
+fn lookup_pattern(db: &Db, pattern: &str, element: &[u8]) -> Option<Vec<u8>> {
+    if pattern == "#" {
+        return Some(element.to_vec());
+    }
+
+    let (prefix, rest) = pattern.split_once('*')?;
+    let (suffix, field) = match rest.split_once("->") {
+        Some((suffix, field)) if !field.is_empty() => (suffix, Some(field)),
+        _ => (rest, None)
+    };
+    let key = format!("{}{}{}", prefix, std::str::from_utf8(element).ok()?, suffix);
+    let value = db.get(&key)?;
+    match field {
+        Some(field) => value.as_hash().ok()?.get(field).map(|value| value.to_vec()),
+        None => value.as_string().ok().map(Vec::from)
+    }
+}
+
+fn parse_score(value: &[u8]) -> Result<f64, RESPError> {
+    std::str::from_utf8(value).ok()
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or(RESPError::InvalidSortScore)
+}
+
+// SORT key [BY pattern] [LIMIT offset count] [GET pattern [GET pattern ...]] [ASC|DESC] [ALPHA]
+//     [STORE destination]
+// SORT_RO is the same, without STORE.
+// Sorts lists and sorted sets, any other type is WRONGTYPE. Sets, which Redis sorts as well, are out
+// of scope, as there is no set type.
+pub fn sort(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let options = parse_sort(command, command[0] == "SORT_RO")?;
+
+    let (results, deleted) = {
+        let mut db = shared.db.lock();
+        let elements: Vec<Vec<u8>> = match db.get(command[1].as_str()) {
+            Some(Value::List(list)) => list.iter().map(<[u8]>::to_vec).collect(),
+            Some(Value::SortedSet(set)) => set.iter().map(|(member, _)| member.as_bytes().to_vec()).collect(),
+            Some(_) => return Err(RESPError::WrongType),
+            None => vec![]
+        };
+
+        // A BY pattern without a `*` skips sorting altogether.
+        let skip_sort = options.by.as_ref().is_some_and(|by| !by.contains('*') && !by.contains("->"));
+        let mut elements = if skip_sort {
+            elements
+        } else {
+            let mut keyed = elements.into_iter().map(|element| {
+                let key = match &options.by {
+                    Some(by) => lookup_pattern(&db, by, &element),
+                    None => Some(element.clone())
+                };
+                let key = match key {
+                    Some(key) if !options.alpha => SortKey::Score(parse_score(&key)?),
+                    Some(key) => SortKey::Alpha(key),
+                    // Missing keys sort as zero, or as the empty string with ALPHA.
+                    None if !options.alpha => SortKey::Score(0.0),
+                    None => SortKey::Alpha(vec![])
+                };
+                Ok((key, element))
+            }).collect::<Result<Vec<_>, RESPError>>()?;
+
+            // Equal keys are ordered by the elements themselves, so the order is deterministic.
+            keyed.sort_by(|(a_key, a), (b_key, b)| a_key.cmp(b_key).then_with(|| a.cmp(b)));
+            if options.descending {
+                keyed.reverse();
+            }
+            keyed.into_iter().map(|(_, element)| element).collect()
+        };
+
+        if let Some((offset, count)) = options.limit {
+            let offset = offset.clamp(0, elements.len() as i64) as usize;
+            let count = if count < 0 { elements.len() } else { count as usize };
+            elements = elements.into_iter().skip(offset).take(count).collect();
+        }
+
+        let results: Vec<Option<Vec<u8>>> = if options.get.is_empty() {
+            elements.into_iter().map(Some).collect()
+        } else {
+            elements.iter()
+                .flat_map(|element| options.get.iter().map(|pattern| lookup_pattern(&db, pattern, element)))
+                .collect()
+        };
+
+        let deleted = match &options.store {
+            Some(destination) if results.is_empty() => lazyfree::free_replaced(db.remove(destination), shared),
+            Some(destination) => {
+                let list: List = results.iter().map(|result| result.clone().unwrap_or_default()).collect();
+                lazyfree::free_replaced(db.set(destination.to_owned(), Value::List(list)), shared);
+                false
+            },
+            None => false
+        };
+        (results, deleted)
+    };
+
+    match &options.store {
+        Some(destination) => {
+            if !results.is_empty() {
+                notify_keyspace_event(shared, NOTIFY_LIST, "sortstore", destination, 0);
+            } else if deleted {
+                notify_keyspace_event(shared, NOTIFY_GENERIC, "del", destination, 0);
+            }
+            Ok(RESPValue::Number(results.len() as i64))
+        },
+        None => Ok(RESPValue::Array(results.into_iter().map(|result| match result {
+            Some(value) => RESPValue::BlobString(value.into()),
+            None => RESPValue::Null
+        }).collect()))
+    }
+}
+
+enum SortKey {
+    Score(f64),
+    Alpha(Vec<u8>),
+}
+
+impl SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Score(a), SortKey::Score(b)) => a.total_cmp(b),
+            (SortKey::Alpha(a), SortKey::Alpha(b)) => a.cmp(b),
+            _ => Ordering::Equal
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::cmp;
+use std::collections::{btree_set, BTreeSet, HashMap};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::listpack::{self, Listpack};
+use crate::memory::{sampled_size, ENTRY_OVERHEAD};
+
+// Scores ordered by their total order, so they can be used as keys.
+#[derive(Clone, Copy, Debug)]
+pub struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// The limits of the listpack encoding, past which sets convert to a skiplist. Set through the config.
+pub static MAX_LISTPACK_ENTRIES: AtomicUsize = AtomicUsize::new(128);
+pub static MAX_LISTPACK_VALUE: AtomicUsize = AtomicUsize::new(64);
+
+#[derive(Clone)]
+enum Encoding {
+    // Every member followed by its score (as little endian bytes), ordered like the skiplist.
+    Listpack(Listpack),
+    // Every member is kept twice, in the score lookup and in the ordered set.
+    Skiplist {
+        scores: HashMap<String, f64>,
+        ordered: BTreeSet<(Score, String)>,
+    },
+}
+
+fn decode_score(bytes: &[u8]) -> f64 {
+    f64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+// The members of a listpack along with their scores and offsets.
+fn listpack_members(listpack: &Listpack) -> impl Iterator<Item = (usize, &str, f64)> {
+    let mut entries = listpack.entries();
+    std::iter::from_fn(move || {
+        let (offset, member) = entries.next()?;
+        let (_, score) = entries.next()?;
+        Some((offset, std::str::from_utf8(member).unwrap(), decode_score(score)))
+    })
+}
+
+// Members ordered by score, then lexicographically. Small sets are packed in a listpack.
+#[derive(Clone)]
+pub struct SortedSet {
+    encoding: Encoding,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        Self { encoding: Encoding::Listpack(Listpack::default()) }
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => listpack.len() / 2,
+            Encoding::Skiplist { scores, .. } => scores.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match &self.encoding {
+            Encoding::Listpack(_) => "listpack",
+            Encoding::Skiplist { .. } => "skiplist",
+        }
+    }
+
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => size_of::<Self>() + listpack.memory_usage(),
+            Encoding::Skiplist { scores, .. } => size_of::<Self>() + sampled_size(self.len(), samples, scores.keys()
+                .map(|member| 2 * (size_of::<String>() + member.capacity() + size_of::<f64>() + ENTRY_OVERHEAD))),
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => listpack_members(listpack).find(|(_, other, _)| *other == member).map(|(_, _, score)| score),
+            Encoding::Skiplist { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    // Adds the member or updates its score, returning the previous score.
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let old = match &mut self.encoding {
+            Encoding::Listpack(listpack) => {
+                let old = listpack_members(listpack).find(|(_, other, _)| *other == member).map(|(offset, _, score)| (offset, score));
+                if let Some((offset, _)) = old {
+                    // The score moves up to the offset of the removed member.
+                    listpack.remove(offset);
+                    listpack.remove(offset);
+                }
+                let offset = listpack_members(listpack)
+                    .find(|(_, other, other_score)| (Score(*other_score), *other) > (Score(score), member.as_str()))
+                    .map_or(listpack.end(), |(offset, _, _)| offset);
+                listpack.insert(offset, &score.to_le_bytes());
+                listpack.insert(offset, member.as_bytes());
+                old.map(|(_, score)| score)
+            },
+            Encoding::Skiplist { scores, ordered } => {
+                let old = scores.insert(member.clone(), score);
+                if let Some(old) = old {
+                    ordered.remove(&(Score(old), member.clone()));
+                }
+                ordered.insert((Score(score), member.clone()));
+                old
+            },
+        };
+
+        if matches!(self.encoding, Encoding::Listpack(_))
+            && (self.len() > MAX_LISTPACK_ENTRIES.load(Ordering::Relaxed) || member.len() > MAX_LISTPACK_VALUE.load(Ordering::Relaxed)) {
+            self.convert_to_skiplist();
+        }
+        old
+    }
+
+    fn convert_to_skiplist(&mut self) {
+        let (mut scores, mut ordered) = (HashMap::new(), BTreeSet::new());
+        for (member, score) in self.iter() {
+            scores.insert(member.to_owned(), score);
+            ordered.insert((Score(score), member.to_owned()));
+        }
+        self.encoding = Encoding::Skiplist { scores, ordered };
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match &self.encoding {
+            Encoding::Listpack(listpack) => Iter::Listpack(listpack.iter()),
+            Encoding::Skiplist { ordered, .. } => Iter::Skiplist(ordered.iter()),
+        }
+    }
+}
+
+pub enum Iter<'a> {
+    Listpack(listpack::Iter<'a>),
+    Skiplist(btree_set::Iter<'a, (Score, String)>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Listpack(entries) => {
+                let member = entries.next()?;
+                let score = entries.next()?;
+                Some((std::str::from_utf8(member).unwrap(), decode_score(score)))
+            },
+            Iter::Skiplist(ordered) => ordered.next().map(|(score, member)| (member.as_str(), score.0)),
+        }
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Listpack(entries) => {
+                let score = entries.next_back()?;
+                let member = entries.next_back()?;
+                Some((std::str::from_utf8(member).unwrap(), decode_score(score)))
+            },
+            Iter::Skiplist(ordered) => ordered.next_back().map(|(score, member)| (member.as_str(), score.0)),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::arg::Arg;
+use crate::db::now_ms;
+use crate::latency::Histogram;
+use crate::{compression, replication, sentinel, SharedState};
+
+// Section names along with their titles.
+const SECTIONS: &[(&str, &str)] = &[
+    ("server", "Server"), ("clients", "Clients"), ("memory", "Memory"), ("persistence", "Persistence"),
+    ("stats", "Stats"), ("replication", "Replication"), ("cpu", "CPU"), ("commandstats", "Commandstats"),
+    ("cluster", "Cluster"), ("keyspace", "Keyspace"), ("sentinel", "Sentinel"),
+];
+
+// Sections only given when asked for by name, or with all.
+const NON_DEFAULT_SECTIONS: &[&str] = &["commandstats"];
+
+#[derive(Default)]
+pub struct CommandStats {
+    pub calls: u64,
+    usec: u64,
+    failed_calls: u64,
+    pub histogram: Histogram,
+}
+
+// The rates sampled last, averaged into an instantaneous rate.
+const SAMPLES: usize = 16;
+
+#[derive(Default)]
+struct Samples {
+    // When the counter was sampled last, and its value then.
+    last: Option<(Instant, u64)>,
+    rates: [f64; SAMPLES],
+    index: usize,
+}
+
+impl Samples {
+    fn add(&mut self, value: u64) {
+        let now = Instant::now();
+        if let Some((at, last)) = self.last {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            if elapsed > 0.0 {
+                self.rates[self.index] = value.saturating_sub(last) as f64 / elapsed;
+                self.index = (self.index + 1) % SAMPLES;
+            }
+        }
+        self.last = Some((now, value));
+    }
+
+    fn average(&self) -> f64 {
+        self.rates.iter().sum::<f64>() / SAMPLES as f64
+    }
+}
+
+// Counters reported by INFO.
+pub struct Stats {
+    start_time: Instant,
+    pub connected_clients: AtomicU64,
+    pub blocked_clients: AtomicU64,
+    pub total_connections_received: AtomicU64,
+    // Connections refused for reaching maxclients.
+    pub rejected_connections: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    // Commands delayed or refused for going over a rate limit.
+    pub rate_limited_commands: AtomicU64,
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    pub expired_keys: AtomicU64,
+    // Expired fields of hashes.
+    pub expired_subkeys: AtomicU64,
+    pub evicted_keys: AtomicU64,
+    pub expired_time_cap_reached_count: AtomicU64,
+    pub expire_cycle_usec: AtomicU64,
+    // The percentage of keys with a TTL that expired but weren't deleted yet, as the bits of an f64.
+    expired_stale_perc: AtomicU64,
+    // Keyed by the name of the command.
+    pub commands: Mutex<BTreeMap<&'static str, CommandStats>>,
+    ops_samples: Mutex<Samples>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            start_time: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            rate_limited_commands: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            expired_subkeys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            expired_time_cap_reached_count: AtomicU64::new(0),
+            expire_cycle_usec: AtomicU64::new(0),
+            expired_stale_perc: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            ops_samples: Mutex::new(Samples::default()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decr(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn expired_stale_perc(&self) -> f64 {
+        f64::from_bits(self.expired_stale_perc.load(Ordering::Relaxed))
+    }
+
+    pub fn set_expired_stale_perc(&self, percentage: f64) {
+        self.expired_stale_perc.store(percentage.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn record_call(&self, name: &'static str, latency: Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name).or_default();
+        stats.calls += 1;
+        stats.usec += latency.as_micros() as u64;
+        stats.failed_calls += failed as u64;
+        stats.histogram.record(latency);
+    }
+
+    // Counts the client as blocked until the returned guard is dropped.
+    pub fn block(&self) -> BlockedClient<'_> {
+        Stats::incr(&self.blocked_clients);
+        BlockedClient(self)
+    }
+}
+
+pub struct BlockedClient<'a>(&'a Stats);
+
+impl Drop for BlockedClient<'_> {
+    fn drop(&mut self) {
+        Stats::decr(&self.0.blocked_clients);
+    }
+}
+
+// Samples the rates reported as instantaneous, run by the cron every 100 milliseconds.
+pub fn sample(shared: &Arc<SharedState>) {
+    let stats = &shared.stats;
+    stats.ops_samples.lock().unwrap().add(load(&stats.total_commands_processed));
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+// The resident set size of the process, only known on Linux.
+pub fn rss_bytes() -> u64 {
+    std::fs::read_to_string("/proc/self/statm").ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4096)
+}
+
+// The user and system CPU seconds used by the process, only known on Linux.
+fn cpu_seconds() -> (f64, f64) {
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    // The fields after the executable name, which is in parentheses and might contain spaces.
+    let fields: Vec<&str> = stat.rsplit_once(')').map_or(vec![], |(_, rest)| rest.split_whitespace().collect());
+    // utime and stime are the 14th and 15th fields, in clock ticks of (almost always) 100Hz.
+    let ticks = |i: usize| fields.get(i).and_then(|field| field.parse::<f64>().ok()).unwrap_or(0.0) / 100.0;
+    (ticks(11), ticks(12))
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.2}{}", value, UNITS[unit])
+    }
+}
+
+fn section(name: &str, title: &str, shared: &SharedState) -> String {
+    let stats = &shared.stats;
+    let fields: Vec<(&str, String)> = match name {
+        "server" => {
+            let uptime = stats.start_time.elapsed().as_secs();
+            vec![
+                ("bast_version", env!("CARGO_PKG_VERSION").to_owned()),
+                ("os", std::env::consts::OS.to_owned()),
+                ("arch_bits", usize::BITS.to_string()),
+                ("process_id", std::process::id().to_string()),
+                ("tcp_port", shared.config.get("port")),
+                ("hz", shared.config.get("hz")),
+                ("uptime_in_seconds", uptime.to_string()),
+                ("uptime_in_days", (uptime / 86400).to_string()),
+            ]
+        },
+        "clients" => vec![
+            ("connected_clients", load(&stats.connected_clients).to_string()),
+            ("blocked_clients", load(&stats.blocked_clients).to_string()),
+            ("maxclients", shared.config.get("maxclients")),
+            ("rejected_connections", load(&stats.rejected_connections).to_string()),
+        ],
+        "memory" => {
+            let (used, (spilled_keys, spilled)) = {
+                let mut db = shared.db.lock();
+                (db.used_memory() as u64, db.spilled())
+            };
+            let rss = rss_bytes();
+            let maxmemory = shared.config.get_int("maxmemory") as u64;
+            let (values, raw, compressed) = compression::totals();
+            vec![
+                ("used_memory", used.to_string()),
+                ("used_memory_human", human_bytes(used)),
+                ("used_memory_rss", rss.to_string()),
+                ("used_memory_rss_human", human_bytes(rss)),
+                ("maxmemory", maxmemory.to_string()),
+                ("maxmemory_human", human_bytes(maxmemory)),
+                ("maxmemory_policy", shared.config.get("maxmemory-policy")),
+                ("spilled_keys", spilled_keys.to_string()),
+                ("spilled_memory", spilled.to_string()),
+                ("spilled_memory_human", human_bytes(spilled as u64)),
+                ("lazyfree_pending_objects", shared.lazyfree.pending().to_string()),
+                ("lazyfreed_objects", shared.lazyfree.freed().to_string()),
+                ("value_compression", shared.config.get("value-compression")),
+                ("compressed_values", values.to_string()),
+                ("compressed_values_raw_bytes", raw.to_string()),
+                ("compressed_values_bytes", compressed.to_string()),
+                ("compressed_values_ratio", format!("{:.2}", if compressed == 0 { 1.0 } else { raw as f64 / compressed as f64 })),
+            ]
+        },
+        "persistence" => {
+            let snapshots = &shared.snapshots;
+            vec![
+                ("loading", String::from("0")),
+                ("rdb_changes_since_last_save", snapshots.changes_since_save(shared).to_string()),
+                ("rdb_bgsave_in_progress", (snapshots.saving() as u8).to_string()),
+                ("rdb_last_save_time", snapshots.last_save().to_string()),
+                ("rdb_last_bgsave_status", String::from(if snapshots.last_bgsave_ok() { "ok" } else { "err" })),
+                ("rdb_last_bgsave_time_sec", snapshots.last_bgsave_seconds().to_string()),
+                ("aof_enabled", (shared.config.get_bool("appendonly") as u8).to_string()),
+                ("aof_rewrite_in_progress", (shared.aof.rewriting() as u8).to_string()),
+                ("aof_rewrite_scheduled", (shared.aof.rewrite_scheduled() as u8).to_string()),
+                ("aof_last_rewrite_time_sec", shared.aof.last_rewrite_seconds().to_string()),
+                ("aof_last_bgrewrite_status", String::from(if shared.aof.last_rewrite_ok() { "ok" } else { "err" })),
+                ("aof_last_write_status", String::from(if shared.aof.last_write_ok() { "ok" } else { "err" })),
+                ("aof_current_size", shared.aof.size().to_string()),
+                ("aof_base_size", shared.aof.base_size().to_string()),
+            ]
+        },
+        "stats" => {
+            let (channels, patterns) = {
+                let pubsub = shared.pubsub.lock().unwrap();
+                (pubsub.channel_count(), pubsub.pattern_count())
+            };
+            vec![
+                ("total_connections_received", load(&stats.total_connections_received).to_string()),
+                ("total_commands_processed", load(&stats.total_commands_processed).to_string()),
+                ("instantaneous_ops_per_sec", (stats.ops_samples.lock().unwrap().average().round() as u64).to_string()),
+                ("rate_limited_commands", load(&stats.rate_limited_commands).to_string()),
+                ("expired_keys", load(&stats.expired_keys).to_string()),
+                ("expired_subkeys", load(&stats.expired_subkeys).to_string()),
+                ("expired_stale_perc", format!("{:.2}", stats.expired_stale_perc())),
+                ("expired_time_cap_reached_count", load(&stats.expired_time_cap_reached_count).to_string()),
+                ("expire_cycle_cpu_milliseconds", (load(&stats.expire_cycle_usec) / 1000).to_string()),
+                ("evicted_keys", load(&stats.evicted_keys).to_string()),
+                ("keyspace_hits", load(&stats.keyspace_hits).to_string()),
+                ("keyspace_misses", load(&stats.keyspace_misses).to_string()),
+                ("pubsub_channels", channels.to_string()),
+                ("pubsub_patterns", patterns.to_string()),
+            ]
+        },
+        "replication" => {
+            let fields = replication::info(shared);
+            return format_section(title, fields.iter().map(|(field, value)| (field.as_str(), value.clone())));
+        },
+        "sentinel" => {
+            let fields = sentinel::info(shared);
+            return format_section(title, fields.iter().map(|(field, value)| (field.as_str(), value.clone())));
+        },
+        "cluster" => vec![("cluster_enabled", (shared.cluster.is_some() as u8).to_string())],
+        "cpu" => {
+            let (user, system) = cpu_seconds();
+            vec![
+                ("used_cpu_sys", format!("{:.6}", system)),
+                ("used_cpu_user", format!("{:.6}", user)),
+            ]
+        },
+        "commandstats" => {
+            let commands = stats.commands.lock().unwrap();
+            let lines: Vec<(String, String)> = commands.iter().map(|(name, stats)| (
+                format!("cmdstat_{}", name.to_ascii_lowercase()),
+                format!("calls={},usec={},usec_per_call={:.2},failed_calls={}",
+                    stats.calls, stats.usec, stats.usec as f64 / stats.calls as f64, stats.failed_calls)
+            )).collect();
+            return format_section(title, lines.iter().map(|(field, value)| (field.as_str(), value.clone())));
+        },
+        "keyspace" => {
+            let db = shared.db.lock();
+            if db.len() == 0 {
+                vec![]
+            } else {
+                let now = now_ms();
+                let ttls: u64 = db.iter_expires().map(|(_, at)| at.saturating_sub(now)).sum();
+                let avg_ttl = ttls.checked_div(db.expires_len() as u64).unwrap_or(0);
+                // There is a single database, whose counters are those of the whole keyspace.
+                vec![("db0", format!(
+                    "keys={},expires={},avg_ttl={},subexpiry={},hits={},misses={},expired={},evicted={}",
+                    db.len(), db.expires_len(), avg_ttl, db.field_expires_len(), load(&stats.keyspace_hits), load(&stats.keyspace_misses),
+                    load(&stats.expired_keys), load(&stats.evicted_keys)
+                ))]
+            }
+        },
+        _ => unreachable!()
+    };
+
+    format_section(title, fields.into_iter())
+}
+
+fn format_section<'a>(title: &str, fields: impl Iterator<Item = (&'a str, String)>) -> String {
+    let mut section = String::new();
+    write!(section, "# {}\r\n", title).unwrap();
+    for (field, value) in fields {
+        write!(section, "{}:{}\r\n", field, value).unwrap();
+    }
+    section
+}
+
+// INFO [section [section ...]]
+pub fn info(command: &[Arg], shared: &SharedState) -> String {
+    let requested: Vec<String> = command[1..].iter().map(|section| section.to_ascii_lowercase()).collect();
+    let all = requested.iter().any(|section| matches!(section.as_str(), "all" | "everything"));
+    let default = requested.is_empty() || requested.iter().any(|section| section == "default");
+
+    SECTIONS.iter()
+        // Only sentinels have the sentinel section.
+        .filter(|(name, _)| *name != "sentinel" || shared.sentinel.is_some())
+        .filter(|(name, _)| {
+            all || (default && !NON_DEFAULT_SECTIONS.contains(name)) || requested.iter().any(|section| section == name)
+        })
+        .map(|(name, title)| section(name, title, shared))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
@@ -0,0 +1,165 @@
+// Where snapshots are kept. By default they are files in `dir`, and with `snapshot-storage command`
+// they are streamed to the stdin of `snapshot-upload-command` and read back from the stdout of
+// `snapshot-download-command`, so they can be kept off the server (like with
+// `aws s3 cp - s3://bucket/%f` and `aws s3 cp s3://bucket/%f -`). In both commands %f is replaced
+// by `dbfilename`. The download command exits successfully without printing anything when there is
+// no snapshot yet, any other failure stops the server from starting.
+//
+// The append only file (and its rewrites) is always kept in `dir`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::SharedState;
+
+pub const BACKENDS: &[&str] = &["disk", "command"];
+
+// A snapshot being written, which replaces the stored one only once it's committed. Dropping it
+// before that discards it.
+pub trait Upload: Write + Send {
+    fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+pub trait Storage: Send + Sync {
+    // The stored snapshot, None when there is none.
+    fn download(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn upload(&self, name: &str) -> io::Result<Box<dyn Upload>>;
+}
+
+// The storage of `snapshot-storage`.
+pub fn configured(shared: &SharedState) -> Box<dyn Storage> {
+    match shared.config.get("snapshot-storage").as_str() {
+        "command" => Box::new(ShellCommands {
+            upload: shared.config.get("snapshot-upload-command"),
+            download: shared.config.get("snapshot-download-command"),
+        }),
+        _ => Box::new(Disk)
+    }
+}
+
+pub struct Disk;
+
+// Written to a temporary file, renamed over the snapshot file once it's complete.
+struct DiskUpload {
+    file: BufWriter<File>,
+    temp_path: String,
+    path: String,
+    committed: bool,
+}
+
+impl Write for DiskUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Upload for DiskUpload {
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        std::fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for DiskUpload {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl Storage for Disk {
+    fn download(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(name) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn upload(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+        let temp_path = Path::new(name).with_file_name(format!("temp-{}.snapshot", std::process::id()))
+            .to_string_lossy().into_owned();
+        let file = BufWriter::new(File::create(&temp_path)?);
+        Ok(Box::new(DiskUpload { file, temp_path, path: name.to_owned(), committed: false }))
+    }
+}
+
+struct ShellCommands {
+    upload: String,
+    download: String,
+}
+
+fn shell(command: &str, name: &str, setting: &str) -> io::Result<Command> {
+    if command.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't set", setting)));
+    }
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command.replace("%f", name));
+    Ok(shell)
+}
+
+// Piped to the upload command, which is killed when the upload is discarded.
+struct CommandUpload {
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+}
+
+impl Write for CommandUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Upload for CommandUpload {
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        // Closing stdin tells the command the snapshot is complete.
+        let stdin = self.stdin.take().unwrap();
+        drop(stdin.into_inner().map_err(|e| e.into_error())?);
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("snapshot-upload-command failed ({})", status)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CommandUpload {
+    fn drop(&mut self) {
+        if self.stdin.take().is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl Storage for ShellCommands {
+    fn download(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let output = shell(&self.download, name, "snapshot-download-command")?.stderr(Stdio::inherit()).output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("snapshot-download-command failed ({})", output.status)));
+        }
+        Ok((!output.stdout.is_empty()).then_some(output.stdout))
+    }
+
+    fn upload(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+        let mut child = shell(&self.upload, name, "snapshot-upload-command")?
+            .stdin(Stdio::piped()).stdout(Stdio::null()).spawn()?;
+        let stdin = child.stdin.take().map(BufWriter::new);
+        Ok(Box::new(CommandUpload { child, stdin }))
+    }
+}
@@ -0,0 +1,1189 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::mem::size_of;
+use std::ops::Bound;
+
+use crate::arg::Arg;
+use crate::db::{now_ms, Db, Value};
+use crate::memory::{sampled_size, ENTRY_OVERHEAD};
+use crate::notify::{notify_keyspace_event, NOTIFY_STREAM};
+use crate::rdb::{self, Element};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    // Parses `<ms>-<seq>` or `<ms>`, in which case the sequence defaults to `missing_seq`.
+    pub fn parse(s: &str, missing_seq: u64) -> Result<StreamId, RESPError> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, Some(seq)),
+            None => (s, None)
+        };
+        let ms = ms.parse().map_err(|_| RESPError::InvalidStreamId)?;
+        let seq = match seq {
+            Some(seq) => seq.parse().map_err(|_| RESPError::InvalidStreamId)?,
+            None => missing_seq
+        };
+        Ok(StreamId { ms, seq })
+    }
+
+    fn next(&self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            Some(StreamId { ms: self.ms, seq: self.seq + 1 })
+        } else if self.ms < u64::MAX {
+            Some(StreamId { ms: self.ms + 1, seq: 0 })
+        } else {
+            None
+        }
+    }
+
+    fn prev(&self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId { ms: self.ms, seq: self.seq - 1 })
+        } else if self.ms > 0 {
+            Some(StreamId { ms: self.ms - 1, seq: u64::MAX })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub type StreamEntry = (StreamId, Vec<(String, String)>);
+
+// The entries of a listpack node of a stream in an RDB file, and the flags of those entries.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+#[derive(Clone)]
+struct PendingEntry {
+    consumer: String,
+    // Milliseconds since the epoch of the last delivery.
+    delivery_time: u64,
+    delivery_count: u64,
+}
+
+#[derive(Clone)]
+struct Consumer {
+    seen_time: u64,
+}
+
+#[derive(Clone, Default)]
+struct ConsumerGroup {
+    last_delivered_id: StreamId,
+    // Entries delivered to a consumer and not acknowledged yet.
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    // Marks the consumer as seen, creating it if needed, returns whether it was created.
+    fn touch_consumer(&mut self, name: &str, now: u64) -> bool {
+        match self.consumers.get_mut(name) {
+            Some(consumer) => {
+                consumer.seen_time = now;
+                false
+            },
+            None => {
+                self.consumers.insert(name.to_owned(), Consumer { seen_time: now });
+                true
+            }
+        }
+    }
+
+    // Transfers a pending entry to the consumer if it has been idle for long enough.
+    fn claim(&mut self, id: &StreamId, consumer: &str, options: &ClaimOptions) -> bool {
+        let pending = match self.pending.get_mut(id) {
+            Some(pending) => pending,
+            None => return false
+        };
+        if options.now.saturating_sub(pending.delivery_time) < options.min_idle {
+            return false;
+        }
+
+        pending.consumer = consumer.to_owned();
+        pending.delivery_time = options.delivery_time;
+        match options.retry_count {
+            Some(retry_count) => pending.delivery_count = retry_count,
+            None if !options.just_id => pending.delivery_count += 1,
+            None => {}
+        }
+        true
+    }
+}
+
+struct ClaimOptions {
+    now: u64,
+    min_idle: u64,
+    delivery_time: u64,
+    retry_count: Option<u64>,
+    just_id: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    last_id: StreamId,
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Entries are sampled, while consumer groups are always fully counted.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        let entries = sampled_size(self.len(), samples, self.entries.values().map(|fields| {
+            size_of::<StreamId>() + size_of::<Vec<(String, String)>>() + ENTRY_OVERHEAD
+                + fields.iter().map(|(field, value)| 2 * size_of::<String>() + field.capacity() + value.capacity()).sum::<usize>()
+        }));
+        let groups: usize = self.groups.iter().map(|(name, group)| {
+            size_of::<String>() + name.capacity() + size_of::<ConsumerGroup>() + ENTRY_OVERHEAD
+                + group.pending.values().map(|pending| size_of::<StreamId>() + size_of::<PendingEntry>() + pending.consumer.capacity() + ENTRY_OVERHEAD).sum::<usize>()
+                + group.consumers.keys().map(|consumer| size_of::<String>() + consumer.capacity() + size_of::<Consumer>() + ENTRY_OVERHEAD).sum::<usize>()
+        }).sum();
+        size_of::<Self>() + entries + groups
+    }
+
+    // A stream of the entries, without consumer groups, its last id being the one of its last entry.
+    pub fn from_entries(entries: impl IntoIterator<Item = StreamEntry>) -> Self {
+        let entries: BTreeMap<StreamId, Vec<(String, String)>> = entries.into_iter().collect();
+        let last_id = entries.keys().next_back().copied().unwrap_or_default();
+        Stream { entries, last_id, ..Default::default() }
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        let encode_id = |encoder: &mut Encoder, id: &StreamId| {
+            encoder.u64(id.ms);
+            encoder.u64(id.seq);
+        };
+        encode_id(encoder, &self.last_id);
+        encoder.len(self.entries.len());
+        for (id, fields) in &self.entries {
+            encode_id(encoder, id);
+            encoder.len(fields.len());
+            for (field, value) in fields {
+                encoder.str(field);
+                encoder.str(value);
+            }
+        }
+        encoder.len(self.groups.len());
+        for (name, group) in &self.groups {
+            encoder.str(name);
+            encode_id(encoder, &group.last_delivered_id);
+            encoder.len(group.pending.len());
+            for (id, pending) in &group.pending {
+                encode_id(encoder, id);
+                encoder.str(&pending.consumer);
+                encoder.u64(pending.delivery_time);
+                encoder.u64(pending.delivery_count);
+            }
+            encoder.len(group.consumers.len());
+            for (name, consumer) in &group.consumers {
+                encoder.str(name);
+                encoder.u64(consumer.seen_time);
+            }
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let decode_id = |decoder: &mut Decoder| Ok::<_, io::Error>(StreamId { ms: decoder.u64()?, seq: decoder.u64()? });
+        let mut stream = Stream { last_id: decode_id(decoder)?, ..Default::default() };
+        for _ in 0..decoder.len()? {
+            let id = decode_id(decoder)?;
+            let fields = (0..decoder.len()?).map(|_| Ok((decoder.string()?, decoder.string()?))).collect::<io::Result<_>>()?;
+            stream.entries.insert(id, fields);
+        }
+        for _ in 0..decoder.len()? {
+            let name = decoder.string()?;
+            let mut group = ConsumerGroup { last_delivered_id: decode_id(decoder)?, ..Default::default() };
+            for _ in 0..decoder.len()? {
+                let id = decode_id(decoder)?;
+                let pending = PendingEntry { consumer: decoder.string()?, delivery_time: decoder.u64()?, delivery_count: decoder.u64()? };
+                group.pending.insert(id, pending);
+            }
+            for _ in 0..decoder.len()? {
+                let name = decoder.string()?;
+                group.consumers.insert(name, Consumer { seen_time: decoder.u64()? });
+            }
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
+    }
+
+    // Writes the stream as an RDB_TYPE_STREAM_LISTPACKS value: its entries in listpacks of up to
+    // STREAM_NODE_MAX_ENTRIES entries, each starting with a master entry holding the fields of the
+    // first entry and delta encoding the following ones against it, then its consumer groups.
+    pub fn write_rdb(&self, buf: &mut Vec<u8>) {
+        let raw_id = |id: &StreamId| [id.ms.to_be_bytes(), id.seq.to_be_bytes()].concat();
+        let entries: Vec<_> = self.entries.iter().collect();
+        rdb::write_len(buf, entries.len().div_ceil(STREAM_NODE_MAX_ENTRIES) as u64);
+        for node in entries.chunks(STREAM_NODE_MAX_ENTRIES) {
+            let (master_id, master_fields) = node[0];
+            let mut elements = vec![Element::Int(node.len() as i64), Element::Int(0), Element::Int(master_fields.len() as i64)];
+            elements.extend(master_fields.iter().map(|(field, _)| Element::Bytes(field.as_bytes())));
+            elements.push(Element::Int(0));
+            for (id, fields) in node {
+                let same_fields = fields.len() == master_fields.len()
+                    && fields.iter().zip(master_fields.iter()).all(|((field, _), (master, _))| field == master);
+                elements.push(Element::Int(if same_fields { STREAM_ITEM_FLAG_SAMEFIELDS } else { 0 }));
+                elements.push(Element::Int((id.ms - master_id.ms) as i64));
+                elements.push(Element::Int(id.seq.wrapping_sub(master_id.seq) as i64));
+                if same_fields {
+                    elements.extend(fields.iter().map(|(_, value)| Element::Bytes(value.as_bytes())));
+                    elements.push(Element::Int(fields.len() as i64 + 3));
+                } else {
+                    elements.push(Element::Int(fields.len() as i64));
+                    for (field, value) in fields.iter() {
+                        elements.push(Element::Bytes(field.as_bytes()));
+                        elements.push(Element::Bytes(value.as_bytes()));
+                    }
+                    elements.push(Element::Int(2 * fields.len() as i64 + 4));
+                }
+            }
+            rdb::write_string(buf, &raw_id(master_id));
+            rdb::write_string(buf, &rdb::encode_listpack(&elements));
+        }
+
+        rdb::write_len(buf, self.entries.len() as u64);
+        rdb::write_len(buf, self.last_id.ms);
+        rdb::write_len(buf, self.last_id.seq);
+        rdb::write_len(buf, self.groups.len() as u64);
+        for (name, group) in &self.groups {
+            rdb::write_string(buf, name.as_bytes());
+            rdb::write_len(buf, group.last_delivered_id.ms);
+            rdb::write_len(buf, group.last_delivered_id.seq);
+            rdb::write_len(buf, group.pending.len() as u64);
+            for (id, pending) in &group.pending {
+                buf.extend_from_slice(&raw_id(id));
+                buf.extend_from_slice(&pending.delivery_time.to_le_bytes());
+                rdb::write_len(buf, pending.delivery_count);
+            }
+            rdb::write_len(buf, group.consumers.len() as u64);
+            for (name, consumer) in &group.consumers {
+                rdb::write_string(buf, name.as_bytes());
+                buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+                let owned: Vec<&StreamId> = group.pending.iter().filter(|(_, pending)| pending.consumer == *name).map(|(id, _)| id).collect();
+                rdb::write_len(buf, owned.len() as u64);
+                for id in owned {
+                    buf.extend_from_slice(&raw_id(id));
+                }
+            }
+        }
+    }
+
+    // Reads a stream written as any of the RDB_TYPE_STREAM_LISTPACKS types.
+    pub fn read_rdb(reader: &mut rdb::Reader, value_type: u8) -> io::Result<Self> {
+        let parse_raw_id = |raw: &[u8]| -> io::Result<StreamId> {
+            if raw.len() != 16 {
+                return Err(rdb::corrupted("invalid stream node key"));
+            }
+            Ok(StreamId { ms: u64::from_be_bytes(raw[..8].try_into().unwrap()), seq: u64::from_be_bytes(raw[8..].try_into().unwrap()) })
+        };
+        let read_id = |reader: &mut rdb::Reader| Ok::<_, io::Error>(StreamId { ms: reader.len()?, seq: reader.len()? });
+
+        let mut stream = Stream::default();
+        for _ in 0..reader.len()? {
+            let master_id = parse_raw_id(&reader.string()?)?;
+            let node = reader.string()?;
+            let elements = rdb::decode_listpack(&node)?;
+            let mut elements = elements.iter();
+            let mut next = || elements.next().ok_or_else(|| rdb::corrupted("truncated stream node"));
+            let count = next()?.as_int()? + next()?.as_int()?;
+            let master_fields = (0..next()?.as_int()?).map(|_| Ok(reader.to_text(&next()?.to_vec()))).collect::<io::Result<Vec<_>>>()?;
+            next()?;
+            for _ in 0..count {
+                let flags = next()?.as_int()?;
+                let id = StreamId {
+                    ms: master_id.ms.wrapping_add(next()?.as_int()? as u64),
+                    seq: master_id.seq.wrapping_add(next()?.as_int()? as u64),
+                };
+                let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                    master_fields.iter().map(|field| Ok((field.clone(), reader.to_text(&next()?.to_vec())))).collect::<io::Result<Vec<_>>>()?
+                } else {
+                    (0..next()?.as_int()?).map(|_| Ok((reader.to_text(&next()?.to_vec()), reader.to_text(&next()?.to_vec())))).collect::<io::Result<Vec<_>>>()?
+                };
+                next()?;
+                if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                    stream.entries.insert(id, fields);
+                }
+            }
+        }
+
+        reader.len()?;
+        stream.last_id = read_id(reader)?;
+        if value_type >= rdb::TYPE_STREAM_LISTPACKS_2 {
+            // The first and the max deleted entry IDs, and the entries ever added.
+            read_id(reader)?;
+            read_id(reader)?;
+            reader.len()?;
+        }
+        for _ in 0..reader.len()? {
+            let name = reader.text()?;
+            let mut group = ConsumerGroup { last_delivered_id: read_id(reader)?, ..Default::default() };
+            if value_type >= rdb::TYPE_STREAM_LISTPACKS_2 {
+                // The entries read.
+                reader.len()?;
+            }
+            for _ in 0..reader.len()? {
+                let id = parse_raw_id(reader.take(16)?)?;
+                let delivery_time = reader.u64_le()?;
+                let delivery_count = reader.len()?;
+                group.pending.insert(id, PendingEntry { consumer: String::new(), delivery_time, delivery_count });
+            }
+            for _ in 0..reader.len()? {
+                let name = reader.text()?;
+                let seen_time = reader.u64_le()?;
+                if value_type >= rdb::TYPE_STREAM_LISTPACKS_3 {
+                    // The active time.
+                    reader.u64_le()?;
+                }
+                for _ in 0..reader.len()? {
+                    let id = parse_raw_id(reader.take(16)?)?;
+                    let pending = group.pending.get_mut(&id).ok_or_else(|| rdb::corrupted("consumer owns an entry that isn't pending"))?;
+                    pending.consumer = name.clone();
+                }
+                group.consumers.insert(name, Consumer { seen_time });
+            }
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>, reverse: bool) -> Vec<StreamEntry> {
+        if start > end {
+            return vec![];
+        }
+
+        let range = self.entries.range(start..=end);
+        let entries: Box<dyn Iterator<Item = _>> = if reverse { Box::new(range.rev()) } else { Box::new(range) };
+        entries.take(count.unwrap_or(usize::MAX)).map(|(id, fields)| (*id, fields.clone())).collect()
+    }
+
+    // Entries with an ID greater than `after`.
+    pub fn entries_after(&self, after: StreamId, count: Option<usize>) -> Vec<StreamEntry> {
+        self.entries.range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect()
+    }
+
+    fn add(&mut self, id: StreamId, fields: Vec<(String, String)>) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+
+    fn delete(&mut self, id: &StreamId) -> bool {
+        self.entries.remove(id).is_some()
+    }
+
+    fn group(&self, key: &str, name: &str) -> Result<&ConsumerGroup, RESPError> {
+        self.groups.get(name).ok_or_else(|| RESPError::NoGroup(key.to_owned(), name.to_owned()))
+    }
+
+    fn group_mut(&mut self, key: &str, name: &str) -> Result<&mut ConsumerGroup, RESPError> {
+        self.groups.get_mut(name).ok_or_else(|| RESPError::NoGroup(key.to_owned(), name.to_owned()))
+    }
+
+    // Delivers entries to a consumer of the group. Without an ID these are the entries never
+    // delivered to the group, otherwise they are the consumer's pending entries after the ID, with
+    // no fields for entries deleted since they were delivered.
+    fn read_group(&mut self, key: &str, group: &str, consumer: &str, after: Option<StreamId>, count: Option<usize>, no_ack: bool) -> Result<(Vec<PendingStreamEntry>, bool), RESPError> {
+        let now = now_ms();
+        let count = count.unwrap_or(usize::MAX);
+        let Stream { entries, groups, .. } = self;
+        let group = groups.get_mut(group).ok_or_else(|| RESPError::NoGroup(key.to_owned(), group.to_owned()))?;
+        let created = group.touch_consumer(consumer, now);
+
+        let delivered = match after {
+            None => {
+                let delivered: Vec<PendingStreamEntry> = entries.range((Bound::Excluded(group.last_delivered_id), Bound::Unbounded))
+                    .take(count)
+                    .map(|(id, fields)| (*id, Some(fields.clone())))
+                    .collect();
+                if let Some((last, _)) = delivered.last() {
+                    group.last_delivered_id = *last;
+                }
+                if !no_ack {
+                    for (id, _) in &delivered {
+                        group.pending.insert(*id, PendingEntry { consumer: consumer.to_owned(), delivery_time: now, delivery_count: 1 });
+                    }
+                }
+                delivered
+            },
+            Some(after) => group.pending.range((Bound::Excluded(after), Bound::Unbounded))
+                .filter(|(_, pending)| pending.consumer == consumer)
+                .take(count)
+                .map(|(id, _)| (*id, entries.get(id).cloned()))
+                .collect()
+        };
+        Ok((delivered, created))
+    }
+
+    fn trim(&mut self, strategy: &TrimStrategy) -> usize {
+        let mut removed = 0;
+        while let Some((&first, _)) = self.entries.iter().next() {
+            let trim = match strategy {
+                TrimStrategy::MaxLen(max_len) => self.entries.len() > *max_len,
+                TrimStrategy::MinId(min_id) => first < *min_id,
+            };
+            if !trim {
+                break;
+            }
+            self.entries.remove(&first);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+// A delivered entry, without fields if it was deleted from the stream since.
+type PendingStreamEntry = (StreamId, Option<Vec<(String, String)>>);
+
+enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at `args[0]`, returning the strategy
+// and the number of arguments consumed.
+fn parse_trim(args: &[Arg]) -> Result<Option<(TrimStrategy, usize)>, RESPError> {
+    let kind = match args.first() {
+        Some(kind) => kind.to_ascii_uppercase(),
+        None => return Ok(None)
+    };
+    if kind != "MAXLEN" && kind != "MINID" {
+        return Ok(None);
+    }
+
+    let mut i = 1;
+    // Trimming is always exact, which is allowed for `~` too, it only promises to trim at least
+    // as much as the exact form would have.
+    if matches!(args.get(i).map(|arg| arg.as_str()), Some("=") | Some("~")) {
+        i += 1;
+    }
+    let threshold = args.get(i).ok_or(RESPError::SyntaxError)?;
+    i += 1;
+
+    let strategy = if kind == "MAXLEN" {
+        let max_len = parse_number(threshold)?;
+        if max_len < 0 {
+            return Err(RESPError::NegativeMaxLen);
+        }
+        TrimStrategy::MaxLen(max_len as usize)
+    } else {
+        TrimStrategy::MinId(StreamId::parse(threshold, 0)?)
+    };
+
+    if args.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("LIMIT")) {
+        let limit = args.get(i + 1).ok_or(RESPError::SyntaxError)?;
+        if parse_number(limit)? < 0 {
+            return Err(RESPError::SyntaxError);
+        }
+        i += 2;
+    }
+
+    Ok(Some((strategy, i)))
+}
+
+// Resolves the ID argument of XADD: `*`, `<ms>-*` or an explicit ID.
+fn next_id(stream: &Stream, arg: &str) -> Result<StreamId, RESPError> {
+    let last = stream.last_id();
+
+    let id = if arg == "*" {
+        let ms = now_ms();
+        if ms > last.ms {
+            StreamId { ms, seq: 0 }
+        } else {
+            last.next().ok_or(RESPError::StreamIdTooSmall)?
+        }
+    } else if let Some(ms) = arg.strip_suffix("-*") {
+        let ms = ms.parse().map_err(|_| RESPError::InvalidStreamId)?;
+        if ms == last.ms {
+            last.next().filter(|id| id.ms == ms).ok_or(RESPError::StreamIdTooSmall)?
+        } else {
+            StreamId { ms, seq: 0 }
+        }
+    } else {
+        StreamId::parse(arg, 0)?
+    };
+
+    if id == StreamId::MIN {
+        return Err(RESPError::StreamIdZero);
+    }
+    if id <= last {
+        return Err(RESPError::StreamIdTooSmall);
+    }
+    Ok(id)
+}
+
+fn entry_reply((id, fields): StreamEntry) -> RESPValue {
+    let fields = fields.into_iter()
+        .flat_map(|(field, value)| [RESPValue::BlobString(field.into()), RESPValue::BlobString(value.into())])
+        .collect();
+    RESPValue::Array(vec![RESPValue::BlobString(id.to_string().into()), RESPValue::Array(fields)])
+}
+
+fn entries_reply(entries: Vec<StreamEntry>) -> RESPValue {
+    RESPValue::Array(entries.into_iter().map(entry_reply).collect())
+}
+
+fn pending_entries_reply(entries: Vec<PendingStreamEntry>) -> RESPValue {
+    RESPValue::Array(entries.into_iter().map(|(id, fields)| match fields {
+        Some(fields) => entry_reply((id, fields)),
+        None => RESPValue::Array(vec![RESPValue::BlobString(id.to_string().into()), RESPValue::Null])
+    }).collect())
+}
+
+fn ids_reply(ids: impl IntoIterator<Item = StreamId>) -> RESPValue {
+    RESPValue::Array(ids.into_iter().map(|id| RESPValue::BlobString(id.to_string().into())).collect())
+}
+
+// The stream stored at `key` for modification, None if the key doesn't exist.
+fn stream_mut<'a>(db: &'a mut Db, key: &str) -> Result<Option<&'a mut Stream>, RESPError> {
+    match db.get(key) {
+        Some(value) => { value.as_stream()?; },
+        None => return Ok(None)
+    }
+    db.get_mut(key).unwrap().as_stream_mut().map(Some)
+}
+
+// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] *|id field value [field value ...]
+pub fn xadd(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let mut i = 2;
+
+    let mut no_mkstream = false;
+    if command.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("NOMKSTREAM")) {
+        no_mkstream = true;
+        i += 1;
+    }
+
+    let trim = parse_trim(&command[i..])?;
+    if let Some((_, consumed)) = &trim {
+        i += consumed;
+    }
+
+    let id_arg = command.get(i).ok_or(RESPError::WrongNumberOfArguments(command[0].to_string()))?;
+    let pairs = &command[i + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(RESPError::WrongNumberOfArguments(command[0].to_string()));
+    }
+
+    let id = {
+        let mut db = shared.db.lock();
+        if no_mkstream && db.get(key).is_none() {
+            return Ok(RESPValue::Null);
+        }
+        db.get(key).map(Value::as_stream).transpose()?;
+
+        let stream = db.get_or_insert_with(key, || Value::Stream(Stream::default())).as_stream_mut()?;
+        let id = next_id(stream, id_arg)?;
+        stream.add(id, pairs.chunks(2).map(|pair| (pair[0].to_string(), pair[1].to_string())).collect());
+        if let Some((strategy, _)) = &trim {
+            stream.trim(strategy);
+        }
+        id
+    };
+
+    notify_keyspace_event(shared, NOTIFY_STREAM, "xadd", key, 0);
+    if trim.is_some() {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xtrim", key, 0);
+    }
+    shared.keys_ready.notify_waiters();
+    Ok(RESPValue::BlobString(id.to_string().into()))
+}
+
+// Where the ID argument of XADD is, past its options.
+pub fn xadd_id_index(command: &[Arg]) -> usize {
+    let i = if command[2].eq_ignore_ascii_case("NOMKSTREAM") { 3 } else { 2 };
+    match parse_trim(&command[i..]) {
+        Ok(Some((_, consumed))) => i + consumed,
+        _ => i
+    }
+}
+
+pub fn xlen(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let db = shared.db.lock();
+    let len = db.get(command[1].as_str()).map(Value::as_stream).transpose()?.map_or(0, Stream::len);
+    Ok(RESPValue::Number(len as i64))
+}
+
+// Parses a range boundary of XRANGE, supporting `-`, `+` and exclusive `(` IDs.
+fn parse_range_bound(arg: &str, is_start: bool) -> Result<Option<StreamId>, RESPError> {
+    match arg {
+        "-" => Ok(Some(StreamId::MIN)),
+        "+" => Ok(Some(StreamId::MAX)),
+        _ => {
+            let missing_seq = if is_start { 0 } else { u64::MAX };
+            match arg.strip_prefix('(') {
+                Some(id) => {
+                    let id = StreamId::parse(id, missing_seq)?;
+                    Ok(if is_start { id.next() } else { id.prev() })
+                },
+                None => StreamId::parse(arg, missing_seq).map(Some)
+            }
+        }
+    }
+}
+
+// XRANGE key start end [COUNT count] / XREVRANGE key end start [COUNT count]
+pub fn xrange(command: &[Arg], shared: &SharedState, reverse: bool) -> Result<RESPValue, RESPError> {
+    let (start, end) = if reverse { (&command[3], &command[2]) } else { (&command[2], &command[3]) };
+    let start = parse_range_bound(start, true)?;
+    let end = parse_range_bound(end, false)?;
+
+    let count = match &command[4..] {
+        [] => None,
+        [option, count] if option.eq_ignore_ascii_case("COUNT") => Some(parse_number(count)?.max(0) as usize),
+        _ => return Err(RESPError::SyntaxError)
+    };
+
+    let db = shared.db.lock();
+    let stream = match db.get(command[1].as_str()) {
+        Some(value) => value.as_stream()?,
+        None => return Ok(RESPValue::Array(vec![]))
+    };
+
+    let entries = match (start, end) {
+        (Some(start), Some(end)) => stream.range(start, end, count, reverse),
+        // An exclusive bound past the edge of the ID space.
+        _ => vec![]
+    };
+    Ok(entries_reply(entries))
+}
+
+pub fn xdel(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let ids = command[2..].iter().map(|id| StreamId::parse(id, 0)).collect::<Result<Vec<_>, _>>()?;
+
+    let deleted = {
+        let mut db = shared.db.lock();
+        let stream = match stream_mut(&mut db, &command[1])? {
+            Some(stream) => stream,
+            None => return Ok(RESPValue::Number(0))
+        };
+        ids.iter().filter(|id| stream.delete(id)).count()
+    };
+
+    if deleted > 0 {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xdel", &command[1], 0);
+    }
+    Ok(RESPValue::Number(deleted as i64))
+}
+
+// XTRIM key MAXLEN|MINID [=|~] threshold [LIMIT count]
+pub fn xtrim(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (strategy, consumed) = parse_trim(&command[2..])?.ok_or(RESPError::SyntaxError)?;
+    if 2 + consumed != command.len() {
+        return Err(RESPError::SyntaxError);
+    }
+
+    let removed = {
+        let mut db = shared.db.lock();
+        match stream_mut(&mut db, &command[1])? {
+            Some(stream) => stream.trim(&strategy),
+            None => return Ok(RESPValue::Number(0))
+        }
+    };
+
+    if removed > 0 {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xtrim", &command[1], 0);
+    }
+    Ok(RESPValue::Number(removed as i64))
+}
+
+pub struct XReadArgs {
+    // The group and consumer names of XREADGROUP.
+    pub group: Option<(String, String)>,
+    pub no_ack: bool,
+    pub count: Option<usize>,
+    pub block: Option<u64>,
+    pub keys: Vec<String>,
+    pub ids: Vec<String>,
+}
+
+// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id [id ...]
+pub fn parse_xread(command: &[Arg]) -> Result<XReadArgs, RESPError> {
+    let mut args = XReadArgs { group: None, no_ack: false, count: None, block: None, keys: vec![], ids: vec![] };
+
+    let mut i = 1;
+    if command[0] == "XREADGROUP" {
+        if command.len() < 4 || !command[1].eq_ignore_ascii_case("GROUP") {
+            return Err(RESPError::SyntaxError);
+        }
+        args.group = Some((command[2].to_string(), command[3].to_string()));
+        i = 4;
+    }
+
+    while i < command.len() {
+        match command[i].to_ascii_uppercase().as_str() {
+            "COUNT" => {
+                // A count of zero means no limit.
+                let count = parse_number(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?;
+                args.count = (count > 0).then_some(count as usize);
+                i += 2;
+            },
+            "NOACK" if args.group.is_some() => {
+                args.no_ack = true;
+                i += 1;
+            },
+            "BLOCK" => {
+                let block = parse_number(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?;
+                if block < 0 {
+                    return Err(RESPError::NegativeTimeout);
+                }
+                args.block = Some(block as u64);
+                i += 2;
+            },
+            "STREAMS" => {
+                let streams = &command[i + 1..];
+                if streams.is_empty() || !streams.len().is_multiple_of(2) {
+                    return Err(RESPError::UnbalancedStreams(command[0].to_string()));
+                }
+                let (keys, ids) = streams.split_at(streams.len() / 2);
+                args.keys = keys.iter().map(|key| key.to_string()).collect();
+                args.ids = ids.iter().map(|id| id.to_string()).collect();
+                return Ok(args);
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+    Err(RESPError::SyntaxError)
+}
+
+// Replaces every `$` ID with the current last ID of its stream, so that a blocked XREAD retried
+// later only returns entries added after it was first called.
+pub fn resolve_last_ids(command: &mut [Arg], shared: &SharedState) -> Result<(), RESPError> {
+    let args = parse_xread(command)?;
+    if args.group.is_some() {
+        return Ok(());
+    }
+    let ids_start = command.len() - args.ids.len();
+
+    let db = shared.db.lock();
+    for (i, key) in args.keys.iter().enumerate() {
+        if command[ids_start + i] == "$" {
+            let last_id = db.get(key).map(Value::as_stream).transpose()?.map_or(StreamId::MIN, Stream::last_id);
+            command[ids_start + i] = Arg::from(last_id.to_string());
+        }
+    }
+    Ok(())
+}
+
+// Returns a null reply when none of the streams has new entries, the caller decides whether to
+// block and retry.
+pub fn xread(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let args = parse_xread(command)?;
+    if let Some((group, consumer)) = &args.group {
+        return xreadgroup(&args, group, consumer, shared);
+    }
+
+    let db = shared.db.lock();
+    let mut replies = vec![];
+    for (key, id) in args.keys.iter().zip(&args.ids) {
+        let stream = match db.get(key) {
+            Some(value) => value.as_stream()?,
+            None => {
+                if id != "$" {
+                    StreamId::parse(id, 0)?;
+                }
+                continue;
+            }
+        };
+
+        let after = if id == "$" { stream.last_id() } else { StreamId::parse(id, 0)? };
+        let entries = stream.entries_after(after, args.count);
+        if !entries.is_empty() {
+            replies.push(RESPValue::Array(vec![RESPValue::BlobString(key.to_owned().into()), entries_reply(entries)]));
+        }
+    }
+
+    Ok(if replies.is_empty() { RESPValue::Null } else { RESPValue::Array(replies) })
+}
+
+// Like XREAD, a null reply means there's nothing new for the group and the caller may block. Reading
+// the history of pending entries never blocks.
+fn xreadgroup(args: &XReadArgs, group: &str, consumer: &str, shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let ids = args.ids.iter()
+        .map(|id| if id == ">" { Ok(None) } else { StreamId::parse(id, 0).map(Some) })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut replies = vec![];
+    let mut created = vec![];
+    {
+        let mut db = shared.db.lock();
+        for (key, after) in args.keys.iter().zip(ids) {
+            let stream = stream_mut(&mut db, key)?.ok_or_else(|| RESPError::NoGroup(key.to_owned(), group.to_owned()))?;
+            let (entries, created_consumer) = stream.read_group(key, group, consumer, after, args.count, args.no_ack)?;
+            if created_consumer {
+                created.push(key);
+            }
+            if after.is_some() || !entries.is_empty() {
+                replies.push(RESPValue::Array(vec![RESPValue::BlobString(key.to_owned().into()), pending_entries_reply(entries)]));
+            }
+        }
+    }
+
+    for key in created {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xgroup-createconsumer", key, 0);
+    }
+    Ok(if replies.is_empty() { RESPValue::Null } else { RESPValue::Array(replies) })
+}
+
+// XGROUP CREATE key group id|$ [MKSTREAM] [ENTRIESREAD entries-read]
+// XGROUP DESTROY key group
+// XGROUP CREATECONSUMER key group consumer
+// XGROUP DELCONSUMER key group consumer
+pub fn xgroup(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let (key, group) = (command[2].as_str(), command[3].as_str());
+    let wrong_arity = |expected: usize| command.len() != expected;
+
+    let (reply, event) = match subcommand.as_str() {
+        "CREATE" => {
+            if command.len() < 5 {
+                return Err(RESPError::WrongNumberOfArguments(format!("{}|{}", command[0], subcommand)));
+            }
+
+            let mut mkstream = false;
+            let mut i = 5;
+            while i < command.len() {
+                match command[i].to_ascii_uppercase().as_str() {
+                    "MKSTREAM" => {
+                        mkstream = true;
+                        i += 1;
+                    },
+                    // Lag tracking isn't supported, so the number of entries read is only validated.
+                    "ENTRIESREAD" => {
+                        parse_number(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?;
+                        i += 2;
+                    },
+                    _ => return Err(RESPError::SyntaxError)
+                }
+            }
+
+            let mut db = shared.db.lock();
+            let stream = match stream_mut(&mut db, key)? {
+                Some(stream) => stream,
+                None if mkstream => db.get_or_insert_with(key, || Value::Stream(Stream::default())).as_stream_mut()?,
+                None => return Err(RESPError::NoStreamForGroup)
+            };
+
+            let last_delivered_id = if command[4] == "$" { stream.last_id() } else { StreamId::parse(&command[4], 0)? };
+            if stream.groups.contains_key(group) {
+                return Err(RESPError::GroupExists);
+            }
+            stream.groups.insert(group.to_owned(), ConsumerGroup { last_delivered_id, ..Default::default() });
+            (RESPValue::SimpleString(String::from("OK")), Some("xgroup-create"))
+        },
+        "DESTROY" => {
+            if wrong_arity(4) {
+                return Err(RESPError::WrongNumberOfArguments(format!("{}|{}", command[0], subcommand)));
+            }
+
+            let mut db = shared.db.lock();
+            let stream = stream_mut(&mut db, key)?.ok_or(RESPError::NoStreamForGroup)?;
+            let destroyed = stream.groups.remove(group).is_some();
+            (RESPValue::Number(destroyed as i64), destroyed.then_some("xgroup-destroy"))
+        },
+        "CREATECONSUMER" => {
+            if wrong_arity(5) {
+                return Err(RESPError::WrongNumberOfArguments(format!("{}|{}", command[0], subcommand)));
+            }
+
+            let mut db = shared.db.lock();
+            let stream = stream_mut(&mut db, key)?.ok_or(RESPError::NoStreamForGroup)?;
+            let created = stream.group_mut(key, group)?.touch_consumer(&command[4], now_ms());
+            (RESPValue::Number(created as i64), created.then_some("xgroup-createconsumer"))
+        },
+        "DELCONSUMER" => {
+            if wrong_arity(5) {
+                return Err(RESPError::WrongNumberOfArguments(format!("{}|{}", command[0], subcommand)));
+            }
+
+            let mut db = shared.db.lock();
+            let stream = stream_mut(&mut db, key)?.ok_or(RESPError::NoStreamForGroup)?;
+            let group = stream.group_mut(key, group)?;
+            let consumer = command[4].as_str();
+            if group.consumers.remove(consumer).is_some() {
+                // The consumer's pending entries are dropped along with it.
+                let before = group.pending.len();
+                group.pending.retain(|_, pending| pending.consumer != *consumer);
+                (RESPValue::Number((before - group.pending.len()) as i64), Some("xgroup-delconsumer"))
+            } else {
+                (RESPValue::Number(0), None)
+            }
+        },
+        _ => return Err(RESPError::UnsupportedCommand(format!("XGROUP {}", command[1])))
+    };
+
+    if let Some(event) = event {
+        notify_keyspace_event(shared, NOTIFY_STREAM, event, key, 0);
+    }
+    Ok(reply)
+}
+
+// XACK key group id [id ...]
+pub fn xack(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let ids = command[3..].iter().map(|id| StreamId::parse(id, 0)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut db = shared.db.lock();
+    let group = match stream_mut(&mut db, &command[1])?.and_then(|stream| stream.groups.get_mut(command[2].as_str())) {
+        Some(group) => group,
+        None => return Ok(RESPValue::Number(0))
+    };
+    let acknowledged = ids.iter().filter(|id| group.pending.remove(id).is_some()).count();
+    Ok(RESPValue::Number(acknowledged as i64))
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+pub fn xpending(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (key, group_name) = (command[1].as_str(), command[2].as_str());
+
+    let mut min_idle = 0;
+    let mut i = 3;
+    if command.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("IDLE")) {
+        min_idle = parse_number(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?.max(0) as u64;
+        i += 2;
+    }
+    let extended = match &command[i..] {
+        [] if i == 3 => None,
+        [start, end, count] | [start, end, count, _] => {
+            let start = parse_range_bound(start, true)?;
+            let end = parse_range_bound(end, false)?;
+            let count = parse_number(count)?.max(0) as usize;
+            Some((start, end, count, command.get(i + 3)))
+        },
+        _ => return Err(RESPError::SyntaxError)
+    };
+
+    let db = shared.db.lock();
+    let stream = db.get(key).map(Value::as_stream).transpose()?
+        .ok_or_else(|| RESPError::NoGroup(key.to_owned(), group_name.to_owned()))?;
+    let group = stream.group(key, group_name)?;
+
+    let (start, end, count, consumer) = match extended {
+        Some(extended) => extended,
+        None => {
+            let (first, last) = match (group.pending.keys().next(), group.pending.keys().next_back()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => return Ok(RESPValue::Array(vec![RESPValue::Number(0), RESPValue::Null, RESPValue::Null, RESPValue::Null]))
+            };
+
+            let mut per_consumer: BTreeMap<&str, usize> = BTreeMap::new();
+            for pending in group.pending.values() {
+                *per_consumer.entry(&pending.consumer).or_default() += 1;
+            }
+            let consumers = per_consumer.into_iter()
+                .map(|(consumer, count)| RESPValue::Array(vec![RESPValue::BlobString(consumer.to_owned().into()), RESPValue::BlobString(count.to_string().into())]))
+                .collect();
+            return Ok(RESPValue::Array(vec![
+                RESPValue::Number(group.pending.len() as i64),
+                RESPValue::BlobString(first.to_string().into()),
+                RESPValue::BlobString(last.to_string().into()),
+                RESPValue::Array(consumers),
+            ]));
+        }
+    };
+
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) if start <= end => (start, end),
+        _ => return Ok(RESPValue::Array(vec![]))
+    };
+    let now = now_ms();
+    let entries = group.pending.range(start..=end)
+        .filter(|(_, pending)| consumer.is_none_or(|consumer| pending.consumer == *consumer))
+        .filter(|(_, pending)| now.saturating_sub(pending.delivery_time) >= min_idle)
+        .take(count)
+        .map(|(id, pending)| RESPValue::Array(vec![
+            RESPValue::BlobString(id.to_string().into()),
+            RESPValue::BlobString(pending.consumer.to_owned().into()),
+            RESPValue::Number(now.saturating_sub(pending.delivery_time) as i64),
+            RESPValue::Number(pending.delivery_count as i64),
+        ]))
+        .collect();
+    Ok(RESPValue::Array(entries))
+}
+
+// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-time-milliseconds]
+//     [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID lastid]
+pub fn xclaim(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (key, group_name, consumer) = (command[1].as_str(), command[2].as_str(), command[3].as_str());
+    let now = now_ms();
+    let mut options = ClaimOptions {
+        now,
+        min_idle: parse_number(&command[4])?.max(0) as u64,
+        delivery_time: now,
+        retry_count: None,
+        just_id: false,
+    };
+
+    // IDs are read up to the first argument that isn't one.
+    let mut i = 5;
+    let mut ids = vec![];
+    while let Some(Ok(id)) = command.get(i).map(|id| StreamId::parse(id, 0)) {
+        ids.push(id);
+        i += 1;
+    }
+
+    let mut force = false;
+    let mut last_id = None;
+    while i < command.len() {
+        let value = command.get(i + 1);
+        let number = || -> Result<u64, RESPError> { Ok(parse_number(value.ok_or(RESPError::SyntaxError)?)?.max(0) as u64) };
+        match command[i].to_ascii_uppercase().as_str() {
+            "IDLE" => options.delivery_time = now.saturating_sub(number()?),
+            "TIME" => options.delivery_time = number()?,
+            "RETRYCOUNT" => options.retry_count = Some(number()?),
+            "LASTID" => last_id = Some(StreamId::parse(value.ok_or(RESPError::SyntaxError)?, 0)?),
+            "FORCE" => {
+                force = true;
+                i += 1;
+                continue;
+            },
+            "JUSTID" => {
+                options.just_id = true;
+                i += 1;
+                continue;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+        i += 2;
+    }
+
+    let (claimed, created) = {
+        let mut db = shared.db.lock();
+        let stream = stream_mut(&mut db, key)?.ok_or_else(|| RESPError::NoGroup(key.to_owned(), group_name.to_owned()))?;
+        let Stream { entries, groups, .. } = stream;
+        let group = groups.get_mut(group_name).ok_or_else(|| RESPError::NoGroup(key.to_owned(), group_name.to_owned()))?;
+
+        if let Some(last_id) = last_id {
+            group.last_delivered_id = group.last_delivered_id.max(last_id);
+        }
+        let created = group.touch_consumer(consumer, now);
+
+        let mut claimed = vec![];
+        for id in &ids {
+            let fields = match entries.get(id) {
+                Some(fields) => fields,
+                None => {
+                    // Deleted entries can't be claimed, so there's no point in keeping them pending.
+                    group.pending.remove(id);
+                    continue;
+                }
+            };
+            if force && !group.pending.contains_key(id) {
+                group.pending.insert(*id, PendingEntry { consumer: consumer.to_owned(), delivery_time: 0, delivery_count: 0 });
+            }
+            if group.claim(id, consumer, &options) {
+                claimed.push((*id, fields.clone()));
+            }
+        }
+        (claimed, created)
+    };
+
+    if created {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xgroup-createconsumer", key, 0);
+    }
+    Ok(if options.just_id { ids_reply(claimed.into_iter().map(|(id, _)| id)) } else { entries_reply(claimed) })
+}
+
+// XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+pub fn xautoclaim(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let (key, group_name, consumer) = (command[1].as_str(), command[2].as_str(), command[3].as_str());
+    let now = now_ms();
+    let mut options = ClaimOptions {
+        now,
+        min_idle: parse_number(&command[4])?.max(0) as u64,
+        delivery_time: now,
+        retry_count: None,
+        just_id: false,
+    };
+    let start = parse_range_bound(&command[5], true)?;
+
+    let mut count = 100;
+    let mut i = 6;
+    while i < command.len() {
+        match command[i].to_ascii_uppercase().as_str() {
+            "COUNT" => {
+                let value = parse_number(command.get(i + 1).ok_or(RESPError::SyntaxError)?)?;
+                if value < 1 {
+                    return Err(RESPError::NonPositiveCount);
+                }
+                count = value as usize;
+                i += 2;
+            },
+            "JUSTID" => {
+                options.just_id = true;
+                i += 1;
+            },
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+
+    let (next, claimed, deleted, created) = {
+        let mut db = shared.db.lock();
+        let stream = stream_mut(&mut db, key)?.ok_or_else(|| RESPError::NoGroup(key.to_owned(), group_name.to_owned()))?;
+        let Stream { entries, groups, .. } = stream;
+        let group = groups.get_mut(group_name).ok_or_else(|| RESPError::NoGroup(key.to_owned(), group_name.to_owned()))?;
+        let created = group.touch_consumer(consumer, now);
+
+        // At most ten entries are scanned for every entry that may be claimed, the scan continues
+        // from the returned ID on the next call, which is 0-0 once the whole list was scanned.
+        let attempts = count.saturating_mul(10);
+        let candidates: Vec<StreamId> = match start {
+            Some(start) => group.pending.range(start..).map(|(id, _)| *id).take(attempts.saturating_add(1)).collect(),
+            None => vec![]
+        };
+
+        let mut next = StreamId::MIN;
+        let mut claimed = vec![];
+        let mut deleted = vec![];
+        for (scanned, id) in candidates.into_iter().enumerate() {
+            if scanned == attempts || claimed.len() == count {
+                next = id;
+                break;
+            }
+
+            match entries.get(&id) {
+                Some(fields) => {
+                    if group.claim(&id, consumer, &options) {
+                        claimed.push((id, fields.clone()));
+                    }
+                },
+                None => {
+                    group.pending.remove(&id);
+                    deleted.push(id);
+                }
+            }
+        }
+        (next, claimed, deleted, created)
+    };
+
+    if created {
+        notify_keyspace_event(shared, NOTIFY_STREAM, "xgroup-createconsumer", key, 0);
+    }
+    let claimed = if options.just_id { ids_reply(claimed.into_iter().map(|(id, _)| id)) } else { entries_reply(claimed) };
+    Ok(RESPValue::Array(vec![RESPValue::BlobString(next.to_string().into()), claimed, ids_reply(deleted)]))
+}
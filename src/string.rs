@@ -0,0 +1,106 @@
+// Setting strings: SET, and the legacy commands that are SET with an option or two (SETNX, SETEX,
+// PSETEX and GETSET), each keeping the reply it always had. Also the counters kept in strings (INCR,
+// INCRBY, DECR and DECRBY).
+
+use crate::arg::Arg;
+use crate::db::{now_ms, Value};
+use crate::lazyfree;
+use crate::notify::{notify_keyspace_event, NOTIFY_GENERIC, NOTIFY_STRING};
+use crate::{parse_number, RESPError, RESPValue, SharedState};
+
+// Sets the key to the string, discarding its TTL or replacing it with one expiring at `expire_at`.
+// Returns the value it replaced, or None without setting it when `nx` and the key exists.
+fn set(key: &str, value: &Arg, nx: bool, expire_at: Option<u64>, shared: &SharedState) -> Option<Option<Value>> {
+    let old = {
+        let mut db = shared.db.lock();
+        if nx && db.contains_key(key) {
+            return None;
+        }
+        let old = db.set(key.to_owned(), Value::string(value.to_bytes()));
+        if let Some(at) = expire_at {
+            db.set_expire(key, at);
+        }
+        old
+    };
+    notify_keyspace_event(shared, NOTIFY_STRING, "set", key, 0);
+    if expire_at.is_some() {
+        notify_keyspace_event(shared, NOTIFY_GENERIC, "expire", key, 0);
+    }
+    Some(old)
+}
+
+// SET key value
+pub fn set_command(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    Ok(match set(&command[1], &command[2], false, None, shared).flatten() {
+        Some(old @ (Value::String(_) | Value::CompressedString(_))) => RESPValue::BlobString(old.as_string()?),
+        old => {
+            lazyfree::free_replaced(old, shared);
+            RESPValue::SimpleString(String::from("OK"))
+        }
+    })
+}
+
+// SETNX key value
+pub fn setnx(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    Ok(RESPValue::Number(set(&command[1], &command[2], true, None, shared).is_some() as i64))
+}
+
+// SETEX key seconds value
+// PSETEX key milliseconds value
+pub fn setex(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let ttl = parse_number(&command[2])?;
+    let ms = if command[0] == "SETEX" { ttl.checked_mul(1000) } else { Some(ttl) };
+    let at = ms.filter(|ms| *ms > 0).and_then(|ms| ms.checked_add(now_ms() as i64))
+        .ok_or_else(|| RESPError::InvalidExpireTime(command[0].to_string()))?;
+
+    let old = set(&command[1], &command[3], false, Some(at as u64), shared).flatten();
+    lazyfree::free_replaced(old, shared);
+    Ok(RESPValue::SimpleString(String::from("OK")))
+}
+
+// GETSET key value
+pub fn getset(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    // Only strings are replaced.
+    if let Some(value) = shared.db.lock().get(command[1].as_str()) {
+        value.as_string()?;
+    }
+    Ok(match set(&command[1], &command[2], false, None, shared).flatten() {
+        Some(old) => RESPValue::BlobString(old.as_string()?),
+        None => RESPValue::Null
+    })
+}
+
+// INCR key
+// INCRBY key increment
+// DECR key
+// DECRBY key decrement
+pub fn incrby(command: &[Arg], shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let key = command[1].as_str();
+    let by = match command[0].as_str() {
+        "INCR" => 1,
+        "DECR" => -1,
+        "INCRBY" => parse_number(&command[2])?,
+        _ => parse_number(&command[2])?.checked_neg().ok_or(RESPError::IncrementOverflow)?
+    };
+
+    let value = {
+        let mut db = shared.db.lock();
+        let current = match db.get(key) {
+            Some(value) => std::str::from_utf8(&value.as_string()?).ok().and_then(|value| value.parse::<i64>().ok())
+                .ok_or(RESPError::NotAnInteger)?,
+            None => 0
+        };
+        let value = current.checked_add(by).ok_or(RESPError::IncrementOverflow)?;
+        // Unlike SET, the key keeps its TTL.
+        match db.get_mut(key) {
+            Some(old) => *old = Value::string(value.to_string()),
+            None => {
+                db.set(key.to_owned(), Value::string(value.to_string()));
+            }
+        }
+        value
+    };
+
+    notify_keyspace_event(shared, NOTIFY_STRING, "incrby", key, 0);
+    Ok(RESPValue::Number(value))
+}
@@ -0,0 +1,60 @@
+// Integration with systemd. With socket activation the listening sockets are passed from systemd
+// (LISTEN_PID and LISTEN_FDS, the sockets starting at descriptor 3) and served instead of binding
+// `port` and `unixsocket`. With Type=notify units systemd is told once the dataset was loaded and
+// connections are accepted, so no traffic is routed to a server still loading it.
+
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use tokio::net::{TcpListener, UnixListener};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+// The TCP and unix listening sockets passed by systemd, none without socket activation.
+pub fn listeners() -> io::Result<(Vec<TcpListener>, Vec<UnixListener>)> {
+    let (mut tcp, mut unix) = (vec![], vec![]);
+    let fds = match (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) {
+        (Ok(pid), Ok(fds)) if pid == std::process::id().to_string() => fds.parse::<RawFd>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid LISTEN_FDS '{}'", fds)))?,
+        _ => return Ok((tcp, unix))
+    };
+    // The processes spawned later on weren't the ones activated.
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        // Safety: the descriptors were passed to this process, which owns them from now on.
+        let socket = socket2::Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        if socket.r#type()? != socket2::Type::STREAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("descriptor {} is not a stream socket", fd)));
+        }
+        if addr.is_unix() {
+            unix.push(UnixListener::from_std(std::os::unix::net::UnixListener::from(socket))?);
+        } else {
+            tcp.push(TcpListener::from_std(std::net::TcpListener::from(socket))?);
+        }
+    }
+    Ok((tcp, unix))
+}
+
+// Sends a state (like READY=1) to the notification socket of systemd, if there's one.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // Paths starting with @ are in the abstract namespace.
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?,
+        None => socket.send_to(state.as_bytes(), &path)?
+    };
+    Ok(())
+}
@@ -0,0 +1,60 @@
+// The disk tier of the keyspace, built with the tiered-storage feature. With `tiered-storage-dir`
+// set, reaching maxmemory spills the values that weren't accessed for the longest to a sled database
+// in that directory instead of evicting them, and accessing a spilled value reads it back into
+// memory. Only values are spilled, keys and their metadata (TTL, versions, access info) always stay
+// in memory.
+//
+// The tier isn't persistence: it's emptied on startup, and the snapshot or append only file still
+// hold the whole keyspace.
+
+use std::io;
+use std::sync::Arc;
+
+// Values are stored serialized like DUMP does (see rdb::dump). A tier is shared by the shards of
+// the keyspace.
+pub trait Tier: Send + Sync {
+    fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn write(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    fn remove(&self, key: &str) -> io::Result<()>;
+
+    fn clear(&self) -> io::Result<()>;
+}
+
+#[cfg(feature = "tiered-storage")]
+struct Sled(sled::Db);
+
+#[cfg(feature = "tiered-storage")]
+impl Tier for Sled {
+    fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn write(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.0.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.0.remove(key)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        Ok(self.0.clear()?)
+    }
+}
+
+#[cfg(feature = "tiered-storage")]
+pub fn open(dir: &str) -> io::Result<Arc<dyn Tier>> {
+    let tier = Sled(sled::open(dir)?);
+    // Left over from the last run, when the keyspace was loaded from elsewhere.
+    tier.clear()?;
+    Ok(Arc::new(tier))
+}
+
+#[cfg(not(feature = "tiered-storage"))]
+pub fn open(_dir: &str) -> io::Result<Arc<dyn Tier>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "tiered storage is not supported by this build"))
+}
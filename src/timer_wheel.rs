@@ -0,0 +1,168 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+// Every level has 64 slots, each a 64th of the level above it, so 11 levels cover every u64 of
+// milliseconds with slots of a single millisecond at the lowest level.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 11;
+
+#[derive(Clone, Copy)]
+enum Location {
+    Due,
+    Slot(usize, usize),
+}
+
+struct Level<T> {
+    slots: Vec<HashSet<T>>,
+    // A bit per non-empty slot, for finding the next one without scanning.
+    occupied: u64,
+}
+
+// The amount of milliseconds a slot of the level spans.
+fn slot_range(level: usize) -> u64 {
+    1 << (level as u32 * SLOT_BITS)
+}
+
+// The amount of milliseconds all slots of the level span together.
+fn level_range(level: usize) -> u128 {
+    1 << ((level as u32 + 1) * SLOT_BITS)
+}
+
+// The level of a deadline after `now`: the one of the highest 6 bits they differ in, so it's in the
+// current span of every level above it.
+fn level_for(now: u64, deadline: u64) -> usize {
+    let masked = (now ^ deadline) | (SLOTS as u64 - 1);
+    (u64::BITS - 1 - masked.leading_zeros()) as usize / SLOT_BITS as usize
+}
+
+fn slot_for(deadline: u64, level: usize) -> usize {
+    ((deadline >> (level as u32 * SLOT_BITS)) as usize) & (SLOTS - 1)
+}
+
+// A hierarchical timing wheel of items expiring at a time in milliseconds, like the one of the
+// Linux kernel (and of tokio). Inserting and removing are O(1), and handing out due items is O(1)
+// per item plus moving items down a level as their time gets closer, which happens at most once
+// per level.
+pub struct TimerWheel<T> {
+    // The time the wheel has advanced to, items due up to it are in `due`.
+    elapsed: u64,
+    levels: Vec<Level<T>>,
+    due: HashSet<T>,
+    timers: HashMap<T, (u64, Location)>,
+}
+
+// The wheel starts at time 0 and catches up on the first poll, moving every item down the levels.
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        let levels = (0..LEVELS).map(|_| Level { slots: (0..SLOTS).map(|_| HashSet::new()).collect(), occupied: 0 }).collect();
+        Self { elapsed: 0, levels, due: HashSet::new(), timers: HashMap::new() }
+    }
+}
+
+impl<T: Hash + Eq + Clone> TimerWheel<T> {
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, item: &Q) -> Option<u64> where T: Borrow<Q> {
+        self.timers.get(item).map(|(deadline, _)| *deadline)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&T, u64)> {
+        self.timers.iter().map(|(item, (deadline, _))| (item, *deadline))
+    }
+
+    // The amount of items already due that weren't handed out by `poll` yet.
+    pub fn due_len(&self) -> usize {
+        self.due.len()
+    }
+
+    fn place(&mut self, item: T, deadline: u64) -> Location {
+        if deadline <= self.elapsed {
+            self.due.insert(item);
+            return Location::Due;
+        }
+        let level = level_for(self.elapsed, deadline);
+        let slot = slot_for(deadline, level);
+        self.levels[level].slots[slot].insert(item);
+        self.levels[level].occupied |= 1 << slot;
+        Location::Slot(level, slot)
+    }
+
+    // Schedules the item, replacing the deadline it had.
+    pub fn insert(&mut self, item: T, deadline: u64) {
+        self.remove(&item);
+        let location = self.place(item.clone(), deadline);
+        self.timers.insert(item, (deadline, location));
+    }
+
+    // Unschedules the item, returning its deadline.
+    pub fn remove<Q: Hash + Eq + ?Sized>(&mut self, item: &Q) -> Option<u64> where T: Borrow<Q> {
+        let (deadline, location) = self.timers.remove(item)?;
+        match location {
+            Location::Due => {
+                self.due.remove(item);
+            },
+            Location::Slot(level, slot) => {
+                let level = &mut self.levels[level];
+                level.slots[slot].remove(item);
+                if level.slots[slot].is_empty() {
+                    level.occupied &= !(1 << slot);
+                }
+            }
+        }
+        Some(deadline)
+    }
+
+    // The start of the next non-empty slot (and where it is), from the lowest level as the items
+    // of lower levels are always due before the ones of higher levels.
+    fn next_slot(&self) -> Option<(u64, usize, usize)> {
+        self.levels.iter().enumerate().find(|(_, level)| level.occupied != 0).map(|(i, level)| {
+            let now_slot = (self.elapsed / slot_range(i)) as usize & (SLOTS - 1);
+            let slot = (level.occupied.rotate_right(now_slot as u32).trailing_zeros() as usize + now_slot) % SLOTS;
+            let level_start = (self.elapsed as u128 & !(level_range(i) - 1)) as u64;
+            (level_start + slot as u64 * slot_range(i), i, slot)
+        })
+    }
+
+    // Advances the wheel to `now`, moving the items due by then to `due`.
+    fn advance(&mut self, now: u64) {
+        while let Some((start, level, slot)) = self.next_slot() {
+            if start > now {
+                break;
+            }
+            self.elapsed = self.elapsed.max(start);
+            let items = std::mem::take(&mut self.levels[level].slots[slot]);
+            self.levels[level].occupied &= !(1 << slot);
+            // Items of higher levels move down to the slots of their exact deadline.
+            for item in items {
+                let deadline = self.timers[&item].0;
+                let location = self.place(item.clone(), deadline);
+                self.timers.get_mut(&item).unwrap().1 = location;
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+    }
+
+    // Hands out (and unschedules) up to `limit` items due by `now`.
+    pub fn poll(&mut self, now: u64, limit: usize) -> Vec<T> {
+        self.advance(now);
+        let items: Vec<T> = self.due.iter().take(limit).cloned().collect();
+        for item in &items {
+            self.due.remove(item);
+            self.timers.remove(item);
+        }
+        items
+    }
+
+    // The earliest deadline, looking no further than the next non-empty slot.
+    pub fn next_deadline(&self) -> Option<u64> {
+        if !self.due.is_empty() {
+            return Some(self.elapsed);
+        }
+        let (_, level, slot) = self.next_slot()?;
+        self.levels[level].slots[slot].iter().map(|item| self.timers[item].0).min()
+    }
+}
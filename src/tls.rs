@@ -0,0 +1,90 @@
+// TLS, built with the tls feature. With `tls-port` set connections are also accepted there, served
+// over TLS with the certificate and key of `tls-cert-file` and `tls-key-file`. Clients present a
+// certificate signed by a CA of `tls-ca-cert-file` as well, unless `tls-auth-clients` is no (or
+// optional, verifying the ones that do). Setting `port` to 0 leaves only the TLS port.
+
+use std::io;
+
+#[cfg(feature = "tls")]
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::SharedState;
+
+#[cfg(feature = "tls")]
+pub type Acceptor = tokio_rustls::TlsAcceptor;
+
+#[cfg(not(feature = "tls"))]
+pub enum Acceptor {}
+
+#[cfg(feature = "tls")]
+fn read_pem(path: &str) -> Result<io::BufReader<std::fs::File>, String> {
+    std::fs::File::open(path).map(io::BufReader::new).map_err(|e| format!("can't read {}: {}", path, e))
+}
+
+// The TLS configuration of the server, from the certificate, key and CA files of the configuration.
+#[cfg(feature = "tls")]
+pub fn server_config(shared: &SharedState) -> Result<tokio_rustls::rustls::ServerConfig, String> {
+    use std::sync::Arc;
+
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+
+    let (cert_file, key_file) = (shared.config.get("tls-cert-file"), shared.config.get("tls-key-file"));
+    if cert_file.is_empty() || key_file.is_empty() {
+        return Err(String::from("tls-cert-file and tls-key-file are needed to serve TLS"));
+    }
+    let certs = rustls_pemfile::certs(&mut read_pem(&cert_file)?).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {}: {}", cert_file, e))?;
+    let key = rustls_pemfile::private_key(&mut read_pem(&key_file)?)
+        .map_err(|e| format!("invalid key in {}: {}", key_file, e))?
+        .ok_or_else(|| format!("no private key in {}", key_file))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match shared.config.get("tls-auth-clients").as_str() {
+        "no" => builder.with_no_client_auth(),
+        auth_clients => {
+            let ca_file = shared.config.get("tls-ca-cert-file");
+            if ca_file.is_empty() {
+                return Err(String::from("tls-ca-cert-file is needed to authenticate clients, unless tls-auth-clients is no"));
+            }
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut read_pem(&ca_file)?) {
+                let cert = cert.map_err(|e| format!("invalid certificate in {}: {}", ca_file, e))?;
+                roots.add(cert).map_err(|e| format!("invalid CA certificate in {}: {}", ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if auth_clients == "optional" { verifier.allow_unauthenticated() } else { verifier };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+    };
+    builder.with_single_cert(certs, key).map_err(|e| format!("invalid certificate or key: {}", e))
+}
+
+// The acceptor of the connections to the TLS port, None when there's none.
+#[cfg(feature = "tls")]
+pub fn acceptor(shared: &SharedState) -> Result<Option<Acceptor>, String> {
+    if shared.config.get_int("tls-port") == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Acceptor::from(std::sync::Arc::new(server_config(shared)?))))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn acceptor(shared: &SharedState) -> Result<Option<Acceptor>, String> {
+    match shared.config.get_int("tls-port") {
+        0 => Ok(None),
+        _ => Err(String::from("TLS is not supported by this build"))
+    }
+}
+
+// Runs the TLS handshake of a connection to the TLS port.
+#[cfg(feature = "tls")]
+pub async fn accept(acceptor: &Acceptor, socket: TcpStream) -> io::Result<impl AsyncRead + AsyncWrite + Unpin> {
+    acceptor.accept(socket).await
+}
+
+#[cfg(not(feature = "tls"))]
+pub async fn accept(acceptor: &Acceptor, _socket: TcpStream) -> io::Result<TcpStream> {
+    match *acceptor {}
+}
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::arg::Arg;
+use crate::pubsub::{ClientId, PubSub};
+use crate::{RESPError, RESPValue, SharedState};
+
+// Connections speak RESP2 (there is no HELLO 3 for push frames), so invalidations are sent as
+// messages of this channel to the client the tracking one redirects to, which subscribed to it. As a
+// subscribed client can't read keys, tracking requires REDIRECT.
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Default)]
+struct Options {
+    redirect: Option<ClientId>,
+    // Every modified key matching a prefix is invalidated, instead of only the keys read.
+    bcast: bool,
+    prefixes: Vec<String>,
+    // Keys are only tracked after CLIENT CACHING yes.
+    optin: bool,
+    // Keys are tracked unless after CLIENT CACHING no.
+    optout: bool,
+    // Keys modified by the client itself aren't invalidated.
+    noloop: bool,
+}
+
+// The keys clients may have cached, for invalidating them once they are modified.
+#[derive(Default)]
+pub struct Tracking {
+    clients: HashMap<ClientId, Options>,
+    // Keys read by tracking clients not in BCAST mode, forgotten once invalidated.
+    keys: HashMap<String, HashSet<ClientId>>,
+}
+
+impl Tracking {
+    pub fn disable(&mut self, id: ClientId) {
+        if self.clients.remove(&id).is_some() {
+            self.keys.retain(|_, clients| {
+                clients.remove(&id);
+                !clients.is_empty()
+            });
+        }
+    }
+
+    // Remembers the keys read by a client, `caching` being the argument of a CLIENT CACHING that
+    // came right before.
+    pub fn track_reads(&mut self, id: ClientId, keys: &[&str], caching: Option<bool>) {
+        let tracked = match self.clients.get(&id) {
+            Some(options) if options.bcast => false,
+            Some(options) if options.optin => caching == Some(true),
+            Some(options) if options.optout => caching != Some(false),
+            Some(_) => true,
+            None => false
+        };
+        if tracked {
+            for key in keys {
+                self.keys.entry(key.to_string()).or_default().insert(id);
+            }
+        }
+    }
+
+    fn send(&self, id: ClientId, keys: RESPValue, pubsub: &PubSub) {
+        let target = self.clients[&id].redirect.unwrap_or(id);
+        pubsub.send_to(INVALIDATE_CHANNEL, target, keys);
+    }
+
+    // Invalidates the keys for every client that might have them cached, `modified_by` being the
+    // client that modified them (if any).
+    pub fn invalidate(&mut self, keys: &[&str], modified_by: Option<ClientId>, pubsub: &PubSub) {
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let mut invalidated: HashMap<ClientId, Vec<RESPValue>> = HashMap::new();
+        for key in keys {
+            let mut clients: HashSet<ClientId> = self.keys.remove(*key).unwrap_or_default();
+            clients.extend(self.clients.iter()
+                .filter(|(_, options)| options.bcast && (options.prefixes.is_empty() || options.prefixes.iter().any(|prefix| key.starts_with(prefix))))
+                .map(|(id, _)| *id));
+
+            for id in clients {
+                if self.clients[&id].noloop && modified_by == Some(id) {
+                    continue;
+                }
+                invalidated.entry(id).or_default().push(RESPValue::BlobString(key.to_string().into()));
+            }
+        }
+
+        for (id, keys) in invalidated {
+            self.send(id, RESPValue::Array(keys), pubsub);
+        }
+    }
+
+    // Invalidates everything (on FLUSHDB and FLUSHALL), which is a null instead of the keys.
+    pub fn invalidate_all(&mut self, pubsub: &PubSub) {
+        self.keys.clear();
+        for id in self.clients.keys() {
+            self.send(*id, RESPValue::Null, pubsub);
+        }
+    }
+}
+
+fn parse_options(args: &[Arg]) -> Result<Options, RESPError> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_ascii_uppercase().as_str() {
+            "REDIRECT" => {
+                let id = args.next().ok_or(RESPError::SyntaxError)?;
+                options.redirect = Some(id.parse().map_err(|_| RESPError::NotAnInteger)?);
+            },
+            "PREFIX" => options.prefixes.push(args.next().ok_or(RESPError::SyntaxError)?.to_string()),
+            "BCAST" => options.bcast = true,
+            "OPTIN" => options.optin = true,
+            "OPTOUT" => options.optout = true,
+            "NOLOOP" => options.noloop = true,
+            _ => return Err(RESPError::SyntaxError)
+        }
+    }
+
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err(RESPError::InvalidTrackingOptions("PREFIX option requires BCAST mode to be enabled"));
+    }
+    if options.optin && options.optout {
+        return Err(RESPError::InvalidTrackingOptions("You can't use both OPTIN and OPTOUT"));
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Err(RESPError::InvalidTrackingOptions("OPTIN and OPTOUT are not compatible with BCAST"));
+    }
+    Ok(options)
+}
+
+// CLIENT TRACKING ON|OFF [REDIRECT client-id] [PREFIX prefix [PREFIX prefix ...]] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]
+// CLIENT CACHING YES|NO
+// CLIENT GETREDIR
+pub fn client_tracking(command: &[Arg], id: ClientId, caching: &mut Option<bool>, shared: &SharedState) -> Result<RESPValue, RESPError> {
+    let subcommand = command[1].to_ascii_uppercase();
+    let check_arity = |valid: bool| if valid {
+        Ok(())
+    } else {
+        Err(RESPError::WrongNumberOfArguments(format!("CLIENT|{}", subcommand)))
+    };
+    let mut tracking = shared.tracking.lock().unwrap();
+
+    match subcommand.as_str() {
+        "TRACKING" => {
+            check_arity(command.len() >= 3)?;
+            match command[2].to_ascii_uppercase().as_str() {
+                "ON" => {
+                    let options = parse_options(&command[3..])?;
+                    if options.redirect.is_none() {
+                        return Err(RESPError::InvalidTrackingOptions(
+                            "Tracking without REDIRECT requires RESP3, which is not supported, redirect to a client subscribed to __redis__:invalidate"));
+                    }
+                    if options.redirect.is_some_and(|redirect| !shared.clients.exists(redirect)) {
+                        return Err(RESPError::InvalidTrackingOptions("The client ID you want redirect to does not exist"));
+                    }
+                    // Switching modes forgets the keys tracked so far, as they were tracked by other rules.
+                    tracking.disable(id);
+                    tracking.clients.insert(id, options);
+                },
+                "OFF" => tracking.disable(id),
+                _ => return Err(RESPError::SyntaxError)
+            }
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "CACHING" => {
+            check_arity(command.len() == 3)?;
+            if !tracking.clients.get(&id).is_some_and(|options| options.optin || options.optout) {
+                return Err(RESPError::InvalidTrackingOptions(
+                    "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"));
+            }
+            *caching = match command[2].to_ascii_uppercase().as_str() {
+                "YES" => Some(true),
+                "NO" => Some(false),
+                _ => return Err(RESPError::SyntaxError)
+            };
+            Ok(RESPValue::SimpleString(String::from("OK")))
+        },
+        "GETREDIR" => {
+            check_arity(command.len() == 2)?;
+            Ok(RESPValue::Number(match tracking.clients.get(&id) {
+                Some(options) => options.redirect.map_or(0, |redirect| redirect as i64),
+                None => -1
+            }))
+        },
+        _ => Err(RESPError::UnsupportedCommand(format!("CLIENT {}", command[1])))
+    }
+}
@@ -0,0 +1,182 @@
+// The io_uring network backend, built with the io-uring feature on Linux. With `network-backend
+// io_uring` the connections to `port` are accepted, read and written through io_uring, by threads of
+// their own (as many as `threads`, or one per core) each listening on every address of `bind` with
+// SO_REUSEPORT, so the kernel spreads the connections among them. Reads and writes use buffers from
+// a pool of the thread. The connections are served by the thread that accepted them, on its own
+// runtime, through an in-memory pipe to the socket, as serving them needs AsyncRead and AsyncWrite
+// which the sockets of io_uring don't implement. That pipe costs a copy each way, so this isn't
+// faster than epoll, measure before choosing it. TLS, the unix socket and the sockets of systemd are
+// served by the main runtime either way.
+
+use std::sync::Arc;
+
+use crate::SharedState;
+
+pub const BACKENDS: &[&str] = &["epoll", "io_uring"];
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub fn serve(port: u16, shared: &Arc<SharedState>) -> Result<(), String> {
+    use std::net::ToSocketAddrs;
+
+    let threads = match shared.config.get_int("threads") {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads as usize
+    };
+    let mut addresses = vec![];
+    for (ip, optional) in crate::bind_addresses(shared) {
+        match (ip.as_str(), port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addresses.push((addr, optional)),
+            Ok(None) if optional => {},
+            Err(e) if optional => crate::logging::log(shared, "warning", format!("Skipping listening on {}:{}: {}", ip, port, e)),
+            Ok(None) => return Err(format!("Failed listening on {}:{}: no address", ip, port)),
+            Err(e) => return Err(format!("Failed listening on {}:{}: {}", ip, port, e))
+        }
+    }
+    // Every thread listens on its own, and the startup waits for all of them to listen.
+    let (listening, results) = std::sync::mpsc::channel();
+    for index in 0..threads {
+        let (addresses, shared, listening) = (addresses.clone(), shared.clone(), listening.clone());
+        std::thread::Builder::new().name(format!("uring-{}", index))
+            .spawn(move || tokio_uring::start(backend::serve(port, addresses, index == 0, shared, listening)))
+            .map_err(|e| format!("Failed starting the io_uring threads: {}", e))?;
+    }
+    drop(listening);
+    for _ in 0..threads {
+        results.recv().map_err(|_| String::from("Failed starting io_uring"))??;
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+pub fn serve(_port: u16, _shared: &Arc<SharedState>) -> Result<(), String> {
+    Err(String::from("io_uring is not supported by this build"))
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod backend {
+    use std::cell::RefCell;
+    use std::net::{Shutdown, SocketAddr};
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    use std::rc::Rc;
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+
+    use socket2::SockRef;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+    use tokio_uring::net::{TcpListener, TcpStream};
+
+    use crate::{handle_connection, logging, set_socket_options, SharedState};
+
+    const BUFFER_SIZE: usize = 16 * 1024;
+    // Returned buffers beyond these are freed.
+    const MAX_POOLED_BUFFERS: usize = 1024;
+
+    #[derive(Default)]
+    struct Pool {
+        buffers: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Pool {
+        fn take(&self) -> Vec<u8> {
+            self.buffers.borrow_mut().pop().unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE))
+        }
+
+        fn give(&self, mut buffer: Vec<u8>) {
+            buffer.clear();
+            let mut buffers = self.buffers.borrow_mut();
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(buffer);
+            }
+        }
+    }
+
+    // Listens on the addresses (with SO_REUSEPORT) and accepts connections, reporting whether it
+    // listens once it does.
+    pub async fn serve(port: u16, addresses: Vec<(SocketAddr, bool)>, first: bool, shared: Arc<SharedState>, listening: Sender<Result<(), String>>) {
+        let mut listeners = vec![];
+        for (addr, optional) in addresses {
+            match TcpListener::bind(addr) {
+                Ok(listener) => listeners.push(listener),
+                // Logged by a single thread.
+                Err(e) if optional => if first {
+                    logging::log(&shared, "warning", format!("Skipping listening on {}: {}", addr, e));
+                },
+                Err(e) => {
+                    let _ = listening.send(Err(format!("Failed listening on {}: {}", addr, e)));
+                    return;
+                }
+            }
+        }
+        if listeners.is_empty() {
+            let _ = listening.send(Err(format!("Failed listening on port {} of any address", port)));
+            return;
+        }
+        let _ = listening.send(Ok(()));
+        drop(listening);
+
+        let pool = Rc::new(Pool::default());
+        futures::future::join_all(listeners.into_iter().map(|listener| {
+            let (shared, pool) = (shared.clone(), pool.clone());
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, addr)) => {
+                            logging::log(&shared, "verbose", format!("New connection from {}", addr));
+                            // Borrowed from the stream, which outlives it.
+                            let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+                            set_socket_options(SockRef::from(&fd), &shared);
+                            let laddr = SockRef::from(&fd).local_addr().ok().and_then(|laddr| laddr.as_socket());
+                            let laddr = laddr.map_or_else(String::new, |laddr| laddr.to_string());
+                            let (pipe, connection) = tokio::io::duplex(BUFFER_SIZE);
+                            tokio_uring::spawn(handle_connection(connection, Some(addr.to_string()), laddr, None, shared.clone()));
+                            tokio_uring::spawn(bridge(stream, pipe, pool.clone()));
+                        },
+                        Err(e) => logging::log(&shared, "warning", format!("Failed accepting a connection: {}", e))
+                    }
+                }
+            }
+        })).await;
+    }
+
+    // Pumps the bytes between the connection and its pipe, until either is closed.
+    async fn bridge(stream: TcpStream, pipe: DuplexStream, pool: Rc<Pool>) {
+        let stream = Rc::new(stream);
+        let (reader, writer) = tokio::io::split(pipe);
+        let receiving = tokio_uring::spawn(receive(stream.clone(), writer, pool.clone()));
+        send(&stream, reader, &pool).await;
+        // The connection was closed, which ends the receiving as well.
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = receiving.await;
+    }
+
+    async fn receive(stream: Rc<TcpStream>, mut pipe: WriteHalf<DuplexStream>, pool: Rc<Pool>) {
+        loop {
+            let (result, buffer) = stream.read(pool.take()).await;
+            let done = match result {
+                Ok(0) | Err(_) => true,
+                Ok(_) => pipe.write_all(&buffer).await.is_err()
+            };
+            pool.give(buffer);
+            if done {
+                break;
+            }
+        }
+        // Lets handle_connection know the client is gone.
+        let _ = pipe.shutdown().await;
+    }
+
+    async fn send(stream: &TcpStream, mut pipe: ReadHalf<DuplexStream>, pool: &Pool) {
+        loop {
+            let mut buffer = pool.take();
+            if !matches!(pipe.read_buf(&mut buffer).await, Ok(1..)) {
+                pool.give(buffer);
+                return;
+            }
+            let (result, buffer) = stream.write_all(buffer).await;
+            pool.give(buffer);
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+}
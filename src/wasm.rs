@@ -0,0 +1,161 @@
+// Commands implemented by WebAssembly modules.
+//
+// Every exported function of the form `(args_ptr: i32, args_len: i32) -> i64` becomes a command
+// named after the export. The arguments are written into the module's memory (through its exported
+// `alloc(len: i32) -> i32`) as a RESP array of blob strings, and the function returns the location
+// of its RESP encoded reply packed as `ptr << 32 | len`.
+//
+// Modules can access the keyspace by importing from the `bast` module:
+// - `get(key_ptr: i32, key_len: i32) -> i64`: the packed location of the value, or -1 if missing.
+// - `set(key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32)`.
+
+use std::sync::Arc;
+
+use crate::protocol::{encode, RESPCodec, RESPValue};
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+use wasmi::core::ValType;
+use wasmi::{Caller, Engine, Extern, Linker, Memory, Module, Store};
+
+use crate::db::{Detached, Value};
+use crate::plugin::{Command, CommandRegistry, Database};
+use crate::RESPError;
+
+const ALLOC_EXPORT: &str = "alloc";
+const MEMORY_EXPORT: &str = "memory";
+
+// The keyspace is moved into the store for the duration of a call, and moved back once it ends.
+type HostState = Detached;
+
+struct WasmModule {
+    engine: Engine,
+    module: Module,
+    linker: Linker<HostState>,
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<Memory, wasmi::Error> {
+    caller.get_export(MEMORY_EXPORT).and_then(Extern::into_memory).ok_or_else(|| wasmi::Error::new("missing memory export"))
+}
+
+fn read_bytes(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let mut buf = vec![0; len as usize];
+    memory(caller)?.read(caller, ptr as usize, &mut buf).map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(buf)
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).map_err(|_| wasmi::Error::new("keys must be valid utf-8"))
+}
+
+// Copies `data` into a buffer allocated by the module, returning its packed location.
+fn write_to_guest(caller: &mut Caller<'_, HostState>, data: &[u8]) -> Result<i64, wasmi::Error> {
+    let alloc = caller.get_export(ALLOC_EXPORT)
+        .and_then(Extern::into_func)
+        .ok_or_else(|| wasmi::Error::new("missing alloc export"))?
+        .typed::<i32, i32>(&caller)?;
+    let ptr = alloc.call(&mut *caller, data.len() as i32)?;
+    memory(caller)?.write(&mut *caller, ptr as usize, data).map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(((ptr as i64) << 32) | data.len() as i64)
+}
+
+fn host_linker(engine: &Engine) -> Result<Linker<HostState>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap("bast", "get", |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32| -> Result<i64, wasmi::Error> {
+        let key = read_string(&caller, key_ptr, key_len)?;
+        let value = match caller.data().get(&key).map(Value::as_string) {
+            Some(Ok(value)) => value,
+            _ => return Ok(-1)
+        };
+        write_to_guest(&mut caller, &value)
+    })?;
+
+    linker.func_wrap("bast", "set", |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32| -> Result<(), wasmi::Error> {
+        let key = read_string(&caller, key_ptr, key_len)?;
+        let value = read_bytes(&caller, value_ptr, value_len)?;
+        caller.data_mut().set(key, Value::string(value));
+        Ok(())
+    })?;
+
+    Ok(linker)
+}
+
+struct WasmCommand {
+    name: String,
+    export: String,
+    module: Arc<WasmModule>,
+}
+
+impl WasmCommand {
+    fn call(&self, store: &mut Store<HostState>, args: &[String]) -> Result<RESPValue, wasmi::Error> {
+        let instance = self.module.linker.instantiate(&mut *store, &self.module.module)?.start(&mut *store)?;
+        let alloc = instance.get_typed_func::<i32, i32>(&*store, ALLOC_EXPORT)?;
+        let memory = instance.get_memory(&*store, MEMORY_EXPORT).ok_or_else(|| wasmi::Error::new("missing memory export"))?;
+        let function = instance.get_typed_func::<(i32, i32), i64>(&*store, &self.export)?;
+
+        let mut request = BytesMut::new();
+        let values = args.iter().map(|arg| RESPValue::BlobString(arg.to_owned().into())).collect();
+        encode(RESPValue::Array(values), &mut request);
+
+        let args_ptr = alloc.call(&mut *store, request.len() as i32)?;
+        memory.write(&mut *store, args_ptr as usize, &request).map_err(|e| wasmi::Error::new(e.to_string()))?;
+
+        let packed = function.call(&mut *store, (args_ptr, request.len() as i32))?;
+        let (reply_ptr, reply_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut reply = BytesMut::zeroed(reply_len);
+        memory.read(&*store, reply_ptr, &mut reply).map_err(|e| wasmi::Error::new(e.to_string()))?;
+
+        RESPCodec::default().decode(&mut reply)
+            .map_err(|e| wasmi::Error::new(format!("invalid reply: {:?}", e)))?
+            .ok_or_else(|| wasmi::Error::new("incomplete reply"))
+    }
+}
+
+impl Command for WasmCommand {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> i64 {
+        -1
+    }
+
+    // There's no telling what the module does with the keyspace.
+    fn flags(&self) -> &[&'static str] {
+        &["write"]
+    }
+
+    fn execute(&self, args: &[String], db: &mut Database<'_>) -> Result<RESPValue, RESPError> {
+        let db = db.db();
+        let mut store = Store::new(&self.module.engine, db.detach());
+        let result = self.call(&mut store, args);
+        db.attach(store.into_data());
+        result.map_err(|e| RESPError::WasmError(e.to_string()))
+    }
+}
+
+// Registers a command for every exported function with the command signature, returning their names.
+pub fn load_module(registry: &mut CommandRegistry, path: &str) -> Result<Vec<String>, RESPError> {
+    let wasm = std::fs::read(path).map_err(|e| RESPError::PluginLoadError(e.to_string()))?;
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm).map_err(|e| RESPError::PluginLoadError(e.to_string()))?;
+    let linker = host_linker(&engine).map_err(|e| RESPError::PluginLoadError(e.to_string()))?;
+
+    let exports: Vec<String> = module.exports()
+        .filter(|export| export.name() != ALLOC_EXPORT)
+        .filter(|export| export.ty().func().is_some_and(|ty| {
+            ty.params() == [ValType::I32, ValType::I32] && ty.results() == [ValType::I64]
+        }))
+        .map(|export| export.name().to_owned())
+        .collect();
+
+    let module = Arc::new(WasmModule { engine, module, linker });
+    let mut names = Vec::with_capacity(exports.len());
+    for export in exports {
+        let name = export.to_ascii_uppercase();
+        registry.register(WasmCommand { name: name.clone(), export, module: module.clone() })?;
+        names.push(name);
+    }
+    Ok(names)
+}